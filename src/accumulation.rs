@@ -0,0 +1,258 @@
+use crate::math::*;
+use crate::raytracer::CHANNELS;
+
+// --------------------------------------------------
+// Accumulation buffer
+// --------------------------------------------------
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Precision {
+    Full, // Compensated sums of the samples in Float, at twice the memory of the sums alone.
+    Half // Means of the samples in 16-bit floats, at a quarter of the memory of full precision with f32.
+}
+
+impl Precision {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Precision::Full => "full",
+            Precision::Half => "half"
+        }
+    }
+}
+
+// Linear RGBA of each pixel accumulated over the samples traced so far, which callers count.
+// Full precision keeps the sums of the samples, each with the rounding error of the last addition to it,
+// which is taken off the next one (Kahan summation). A plain f32 sum of thousands of samples is large next to each
+// pass, so every addition rounds away more of the pass, and eventually more samples stop changing it.
+// Half precision can't keep sums at all: a sum grows with the samples, and
+// past 2048 a 16-bit float no longer even counts in ones, so small additions round away. Instead it
+// keeps the mean, which stays near the pixel's value and so keeps its relative precision (about 1/2048).
+// Each pass's samples are gathered in Float by the renderer and only rounded once, when they are merged
+// into the mean. Late passes move the mean by less, until their change is below what a half can hold,
+// by which point the pixel has converged far beyond what 8-bit output shows. Values above the largest
+// half (65504) are clamped.
+#[derive(Clone)]
+pub struct AccumulationBuffer {
+    values: Values
+}
+
+#[derive(Clone)]
+enum Values {
+    Full {sums: Box<[Float]>, compensation: Box<[Float]>},
+    Half(Box<[u16]>)
+}
+
+const HALF_MAX: f32 = 65504.0;
+
+impl AccumulationBuffer {
+    // Black and transparent, with no samples.
+    pub fn new(pixel_count: usize, precision: Precision) -> AccumulationBuffer {
+        let values = match precision {
+            Precision::Full => Values::Full {
+                sums: vec![0.0; pixel_count * (CHANNELS as usize)].into_boxed_slice(),
+                compensation: vec![0.0; pixel_count * (CHANNELS as usize)].into_boxed_slice()
+            },
+            Precision::Half => Values::Half(vec![0; pixel_count * (CHANNELS as usize)].into_boxed_slice())
+        };
+        AccumulationBuffer {
+            values: values
+        }
+    }
+
+    pub fn precision(&self) -> Precision {
+        match self.values {
+            Values::Full{..} => Precision::Full,
+            Values::Half(_) => Precision::Half
+        }
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        match &self.values {
+            Values::Full{sums, compensation} => (sums.len() + compensation.len()) * std::mem::size_of::<Float>(),
+            Values::Half(values) => values.len() * std::mem::size_of::<u16>()
+        }
+    }
+
+    // Sums of a pixel's color and alpha over its samples.
+    pub fn get(&self, idx: usize, samples: u32) -> (Vec3, Float) {
+        let offset = idx * (CHANNELS as usize);
+        match &self.values {
+            Values::Full{sums, compensation} => {
+                let value = |channel: usize| sums[offset + channel] - compensation[offset + channel];
+                (Vec3::new(value(0), value(1), value(2)), value(3))
+            }
+            Values::Half(values) => {
+                let scale = samples as Float;
+                let value = |channel: usize| (half_to_f32(values[offset + channel]) as Float) * scale;
+                (Vec3::new(value(0), value(1), value(2)), value(3))
+            }
+        }
+    }
+
+    // Replaces a pixel with sums of color and alpha over a number of samples.
+    pub fn set(&mut self, idx: usize, color: Vec3, alpha: Float, samples: u32) {
+        let offset = idx * (CHANNELS as usize);
+        let sums = [color.x, color.y, color.z, alpha];
+        match &mut self.values {
+            Values::Full{sums: values, compensation} => {
+                values[offset..(offset + CHANNELS as usize)].copy_from_slice(&sums);
+                compensation[offset..(offset + CHANNELS as usize)].iter_mut().for_each(|value| *value = 0.0);
+            }
+            Values::Half(values) => {
+                for (channel, sum) in sums.iter().enumerate() {
+                    values[offset + channel] = f32_to_half((sum / (samples.max(1) as Float)) as f32);
+                }
+            }
+        }
+    }
+
+    // Merges the average color and alpha of a pass of samples into a pixel, which then has total_samples
+    // in all. Passes are weighted by their samples. A total equal to the pass's samples replaces the pixel.
+    pub fn add(&mut self, idx: usize, color: Vec3, alpha: Float, samples: u32, total_samples: u32) {
+        let offset = idx * (CHANNELS as usize);
+        let averages = [color.x, color.y, color.z, alpha];
+        if samples >= total_samples {
+            self.set(idx, color * (samples as Float), alpha * (samples as Float), samples);
+            return
+        }
+        match &mut self.values {
+            Values::Full{sums, compensation} => {
+                for (channel, average) in averages.iter().enumerate() {
+                    // What the sum actually grew by, less what was added, is the error to make up next time.
+                    let sum = sums[offset + channel];
+                    let value = average * (samples as Float) - compensation[offset + channel];
+                    let total = sum + value;
+                    compensation[offset + channel] = (total - sum) - value;
+                    sums[offset + channel] = total;
+                }
+            }
+            Values::Half(values) => {
+                let weight = (samples as Float) / (total_samples as Float);
+                for (channel, average) in averages.iter().enumerate() {
+                    let mean = half_to_f32(values[offset + channel]) as Float;
+                    values[offset + channel] = f32_to_half((mean + (average - mean) * weight) as f32);
+                }
+            }
+        }
+    }
+
+    // The same pixels at another precision, given each pixel's number of samples.
+    pub fn with_precision(&self, precision: Precision, sample_counts: &[u32]) -> AccumulationBuffer {
+        if precision == self.precision() {
+            return self.clone()
+        }
+        let mut converted = AccumulationBuffer::new(sample_counts.len(), precision);
+        for (idx, samples) in sample_counts.iter().enumerate() {
+            let (color, alpha) = self.get(idx, *samples);
+            converted.set(idx, color, alpha, *samples);
+        }
+        converted
+    }
+}
+
+// Rounds to the nearest IEEE 754 half, ties to even. Non-negative values above the largest half are
+// clamped to it, as are negative ones below its negative.
+fn f32_to_half(value: f32) -> u16 {
+    if value.is_nan() {
+        return 0x7e00
+    }
+    let bits = value.clamp(-HALF_MAX, HALF_MAX).to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+
+    // Rounds away the low bits of a mantissa. A carry into the exponent gives the next power of two.
+    let round = |mantissa: u32, shift: u32| {
+        let kept = mantissa >> shift;
+        let rest = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        if rest > halfway || (rest == halfway && kept & 1 == 1) {kept + 1} else {kept}
+    };
+    if exponent <= 0 {
+        // Subnormal, with the implicit leading one made explicit. Too small rounds to zero.
+        if exponent < -10 {
+            return sign
+        }
+        return sign | round(mantissa | 0x80_0000, (14 - exponent) as u32) as u16
+    }
+    sign | (((exponent as u32) << 10) + round(mantissa, 13)) as u16
+}
+
+fn half_to_f32(half: u16) -> f32 {
+    let sign = ((half & 0x8000) as u32) << 16;
+    let exponent = ((half >> 10) & 0x1f) as u32;
+    let mantissa = (half & 0x3ff) as u32;
+    match exponent {
+        0 => {
+            let magnitude = (mantissa as f32) * (2.0f32).powi(-24);
+            if sign != 0 {-magnitude} else {magnitude}
+        }
+        31 => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+        _ => f32::from_bits(sign | ((exponent + 127 - 15) << 23) | (mantissa << 13))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_buffer_keeps_the_mean_over_many_passes() {
+        // Halves round trip exactly, and other values round to the nearest one.
+        for value in [0.0, 1.0, -2.5, 1365.0 / 4096.0, 65504.0, 1.0 / 16384.0, 1.0 / 16777216.0].iter() {
+            assert_eq!(half_to_f32(f32_to_half(*value)), *value);
+        }
+        assert_eq!(half_to_f32(f32_to_half(1.0 + 1.0 / 4096.0)), 1.0);
+        assert_eq!(half_to_f32(f32_to_half(1.0 + 3.0 / 4096.0)), 1.0 + 2.0 / 2048.0);
+        assert_eq!(half_to_f32(f32_to_half(1e6)), 65504.0);
+        assert_eq!(half_to_f32(f32_to_half(1e-9)), 0.0);
+
+        // Ten thousand single sample passes alternating between two values average out to their mean,
+        // where a sum of halves would have stopped growing long before.
+        let mut full = AccumulationBuffer::new(1, Precision::Full);
+        let mut half = AccumulationBuffer::new(1, Precision::Half);
+        let mut naive: u16 = 0;
+        let passes = 10000;
+        for pass in 0..passes {
+            let value = if pass % 2 == 0 {0.2} else {0.45};
+            full.add(0, Vec3::one() * value, 1.0, 1, pass + 1);
+            half.add(0, Vec3::one() * value, 1.0, 1, pass + 1);
+            naive = f32_to_half(half_to_f32(naive) + value as f32);
+        }
+        let (full_sum, _) = full.get(0, passes);
+        let (half_sum, half_alpha) = half.get(0, passes);
+        assert!((full_sum.x / (passes as Float) - 0.325).abs() < 1e-6);
+        assert!((half_sum.x / (passes as Float) - 0.325).abs() < 2e-3);
+        assert_eq!(half_alpha, passes as Float);
+        assert!(((half_to_f32(naive) as Float) / (passes as Float) - 0.325).abs() > 0.05);
+
+        // A replacing pass ignores what was there, and converting keeps the pixel.
+        half.add(0, Vec3::one() * 4.0, 0.5, 8, 8);
+        let (color, alpha) = half.get(0, 8);
+        assert_eq!((color.y, alpha), (32.0, 4.0));
+        let converted = half.with_precision(Precision::Full, &[8]);
+        assert_eq!(converted.get(0, 8).0.z, 32.0);
+        assert_eq!(converted.memory_usage(), 2 * 4 * std::mem::size_of::<Float>());
+        assert_eq!(half.memory_usage(), 8);
+    }
+
+    #[test]
+    fn full_buffer_compensates_long_sums() {
+        // A million single sample passes of 0.1, which isn't exact in binary. A plain f32 sum is off by
+        // about 1% by then.
+        let mut buffer = AccumulationBuffer::new(1, Precision::Full);
+        let mut naive: f32 = 0.0;
+        let passes = 1_000_000;
+        for pass in 0..passes {
+            buffer.add(0, Vec3::one() * 0.1, 1.0, 1, pass + 1);
+            naive += 0.1;
+        }
+        let (sum, alpha) = buffer.get(0, passes);
+        assert!((sum.x / (passes as Float) - 0.1).abs() < 1e-7);
+        assert_eq!(alpha, passes as Float);
+        assert!((naive / (passes as f32) - 0.1).abs() > 5e-4);
+
+        // Replacing a pixel clears what was lost.
+        buffer.set(0, Vec3::one() * 2.0, 1.0, 2);
+        assert_eq!(buffer.get(0, 2).0.x, 2.0);
+    }
+}
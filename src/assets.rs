@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use log::{debug, info};
+
+use crate::error::{RaytracerError, Result};
+use crate::image::{Image, StreamedImage, Texture};
+use crate::obj_loader;
+use crate::primitives::Mesh;
+
+// --------------------------------------------------
+// Handles
+// --------------------------------------------------
+// Refers to an asset of an AssetManager. Handles stay valid until the manager is cleared, even if the
+// asset is released in between, in which case they no longer resolve.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct TextureHandle(u32);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct MeshHandle(u32);
+
+// Paths are compared by where they lead, so that "tex.ppm" and "./tex.ppm" load once.
+fn cache_key(path: &str) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
+
+// --------------------------------------------------
+// AssetManager
+// --------------------------------------------------
+// Loads files that scene objects share, such as textures and meshes, once per path. Assets are reference
+// counted: whoever uses one holds an Arc to it, and the manager holds one more until the asset is released.
+pub struct AssetManager {
+    textures: Vec<Option<Arc<Texture>>>,
+    texture_paths: HashMap<PathBuf, TextureHandle>,
+    meshes: Vec<Option<Arc<Mesh>>>,
+    mesh_paths: HashMap<(PathBuf, u32), MeshHandle>, // Keyed by (path, material index) as meshes carry their material.
+    texture_budget: Option<usize>, // Bytes above which textures are streamed from disk, if set.
+    cache_hits: usize
+}
+
+impl Default for AssetManager {
+    fn default() -> AssetManager {
+        AssetManager::new()
+    }
+}
+
+impl AssetManager {
+    pub fn new() -> AssetManager {
+        AssetManager {
+            textures: Vec::new(),
+            texture_paths: HashMap::new(),
+            meshes: Vec::new(),
+            mesh_paths: HashMap::new(),
+            texture_budget: None,
+            cache_hits: 0
+        }
+    }
+
+    // Forgets all assets, e.g. before reloading a scene whose files may have changed.
+    // Objects that still hold an asset keep it alive. The texture budget is kept.
+    pub fn clear(&mut self) {
+        let texture_budget = self.texture_budget;
+        *self = AssetManager::new();
+        self.texture_budget = texture_budget;
+    }
+
+    // Textures that would use more bytes than this are streamed from disk in tiles, keeping at most this
+    // many bytes of each in memory. Only applies to 8-bit binary PGM/PPM files loaded afterwards. The budget is
+    // per texture, not shared, so a scene with several streamed textures can use several budgets' worth.
+    pub fn set_texture_budget(&mut self, budget: Option<usize>) {
+        self.texture_budget = budget;
+    }
+
+    pub fn texture_budget(&self) -> Option<usize> {
+        self.texture_budget
+    }
+
+    // Reads a PGM/PPM image and builds its mip chain, or returns the already loaded texture for the same file.
+    // Images over the texture budget are streamed instead.
+    pub fn load_texture(&mut self, path: &str) -> Result<TextureHandle> {
+        let key = cache_key(path);
+        if let Some(handle) = self.cached_texture(&key) {
+            return Ok(handle)
+        }
+
+        let streamed = self.texture_budget
+            .and_then(|budget| StreamedImage::open(path, budget).ok())
+            .filter(|source| source.full_memory_usage() > source.budget());
+        let texture = match streamed {
+            Some(source) => {
+                info!(target: "io", "Streaming image {} ({}x{}) within {} bytes...", path, source.width(), source.height(), source.budget());
+                Texture::streamed(source)?
+            }
+            None => {
+                info!(target: "io", "Reading image {}...", path);
+                Texture::new(Image::read_ppm(path)?)
+            }
+        };
+        Ok(self.insert_texture(key, texture))
+    }
+
+    // A texture built from part of a file rather than read from a file of its own, such as an image embedded in
+    // a glTF file, named by the file and the part. Built with make unless already loaded.
+    pub fn load_embedded_texture<F: FnOnce() -> Texture>(&mut self, path: &str, part: &str, make: F) -> TextureHandle {
+        let key = PathBuf::from(format!("{}#{}", cache_key(path).display(), part));
+        match self.cached_texture(&key) {
+            Some(handle) => handle,
+            None => self.insert_texture(key, make())
+        }
+    }
+
+    fn cached_texture(&mut self, key: &PathBuf) -> Option<TextureHandle> {
+        let handle = *self.texture_paths.get(key)?;
+        self.textures[handle.0 as usize].as_ref()?;
+        self.cache_hits += 1;
+        Some(handle)
+    }
+
+    // Released textures get a new handle when they are loaded again, so that old handles never resolve to them.
+    fn insert_texture(&mut self, key: PathBuf, texture: Texture) -> TextureHandle {
+        let handle = TextureHandle(self.textures.len() as u32);
+        self.textures.push(Some(Arc::new(texture)));
+        self.texture_paths.insert(key, handle);
+        handle
+    }
+
+    // Reads all faces of an OBJ file into a single mesh with the given material, or returns the already
+    // loaded one. Materials in the file are ignored.
+    pub fn load_mesh(&mut self, path: &str, mat_id: u32) -> Result<MeshHandle> {
+        let key = (cache_key(path), mat_id);
+        if let Some(handle) = self.mesh_paths.get(&key) {
+            if self.meshes[handle.0 as usize].is_some() {
+                self.cache_hits += 1;
+                return Ok(*handle)
+            }
+        }
+
+        let mesh = obj_loader::read_meshes(path, None, mat_id)?.pop().ok_or_else(|| RaytracerError::InvalidScene {
+            path: path.to_string(),
+            reason: "no faces".to_string()
+        })?;
+        let handle = MeshHandle(self.meshes.len() as u32);
+        self.meshes.push(Some(Arc::new(mesh)));
+        self.mesh_paths.insert(key, handle);
+        Ok(handle)
+    }
+
+    pub fn get_texture(&self, handle: TextureHandle) -> Option<Arc<Texture>> {
+        self.textures.get(handle.0 as usize).and_then(|texture| texture.clone())
+    }
+
+    pub fn get_mesh(&self, handle: MeshHandle) -> Option<Arc<Mesh>> {
+        self.meshes.get(handle.0 as usize).and_then(|mesh| mesh.clone())
+    }
+
+    // Number of references held outside of the manager.
+    pub fn texture_users(&self, handle: TextureHandle) -> usize {
+        self.textures.get(handle.0 as usize).and_then(|texture| texture.as_ref()).map_or(0, |texture| Arc::strong_count(texture) - 1)
+    }
+
+    pub fn mesh_users(&self, handle: MeshHandle) -> usize {
+        self.meshes.get(handle.0 as usize).and_then(|mesh| mesh.as_ref()).map_or(0, |mesh| Arc::strong_count(mesh) - 1)
+    }
+
+    // Drops the assets nothing else refers to. Returns how many were released.
+    pub fn release_unused(&mut self) -> usize {
+        let mut released = 0;
+        for texture in self.textures.iter_mut() {
+            if texture.as_ref().is_some_and(|texture| Arc::strong_count(texture) == 1) {
+                *texture = None;
+                released += 1;
+            }
+        }
+        for mesh in self.meshes.iter_mut() {
+            if mesh.as_ref().is_some_and(|mesh| Arc::strong_count(mesh) == 1) {
+                *mesh = None;
+                released += 1;
+            }
+        }
+        if released > 0 {
+            debug!(target: "scene", "Released {} unused assets.", released);
+        }
+        released
+    }
+
+    pub fn texture_count(&self) -> usize {
+        self.textures.iter().filter(|texture| texture.is_some()).count()
+    }
+
+    pub fn mesh_count(&self) -> usize {
+        self.meshes.iter().filter(|mesh| mesh.is_some()).count()
+    }
+
+    // Loads that were served from the cache instead of reading the file again.
+    pub fn cache_hits(&self) -> usize {
+        self.cache_hits
+    }
+
+    // Approximate bytes used by the loaded assets.
+    pub fn memory_usage(&self) -> usize {
+        let meshes: usize = self.meshes.iter().flatten().map(|mesh| mesh.memory_usage()).sum();
+        self.texture_memory_usage() + meshes
+    }
+
+    // Approximate bytes used by the loaded textures alone.
+    pub fn texture_memory_usage(&self) -> usize {
+        self.textures.iter().flatten().map(|texture| texture.memory_usage()).sum()
+    }
+
+    pub fn log_usage(&self) {
+        if self.textures.is_empty() && self.meshes.is_empty() {
+            return
+        }
+        info!(target: "scene", "Assets: {} textures and {} meshes using {:.1} MB ({} loads served from the cache).",
+            self.texture_count(), self.mesh_count(), (self.memory_usage() as f64) / (1024.0 * 1024.0), self.cache_hits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes the files the tests load into a directory of their own.
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("rs_raytracer_assets_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("tex.ppm"), b"P6\n2 1\n255\n\xff\x00\x00\x00\x00\xff").unwrap();
+        fs::write(directory.join("tri.obj"), "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+        directory
+    }
+
+    #[test]
+    fn textures_load_once_per_file() {
+        let directory = test_directory("textures");
+        let path = directory.join("tex.ppm").to_string_lossy().into_owned();
+        let same = directory.join(".").join("tex.ppm").to_string_lossy().into_owned();
+        let mut assets = AssetManager::new();
+
+        // The same file by another path is served from the cache.
+        let handle = assets.load_texture(&path).unwrap();
+        assert_eq!(assets.load_texture(&same).unwrap(), handle);
+        assert_eq!((assets.texture_count(), assets.cache_hits()), (1, 1));
+        assert!(assets.load_texture(&directory.join("missing.ppm").to_string_lossy()).is_err());
+
+        // Textures are released once only the manager holds them, and load again under a new handle.
+        let texture = assets.get_texture(handle).unwrap();
+        assert_eq!(assets.texture_users(handle), 1);
+        assert_eq!(assets.release_unused(), 0);
+        drop(texture);
+        assert_eq!(assets.release_unused(), 1);
+        assert!(assets.get_texture(handle).is_none());
+        let reloaded = assets.load_texture(&path).unwrap();
+        assert_ne!(reloaded, handle);
+        assert!(assets.get_texture(handle).is_none() && assets.get_texture(reloaded).is_some());
+        assert_eq!(assets.cache_hits(), 1);
+
+        // Embedded images are built once per file and part.
+        let mut built = 0;
+        let mut embedded = |assets: &mut AssetManager, part: &str| assets.load_embedded_texture(&path, part, || {
+            built += 1;
+            Texture::new(Image::read_ppm(&path).unwrap())
+        });
+        let first = embedded(&mut assets, "image0");
+        assert_eq!(embedded(&mut assets, "image0"), first);
+        assert_ne!(embedded(&mut assets, "image1"), first);
+        assert_eq!(built, 2);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn meshes_are_keyed_by_file_and_material() {
+        let directory = test_directory("meshes");
+        let path = directory.join("tri.obj").to_string_lossy().into_owned();
+        let mut assets = AssetManager::new();
+
+        let handle = assets.load_mesh(&path, 0).unwrap();
+        assert_eq!(assets.load_mesh(&path, 0).unwrap(), handle);
+        let other = assets.load_mesh(&path, 1).unwrap();
+        assert_ne!(other, handle);
+        assert_eq!(assets.get_mesh(other).unwrap().mat_id, 1);
+        assert_eq!((assets.mesh_count(), assets.cache_hits()), (2, 1));
+
+        // Clearing forgets every asset, but objects holding one keep it.
+        let mesh = assets.get_mesh(handle).unwrap();
+        assert_eq!(assets.mesh_users(handle), 1);
+        assets.clear();
+        assert_eq!(assets.mesh_count(), 0);
+        assert!(assets.get_mesh(handle).is_none());
+        assert_eq!(mesh.triangle_count(), 1);
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::error::{RaytracerError, Result};
+use crate::raytracer::RenderSettings;
+
+// --------------------------------------------------
+// Batch files
+// --------------------------------------------------
+// A batch file lists scenes to render one after another, one per line, each optionally followed by
+// key=value overrides of the command line settings:
+//
+//     # Comments and blank lines are skipped.
+//     scenes/cornell.obj samples=256 output=cornell.png
+//     scenes/spheres.pbrt depth=8 max-time=600
+//
+// Keys are samples, depth, max-time (seconds), max-samples, script, and output. Scene and script paths are
+// relative to the working directory, and outputs to the output directory. Without an output, jobs are saved
+// as batch_<job>_<scene name>.png.
+pub struct BatchJob {
+    pub line: usize, // In the batch file, from 1.
+    pub number: usize, // Position in the batch, from 1.
+    pub text: String, // The line as written, which with the output identifies the job when resuming.
+    pub scene_path: String,
+    pub script_path: Option<String>,
+    pub output: Option<String>,
+    pub samples_per_pixel: Option<u32>,
+    pub max_depth: Option<u32>,
+    pub max_time: Option<Duration>,
+    pub max_total_samples: Option<u64>
+}
+
+impl BatchJob {
+    // Overrides the settings the job sets.
+    pub fn apply(&self, settings: &mut RenderSettings) {
+        if let Some(samples) = self.samples_per_pixel {
+            settings.samples_per_pixel = samples;
+        }
+        if let Some(depth) = self.max_depth {
+            settings.max_depth = depth;
+        }
+        if self.max_time.is_some() {
+            settings.max_time = self.max_time;
+        }
+        if self.max_total_samples.is_some() {
+            settings.max_total_samples = self.max_total_samples;
+        }
+    }
+
+    // Where the job's image is saved.
+    pub fn output_path(&self) -> String {
+        if let Some(output) = &self.output {
+            return output.clone()
+        }
+        let name = Path::new(&self.scene_path).file_stem().map_or("scene".to_string(), |stem| stem.to_string_lossy().into_owned());
+        format!("batch_{:03}_{}.png", self.number, name)
+    }
+
+    // Written to the progress file once the job is done. Editing the line makes it a new job, but moving it
+    // doesn't, unless it has no output and so is saved under its new number.
+    fn key(&self) -> String {
+        format!("{}\t{}", self.output_path(), self.text)
+    }
+}
+
+pub fn read(path: &str) -> Result<Vec<BatchJob>> {
+    parse(path, &fs::read_to_string(path)?)
+}
+
+pub fn parse(path: &str, text: &str) -> Result<Vec<BatchJob>> {
+    let mut jobs = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let invalid = |reason: String| RaytracerError::InvalidBatch {
+            path: path.to_string(),
+            line: idx + 1,
+            reason: reason
+        };
+
+        let content = line.split('#').next().unwrap_or("").trim();
+        let mut tokens = content.split_whitespace();
+        let scene_path = match tokens.next() {
+            Some(scene_path) => scene_path,
+            None => continue
+        };
+        let mut job = BatchJob {
+            line: idx + 1,
+            number: jobs.len() + 1,
+            text: content.to_string(),
+            scene_path: scene_path.to_string(),
+            script_path: None,
+            output: None,
+            samples_per_pixel: None,
+            max_depth: None,
+            max_time: None,
+            max_total_samples: None
+        };
+        for token in tokens {
+            let (key, value) = match token.find('=') {
+                Some(split) => (&token[..split], &token[(split + 1)..]),
+                None => return Err(invalid(format!("expected key=value but got '{}'", token)))
+            };
+            let bad_value = || invalid(format!("bad value '{}' for {}", value, key));
+            match key {
+                "samples" => job.samples_per_pixel = Some(value.parse().map_err(|_| bad_value())?),
+                "depth" => job.max_depth = Some(value.parse().map_err(|_| bad_value())?),
+                "max-time" => {
+                    job.max_time = Some(value.parse().ok().and_then(|seconds| Duration::try_from_secs_f64(seconds).ok()).ok_or_else(bad_value)?);
+                }
+                "max-samples" => job.max_total_samples = Some(value.parse().map_err(|_| bad_value())?),
+                "script" => job.script_path = Some(value.to_string()),
+                "output" => job.output = Some(value.to_string()),
+                _ => return Err(invalid(format!("unknown key '{}'", key)))
+            }
+        }
+        jobs.push(job);
+    }
+    Ok(jobs)
+}
+
+// --------------------------------------------------
+// Progress
+// --------------------------------------------------
+// Jobs of a batch that are done, kept next to the batch file as <batch file>.progress so that an
+// interrupted batch resumes with the first job it didn't finish. Removed once the whole batch is done.
+pub struct BatchProgress {
+    path: String,
+    done: HashSet<String>
+}
+
+impl BatchProgress {
+    pub fn open(batch_path: &str) -> Result<BatchProgress> {
+        let path = format!("{}.progress", batch_path);
+        let done = match fs::read_to_string(&path) {
+            Ok(text) => text.lines().map(|line| line.to_string()).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e.into())
+        };
+        Ok(BatchProgress {
+            path: path,
+            done: done
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn is_done(&self, job: &BatchJob) -> bool {
+        self.done.contains(&job.key())
+    }
+
+    // Written through at once, so that it survives the batch being killed.
+    pub fn mark_done(&mut self, job: &BatchJob) -> Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", job.key())?;
+        self.done.insert(job.key());
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_files_parse_overrides_and_resume() {
+        let text = "# Overnight renders\n\nscenes/cornell.obj samples=64 output=cornell.png\n  spheres.pbrt depth=8 max-time=1.5 # short\n";
+        let jobs = parse("jobs.txt", text).unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!((jobs[0].line, jobs[0].samples_per_pixel), (3, Some(64)));
+        assert_eq!(jobs[0].output_path(), "cornell.png");
+        assert_eq!(jobs[1].output_path(), "batch_002_spheres.png");
+        assert_eq!(jobs[1].text, "spheres.pbrt depth=8 max-time=1.5");
+
+        let mut settings = RenderSettings::new();
+        jobs[1].apply(&mut settings);
+        assert_eq!(settings.max_depth, 8);
+        assert_eq!(settings.max_time, Some(Duration::from_millis(1500)));
+        assert_eq!(settings.samples_per_pixel, RenderSettings::new().samples_per_pixel);
+
+        for bad in ["a.obj samples", "a.obj samples=many", "a.obj colour=red", "a.obj max-time=inf", "a.obj max-time=-1"].iter() {
+            match parse("jobs.txt", bad) {
+                Err(RaytracerError::InvalidBatch{line, ..}) => assert_eq!(line, 1),
+                _ => panic!("'{}' should not parse", bad)
+            }
+        }
+
+        // Finished jobs are remembered across runs until the batch is done.
+        let batch_path = std::env::temp_dir().join(format!("rs_raytracer_batch_{}.txt", std::process::id()));
+        let batch_path = batch_path.to_str().unwrap();
+        let mut progress = BatchProgress::open(batch_path).unwrap();
+        assert!(!progress.is_done(&jobs[0]));
+        progress.mark_done(&jobs[0]).unwrap();
+        let progress = BatchProgress::open(batch_path).unwrap();
+        assert!(progress.is_done(&jobs[0]) && !progress.is_done(&jobs[1]));
+        let progress_path = progress.path().to_string();
+
+        // Lines added above a job with an output don't make it a new job.
+        let moved = parse("jobs.txt", &format!("extra.obj\n{}", text)).unwrap();
+        assert_eq!(moved[1].line, 4);
+        assert!(progress.is_done(&moved[1]) && !progress.is_done(&moved[0]));
+        progress.finish().unwrap();
+        assert!(!Path::new(&progress_path).exists());
+    }
+}
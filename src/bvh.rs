@@ -0,0 +1,217 @@
+use crate::math::*;
+use crate::raytracer::HitRecord;
+
+// --------------------------------------------------
+// Bvh
+// --------------------------------------------------
+const MAX_LEAF_SIZE: usize = 4;
+
+fn axis_value(v: &Vec3, axis: usize) -> Float {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z
+    }
+}
+
+// Nodes are stored depth first, so a node's left child directly follows it.
+struct BvhNode {
+    bounds: Aabb,
+    first: u32, // Leaf: first entry in indices. Interior: index of the right child.
+    count: u32 // Number of items in a leaf. Zero for interior nodes.
+}
+
+// Bounding volume hierarchy over any list of items that have bounds.
+// The items themselves live elsewhere; the Bvh only refers to them by index.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    indices: Vec<u32>
+}
+
+impl Bvh {
+    pub fn build(bounds: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(bounds.len() * 2),
+            indices: (0..bounds.len() as u32).collect()
+        };
+        if !bounds.is_empty() {
+            bvh.build_node(bounds, 0, bounds.len());
+        }
+        bvh
+    }
+
+    fn build_node(&mut self, bounds: &[Aabb], start: usize, end: usize) {
+        let mut node_bounds = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for idx in &self.indices[start..end] {
+            node_bounds = node_bounds.union(&bounds[*idx as usize]);
+            centroid_bounds = centroid_bounds.grow(bounds[*idx as usize].centroid());
+        }
+
+        let node_idx = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds: node_bounds,
+            first: start as u32,
+            count: (end - start) as u32
+        });
+        if end - start <= MAX_LEAF_SIZE {
+            return
+        }
+
+        // Median split along the longest axis of the centroids.
+        let axis = centroid_bounds.longest_axis();
+        let mid = (start + end) / 2;
+        self.indices[start..end].select_nth_unstable_by(mid - start, |a, b| {
+            let ca = axis_value(&bounds[*a as usize].centroid(), axis);
+            let cb = axis_value(&bounds[*b as usize].centroid(), axis);
+            ca.partial_cmp(&cb).unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        self.build_node(bounds, start, mid);
+        let right = self.nodes.len() as u32;
+        self.build_node(bounds, mid, end);
+
+        self.nodes[node_idx].first = right;
+        self.nodes[node_idx].count = 0;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.nodes.len() * std::mem::size_of::<BvhNode>() + self.indices.len() * std::mem::size_of::<u32>()
+    }
+
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.nodes.first().map(|node| node.bounds)
+    }
+
+    // Depth of the leaf holding each item (the root is at depth 0), indexed by item.
+    pub fn leaf_depths(&self) -> Vec<u32> {
+        let mut depths = vec![0; self.indices.len()];
+        if self.nodes.is_empty() {
+            return depths
+        }
+        let mut stack: Vec<(usize, u32)> = vec![(0, 0)];
+        while let Some((node_idx, depth)) = stack.pop() {
+            let node = &self.nodes[node_idx];
+            if node.count > 0 {
+                for idx in &self.indices[(node.first as usize)..((node.first + node.count) as usize)] {
+                    depths[*idx as usize] = depth;
+                }
+            } else {
+                stack.push((node.first as usize, depth + 1));
+                stack.push((node_idx + 1, depth + 1));
+            }
+        }
+        depths
+    }
+
+    // Bounds of the nodes down to max_depth (the root is at depth 0) with their depth, parents first.
+    pub fn node_bounds(&self, max_depth: u32) -> Vec<(Aabb, u32)> {
+        let mut out = Vec::new();
+        if self.nodes.is_empty() {
+            return out
+        }
+        let mut stack: Vec<(usize, u32)> = vec![(0, 0)];
+        while let Some((node_idx, depth)) = stack.pop() {
+            let node = &self.nodes[node_idx];
+            out.push((node.bounds, depth));
+            if node.count == 0 && depth < max_depth {
+                stack.push((node.first as usize, depth + 1));
+                stack.push((node_idx + 1, depth + 1));
+            }
+        }
+        out
+    }
+
+    // Finds the closest hit. hit_item tests a single item against (ray, ray_t).
+    // Returns the hit and the number of nodes visited.
+    pub fn hit<F>(&self, ray: &Ray, ray_t: Interval, mut hit_item: F) -> (Option<HitRecord>, u32)
+        where F: FnMut(u32, &Ray, Interval) -> Option<HitRecord> {
+        let mut best: Option<HitRecord> = None;
+        let mut closest = ray_t;
+        let mut visits = 0;
+
+        if self.nodes.is_empty() || self.nodes[0].bounds.hit(ray, closest).is_none() {
+            return (None, visits)
+        }
+
+        let mut stack: Vec<usize> = Vec::with_capacity(64);
+        stack.push(0);
+        while let Some(node_idx) = stack.pop() {
+            visits += 1;
+            let node = &self.nodes[node_idx];
+            if node.bounds.hit(ray, closest).is_none() {
+                continue;
+            }
+
+            if node.count > 0 {
+                for idx in &self.indices[(node.first as usize)..((node.first + node.count) as usize)] {
+                    if let Some(hr) = hit_item(*idx, ray, closest) {
+                        closest.max = hr.t;
+                        best = Some(hr);
+                    }
+                }
+                continue;
+            }
+
+            // Visit the nearer child first so that the farther one is more likely to be culled.
+            let left = node_idx + 1;
+            let right = node.first as usize;
+            let left_t = self.nodes[left].bounds.hit(ray, closest);
+            let right_t = self.nodes[right].bounds.hit(ray, closest);
+            match (left_t, right_t) {
+                (Some(lt), Some(rt)) => {
+                    if lt <= rt {
+                        stack.push(right);
+                        stack.push(left);
+                    } else {
+                        stack.push(left);
+                        stack.push(right);
+                    }
+                }
+                (Some(_), None) => stack.push(left),
+                (None, Some(_)) => stack.push(right),
+                (None, None) => {}
+            }
+        }
+
+        (best, visits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raytracer::{Hittable, Sphere};
+
+    #[test]
+    fn bvh_finds_the_same_closest_hit_as_every_item() {
+        // A row of spheres along X, more than fit in one leaf.
+        let spheres: Vec<Sphere> = (0..20).map(|i| Sphere::new(Vec3::new(i as Float * 3.0, 0.0, 0.0), 1.0, 0)).collect();
+        let bounds: Vec<Aabb> = spheres.iter().map(|sphere| sphere.bounding_box().unwrap()).collect();
+        let bvh = Bvh::build(&bounds);
+        assert!(!bvh.is_empty());
+        assert!(bvh.leaf_depths().len() > 1);
+
+        let mut rand_gen = RandGen::seeded(7);
+        for _ in 0..200 {
+            let origin = Vec3::new(rand_gen.next01() * 60.0 - 5.0, 10.0, rand_gen.next01() * 2.0 - 1.0);
+            let ray = Ray::new(origin, Vec3::new(rand_gen.next01() - 0.5, -1.0, rand_gen.next01() - 0.5));
+            let expected = spheres.iter().filter_map(|sphere| sphere.hit(&ray, Interval::new(0.001, Float::MAX))).map(|hr| hr.t).fold(Float::MAX, Float::min);
+            let (hit, _) = bvh.hit(&ray, Interval::new(0.001, Float::MAX), |idx, ray, ray_t| spheres[idx as usize].hit(ray, ray_t));
+            assert_eq!(hit.map_or(Float::MAX, |hr| hr.t), expected);
+        }
+
+        let empty = Bvh::build(&[]);
+        assert!(empty.is_empty() && empty.bounds().is_none());
+        let ray = Ray::new(Vec3::zero(), Vec3::new(1.0, 0.0, 0.0));
+        assert!(empty.hit(&ray, Interval::new(0.001, Float::MAX), |_, _, _| panic!("no items to test")).0.is_none());
+    }
+}
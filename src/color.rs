@@ -0,0 +1,214 @@
+use crate::math::*;
+
+// --------------------------------------------------
+// Color spaces
+// --------------------------------------------------
+// Rendering happens in linear RGB with the sRGB / Rec.709 primaries and a D65 white point. Output spaces
+// convert that to their own primaries, then apply their transfer function, when the image is shown or saved.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ColorSpace {
+    Srgb, // sRGB primaries with the sRGB curve, for screens and most image viewers.
+    Rec709, // The same primaries with the Rec.709 (BT.709) camera curve, for video.
+    AcesCg, // Linear with the ACES AP1 primaries and a D60 white point, for compositing and grading.
+    Linear // Linear working space, unchanged.
+}
+
+impl ColorSpace {
+    // Parses a color space by name, e.g. from the command line.
+    pub fn from_name(name: &str) -> Option<ColorSpace> {
+        match name {
+            "srgb" => Some(ColorSpace::Srgb),
+            "rec709" => Some(ColorSpace::Rec709),
+            "acescg" => Some(ColorSpace::AcesCg),
+            "linear" => Some(ColorSpace::Linear),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ColorSpace::Srgb => "sRGB",
+            ColorSpace::Rec709 => "Rec.709",
+            ColorSpace::AcesCg => "ACEScg",
+            ColorSpace::Linear => "linear"
+        }
+    }
+
+    // Converts a working space linear color to this space's encoded values, which are [0, 1] for
+    // displayable colors but are not clamped.
+    pub fn encode(&self, color: &Vec3) -> Vec3 {
+        match *self {
+            ColorSpace::Srgb => Vec3::new(srgb_curve(color.x), srgb_curve(color.y), srgb_curve(color.z)),
+            ColorSpace::Rec709 => Vec3::new(rec709_curve(color.x), rec709_curve(color.y), rec709_curve(color.z)),
+            ColorSpace::AcesCg => {
+                // Linear sRGB to AP1, with a Bradford adaptation from D65 to D60.
+                Vec3::new(
+                    0.6130973 * color.x + 0.3395229 * color.y + 0.0473793 * color.z,
+                    0.0701942 * color.x + 0.9163556 * color.y + 0.0134502 * color.z,
+                    0.0206156 * color.x + 0.1095698 * color.y + 0.8698151 * color.z
+                )
+            }
+            ColorSpace::Linear => *color
+        }
+    }
+}
+
+// --------------------------------------------------
+// White balance
+// --------------------------------------------------
+// Corrects the color of the light the scene was lit by, as a camera does, by scaling the linear channels.
+// The temperature is that of the light to make white: setting it lower than the light's makes the image
+// bluer, and higher makes it warmer. Applied before the output color space.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct WhiteBalance {
+    pub temperature: Float, // In kelvin, on the blackbody curve. 6500 is neutral.
+    pub tint: Float // From -1 (green) to 1 (magenta), for light off the blackbody curve. 0 is neutral.
+}
+
+impl Default for WhiteBalance {
+    fn default() -> WhiteBalance {
+        WhiteBalance::new()
+    }
+}
+
+impl WhiteBalance {
+    pub const NEUTRAL_TEMPERATURE: Float = 6500.0;
+
+    pub fn new() -> WhiteBalance {
+        WhiteBalance {
+            temperature: WhiteBalance::NEUTRAL_TEMPERATURE,
+            tint: 0.0
+        }
+    }
+
+    pub fn is_neutral(&self) -> bool {
+        self.temperature == WhiteBalance::NEUTRAL_TEMPERATURE && self.tint == 0.0
+    }
+
+    // Per channel factors, which keep the luminance of white.
+    pub fn gains(&self) -> Vec3 {
+        if self.is_neutral() {
+            return Vec3::one()
+        }
+        let neutral = blackbody_rgb(WhiteBalance::NEUTRAL_TEMPERATURE);
+        let light = blackbody_rgb(self.temperature);
+        let mut gains = Vec3::new(neutral.x / light.x, neutral.y / light.y, neutral.z / light.z);
+        gains.y *= 1.0 - 0.3 * self.tint.clamp(-1.0, 1.0);
+        gains / luminance(&gains)
+    }
+}
+
+// Gray world white balance, which makes an average color (e.g. of the environment lighting the scene) gray
+// while keeping its luminance.
+pub fn gray_world_gains(average: &Vec3) -> Vec3 {
+    let level = luminance(average);
+    if level <= 0.0 || average.x <= 0.0 || average.y <= 0.0 || average.z <= 0.0 {
+        return Vec3::one()
+    }
+    Vec3::new(level / average.x, level / average.y, level / average.z)
+}
+
+// Of a linear sRGB color (Rec.709 weights).
+pub fn luminance(color: &Vec3) -> Float {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+// Linear sRGB color of a blackbody, with a luminance of 1. Kim et al.'s fit of the Planckian locus, which
+// covers 1667K to 25000K. The coefficients are as published, which f64 builds use in full.
+#[allow(clippy::excessive_precision)]
+fn blackbody_rgb(temperature: Float) -> Vec3 {
+    let t = temperature.clamp(1667.0, 25000.0);
+    let (t2, t3) = (t * t, t * t * t);
+    let x = if t <= 4000.0 {
+        -0.2661239e9 / t3 - 0.2343589e6 / t2 + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.0258469e9 / t3 + 2.1070379e6 / t2 + 0.2226347e3 / t + 0.240390
+    };
+    let (x2, x3) = (x * x, x * x * x);
+    let y = if t <= 2222.0 {
+        -1.1063814 * x3 - 1.34811020 * x2 + 2.18555832 * x - 0.20219683
+    } else if t <= 4000.0 {
+        -0.9549476 * x3 - 1.37418593 * x2 + 2.09137015 * x - 0.16748867
+    } else {
+        3.0817580 * x3 - 5.87338670 * x2 + 3.75112997 * x - 0.37001483
+    };
+
+    // xyY with Y = 1 to XYZ, then to linear sRGB (D65). Very warm lights fall slightly outside the gamut.
+    let (big_x, big_z) = (x / y, (1.0 - x - y) / y);
+    Vec3::new(
+        (3.2406 * big_x - 1.5372 - 0.4986 * big_z).max(1e-4),
+        (-0.9689 * big_x + 1.8758 + 0.0415 * big_z).max(1e-4),
+        (0.0557 * big_x - 0.2040 + 1.0570 * big_z).max(1e-4)
+    )
+}
+
+// IEC 61966-2-1 transfer function, linear near black.
+fn srgb_curve(value: Float) -> Float {
+    if value <= 0.0031308 {12.92 * value} else {1.055 * value.max(0.0).powf(1.0 / 2.4) - 0.055}
+}
+
+// ITU-R BT.709 transfer function, linear near black.
+fn rec709_curve(value: Float) -> Float {
+    if value < 0.018 {4.5 * value} else {1.099 * value.powf(0.45) - 0.099}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_spaces_encode_with_their_primaries_and_curves() {
+        let close = |a: Float, b: Float| (a - b).abs() < 1e-3;
+        let gray = Vec3::one() * 0.5;
+
+        // The curves are continuous and map white to white.
+        assert!(close(ColorSpace::Srgb.encode(&gray).x, 0.7354));
+        assert!(close(srgb_curve(0.0031308), 1.055 * (0.0031308 as Float).powf(1.0 / 2.4) - 0.055));
+        assert!(close(ColorSpace::Rec709.encode(&gray).x, 0.7055));
+        assert!(close(rec709_curve(0.018), 0.081));
+        for space in [ColorSpace::Srgb, ColorSpace::Rec709, ColorSpace::AcesCg, ColorSpace::Linear].iter() {
+            let white = space.encode(&Vec3::one());
+            assert!(close(white.x, 1.0) && close(white.y, 1.0) && close(white.z, 1.0));
+        }
+
+        // ACEScg has wider primaries, so saturated colors are less so in it, and it stays linear.
+        let red = ColorSpace::AcesCg.encode(&Vec3::new(1.0, 0.0, 0.0));
+        assert!(red.x < 1.0 && red.y > 0.0 && red.z > 0.0);
+        let half_red = ColorSpace::AcesCg.encode(&Vec3::new(0.5, 0.0, 0.0));
+        assert!(close(half_red.x * 2.0, red.x));
+        assert!(close(ColorSpace::Linear.encode(&gray).y, 0.5));
+
+        assert_eq!(ColorSpace::from_name("acescg"), Some(ColorSpace::AcesCg));
+        assert_eq!(ColorSpace::from_name("gamma"), None);
+    }
+
+    #[test]
+    fn white_balance_cools_warm_light_and_grays_the_environment() {
+        let close = |a: Float, b: Float| (a - b).abs() < 1e-3;
+        let mut balance = WhiteBalance::new();
+        let neutral = balance.gains();
+        assert!(close(neutral.x, 1.0) && close(neutral.y, 1.0) && close(neutral.z, 1.0));
+
+        // Balancing for tungsten light turns its own color white and other colors bluer.
+        balance.temperature = 3200.0;
+        let gains = balance.gains();
+        assert!(gains.z > gains.y && gains.y > gains.x);
+        assert!(close(luminance(&gains), 1.0));
+        let tungsten = blackbody_rgb(3200.0) * gains;
+        assert!(close(tungsten.x / tungsten.z, blackbody_rgb(6500.0).x / blackbody_rgb(6500.0).z));
+        balance.temperature = 9000.0;
+        assert!(balance.gains().x > balance.gains().z);
+
+        // A magenta tint takes away green.
+        balance.temperature = WhiteBalance::NEUTRAL_TEMPERATURE;
+        balance.tint = 0.5;
+        let tinted = balance.gains();
+        assert!(tinted.y < tinted.x && close(tinted.x, tinted.z));
+
+        // Gray world takes out the color cast of the average.
+        let sky = Vec3::new(0.5, 0.7, 1.0);
+        let balanced = sky * gray_world_gains(&sky);
+        assert!(close(balanced.x, balanced.y) && close(balanced.y, balanced.z) && close(balanced.x, luminance(&sky)));
+        assert!(close(gray_world_gains(&Vec3::zero()).x, 1.0));
+    }
+}
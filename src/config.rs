@@ -0,0 +1,229 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::color::ColorSpace;
+use crate::error::{RaytracerError, Result};
+use crate::math::*;
+use crate::raytracer::{PixelFilter, MAX_RESOLUTION};
+
+// --------------------------------------------------
+// Config
+// --------------------------------------------------
+pub const CONFIG_FILE: &str = "rs-raytracer.toml";
+
+// Defaults for the command line settings, so that common ones needn't be typed each time. Flags given on the
+// command line take precedence. Written in TOML, with the settings at the top level and a [keys] table that
+// binds extra keys to the viewer's actions:
+//
+//     samples = 64
+//     resolution = "3840x2160"
+//     color_space = "acescg"
+//     output_dir = "renders"
+//
+//     [keys]
+//     P = "F12" # F12 saves a PNG too.
+//
+// Settings the ray tracer doesn't have, such as a width on its own, are warned about rather than refused, as
+// are unknown ones, so that a config shared between versions still loads.
+pub struct Config {
+    pub path: String,
+    pub samples_per_pixel: Option<u32>,
+    pub max_depth: Option<u32>,
+    pub resolution: Option<(u32, u32)>,
+    pub filter: Option<PixelFilter>,
+    pub color_space: Option<ColorSpace>,
+    pub white_balance: Option<Float>, // In kelvin.
+    pub tint: Option<Float>,
+    pub output_directory: Option<String>,
+    pub texture_budget: Option<usize>, // In bytes, given in megabytes.
+    pub half_buffer: Option<bool>,
+    pub low_priority: Option<bool>,
+    pub stamp: Option<bool>,
+    pub max_time: Option<Duration>,
+    pub max_total_samples: Option<u64>,
+    pub key_bindings: Vec<(String, String)>, // Key of an action, and the key that also triggers it, by SDL key name.
+    pub warnings: Vec<String>
+}
+
+// The config in the working directory, or else in the home directory, if either exists.
+pub fn find() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+    let candidates = std::iter::once(PathBuf::from(CONFIG_FILE)).chain(home.map(|home| PathBuf::from(home).join(CONFIG_FILE)));
+    for candidate in candidates {
+        if candidate.is_file() {
+            return Some(candidate)
+        }
+    }
+    None
+}
+
+pub fn read(path: &str) -> Result<Config> {
+    parse(path, &fs::read_to_string(path)?)
+}
+
+pub fn parse(path: &str, text: &str) -> Result<Config> {
+    let mut config = Config {
+        path: path.to_string(),
+        samples_per_pixel: None,
+        max_depth: None,
+        resolution: None,
+        filter: None,
+        color_space: None,
+        white_balance: None,
+        tint: None,
+        output_directory: None,
+        texture_budget: None,
+        half_buffer: None,
+        low_priority: None,
+        stamp: None,
+        max_time: None,
+        max_total_samples: None,
+        key_bindings: Vec::new(),
+        warnings: Vec::new()
+    };
+
+    let invalid = |line: usize, reason: String| RaytracerError::InvalidConfig {
+        path: path.to_string(),
+        line: line,
+        reason: reason
+    };
+    let table = match text.parse::<toml::Value>() {
+        Ok(toml::Value::Table(table)) => table,
+        Ok(_) => return Err(invalid(1, "expected key = value lines".to_string())),
+        Err(e) => return Err(invalid(e.line_col().map_or(1, |(line, _)| line + 1), e.to_string()))
+    };
+
+    // Tables are read in key order, so settings are sorted back into the file's to warn and bind in that order.
+    let mut settings: Vec<(usize, &str, &toml::Value)> = Vec::new();
+    let mut bindings: Vec<(usize, String, String)> = Vec::new();
+    let mut warnings: Vec<(usize, String)> = Vec::new();
+    for (key, value) in table.iter() {
+        match value {
+            toml::Value::Table(keys) if key == "keys" => {
+                for (key, binding) in keys.iter() {
+                    let line = key_line(text, "keys", key);
+                    match binding {
+                        toml::Value::String(binding) => bindings.push((line, key.to_string(), binding.to_string())),
+                        _ => return Err(invalid(line, format!("bad value for {}", key)))
+                    }
+                }
+            }
+            toml::Value::Table(_) => {
+                let line = key_line(text, "", key);
+                warnings.push((line, format!("line {}: unknown section [{}] is ignored", line, key)));
+            }
+            _ => settings.push((key_line(text, "", key), key.as_str(), value))
+        }
+    }
+    settings.sort_by_key(|(line, _, _)| *line);
+
+    for (line, key, value) in settings {
+        let bad_value = || invalid(line, format!("bad value for {}", key));
+        let number = match value {
+            toml::Value::Integer(n) => Some(*n as f64),
+            toml::Value::Float(n) => Some(*n),
+            _ => None
+        };
+        match (key, value, number) {
+            ("samples", _, Some(n)) if n >= 1.0 => config.samples_per_pixel = Some(n as u32),
+            ("depth", _, Some(n)) if n >= 0.0 => config.max_depth = Some(n as u32),
+            ("filter", toml::Value::String(name), _) => config.filter = Some(PixelFilter::from_name(name).ok_or_else(bad_value)?),
+            ("color_space", toml::Value::String(name), _) => config.color_space = Some(ColorSpace::from_name(name).ok_or_else(bad_value)?),
+            ("white_balance", _, Some(n)) => config.white_balance = Some(n as Float),
+            ("tint", _, Some(n)) => config.tint = Some(n as Float),
+            ("output_dir", toml::Value::String(directory), _) => config.output_directory = Some(directory.to_string()),
+            ("texture_budget", _, Some(n)) if n >= 0.0 => config.texture_budget = Some((n as usize).checked_mul(1024 * 1024).ok_or_else(bad_value)?),
+            ("half_buffer", toml::Value::Boolean(b), _) => config.half_buffer = Some(*b),
+            ("low_priority", toml::Value::Boolean(b), _) => config.low_priority = Some(*b),
+            ("stamp", toml::Value::Boolean(b), _) => config.stamp = Some(*b),
+            ("max_time", _, Some(n)) => config.max_time = Some(Duration::try_from_secs_f64(n).map_err(|_| bad_value())?),
+            ("max_samples", _, Some(n)) if n >= 0.0 => config.max_total_samples = Some(n as u64),
+            ("resolution", toml::Value::String(text), _) => config.resolution = Some(parse_resolution(text).ok_or_else(bad_value)?),
+            ("width", _, _) | ("height", _, _) => {
+                warnings.push((line, format!("line {}: {} is ignored, set both as resolution = \"<width>x<height>\"", line, key)));
+            }
+            ("samples", _, _) | ("depth", _, _) | ("resolution", _, _) | ("filter", _, _) | ("color_space", _, _) | ("white_balance", _, _) | ("tint", _, _)
+                | ("output_dir", _, _) | ("texture_budget", _, _) | ("half_buffer", _, _) | ("low_priority", _, _) | ("stamp", _, _) | ("max_time", _, _)
+                | ("max_samples", _, _) => {
+                return Err(bad_value())
+            }
+            _ => warnings.push((line, format!("line {}: unknown setting '{}' is ignored", line, key)))
+        }
+    }
+    bindings.sort_by_key(|(line, _, _)| *line);
+    warnings.sort_by_key(|(line, _)| *line);
+    config.key_bindings = bindings.into_iter().map(|(_, key, binding)| (key, binding)).collect();
+    config.warnings = warnings.into_iter().map(|(_, warning)| warning).collect();
+    Ok(config)
+}
+
+// Line of a key in a section ("" for the top level), from 1, for messages. TOML tables don't keep where their
+// keys were, so the file is searched for the first line that sets it.
+fn key_line(text: &str, section: &str, key: &str) -> usize {
+    let mut current = String::new();
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            current = line.trim_matches(|c| c == '[' || c == ']').trim().to_string();
+            if current == key && section.is_empty() {
+                return idx + 1
+            }
+            continue;
+        }
+        let name = line.split('=').next().unwrap_or("").trim().trim_matches(|c| c == '"' || c == '\'');
+        if current == section && name == key && line.contains('=') {
+            return idx + 1
+        }
+    }
+    1
+}
+
+// A resolution written as <width>x<height>, e.g. 3840x2160, of at most MAX_RESOLUTION along either side.
+pub fn parse_resolution(text: &str) -> Option<(u32, u32)> {
+    let (width, height) = text.trim().split_once(['x', 'X'])?;
+    let (width, height): (u32, u32) = (width.parse().ok()?, height.parse().ok()?);
+    let valid = |size: u32| (1..=MAX_RESOLUTION).contains(&size);
+    if valid(width) && valid(height) {Some((width, height))} else {None}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_reads_settings_bindings_and_warns_about_the_rest() {
+        let text = "# Defaults\nsamples = 64\nresolution = \"3840x2160\"\ncolor_space = \"acescg\" # for grading\nmax_time = 1_800\nhalf_buffer = true\n\
+            width = 3840\nshininess = 3\n\n[keys]\nP = \"F12\"\n\"Space\" = \"R\"\n";
+        let config = parse("rs-raytracer.toml", text).unwrap();
+        assert_eq!(config.samples_per_pixel, Some(64));
+        assert_eq!(config.color_space, Some(ColorSpace::AcesCg));
+        assert_eq!(config.max_time, Some(Duration::from_secs(1800)));
+        assert_eq!(config.half_buffer, Some(true));
+        assert_eq!(config.resolution, Some((3840, 2160)));
+        assert_eq!(config.max_depth, None);
+        assert_eq!(config.key_bindings, vec![("P".to_string(), "F12".to_string()), ("Space".to_string(), "R".to_string())]);
+        assert_eq!(config.warnings.len(), 2);
+        assert!(config.warnings[0].contains("resolution"));
+
+        assert_eq!(parse_resolution("1920X1080"), Some((1920, 1080)));
+        assert_eq!(parse_resolution("0x720"), None);
+        assert_eq!(parse_resolution("1280"), None);
+
+        for bad in ["samples = \"many\"", "resolution = \"big\"", "filter = \"lanczos\"", "samples", "output_dir = \"renders", "[keys\n", "max_time = 1e30", "texture_budget = 1e30"].iter() {
+            match parse("rs-raytracer.toml", bad) {
+                Err(RaytracerError::InvalidConfig{line, ..}) => assert_eq!(line, 1),
+                _ => panic!("'{}' should not parse", bad)
+            }
+        }
+
+        // Any valid TOML is read, e.g. literal strings, escapes, and inline comments, and errors name their line.
+        let config = parse("rs-raytracer.toml", "output_dir = 'C:\\renders' # literal\n[keys]\n\"P\" = \"F\\u0031\\u0032\"\n").unwrap();
+        assert_eq!(config.output_directory.as_deref(), Some("C:\\renders"));
+        assert_eq!(config.key_bindings, vec![("P".to_string(), "F12".to_string())]);
+        match parse("rs-raytracer.toml", "samples = 8\n\ndepth = true\n") {
+            Err(RaytracerError::InvalidConfig{line, ..}) => assert_eq!(line, 3),
+            _ => panic!("a boolean depth should not parse")
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// --------------------------------------------------
+// RaytracerError
+// --------------------------------------------------
+#[derive(Debug)]
+pub enum RaytracerError {
+    Io(io::Error), // Reading or writing a file failed.
+    Sdl(String), // Any error reported by SDL.
+    InvalidArgument(String), // Bad command line argument.
+    InvalidImage { path: String, reason: String }, // An image file could not be parsed.
+    InvalidScene { path: String, reason: String }, // A scene or geometry file could not be parsed.
+    SizeMismatch { expected: (u32, u32), actual: (u32, u32) }, // Image, buffer, or texture dimensions differ from the ray tracer's.
+    InvalidMaterial(u32), // Material index that was never added.
+    MaterialType { idx: u32, expected: &'static str }, // Material of another type than asked for.
+    UnknownMaterial(String), // Material name that was never added.
+    InvalidCamera(u32), // Named camera index that was never added.
+    InvalidObject(u32), // Object id (as numbered by the ID passes) that doesn't exist.
+    InvalidNode(u32), // Transform node that was never added.
+    NodeCycle { node: u32, parent: u32 }, // Parenting a node to itself or one of its descendants.
+    Watch(String), // The scene file watcher could not be set up.
+    Clipboard(String), // The system clipboard could not be opened or written to.
+    InvalidBatch { path: String, line: usize, reason: String }, // A batch file could not be parsed.
+    InvalidConfig { path: String, line: usize, reason: String }, // A config file could not be parsed.
+    BatchFailed { failed: usize, total: usize }, // Jobs of a batch render failed. The others were rendered.
+    FurnaceFailed { failed: usize, total: usize }, // Materials gained or lost light in the furnace test.
+    SaveInProgress(String) // A save was started while files were still being written in the background.
+}
+
+impl fmt::Display for RaytracerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RaytracerError::Io(e) => write!(f, "I/O error: {}", e),
+            RaytracerError::Sdl(e) => write!(f, "SDL error: {}", e),
+            RaytracerError::InvalidArgument(e) => write!(f, "Invalid argument: {}", e),
+            RaytracerError::InvalidImage{path, reason} => write!(f, "Invalid image {}: {}", path, reason),
+            RaytracerError::InvalidScene{path, reason} => write!(f, "Invalid scene file {}: {}", path, reason),
+            RaytracerError::SizeMismatch{expected, actual} => write!(
+                f, "Size mismatch: expected {}x{} but got {}x{}", expected.0, expected.1, actual.0, actual.1
+            ),
+            RaytracerError::InvalidMaterial(idx) => write!(f, "Material {} does not exist", idx),
+            RaytracerError::MaterialType{idx, expected} => write!(f, "Material {} is not a {}", idx, expected),
+            RaytracerError::UnknownMaterial(name) => write!(f, "Material '{}' does not exist", name),
+            RaytracerError::InvalidCamera(idx) => write!(f, "Camera {} does not exist", idx),
+            RaytracerError::InvalidObject(idx) => write!(f, "Object {} does not exist", idx),
+            RaytracerError::InvalidNode(idx) => write!(f, "Transform node {} does not exist", idx),
+            RaytracerError::NodeCycle{node, parent} => write!(f, "Cannot parent node {} to {}, which is below it", node, parent),
+            RaytracerError::Watch(e) => write!(f, "Cannot watch scene file: {}", e),
+            RaytracerError::Clipboard(e) => write!(f, "Clipboard error: {}", e),
+            RaytracerError::InvalidBatch{path, line, reason} => write!(f, "Invalid batch file {} (line {}): {}", path, line, reason),
+            RaytracerError::InvalidConfig{path, line, reason} => write!(f, "Invalid config file {} (line {}): {}", path, line, reason),
+            RaytracerError::BatchFailed{failed, total} => write!(f, "{} of {} batch jobs failed", failed, total),
+            RaytracerError::FurnaceFailed{failed, total} => write!(f, "{} of {} materials failed the furnace test", failed, total),
+            RaytracerError::SaveInProgress(paths) => write!(f, "Still writing {}", paths)
+        }
+    }
+}
+
+impl Error for RaytracerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RaytracerError::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for RaytracerError {
+    fn from(e: io::Error) -> RaytracerError {
+        RaytracerError::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, RaytracerError>;
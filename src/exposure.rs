@@ -0,0 +1,207 @@
+use std::sync::Arc;
+
+use crate::color;
+use crate::image::Image;
+use crate::math::*;
+
+// --------------------------------------------------
+// Physical exposure
+// --------------------------------------------------
+// Exposes the image as a camera with an f-stop, shutter speed, and ISO would, so that scenes lit in physical units
+// (luminance in cd/m², with a scene unit of one meter) come out as bright as a photograph taken with the same
+// settings would. The f-stop also sets the size of the lens, so the depth of field follows it too.
+// https://seblagarde.files.wordpress.com/2015/07/course_notes_moving_frostbite_to_pbr_v32.pdf
+
+// Height of the sensor the field of view is measured across, in meters. That of a full frame (35mm) camera.
+const SENSOR_HEIGHT: Float = 0.024;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PhysicalExposure {
+    pub f_stop: Float, // Focal length over the aperture's diameter.
+    pub shutter: Float, // In seconds.
+    pub iso: Float // Sensitivity of the film or sensor.
+}
+
+impl Default for PhysicalExposure {
+    fn default() -> PhysicalExposure {
+        PhysicalExposure::new()
+    }
+}
+
+impl PhysicalExposure {
+    // Sunny 16: bright daylight is well exposed at f/16 with a shutter of 1/ISO.
+    pub fn new() -> PhysicalExposure {
+        PhysicalExposure {
+            f_stop: 16.0,
+            shutter: 1.0 / 100.0,
+            iso: 100.0
+        }
+    }
+
+    // Exposure value at ISO 100. Each step up halves the light that reaches the image.
+    pub fn ev100(&self) -> Float {
+        ((self.f_stop * self.f_stop) / self.shutter * 100.0 / self.iso).log2()
+    }
+
+    // Factor on the scene's luminance. The luminance that just reaches white, by the saturation based ISO
+    // standard, ends up at 1.
+    pub fn gain(&self) -> Float {
+        1.0 / (1.2 * self.ev100().exp2())
+    }
+
+    // Of the lens of a camera with the vertical field of view in degrees, in meters. Its focal length is that
+    // which gives the field of view across a full frame sensor.
+    pub fn lens_radius(&self, vertical_fov: Float) -> Float {
+        let focal_length = SENSOR_HEIGHT * 0.5 / (vertical_fov.to_radians() * 0.5).tan();
+        focal_length / self.f_stop * 0.5
+    }
+}
+
+// Shape of the lens's opening, which out of focus highlights (bokeh) take on.
+#[derive(Clone)]
+pub enum Aperture {
+    Circle, // Fully open.
+    Blades{count: u32, rotation: Float}, // Stopped down by straight blades into a polygon, turned by an angle in degrees.
+    Mask(Arc<ApertureMask>) // Lets through the share given by an image's luminance, over the square around the lens.
+}
+
+// Image of an aperture, with the luminance of its pixels summed in order so that points can be picked in
+// proportion to the light they let through, however little of the mask is light.
+pub struct ApertureMask {
+    width: u32,
+    height: u32,
+    sums: Vec<Float> // Of the luminance of every pixel up to each, in rows from the top, clamped to [0, 1].
+}
+
+impl ApertureMask {
+    // None if the mask lets no light through.
+    pub fn new(image: &Image) -> Option<ApertureMask> {
+        let mut sum = 0.0;
+        let sums: Vec<Float> = image.pixels.chunks(3).map(|rgb| {
+            // Taking the larger with zero first also turns NaN into zero.
+            sum += color::luminance(&Vec3::new(rgb[0] as Float, rgb[1] as Float, rgb[2] as Float)).clamp(0.0, 1.0);
+            sum
+        }).collect();
+        if sum <= 0.0 {
+            return None
+        }
+        Some(ApertureMask {
+            width: image.width,
+            height: image.height,
+            sums: sums
+        })
+    }
+
+    // Point of the square [-1, 1] with y up.
+    fn sample(&self, rand_gen: &mut RandGen) -> (Float, Float) {
+        // Dark pixels add nothing to the sum, so the first pixel whose sum passes the target is a lit one.
+        let target = rand_gen.next01() * self.sums[self.sums.len() - 1];
+        let idx = self.sums.partition_point(|sum| *sum <= target).min(self.sums.len() - 1) as u32;
+        let u = ((idx % self.width) as Float + rand_gen.next01()) / (self.width as Float);
+        let v = ((idx / self.width) as Float + rand_gen.next01()) / (self.height as Float);
+        (u * 2.0 - 1.0, 1.0 - v * 2.0)
+    }
+}
+
+impl Aperture {
+    // Whether the opening is anything but round.
+    pub fn is_shaped(&self) -> bool {
+        !matches!(self, Aperture::Circle)
+    }
+
+    // Point of the opening, on a lens of radius 1, with every point equally likely.
+    pub fn sample(&self, rand_gen: &mut RandGen) -> (Float, Float) {
+        match self {
+            Aperture::Circle => {
+                let radius = rand_gen.next01().sqrt();
+                let (sin, cos) = (2.0 * consts::PI * rand_gen.next01()).sin_cos();
+                (radius * cos, radius * sin)
+            }
+            Aperture::Blades{count, rotation} => {
+                // A corner is at the top when not turned. Each blade's edge closes off a triangle of the polygon
+                // with the center, and they are all the same size.
+                let count = (*count).max(3);
+                let blade = ((rand_gen.next01() * count as Float) as u32).min(count - 1);
+                let corner = |idx: u32| {
+                    let angle = rotation.to_radians() + consts::PI * 0.5 + 2.0 * consts::PI * (idx as Float) / (count as Float);
+                    (angle.cos(), angle.sin())
+                };
+                let (a, b) = (corner(blade), corner(blade + 1));
+                let (along, across) = (rand_gen.next01().sqrt(), rand_gen.next01());
+                (along * (a.0 + (b.0 - a.0) * across), along * (a.1 + (b.1 - a.1) * across))
+            }
+            Aperture::Mask(mask) => mask.sample(rand_gen)
+        }
+    }
+}
+
+// A shutter speed in seconds, written as a number of seconds or as a fraction such as 1/125.
+pub fn parse_shutter(text: &str) -> Option<Float> {
+    let seconds = match text.trim().split_once('/') {
+        Some((numerator, denominator)) => numerator.trim().parse::<Float>().ok()? / denominator.trim().parse::<Float>().ok()?,
+        None => text.trim().parse().ok()?
+    };
+    if seconds.is_finite() && seconds > 0.0 {Some(seconds)} else {None}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposure_follows_the_camera_settings() {
+        // Sunny 16 at ISO 100 is about EV 14.6.
+        let sunny = PhysicalExposure::new();
+        assert!((sunny.ev100() - 14.64).abs() < 0.01);
+
+        // Opening up a stop, doubling the shutter time, or doubling the ISO each doubles the image's brightness.
+        let gain = sunny.gain();
+        let brighter = [
+            PhysicalExposure{f_stop: 16.0 / (2.0 as Float).sqrt(), ..sunny},
+            PhysicalExposure{shutter: 2.0 / 100.0, ..sunny},
+            PhysicalExposure{iso: 200.0, ..sunny}
+        ];
+        for exposure in brighter.iter() {
+            assert!((exposure.gain() / gain - 2.0).abs() < 1e-3);
+        }
+
+        // A 50mm lens (about 27 degrees across full frame) at f/2 is 25mm across.
+        let portrait = PhysicalExposure{f_stop: 2.0, ..sunny};
+        assert!((portrait.lens_radius(26.99) - 0.0125).abs() < 1e-5);
+
+        assert_eq!(parse_shutter("1/125"), Some(1.0 / 125.0));
+        assert_eq!(parse_shutter("2"), Some(2.0));
+        assert_eq!(parse_shutter("1/0"), None);
+        assert_eq!(parse_shutter("fast"), None);
+    }
+
+    #[test]
+    fn apertures_keep_to_their_shape() {
+        let mut rand_gen = RandGen::seeded(3);
+        let mut inside = |aperture: &Aperture, test: &dyn Fn(Float, Float) -> bool| (0..2000).all(|_| {
+            let (x, y) = aperture.sample(&mut rand_gen);
+            test(x, y)
+        });
+        assert!(inside(&Aperture::Circle, &|x, y| x * x + y * y <= 1.0));
+
+        // A square turned by 45 degrees has its sides along the axes, half the diagonal out.
+        let square = Aperture::Blades{count: 4, rotation: 45.0};
+        let half = (0.5 as Float).sqrt() + 1e-4;
+        assert!(inside(&square, &|x, y| x.abs() <= half && y.abs() <= half));
+        // Five blades leave a corner at the top, and a flat edge at the bottom.
+        let bottom = -(consts::PI / 5.0).cos() - 1e-4;
+        assert!(inside(&Aperture::Blades{count: 5, rotation: 0.0}, &|_, y| y >= bottom && y <= 1.0));
+
+        // Masks only let points through where they are light: here only the right half.
+        let mut image = Image{width: 2, height: 1, pixels: vec![0.0, 0.0, 0.0, 1.0, 1.0, 1.0], alpha: Vec::new()};
+        assert!(inside(&Aperture::Mask(Arc::new(ApertureMask::new(&image).unwrap())), &|x, _| x >= 0.0));
+
+        // However small the lit part, every point is in it rather than at the lens's center.
+        image = Image{width: 16, height: 16, pixels: vec![0.0; 16 * 16 * 3], alpha: Vec::new()};
+        image.pixels[(16 * 15 + 15) * 3..].copy_from_slice(&[0.1, 0.1, 0.1]);
+        let corner = Aperture::Mask(Arc::new(ApertureMask::new(&image).unwrap()));
+        assert!(inside(&corner, &|x, y| x >= 0.875 && y <= -0.875));
+        image.pixels = vec![0.0; 16 * 16 * 3];
+        assert!(ApertureMask::new(&image).is_none());
+    }
+}
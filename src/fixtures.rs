@@ -0,0 +1,36 @@
+use std::fs;
+use std::path::PathBuf;
+
+// --------------------------------------------------
+// Test fixtures
+// --------------------------------------------------
+// Files for the loaders' tests to read, written to a directory of the test's own under the temp directory.
+// The directory and everything in it are removed when the fixture is dropped at the end of the test, even
+// when the test fails.
+pub struct Fixture {
+    directory: PathBuf
+}
+
+impl Fixture {
+    // The name should be unique to the test, as tests run in parallel.
+    pub fn new(name: &str) -> Fixture {
+        let directory = std::env::temp_dir().join(format!("rs_raytracer_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        Fixture {
+            directory: directory
+        }
+    }
+
+    // Writes a file to the directory and returns its path.
+    pub fn write<T: AsRef<[u8]>>(&self, file: &str, data: T) -> String {
+        let path = self.directory.join(file);
+        fs::write(&path, data).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.directory);
+    }
+}
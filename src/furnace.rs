@@ -0,0 +1,118 @@
+use std::fmt;
+
+use crate::error::{RaytracerError, Result};
+use crate::math::*;
+use crate::presets;
+use crate::raytracer::{CoatedMaterial, Dielectric, Lambertian, Material, Metal, MixMaterial, Region, RSRaytracer, WIDTH, HEIGHT};
+use crate::scene::Scene;
+
+// --------------------------------------------------
+// Furnace test
+// --------------------------------------------------
+// Checks that a material neither loses nor makes up light, by rendering a sphere of it in the white furnace
+// (see presets::furnace_test) and comparing it to what it should show. A sphere can't see itself, so
+// anything that isn't absorbed escapes to the white background: a diffuse sphere shows its albedo, and
+// clear glass disappears. Only pixels fully covered by the sphere in a row across its middle are measured,
+// which covers every angle from head-on to grazing.
+pub const SAMPLES_PER_PIXEL: u32 = 64;
+pub const TOLERANCE: Float = 0.01;
+// Materials that pick between paths at random (e.g. a wavelength or a layer) stay noisy at the mean.
+pub const NOISY_TOLERANCE: Float = 0.05;
+
+pub struct FurnaceReport {
+    pub name: String,
+    pub expected: Vec3,
+    pub measured: Vec3, // Mean over the measured pixels.
+    pub pixels: usize,
+    pub tolerance: Float // Largest error that passes.
+}
+
+impl FurnaceReport {
+    // Largest difference of a channel from what was expected.
+    pub fn error(&self) -> Float {
+        let difference = self.measured - self.expected;
+        difference.x.abs().max(difference.y.abs()).max(difference.z.abs())
+    }
+
+    pub fn passes(&self) -> bool {
+        self.pixels > 0 && self.error() <= self.tolerance
+    }
+}
+
+impl fmt::Display for FurnaceReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let gain = if self.measured.x + self.measured.y + self.measured.z > self.expected.x + self.expected.y + self.expected.z {"gains"} else {"loses"};
+        write!(f, "{}: expected ({:.3}, {:.3}, {:.3}), measured ({:.3}, {:.3}, {:.3}) over {} pixels, {} up to {:.4}",
+            self.name, self.expected.x, self.expected.y, self.expected.z, self.measured.x, self.measured.y, self.measured.z,
+            self.pixels, gain, self.error())
+    }
+}
+
+// Renders a sphere of the material in the furnace.
+pub fn run<M: Material + 'static>(name: &str, material: M, expected: Vec3, samples_per_pixel: u32) -> Result<FurnaceReport> {
+    let mut scene = Scene::new();
+    presets::furnace_test(&mut scene, 1.0)?;
+    let mat_id = scene.get_material_by_name(presets::FURNACE_MATERIAL).ok_or(RaytracerError::UnknownMaterial(presets::FURNACE_MATERIAL.to_string()))?;
+    scene.set_material(mat_id, material)?;
+
+    let mut ray_tracer = RSRaytracer::new();
+    ray_tracer.get_settings_mut().samples_per_pixel = samples_per_pixel;
+    ray_tracer.get_settings_mut().region = Some(Region::new(0, HEIGHT / 2, WIDTH, 1));
+    ray_tracer.run(&scene);
+
+    let mut sum = Vec3::zero();
+    let mut pixels = 0;
+    for x in 0..WIDTH {
+        let info = match ray_tracer.pixel_info(x, HEIGHT / 2) {
+            Some(info) if info.alpha >= 1.0 => info,
+            _ => continue
+        };
+        sum += info.linear;
+        pixels += 1;
+    }
+    Ok(FurnaceReport {
+        name: name.to_string(),
+        expected: expected,
+        measured: sum / (pixels.max(1) as Float),
+        pixels: pixels,
+        tolerance: TOLERANCE
+    })
+}
+
+// The built-in materials whose result in the furnace is known. Rough metals and PBR materials are left out,
+// as their microfacet models lose the light of reflections that would go below the surface.
+pub fn run_standard(samples_per_pixel: u32) -> Result<Vec<FurnaceReport>> {
+    let white = Vec3::one();
+    let gray = Vec3::one() * 0.5;
+    let tinted = Vec3::new(0.9, 0.6, 0.3);
+    let noisy = |report: Result<FurnaceReport>| report.map(|report| FurnaceReport {tolerance: NOISY_TOLERANCE, ..report});
+    Ok(vec![
+        run("white lambertian", Lambertian::new(white), white, samples_per_pixel)?,
+        run("tinted lambertian", Lambertian::new(tinted), tinted, samples_per_pixel)?,
+        run("mirror", Metal::new(tinted, 0.0), tinted, samples_per_pixel)?,
+        run("glass", Dielectric::new(1.5), white, samples_per_pixel)?,
+        noisy(run("dispersive glass", Dielectric::cauchy(1.728, 0.0134), white, samples_per_pixel))?,
+        run("coated white", CoatedMaterial::new(Lambertian::new(white), 1.5, 0.0), white, samples_per_pixel)?,
+        noisy(run("gray mix", MixMaterial::new(Lambertian::new(white), Lambertian::new(Vec3::zero()), 0.5), gray, samples_per_pixel))?
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standard_materials_keep_energy_in_the_furnace() {
+        // Materials that scatter the same way every time are exact, and the others are within their noise.
+        for report in run_standard(32).unwrap() {
+            assert!(report.pixels > 300, "{}", report);
+            assert!(report.passes(), "{}", report);
+            assert!(report.tolerance > TOLERANCE || report.error() < 1e-4, "{}", report);
+        }
+
+        // A material that absorbs more than it should is caught.
+        let report = run("dark lambertian", Lambertian::new(Vec3::one() * 0.9), Vec3::one(), 1).unwrap();
+        assert!(!report.passes() && (report.error() - 0.1).abs() < 1e-4);
+        assert!(report.to_string().contains("loses"));
+    }
+}
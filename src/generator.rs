@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+
+use log::{info, warn};
+
+use crate::error::{RaytracerError, Result};
+use crate::math::*;
+use crate::primitives::Plane;
+use crate::raytracer::{Camera, Dielectric, Lambertian, Metal, Sphere, WIDTH, HEIGHT};
+use crate::scene::Scene;
+
+// --------------------------------------------------
+// Scene generator
+// --------------------------------------------------
+// Random spheres resting on a ground plane, like the default scene but with the number of spheres, their
+// sizes and materials, and how they are laid out chosen, e.g. to stress the BVH or compare samplers on
+// scenes of different sizes. The same settings and seed always give the same scene.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Placement {
+    Grid, // Evenly spaced rows and columns.
+    Spiral, // A sunflower spiral, denser in the middle than a grid is at its edges.
+    Uniform, // Anywhere, so spheres may overlap and cluster.
+    PoissonDisk // Random but apart from each other, by rejecting spheres too close to those already placed.
+}
+
+impl Placement {
+    pub fn from_name(name: &str) -> Option<Placement> {
+        match name {
+            "grid" => Some(Placement::Grid),
+            "spiral" => Some(Placement::Spiral),
+            "uniform" => Some(Placement::Uniform),
+            "poisson" => Some(Placement::PoissonDisk),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SizeDistribution {
+    Uniform, // Radii spread evenly between the smallest and largest.
+    LogUniform // Many small spheres and few large ones, as in scenes of very uneven detail.
+}
+
+#[derive(Clone, Debug)]
+pub struct GeneratorSettings {
+    pub count: u32,
+    pub placement: Placement,
+    pub extent: Float, // Half the width of the square the spheres are placed in, around the origin.
+    pub min_radius: Float,
+    pub max_radius: Float,
+    pub sizes: SizeDistribution,
+    pub material_weights: [Float; 3], // Relative chances of diffuse, metal, and glass spheres.
+    pub seed: u64
+}
+
+impl Default for GeneratorSettings {
+    fn default() -> GeneratorSettings {
+        GeneratorSettings::new()
+    }
+}
+
+impl GeneratorSettings {
+    // As many spheres as the default scene, with its sizes and materials.
+    pub fn new() -> GeneratorSettings {
+        GeneratorSettings {
+            count: 484,
+            placement: Placement::Grid,
+            extent: 11.0,
+            min_radius: 0.2,
+            max_radius: 0.2,
+            sizes: SizeDistribution::Uniform,
+            material_weights: [0.8, 0.15, 0.05],
+            seed: 0
+        }
+    }
+
+    // Parses comma separated key=value settings on top of the defaults, e.g. from the command line:
+    // "count=10000,placement=poisson,radius=0.05-0.5,sizes=log,materials=1:1:0,seed=7". Keys are count,
+    // placement (grid, spiral, uniform, or poisson), extent, radius (one value, or the smallest and largest),
+    // sizes (uniform or log), materials (weights of diffuse, metal, and glass), and seed.
+    pub fn parse(spec: &str) -> Result<GeneratorSettings> {
+        let mut settings = GeneratorSettings::new();
+        for token in spec.split(',').map(|token| token.trim()).filter(|token| !token.is_empty()) {
+            let (key, value) = match token.find('=') {
+                Some(split) => (&token[..split], &token[(split + 1)..]),
+                None => return Err(RaytracerError::InvalidArgument(format!("generator setting '{}' is not key=value", token)))
+            };
+            let bad_value = || RaytracerError::InvalidArgument(format!("bad generator value '{}' for {}", value, key));
+            let number = |text: &str| text.parse::<Float>().ok().filter(|n| n.is_finite() && *n >= 0.0).ok_or_else(bad_value);
+            match key {
+                "count" => settings.count = value.parse().map_err(|_| bad_value())?,
+                "placement" => settings.placement = Placement::from_name(value).ok_or_else(bad_value)?,
+                "extent" => settings.extent = number(value)?,
+                "radius" => {
+                    let (min, max) = match value.find('-') {
+                        Some(split) => (number(&value[..split])?, number(&value[(split + 1)..])?),
+                        None => (number(value)?, number(value)?)
+                    };
+                    if min <= 0.0 || min > max {
+                        return Err(bad_value())
+                    }
+                    settings.min_radius = min;
+                    settings.max_radius = max;
+                }
+                "sizes" => {
+                    settings.sizes = match value {
+                        "uniform" => SizeDistribution::Uniform,
+                        "log" => SizeDistribution::LogUniform,
+                        _ => return Err(bad_value())
+                    };
+                }
+                "materials" => {
+                    let weights = value.split(':').map(number).collect::<Result<Vec<Float>>>()?;
+                    if weights.len() != 3 || weights.iter().sum::<Float>() <= 0.0 {
+                        return Err(bad_value())
+                    }
+                    settings.material_weights = [weights[0], weights[1], weights[2]];
+                }
+                "seed" => settings.seed = value.parse().map_err(|_| bad_value())?,
+                _ => return Err(RaytracerError::InvalidArgument(format!("unknown generator setting '{}'", key)))
+            }
+        }
+        Ok(settings)
+    }
+
+    // For logs and the stamp, e.g. "Generated 10000 spheres (poisson)".
+    pub fn describe(&self) -> String {
+        let placement = match self.placement {
+            Placement::Grid => "grid",
+            Placement::Spiral => "spiral",
+            Placement::Uniform => "uniform",
+            Placement::PoissonDisk => "poisson"
+        };
+        format!("Generated {} spheres ({})", self.count, placement)
+    }
+}
+
+// Adds a ground plane and the spheres to the scene, and frames them with the camera. Returns the number of
+// spheres added, which is fewer than asked for when Poisson disk placement runs out of room.
+pub fn generate(scene: &mut Scene, settings: &GeneratorSettings) -> Result<u32> {
+    let mut rand_gen = RandGen::seeded(settings.seed);
+    let ground_mat = scene.add_lambertian_material(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+    scene.add_plane(Plane::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), ground_mat))?;
+
+    let radii: Vec<Float> = (0..settings.count).map(|_| random_radius(settings, &mut rand_gen)).collect();
+    let spheres: Vec<(Float, Float, Float)> = match settings.placement {
+        Placement::Grid => with_radii(grid(settings), &radii),
+        Placement::Spiral => with_radii(spiral(settings), &radii),
+        Placement::Uniform => with_radii((0..settings.count).map(|_| random_position(settings, &mut rand_gen)).collect(), &radii),
+        Placement::PoissonDisk => poisson_disk(settings, &radii, &mut rand_gen)
+    };
+    if spheres.len() < radii.len() {
+        warn!(target: "scene", "Only found room for {} of {} spheres. Increase the extent or make them smaller.", spheres.len(), radii.len());
+    }
+
+    let total_weight: Float = settings.material_weights.iter().sum();
+    for (x, z, radius) in spheres.iter() {
+        let center = Vec3::new(*x, *radius, *z);
+        let pick = rand_gen.next01() * total_weight;
+        let mat = if pick < settings.material_weights[0] {
+            let albedo = random_color(&mut rand_gen) * random_color(&mut rand_gen);
+            scene.add_lambertian_material(Lambertian::new(albedo))
+        } else if pick < settings.material_weights[0] + settings.material_weights[1] {
+            let albedo = random_color(&mut rand_gen) * 0.5 + Vec3::one() * 0.5;
+            scene.add_metal_material(Metal::new(albedo, rand_gen.next_range(0.0..0.5)))
+        } else {
+            scene.add_dielectric_material(Dielectric::new(1.5))
+        };
+        scene.add_sphere(Sphere::new(center, *radius, mat))?;
+    }
+
+    // The default scene's view, scaled to the extent.
+    let scale = settings.extent.max(settings.max_radius) / 11.0;
+    let aspect_ratio = (WIDTH as Float) / (HEIGHT as Float);
+    scene.set_camera(Camera::new(Vec3::new(13.0, 2.0, 3.0) * scale, Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 20.0, aspect_ratio));
+    info!(target: "scene", "{} with seed {}.", settings.describe(), settings.seed);
+    Ok(spheres.len() as u32)
+}
+
+// Spheres as (x, z, radius).
+fn with_radii(positions: Vec<(Float, Float)>, radii: &[Float]) -> Vec<(Float, Float, Float)> {
+    positions.into_iter().zip(radii.iter()).map(|((x, z), radius)| (x, z, *radius)).collect()
+}
+
+fn random_radius(settings: &GeneratorSettings, rand_gen: &mut RandGen) -> Float {
+    if settings.max_radius <= settings.min_radius {
+        return settings.min_radius
+    }
+    let t = rand_gen.next01();
+    match settings.sizes {
+        SizeDistribution::Uniform => settings.min_radius + (settings.max_radius - settings.min_radius) * t,
+        SizeDistribution::LogUniform => settings.min_radius * (settings.max_radius / settings.min_radius).powf(t)
+    }
+}
+
+fn random_color(rand_gen: &mut RandGen) -> Vec3 {
+    Vec3::new(rand_gen.next01(), rand_gen.next01(), rand_gen.next01())
+}
+
+// On the ground, as (x, z).
+fn random_position(settings: &GeneratorSettings, rand_gen: &mut RandGen) -> (Float, Float) {
+    (rand_gen.next_range(-settings.extent..settings.extent), rand_gen.next_range(-settings.extent..settings.extent))
+}
+
+fn grid(settings: &GeneratorSettings) -> Vec<(Float, Float)> {
+    let columns = ((settings.count as Float).sqrt().ceil() as u32).max(1);
+    let spacing = settings.extent * 2.0 / (columns as Float);
+    (0..settings.count).map(|idx| {
+        let (column, row) = (idx % columns, idx / columns);
+        (-settings.extent + spacing * ((column as Float) + 0.5), -settings.extent + spacing * ((row as Float) + 0.5))
+    }).collect()
+}
+
+// Each sphere turned by the golden angle from the last and at a distance that keeps the density even.
+fn spiral(settings: &GeneratorSettings) -> Vec<(Float, Float)> {
+    let golden_angle = consts::PI * (3.0 - (5.0 as Float).sqrt());
+    (0..settings.count).map(|idx| {
+        let distance = settings.extent * (((idx as Float) + 0.5) / (settings.count as Float)).sqrt();
+        let angle = (idx as Float) * golden_angle;
+        (distance * angle.cos(), distance * angle.sin())
+    }).collect()
+}
+
+// Dart throwing: each sphere tries random positions until one is clear of the spheres placed so far, and is
+// dropped after too many tries. Placed spheres are kept in a grid of cells as wide as the largest sphere, so
+// each try only checks the cells around it.
+const POISSON_TRIES: u32 = 30;
+
+fn poisson_disk(settings: &GeneratorSettings, radii: &[Float], rand_gen: &mut RandGen) -> Vec<(Float, Float, Float)> {
+    let gap = settings.min_radius * 0.25;
+    let cell_size = settings.max_radius * 2.0 + gap;
+    let cell = |x: Float, z: Float| ((x / cell_size).floor() as i64, (z / cell_size).floor() as i64);
+    let mut cells: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
+    let mut placed: Vec<(Float, Float, Float)> = Vec::new();
+    for radius in radii.iter() {
+        for _ in 0..POISSON_TRIES {
+            let (x, z) = random_position(settings, rand_gen);
+            let (cx, cz) = cell(x, z);
+            let clear = (-1..=1).all(|dx| (-1..=1).all(|dz| {
+                cells.get(&(cx + dx, cz + dz)).is_none_or(|others| others.iter().all(|other| {
+                    let (ox, oz, oradius) = placed[*other];
+                    let distance = ((x - ox) * (x - ox) + (z - oz) * (z - oz)).sqrt();
+                    distance >= radius + oradius + gap
+                }))
+            }));
+            if clear {
+                cells.entry((cx, cz)).or_default().push(placed.len());
+                placed.push((x, z, *radius));
+                break;
+            }
+        }
+    }
+    placed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_scenes_follow_their_settings() {
+        let settings = GeneratorSettings::parse("count=300, placement=poisson, extent=6, radius=0.1-0.4, sizes=log, seed=3").unwrap();
+        assert_eq!((settings.count, settings.placement, settings.sizes), (300, Placement::PoissonDisk, SizeDistribution::LogUniform));
+        for bad in ["count", "count=-1", "placement=hex", "radius=0.5-0.1", "materials=1:1", "colour=red"].iter() {
+            assert!(GeneratorSettings::parse(bad).is_err(), "'{}' should not parse", bad);
+        }
+
+        // Poisson disk spheres don't touch, and the same seed places them the same way.
+        let mut scene = Scene::new();
+        let count = generate(&mut scene, &settings).unwrap();
+        assert!(count > 100 && count <= 300);
+        assert_eq!(scene.object_count(), (count as usize) + 1);
+        let mut rand_gen = RandGen::seeded(3);
+        let radii: Vec<Float> = (0..300).map(|_| random_radius(&settings, &mut rand_gen)).collect();
+        assert!(radii.iter().all(|r| *r >= 0.1 && *r <= 0.4) && radii[0] != radii[1]);
+        let placed = poisson_disk(&settings, &radii, &mut RandGen::seeded(3));
+        assert_eq!(placed, poisson_disk(&settings, &radii, &mut RandGen::seeded(3)));
+        for (a, (ax, az, ar)) in placed.iter().enumerate() {
+            for (bx, bz, br) in placed.iter().skip(a + 1) {
+                assert!(((ax - bx) * (ax - bx) + (az - bz) * (az - bz)).sqrt() >= ar + br);
+            }
+        }
+
+        // Grids and spirals place every sphere within the extent.
+        for placement in [Placement::Grid, Placement::Spiral].iter() {
+            let settings = GeneratorSettings{placement: *placement, count: 50, ..GeneratorSettings::new()};
+            let positions = if *placement == Placement::Grid {grid(&settings)} else {spiral(&settings)};
+            assert_eq!(positions.len(), 50);
+            assert!(positions.iter().all(|(x, z)| x.abs() <= settings.extent && z.abs() <= settings.extent));
+        }
+    }
+}
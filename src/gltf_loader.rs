@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::{info, warn};
+
+use crate::error::{RaytracerError, Result};
+use crate::image::{Image, Texture};
+use crate::math::*;
+use crate::primitives::Mesh;
+use crate::raytracer::{AlphaMode, Camera, PbrMaterial, WIDTH, HEIGHT};
+use crate::scene::{BackFace, Scene};
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {c / 12.92} else {((c + 0.055) / 1.055).powf(2.4)}
+}
+
+// glTF data is always single precision.
+fn vec3(v: &[f32; 3]) -> Vec3 {
+    Vec3::new(v[0] as Float, v[1] as Float, v[2] as Float)
+}
+
+// Converts decoded glTF image data to an RGB Image, keeping the alpha channel of RGBA images.
+// Color textures are stored in sRGB, but alpha is always linear.
+fn convert_image(data: &gltf::image::Data, srgb: bool) -> Image {
+    use gltf::image::Format;
+    let (channels, bytes_per_channel, swap_rb) = match data.format {
+        Format::R8 => (1, 1, false),
+        Format::R8G8 => (2, 1, false),
+        Format::R8G8B8 => (3, 1, false),
+        Format::R8G8B8A8 => (4, 1, false),
+        Format::B8G8R8 => (3, 1, true),
+        Format::B8G8R8A8 => (4, 1, true),
+        Format::R16 => (1, 2, false),
+        Format::R16G16 => (2, 2, false),
+        Format::R16G16B16 => (3, 2, false),
+        Format::R16G16B16A16 => (4, 2, false)
+    };
+    let raw = |offset: usize| -> f32 {
+        if bytes_per_channel == 1 {
+            (data.pixels[offset] as f32) / 255.0
+        } else {
+            (u16::from_le_bytes([data.pixels[offset], data.pixels[offset + 1]]) as f32) / 65535.0
+        }
+    };
+    let channel = |offset: usize| -> f32 {
+        if srgb {srgb_to_linear(raw(offset))} else {raw(offset)}
+    };
+
+    let count = (data.width * data.height) as usize;
+    let mut pixels = Vec::with_capacity(count * 3);
+    let mut alpha = Vec::with_capacity(if channels == 4 {count} else {0});
+    for i in 0..count {
+        let texel = i * channels * bytes_per_channel;
+        let mut rgb = match channels {
+            1 => {let r = channel(texel); [r, r, r]}
+            2 => [channel(texel), channel(texel + bytes_per_channel), 0.0],
+            _ => [channel(texel), channel(texel + bytes_per_channel), channel(texel + 2 * bytes_per_channel)]
+        };
+        if swap_rb {
+            rgb.swap(0, 2);
+        }
+        pixels.extend_from_slice(&rgb);
+        if channels == 4 {
+            alpha.push(raw(texel + 3 * bytes_per_channel));
+        }
+    }
+
+    Image {
+        width: data.width,
+        height: data.height,
+        pixels: pixels,
+        alpha: alpha
+    }
+}
+
+struct Loader<'a> {
+    path: &'a str,
+    buffers: Vec<gltf::buffer::Data>,
+    images: Vec<gltf::image::Data>,
+    materials: HashMap<usize, u32>, // glTF material index to ray tracer material index.
+    model_material: Option<u32>, // When only the meshes are loaded, the material they all use.
+    camera: Option<Camera>,
+    mesh_count: usize,
+    triangle_count: usize
+}
+
+impl<'a> Loader<'a> {
+    // Loaded through the scene's assets, once per image and color space, so that the materials using an image
+    // and later loads of the same file share it.
+    fn texture(&self, scene: &mut Scene, texture: gltf::Texture, srgb: bool) -> Arc<Texture> {
+        let idx = texture.source().index();
+        let part = format!("image{}{}", idx, if srgb {"_srgb"} else {""});
+        let assets = scene.get_assets_mut();
+        let handle = assets.load_embedded_texture(self.path, &part, || Texture::new(convert_image(&self.images[idx], srgb)));
+        assets.get_texture(handle).expect("texture was just loaded")
+    }
+
+    // Returns the ray tracer material for a glTF material, creating it on first use.
+    fn material(&mut self, scene: &mut Scene, material: &gltf::Material) -> u32 {
+        if let Some(mat_id) = self.model_material {
+            return mat_id
+        }
+        // Primitives without a material use the default one.
+        let idx = match material.index() {
+            Some(idx) => idx,
+            None => return 0
+        };
+        if let Some(mat_id) = self.materials.get(&idx) {
+            return *mat_id
+        }
+
+        let pbr = material.pbr_metallic_roughness();
+        let factor = pbr.base_color_factor();
+        let mut mat = PbrMaterial::new(vec3(&[factor[0], factor[1], factor[2]]), pbr.metallic_factor() as Float, pbr.roughness_factor() as Float);
+        if let Some(info) = pbr.base_color_texture() {
+            mat.base_color_texture = Some(self.texture(scene, info.texture(), true));
+        }
+        if let Some(info) = pbr.metallic_roughness_texture() {
+            mat.metallic_roughness_texture = Some(self.texture(scene, info.texture(), false));
+        }
+
+        // Alpha is the base color's, from its factor and the texture's alpha channel if it has one.
+        mat.alpha.mode = match material.alpha_mode() {
+            gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+            gltf::material::AlphaMode::Mask => AlphaMode::Mask(material.alpha_cutoff().unwrap_or(0.5) as Float),
+            gltf::material::AlphaMode::Blend => AlphaMode::Blend
+        };
+        mat.alpha.value = factor[3] as Float;
+        mat.alpha.texture = mat.base_color_texture.clone().filter(|texture| texture.image().has_alpha());
+
+        let mat_id = scene.add_pbr_material(mat);
+        // Single-sided materials are invisible from behind.
+        if !material.double_sided() {
+            scene.set_back_face(mat_id, BackFace::Cull).expect("material was just added");
+        }
+        self.materials.insert(idx, mat_id);
+        mat_id
+    }
+
+    fn load_node(&mut self, scene: &mut Scene, node: gltf::Node, parent: &Mat4) -> Result<()> {
+        let transform = *parent * Mat4::from_cols(&node.transform().matrix().map(|col| col.map(|v| v as Float)));
+
+        // Every perspective camera is added as a named camera, and the first one is used.
+        if let Some(camera) = node.camera().filter(|_| self.model_material.is_none()) {
+            match camera.projection() {
+                gltf::camera::Projection::Perspective(perspective) => {
+                    let look_from = transform.transform_point(Vec3::zero());
+                    let forward = transform.transform_dir(Vec3::new(0.0, 0.0, -1.0));
+                    let up = transform.transform_dir(Vec3::new(0.0, 1.0, 0.0));
+                    let vertical_fov = perspective.yfov().to_degrees() as Float;
+                    let cam = Camera::new(look_from, look_from + forward, up, vertical_fov, (WIDTH as Float) / (HEIGHT as Float));
+                    let name = camera.name().or(node.name()).map_or_else(|| format!("Camera {}", camera.index()), |name| name.to_string());
+                    scene.add_named_camera(&name, cam);
+                    if self.camera.is_none() {
+                        self.camera = Some(cam);
+                    }
+                }
+                gltf::camera::Projection::Orthographic(_) => {
+                    warn!(target: "scene", "Skipping orthographic camera in {}.", self.path);
+                }
+            }
+        }
+
+        // Meshes are named after their node, or the glTF mesh if the node has no name.
+        if let Some(mesh) = node.mesh() {
+            let name = node.name().or(mesh.name()).map_or_else(|| format!("Mesh {}", mesh.index()), |name| name.to_string());
+            for primitive in mesh.primitives() {
+                self.load_primitive(scene, &primitive, &transform, &name)?;
+            }
+        }
+
+        for child in node.children() {
+            self.load_node(scene, child, &transform)?;
+        }
+        Ok(())
+    }
+
+    fn load_primitive(&mut self, scene: &mut Scene, primitive: &gltf::Primitive, transform: &Mat4, name: &str) -> Result<()> {
+        if primitive.mode() != gltf::mesh::Mode::Triangles {
+            warn!(target: "scene", "Skipping non-triangle primitive in {}.", self.path);
+            return Ok(())
+        }
+
+        let material = primitive.material();
+        let mat_id = self.material(scene, &material);
+        let pbr = material.pbr_metallic_roughness();
+        let tex_coord = pbr.base_color_texture().map(|info| info.tex_coord())
+            .or_else(|| pbr.metallic_roughness_texture().map(|info| info.tex_coord()))
+            .unwrap_or(0);
+
+        let buffers = &self.buffers;
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+        let positions: Vec<Vec3> = match reader.read_positions() {
+            Some(positions) => positions.map(|p| transform.transform_point(vec3(&p))).collect(),
+            None => {
+                warn!(target: "scene", "Skipping primitive without positions in {}.", self.path);
+                return Ok(())
+            }
+        };
+        let normals: Vec<Vec3> = reader.read_normals().map_or(Vec::new(), |normals| {
+            normals.map(|n| transform.transform_normal(vec3(&n))).collect()
+        });
+        let uvs: Vec<(Float, Float)> = reader.read_tex_coords(tex_coord).map_or(Vec::new(), |uvs| {
+            uvs.into_f32().map(|uv| (uv[0] as Float, uv[1] as Float)).collect()
+        });
+        let indices: Vec<u32> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..positions.len() as u32).collect()
+        };
+
+        // Mirroring transforms flip the winding, so swap it back to keep front faces in front.
+        let mirrored = transform.determinant() < 0.0;
+        let triangles: Vec<[u32; 3]> = indices.chunks_exact(3)
+            .map(|tri| if mirrored {[tri[0], tri[2], tri[1]]} else {[tri[0], tri[1], tri[2]]})
+            .collect();
+
+        let mesh = Mesh::new(positions, normals, uvs, triangles, mat_id).map_err(|e| RaytracerError::InvalidScene {
+            path: self.path.to_string(),
+            reason: e.to_string()
+        })?;
+        self.mesh_count += 1;
+        self.triangle_count += mesh.triangle_count();
+        scene.add_mesh(mesh)?;
+        match scene.last_object_id() {
+            Some(object_id) => scene.set_object_name(object_id, name),
+            None => Ok(())
+        }
+    }
+}
+
+// Adds the meshes, materials, and perspective cameras of a .gltf or .glb file's default scene. The first camera becomes the active one.
+pub fn load(scene: &mut Scene, path: &str) -> Result<()> {
+    load_meshes(scene, path, None)
+}
+
+// Adds only the meshes of a .gltf or .glb file's default scene, all with mat_id, e.g. to drop a model into another scene.
+pub fn load_model(scene: &mut Scene, path: &str, mat_id: u32) -> Result<()> {
+    load_meshes(scene, path, Some(mat_id))
+}
+
+fn load_meshes(scene: &mut Scene, path: &str, model_material: Option<u32>) -> Result<()> {
+    info!(target: "io", "Reading glTF file {}...", path);
+    let (document, buffers, images) = gltf::import(path).map_err(|e| match e {
+        gltf::Error::Io(e) => RaytracerError::Io(e),
+        e => RaytracerError::InvalidScene {
+            path: path.to_string(),
+            reason: e.to_string()
+        }
+    })?;
+
+    let gltf_scene = document.default_scene().or_else(|| document.scenes().next()).ok_or_else(|| RaytracerError::InvalidScene {
+        path: path.to_string(),
+        reason: "no scenes".to_string()
+    })?;
+
+    let mut loader = Loader {
+        path: path,
+        buffers: buffers,
+        images: images,
+        materials: HashMap::new(),
+        model_material: model_material,
+        camera: None,
+        mesh_count: 0,
+        triangle_count: 0
+    };
+    for node in gltf_scene.nodes() {
+        loader.load_node(scene, node, &Mat4::identity())?;
+    }
+
+    if let Some(camera) = loader.camera {
+        scene.set_camera(camera);
+    }
+    info!(target: "scene", "Loaded {} meshes with {} triangles and {} materials from {}.",
+        loader.mesh_count, loader.triangle_count, loader.materials.len(), path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Fixture;
+
+    // One triangle in the XY plane, on a node moved 2 along Z, and a camera 5 back from the origin.
+    const TRIANGLE: &str = r#"{
+        "asset": {"version": "2.0"},
+        "scene": 0,
+        "scenes": [{"nodes": [0, 1]}],
+        "nodes": [
+            {"name": "Triangle", "mesh": 0, "translation": [0, 0, 2]},
+            {"camera": 0, "translation": [0, 0, 5]}
+        ],
+        "cameras": [{"name": "Front", "type": "perspective", "perspective": {"yfov": 0.8, "znear": 0.1}}],
+        "meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "material": 0}]}],
+        "materials": [{"pbrMetallicRoughness": {"baseColorFactor": [1, 0, 0, 1], "metallicFactor": 0}, "doubleSided": true}],
+        "buffers": [{"byteLength": 36, "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAA"}],
+        "bufferViews": [{"buffer": 0, "byteLength": 36}],
+        "accessors": [{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0]}]
+    }"#;
+
+    #[test]
+    fn gltf_scenes_load_meshes_materials_and_cameras() {
+        let files = Fixture::new("gltf_scenes");
+        let path = files.write("triangle.gltf", TRIANGLE);
+        let mut scene = Scene::new();
+        load(&mut scene, &path).unwrap();
+        assert_eq!(scene.object_count(), 1);
+        assert_eq!(scene.material_count(), 2);
+        assert!(scene.get_material_as::<PbrMaterial>(1).is_ok());
+        assert_eq!(scene.get_object_by_name("Triangle"), scene.last_object_id());
+        assert_eq!(scene.named_camera_count(), 1);
+        assert_eq!(scene.get_named_camera(0).unwrap().0, "Front");
+        assert!((scene.get_camera().get_position().z - 5.0).abs() < 1e-5);
+
+        // The node's translation is applied to the mesh.
+        let ray = Ray::new(Vec3::new(0.25, 0.25, 10.0), Vec3::new(0.0, 0.0, -1.0));
+        let hr = scene.hit(&ray, Interval::new(0.001, Float::MAX)).0.unwrap();
+        assert!((hr.t - 8.0).abs() < 1e-4);
+
+        // Models only bring their meshes, with the material they are given.
+        let mut model = Scene::new();
+        load_model(&mut model, &path, 0).unwrap();
+        assert_eq!((model.object_count(), model.material_count(), model.named_camera_count()), (1, 1, 0));
+    }
+
+    #[test]
+    fn malformed_gltf_files_are_scene_errors() {
+        let files = Fixture::new("malformed_gltf");
+        let mut scene = Scene::new();
+        let broken = files.write("broken.gltf", "{\"asset\": {\"version\": \"2.0\"}, \"nodes\": [");
+        assert!(matches!(load(&mut scene, &broken), Err(RaytracerError::InvalidScene{..})));
+        let missing_mesh = files.write("missing_mesh.gltf", TRIANGLE.replace("\"mesh\": 0", "\"mesh\": 3"));
+        assert!(matches!(load(&mut scene, &missing_mesh), Err(RaytracerError::InvalidScene{..})));
+        assert!(matches!(load(&mut scene, "no_such_file.gltf"), Err(RaytracerError::Io(_))));
+        assert_eq!(scene.object_count(), 0);
+    }
+}
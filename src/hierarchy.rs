@@ -0,0 +1,197 @@
+use std::sync::OnceLock;
+
+use crate::error::{RaytracerError, Result};
+use crate::math::*;
+
+// --------------------------------------------------
+// Transform hierarchy
+// --------------------------------------------------
+// Nodes with a transform relative to their parent, so that groups of objects can be moved, rotated, and
+// animated together. Objects are attached to nodes, and their geometry, as it was added, is moved by the
+// node's world transform. Nodes without objects (empties) only group their children.
+#[derive(Clone)]
+struct Node {
+    parent: Option<u32>,
+    local: Mat4, // Relative to the parent, or to the world for root nodes.
+    name: Option<String>
+}
+
+// World transform of a node, and its inverse for bringing rays into the space of the node's objects.
+#[derive(Clone, Copy)]
+pub struct NodeTransform {
+    pub to_world: Mat4,
+    pub to_local: Option<Mat4>, // None if the transform is singular (e.g. scaled to zero), which hides its objects.
+    pub is_identity: bool
+}
+
+pub struct TransformGraph {
+    nodes: Vec<Node>,
+    world: OnceLock<Vec<NodeTransform>> // Computed on demand. Cleared whenever a node changes.
+}
+
+impl Default for TransformGraph {
+    fn default() -> TransformGraph {
+        TransformGraph::new()
+    }
+}
+
+impl TransformGraph {
+    pub fn new() -> TransformGraph {
+        TransformGraph {
+            nodes: Vec::new(),
+            world: OnceLock::new()
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.world = OnceLock::new();
+    }
+
+    pub fn node_count(&self) -> u32 {
+        self.nodes.len() as u32
+    }
+
+    pub fn add_node(&mut self, name: Option<&str>, parent: Option<u32>, local: Mat4) -> Result<u32> {
+        if let Some(parent) = parent {
+            self.check(parent)?;
+        }
+        self.nodes.push(Node {
+            parent: parent,
+            local: local,
+            name: name.map(|name| name.to_string())
+        });
+        self.world = OnceLock::new();
+        Ok((self.nodes.len() - 1) as u32)
+    }
+
+    pub fn get_parent(&self, node: u32) -> Result<Option<u32>> {
+        self.check(node)?;
+        Ok(self.nodes[node as usize].parent)
+    }
+
+    // Keeps the node's local transform, so it moves with its new parent. Fails if the parent is the node or
+    // one of its descendants.
+    pub fn set_parent(&mut self, node: u32, parent: Option<u32>) -> Result<()> {
+        self.check(node)?;
+        if let Some(parent) = parent {
+            self.check(parent)?;
+            let mut ancestor = Some(parent);
+            while let Some(idx) = ancestor {
+                if idx == node {
+                    return Err(RaytracerError::NodeCycle{node: node, parent: parent})
+                }
+                ancestor = self.nodes[idx as usize].parent;
+            }
+        }
+        self.nodes[node as usize].parent = parent;
+        self.world = OnceLock::new();
+        Ok(())
+    }
+
+    pub fn get_local(&self, node: u32) -> Result<Mat4> {
+        self.check(node)?;
+        Ok(self.nodes[node as usize].local)
+    }
+
+    pub fn set_local(&mut self, node: u32, local: Mat4) -> Result<()> {
+        self.check(node)?;
+        self.nodes[node as usize].local = local;
+        self.world = OnceLock::new();
+        Ok(())
+    }
+
+    // Id of the first node with the name.
+    pub fn get_by_name(&self, name: &str) -> Option<u32> {
+        self.nodes.iter().position(|node| node.name.as_deref() == Some(name)).map(|idx| idx as u32)
+    }
+
+    pub fn world(&self, node: u32) -> Result<&NodeTransform> {
+        self.check(node)?;
+        Ok(&self.world.get_or_init(|| self.compute_world())[node as usize])
+    }
+
+    fn check(&self, node: u32) -> Result<()> {
+        if (node as usize) < self.nodes.len() {Ok(())} else {Err(RaytracerError::InvalidNode(node))}
+    }
+
+    // Parents can be added after their children, so nodes are resolved in any order, each once.
+    fn compute_world(&self) -> Vec<NodeTransform> {
+        let mut world: Vec<Option<Mat4>> = vec![None; self.nodes.len()];
+        for idx in 0..self.nodes.len() {
+            // Walk up to the nearest resolved ancestor, then back down.
+            let mut chain = vec![idx];
+            while let Some(parent) = self.nodes[*chain.last().unwrap()].parent {
+                if world[parent as usize].is_some() {
+                    break;
+                }
+                chain.push(parent as usize);
+            }
+            for node in chain.into_iter().rev() {
+                if world[node].is_some() {
+                    continue;
+                }
+                let parent = self.nodes[node].parent.and_then(|parent| world[parent as usize]);
+                world[node] = Some(match parent {
+                    Some(parent) => parent * self.nodes[node].local,
+                    None => self.nodes[node].local
+                });
+            }
+        }
+        world.into_iter().map(|to_world| {
+            let to_world = to_world.unwrap_or_else(Mat4::identity);
+            NodeTransform {
+                to_world: to_world,
+                to_local: to_world.inverse(),
+                is_identity: to_world.m == Mat4::identity().m
+            }
+        }).collect()
+    }
+}
+
+impl NodeTransform {
+    // Bounds of a box moved by the transform, from its corners.
+    pub fn transform_box(&self, aabb: &Aabb) -> Aabb {
+        let mut out = Aabb::empty();
+        for corner in 0..8 {
+            let p = Vec3::new(
+                if corner & 1 == 0 {aabb.min.x} else {aabb.max.x},
+                if corner & 2 == 0 {aabb.min.y} else {aabb.max.y},
+                if corner & 4 == 0 {aabb.min.z} else {aabb.max.z}
+            );
+            out = out.grow(self.to_world.transform_point(p));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn children_move_with_their_parents() {
+        let close = |a: Vec3, b: Vec3| (a - b).length() < 1e-4;
+        let mut graph = TransformGraph::new();
+        let wheel = graph.add_node(Some("wheel"), None, Mat4::translation(Vec3::new(1.0, 0.0, 0.0))).unwrap();
+        let car = graph.add_node(Some("car"), None, Mat4::translation(Vec3::new(0.0, 0.0, 5.0))).unwrap();
+        graph.set_parent(wheel, Some(car)).unwrap();
+        let origin = |graph: &TransformGraph, node: u32| graph.world(node).unwrap().to_world.transform_point(Vec3::zero());
+        assert!(close(origin(&graph, wheel), Vec3::new(1.0, 0.0, 5.0)));
+
+        // Turning the parent swings the child around it.
+        graph.set_local(car, Mat4::translation(Vec3::new(0.0, 0.0, 5.0)) * Mat4::rotation(consts::FRAC_PI_2, Vec3::new(0.0, 1.0, 0.0))).unwrap();
+        assert!(close(origin(&graph, wheel), Vec3::new(0.0, 0.0, 4.0)));
+        assert!(!graph.world(wheel).unwrap().is_identity);
+        assert_eq!(graph.get_by_name("car"), Some(car));
+
+        // Nodes can't become their own ancestors, and flattened ones can't be brought back.
+        match graph.set_parent(car, Some(wheel)) {
+            Err(RaytracerError::NodeCycle{node, parent}) => assert_eq!((node, parent), (car, wheel)),
+            _ => panic!("the car can't hang off its wheel")
+        }
+        assert!(graph.set_parent(car, Some(7)).is_err());
+        graph.set_local(car, Mat4::scale(Vec3::new(1.0, 0.0, 1.0))).unwrap();
+        assert!(graph.world(wheel).unwrap().to_local.is_none());
+    }
+}
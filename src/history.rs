@@ -0,0 +1,152 @@
+use crate::error::Result;
+use crate::raytracer::Camera;
+use crate::scene::Scene;
+
+// --------------------------------------------------
+// History
+// --------------------------------------------------
+// Undo and redo for interactive edits of a scene. Each edit is a Command that can apply itself to the
+// scene and take itself back again, so the history keeps the edits rather than copies of the scene.
+
+// Edits kept for undo. The oldest are forgotten first.
+const HISTORY_LIMIT: usize = 100;
+
+pub trait Command {
+    fn apply(&mut self, scene: &mut Scene) -> Result<()>;
+
+    // Restores the scene as it was before apply.
+    fn undo(&mut self, scene: &mut Scene) -> Result<()>;
+
+    // Short description for the log, e.g. "Change camera".
+    fn name(&self) -> &str;
+}
+
+// Replaces the camera. It holds whichever camera the scene doesn't, so applying and undoing both swap them.
+pub struct SetCamera {
+    camera: Camera
+}
+
+impl SetCamera {
+    pub fn new(camera: Camera) -> SetCamera {
+        SetCamera {
+            camera: camera
+        }
+    }
+}
+
+impl Command for SetCamera {
+    fn apply(&mut self, scene: &mut Scene) -> Result<()> {
+        let previous = *scene.get_camera();
+        scene.set_camera(self.camera);
+        self.camera = previous;
+        Ok(())
+    }
+
+    fn undo(&mut self, scene: &mut Scene) -> Result<()> {
+        self.apply(scene)
+    }
+
+    fn name(&self) -> &str {
+        "Change camera"
+    }
+}
+
+pub struct History {
+    done: Vec<Box<dyn Command>>,
+    undone: Vec<Box<dyn Command>> // Most recently undone last.
+}
+
+impl Default for History {
+    fn default() -> History {
+        History::new()
+    }
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            done: Vec::new(),
+            undone: Vec::new()
+        }
+    }
+
+    // Applies an edit and records it. Edits that were undone can no longer be redone afterwards.
+    pub fn execute(&mut self, mut command: Box<dyn Command>, scene: &mut Scene) -> Result<()> {
+        command.apply(scene)?;
+        self.undone.clear();
+        self.done.push(command);
+        if self.done.len() > HISTORY_LIMIT {
+            self.done.remove(0);
+        }
+        Ok(())
+    }
+
+    // Takes back the last edit and returns its name, or None if there is nothing to undo.
+    pub fn undo(&mut self, scene: &mut Scene) -> Result<Option<String>> {
+        let mut command = match self.done.pop() {
+            Some(command) => command,
+            None => return Ok(None)
+        };
+        if let Err(e) = command.undo(scene) {
+            self.done.push(command);
+            return Err(e)
+        }
+        let name = command.name().to_string();
+        self.undone.push(command);
+        Ok(Some(name))
+    }
+
+    // Applies the last undone edit again and returns its name, or None if there is nothing to redo.
+    pub fn redo(&mut self, scene: &mut Scene) -> Result<Option<String>> {
+        let mut command = match self.undone.pop() {
+            Some(command) => command,
+            None => return Ok(None)
+        };
+        if let Err(e) = command.apply(scene) {
+            self.undone.push(command);
+            return Err(e)
+        }
+        let name = command.name().to_string();
+        self.done.push(command);
+        Ok(Some(name))
+    }
+
+    // Forgets all edits, e.g. when the scene is replaced.
+    pub fn clear(&mut self) {
+        self.done.clear();
+        self.undone.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn camera_moves_are_undone_and_redone() {
+        let mut scene = Scene::new();
+        let mut history = History::new();
+        let x = |scene: &Scene| scene.get_camera().get_ray(0.5, 0.5).origin.x;
+        let start = x(&scene);
+        assert!(history.undo(&mut scene).unwrap().is_none());
+
+        for _ in 0..2 {
+            let moved = scene.get_camera().moved(0.0, 0.0, 1.0);
+            history.execute(Box::new(SetCamera::new(moved)), &mut scene).unwrap();
+        }
+        let twice = x(&scene);
+        assert!((twice - start).abs() > 1.0);
+        assert_eq!(history.undo(&mut scene).unwrap().as_deref(), Some("Change camera"));
+        history.undo(&mut scene).unwrap();
+        assert_eq!(x(&scene), start);
+        history.redo(&mut scene).unwrap();
+        history.redo(&mut scene).unwrap();
+        assert_eq!(x(&scene), twice);
+        assert!(history.redo(&mut scene).unwrap().is_none());
+
+        // A new edit after an undo drops what could have been redone.
+        history.undo(&mut scene).unwrap();
+        history.execute(Box::new(SetCamera::new(scene.get_camera().moved(1.0, 0.0, 0.0))), &mut scene).unwrap();
+        assert!(history.redo(&mut scene).unwrap().is_none());
+    }
+}
@@ -0,0 +1,1020 @@
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::{BufReader, SeekFrom};
+use std::fs::File;
+use std::sync::Mutex;
+
+use log::warn;
+
+use crate::error::{RaytracerError, Result};
+use std::ops::{Add, Mul};
+
+use crate::math::{Float, Vec3};
+
+// --------------------------------------------------
+// Image
+// --------------------------------------------------
+// RGB image with values in [0, 1], stored in rows from the top left.
+#[derive(Clone)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<f32>,
+    pub alpha: Vec<f32> // One value per pixel, or empty if the image is opaque.
+}
+
+impl Image {
+    pub fn get(&self, x: u32, y: u32) -> (f32, f32, f32) {
+        let offset = ((y * self.width + x) * 3) as usize;
+        (self.pixels[offset + 0], self.pixels[offset + 1], self.pixels[offset + 2])
+    }
+
+    pub fn has_alpha(&self) -> bool {
+        !self.alpha.is_empty()
+    }
+
+    pub fn get_alpha(&self, x: u32, y: u32) -> f32 {
+        if self.alpha.is_empty() {
+            return 1.0
+        }
+        self.alpha[(y * self.width + x) as usize]
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        (self.pixels.len() + self.alpha.len()) * std::mem::size_of::<f32>()
+    }
+
+    pub fn luminance(&self, x: u32, y: u32) -> f32 {
+        let (r, g, b) = self.get(x, y);
+        0.2126 * r + 0.7152 * g + 0.0722 * b
+    }
+
+    fn color(&self, x: u32, y: u32) -> Vec3 {
+        let (r, g, b) = self.get(x, y);
+        Vec3::new(r as Float, g as Float, b as Float)
+    }
+
+    fn nearest<T, F: Fn(u32, u32) -> T>(&self, u: Float, v: Float, texel: F) -> T {
+        nearest_texel(self.width, self.height, u, v, texel)
+    }
+
+    fn bilinear<T, F>(&self, u: Float, v: Float, texel: F) -> T
+        where T: Add<Output = T> + Mul<Float, Output = T>, F: Fn(u32, u32) -> T {
+        bilinear_texels(self.width, self.height, u, v, texel)
+    }
+
+    pub fn sample_nearest(&self, u: Float, v: Float) -> Vec3 {
+        if self.width == 0 || self.height == 0 {
+            return Vec3::zero()
+        }
+        self.nearest(u, v, |x, y| self.color(x, y))
+    }
+
+    pub fn sample(&self, u: Float, v: Float) -> Vec3 {
+        if self.width == 0 || self.height == 0 {
+            return Vec3::zero()
+        }
+        self.bilinear(u, v, |x, y| self.color(x, y))
+    }
+
+    pub fn sample_alpha_nearest(&self, u: Float, v: Float) -> Float {
+        if self.alpha.is_empty() {
+            return 1.0
+        }
+        self.nearest(u, v, |x, y| self.get_alpha(x, y) as Float)
+    }
+
+    pub fn sample_alpha(&self, u: Float, v: Float) -> Float {
+        if self.alpha.is_empty() {
+            return 1.0
+        }
+        self.bilinear(u, v, |x, y| self.get_alpha(x, y) as Float)
+    }
+
+    // Half the size in each direction (but at least 1), averaging 2x2 blocks. The last row or column
+    // of odd sizes is averaged with its neighbor.
+    pub fn downsample(&self) -> Image {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut pixels = Vec::with_capacity((width * height * 3) as usize);
+        let mut alpha = Vec::with_capacity(if self.has_alpha() {(width * height) as usize} else {0});
+        for y in 0..height {
+            for x in 0..width {
+                let xs = [(2 * x).min(self.width - 1), (2 * x + 1).min(self.width - 1)];
+                let ys = [(2 * y).min(self.height - 1), (2 * y + 1).min(self.height - 1)];
+                let mut sum = (0.0, 0.0, 0.0);
+                let mut alpha_sum = 0.0;
+                for sy in ys.iter() {
+                    for sx in xs.iter() {
+                        let (r, g, b) = self.get(*sx, *sy);
+                        sum = (sum.0 + r, sum.1 + g, sum.2 + b);
+                        alpha_sum += self.get_alpha(*sx, *sy);
+                    }
+                }
+                pixels.extend_from_slice(&[sum.0 * 0.25, sum.1 * 0.25, sum.2 * 0.25]);
+                if self.has_alpha() {
+                    alpha.push(alpha_sum * 0.25);
+                }
+            }
+        }
+        Image {
+            width: width,
+            height: height,
+            pixels: pixels,
+            alpha: alpha
+        }
+    }
+
+    // Reads a P2/P5 (grayscale) or P3/P6 (RGB) file, in ASCII or binary form respectively.
+    pub fn read_ppm(path: &str) -> Result<Image> {
+        let mut data = Vec::new();
+        BufReader::new(File::open(path)?).read_to_end(&mut data)?;
+
+        let invalid = |reason: &str| RaytracerError::InvalidImage {
+            path: path.to_string(),
+            reason: reason.to_string()
+        };
+
+        let mut pos = 0;
+        let magic = next_token(&data, &mut pos).ok_or_else(|| invalid("missing header"))?;
+        let mut header = [0u32; 3];
+        for value in header.iter_mut() {
+            *value = next_token(&data, &mut pos)
+                .and_then(|t| t.parse().ok())
+                .ok_or_else(|| invalid("malformed header"))?;
+        }
+        let (width, height, max_value) = (header[0], header[1], header[2]);
+        if max_value == 0 || max_value > 255 {
+            return Err(invalid("only 8-bit PPM files are supported"))
+        }
+
+        let channels = match magic.as_str() {
+            "P2" | "P5" => 1,
+            "P3" | "P6" => 3,
+            _ => return Err(invalid("not a PGM or PPM file"))
+        };
+        let count = (width * height * channels) as usize;
+        let scale = 1.0 / (max_value as f32);
+        let mut values = Vec::with_capacity(count);
+        if magic == "P2" || magic == "P3" {
+            for _ in 0..count {
+                let value: u32 = next_token(&data, &mut pos)
+                    .and_then(|t| t.parse().ok())
+                    .ok_or_else(|| invalid("truncated pixel data"))?;
+                values.push((value as f32) * scale);
+            }
+        } else {
+            // Exactly one whitespace byte separates the header from the binary data.
+            let start = pos + 1;
+            if data.len() < start + count {
+                return Err(invalid("truncated pixel data"))
+            }
+            for value in &data[start..(start + count)] {
+                values.push((*value as f32) * scale);
+            }
+        }
+
+        // Grayscale is expanded to RGB so that all images look the same to callers.
+        let pixels = if channels == 1 {
+            values.iter().flat_map(|v| vec![*v, *v, *v]).collect()
+        } else {
+            values
+        };
+
+        Ok(Image {
+            width: width,
+            height: height,
+            pixels: pixels,
+            alpha: Vec::new()
+        })
+    }
+}
+
+// Closest texel with (0, 0) at the top left. Coordinates outside [0, 1] wrap around.
+fn nearest_texel<T, F: FnMut(u32, u32) -> T>(width: u32, height: u32, u: Float, v: Float, mut texel: F) -> T {
+    let x = (((u - u.floor()) * (width as Float)) as u32).min(width - 1);
+    let y = (((v - v.floor()) * (height as Float)) as u32).min(height - 1);
+    texel(x, y)
+}
+
+// Bilinear lookup with (0, 0) at the top left. Coordinates outside [0, 1] wrap around.
+fn bilinear_texels<T, F>(width: u32, height: u32, u: Float, v: Float, mut texel: F) -> T
+    where T: Add<Output = T> + Mul<Float, Output = T>, F: FnMut(u32, u32) -> T {
+    let x = (u - u.floor()) * (width as Float) - 0.5;
+    let y = (v - v.floor()) * (height as Float) - 0.5;
+    let x0 = x.floor();
+    let y0 = y.floor();
+    let fx = x - x0;
+    let fy = y - y0;
+
+    let mut wrapped = |x: Float, y: Float| {
+        let x = (x as i64).rem_euclid(width as i64) as u32;
+        let y = (y as i64).rem_euclid(height as i64) as u32;
+        texel(x, y)
+    };
+    let top = wrapped(x0, y0) * (1.0 - fx) + wrapped(x0 + 1.0, y0) * fx;
+    let bottom = wrapped(x0, y0 + 1.0) * (1.0 - fx) + wrapped(x0 + 1.0, y0 + 1.0) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+
+// --------------------------------------------------
+// StreamedImage
+// --------------------------------------------------
+// Edge length in pixels of the tiles a streamed image is read in.
+const TILE_SIZE: u32 = 64;
+
+// Binary PGM/PPM image read from its file in tiles as they are sampled, for images too large to keep in
+// memory. At most the budget's bytes of tiles stay loaded, evicting the least recently used ones first.
+// Like Image, values are in [0, 1] and grayscale is expanded to RGB. There is no alpha.
+pub struct StreamedImage {
+    path: String,
+    width: u32,
+    height: u32,
+    channels: u32, // In the file: 1 for grayscale, 3 for RGB.
+    scale: f32, // From the file's values to [0, 1].
+    data_start: u64, // Offset of the pixel data in the file.
+    budget: usize,
+    cache: Mutex<TileCache>
+}
+
+struct Tile {
+    pixels: Vec<f32>, // RGB, in rows as wide as the tile.
+    width: u32,
+    last_used: u64
+}
+
+struct TileCache {
+    file: File,
+    tiles: HashMap<(u32, u32), Tile>,
+    clock: u64, // Counts lookups, to find the least recently used tile.
+    resident: usize, // Bytes of the loaded tiles.
+    loads: u64,
+    evictions: u64
+}
+
+impl StreamedImage {
+    // Reads the header only. ASCII files can't be streamed, as their pixels can't be found without reading
+    // all of those before them.
+    pub fn open(path: &str, budget: usize) -> Result<StreamedImage> {
+        let invalid = |reason: &str| RaytracerError::InvalidImage {
+            path: path.to_string(),
+            reason: reason.to_string()
+        };
+
+        // Headers are a few short tokens, but may have comments.
+        let mut file = File::open(path)?;
+        let mut data = Vec::new();
+        (&mut file).take(4096).read_to_end(&mut data)?;
+        let mut pos = 0;
+        let magic = next_token(&data, &mut pos).ok_or_else(|| invalid("missing header"))?;
+        let mut header = [0u32; 3];
+        for value in header.iter_mut() {
+            *value = next_token(&data, &mut pos)
+                .and_then(|t| t.parse().ok())
+                .ok_or_else(|| invalid("malformed header"))?;
+        }
+        let (width, height, max_value) = (header[0], header[1], header[2]);
+        if max_value == 0 || max_value > 255 {
+            return Err(invalid("only 8-bit PPM files are supported"))
+        }
+        if width == 0 || height == 0 {
+            return Err(invalid("empty image"))
+        }
+        let channels = match magic.as_str() {
+            "P5" => 1,
+            "P6" => 3,
+            _ => return Err(invalid("only binary PGM and PPM files can be streamed"))
+        };
+
+        // Exactly one whitespace byte separates the header from the binary data.
+        let data_start = (pos + 1) as u64;
+        if file.metadata()?.len() < data_start + ((width as u64) * (height as u64) * (channels as u64)) {
+            return Err(invalid("truncated pixel data"))
+        }
+
+        Ok(StreamedImage {
+            path: path.to_string(),
+            width: width,
+            height: height,
+            channels: channels,
+            scale: 1.0 / (max_value as f32),
+            data_start: data_start,
+            budget: budget,
+            cache: Mutex::new(TileCache {
+                file: file,
+                tiles: HashMap::new(),
+                clock: 0,
+                resident: 0,
+                loads: 0,
+                evictions: 0
+            })
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    // Bytes the whole image would use as an Image.
+    pub fn full_memory_usage(&self) -> usize {
+        (self.width as usize) * (self.height as usize) * 3 * std::mem::size_of::<f32>()
+    }
+
+    // Bytes of the tiles loaded now.
+    pub fn memory_usage(&self) -> usize {
+        self.cache.lock().unwrap().resident
+    }
+
+    // Tiles read from the file and tiles evicted so far.
+    pub fn tile_counts(&self) -> (u64, u64) {
+        let cache = self.cache.lock().unwrap();
+        (cache.loads, cache.evictions)
+    }
+
+    pub fn sample_nearest(&self, u: Float, v: Float) -> Vec3 {
+        let mut cache = self.cache.lock().unwrap();
+        nearest_texel(self.width, self.height, u, v, |x, y| self.texel(&mut cache, x, y))
+    }
+
+    pub fn sample(&self, u: Float, v: Float) -> Vec3 {
+        let mut cache = self.cache.lock().unwrap();
+        bilinear_texels(self.width, self.height, u, v, |x, y| self.texel(&mut cache, x, y))
+    }
+
+    fn texel(&self, cache: &mut TileCache, x: u32, y: u32) -> Vec3 {
+        cache.clock += 1;
+        let key = (x / TILE_SIZE, y / TILE_SIZE);
+        if !cache.tiles.contains_key(&key) {
+            self.load_tile(cache, key);
+        }
+        let clock = cache.clock;
+        let tile = cache.tiles.get_mut(&key).unwrap();
+        tile.last_used = clock;
+        let offset = (((y % TILE_SIZE) * tile.width + (x % TILE_SIZE)) * 3) as usize;
+        Vec3::new(tile.pixels[offset + 0] as Float, tile.pixels[offset + 1] as Float, tile.pixels[offset + 2] as Float)
+    }
+
+    // Makes room within the budget (keeping at least the new tile), then reads the tile's rows. A file that
+    // can't be read anymore gives black.
+    fn load_tile(&self, cache: &mut TileCache, key: (u32, u32)) {
+        let (x0, y0) = (key.0 * TILE_SIZE, key.1 * TILE_SIZE);
+        let width = TILE_SIZE.min(self.width - x0);
+        let height = TILE_SIZE.min(self.height - y0);
+        let bytes = ((width * height * 3) as usize) * std::mem::size_of::<f32>();
+        while cache.resident + bytes > self.budget && !cache.tiles.is_empty() {
+            let oldest = *cache.tiles.iter().min_by_key(|(_, tile)| tile.last_used).unwrap().0;
+            let evicted = cache.tiles.remove(&oldest).unwrap();
+            cache.resident -= evicted.pixels.len() * std::mem::size_of::<f32>();
+            cache.evictions += 1;
+        }
+
+        let mut pixels = Vec::with_capacity((width * height * 3) as usize);
+        let mut row = vec![0u8; (width * self.channels) as usize];
+        for y in y0..(y0 + height) {
+            let offset = self.data_start + ((y as u64) * (self.width as u64) + (x0 as u64)) * (self.channels as u64);
+            let read = cache.file.seek(SeekFrom::Start(offset)).and_then(|_| cache.file.read_exact(&mut row));
+            if let Err(e) = read {
+                warn!(target: "io", "Failed to read a tile of {}: {}", self.path, e);
+                row.iter_mut().for_each(|value| *value = 0);
+            }
+            for value in row.chunks(self.channels as usize) {
+                let (r, g, b) = if self.channels == 1 {(value[0], value[0], value[0])} else {(value[0], value[1], value[2])};
+                pixels.extend_from_slice(&[(r as f32) * self.scale, (g as f32) * self.scale, (b as f32) * self.scale]);
+            }
+        }
+        cache.resident += bytes;
+        cache.loads += 1;
+        cache.tiles.insert(key, Tile {
+            pixels: pixels,
+            width: width,
+            last_used: cache.clock
+        });
+    }
+
+    // Reads the whole file once, row by row, averaging blocks of 2^level pixels into the image of that mip
+    // level. Pixels past the last whole block are averaged into the last row or column.
+    pub fn read_level(&self, level: u32) -> Result<Image> {
+        let block = 1u32 << level.min(31);
+        let width = (self.width / block).max(1);
+        let height = (self.height / block).max(1);
+        let mut sums = vec![0.0f32; (width * height * 3) as usize];
+        let mut counts = vec![0u32; (width * height) as usize];
+
+        let mut file = BufReader::new(File::open(&self.path)?);
+        file.seek(SeekFrom::Start(self.data_start))?;
+        let mut row = vec![0u8; (self.width * self.channels) as usize];
+        for y in 0..self.height {
+            file.read_exact(&mut row)?;
+            let ty = (y / block).min(height - 1);
+            for x in 0..self.width {
+                let idx = (ty * width + (x / block).min(width - 1)) as usize;
+                let value = &row[((x * self.channels) as usize)..(((x + 1) * self.channels) as usize)];
+                for channel in 0..3 {
+                    let raw = if self.channels == 1 {value[0]} else {value[channel]};
+                    sums[idx * 3 + channel] += (raw as f32) * self.scale;
+                }
+                counts[idx] += 1;
+            }
+        }
+
+        let pixels = sums.iter().enumerate().map(|(idx, sum)| sum / (counts[idx / 3].max(1) as f32)).collect();
+        Ok(Image {
+            width: width,
+            height: height,
+            pixels: pixels,
+            alpha: Vec::new()
+        })
+    }
+}
+
+
+// --------------------------------------------------
+// Texture
+// --------------------------------------------------
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TextureFilter {
+    Nearest,
+    Bilinear, // Always from the full resolution image.
+    Trilinear // Bilinear from the two mip levels closest to the footprint, blended.
+}
+
+// Image with its mip chain, each level half the size of the previous one down to 1x1, so that
+// distant or grazing surfaces are sampled from a prefiltered image instead of sparkling.
+// Streamed textures keep their full resolution image on disk and only the levels that fit their budget in
+// memory, starting at first_level.
+pub struct Texture {
+    levels: Vec<Image>,
+    streamed: Option<StreamedImage>,
+    first_level: usize,
+    pub filter: TextureFilter
+}
+
+// A single level to sample, from memory or streamed from disk.
+enum Level<'a> {
+    Memory(&'a Image),
+    Streamed(&'a StreamedImage)
+}
+
+impl Texture {
+    pub fn new(image: Image) -> Texture {
+        Texture {
+            levels: Texture::mip_chain(image),
+            streamed: None,
+            first_level: 0,
+            filter: TextureFilter::Trilinear
+        }
+    }
+
+    // Streams the full resolution image from disk and keeps the mip levels from the first one using at most
+    // a quarter of the source's budget in memory, so that the tiles get the rest.
+    pub fn streamed(source: StreamedImage) -> Result<Texture> {
+        let mut first_level = 1;
+        while (source.full_memory_usage() >> (2 * first_level)) > source.budget() / 4
+            && (source.width().max(source.height()) >> first_level) > 1 {
+            first_level += 1;
+        }
+        let image = source.read_level(first_level as u32)?;
+        Ok(Texture {
+            levels: Texture::mip_chain(image),
+            streamed: Some(source),
+            first_level: first_level,
+            filter: TextureFilter::Trilinear
+        })
+    }
+
+    fn mip_chain(image: Image) -> Vec<Image> {
+        let mut levels = vec![image];
+        loop {
+            let last = levels.last().unwrap();
+            if last.width <= 1 && last.height <= 1 {
+                break;
+            }
+            let next = last.downsample();
+            levels.push(next);
+        }
+        levels
+    }
+
+    pub fn width(&self) -> u32 {
+        self.streamed.as_ref().map_or(self.levels[0].width, |source| source.width())
+    }
+
+    pub fn height(&self) -> u32 {
+        self.streamed.as_ref().map_or(self.levels[0].height, |source| source.height())
+    }
+
+    pub fn is_streamed(&self) -> bool {
+        self.streamed.is_some()
+    }
+
+    // The largest image in memory: the full resolution one, unless the texture is streamed.
+    pub fn image(&self) -> &Image {
+        &self.levels[0]
+    }
+
+    // Of the levels in memory.
+    pub fn level_count(&self) -> usize {
+        self.levels.len()
+    }
+
+    // Of the levels in memory, so level 0 is the same as image().
+    pub fn level(&self, idx: usize) -> &Image {
+        &self.levels[idx]
+    }
+
+    // Including the tiles streamed in so far.
+    pub fn memory_usage(&self) -> usize {
+        let streamed = self.streamed.as_ref().map_or(0, |source| source.memory_usage());
+        self.levels.iter().map(|level| level.memory_usage()).sum::<usize>() + streamed
+    }
+
+    // Mip level whose texels match footprint, the width of the sampled area in texture coordinates.
+    pub fn lod(&self, footprint: Float) -> Float {
+        let texels = footprint * (self.width().max(self.height()) as Float);
+        if texels <= 1.0 {
+            return 0.0
+        }
+        texels.log2().min((self.first_level + self.levels.len() - 1) as Float)
+    }
+
+    // Full resolution level 0, and the mip levels after it, which are only missing for streamed textures.
+    fn level_at(&self, idx: usize) -> Level<'_> {
+        match &self.streamed {
+            Some(source) if idx == 0 => Level::Streamed(source),
+            _ => Level::Memory(&self.levels[idx.max(self.first_level) - self.first_level])
+        }
+    }
+
+    // Applies the filter to a lookup of a single level, which is told whether to interpolate bilinearly.
+    // Streamed textures blend from full resolution straight to the first level in memory.
+    fn filtered<T, F>(&self, footprint: Float, lookup: F) -> T
+        where T: Add<Output = T> + Mul<Float, Output = T>, F: Fn(Level, bool) -> T {
+        match self.filter {
+            TextureFilter::Nearest => lookup(self.level_at(0), false),
+            TextureFilter::Bilinear => lookup(self.level_at(0), true),
+            TextureFilter::Trilinear => {
+                let lod = self.lod(footprint);
+                let (level, next, blend) = if self.first_level > 0 && lod < (self.first_level as Float) {
+                    (0, self.first_level, lod / (self.first_level as Float))
+                } else {
+                    (lod.floor() as usize, lod.floor() as usize + 1, lod - lod.floor())
+                };
+                let fine = lookup(self.level_at(level), true);
+                if blend <= 0.0 || next >= self.first_level + self.levels.len() {
+                    return fine
+                }
+                fine * (1.0 - blend) + lookup(self.level_at(next), true) * blend
+            }
+        }
+    }
+
+    // footprint is ignored by all but trilinear filtering. Zero samples the full resolution image.
+    pub fn sample(&self, u: Float, v: Float, footprint: Float) -> Vec3 {
+        self.filtered(footprint, |level, bilinear| match level {
+            Level::Memory(image) => if bilinear {image.sample(u, v)} else {image.sample_nearest(u, v)},
+            Level::Streamed(source) => if bilinear {source.sample(u, v)} else {source.sample_nearest(u, v)}
+        })
+    }
+
+    // One for textures without an alpha channel, which includes streamed ones.
+    pub fn sample_alpha(&self, u: Float, v: Float, footprint: Float) -> Float {
+        self.filtered(footprint, |level, bilinear| match level {
+            Level::Memory(image) => if bilinear {image.sample_alpha(u, v)} else {image.sample_alpha_nearest(u, v)},
+            Level::Streamed(_) => 1.0
+        })
+    }
+
+    // The alpha channel, or the luminance for textures without one, so that grayscale masks such as
+    // MTL map_d textures can be used as alpha.
+    pub fn sample_mask(&self, u: Float, v: Float, footprint: Float) -> Float {
+        if self.image().has_alpha() {
+            return self.sample_alpha(u, v, footprint)
+        }
+        let color = self.sample(u, v, footprint);
+        0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+    }
+}
+
+
+// --------------------------------------------------
+// EXR
+// --------------------------------------------------
+// Writes named channels of 32-bit floats, each in rows from the top left, as an uncompressed OpenEXR
+// scanline image. This is the bare minimum every EXR reader supports.
+pub fn write_exr<W: Write>(writer: &mut W, width: u32, height: u32, channels: &[(&str, &[f32])]) -> std::io::Result<()> {
+    // EXR keeps channels sorted by name.
+    let mut channels = channels.to_vec();
+    channels.sort_by(|a, b| a.0.cmp(b.0));
+    for (_, values) in channels.iter() {
+        assert_eq!(values.len(), (width * height) as usize);
+    }
+    let names: Vec<&str> = channels.iter().map(|(name, _)| *name).collect();
+    let header = exr_header(width, height, &names, None);
+
+    // Magic number and version 2 (single part scanlines), the header, and then a table of where each
+    // row's chunk starts. Uncompressed chunks hold a single row of each channel in turn.
+    writer.write_all(&[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0])?;
+    writer.write_all(&header)?;
+    let row_size = (width * 4) as u64 * (channels.len() as u64);
+    let first_row = 8 + header.len() as u64 + 8 * (height as u64);
+    for y in 0..(height as u64) {
+        writer.write_all(&(first_row + y * (8 + row_size)).to_le_bytes())?;
+    }
+    for y in 0..height {
+        writer.write_all(&(y as i32).to_le_bytes())?;
+        writer.write_all(&(row_size as i32).to_le_bytes())?;
+        for (_, values) in channels.iter() {
+            for value in &values[((y * width) as usize)..(((y + 1) * width) as usize)] {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Attributes of an uncompressed image of FLOAT channels, sorted by name, ending with the null byte. Tiled
+// images give the size of their tiles.
+fn exr_header(width: u32, height: u32, channels: &[&str], tiles: Option<(u32, u32)>) -> Vec<u8> {
+    let mut header: Vec<u8> = Vec::new();
+    let mut attribute = |name: &str, type_name: &str, value: &[u8]| {
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(type_name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        header.extend_from_slice(value);
+    };
+
+    // Name, FLOAT pixels, not perceptually linear, reserved bytes, and x/y sampling of 1 for each channel.
+    let mut channel_list: Vec<u8> = Vec::new();
+    for name in channels.iter() {
+        channel_list.extend_from_slice(name.as_bytes());
+        channel_list.push(0);
+        channel_list.extend_from_slice(&2i32.to_le_bytes());
+        channel_list.extend_from_slice(&[0, 0, 0, 0]);
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channel_list.push(0);
+    let mut window: Vec<u8> = Vec::new();
+    for value in [0, 0, width as i32 - 1, height as i32 - 1].iter() {
+        window.extend_from_slice(&value.to_le_bytes());
+    }
+    attribute("channels", "chlist", &channel_list);
+    attribute("compression", "compression", &[0]);
+    attribute("dataWindow", "box2i", &window);
+    attribute("displayWindow", "box2i", &window);
+    // Tiles can be written in any order (random y), scanlines only from the top (increasing y).
+    attribute("lineOrder", "lineOrder", &[if tiles.is_some() {2} else {0}]);
+    attribute("pixelAspectRatio", "float", &1f32.to_le_bytes());
+    attribute("screenWindowCenter", "v2f", &[0; 8]);
+    attribute("screenWindowWidth", "float", &1f32.to_le_bytes());
+    if let Some((tile_width, tile_height)) = tiles {
+        // A single level of tiles, without mipmaps.
+        let mut description = Vec::new();
+        description.extend_from_slice(&tile_width.to_le_bytes());
+        description.extend_from_slice(&tile_height.to_le_bytes());
+        description.push(0);
+        attribute("tiles", "tiledesc", &description);
+    }
+    header.push(0);
+    header
+}
+
+// Writes an uncompressed, tiled OpenEXR image a tile at a time, so that images far larger than memory can
+// be written as their parts are rendered. Tiles are in rows from the top left, and those on the right and
+// bottom edges are cut to the image. The table of where each tile starts is filled in by finish().
+pub struct TiledExrWriter<W: Write + Seek> {
+    writer: W,
+    width: u32,
+    height: u32,
+    tile_width: u32,
+    tile_height: u32,
+    channels: Vec<String>, // Sorted by name, as EXR keeps them.
+    table_start: u64,
+    offsets: Vec<u64> // Of each tile's chunk. Zero until it is written.
+}
+
+impl<W: Write + Seek> TiledExrWriter<W> {
+    pub fn new(mut writer: W, width: u32, height: u32, tile_width: u32, tile_height: u32, channels: &[&str]) -> std::io::Result<TiledExrWriter<W>> {
+        let mut channels: Vec<String> = channels.iter().map(|name| name.to_string()).collect();
+        channels.sort();
+        let names: Vec<&str> = channels.iter().map(|name| name.as_str()).collect();
+        let header = exr_header(width, height, &names, Some((tile_width, tile_height)));
+
+        // Magic number and version 2 with the tiled flag, the header, and room for the table.
+        writer.write_all(&[0x76, 0x2f, 0x31, 0x01, 2, 2, 0, 0])?;
+        writer.write_all(&header)?;
+        let table_start = writer.stream_position()?;
+        let tile_count = (width.div_ceil(tile_width) * height.div_ceil(tile_height)) as usize;
+        writer.write_all(&vec![0u8; 8 * tile_count])?;
+        Ok(TiledExrWriter {
+            writer: writer,
+            width: width,
+            height: height,
+            tile_width: tile_width,
+            tile_height: tile_height,
+            channels: channels,
+            table_start: table_start,
+            offsets: vec![0; tile_count]
+        })
+    }
+
+    pub fn columns(&self) -> u32 {
+        self.width.div_ceil(self.tile_width)
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.height.div_ceil(self.tile_height)
+    }
+
+    // Size of a tile, smaller than the others on the right and bottom edges.
+    pub fn tile_size(&self, column: u32, row: u32) -> (u32, u32) {
+        (self.tile_width.min(self.width - column * self.tile_width), self.tile_height.min(self.height - row * self.tile_height))
+    }
+
+    // Every channel of the tile, each in rows from the tile's top left.
+    pub fn write_tile(&mut self, column: u32, row: u32, channels: &[(&str, &[f32])]) -> std::io::Result<()> {
+        let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+        if column >= self.columns() || row >= self.rows() {
+            return Err(invalid(format!("tile ({}, {}) is outside of the {}x{} tiles", column, row, self.columns(), self.rows())))
+        }
+        let (width, height) = self.tile_size(column, row);
+        let mut sorted = Vec::with_capacity(self.channels.len());
+        for name in self.channels.iter() {
+            let values = channels.iter().find(|(channel, _)| channel == name).map(|(_, values)| *values)
+                .ok_or_else(|| invalid(format!("tile ({}, {}) has no channel {}", column, row, name)))?;
+            if values.len() != (width * height) as usize {
+                return Err(invalid(format!("tile ({}, {}) has {} values per channel instead of {}", column, row, values.len(), width * height)))
+            }
+            sorted.push(values);
+        }
+
+        // Tile coordinates, its level (always the first), and the size of its data, which holds a row of each
+        // channel in turn for every row of the tile.
+        let data_size = width * height * 4 * (sorted.len() as u32);
+        let mut chunk = Vec::with_capacity(20 + data_size as usize);
+        for value in [column as i32, row as i32, 0, 0, data_size as i32].iter() {
+            chunk.extend_from_slice(&value.to_le_bytes());
+        }
+        for y in 0..height {
+            for values in sorted.iter() {
+                for value in &values[((y * width) as usize)..(((y + 1) * width) as usize)] {
+                    chunk.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+        let tile = (row * self.columns() + column) as usize;
+        self.offsets[tile] = self.writer.seek(SeekFrom::End(0))?;
+        self.writer.write_all(&chunk)
+    }
+
+    // Fills in the table of tiles. Fails if any tile wasn't written.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        if let Some(missing) = self.offsets.iter().position(|offset| *offset == 0) {
+            let (column, row) = (missing as u32 % self.columns(), missing as u32 / self.columns());
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("tile ({}, {}) was never written", column, row)))
+        }
+        self.writer.seek(SeekFrom::Start(self.table_start))?;
+        for offset in self.offsets.iter() {
+            self.writer.write_all(&offset.to_le_bytes())?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// Returns the next whitespace separated token, skipping # comments.
+fn next_token(data: &[u8], pos: &mut usize) -> Option<String> {
+    loop {
+        while *pos < data.len() && data[*pos].is_ascii_whitespace() {
+            *pos += 1;
+        }
+        if *pos < data.len() && data[*pos] == b'#' {
+            while *pos < data.len() && data[*pos] != b'\n' {
+                *pos += 1;
+            }
+            continue;
+        }
+        break;
+    }
+
+    let start = *pos;
+    while *pos < data.len() && !data[*pos].is_ascii_whitespace() {
+        *pos += 1;
+    }
+    if start == *pos {None} else {Some(String::from_utf8_lossy(&data[start..*pos]).into_owned())}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkerboard(width: u32, height: u32) -> Image {
+        let mut pixels = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                let value = ((x + y) % 2) as f32;
+                pixels.extend_from_slice(&[value, value, value]);
+            }
+        }
+        Image {
+            width: width,
+            height: height,
+            pixels: pixels,
+            alpha: Vec::new()
+        }
+    }
+
+    #[test]
+    fn mip_chain_sizes() {
+        let texture = Texture::new(checkerboard(5, 2));
+        let sizes: Vec<(u32, u32)> = (0..texture.level_count()).map(|idx| (texture.level(idx).width, texture.level(idx).height)).collect();
+        assert_eq!(sizes, vec![(5, 2), (2, 1), (1, 1)]);
+        assert_eq!(Texture::new(checkerboard(1, 1)).level_count(), 1);
+    }
+
+    #[test]
+    fn checkerboard_filters_to_gray() {
+        let texture = Texture::new(checkerboard(8, 8));
+        assert_eq!(texture.level_count(), 4);
+        assert_eq!(texture.level(1).get(0, 0), (0.5, 0.5, 0.5));
+
+        // Footprints below a texel sample the full image, larger ones blur towards the average.
+        assert_eq!(texture.lod(0.0), 0.0);
+        assert_eq!(texture.lod(1.0 / 16.0), 0.0);
+        assert_eq!(texture.lod(0.25), 1.0);
+        assert_eq!(texture.lod(100.0), 3.0);
+        let center = (0.5 / 8.0, 0.5 / 8.0);
+        assert_eq!(texture.sample(center.0, center.1, 0.0).x, 0.0);
+        assert!((texture.sample(center.0, center.1, 1.0).x - 0.5).abs() < 1e-6);
+        let between = texture.sample(center.0, center.1, 1.5 / 8.0).x;
+        assert!(between > 0.0 && between < 0.5);
+    }
+
+    #[test]
+    fn nearest_and_bilinear_ignore_footprint() {
+        let mut texture = Texture::new(checkerboard(4, 4));
+        texture.filter = TextureFilter::Nearest;
+        assert_eq!(texture.sample(0.3, 0.05, 1.0).x, 1.0);
+        texture.filter = TextureFilter::Bilinear;
+        assert_eq!(texture.sample(0.3, 0.05, 1.0).x, texture.image().sample(0.3, 0.05).x);
+    }
+
+    #[test]
+    fn alpha_filters_with_color() {
+        let mut image = checkerboard(4, 4);
+        image.alpha = (0..16).map(|idx| if idx < 8 {0.0} else {1.0}).collect();
+        let texture = Texture::new(image);
+        assert!(texture.level(1).has_alpha());
+        assert_eq!(texture.level(2).get_alpha(0, 0), 0.5);
+
+        assert_eq!(texture.sample_alpha(0.375, 0.125, 0.0), 0.0);
+        assert_eq!(texture.sample_alpha(0.375, 0.875, 0.0), 1.0);
+        assert!((texture.sample_alpha(0.375, 0.125, 10.0) - 0.5).abs() < 1e-6);
+        assert_eq!(texture.sample_mask(0.375, 0.875, 0.0), 1.0);
+
+        // Opaque textures have no alpha, but can still be used as luminance masks.
+        let opaque = Texture::new(checkerboard(4, 4));
+        assert_eq!(opaque.sample_alpha(0.1, 0.1, 0.0), 1.0);
+        assert_eq!(opaque.sample_mask(0.125, 0.125, 0.0), 0.0);
+        assert!((opaque.sample_mask(0.375, 0.125, 0.0) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn exr_rows_are_where_the_offsets_say() {
+        let values = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        let mut data: Vec<u8> = Vec::new();
+        write_exr(&mut data, 2, 3, &[("Z", &values)]).unwrap();
+        assert_eq!(&data[0..8], &[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+
+        // The offset table sits just before the first row, which is followed by the other two.
+        let row_size = 8 + 2 * 4;
+        let first_row = data.len() - 3 * row_size;
+        let offset = |y: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&data[(first_row - 24 + 8 * y)..(first_row - 16 + 8 * y)]);
+            u64::from_le_bytes(bytes) as usize
+        };
+        assert_eq!(offset(0), first_row);
+        let last = offset(2);
+        assert_eq!(&data[last..(last + 4)], &2i32.to_le_bytes());
+        assert_eq!(&data[(last + 12)..(last + 16)], &6f32.to_le_bytes());
+
+        // Rows hold each channel in turn, sorted by name.
+        let zeros = [0.0; 6];
+        let mut data: Vec<u8> = Vec::new();
+        write_exr(&mut data, 2, 3, &[("Z", &values), ("A", &zeros)]).unwrap();
+        let row_size = 8 + 2 * 2 * 4;
+        let last = data.len() - row_size;
+        assert_eq!(&data[(last + 4)..(last + 8)], &16i32.to_le_bytes());
+        assert_eq!(&data[(last + 8)..(last + 12)], &0f32.to_le_bytes());
+        assert_eq!(&data[(last + 20)..(last + 24)], &6f32.to_le_bytes());
+    }
+
+    #[test]
+    fn exr_tiles_are_where_the_table_says() {
+        // 3x3 pixels in 2x2 tiles, so the right and bottom tiles are cut. Tiles can come in any order.
+        let mut exr = TiledExrWriter::new(std::io::Cursor::new(Vec::new()), 3, 3, 2, 2, &["Z", "A"]).unwrap();
+        assert_eq!((exr.columns(), exr.rows(), exr.tile_size(1, 0), exr.tile_size(1, 1)), (2, 2, (1, 2), (1, 1)));
+        for (column, row) in [(1, 1), (0, 0), (1, 0)].iter() {
+            let (width, height) = exr.tile_size(*column, *row);
+            let values: Vec<f32> = (0..(width * height)).map(|idx| (10 * column + 100 * row + idx) as f32).collect();
+            let zeros = vec![0.0; values.len()];
+            exr.write_tile(*column, *row, &[("Z", &values), ("A", &zeros)]).unwrap();
+        }
+        assert!(exr.write_tile(2, 0, &[]).is_err());
+        assert!(exr.write_tile(0, 1, &[("Z", &[1.0, 2.0]), ("A", &[0.0])]).is_err());
+        let table_start = exr.table_start as usize;
+        assert!(exr.finish().is_err());
+
+        let mut exr = TiledExrWriter::new(std::io::Cursor::new(Vec::new()), 3, 3, 2, 2, &["Z", "A"]).unwrap();
+        for row in 0..2 {
+            for column in 0..2 {
+                let (width, height) = exr.tile_size(column, row);
+                let values: Vec<f32> = (0..(width * height)).map(|idx| (10 * column + 100 * row + idx) as f32).collect();
+                let zeros = vec![0.0; values.len()];
+                exr.write_tile(column, row, &[("Z", &values), ("A", &zeros)]).unwrap();
+            }
+        }
+        let data = exr.finish().unwrap().into_inner();
+        assert_eq!(&data[0..8], &[0x76, 0x2f, 0x31, 0x01, 2, 2, 0, 0]);
+        let int_at = |pos: usize| i32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]);
+        let float_at = |pos: usize| f32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]);
+        let offset = |tile: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&data[(table_start + 8 * tile)..(table_start + 8 * tile + 8)]);
+            u64::from_le_bytes(bytes) as usize
+        };
+
+        // The bottom right tile holds one pixel of each channel, A before Z.
+        let last = offset(3);
+        assert_eq!((int_at(last), int_at(last + 4), int_at(last + 8), int_at(last + 16)), (1, 1, 0, 8));
+        assert_eq!((float_at(last + 20), float_at(last + 24)), (0.0, 110.0));
+        // The top right tile is a column of two, with a row of each channel in turn.
+        let right = offset(1);
+        assert_eq!(int_at(right + 16), 16);
+        assert_eq!((float_at(right + 24), float_at(right + 32)), (10.0, 11.0));
+    }
+
+    #[test]
+    fn streamed_textures_match_loaded_ones_within_budget() {
+        // An RGB pattern that isn't a whole number of tiles, with a comment in the header.
+        let (width, height) = (200u32, 130u32);
+        let mut data = format!("P6\n# pattern\n{} {}\n255\n", width, height).into_bytes();
+        for y in 0..height {
+            for x in 0..width {
+                data.extend_from_slice(&[(x % 256) as u8, (y * 2 % 256) as u8, ((x * 7 + y * 3) % 256) as u8]);
+            }
+        }
+        let path = std::env::temp_dir().join(format!("rs_raytracer_streamed_{}.ppm", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        let path = path.to_str().unwrap().to_string();
+
+        let close = |a: Vec3, b: Vec3| (a - b).length() < 1e-4;
+        let image = Image::read_ppm(&path).unwrap();
+        let budget = 6 * 64 * 64 * 3 * 4;
+        let streamed = StreamedImage::open(&path, budget).unwrap();
+        assert_eq!((streamed.width(), streamed.height()), (width, height));
+        assert!(streamed.full_memory_usage() > budget);
+
+        // Visiting every texel twice in opposite orders has to evict tiles, but never goes over the budget.
+        for pass in 0..2 {
+            for idx in 0..(width * height) {
+                let idx = if pass == 0 {idx} else {width * height - 1 - idx};
+                let (x, y) = (idx % width, idx / width);
+                let u = ((x as Float) + 0.5) / (width as Float);
+                let v = ((y as Float) + 0.5) / (height as Float);
+                assert!(close(streamed.sample_nearest(u, v), image.color(x, y)));
+            }
+            assert!(streamed.memory_usage() <= budget);
+        }
+        let (loads, evictions) = streamed.tile_counts();
+        assert!(evictions > 0 && loads > 12);
+
+        // Mip levels read from the file match the ones built in memory.
+        let texture = Texture::new(Image::read_ppm(&path).unwrap());
+        let level = streamed.read_level(2).unwrap();
+        assert_eq!((level.width, level.height), (texture.level(2).width, texture.level(2).height));
+        assert!(close(level.color(10, 10), texture.level(2).color(10, 10)));
+
+        // The streamed texture samples the full resolution from disk and only keeps small levels in memory.
+        let streamed = Texture::streamed(streamed).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(streamed.is_streamed() && streamed.image().width < width);
+        assert_eq!((streamed.width(), streamed.height()), (width, height));
+        assert!(close(streamed.sample(0.31, 0.47, 0.0), texture.sample(0.31, 0.47, 0.0)));
+        // Far away, both average the whole image, up to the odd rows that halving drops.
+        assert!((streamed.sample(0.31, 0.47, 1.0) - texture.sample(0.31, 0.47, 1.0)).length() < 0.02);
+        assert_eq!(streamed.lod(1.0), texture.lod(1.0));
+    }
+}
@@ -0,0 +1,407 @@
+use std::fs;
+use std::sync::Arc;
+
+use log::warn;
+
+use crate::error::{RaytracerError, Result};
+use crate::math::*;
+
+// --------------------------------------------------
+// IesProfile
+// --------------------------------------------------
+// Angular intensity distribution of a real fixture, read from an IES LM-63 photometric file. Only type C
+// photometry, which nearly all architectural fixtures use, is supported. Vertical angles are measured
+// from straight down the light's axis (nadir), and horizontal angles around it.
+pub struct IesProfile {
+    vertical_angles: Vec<Float>, // Degrees, increasing.
+    horizontal_angles: Vec<Float>, // Degrees, increasing. A single angle means the profile is rotationally symmetric.
+    candela: Vec<Float> // Per vertical angle for each horizontal angle in turn. Scaled to a maximum of 1.
+}
+
+impl IesProfile {
+    pub fn read(path: &str) -> Result<IesProfile> {
+        // Some files are not UTF-8, but everything that is read is ASCII.
+        let text = String::from_utf8_lossy(&fs::read(path)?).to_string();
+        IesProfile::parse(&text, path)
+    }
+
+    // path is only used in error messages.
+    pub fn parse(text: &str, path: &str) -> Result<IesProfile> {
+        let invalid = |reason: &str| RaytracerError::InvalidScene {
+            path: path.to_string(),
+            reason: reason.to_string()
+        };
+
+        // Keywords and labels come before the TILT line. The rest is numbers.
+        let mut lines = text.lines();
+        let tilt = lines.by_ref().find(|line| line.trim_start().starts_with("TILT="))
+            .ok_or_else(|| invalid("missing TILT line"))?;
+        let rest: Vec<&str> = lines.collect();
+        let mut numbers = Vec::new();
+        for word in rest.iter().flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ',')).filter(|w| !w.is_empty()) {
+            numbers.push(word.parse::<Float>().map_err(|_| invalid(&format!("'{}' is not a number", word)))?);
+        }
+        let mut numbers = numbers.into_iter();
+        let mut next = || numbers.next().ok_or_else(|| invalid("truncated photometric data"));
+
+        // Lamp tilt only matters for fixtures that are not mounted level.
+        if tilt.trim() == "TILT=INCLUDE" {
+            next()?;
+            let count = next()? as usize;
+            for _ in 0..(2 * count) {
+                next()?;
+            }
+            warn!(target: "io", "Ignoring the lamp tilt of {}.", path);
+        } else if tilt.trim() != "TILT=NONE" {
+            warn!(target: "io", "Ignoring the lamp tilt file of {}.", path);
+        }
+
+        let _lamp_count = next()?;
+        let _lumens_per_lamp = next()?;
+        let _multiplier = next()?;
+        let vertical_count = next()? as usize;
+        let horizontal_count = next()? as usize;
+        let photometric_type = next()?;
+        for _ in 0..7 {
+            // Units, luminous opening size, ballast factors, and input watts.
+            next()?;
+        }
+        if photometric_type != 1.0 {
+            return Err(invalid("only type C photometry is supported"))
+        }
+        if vertical_count == 0 || horizontal_count == 0 {
+            return Err(invalid("no angles"))
+        }
+
+        let mut read = |count: usize| (0..count).map(|_| next()).collect::<Result<Vec<Float>>>();
+        let vertical_angles = read(vertical_count)?;
+        let horizontal_angles = read(horizontal_count)?;
+        let mut candela = read(vertical_count * horizontal_count)?;
+        let max = candela.iter().copied().fold(0.0, Float::max);
+        if max <= 0.0 {
+            return Err(invalid("the fixture emits no light"))
+        }
+        for value in candela.iter_mut() {
+            *value /= max;
+        }
+
+        Ok(IesProfile {
+            vertical_angles: vertical_angles,
+            horizontal_angles: horizontal_angles,
+            candela: candela
+        })
+    }
+
+    // Relative intensity in [0, 1] for angles in degrees, interpolated between the measured ones.
+    // Directions beyond the measured vertical angles are dark.
+    pub fn intensity(&self, vertical: Float, horizontal: Float) -> Float {
+        let last_vertical = *self.vertical_angles.last().unwrap();
+        if vertical < self.vertical_angles[0] || vertical > last_vertical {
+            return 0.0
+        }
+
+        // Files only hold the part of the distribution that isn't repeated by its symmetry.
+        let last_horizontal = *self.horizontal_angles.last().unwrap();
+        let mut h = horizontal.rem_euclid(360.0);
+        if last_horizontal <= 90.0 {
+            if h > 180.0 {
+                h = 360.0 - h;
+            }
+            if h > 90.0 {
+                h = 180.0 - h;
+            }
+        } else if last_horizontal <= 180.0 && h > 180.0 {
+            h = 360.0 - h;
+        }
+
+        let (v0, v1, fv) = bracket(&self.vertical_angles, vertical);
+        let (h0, h1, fh) = bracket(&self.horizontal_angles, h);
+        let value = |h: usize, v: usize| self.candela[h * self.vertical_angles.len() + v];
+        let near = value(h0, v0) * (1.0 - fv) + value(h0, v1) * fv;
+        let far = value(h1, v0) * (1.0 - fv) + value(h1, v1) * fv;
+        near * (1.0 - fh) + far * fh
+    }
+}
+
+// Indices of the angles around a value and how far it is from the first to the second, clamped to the ends.
+fn bracket(angles: &[Float], value: Float) -> (usize, usize, Float) {
+    let upper = angles.partition_point(|a| *a <= value);
+    if upper == 0 {
+        return (0, 0, 0.0)
+    }
+    if upper == angles.len() {
+        return (upper - 1, upper - 1, 0.0)
+    }
+    let (a, b) = (angles[upper - 1], angles[upper]);
+    (upper - 1, upper, (value - a) / (b - a))
+}
+
+// --------------------------------------------------
+// Light
+// --------------------------------------------------
+// Lights that are infinitely small or far away, so rays can never hit them. Instead, every surface hit
+// looks for the lights it can see (next-event estimation) and adds the light they send its way.
+// Point and spot lights can be shaped by an IES profile. Its nadir points straight down for point lights and
+// along the cone's axis for spot lights.
+#[derive(Clone)]
+pub enum Light {
+    Point { position: Vec3, intensity: Vec3, profile: Option<Arc<IesProfile>> }, // Falls off with the squared distance.
+    Spot { position: Vec3, direction: Vec3, intensity: Vec3, cos_outer: Float, cos_inner: Float, profile: Option<Arc<IesProfile>> }, // A point light limited to a cone, fading out between the inner and outer angles.
+    Directional { direction: Vec3, irradiance: Vec3 } // Parallel light from far away, like the sun. The direction is the one the light travels in.
+}
+
+// Light arriving at a point from a light, if nothing is in the way.
+pub struct LightSample {
+    pub direction: Vec3, // Unit vector from the point towards the light.
+    pub distance: Float, // To the light, so that shadow rays stop there. Infinite for directional lights.
+    pub irradiance: Vec3 // On a surface facing the light.
+}
+
+impl Light {
+    pub fn point(position: Vec3, intensity: Vec3) -> Light {
+        Light::Point {
+            position: position,
+            intensity: intensity,
+            profile: None
+        }
+    }
+
+    // Angles in degrees from the cone's axis. The light fades out over the last falloff_angle degrees
+    // before cone_angle, as with PBRT's coneangle and conedeltaangle.
+    pub fn spot(position: Vec3, direction: Vec3, intensity: Vec3, cone_angle: Float, falloff_angle: Float) -> Light {
+        let cone_angle = cone_angle.clamp(0.0, 180.0);
+        Light::Spot {
+            position: position,
+            direction: direction.normalized(),
+            intensity: intensity,
+            cos_outer: cone_angle.to_radians().cos(),
+            cos_inner: (cone_angle - falloff_angle.max(0.0)).max(0.0).to_radians().cos(),
+            profile: None
+        }
+    }
+
+    pub fn directional(direction: Vec3, irradiance: Vec3) -> Light {
+        Light::Directional {
+            direction: direction.normalized(),
+            irradiance: irradiance
+        }
+    }
+
+    // Shapes a point or spot light by a profile. The intensity becomes that of its brightest direction.
+    // Directional lights are returned unchanged.
+    pub fn with_profile(self, ies: Arc<IesProfile>) -> Light {
+        match self {
+            Light::Point{position, intensity, ..} => Light::Point {
+                position: position,
+                intensity: intensity,
+                profile: Some(ies)
+            },
+            Light::Spot{position, direction, intensity, cos_outer, cos_inner, ..} => Light::Spot {
+                position: position,
+                direction: direction,
+                intensity: intensity,
+                cos_outer: cos_outer,
+                cos_inner: cos_inner,
+                profile: Some(ies)
+            },
+            light => light
+        }
+    }
+
+    pub fn sample(&self, p: Vec3) -> Option<LightSample> {
+        match self {
+            Light::Point{position, intensity, profile} => {
+                let (position, intensity) = (*position, *intensity);
+                let to_light = position - p;
+                let distance_squared = to_light.sqr_length();
+                if distance_squared <= 0.0 {
+                    return None
+                }
+                let distance = distance_squared.sqrt();
+                let shape = profile_intensity(profile, Vec3::new(0.0, -1.0, 0.0), -to_light / distance);
+                Some(LightSample {
+                    direction: to_light / distance,
+                    distance: distance,
+                    irradiance: intensity * (shape / distance_squared)
+                })
+            }
+
+            Light::Spot{position, direction, intensity, cos_outer, cos_inner, profile} => {
+                let (position, direction, intensity, cos_outer, cos_inner) = (*position, *direction, *intensity, *cos_outer, *cos_inner);
+                let to_light = position - p;
+                let distance_squared = to_light.sqr_length();
+                if distance_squared <= 0.0 {
+                    return None
+                }
+                let distance = distance_squared.sqrt();
+                let cos_angle = (-to_light / distance).dot(&direction);
+                let falloff = if cos_angle >= cos_inner {
+                    1.0
+                } else if cos_angle <= cos_outer {
+                    return None
+                } else {
+                    // Smoothstep from the outer to the inner edge.
+                    let t = (cos_angle - cos_outer) / (cos_inner - cos_outer);
+                    t * t * (3.0 - 2.0 * t)
+                };
+                let shape = profile_intensity(profile, direction, -to_light / distance);
+                Some(LightSample {
+                    direction: to_light / distance,
+                    distance: distance,
+                    irradiance: intensity * (falloff * shape / distance_squared)
+                })
+            }
+
+            Light::Directional{direction, irradiance} => {
+                Some(LightSample {
+                    direction: -*direction,
+                    distance: Float::INFINITY,
+                    irradiance: *irradiance
+                })
+            }
+        }
+    }
+}
+
+// Relative intensity towards a unit direction away from a light with the given axis. The 0 degree plane
+// of the profile is along the first axis of the axis' Onb, e.g. +X (and 90 degrees +Z) for lights pointing down.
+fn profile_intensity(profile: &Option<Arc<IesProfile>>, axis: Vec3, direction: Vec3) -> Float {
+    let profile = match profile {
+        Some(profile) => profile,
+        None => return 1.0
+    };
+    let onb = Onb::from_normal(axis);
+    let vertical = direction.dot(&onb.w).clamp(-1.0, 1.0).acos().to_degrees();
+    let horizontal = direction.dot(&onb.v).atan2(direction.dot(&onb.u)).to_degrees();
+    profile.intensity(vertical, horizontal)
+}
+
+// --------------------------------------------------
+// Portal
+// --------------------------------------------------
+// Rectangular opening, such as a window, through which the background lights an interior. Surfaces look
+// for the background through portals directly, instead of waiting for bounces to find the way out by chance.
+#[derive(Clone, Copy)]
+pub struct Portal {
+    corner: Vec3,
+    edge_u: Vec3,
+    edge_v: Vec3
+}
+
+impl Portal {
+    // The rectangle spanned by two perpendicular edges from a corner.
+    pub fn new(corner: Vec3, edge_u: Vec3, edge_v: Vec3) -> Portal {
+        Portal {
+            corner: corner,
+            edge_u: edge_u,
+            edge_v: edge_v
+        }
+    }
+
+    pub fn area(&self) -> Float {
+        self.edge_u.cross(&self.edge_v).length()
+    }
+
+    // Unit direction from p towards a uniformly random point on the portal, or None if p sees it edge-on.
+    pub fn sample(&self, p: Vec3, rng: &mut RandGen) -> Option<Vec3> {
+        let target = self.corner + self.edge_u * rng.next01() + self.edge_v * rng.next01();
+        let to_target = target - p;
+        let distance = to_target.length();
+        if distance <= 0.0 || self.pdf(p, to_target / distance) <= 0.0 {
+            return None
+        }
+        Some(to_target / distance)
+    }
+
+    // Density in solid angle with which sample picks a unit direction from p. Zero if it misses the portal.
+    pub fn pdf(&self, p: Vec3, direction: Vec3) -> Float {
+        let normal = self.edge_u.cross(&self.edge_v);
+        let area = normal.length();
+        if area <= 0.0 {
+            return 0.0
+        }
+        let normal = normal / area;
+        let cos_portal = direction.dot(&normal);
+        if cos_portal.abs() < 1e-6 {
+            return 0.0
+        }
+        let t = (self.corner - p).dot(&normal) / cos_portal;
+        if t <= 0.0 {
+            return 0.0
+        }
+        let offset = p + direction * t - self.corner;
+        let u = offset.dot(&self.edge_u) / self.edge_u.sqr_length();
+        let v = offset.dot(&self.edge_v) / self.edge_v.sqr_length();
+        if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+            return 0.0
+        }
+        t * t / (area * cos_portal.abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lights_fall_off() {
+        let point = Light::point(Vec3::new(0.0, 2.0, 0.0), Vec3::one() * 8.0);
+        let sample = point.sample(Vec3::zero()).unwrap();
+        assert!((sample.irradiance.x - 2.0).abs() < 1e-5);
+        assert!((sample.direction.y - 1.0).abs() < 1e-5 && (sample.distance - 2.0).abs() < 1e-5);
+
+        // Full inside the inner cone, fading towards the outer one, and dark outside.
+        let spot = Light::spot(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0), Vec3::one(), 45.0, 15.0);
+        let at = |x: Float| spot.sample(Vec3::new(x, 0.0, 0.0)).map_or(0.0, |s| s.irradiance.x * (x * x + 1.0));
+        assert!((at(0.5) - 1.0).abs() < 1e-5);
+        assert!(at(0.8) > 0.0 && at(0.8) < 1.0);
+        assert_eq!(at(1.5), 0.0);
+
+        let sun = Light::directional(Vec3::new(0.0, -2.0, 0.0), Vec3::one());
+        let sample = sun.sample(Vec3::new(100.0, 0.0, 0.0)).unwrap();
+        assert!((sample.direction.y - 1.0).abs() < 1e-5 && sample.distance.is_infinite());
+    }
+
+    #[test]
+    fn ies_profiles_shape_lights() {
+        // A downlight measured in two planes, brightest straight down and dark from 90 degrees.
+        let text = "IESNA:LM-63-2002\n[TEST] test\nTILT=NONE\n1 1000 1 3 2 1 2 0.1 0.1 0\n1 1 50\n0 45 90\n0 90\n200 100 0\n100, 50, 0\n";
+        let profile = IesProfile::parse(text, "test.ies").unwrap();
+        assert_eq!(profile.intensity(0.0, 0.0), 1.0);
+        assert!((profile.intensity(22.5, 0.0) - 0.75).abs() < 1e-5);
+        assert!((profile.intensity(0.0, 45.0) - 0.75).abs() < 1e-5);
+        assert_eq!(profile.intensity(120.0, 0.0), 0.0);
+        // Quadrant symmetry mirrors the 0 to 90 degree planes around.
+        assert!((profile.intensity(0.0, 270.0) - 0.5).abs() < 1e-5);
+        assert!((profile.intensity(0.0, 180.0) - 1.0).abs() < 1e-5);
+        assert!(IesProfile::parse(&text.replace(" 2 1 2 ", " 2 2 2 "), "test.ies").is_err());
+        assert!(IesProfile::parse("TILT=NONE\n1 1000", "test.ies").is_err());
+
+        // Point lights point down, with the 0 degree plane along +X and the 90 degree one along +Z.
+        let light = Light::point(Vec3::new(0.0, 1.0, 0.0), Vec3::one()).with_profile(Arc::new(profile));
+        let at = |p: Vec3| light.sample(p).unwrap().irradiance.x;
+        assert!((at(Vec3::zero()) - 1.0).abs() < 1e-4);
+        assert!((at(Vec3::new(1.0, 0.0, 0.0)) - 0.5 / 2.0).abs() < 1e-4);
+        assert!((at(Vec3::new(0.0, 0.0, 1.0)) - 0.25 / 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn portals_are_sampled_by_solid_angle() {
+        // A 2x2 window one unit above the point covers a solid angle of 2pi/3.
+        let portal = Portal::new(Vec3::new(-1.0, 1.0, -1.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 2.0));
+        assert_eq!(portal.area(), 4.0);
+        assert!((portal.pdf(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0)) - 0.25).abs() < 1e-5);
+        assert_eq!(portal.pdf(Vec3::zero(), Vec3::new(0.0, -1.0, 0.0)), 0.0);
+        assert_eq!(portal.pdf(Vec3::zero(), Vec3::new(1.0, 0.1, 0.0).normalized()), 0.0);
+
+        let mut rng = RandGen::new();
+        let count = 20000;
+        let mut solid_angle = 0.0;
+        for _ in 0..count {
+            let direction = portal.sample(Vec3::zero(), &mut rng).unwrap();
+            solid_angle += 1.0 / portal.pdf(Vec3::zero(), direction);
+        }
+        solid_angle /= count as Float;
+        assert!((solid_angle - 2.0 * consts::PI / 3.0).abs() < 0.05);
+    }
+}
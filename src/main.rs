@@ -1,3 +1,7 @@
+// The code spells out struct fields and returns, and lines up channel offsets (offset + 0, + 1, + 2).
+// Casts from Float to f32 are only no-ops in f32 builds.
+#![allow(clippy::redundant_field_names, clippy::needless_return, clippy::identity_op, clippy::unnecessary_cast)]
+
 /// RS Raytracer
 /// 
 /// A ray tracer implemented in Rust based on https://raytracing.github.io/books/RayTracingInOneWeekend.html.
@@ -8,6 +12,79 @@
 /// Escape: Quit.
 /// Space: Run the ray tracer and update the preview with its result.
 /// S: Save the current ray tracer buffer to a PPM file. This is local to your terminal CWD or exe if run directly.
+/// P: Save the current ray tracer buffer to an RGBA PNG file, next to the PPM file.
+/// Shift+P: Save an exposure bracket of the current buffer, two stops under, as is, and two stops over, to PNG files.
+/// Ctrl+C: Copy the current image, as saved to PNG, to the system clipboard.
+/// D: Save the depth pass of the last render to a 16-bit PNG file and a float EXR file.
+/// O: Save the object and material ID passes of the last render to EXR files, for masks in compositing.
+/// R: Toggle region selection mode. While active, drag with the left mouse button to set the render region.
+/// C: Clear the render region so that the full image is traced again.
+/// I: Toggle pixel inspection mode. While active, hovering over a pixel shows its values in the window title.
+/// Right click: Focus the camera on the surface under the mouse, for depth of field, and render a quick draft.
+/// Mouse wheel / middle drag / 0: Zoom in and out around the mouse, pan the zoomed image, and show the whole image again.
+/// A: Stash the current ray tracer buffer for A/B comparison.
+/// B: Toggle the A/B split view between the stash (left) and the current buffer (right). Drag with the left mouse button to move the split.
+/// H: Toggle the per-pixel cost heatmap of the last render.
+/// V: Cycle the BVH views of the last render: nodes visited per pixel, leaf depth of the visible object, and off.
+/// W: Cycle the debug overlay between object edges, scene BVH boxes, and none.
+/// G: Cycle the bloom post effect between a glow around bright parts, the glow with starburst streaks, and off.
+/// F: Toggle the lens effects: vignette, chromatic aberration, and film grain.
+/// Shift+F: Toggle autofocus, which focuses on the nearest surface in the middle of the image before each render.
+/// K / Shift+K: Lower / raise the white balance temperature by 500K. J / Shift+J: Shift the tint towards green / magenta.
+/// N: Toggle auto white balance, which grays the average of the background.
+/// E: Toggle the exposure overlay: a luminance histogram, with zebra stripes over black (blue) and clipped (red) pixels.
+/// X: Toggle the false color view, which shows luminance bands in calibrated colors.
+/// Q: Toggle low priority rendering, which lowers the render thread's priority on Unix and pauses between rows to leave the CPU to other applications.
+/// Tab: Toggle the raycast mode, a fast preview that only shades what camera rays hit, with direct light and hard shadows, and render.
+/// M: Print error metrics (MSE, RMSE, SSIM) against the reference image given with `--reference <file.ppm>`.
+/// Enter: Start a progressive render that refines until paused, then pause / resume it. The samples per pixel are shown in the window title.
+/// L: Render a quality ladder at 1, 4, 16, ... samples per pixel, saving each stage to a PNG file and logging how much it changed the image.
+/// Arrow keys, Page Up, and Page Down: Move the camera and render a quick draft.
+/// T: Lock the camera on the surface at the center of the view, so that moves circle around it. Press again to unlock.
+/// Shift+Up / Shift+Down: Dolly zoom towards / away from the locked (or centered) surface.
+/// , / . / /: Roll the camera left / right by 5 degrees, or level its horizon again.
+/// [ / ]: Pull the camera's near clip in / push it out, to cut away what is in front and look inside objects.
+/// 1-9: Switch to one of the scene's named cameras and render a quick draft.
+/// Ctrl+Z / Ctrl+Y: Undo / redo camera moves and switches.
+/// Drop a file onto the window: OBJ and glTF models are added to the scene with a gray material, and other scene files replace it.
+/// 
+/// Pass `--scene <file>` to render a glTF 2.0 (.gltf or .glb), OBJ, or PBRT v3 scene instead of the default spheres.
+/// USD stages (.usda or .usdz) are supported when built with the `usd` feature.
+/// `--generate <settings>` generates random spheres instead, e.g. `count=10000,placement=poisson,radius=0.05-0.5,seed=7`.
+/// `--preset <cornell-box|furnace|three-spheres|material-showcase>` renders one of the standard test scenes instead.
+/// Rhai scripts (.rhai) can also be used as scenes, or run on top of any scene with `--script <file>`.
+/// `--filter <box|tent|gaussian|mitchell>` picks the pixel reconstruction filter. The default is box.
+/// `--color-space <srgb|rec709|acescg|linear>` picks the color space of the shown and saved images. The default is sRGB.
+/// `--white-balance <kelvin>` and `--tint <value>` balance for the color of the light, and `--auto-white-balance` for the background.
+/// `--backplate <file.ppm>` shows an image behind the scene to the camera, while the sky still lights it.
+/// `--transparent` renders the background as transparent, to composite the PNG output over other imagery.
+/// `--depth-clips <near> <far>` and `--depth-encoding <linear|normalized>` set up the depth pass export.
+/// `--bloom` adds a glow around bright parts of the image, and `--glare <streaks>` adds starburst streaks to it.
+/// `--lens-effects` adds a vignette, chromatic aberration, and film grain to the image.
+/// `--id-coverage` makes the ID passes count every camera sample, for anti-aliased masks.
+/// `--autofocus` focuses on the nearest surface in the middle of the image before each render.
+/// `--max-diffuse-depth <n>` and `--max-specular-depth <n>` limit the bounces of each kind per path, within the overall depth.
+/// `--low-priority` starts with low priority rendering.
+/// `--raycast` starts in the raycast mode.
+/// `--bracket <stops>` sets how many stops apart the exposures saved with Shift+P are. The default is 2.
+/// `--f-stop <n>`, `--shutter <seconds>`, and `--iso <n>` expose as a physical camera would, for scenes lit in physical units, and blur by the f-stop's depth of field.
+/// `--aperture-blades <n>` and `--blade-rotation <degrees>`, or `--bokeh-mask <file.ppm>`, shape out of focus highlights.
+/// `--stamp` burns the scene name, samples per pixel, render time, and resolution into a corner of saved images.
+/// `--max-time <seconds>` and `--max-samples <n>` stop renders at a time or camera sample budget and save what they have as a PNG.
+/// `--snapshot-minutes <n>` and `--snapshot-samples <n>` save numbered PNG snapshots of progressive renders as they refine.
+/// `--half-buffer` accumulates samples in a half precision buffer, at a quarter of the memory.
+/// `--resolution <width>x<height>` renders at a resolution other than the window's, e.g. 3840x2160. Larger images are shown scaled down.
+/// `--texture-budget <MB>` streams 8-bit binary PGM/PPM textures larger than the budget from disk in tiles. The budget is per texture.
+/// `--poster <tiles>` renders the scene at tiles times the resolution along each side to a tiled EXR file, a tile at a time, without opening a window.
+/// `--tile-order <rows|spiral|center|hilbert>` sets the order poster tiles are rendered in, e.g. from the middle outward.
+/// `--batch <file>` renders the scenes listed in a file one after another without opening a window, resuming where an interrupted batch stopped.
+/// `--furnace-test` checks that the built-in materials neither lose nor gain light, without opening a window.
+/// `--output-dir <dir>` saves images to a directory rather than the working directory.
+/// Defaults for many of these can be set in an rs-raytracer.toml file in the working or home directory. `--no-config` ignores it.
+/// Add `--watch` to reload the scene and render a quick draft whenever the file is saved.
+/// 
+/// Logging defaults to info level. Pass --quiet for warnings only or --verbose (twice for per-line progress) for more detail.
+/// Messages are categorized by target (render, io, scene, viewer, metrics) and can be filtered with RUST_LOG.
 /// 
 /// Daniel Green <KasumiL5x@gmail.com>
 
@@ -15,25 +92,178 @@ use sdl2::pixels::PixelFormatEnum;
 use sdl2::rect::Rect;
 use sdl2::event::Event;
 use sdl2::pixels::Color;
-use sdl2::keyboard::Keycode;
+use sdl2::render::BlendMode;
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::mouse::MouseButton;
+use log::{error, info, warn};
+use notify::Watcher;
+use std::collections::HashMap;
 
+use error::RaytracerError;
+
+pub mod accumulation;
+pub mod assets;
+pub mod batch;
+pub mod bvh;
+pub mod color;
+pub mod config;
+pub mod error;
+pub mod exposure;
+#[cfg(test)]
+pub mod fixtures;
+pub mod furnace;
+pub mod generator;
+pub mod gltf_loader;
+pub mod hierarchy;
+pub mod history;
+pub mod image;
+pub mod light;
 pub mod math;
+pub mod metrics;
+pub mod obj_loader;
+pub mod options;
+pub mod pbrt_loader;
+pub mod pointcloud;
+pub mod presets;
+pub mod post;
+pub mod poster;
+pub mod primitives;
 pub mod raytracer;
+pub mod saver;
+pub mod scene;
+pub mod script_loader;
+pub mod sdf;
+pub mod sky;
+pub mod snapshot;
+pub mod stamp;
+#[cfg(feature = "usd")]
+pub mod usd_loader;
+pub mod viewport;
+
+// Depth of the deepest scene BVH nodes shown by the debug overlay.
+const BVH_OVERLAY_DEPTH: u32 = 6;
+
+// Samples per pixel added by each pass of a progressive render. Few keep the viewer responsive.
+const PROGRESSIVE_PASS_SAMPLES: u32 = 1;
+
+// Streaks of the starburst glare the viewer adds to the bloom.
+const GLARE_STREAKS: u32 = 6;
+
+// White balance steps of the viewer keys, in kelvin and tint.
+const WHITE_BALANCE_STEP: math::Float = 500.0;
+const TINT_STEP: math::Float = 0.1;
+
+// Distance the camera moves per key press, in scene units.
+const CAMERA_STEP: math::Float = 0.5;
+// Degrees the camera rolls per key press.
+const ROLL_STEP: math::Float = 5.0;
+
+// Viewer decorations drawn on top of the ray tracer image.
+struct Overlay {
+    region: Option<raytracer::Region>, // Outline of the render region.
+    split: Option<u32> // Column of the A/B comparison divider.
+}
+
+impl Overlay {
+    fn none() -> Overlay {
+        Overlay {
+            region: None,
+            split: None
+        }
+    }
+
+    fn split(split: Option<u32>) -> Overlay {
+        Overlay {
+            region: None,
+            split: split
+        }
+    }
+}
+
+pub fn main() -> Result<(), RaytracerError> {
+    // Defaults from the config file, which the command line overrides.
+    let config = match config::find() {
+        Some(path) if !std::env::args().any(|arg| arg == "--no-config") => Some(config::read(&path.to_string_lossy())?),
+        _ => None
+    };
+    let config = config.as_ref();
+
+    // Settings from the command line, over the config's defaults.
+    let options = options::Options::parse(config, std::env::args().skip(1))?;
+    let settings = options.render_settings()?;
+    let options::Options{reference_path, mut scene_path, builtin_scene, batch_path, furnace_test, poster_tiles, poster_order, script_path, backplate_path, watch,
+        output_directory, resolution, texture_budget, bracket_stops, snapshot_every, snapshot_samples, key_bindings: extra_keys, log_level, ..} = options;
+
+    // Console logging. RUST_LOG can further filter by category (e.g. RUST_LOG=render=trace).
+    let mut log_builder = env_logger::Builder::new();
+    log_builder.filter_level(log_level);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        log_builder.parse_filters(&filters);
+    }
+    log_builder.init();
+
+    if let Some(config) = config {
+        info!(target: "io", "Using defaults from {}.", config.path);
+        for warning in config.warnings.iter() {
+            warn!(target: "io", "{}: {}", config.path, warning);
+        }
+    }
+    // Cameras are set up for the default aspect ratio.
+    if (resolution.0 as u64) * (raytracer::HEIGHT as u64) != (resolution.1 as u64) * (raytracer::WIDTH as u64) {
+        warn!(target: "render", "The resolution {}x{} has a different aspect ratio than {}x{}, which scene cameras are set up for, so the image will be stretched.",
+            resolution.0, resolution.1, raytracer::WIDTH, raytracer::HEIGHT);
+    }
+    if let Err(e) = std::fs::create_dir_all(&output_directory) {
+        warn!(target: "io", "Cannot create output directory {}: {}", output_directory, e);
+    }
+
+    if settings.physical_exposure.is_none() && settings.aperture.is_shaped() {
+        warn!(target: "render", "The aperture shape only shows with depth of field, which --f-stop, --shutter, or --iso turn on.");
+    }
+
+    // Loaded once, and set again whenever the scene is reloaded.
+    let backplate = match &backplate_path {
+        Some(path) => Some(image::Image::read_ppm(path)?),
+        None => None
+    };
+
+    // Posters, batches, and the furnace test render without the viewer.
+    if furnace_test {
+        return run_furnace_test()
+    }
+    if let Some(tiles) = poster_tiles {
+        let mut ray_tracer = raytracer::RSRaytracer::with_resolution(resolution.0, resolution.1);
+        *ray_tracer.get_settings_mut() = settings;
+        let mut scene = scene::Scene::new();
+        scene.get_assets_mut().set_texture_budget(texture_budget);
+        setup_scene(&mut scene, scene_path.as_deref(), builtin_scene.as_ref(), script_path.as_deref(), backplate.as_ref())?;
+        info!(target: "scene", "Scene statistics:\n{}", ray_tracer.scene_stats(&scene));
+        let path = std::path::Path::new(&output_directory).join(raytracer::POSTER_OUT).to_string_lossy().into_owned();
+        return poster::render(&mut ray_tracer, &scene, tiles, poster_order, &path)
+    }
+    if let Some(path) = batch_path {
+        return run_batch(&path, &settings, resolution, &output_directory, script_path.as_deref(), backplate.as_ref(), texture_budget)
+    }
 
-pub fn main() -> Result<(), String> {
-    let window_width = raytracer::WIDTH;
-    let window_height = raytracer::HEIGHT;
+    // The window shows the image at the default resolution at most, scaled down with its aspect ratio kept. The
+    // canvas takes the image's size, so that drawing and mouse positions are in image pixels.
+    let (image_width, image_height) = resolution;
+    let mut view = viewport::Viewport::new(image_width, image_height);
+    let (window_width, window_height) = view.window_size();
+    if (window_width, window_height) != resolution {
+        info!(target: "viewer", "Showing the {}x{} image at {}x{}.", image_width, image_height, window_width, window_height);
+    }
 
     // Setup SDL and create the video subsystem.
-    let sdl_context = sdl2::init()?;
-    let video_subsys = sdl_context.video()?;
+    let sdl_context = sdl2::init().map_err(RaytracerError::Sdl)?;
+    let video_subsys = sdl_context.video().map_err(RaytracerError::Sdl)?;
 
     // Create the window.
     let window = video_subsys
         .window("RS Raytracer", window_width, window_height)
         .position_centered()
         .build()
-        .map_err(|e| e.to_string())?;
+        .map_err(|e| RaytracerError::Sdl(e.to_string()))?;
 
     // Create the Canvas that we can draw to.
     let mut canvas = window
@@ -41,9 +271,10 @@ pub fn main() -> Result<(), String> {
         .target_texture() // Allows rendering to a texture.
         .present_vsync() // Enables vsync.
         .build()
-        .map_err(|e| e.to_string())?;
+        .map_err(|e| RaytracerError::Sdl(e.to_string()))?;
     
-    println!("Using SDL_Renderer {}.", canvas.info().name);
+    canvas.set_logical_size(image_width, image_height).map_err(|e| RaytracerError::Sdl(e.to_string()))?;
+    info!(target: "viewer", "Using SDL_Renderer {}.", canvas.info().name);
 
     // Clear the Canvas and push it to the window.
     canvas.set_draw_color(Color::RGB(1, 0, 1));
@@ -53,91 +284,95 @@ pub fn main() -> Result<(), String> {
     // Create a TextureCreator (the Canvas cannot do so directly due to lifetime issues).
     let texture_creator = canvas.texture_creator();
     // Create the actual texture we'll be splatting to the Canvas.
-    let mut texture = texture_creator
-        .create_texture_streaming(PixelFormatEnum::RGB24, window_width, window_height)
-        .map_err(|e| e.to_string())?;
+    let mut texture = create_texture(&texture_creator, &view)?;
 
     // Create the ray tracer instance.
-    let mut ray_tracer = raytracer::RSRaytracer::new();
+    let mut ray_tracer = raytracer::RSRaytracer::with_resolution(image_width, image_height);
+    *ray_tracer.get_settings_mut() = settings;
+    ray_tracer.set_output_directory(&output_directory);
+    let mut scene = scene::Scene::new();
+    scene.get_assets_mut().set_texture_budget(texture_budget);
 
-    // Setup the scene.
-    let ground_mat = ray_tracer.add_lambertian_material(raytracer::Lambertian::new(math::Vec3::new(0.5, 0.5, 0.5)));
-    ray_tracer.add_sphere(raytracer::Sphere::new(math::Vec3::new(0.0, -1000.0, 0.0), 1000.0, ground_mat));
-    let mut rand_gen = math::RandGen::new();
-    for x in -11..11 {
-        for y in -11..11 {
-            let center = math::Vec3::new(
-                (x as f32) + rand_gen.next01() * 0.9, 0.2, (y as f32) + rand_gen.next01() * 0.9
-            );
+    // Setup the scene. When watching, a broken scene file can still be fixed after startup.
+    if let Err(e) = setup_scene(&mut scene, scene_path.as_deref(), builtin_scene.as_ref(), script_path.as_deref(), backplate.as_ref()) {
+        if !watch {
+            return Err(e)
+        }
+        error!(target: "scene", "Failed to load {}: {}", scene_path.as_deref().unwrap_or_default(), e);
+    }
+    match &builtin_scene {
+        Some(builtin) => ray_tracer.set_scene_name(&builtin.name()),
+        None => ray_tracer.set_scene_name(&scene_name(scene_path.as_deref()))
+    }
+    info!(target: "scene", "Scene statistics:\n{}", ray_tracer.scene_stats(&scene));
 
-            if (center - math::Vec3::new(4.0, 0.2, 0.0)).length() > 0.9 {
-                let rand_mat = rand_gen.next01();
-                if rand_mat < 0.8  {
-                    let albedo = math::Vec3::random() * math::Vec3::random();
-                    let mat = ray_tracer.add_lambertian_material(raytracer::Lambertian::new(albedo));
-                    ray_tracer.add_sphere(raytracer::Sphere::new(center, 0.2, mat));
-                } else if rand_mat < 0.95  {
-                    let albedo = math::Vec3::random_range(0.5..1.0);
-                    let fuzz = rand_gen.next_range(0.0..0.5);
-                    let mat = ray_tracer.add_metal_material(raytracer::Metal::new(albedo, fuzz));
-                    ray_tracer.add_sphere(raytracer::Sphere::new(center, 0.2, mat));
-                } else {
-                    let mat = ray_tracer.add_dielectric_material(raytracer::Dielectric::new(1.5));
-                    ray_tracer.add_sphere(raytracer::Sphere::new(center, 0.2, mat));
-                }
+    // Interactive edits of the scene, for undo and redo.
+    let mut history = history::History::new();
+
+    // Scene file watcher.
+    let (watch_tx, watch_rx) = std::sync::mpsc::channel();
+    let mut scene_watcher = None;
+    if let (true, Some(path)) = (watch, &scene_path) {
+        scene_watcher = Some(watch_scene(path, watch_tx.clone())?);
+    }
+
+    // Copy the initial raytracer texture over and display it.
+    ray_tracer.copy_to(&mut texture)?;
+    copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::none())?;
+
+    // Region selection state. The drag start is only set while the mouse button is held.
+    let mut region_mode = false;
+    let mut drag_start: Option<(i32, i32)> = None;
+
+    // Pixel inspection state.
+    let mut inspect_mode = false;
+
+    // Whether progressive passes are being rendered. Any other render restarts them.
+    let mut progressive = false;
+
+    // Point the camera stays aimed at while moving, if locked.
+    let mut camera_target: Option<math::Vec3> = None;
+
+    // System clipboard, opened on the first copy.
+    let mut clipboard: Option<arboard::Clipboard> = None;
+
+    // Extra keys from the config, each mapped to the key of the action it triggers.
+    let mut key_bindings: HashMap<Keycode, Keycode> = HashMap::new();
+    for (action, key) in extra_keys.iter() {
+        match (Keycode::from_name(action), Keycode::from_name(key)) {
+            (Some(action), Some(key)) => {
+                key_bindings.insert(key, action);
             }
+            _ => warn!(target: "viewer", "Unknown key in the binding of {} to {}.", key, action)
         }
     }
-    let mat_1 = ray_tracer.add_dielectric_material(raytracer::Dielectric::new(1.5));
-    ray_tracer.add_sphere(raytracer::Sphere::new(math::Vec3::new(0.0, 1.0, 0.0), 1.0, mat_1));
-    let mat_2 = ray_tracer.add_lambertian_material(raytracer::Lambertian::new(math::Vec3::new(0.4, 0.2, 0.1)));
-    ray_tracer.add_sphere(raytracer::Sphere::new(math::Vec3::new(-4.0, 1.0, 0.0), 1.0, mat_2));
-    let mat_3 = ray_tracer.add_metal_material(raytracer::Metal::new(math::Vec3::new(0.7, 0.6, 0.5), 0.0));
-    ray_tracer.add_sphere(raytracer::Sphere::new(math::Vec3::new(4.0, 1.0, 0.0), 1.0, mat_3));
-    //
-    // let mat_left = ray_tracer.add_lambertian_material(raytracer::Lambertian::new(math::Vec3::new(0.0, 0.0, 1.0)));
-    // let mat_right = ray_tracer.add_lambertian_material(raytracer::Lambertian::new(math::Vec3::new(1.0, 0.0, 0.0)));
-    // let R = (3.1415926 * 0.25 as f32).cos();
-    // ray_tracer.add_sphere(raytracer::Sphere::new(math::Vec3::new(-R, 0.0, -1.0), R, mat_left));
-    // ray_tracer.add_sphere(raytracer::Sphere::new(math::Vec3::new( R, 0.0, -1.0), R, mat_right));
-    //
-    // let mat_ground = ray_tracer.add_lambertian_material(raytracer::Lambertian::new(math::Vec3::new(0.8, 0.8, 0.0)));
-    // let mat_center = ray_tracer.add_lambertian_material(raytracer::Lambertian::new(math::Vec3::new(0.1, 0.2, 0.5)));
-    // let mat_left = ray_tracer.add_dielectric_material(raytracer::Dielectric::new(1.5));
-    // let mat_right = ray_tracer.add_metal_material(raytracer::Metal::new(math::Vec3::new(0.8, 0.6, 0.2), 0.0));
-    // ray_tracer.add_sphere(
-    //     raytracer::Sphere::new(
-    //         math::Vec3::new(0.0, -100.5, -1.0), 100.0,
-    //         mat_ground
-    //     )
-    // );
-    // ray_tracer.add_sphere(
-    //     raytracer::Sphere::new(
-    //         math::Vec3::new(0.0, 0.0, -1.0), 0.5,
-    //         mat_center
-    //     )
-    // );
-    // ray_tracer.add_sphere(
-    //     raytracer::Sphere::new(
-    //         math::Vec3::new(-1.0, 0.0, -1.0), -0.4,
-    //         mat_left
-    //     )
-    // );
-    // ray_tracer.add_sphere(
-    //     raytracer::Sphere::new(
-    //         math::Vec3::new(1.0, 0.0, -1.0), 0.5,
-    //         mat_right
-    //     )
-    // );
 
-    // Copy the initial raytracer texture over and display it.
-    ray_tracer.copy_to(&mut texture);
-    copy_texture_to_canvas(&texture, &mut canvas, window_width, window_height);
+    // Last mouse position on the canvas, which zooming centers on.
+    let mut mouse_position = (0, 0);
+
+    // Writes saved images without stalling the viewer.
+    let mut saver = saver::BackgroundSaver::new();
+    let mut snapshots = snapshot::SnapshotSchedule::new(snapshot_every, snapshot_samples);
 
     // Event loop.
-    let mut event_pump = sdl_context.event_pump()?;
+    let mut event_pump = sdl_context.event_pump().map_err(RaytracerError::Sdl)?;
     'running: loop {
-        for event in event_pump.poll_iter() {
+        for mut event in event_pump.poll_iter() {
+            if let Event::KeyDown{keycode: Some(ref mut keycode), ..} = event {
+                if let Some(action) = key_bindings.get(keycode) {
+                    *keycode = *action;
+                }
+            }
+            // Mouse positions are handled in image pixels, wherever the view is zoomed to.
+            match event {
+                Event::MouseMotion{ref mut x, ref mut y, ..} | Event::MouseButtonDown{ref mut x, ref mut y, ..} | Event::MouseButtonUp{ref mut x, ref mut y, ..} => {
+                    mouse_position = (*x, *y);
+                    let (image_x, image_y) = view.to_image(*x, *y);
+                    *x = image_x;
+                    *y = image_y;
+                }
+                _ => {}
+            }
             match event {
                 // Quit.
                 Event::Quit{..} | Event::KeyDown{keycode: Some(Keycode::Escape),..} => {
@@ -146,37 +381,930 @@ pub fn main() -> Result<(), String> {
 
                 // Run ray tracer and update preview.
                 Event::KeyDown{keycode: Some(Keycode::Space), repeat: false, ..} => {
-                    run_raytracer(&mut ray_tracer, &mut texture, &mut canvas, window_width, window_height);
+                    run_raytracer(&mut ray_tracer, &scene, &mut texture, &mut canvas, &view)?;
+                    save_at_limit(&ray_tracer, &mut saver);
+                }
+
+                // Start, pause, or resume the progressive render.
+                Event::KeyDown{keycode: Some(Keycode::Return), repeat: false, ..} => {
+                    progressive = !progressive;
+                    if progressive {
+                        info!(target: "viewer", "Progressive render {}.", if ray_tracer.is_progressive() {"resumed"} else {"started"});
+                        snapshots.restart(if ray_tracer.is_progressive() {ray_tracer.average_samples()} else {0.0});
+                    } else {
+                        let samples = ray_tracer.average_samples();
+                        info!(target: "viewer", "Progressive render paused at {:.1} samples per pixel.", samples);
+                        if !inspect_mode {
+                            let title = format!("RS Raytracer - paused at {:.1} spp", samples);
+                            canvas.window_mut().set_title(&title).map_err(|e| RaytracerError::Sdl(e.to_string()))?;
+                        }
+                    }
+                }
+
+                // Cycle the bloom post effect. The image is shown again without rendering.
+                Event::KeyDown{keycode: Some(Keycode::G), repeat: false, ..} => {
+                    let bloom = match ray_tracer.get_settings().bloom {
+                        None => Some(post::Bloom::new()),
+                        Some(bloom) if bloom.streaks == 0 => Some(post::Bloom{streaks: GLARE_STREAKS, ..bloom}),
+                        Some(_) => None
+                    };
+                    info!(target: "viewer", "Bloom {}.", match bloom {
+                        None => "off",
+                        Some(bloom) if bloom.streaks == 0 => "on",
+                        Some(_) => "on with glare"
+                    });
+                    ray_tracer.get_settings_mut().bloom = bloom;
+                    ray_tracer.copy_to(&mut texture)?;
+                    copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::split(ray_tracer.get_compare_split()))?;
+                }
+
+                // Toggle autofocus, and render with it.
+                Event::KeyDown{keycode: Some(Keycode::F), keymod, repeat: false, ..} if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) => {
+                    let settings = ray_tracer.get_settings_mut();
+                    settings.autofocus = !settings.autofocus;
+                    info!(target: "viewer", "Autofocus {}.", if settings.autofocus {"on"} else {"off"});
+                    draft_render(&mut ray_tracer, &scene, &mut texture, &mut canvas, &view)?;
+                }
+
+                // Toggle the lens effects. The image is shown again without rendering.
+                Event::KeyDown{keycode: Some(Keycode::F), repeat: false, ..} => {
+                    let lens = match ray_tracer.get_settings().lens {
+                        None => Some(post::LensEffects::new()),
+                        Some(_) => None
+                    };
+                    info!(target: "viewer", "Lens effects {}.", if lens.is_some() {"on"} else {"off"});
+                    ray_tracer.get_settings_mut().lens = lens;
+                    ray_tracer.copy_to(&mut texture)?;
+                    copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::split(ray_tracer.get_compare_split()))?;
+                }
+
+                // Adjust the white balance. The image is shown again without rendering.
+                Event::KeyDown{keycode: Some(keycode @ (Keycode::K | Keycode::J | Keycode::N)), keymod, repeat: false, ..} => {
+                    let raise = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                    let settings = ray_tracer.get_settings_mut();
+                    match keycode {
+                        Keycode::K => {
+                            let step = if raise {WHITE_BALANCE_STEP} else {-WHITE_BALANCE_STEP};
+                            settings.white_balance.temperature = (settings.white_balance.temperature + step).clamp(2000.0, 15000.0);
+                        }
+                        Keycode::J => {
+                            let step = if raise {TINT_STEP} else {-TINT_STEP};
+                            settings.white_balance.tint = (settings.white_balance.tint + step).clamp(-1.0, 1.0);
+                        }
+                        _ => settings.auto_white_balance = !settings.auto_white_balance
+                    }
+                    info!(target: "viewer", "White balance {:.0}K, tint {:.1}, auto {}.", settings.white_balance.temperature, settings.white_balance.tint,
+                        if settings.auto_white_balance {"on"} else {"off"});
+                    ray_tracer.copy_to(&mut texture)?;
+                    copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::split(ray_tracer.get_compare_split()))?;
+                }
+
+                // Render a quality ladder, showing and saving every stage.
+                Event::KeyDown{keycode: Some(Keycode::L), repeat: false, ..} => {
+                    let max_samples = ray_tracer.get_settings().samples_per_pixel;
+                    ray_tracer.run_ladder(&scene, max_samples, |rt, stage| {
+                        if let Err(e) = rt.save_as_png_to(&rt.output_path(&format!("{}_{}spp.png", raytracer::LADDER_OUT, stage))) {
+                            error!(target: "io", "Failed to write ladder stage: {}", e);
+                        }
+                        rt.copy_to(&mut texture)?;
+                        copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::split(rt.get_compare_split()))
+                    })?;
                 }
 
                 // Save ray tracer result to file.
                 Event::KeyDown{keycode: Some(Keycode::S), repeat: false, ..} => {
-                    ray_tracer.save_as_ppm().expect("Failed to write PPM file.");
+                    save_in_background(&mut saver, || vec![ray_tracer.ppm_job()]);
+                }
+
+                // Save the ray tracer result at several exposures, to pick one later.
+                Event::KeyDown{keycode: Some(Keycode::P), keymod, repeat: false, ..} if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) => {
+                    save_in_background(&mut saver, || ray_tracer.exposure_bracket_jobs(&[-bracket_stops, 0.0, bracket_stops]));
+                }
+
+                // Save ray tracer result to a PNG file with alpha.
+                Event::KeyDown{keycode: Some(Keycode::P), repeat: false, ..} => {
+                    save_in_background(&mut saver, || vec![ray_tracer.png_job(&ray_tracer.output_path(raytracer::PNG_OUT))]);
+                }
+
+                // Save the depth pass for compositing.
+                Event::KeyDown{keycode: Some(Keycode::D), repeat: false, ..} => {
+                    let (near, far) = ray_tracer.depth_clips();
+                    info!(target: "io", "Depth clips: {} to {}.", near, far);
+                    if let Err(e) = ray_tracer.save_depth_as_png() {
+                        error!(target: "io", "Failed to write depth PNG file: {}", e);
+                    }
+                    if let Err(e) = ray_tracer.save_depth_as_exr() {
+                        error!(target: "io", "Failed to write depth EXR file: {}", e);
+                    }
+                }
+
+                // Save the ID passes for compositing.
+                Event::KeyDown{keycode: Some(Keycode::O), repeat: false, ..} => {
+                    for pass in [raytracer::IdPass::Object, raytracer::IdPass::Material].iter() {
+                        if let Err(e) = ray_tracer.save_id_pass_as_exr(*pass) {
+                            error!(target: "io", "Failed to write {:?} ID pass: {}", pass, e);
+                        }
+                    }
+                }
+
+                // Lock the camera on the surface in the middle of the view.
+                Event::KeyDown{keycode: Some(Keycode::T), repeat: false, ..} => {
+                    camera_target = match camera_target {
+                        Some(_) => {
+                            info!(target: "viewer", "Camera unlocked.");
+                            None
+                        }
+                        None => {
+                            let target = center_target(&scene);
+                            match target {
+                                Some(p) => info!(target: "viewer", "Camera locked on ({}, {}, {}).", p.x, p.y, p.z),
+                                None => warn!(target: "viewer", "Nothing in the middle of the view to lock on.")
+                            }
+                            target
+                        }
+                    };
+                }
+
+                // Dolly zoom, keeping the target's plane the same size while the perspective changes.
+                Event::KeyDown{keycode: Some(keycode @ (Keycode::Up | Keycode::Down)), keymod, repeat: false, ..} if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) => {
+                    let distance = if keycode == Keycode::Up {CAMERA_STEP} else {-CAMERA_STEP};
+                    match camera_target.or_else(|| center_target(&scene)) {
+                        Some(target) => match scene.get_camera().dolly_zoom(distance, target) {
+                            Some(zoomed) => {
+                                info!(target: "viewer", "Field of view: {:.1} degrees.", zoomed.get_vertical_fov());
+                                history.execute(Box::new(history::SetCamera::new(zoomed)), &mut scene)?;
+                                draft_render(&mut ray_tracer, &scene, &mut texture, &mut canvas, &view)?;
+                            }
+                            None => warn!(target: "viewer", "The camera can't dolly zoom any closer.")
+                        }
+                        None => warn!(target: "viewer", "Nothing in the middle of the view to dolly zoom on.")
+                    }
+                }
+
+                // Move the camera along its own axes, still aimed at the target if locked.
+                Event::KeyDown{keycode: Some(keycode @ (Keycode::Left | Keycode::Right | Keycode::Up | Keycode::Down | Keycode::PageUp | Keycode::PageDown)), repeat: false, ..} => {
+                    let (right, up, forward) = match keycode {
+                        Keycode::Left => (-CAMERA_STEP, 0.0, 0.0),
+                        Keycode::Right => (CAMERA_STEP, 0.0, 0.0),
+                        Keycode::Up => (0.0, 0.0, CAMERA_STEP),
+                        Keycode::Down => (0.0, 0.0, -CAMERA_STEP),
+                        Keycode::PageUp => (0.0, CAMERA_STEP, 0.0),
+                        _ => (0.0, -CAMERA_STEP, 0.0)
+                    };
+                    let moved = match camera_target {
+                        Some(target) => scene.get_camera().tracking(right, up, forward, target),
+                        None => scene.get_camera().moved(right, up, forward)
+                    };
+                    history.execute(Box::new(history::SetCamera::new(moved)), &mut scene)?;
+                    draft_render(&mut ray_tracer, &scene, &mut texture, &mut canvas, &view)?;
+                }
+
+                // Roll the camera about its view direction, or level it again.
+                Event::KeyDown{keycode: Some(keycode @ (Keycode::Comma | Keycode::Period | Keycode::Slash)), repeat: false, ..} => {
+                    let cam = scene.get_camera();
+                    let rolled = match keycode {
+                        Keycode::Comma => cam.rolled(-ROLL_STEP),
+                        Keycode::Period => cam.rolled(ROLL_STEP),
+                        _ => cam.leveled()
+                    };
+                    info!(target: "viewer", "Camera roll: {:.1} degrees.", rolled.get_roll());
+                    history.execute(Box::new(history::SetCamera::new(rolled)), &mut scene)?;
+                    draft_render(&mut ray_tracer, &scene, &mut texture, &mut canvas, &view)?;
+                }
+
+                // Move the near clip.
+                Event::KeyDown{keycode: Some(keycode @ (Keycode::LeftBracket | Keycode::RightBracket)), repeat: false, ..} => {
+                    let mut cam = *scene.get_camera();
+                    let clips = cam.get_clip_range();
+                    let step = if keycode == Keycode::RightBracket {CAMERA_STEP} else {-CAMERA_STEP};
+                    cam.set_clips(clips.min + step, clips.max);
+                    info!(target: "viewer", "Near clip: {:.3}.", cam.get_clip_range().min);
+                    history.execute(Box::new(history::SetCamera::new(cam)), &mut scene)?;
+                    draft_render(&mut ray_tracer, &scene, &mut texture, &mut canvas, &view)?;
+                }
+
+                // Switch to a named camera.
+                Event::KeyDown{keycode: Some(keycode @ (Keycode::Num1 | Keycode::Num2 | Keycode::Num3 | Keycode::Num4 | Keycode::Num5 | Keycode::Num6 | Keycode::Num7 | Keycode::Num8 | Keycode::Num9)), repeat: false, ..} => {
+                    let idx = (keycode as i32 - Keycode::Num1 as i32) as u32;
+                    match scene.get_named_camera(idx) {
+                        Ok((name, cam)) => {
+                            info!(target: "viewer", "Camera {}: {}.", idx + 1, name);
+                            let cam = *cam;
+                            history.execute(Box::new(history::SetCamera::new(cam)), &mut scene)?;
+                            draft_render(&mut ray_tracer, &scene, &mut texture, &mut canvas, &view)?;
+                        }
+                        Err(_) => warn!(target: "viewer", "The scene has {} named cameras.", scene.named_camera_count())
+                    }
+                }
+
+                // Undo and redo edits.
+                Event::KeyDown{keycode: Some(keycode @ (Keycode::Z | Keycode::Y)), keymod, repeat: false, ..} if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => {
+                    let edit = if keycode == Keycode::Z {history.undo(&mut scene)} else {history.redo(&mut scene)};
+                    match edit {
+                        Ok(Some(name)) => {
+                            info!(target: "viewer", "{}: {}.", if keycode == Keycode::Z {"Undid"} else {"Redid"}, name);
+                            draft_render(&mut ray_tracer, &scene, &mut texture, &mut canvas, &view)?;
+                        }
+                        Ok(None) => info!(target: "viewer", "Nothing to {}.", if keycode == Keycode::Z {"undo"} else {"redo"}),
+                        Err(e) => error!(target: "scene", "Failed to {}: {}", if keycode == Keycode::Z {"undo"} else {"redo"}, e)
+                    }
                 }
 
                 // Update preview from raytracer (if window focus is lost during a ray trace, it may not update).
                 Event::KeyDown{keycode: Some(Keycode::U), repeat: false, ..} => {
-                    ray_tracer.copy_to(&mut texture);
-                    copy_texture_to_canvas(&mut texture, &mut canvas, window_width, window_height);
+                    ray_tracer.copy_to(&mut texture)?;
+                    copy_texture_to_canvas(&mut texture, &mut canvas, &view, &Overlay::split(ray_tracer.get_compare_split()))?;
+                }
+
+                // Toggle region selection mode.
+                Event::KeyDown{keycode: Some(Keycode::R), repeat: false, ..} => {
+                    region_mode = !region_mode;
+                    drag_start = None;
+                    info!(target: "viewer", "Region selection mode {}.", if region_mode {"enabled"} else {"disabled"});
+                    let mut overlay = Overlay::split(ray_tracer.get_compare_split());
+                    overlay.region = if region_mode {ray_tracer.get_settings().region} else {None};
+                    copy_texture_to_canvas(&texture, &mut canvas, &view, &overlay)?;
+                }
+
+                // Copy the image to the clipboard.
+                Event::KeyDown{keycode: Some(Keycode::C), keymod, repeat: false, ..} if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => {
+                    match copy_to_clipboard(&ray_tracer, &mut clipboard) {
+                        Ok(()) => info!(target: "viewer", "Copied the image to the clipboard."),
+                        Err(e) => error!(target: "viewer", "Failed to copy the image: {}", e)
+                    }
+                }
+
+                // Clear the render region.
+                Event::KeyDown{keycode: Some(Keycode::C), repeat: false, ..} => {
+                    ray_tracer.get_settings_mut().region = None;
+                    info!(target: "viewer", "Render region cleared.");
+                    copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::split(ray_tracer.get_compare_split()))?;
+                }
+
+                // Toggle pixel inspection mode.
+                Event::KeyDown{keycode: Some(Keycode::I), repeat: false, ..} => {
+                    inspect_mode = !inspect_mode;
+                    info!(target: "viewer", "Pixel inspection mode {}.", if inspect_mode {"enabled"} else {"disabled"});
+                    if !inspect_mode {
+                        canvas.window_mut().set_title("RS Raytracer").map_err(|e| RaytracerError::Sdl(e.to_string()))?;
+                    }
+                }
+
+                // Focus on the surface under the mouse.
+                Event::MouseButtonDown{mouse_btn: MouseButton::Right, x, y, ..} if x >= 0 && y >= 0 => {
+                    match ray_tracer.focus_distance_at(&scene, x as u32, y as u32) {
+                        Some(distance) => {
+                            let mut cam = *scene.get_camera();
+                            cam.set_focus_distance(distance);
+                            info!(target: "viewer", "Focus distance: {:.3}.", distance);
+                            if ray_tracer.get_settings().physical_exposure.is_none() {
+                                info!(target: "viewer", "Only renders with an f-stop (see --f-stop) have depth of field.");
+                            }
+                            history.execute(Box::new(history::SetCamera::new(cam)), &mut scene)?;
+                            draft_render(&mut ray_tracer, &scene, &mut texture, &mut canvas, &view)?;
+                        }
+                        None => info!(target: "viewer", "Nothing to focus on at ({}, {}).", x, y)
+                    }
+                }
+
+                // Start dragging out a region.
+                Event::MouseButtonDown{mouse_btn: MouseButton::Left, x, y, ..} if region_mode => {
+                    drag_start = Some((x, y));
+                }
+
+                // Preview the region being dragged out.
+                Event::MouseMotion{x, y, ..} if region_mode && drag_start.is_some() => {
+                    let (start_x, start_y) = drag_start.unwrap();
+                    let mut overlay = Overlay::split(ray_tracer.get_compare_split());
+                    overlay.region = Some(raytracer::Region::from_corners(start_x, start_y, x, y).clamped(image_width, image_height));
+                    copy_texture_to_canvas(&texture, &mut canvas, &view, &overlay)?;
+                }
+
+                // Finish dragging and apply the region.
+                Event::MouseButtonUp{mouse_btn: MouseButton::Left, x, y, ..} if region_mode && drag_start.is_some() => {
+                    let (start_x, start_y) = drag_start.take().unwrap();
+                    let region = raytracer::Region::from_corners(start_x, start_y, x, y).clamped(image_width, image_height);
+                    if region.is_empty() {
+                        warn!(target: "viewer", "Ignoring empty render region.");
+                    } else {
+                        info!(target: "viewer", "Render region set to {}x{} at ({}, {}).", region.width, region.height, region.x, region.y);
+                        ray_tracer.get_settings_mut().region = Some(region);
+                    }
+                    let mut overlay = Overlay::split(ray_tracer.get_compare_split());
+                    overlay.region = ray_tracer.get_settings().region;
+                    copy_texture_to_canvas(&texture, &mut canvas, &view, &overlay)?;
+                }
+
+                // Toggle the cost heatmap.
+                Event::KeyDown{keycode: Some(Keycode::H), repeat: false, ..} => {
+                    let mode = if ray_tracer.get_display_mode() == raytracer::DisplayMode::CostHeatmap {
+                        raytracer::DisplayMode::Color
+                    } else {
+                        raytracer::DisplayMode::CostHeatmap
+                    };
+                    ray_tracer.set_display_mode(mode);
+                    ray_tracer.copy_to(&mut texture)?;
+                    copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::split(ray_tracer.get_compare_split()))?;
+                }
+
+                // Toggle the exposure overlay.
+                Event::KeyDown{keycode: Some(Keycode::E), repeat: false, ..} => {
+                    let enabled = !ray_tracer.get_exposure_overlay();
+                    ray_tracer.set_exposure_overlay(enabled);
+                    if enabled {
+                        let histogram = ray_tracer.histogram(1);
+                        let total = histogram.bins[0].max(1) as f32;
+                        info!(target: "viewer", "Exposure overlay on: {:.1}% black, {:.1}% clipped.",
+                            100.0 * (histogram.under as f32) / total, 100.0 * (histogram.over as f32) / total);
+                    } else {
+                        info!(target: "viewer", "Exposure overlay off.");
+                    }
+                    ray_tracer.copy_to(&mut texture)?;
+                    copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::split(ray_tracer.get_compare_split()))?;
+                }
+
+                // Toggle low priority rendering, which applies from the next render or pass.
+                Event::KeyDown{keycode: Some(Keycode::Q), repeat: false, ..} => {
+                    let settings = ray_tracer.get_settings_mut();
+                    settings.low_priority = !settings.low_priority;
+                    info!(target: "viewer", "Low priority rendering {}.", if settings.low_priority {"on"} else {"off"});
+                }
+
+                // Switch between path tracing and the fast raycast mode, and render in the new one.
+                Event::KeyDown{keycode: Some(Keycode::Tab), repeat: false, ..} => {
+                    let settings = ray_tracer.get_settings_mut();
+                    settings.mode = if settings.mode == raytracer::RenderMode::Raycast {raytracer::RenderMode::PathTrace} else {raytracer::RenderMode::Raycast};
+                    info!(target: "viewer", "Raycast mode {}.", if settings.mode == raytracer::RenderMode::Raycast {"on"} else {"off"});
+                    run_raytracer(&mut ray_tracer, &scene, &mut texture, &mut canvas, &view)?;
+                }
+
+                // Toggle the false color view.
+                Event::KeyDown{keycode: Some(Keycode::X), repeat: false, ..} => {
+                    let mode = if ray_tracer.get_display_mode() == raytracer::DisplayMode::FalseColor {
+                        raytracer::DisplayMode::Color
+                    } else {
+                        raytracer::DisplayMode::FalseColor
+                    };
+                    ray_tracer.set_display_mode(mode);
+                    ray_tracer.copy_to(&mut texture)?;
+                    copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::split(ray_tracer.get_compare_split()))?;
+                }
+
+                // Cycle the BVH views.
+                Event::KeyDown{keycode: Some(Keycode::V), repeat: false, ..} => {
+                    let mode = match ray_tracer.get_display_mode() {
+                        raytracer::DisplayMode::BvhVisits => raytracer::DisplayMode::BvhLeafDepth,
+                        raytracer::DisplayMode::BvhLeafDepth => raytracer::DisplayMode::Color,
+                        _ => raytracer::DisplayMode::BvhVisits
+                    };
+                    info!(target: "viewer", "Display mode: {:?}.", mode);
+                    ray_tracer.set_display_mode(mode);
+                    ray_tracer.copy_to(&mut texture)?;
+                    copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::split(ray_tracer.get_compare_split()))?;
+                }
+
+                // Cycle the debug overlay.
+                Event::KeyDown{keycode: Some(Keycode::W), repeat: false, ..} => {
+                    let overlay = match ray_tracer.get_debug_overlay() {
+                        raytracer::DebugOverlay::None => raytracer::DebugOverlay::Edges,
+                        raytracer::DebugOverlay::Edges => raytracer::DebugOverlay::BvhBoxes(BVH_OVERLAY_DEPTH),
+                        raytracer::DebugOverlay::BvhBoxes(_) => raytracer::DebugOverlay::None
+                    };
+                    info!(target: "viewer", "Debug overlay: {:?}.", overlay);
+                    ray_tracer.set_debug_overlay(overlay, &scene);
+                    ray_tracer.copy_to(&mut texture)?;
+                    copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::split(ray_tracer.get_compare_split()))?;
+                }
+
+                // Print error metrics against the reference image.
+                Event::KeyDown{keycode: Some(Keycode::M), repeat: false, ..} => {
+                    match &reference_path {
+                        Some(path) => {
+                            match ray_tracer.compare_to(raytracer::Reference::File(path)) {
+                                Ok(image_metrics) => info!(target: "metrics", "Compared to {}: {}", path, image_metrics),
+                                Err(e) => error!(target: "metrics", "Failed to compare to {}: {}", path, e)
+                            }
+                        }
+                        None => warn!(target: "metrics", "No reference image. Pass one with --reference <file.ppm>.")
+                    }
+                }
+
+                // Stash the current buffer for comparison.
+                Event::KeyDown{keycode: Some(Keycode::A), repeat: false, ..} => {
+                    ray_tracer.stash_for_compare();
+                    info!(target: "viewer", "Stashed current buffer for comparison.");
+                }
+
+                // Toggle the A/B split view.
+                Event::KeyDown{keycode: Some(Keycode::B), repeat: false, ..} => {
+                    if !ray_tracer.has_compare_stash() {
+                        warn!(target: "viewer", "Nothing to compare against. Press A to stash a buffer first.");
+                    } else {
+                        let split = if ray_tracer.get_compare_split().is_some() {None} else {Some(image_width / 2)};
+                        ray_tracer.set_compare_split(split);
+                        ray_tracer.copy_to(&mut texture)?;
+                        copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::split(split))?;
+                    }
+                }
+
+                // Zoom in or out around the mouse.
+                Event::MouseWheel{y, ..} if y != 0 => {
+                    view.zoom_at(y, mouse_position.0, mouse_position.1);
+                    info!(target: "viewer", "Zoomed to {}%.", (view.get_scale() * 100.0).round());
+                    if texture_size(&texture) != view.texture_size() {
+                        texture = create_texture(&texture_creator, &view)?;
+                        ray_tracer.copy_to(&mut texture)?;
+                    }
+                    copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::split(ray_tracer.get_compare_split()))?;
+                }
+
+                // Show the whole image again.
+                Event::KeyDown{keycode: Some(Keycode::Num0), repeat: false, ..} => {
+                    view.reset();
+                    if texture_size(&texture) != view.texture_size() {
+                        texture = create_texture(&texture_creator, &view)?;
+                        ray_tracer.copy_to(&mut texture)?;
+                    }
+                    copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::split(ray_tracer.get_compare_split()))?;
+                }
+
+                // Pan the zoomed view.
+                Event::MouseMotion{xrel, yrel, mousestate, ..} if mousestate.middle() => {
+                    view.pan(xrel, yrel);
+                    copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::split(ray_tracer.get_compare_split()))?;
+                }
+
+                // Drag the A/B split.
+                Event::MouseMotion{x, mousestate, ..} if !region_mode && mousestate.left() && ray_tracer.get_compare_split().is_some() => {
+                    ray_tracer.set_compare_split(Some(x.max(0) as u32));
+                    ray_tracer.copy_to(&mut texture)?;
+                    copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::split(ray_tracer.get_compare_split()))?;
+                }
+
+                // Show the values of the hovered pixel.
+                Event::MouseMotion{x, y, ..} if inspect_mode => {
+                    if let Some(info) = ray_tracer.pixel_info(x.max(0) as u32, y.max(0) as u32) {
+                        let title = format!(
+                            "RS Raytracer - ({}, {}) linear: {:.4} {:.4} {:.4} | alpha: {:.3} | display: {} {} {} | samples: {} | cost: {} | BVH visits: {} | leaf depth: {} | distance: {} | object: {} | material: {}",
+                            x, y,
+                            info.linear.x, info.linear.y, info.linear.z, info.alpha,
+                            info.display.0, info.display.1, info.display.2,
+                            info.samples, info.cost, info.bvh_visits,
+                            info.leaf_depth.map_or("-".to_string(), |depth| depth.to_string()),
+                            info.distance.map_or("-".to_string(), |distance| format!("{:.3}", distance)),
+                            info.object_id.map_or("-".to_string(), |id| scene.describe_object(id)),
+                            info.material_id.map_or("-".to_string(), |id| scene.describe_material(id))
+                        );
+                        canvas.window_mut().set_title(&title).map_err(|e| RaytracerError::Sdl(e.to_string()))?;
+                    }
+                }
+
+                // Dropping a model onto the window adds it to the scene, and dropping a scene file replaces the scene.
+                Event::DropFile{filename, ..} => {
+                    if is_model_file(&filename) {
+                        let material_count = scene.material_count();
+                        let mat = scene.add_lambertian_material(raytracer::Lambertian::new(math::Vec3::new(0.5, 0.5, 0.5)));
+                        let result = match std::path::Path::new(&filename).extension().map(|e| e.to_string_lossy().to_lowercase()).as_deref() {
+                            Some("obj") => obj_loader::load_model(&mut scene, &filename, mat),
+                            _ => gltf_loader::load_model(&mut scene, &filename, mat)
+                        };
+                        match result {
+                            Ok(()) => {
+                                info!(target: "scene", "Scene statistics:\n{}", ray_tracer.scene_stats(&scene));
+                                if let Err(e) = draft_render(&mut ray_tracer, &scene, &mut texture, &mut canvas, &view) {
+                                    error!(target: "viewer", "Failed to render {}: {}", filename, e);
+                                }
+                            }
+                            Err(e) => {
+                                error!(target: "scene", "Failed to add {}: {}", filename, e);
+                                scene.truncate_materials(material_count);
+                            }
+                        }
+                    } else if is_data_scene_file(&filename) {
+                        error!(target: "viewer", "Can't replace the scene with {}: TOML and JSON scene files aren't supported. Drop a PBRT, Rhai, or USD scene to replace it.", filename);
+                    } else {
+                        // Loaded aside, so that a file that fails to load leaves the current scene.
+                        let mut dropped = scene::Scene::new();
+                        dropped.get_assets_mut().set_texture_budget(texture_budget);
+                        match setup_scene(&mut dropped, Some(&filename), None, script_path.as_deref(), backplate.as_ref()) {
+                            Ok(()) => {
+                                info!(target: "viewer", "Replaced the scene with {}.", filename);
+                                scene = dropped;
+                                history.clear();
+                                camera_target = None;
+                                if scene_watcher.is_some() {
+                                    scene_watcher = match watch_scene(&filename, watch_tx.clone()) {
+                                        Ok(watcher) => Some(watcher),
+                                        Err(e) => {
+                                            error!(target: "viewer", "Failed to watch {}: {}", filename, e);
+                                            None
+                                        }
+                                    };
+                                }
+                                ray_tracer.set_scene_name(&scene_name(Some(&filename)));
+                                info!(target: "scene", "Scene statistics:\n{}", ray_tracer.scene_stats(&scene));
+                                if let Err(e) = draft_render(&mut ray_tracer, &scene, &mut texture, &mut canvas, &view) {
+                                    error!(target: "viewer", "Failed to render {}: {}", filename, e);
+                                }
+                                scene_path = Some(filename);
+                            }
+                            Err(e) => error!(target: "scene", "Failed to load {}: {}", filename, e)
+                        }
+                    }
                 }
 
                 _ => {}
             }
         }
+
+        // Reload and draft render the scene when the watched file changes.
+        if let (Some((_, scene_file)), Some(path)) = (&scene_watcher, &scene_path) {
+            let mut changed = false;
+            while let Ok(event) = watch_rx.try_recv() {
+                match event {
+                    notify::DebouncedEvent::Write(p) | notify::DebouncedEvent::Create(p) | notify::DebouncedEvent::Rename(_, p) => {
+                        changed |= &p == scene_file;
+                    }
+                    notify::DebouncedEvent::Error(e, _) => warn!(target: "viewer", "File watcher error: {}", e),
+                    _ => {}
+                }
+            }
+            if changed {
+                // Edits were made to the old scene, so they can't be undone on the new one.
+                history.clear();
+                if reload_scene(&ray_tracer, &mut scene, path, script_path.as_deref(), backplate.as_ref()) {
+                    draft_render(&mut ray_tracer, &scene, &mut texture, &mut canvas, &view)?;
+                }
+            }
+        }
+
+        // Refine the progressive render by a pass between events.
+        if progressive {
+            ray_tracer.run_pass(&scene, PROGRESSIVE_PASS_SAMPLES);
+            ray_tracer.copy_to(&mut texture)?;
+            copy_texture_to_canvas(&texture, &mut canvas, &view, &Overlay::split(ray_tracer.get_compare_split()))?;
+            // Stops refining, as further passes would trace nothing.
+            if ray_tracer.limit_reached() {
+                progressive = false;
+                save_at_limit(&ray_tracer, &mut saver);
+            } else if snapshots.is_enabled() {
+                save_snapshot(&ray_tracer, &mut saver, &mut snapshots);
+            }
+            if !inspect_mode {
+                let status = if progressive {""} else {"stopped at "};
+                let title = format!("RS Raytracer - {}{:.1} spp", status, ray_tracer.average_samples());
+                canvas.window_mut().set_title(&title).map_err(|e| RaytracerError::Sdl(e.to_string()))?;
+            }
+        }
+
+        // Report saves that finished in the background.
+        if let Some((paths, result)) = saver.poll() {
+            if report_save(&paths, result) && !inspect_mode {
+                let title = format!("RS Raytracer - saved {}", paths.join(", "));
+                canvas.window_mut().set_title(&title).map_err(|e| RaytracerError::Sdl(e.to_string()))?;
+            }
+        }
     }
 
+    // Files still being written are finished before quitting.
+    if saver.is_busy() {
+        info!(target: "io", "Finishing saves before quitting...");
+    }
+    if let Some((paths, result)) = saver.wait() {
+        report_save(&paths, result);
+    }
 
     Ok(())
 }
 
-fn run_raytracer(rt: &mut raytracer::RSRaytracer, texture: &mut sdl2::render::Texture, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, width: u32, height: u32) {
-    rt.run();
-    rt.copy_to(texture);
-    copy_texture_to_canvas(texture, canvas, width, height);
+// Loads the scene file, or builds a built-in scene, or the default scene without either, then runs the script on top of it.
+fn setup_scene(scene: &mut scene::Scene, scene_path: Option<&str>, builtin: Option<&options::BuiltinScene>, script_path: Option<&str>, backplate: Option<&image::Image>) -> Result<(), RaytracerError> {
+    match (scene_path, builtin) {
+        (Some(path), _) => load_scene(scene, path)?,
+        (None, Some(builtin)) => builtin.build(scene)?,
+        (None, None) => setup_default_scene(scene)?
+    }
+    if let Some(path) = script_path {
+        script_loader::load(scene, path)?;
+    }
+    if let Some(backplate) = backplate {
+        scene.set_backplate(Some(backplate.clone()));
+    }
+    scene.get_assets().log_usage();
+    Ok(())
+}
+
+// File name of the scene, for the stamp.
+fn scene_name(scene_path: Option<&str>) -> String {
+    match scene_path {
+        Some(path) => std::path::Path::new(path).file_name().map_or(path.to_string(), |name| name.to_string_lossy().into_owned()),
+        None => "Default spheres".to_string()
+    }
+}
+
+// Whether a file is a model to add to the scene rather than a scene to replace it with.
+fn is_model_file(path: &str) -> bool {
+    let extension = std::path::Path::new(path).extension().map(|e| e.to_string_lossy().to_lowercase());
+    matches!(extension.as_deref(), Some("obj") | Some("gltf") | Some("glb"))
+}
+
+// Whether a file is a TOML or JSON scene description, which there is no loader for.
+fn is_data_scene_file(path: &str) -> bool {
+    let extension = std::path::Path::new(path).extension().map(|e| e.to_string_lossy().to_lowercase());
+    matches!(extension.as_deref(), Some("toml") | Some("json"))
+}
+
+// Picks the loader from the file extension.
+fn load_scene(scene: &mut scene::Scene, path: &str) -> Result<(), RaytracerError> {
+    let extension = std::path::Path::new(path).extension().map(|e| e.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("gltf") | Some("glb") => gltf_loader::load(scene, path),
+        Some("obj") => obj_loader::load(scene, path),
+        Some("pbrt") => pbrt_loader::load(scene, path),
+        Some("rhai") => script_loader::load(scene, path),
+        #[cfg(feature = "usd")]
+        Some("usd") | Some("usda") | Some("usdz") => usd_loader::load(scene, path),
+        _ => Err(RaytracerError::InvalidArgument(format!("Unsupported scene file '{}'.", path)))
+    }
 }
 
-fn copy_texture_to_canvas(texture: &sdl2::render::Texture, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, width: u32, height: u32) {
+// Random spheres on a ground plane, as on the cover of Ray Tracing in One Weekend.
+fn setup_default_scene(scene: &mut scene::Scene) -> Result<(), RaytracerError> {
+    let ground_mat = scene.add_lambertian_material(raytracer::Lambertian::new(math::Vec3::new(0.5, 0.5, 0.5)));
+    scene.add_plane(primitives::Plane::new(math::Vec3::zero(), math::Vec3::new(0.0, 1.0, 0.0), ground_mat))?;
+    let mut rand_gen = math::RandGen::new();
+    for x in -11..11 {
+        for y in -11..11 {
+            let center = math::Vec3::new(
+                (x as math::Float) + rand_gen.next01() * 0.9, 0.2, (y as math::Float) + rand_gen.next01() * 0.9
+            );
+
+            if (center - math::Vec3::new(4.0, 0.2, 0.0)).length() > 0.9 {
+                let rand_mat = rand_gen.next01();
+                if rand_mat < 0.8  {
+                    let albedo = math::Vec3::random() * math::Vec3::random();
+                    let mat = scene.add_lambertian_material(raytracer::Lambertian::new(albedo));
+                    scene.add_sphere(raytracer::Sphere::new(center, 0.2, mat))?;
+                } else if rand_mat < 0.95  {
+                    let albedo = math::Vec3::random_range(0.5..1.0);
+                    let fuzz = rand_gen.next_range(0.0..0.5);
+                    let mat = scene.add_metal_material(raytracer::Metal::new(albedo, fuzz));
+                    scene.add_sphere(raytracer::Sphere::new(center, 0.2, mat))?;
+                } else {
+                    let mat = scene.add_dielectric_material(raytracer::Dielectric::new(1.5));
+                    scene.add_sphere(raytracer::Sphere::new(center, 0.2, mat))?;
+                }
+            }
+        }
+    }
+    let mat_1 = scene.add_dielectric_material(raytracer::Dielectric::new(1.5));
+    scene.add_sphere(raytracer::Sphere::new(math::Vec3::new(0.0, 1.0, 0.0), 1.0, mat_1))?;
+    let mat_2 = scene.add_lambertian_material(raytracer::Lambertian::new(math::Vec3::new(0.4, 0.2, 0.1)));
+    scene.add_sphere(raytracer::Sphere::new(math::Vec3::new(-4.0, 1.0, 0.0), 1.0, mat_2))?;
+    let mat_3 = scene.add_metal_material(raytracer::Metal::new(math::Vec3::new(0.7, 0.6, 0.5), 0.0));
+    scene.add_sphere(raytracer::Sphere::new(math::Vec3::new(4.0, 1.0, 0.0), 1.0, mat_3))?;
+    Ok(())
+}
+
+// Renders each job of a batch file that an earlier run didn't finish, with its overrides of the settings.
+// A job that fails is logged and skipped, and is tried again when the batch is resumed.
+fn run_batch(path: &str, settings: &raytracer::RenderSettings, resolution: (u32, u32), output_directory: &str, script_path: Option<&str>, backplate: Option<&image::Image>, texture_budget: Option<usize>) -> Result<(), RaytracerError> {
+    let jobs = batch::read(path)?;
+    let mut progress = batch::BatchProgress::open(path)?;
+    let remaining = jobs.iter().filter(|job| !progress.is_done(job)).count();
+    if remaining < jobs.len() {
+        info!(target: "render", "Resuming batch {}: {} of {} jobs left.", path, remaining, jobs.len());
+    } else {
+        info!(target: "render", "Starting batch {} of {} jobs.", path, jobs.len());
+    }
+
+    let start_time = std::time::Instant::now();
+    let mut failed = 0;
+    for (idx, job) in jobs.iter().enumerate() {
+        if progress.is_done(job) {
+            continue;
+        }
+        let mut ray_tracer = raytracer::RSRaytracer::with_resolution(resolution.0, resolution.1);
+        *ray_tracer.get_settings_mut() = settings.clone();
+        job.apply(ray_tracer.get_settings_mut());
+        ray_tracer.set_output_directory(output_directory);
+        ray_tracer.set_scene_name(&scene_name(Some(&job.scene_path)));
+        let output = ray_tracer.output_path(&job.output_path());
+        info!(target: "render", "Job {}/{} (line {}): {} -> {}", idx + 1, jobs.len(), job.line, job.scene_path, output);
+        let job_start = std::time::Instant::now();
+
+        let mut scene = scene::Scene::new();
+        scene.get_assets_mut().set_texture_budget(texture_budget);
+        let result = setup_scene(&mut scene, Some(&job.scene_path), None, job.script_path.as_deref().or(script_path), backplate).and_then(|_| {
+            info!(target: "scene", "Scene statistics:\n{}", ray_tracer.scene_stats(&scene));
+            ray_tracer.run(&scene);
+            ray_tracer.save_as_png_to(&output)
+        });
+        match result {
+            Ok(()) => {
+                info!(target: "render", "Job {}/{} done in {:?}.", idx + 1, jobs.len(), job_start.elapsed());
+                progress.mark_done(job)?;
+            }
+            Err(e) => {
+                error!(target: "render", "Job {}/{} (line {}) failed: {}", idx + 1, jobs.len(), job.line, e);
+                failed += 1;
+            }
+        }
+    }
+
+    info!(target: "render", "Batch {} finished in {:?}.", path, start_time.elapsed());
+    if failed > 0 {
+        warn!(target: "render", "Rerun the batch to retry the failed jobs. Finished jobs are kept in {}.", progress.path());
+        return Err(RaytracerError::BatchFailed {
+            failed: failed,
+            total: jobs.len()
+        })
+    }
+    progress.finish()?;
+    Ok(())
+}
+
+// Renders each built-in material with a known result in the furnace and logs how far it is off.
+fn run_furnace_test() -> Result<(), RaytracerError> {
+    info!(target: "render", "Running the furnace test at {} samples per pixel.", furnace::SAMPLES_PER_PIXEL);
+    let reports = furnace::run_standard(furnace::SAMPLES_PER_PIXEL)?;
+    let mut failed = 0;
+    for report in reports.iter() {
+        if report.passes() {
+            info!(target: "render", "Passed {}", report);
+        } else {
+            error!(target: "render", "Failed {}", report);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(RaytracerError::FurnaceFailed{failed: failed, total: reports.len()})
+    }
+    info!(target: "render", "All {} materials passed the furnace test.", reports.len());
+    Ok(())
+}
+
+// Watches a scene file for the viewer to reload. The directory is watched as many editors save by replacing the file.
+fn watch_scene(path: &str, tx: std::sync::mpsc::Sender<notify::DebouncedEvent>) -> Result<(notify::RecommendedWatcher, std::path::PathBuf), RaytracerError> {
+    let scene_file = std::fs::canonicalize(path)?;
+    let directory = scene_file.parent().unwrap_or_else(|| std::path::Path::new("/")).to_path_buf();
+    let mut watcher = notify::watcher(tx, std::time::Duration::from_millis(250)).map_err(|e| RaytracerError::Watch(e.to_string()))?;
+    watcher.watch(&directory, notify::RecursiveMode::NonRecursive).map_err(|e| RaytracerError::Watch(e.to_string()))?;
+    info!(target: "viewer", "Watching {} for changes.", path);
+    Ok((watcher, scene_file))
+}
+
+// Reloads the scene file for a quick draft. Space renders at full quality again.
+// Returns whether the scene loaded, and so is worth rendering.
+fn reload_scene(rt: &raytracer::RSRaytracer, scene: &mut scene::Scene, path: &str, script_path: Option<&str>, backplate: Option<&image::Image>) -> bool {
+    info!(target: "viewer", "{} changed. Reloading...", path);
+    scene.clear();
+    if let Err(e) = setup_scene(scene, Some(path), None, script_path, backplate) {
+        error!(target: "scene", "Failed to reload {}: {}", path, e);
+        return false
+    }
+    info!(target: "scene", "Scene statistics:\n{}", rt.scene_stats(scene));
+    true
+}
+
+// Point on the surface in the middle of the view, if any.
+fn center_target(scene: &scene::Scene) -> Option<math::Vec3> {
+    let ray = scene.get_camera().get_ray(0.5, 0.5);
+    scene.hit(&ray, math::Interval::new(0.001, math::Float::MAX)).0.map(|rec| rec.p)
+}
+
+// Renders a quick, noisy draft with the draft settings' samples and depth, e.g. after an edit.
+fn draft_render(rt: &mut raytracer::RSRaytracer, scene: &scene::Scene, texture: &mut sdl2::render::Texture, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, view: &viewport::Viewport) -> Result<(), RaytracerError> {
+    let draft = raytracer::RenderSettings::draft();
+    let settings = rt.get_settings_mut();
+    let full = (settings.samples_per_pixel, settings.max_depth);
+    settings.samples_per_pixel = draft.samples_per_pixel;
+    settings.max_depth = draft.max_depth;
+    let result = run_raytracer(rt, scene, texture, canvas, view);
+    let settings = rt.get_settings_mut();
+    settings.samples_per_pixel = full.0;
+    settings.max_depth = full.1;
+    result
+}
+
+fn run_raytracer(rt: &mut raytracer::RSRaytracer, scene: &scene::Scene, texture: &mut sdl2::render::Texture, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, view: &viewport::Viewport) -> Result<(), RaytracerError> {
+    rt.run(scene);
+    rt.copy_to(texture)?;
+    copy_texture_to_canvas(texture, canvas, view, &Overlay::split(rt.get_compare_split()))
+}
+
+// Copies the image, as saved to PNG, to the system clipboard. The clipboard is kept open for the rest of
+// the viewer's run, as on Linux the copied image is only available while it is.
+fn copy_to_clipboard(rt: &raytracer::RSRaytracer, clipboard: &mut Option<arboard::Clipboard>) -> Result<(), RaytracerError> {
+    let clipboard = match clipboard {
+        Some(clipboard) => clipboard,
+        None => clipboard.insert(arboard::Clipboard::new().map_err(|e| RaytracerError::Clipboard(e.to_string()))?)
+    };
+    let (width, height) = rt.resolution();
+    let image = arboard::ImageData {
+        width: width as usize,
+        height: height as usize,
+        bytes: rt.rgba_image().into()
+    };
+    clipboard.set_image(image).map_err(|e| RaytracerError::Clipboard(e.to_string()))
+}
+
+// Saves what a render that stopped at its time or sample limit has accumulated, as an unattended render
+// would otherwise lose it.
+fn save_at_limit(rt: &raytracer::RSRaytracer, saver: &mut saver::BackgroundSaver) {
+    if !rt.limit_reached() {
+        return
+    }
+    info!(target: "render", "Render stopped at its limit with {:.1} samples per pixel.", rt.average_samples());
+    // Unattended renders must not lose their final image to a snapshot or save still being written.
+    if let Some((paths, result)) = saver.wait() {
+        report_save(&paths, result);
+    }
+    save_in_background(saver, || vec![rt.png_job(&rt.output_path(raytracer::PNG_OUT))]);
+}
+
+// Saves a numbered snapshot of a progressive render when one is due, so that long renders keep what they have
+// accumulated if the program dies. Waits for the next pass while files are still being written.
+fn save_snapshot(rt: &raytracer::RSRaytracer, saver: &mut saver::BackgroundSaver, snapshots: &mut snapshot::SnapshotSchedule) {
+    let samples = rt.average_samples();
+    if !snapshots.is_due(samples) || saver.is_busy() {
+        return
+    }
+    let number = snapshots.taken(samples);
+    let path = rt.output_path(&format!("{}_{:04}.png", raytracer::SNAPSHOT_OUT, number));
+    save_in_background(saver, || vec![rt.png_job(&path)]);
+}
+
+// Takes images from the ray tracer and writes them on a background thread, unless files are still being
+// written, in which case nothing is taken.
+fn save_in_background<F: FnOnce() -> Vec<saver::SaveJob>>(saver: &mut saver::BackgroundSaver, jobs: F) {
+    if saver.is_busy() {
+        warn!(target: "io", "Still writing {}. Save again once it is done.", saver.writing().join(", "));
+        return
+    }
+    let jobs = jobs();
+    let paths: Vec<String> = jobs.iter().map(|job| job.path.clone()).collect();
+    match saver.start(jobs) {
+        Ok(()) => info!(target: "io", "Writing {} in the background...", paths.join(", ")),
+        Err(e) => error!(target: "io", "Failed to start saving: {}", e)
+    }
+}
+
+// Logs the outcome of a background save. Returns whether it succeeded.
+fn report_save(paths: &[String], result: Result<(), RaytracerError>) -> bool {
+    match result {
+        Ok(()) => {
+            info!(target: "io", "Saved {}.", paths.join(", "));
+            true
+        }
+        Err(e) => {
+            error!(target: "io", "Failed to write {}: {}", paths.join(", "), e);
+            false
+        }
+    }
+}
+
+// Creates the texture the image is copied to, at the size the view needs.
+fn create_texture<'a>(texture_creator: &'a sdl2::render::TextureCreator<sdl2::video::WindowContext>, view: &viewport::Viewport) -> Result<sdl2::render::Texture<'a>, RaytracerError> {
+    let (width, height) = view.texture_size();
+    let mut texture = texture_creator
+        .create_texture_streaming(PixelFormatEnum::RGBA32, width, height)
+        .map_err(|e| RaytracerError::Sdl(e.to_string()))?;
+    // Transparent parts of the image show the cleared canvas.
+    texture.set_blend_mode(BlendMode::Blend);
+    Ok(texture)
+}
+
+fn texture_size(texture: &sdl2::render::Texture) -> (u32, u32) {
+    let query = texture.query();
+    (query.width, query.height)
+}
+
+// Shows the part of the image in view. The overlay is given in image pixels.
+fn copy_texture_to_canvas(texture: &sdl2::render::Texture, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, view: &viewport::Viewport, overlay: &Overlay) -> Result<(), RaytracerError> {
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
     canvas.clear();
-    canvas.copy(texture, None, Some(Rect::new(0, 0, width, height))).unwrap();
+    let (texture_width, texture_height) = texture_size(texture);
+    let (source, destination) = view.copy_rects(texture_width, texture_height);
+    canvas.copy(texture, Some(source), Some(destination)).map_err(RaytracerError::Sdl)?;
+
+    // Divider between the stashed and current buffers.
+    if let Some(split) = overlay.split {
+        let (x, _) = view.to_canvas(split as f64, 0.0);
+        canvas.set_draw_color(Color::RGB(255, 255, 255));
+        canvas.draw_line((x, 0), (x, destination.height() as i32)).map_err(RaytracerError::Sdl)?;
+    }
+
+    // Outline the render region on top of the image.
+    if let Some(region) = overlay.region {
+        if !region.is_empty() {
+            let (left, top) = view.to_canvas(region.x as f64, region.y as f64);
+            let (right, bottom) = view.to_canvas((region.x + region.width) as f64, (region.y + region.height) as f64);
+            canvas.set_draw_color(Color::RGB(255, 255, 0));
+            canvas.draw_rect(Rect::new(left, top, (right - left).max(1) as u32, (bottom - top).max(1) as u32)).map_err(RaytracerError::Sdl)?;
+        }
+    }
+
     canvas.present();
+    Ok(())
 }
@@ -3,6 +3,18 @@ use std::ops::Range;
 
 use rand::prelude::*;
 
+// Precision of the core math. The f64 feature trades speed and memory for fewer precision artifacts
+// (e.g. shadow acne and banding) in large scenes.
+#[cfg(not(feature = "f64"))]
+pub type Float = f32;
+#[cfg(not(feature = "f64"))]
+pub use std::f32::consts;
+
+#[cfg(feature = "f64")]
+pub type Float = f64;
+#[cfg(feature = "f64")]
+pub use std::f64::consts;
+
 pub struct RandGen {
     rng: SmallRng // Much, much more efficient than thread_rng.
 }
@@ -12,28 +24,50 @@ impl RandGen {
             rng: SmallRng::from_entropy()
         }
     }
+
+    // The same numbers every time for a seed, e.g. for reproducible generated scenes.
+    pub fn seeded(seed: u64) -> RandGen {
+        RandGen {
+            rng: SmallRng::seed_from_u64(seed)
+        }
+    }
     
-    pub fn next01(&mut self) -> f32 {
+    pub fn next01(&mut self) -> Float {
         self.rng.gen()
     }
 
-    pub fn next_range(&mut self, r: Range<f32>) -> f32 {
+    pub fn next_range(&mut self, r: Range<Float>) -> Float {
         self.rng.gen_range(r)
     }
+
+    // Direction in the +Z hemisphere with a probability proportional to its cosine with +Z (see cosine_pdf).
+    // Map it to a surface with Onb::local.
+    pub fn cosine_direction(&mut self) -> Vec3 {
+        let r1 = self.next01();
+        let r2 = self.next01();
+        let phi = 2.0 * consts::PI * r1;
+        let r = r2.sqrt();
+        Vec3::new(phi.cos() * r, phi.sin() * r, (1.0 - r2).sqrt())
+    }
+}
+
+// Probability density of cosine_direction for a direction at cos_theta to the normal.
+pub fn cosine_pdf(cos_theta: Float) -> Float {
+    cos_theta.max(0.0) / consts::PI
 }
 
 // --------------------------------------------------
 // Vec3
 // --------------------------------------------------
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct Vec3 {
-    pub x: f32,
-    pub y: f32,
-    pub z: f32
+    pub x: Float,
+    pub y: Float,
+    pub z: Float
 }
 
 impl Vec3 {
-    pub fn new(x: f32, y: f32, z: f32) -> Vec3 {
+    pub fn new(x: Float, y: Float, z: Float) -> Vec3 {
         Vec3 {
             x: x,
             y: y,
@@ -66,7 +100,7 @@ impl Vec3 {
         }
     }
 
-    pub fn random_range(r: Range<f32>) -> Vec3 {
+    pub fn random_range(r: Range<Float>) -> Vec3 {
         let mut rng = SmallRng::from_entropy();
         Vec3 {
             x: rng.gen_range(r.clone()),
@@ -75,15 +109,15 @@ impl Vec3 {
         }
     }
 
-    pub fn length(&self) -> f32 {
+    pub fn length(&self) -> Float {
         (self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
     }
 
-    pub fn sqr_length(&self) -> f32 {
+    pub fn sqr_length(&self) -> Float {
         (self.x * self.x) + (self.y * self.y) + (self.z * self.z)
     }
 
-    pub fn dot(&self, rhs: &Vec3) -> f32 {
+    pub fn dot(&self, rhs: &Vec3) -> Float {
         (self.x * rhs.x) + (self.y * rhs.y) + (self.z * rhs.z)
     }
 
@@ -117,16 +151,16 @@ impl Vec3 {
     }
 
     pub fn near_zero(&self) -> bool {
-        let eps: f32 = 1e-5; // Not sure what the precision is in Rust for f32.
+        let eps: Float = 1e-5; // Not sure what the precision is in Rust for f32. Loose enough for it, and so for f64.
         return (self.x.abs() < eps) && (self.y.abs() < eps) && (self.z.abs() < eps)
     }
 
     pub fn random_on_sphere() -> Vec3 {
         let mut rng = SmallRng::from_entropy();
         Vec3::new(
-            rng.gen::<f32>() - 0.5,
-            rng.gen::<f32>() - 0.5,
-            rng.gen::<f32>() - 0.5
+            rng.gen::<Float>() - 0.5,
+            rng.gen::<Float>() - 0.5,
+            rng.gen::<Float>() - 0.5
         ).normalized()
     }
     
@@ -139,7 +173,7 @@ impl Vec3 {
         }
     }
 
-    pub fn refract(uv: Vec3, n: Vec3, eta_i_over_eta_t: f32) -> Vec3 {
+    pub fn refract(uv: Vec3, n: Vec3, eta_i_over_eta_t: Float) -> Vec3 {
         // NOTE: Inputs aren't references as you have to re-implement all std::ops for reference types...not worth it yet.
         let cos_theta = (-uv).dot(&n).min(1.0);
         let r_out_perpendicular = eta_i_over_eta_t * (uv + cos_theta * n);
@@ -195,10 +229,10 @@ impl ops::Mul for Vec3 {
     }
 }
 
-// Vec3 * f32
-impl ops::Mul<f32> for Vec3 {
+// Vec3 * Float
+impl ops::Mul<Float> for Vec3 {
     type Output = Vec3;
-    fn mul(self, rhs: f32) -> Vec3 {
+    fn mul(self, rhs: Float) -> Vec3 {
         Vec3 {
             x: self.x * rhs,
             y: self.y * rhs,
@@ -207,8 +241,8 @@ impl ops::Mul<f32> for Vec3 {
     }
 }
 
-// f32 * Vec3
-impl ops::Mul<Vec3> for f32 {
+// Float * Vec3
+impl ops::Mul<Vec3> for Float {
     type Output = Vec3;
     fn mul(self, rhs: Vec3) -> Vec3 {
         Vec3 {
@@ -219,10 +253,10 @@ impl ops::Mul<Vec3> for f32 {
     }
 }
 
-// Vec3 / f32
-impl ops::Div<f32> for Vec3 {
+// Vec3 / Float
+impl ops::Div<Float> for Vec3 {
     type Output = Vec3;
-    fn div(self, rhs: f32) -> Vec3 {
+    fn div(self, rhs: Float) -> Vec3 {
         Vec3 {
             x: self.x / rhs,
             y: self.y / rhs,
@@ -255,24 +289,983 @@ impl ops::Neg for Vec3 {
 //     }
 // }
 
+// --------------------------------------------------
+// Onb
+// --------------------------------------------------
+// Orthonormal basis with w along a given direction, for mapping directions sampled around +Z onto a surface.
+#[derive(Clone, Copy)]
+pub struct Onb {
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3
+}
+
+impl Onb {
+    // Branchless construction from Duff et al., "Building an Orthonormal Basis, Revisited" (2017).
+    pub fn from_normal(n: Vec3) -> Onb {
+        let w = n.normalized();
+        let sign = if w.z >= 0.0 {1.0} else {-1.0};
+        let a = -1.0 / (sign + w.z);
+        let b = w.x * w.y * a;
+        Onb {
+            u: Vec3::new(1.0 + sign * w.x * w.x * a, sign * b, -sign * w.x),
+            v: Vec3::new(b, sign + w.y * w.y * a, -w.y),
+            w: w
+        }
+    }
+
+    // Converts from basis coordinates to world space.
+    pub fn local(&self, a: Vec3) -> Vec3 {
+        self.u * a.x + self.v * a.y + self.w * a.z
+    }
+}
+
+// --------------------------------------------------
+// Vec4
+// --------------------------------------------------
+// Homogeneous coordinates. Points have w = 1 and directions w = 0.
+#[derive(Clone, Copy)]
+pub struct Vec4 {
+    pub x: Float,
+    pub y: Float,
+    pub z: Float,
+    pub w: Float
+}
+
+impl Vec4 {
+    pub fn new(x: Float, y: Float, z: Float, w: Float) -> Vec4 {
+        Vec4 {
+            x: x,
+            y: y,
+            z: z,
+            w: w
+        }
+    }
+
+    pub fn from_point(p: Vec3) -> Vec4 {
+        Vec4::new(p.x, p.y, p.z, 1.0)
+    }
+
+    pub fn from_dir(d: Vec3) -> Vec4 {
+        Vec4::new(d.x, d.y, d.z, 0.0)
+    }
+
+    pub fn xyz(&self) -> Vec3 {
+        Vec3::new(self.x, self.y, self.z)
+    }
+
+    // Projects back to 3D by dividing by w. Directions (w = 0) are returned as is.
+    pub fn to_point(&self) -> Vec3 {
+        if self.w == 0.0 {self.xyz()} else {self.xyz() / self.w}
+    }
+
+    pub fn dot(&self, rhs: &Vec4) -> Float {
+        self.x * rhs.x + self.y * rhs.y + self.z * rhs.z + self.w * rhs.w
+    }
+}
+
+impl ops::Add for Vec4 {
+    type Output = Vec4;
+    fn add(self, rhs: Vec4) -> Vec4 {
+        Vec4::new(self.x + rhs.x, self.y + rhs.y, self.z + rhs.z, self.w + rhs.w)
+    }
+}
+
+impl ops::Sub for Vec4 {
+    type Output = Vec4;
+    fn sub(self, rhs: Vec4) -> Vec4 {
+        Vec4::new(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z, self.w - rhs.w)
+    }
+}
+
+impl ops::Mul<Float> for Vec4 {
+    type Output = Vec4;
+    fn mul(self, rhs: Float) -> Vec4 {
+        Vec4::new(self.x * rhs, self.y * rhs, self.z * rhs, self.w * rhs)
+    }
+}
+
+// --------------------------------------------------
+// Mat4
+// --------------------------------------------------
+// Transform acting on column vectors, stored as rows (m[row][col]).
+#[derive(Clone, Copy)]
+pub struct Mat4 {
+    pub m: [[Float; 4]; 4]
+}
+
+impl Mat4 {
+    pub fn identity() -> Mat4 {
+        Mat4 {
+            m: [
+                [1.0, 0.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0, 0.0],
+                [0.0, 0.0, 1.0, 0.0],
+                [0.0, 0.0, 0.0, 1.0]
+            ]
+        }
+    }
+
+    // From 16 values in column order (as used by glTF and PBRT).
+    pub fn from_cols(cols: &[[Float; 4]; 4]) -> Mat4 {
+        let mut m = [[0.0; 4]; 4];
+        for row in 0..4 {
+            for col in 0..4 {
+                m[row][col] = cols[col][row];
+            }
+        }
+        Mat4 {
+            m: m
+        }
+    }
+
+    pub fn translation(t: Vec3) -> Mat4 {
+        let mut out = Mat4::identity();
+        out.m[0][3] = t.x;
+        out.m[1][3] = t.y;
+        out.m[2][3] = t.z;
+        out
+    }
+
+    pub fn scale(s: Vec3) -> Mat4 {
+        let mut out = Mat4::identity();
+        out.m[0][0] = s.x;
+        out.m[1][1] = s.y;
+        out.m[2][2] = s.z;
+        out
+    }
+
+    // Counter-clockwise rotation by angle (in radians) around axis.
+    pub fn rotation(angle: Float, axis: Vec3) -> Mat4 {
+        let a = axis.normalized();
+        let (sin, cos) = angle.sin_cos();
+        let t = 1.0 - cos;
+        Mat4 {
+            m: [
+                [t * a.x * a.x + cos, t * a.x * a.y - sin * a.z, t * a.x * a.z + sin * a.y, 0.0],
+                [t * a.x * a.y + sin * a.z, t * a.y * a.y + cos, t * a.y * a.z - sin * a.x, 0.0],
+                [t * a.x * a.z - sin * a.y, t * a.y * a.z + sin * a.x, t * a.z * a.z + cos, 0.0],
+                [0.0, 0.0, 0.0, 1.0]
+            ]
+        }
+    }
+
+    pub fn transpose(&self) -> Mat4 {
+        let mut m = [[0.0; 4]; 4];
+        for (row, values) in m.iter_mut().enumerate() {
+            for (col, value) in values.iter_mut().enumerate() {
+                *value = self.m[col][row];
+            }
+        }
+        Mat4 {
+            m: m
+        }
+    }
+
+    // Ignores the bottom row, so only right for affine transforms. Projections need transform_vec4.
+    pub fn transform_point(&self, p: Vec3) -> Vec3 {
+        self.transform_dir(p) + Vec3::new(self.m[0][3], self.m[1][3], self.m[2][3])
+    }
+
+    // Any transform, projections included. Vec4::to_point divides the result by w.
+    pub fn transform_vec4(&self, v: Vec4) -> Vec4 {
+        let row = |r: usize| Vec4::new(self.m[r][0], self.m[r][1], self.m[r][2], self.m[r][3]).dot(&v);
+        Vec4::new(row(0), row(1), row(2), row(3))
+    }
+
+    // Ignores the translation.
+    pub fn transform_dir(&self, d: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0] * d.x + m[0][1] * d.y + m[0][2] * d.z,
+            m[1][0] * d.x + m[1][1] * d.y + m[1][2] * d.z,
+            m[2][0] * d.x + m[2][1] * d.y + m[2][2] * d.z
+        )
+    }
+
+    // Transforms a normal by the inverse transpose, so it stays perpendicular to transformed surfaces.
+    // The result is not normalized.
+    pub fn transform_normal(&self, n: Vec3) -> Vec3 {
+        let m = &self.m;
+        let c0 = Vec3::new(m[0][0], m[1][0], m[2][0]);
+        let c1 = Vec3::new(m[0][1], m[1][1], m[2][1]);
+        let c2 = Vec3::new(m[0][2], m[1][2], m[2][2]);
+        // The cofactors are the inverse transpose scaled by the determinant.
+        let sign = if self.determinant() < 0.0 {-1.0} else {1.0};
+        (c1.cross(&c2) * n.x + c2.cross(&c0) * n.y + c0.cross(&c1) * n.z) * sign
+    }
+
+    // Determinant of the upper 3x3. Negative when the transform mirrors.
+    pub fn determinant(&self) -> Float {
+        let m = &self.m;
+        let c0 = Vec3::new(m[0][0], m[1][0], m[2][0]);
+        let c1 = Vec3::new(m[0][1], m[1][1], m[2][1]);
+        let c2 = Vec3::new(m[0][2], m[1][2], m[2][2]);
+        c0.dot(&c1.cross(&c2))
+    }
+
+    // Inverse by Gauss-Jordan elimination with partial pivoting, or None if the matrix is singular or has
+    // values that aren't finite.
+    pub fn inverse(&self) -> Option<Mat4> {
+        if !self.m.iter().flatten().all(|value| value.is_finite()) {
+            return None
+        }
+        let mut a = self.m;
+        let mut out = Mat4::identity().m;
+        for col in 0..4 {
+            let pivot = (col..4).max_by(|i, j| a[*i][col].abs().total_cmp(&a[*j][col].abs())).unwrap();
+            if a[pivot][col].abs() < 1e-12 {
+                return None
+            }
+            a.swap(col, pivot);
+            out.swap(col, pivot);
+
+            let scale = 1.0 / a[col][col];
+            for k in 0..4 {
+                a[col][k] *= scale;
+                out[col][k] *= scale;
+            }
+            for row in (0..4).filter(|row| *row != col) {
+                let factor = a[row][col];
+                for k in 0..4 {
+                    a[row][k] -= factor * a[col][k];
+                    out[row][k] -= factor * out[col][k];
+                }
+            }
+        }
+        Some(Mat4 {
+            m: out
+        })
+    }
+}
+
+// Mat4 * Mat4 applies the right hand side first.
+impl ops::Mul for Mat4 {
+    type Output = Mat4;
+    fn mul(self, rhs: Mat4) -> Mat4 {
+        let mut m = [[0.0; 4]; 4];
+        for (row, values) in m.iter_mut().enumerate() {
+            for (col, value) in values.iter_mut().enumerate() {
+                *value = (0..4).map(|k| self.m[row][k] * rhs.m[k][col]).sum();
+            }
+        }
+        Mat4 {
+            m: m
+        }
+    }
+}
+
+// --------------------------------------------------
+// Quat
+// --------------------------------------------------
+// Rotation quaternion w + xi + yj + zk. Rotations are only meaningful for unit quaternions.
+#[derive(Clone, Copy)]
+pub struct Quat {
+    pub x: Float,
+    pub y: Float,
+    pub z: Float,
+    pub w: Float
+}
+
+impl Quat {
+    pub fn new(x: Float, y: Float, z: Float, w: Float) -> Quat {
+        Quat {
+            x: x,
+            y: y,
+            z: z,
+            w: w
+        }
+    }
+
+    pub fn identity() -> Quat {
+        Quat::new(0.0, 0.0, 0.0, 1.0)
+    }
+
+    // Counter-clockwise rotation by angle (in radians) around axis, matching Mat4::rotation.
+    pub fn from_axis_angle(axis: Vec3, angle: Float) -> Quat {
+        let a = axis.normalized();
+        let (sin, cos) = (angle * 0.5).sin_cos();
+        Quat::new(a.x * sin, a.y * sin, a.z * sin, cos)
+    }
+
+    // Euler angles (in radians) applied around X, then Y, then Z, all in world space.
+    pub fn from_euler(euler: Vec3) -> Quat {
+        Quat::from_axis_angle(Vec3::new(0.0, 0.0, 1.0), euler.z)
+            * Quat::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), euler.y)
+            * Quat::from_axis_angle(Vec3::new(1.0, 0.0, 0.0), euler.x)
+    }
+
+    // Inverse of from_euler. Y is kept in [-pi/2, pi/2], so at the poles X and Z are not unique.
+    pub fn to_euler(&self) -> Vec3 {
+        let q = self.normalized();
+        let sin_y = (2.0 * (q.w * q.y - q.z * q.x)).clamp(-1.0, 1.0);
+        Vec3::new(
+            (2.0 * (q.w * q.x + q.y * q.z)).atan2(1.0 - 2.0 * (q.x * q.x + q.y * q.y)),
+            sin_y.asin(),
+            (2.0 * (q.w * q.z + q.x * q.y)).atan2(1.0 - 2.0 * (q.y * q.y + q.z * q.z))
+        )
+    }
+
+    pub fn length(&self) -> Float {
+        self.dot(self).sqrt()
+    }
+
+    pub fn normalized(&self) -> Quat {
+        let len = self.length();
+        Quat::new(self.x / len, self.y / len, self.z / len, self.w / len)
+    }
+
+    pub fn dot(&self, rhs: &Quat) -> Float {
+        self.x * rhs.x + self.y * rhs.y + self.z * rhs.z + self.w * rhs.w
+    }
+
+    // The inverse rotation of a unit quaternion.
+    pub fn conjugate(&self) -> Quat {
+        Quat::new(-self.x, -self.y, -self.z, self.w)
+    }
+
+    pub fn rotate(&self, v: Vec3) -> Vec3 {
+        let u = Vec3::new(self.x, self.y, self.z);
+        let t = u.cross(&v) * 2.0;
+        v + t * self.w + u.cross(&t)
+    }
+
+    pub fn to_mat4(&self) -> Mat4 {
+        let Quat {x, y, z, w} = self.normalized();
+        Mat4 {
+            m: [
+                [1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y - w * z), 2.0 * (x * z + w * y), 0.0],
+                [2.0 * (x * y + w * z), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z - w * x), 0.0],
+                [2.0 * (x * z - w * y), 2.0 * (y * z + w * x), 1.0 - 2.0 * (x * x + y * y), 0.0],
+                [0.0, 0.0, 0.0, 1.0]
+            ]
+        }
+    }
+
+    // Constant speed interpolation along the shorter arc, e.g. for animating cameras.
+    pub fn slerp(&self, rhs: &Quat, t: Float) -> Quat {
+        let mut cos = self.dot(rhs);
+        let mut end = *rhs;
+        if cos < 0.0 {
+            cos = -cos;
+            end = Quat::new(-rhs.x, -rhs.y, -rhs.z, -rhs.w);
+        }
+        // Nearly parallel, so fall back to a normalized lerp to avoid dividing by ~0.
+        let (a, b) = if cos > 0.9995 {
+            (1.0 - t, t)
+        } else {
+            let angle = cos.acos();
+            let sin = angle.sin();
+            (((1.0 - t) * angle).sin() / sin, (t * angle).sin() / sin)
+        };
+        Quat::new(
+            self.x * a + end.x * b, self.y * a + end.y * b, self.z * a + end.z * b, self.w * a + end.w * b
+        ).normalized()
+    }
+}
+
+// Quat * Quat applies the right hand side first.
+impl ops::Mul for Quat {
+    type Output = Quat;
+    fn mul(self, rhs: Quat) -> Quat {
+        Quat::new(
+            self.w * rhs.x + self.x * rhs.w + self.y * rhs.z - self.z * rhs.y,
+            self.w * rhs.y - self.x * rhs.z + self.y * rhs.w + self.z * rhs.x,
+            self.w * rhs.z + self.x * rhs.y - self.y * rhs.x + self.z * rhs.w,
+            self.w * rhs.w - self.x * rhs.x - self.y * rhs.y - self.z * rhs.z
+        )
+    }
+}
+
+// --------------------------------------------------
+// Polynomial roots
+// --------------------------------------------------
+// Real roots of a*x^4 + b*x^3 + c*x^2 + d*x + e in ascending order. Repeated roots are reported once.
+// Computed in f64 as the coefficients of ray/torus quartics span many orders of magnitude.
+pub fn solve_quartic(a: f64, b: f64, c: f64, d: f64, e: f64) -> Vec<f64> {
+    polynomial_roots(&[a, b, c, d, e])
+}
+
+// Real roots of a polynomial with coefficients from the highest degree down.
+// Roots of the derivative split the real line into monotonic intervals that are each searched by bisection.
+// Critical points that touch zero are roots themselves (e.g. grazing rays), which bisection alone would miss.
+pub fn polynomial_roots(coeffs: &[f64]) -> Vec<f64> {
+    // Drop vanishing leading terms so that the degree is correct.
+    let mut coeffs = coeffs;
+    while coeffs.len() > 1 && coeffs[0].abs() < 1e-30 {
+        coeffs = &coeffs[1..];
+    }
+
+    let degree = coeffs.len() as i32 - 1;
+    if degree < 1 {
+        return Vec::new()
+    }
+    if degree == 1 {
+        return vec![-coeffs[1] / coeffs[0]]
+    }
+
+    // Every root lies within the Cauchy bound.
+    let mut bound = 0.0f64;
+    for c in &coeffs[1..] {
+        bound = bound.max((c / coeffs[0]).abs());
+    }
+    bound += 1.0;
+
+    let derivative: Vec<f64> = coeffs[..coeffs.len() - 1].iter().enumerate()
+        .map(|(i, c)| c * ((degree - i as i32) as f64))
+        .collect();
+
+    let mut points = vec![-bound];
+    for x in polynomial_roots(&derivative) {
+        if x > -bound && x < bound {
+            points.push(x);
+        }
+    }
+    points.push(bound);
+
+    let mut roots: Vec<f64> = Vec::new();
+    for i in 0..(points.len() - 1) {
+        let (lo, hi) = (points[i], points[i + 1]);
+        if polynomial_is_zero(coeffs, lo) {
+            push_root(&mut roots, lo);
+        }
+        if let Some(x) = bisect_root(coeffs, lo, hi) {
+            push_root(&mut roots, x);
+        }
+    }
+    if polynomial_is_zero(coeffs, bound) {
+        push_root(&mut roots, bound);
+    }
+    roots
+}
+
+// Roots are found in ascending order, so only the last one can be a duplicate.
+fn push_root(roots: &mut Vec<f64>, x: f64) {
+    if roots.last().is_none_or(|last| (x - last).abs() > 1e-9 * (1.0 + x.abs())) {
+        roots.push(x);
+    }
+}
+
+// Horner evaluation returning (value, sum of the absolute terms). The latter bounds the rounding error.
+fn polynomial_eval(coeffs: &[f64], x: f64) -> (f64, f64) {
+    let mut value = 0.0;
+    let mut magnitude = 0.0;
+    for c in coeffs {
+        value = value * x + c;
+        magnitude = magnitude * x.abs() + c.abs();
+    }
+    (value, magnitude)
+}
+
+fn polynomial_is_zero(coeffs: &[f64], x: f64) -> bool {
+    let (value, magnitude) = polynomial_eval(coeffs, x);
+    value.abs() <= 1e-9 * magnitude
+}
+
+// Finds the single root in a monotonic interval, if the polynomial changes sign across it.
+fn bisect_root(coeffs: &[f64], lo: f64, hi: f64) -> Option<f64> {
+    let (mut lo, mut hi) = (lo, hi);
+    let lo_value = polynomial_eval(coeffs, lo).0;
+    let hi_value = polynomial_eval(coeffs, hi).0;
+    if lo_value == 0.0 || hi_value == 0.0 || (lo_value < 0.0) == (hi_value < 0.0) {
+        return None
+    }
+
+    let lo_is_negative = lo_value < 0.0;
+    for _ in 0..100 {
+        let mid = 0.5 * (lo + hi);
+        if mid <= lo || mid >= hi {
+            break;
+        }
+        if (polynomial_eval(coeffs, mid).0 < 0.0) == lo_is_negative {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    Some(0.5 * (lo + hi))
+}
+
 // --------------------------------------------------
 // Ray
 // --------------------------------------------------
+// What a ray is traced for, so that objects can be hidden from some kinds of rays.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RayKind {
+    Camera, // From the camera, including through cutouts.
+    Shadow, // Towards a light.
+    Diffuse, // Scattered by the diffuse part of a material.
+    Specular // Reflected or refracted by the specular part of a material, glossy or sharp.
+}
+
+// Rays optionally carry a cone that approximates the area they cover, for picking texture mip levels.
 #[derive(Clone, Copy)]
 pub struct Ray {
     pub origin: Vec3,
-    pub direction: Vec3
+    pub direction: Vec3,
+    pub cone_width: Float, // Width at the origin.
+    pub cone_spread: Float, // Growth in width per unit of distance. Zero for rays without a cone.
+    pub wavelength: Float, // In nanometers, once a dispersive material has picked one for the path. Zero otherwise.
+    pub scatter_pdf: Float, // Density with which a material's diffuse part picked the direction (see Material::pdf). Zero otherwise.
+    pub kind: RayKind // Specular unless set. The ray tracer tags what materials scatter.
 }
 
 impl Ray {
     pub fn new(origin: Vec3, direction: Vec3) -> Ray {
         Ray {
             origin: origin,
-            direction: direction
+            direction: direction,
+            cone_width: 0.0,
+            cone_spread: 0.0,
+            wavelength: 0.0,
+            scatter_pdf: 0.0,
+            kind: RayKind::Specular
         }
     }
 
-    pub fn at(&self, t: f32) -> Vec3 {
+    pub fn at(&self, t: Float) -> Vec3 {
         self.origin + self.direction * t
     }
+
+    pub fn cone_width_at(&self, t: Float) -> Float {
+        self.cone_width + self.cone_spread * t * self.direction.length()
+    }
+}
+
+// --------------------------------------------------
+// Interval
+// --------------------------------------------------
+// Closed range [min, max], mostly of ray parameters. Empty when min > max.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Interval {
+    pub min: Float,
+    pub max: Float
+}
+
+impl Interval {
+    pub fn new(min: Float, max: Float) -> Interval {
+        Interval {
+            min: min,
+            max: max
+        }
+    }
+
+    pub fn empty() -> Interval {
+        Interval::new(Float::MAX, Float::MIN)
+    }
+
+    pub fn universe() -> Interval {
+        Interval::new(Float::MIN, Float::MAX)
+    }
+
+    pub fn size(&self) -> Float {
+        self.max - self.min
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.max < self.min
+    }
+
+    // Includes the end points.
+    pub fn contains(&self, x: Float) -> bool {
+        self.min <= x && x <= self.max
+    }
+
+    // Excludes the end points.
+    pub fn surrounds(&self, x: Float) -> bool {
+        self.min < x && x < self.max
+    }
+
+    pub fn clamp(&self, x: Float) -> Float {
+        x.max(self.min).min(self.max)
+    }
+}
+
+// --------------------------------------------------
+// Aabb
+// --------------------------------------------------
+#[derive(Clone, Copy)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Aabb {
+        Aabb {
+            min: min,
+            max: max
+        }
+    }
+
+    // Inverted box that any union or grow replaces.
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3::new(Float::MAX, Float::MAX, Float::MAX),
+            max: Vec3::new(Float::MIN, Float::MIN, Float::MIN)
+        }
+    }
+
+    pub fn around_sphere(center: Vec3, radius: Float) -> Aabb {
+        let r = Vec3::new(radius.abs(), radius.abs(), radius.abs());
+        Aabb::new(center - r, center + r)
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Vec3::new(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)),
+            max: Vec3::new(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z))
+        }
+    }
+
+    pub fn grow(&self, p: Vec3) -> Aabb {
+        self.union(&Aabb::new(p, p))
+    }
+
+    // Smallest box containing all of boxes. Empty if there are none.
+    pub fn surrounding_box(boxes: &[Aabb]) -> Aabb {
+        boxes.iter().fold(Aabb::empty(), |acc, b| acc.union(b))
+    }
+
+    // True for inverted boxes such as Aabb::empty(). Flat and point boxes are not empty.
+    pub fn is_empty(&self) -> bool {
+        self.max.x < self.min.x || self.max.y < self.min.y || self.max.z < self.min.z
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn extent(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    // Zero for empty boxes. Flat boxes keep the area of both faces.
+    pub fn surface_area(&self) -> Float {
+        if self.is_empty() {
+            return 0.0
+        }
+        let e = self.extent();
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+
+    // 0, 1, or 2 for X, Y, or Z.
+    pub fn longest_axis(&self) -> usize {
+        let e = self.extent();
+        if e.x >= e.y && e.x >= e.z {0} else if e.y >= e.z {1} else {2}
+    }
+
+    // Slab test returning the distance the ray enters the box, clipped to ray_t.
+    pub fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Float> {
+        let mut t_enter = ray_t.min;
+        let mut t_exit = ray_t.max;
+        let slabs = [
+            (ray.origin.x, ray.direction.x, self.min.x, self.max.x),
+            (ray.origin.y, ray.direction.y, self.min.y, self.max.y),
+            (ray.origin.z, ray.direction.z, self.min.z, self.max.z)
+        ];
+        for (origin, dir, lo, hi) in slabs.iter() {
+            let inv_dir = 1.0 / dir;
+            let mut t0 = (lo - origin) * inv_dir;
+            let mut t1 = (hi - origin) * inv_dir;
+            if inv_dir < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            // NaN (0 * inf for rays lying in a slab plane) is ignored by max/min.
+            t_enter = t_enter.max(t0);
+            t_exit = t_exit.min(t1);
+            if t_exit < t_enter {
+                return None
+            }
+        }
+        Some(t_enter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn assert_roots(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len(), "roots {:?}, expected {:?}", actual, expected);
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "roots {:?}, expected {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn quartic_distinct_roots() {
+        // (x - 1)(x - 2)(x - 3)(x - 4)
+        assert_roots(&solve_quartic(1.0, -10.0, 35.0, -50.0, 24.0), &[1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn quartic_repeated_roots() {
+        // (x - 1)^2 (x + 2)^2 touches zero without changing sign.
+        assert_roots(&solve_quartic(1.0, 2.0, -3.0, -4.0, 4.0), &[-2.0, 1.0]);
+    }
+
+    #[test]
+    fn quartic_no_real_roots() {
+        // x^4 + 1
+        assert_roots(&solve_quartic(1.0, 0.0, 0.0, 0.0, 1.0), &[]);
+    }
+
+    #[test]
+    fn quartic_degenerate_leading_term() {
+        // Falls back to the quadratic (x - 1)(x - 3).
+        assert_roots(&solve_quartic(0.0, 0.0, 1.0, -4.0, 3.0), &[1.0, 3.0]);
+    }
+
+    fn assert_vec(actual: Vec3, expected: Vec3) {
+        assert!((actual - expected).length() < 1e-5, "got ({}, {}, {}), expected ({}, {}, {})",
+            actual.x, actual.y, actual.z, expected.x, expected.y, expected.z);
+    }
+
+    #[test]
+    fn mat4_rotation_is_counter_clockwise() {
+        let m = Mat4::rotation(consts::FRAC_PI_2, Vec3::new(0.0, 0.0, 1.0));
+        assert_vec(m.transform_dir(Vec3::new(1.0, 0.0, 0.0)), Vec3::new(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn mat4_inverse_round_trip() {
+        let m = Mat4::translation(Vec3::new(1.0, -2.0, 3.0))
+            * Mat4::rotation(0.7, Vec3::new(1.0, 1.0, 0.0))
+            * Mat4::scale(Vec3::new(2.0, -1.0, 0.5));
+        let p = Vec3::new(0.3, 4.0, -2.0);
+        assert_vec(m.inverse().unwrap().transform_point(m.transform_point(p)), p);
+        assert!(Mat4::scale(Vec3::new(1.0, 0.0, 1.0)).inverse().is_none());
+        assert!(Mat4::scale(Vec3::new(Float::NAN, 1.0, 1.0)).inverse().is_none());
+        assert!(Mat4::translation(Vec3::new(Float::INFINITY, 0.0, 0.0)).inverse().is_none());
+    }
+
+    #[test]
+    fn mat4_inverse_of_projection() {
+        // A perspective projection isn't affine, so this needs the full 4x4 inverse.
+        let mut m = Mat4::identity();
+        m.m[2] = [0.0, 0.0, -1.2, -2.2];
+        m.m[3] = [0.0, 0.0, -1.0, 0.0];
+        let p = Vec4::new(0.5, -0.25, -3.0, 1.0);
+        let round_trip = m.inverse().unwrap().transform_vec4(m.transform_vec4(p));
+        assert_vec(round_trip.to_point(), p.to_point());
+    }
+
+    #[test]
+    fn mat4_transpose() {
+        let m = Mat4::translation(Vec3::new(1.0, 2.0, 3.0)).transpose();
+        assert_eq!(m.m[3][..3], [1.0, 2.0, 3.0]);
+        assert_eq!(m.m[0][3], 0.0);
+    }
+
+    #[test]
+    fn quat_matches_mat4_rotation() {
+        let axis = Vec3::new(1.0, 2.0, -0.5);
+        let q = Quat::from_axis_angle(axis, 1.1);
+        let m = Mat4::rotation(1.1, axis);
+        let v = Vec3::new(0.3, -1.0, 2.0);
+        assert_vec(q.rotate(v), m.transform_dir(v));
+        assert_vec(q.to_mat4().transform_dir(v), m.transform_dir(v));
+        assert_vec(q.conjugate().rotate(q.rotate(v)), v);
+    }
+
+    #[test]
+    fn quat_euler_round_trip() {
+        let euler = Vec3::new(0.4, -0.9, 2.5);
+        assert_vec(Quat::from_euler(euler).to_euler(), euler);
+
+        // X is applied first, so this turns +Y into +Z, which Z leaves in place, and +X into +Y.
+        let q = Quat::from_euler(Vec3::new(consts::FRAC_PI_2, 0.0, consts::FRAC_PI_2));
+        assert_vec(q.rotate(Vec3::new(0.0, 1.0, 0.0)), Vec3::new(0.0, 0.0, 1.0));
+        assert_vec(q.rotate(Vec3::new(1.0, 0.0, 0.0)), Vec3::new(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn quat_slerp_halfway() {
+        let a = Quat::identity();
+        let b = Quat::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), consts::FRAC_PI_2);
+        let half = a.slerp(&b, 0.5);
+        let expected = Quat::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), consts::FRAC_PI_4);
+        assert!((half.dot(&expected).abs() - 1.0).abs() < 1e-5);
+        assert_vec(a.slerp(&b, 1.0).rotate(Vec3::new(1.0, 0.0, 0.0)), b.rotate(Vec3::new(1.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn onb_is_orthonormal() {
+        for n in &[Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), Vec3::new(1.0, 2.0, -3.0), Vec3::new(0.0, 1.0, 0.0)] {
+            let onb = Onb::from_normal(*n);
+            for (a, b) in &[(onb.u, onb.v), (onb.v, onb.w), (onb.w, onb.u)] {
+                assert!(a.dot(b).abs() < 1e-5);
+                assert!((a.length() - 1.0).abs() < 1e-5);
+            }
+            assert_vec(onb.u.cross(&onb.v), onb.w);
+            assert_vec(onb.local(Vec3::new(0.0, 0.0, 1.0)), n.normalized());
+        }
+    }
+
+    #[test]
+    fn cosine_directions_are_cosine_weighted() {
+        // The mean cosine of a cosine-weighted hemisphere is 2/3.
+        let mut rng = RandGen::new();
+        let count = 20000;
+        let mut sum = 0.0;
+        for _ in 0..count {
+            let d = rng.cosine_direction();
+            assert!(d.z >= 0.0 && (d.length() - 1.0).abs() < 1e-4);
+            sum += d.z;
+        }
+        assert!((sum / (count as Float) - 2.0 / 3.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn interval_bounds() {
+        let i = Interval::new(1.0, 2.0);
+        assert_eq!(i.size(), 1.0);
+        assert!(i.contains(1.0) && i.contains(2.0) && !i.contains(2.5));
+        assert!(i.surrounds(1.5) && !i.surrounds(1.0) && !i.surrounds(2.0));
+        assert_eq!(i.clamp(0.0), 1.0);
+        assert_eq!(i.clamp(1.25), 1.25);
+        assert_eq!(i.clamp(3.0), 2.0);
+        assert!(Interval::empty().is_empty() && !Interval::empty().contains(0.0));
+        assert!(Interval::universe().contains(0.0) && !Interval::universe().is_empty());
+        // A single point is not empty.
+        assert!(!Interval::new(1.0, 1.0).is_empty() && Interval::new(1.0, 1.0).contains(1.0));
+    }
+
+    #[test]
+    fn aabb_slab_hits() {
+        let b = Aabb::new(Vec3::new(-1.0, -1.0, -1.0), Vec3::new(1.0, 1.0, 1.0));
+        let towards = Ray::new(Vec3::new(-3.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(b.hit(&towards, Interval::new(0.0, Float::MAX)), Some(2.0));
+        assert_eq!(b.hit(&towards, Interval::new(0.0, 1.5)), None);
+        // Starting inside reports the start of the range.
+        assert_eq!(b.hit(&towards, Interval::new(2.5, Float::MAX)), Some(2.5));
+        // Negative directions and misses.
+        let back = Ray::new(Vec3::new(3.0, 0.5, 0.5), Vec3::new(-1.0, 0.0, 0.0));
+        assert_eq!(b.hit(&back, Interval::new(0.0, Float::MAX)), Some(2.0));
+        let away = Ray::new(Vec3::new(3.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(b.hit(&away, Interval::new(0.0, Float::MAX)), None);
+        let beside = Ray::new(Vec3::new(-3.0, 1.5, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(b.hit(&beside, Interval::new(0.0, Float::MAX)), None);
+        let diagonal = Ray::new(Vec3::new(-3.0, -3.0, -3.0), Vec3::new(1.0, 1.0, 1.0));
+        assert_eq!(b.hit(&diagonal, Interval::new(0.0, Float::MAX)), Some(2.0));
+    }
+
+    #[test]
+    fn aabb_degenerate_boxes() {
+        // Flat box in the x = 0 plane.
+        let flat = Aabb::new(Vec3::new(0.0, -1.0, -1.0), Vec3::new(0.0, 1.0, 1.0));
+        let through = Ray::new(Vec3::new(-1.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(flat.hit(&through, Interval::new(0.0, Float::MAX)), Some(1.0));
+        // Rays lying in the plane hit, rays parallel to it do not.
+        let in_plane = Ray::new(Vec3::new(0.0, -3.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(flat.hit(&in_plane, Interval::new(0.0, Float::MAX)), Some(2.0));
+        let parallel = Ray::new(Vec3::new(0.5, -3.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(flat.hit(&parallel, Interval::new(0.0, Float::MAX)), None);
+        assert_eq!(flat.surface_area(), 8.0);
+        assert!(!flat.is_empty());
+
+        // A single point.
+        let point = Aabb::new(Vec3::new(1.0, 2.0, 3.0), Vec3::new(1.0, 2.0, 3.0));
+        let at_point = Ray::new(Vec3::new(1.0, 2.0, 0.0), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(point.hit(&at_point, Interval::new(0.0, Float::MAX)), Some(3.0));
+        assert_eq!(point.surface_area(), 0.0);
+        assert!(!point.is_empty());
+
+        // The empty box is never hit and adds nothing to unions.
+        let empty = Aabb::empty();
+        assert!(empty.is_empty());
+        assert_eq!(empty.surface_area(), 0.0);
+        assert_eq!(empty.hit(&through, Interval::new(0.0, Float::MAX)), None);
+        assert_eq!(empty.hit(&Ray::new(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0)), Interval::universe()), None);
+        let joined = empty.union(&point);
+        assert_vec(joined.min, point.min);
+        assert_vec(joined.max, point.max);
+    }
+
+    #[test]
+    fn aabb_surrounding_box() {
+        let a = Aabb::new(Vec3::new(-1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 1.0));
+        let b = Aabb::new(Vec3::new(2.0, -2.0, 0.5), Vec3::new(3.0, 0.0, 0.5));
+        let both = Aabb::surrounding_box(&[a, b]);
+        assert_vec(both.min, Vec3::new(-1.0, -2.0, 0.0));
+        assert_vec(both.max, Vec3::new(3.0, 1.0, 1.0));
+        assert_eq!(both.surface_area(), 2.0 * (4.0 * 3.0 + 3.0 * 1.0 + 1.0 * 4.0));
+        assert_eq!(a.surface_area(), 6.0);
+        assert!(Aabb::surrounding_box(&[]).is_empty());
+    }
+
+    // --------------------------------------------------
+    // Properties
+    // --------------------------------------------------
+    // Identities that must hold for any input, checked on random vectors. Tolerances are relative to the
+    // size of the inputs, and loose enough for f32.
+
+    fn vec3(extent: f64) -> impl Strategy<Value = Vec3> {
+        (-extent..extent, -extent..extent, -extent..extent).prop_map(|(x, y, z)| Vec3::new(x as Float, y as Float, z as Float))
+    }
+
+    // Vectors long enough to normalize without losing precision.
+    fn direction() -> impl Strategy<Value = Vec3> {
+        vec3(1.0).prop_filter("too short to normalize", |v| v.length() > 0.1).prop_map(|v| v.normalized())
+    }
+
+    fn close(a: Float, b: Float, scale: Float) -> bool {
+        (a - b).abs() <= 1e-4 * scale.max(1.0)
+    }
+
+    proptest! {
+        #[test]
+        fn normalized_vectors_have_unit_length(v in vec3(1000.0).prop_filter("zero", |v| v.length() > 1e-3)) {
+            prop_assert!(close(v.normalized().length(), 1.0, 1.0));
+            let mut w = v;
+            w.normalize();
+            prop_assert!(close(w.x, v.normalized().x, 1.0) && close(w.y, v.normalized().y, 1.0) && close(w.z, v.normalized().z, 1.0));
+        }
+
+        #[test]
+        fn cross_products_are_orthogonal(a in vec3(100.0), b in vec3(100.0)) {
+            let c = a.cross(&b);
+            let scale = a.length() * a.length() * b.length();
+            prop_assert!(close(c.dot(&a), 0.0, scale) && close(c.dot(&b), 0.0, a.length() * b.length() * b.length()));
+            // Swapping the operands flips the result.
+            prop_assert!(close((c + b.cross(&a)).length(), 0.0, a.length() * b.length()));
+        }
+
+        #[test]
+        fn reflection_preserves_length_and_undoes_itself(v in vec3(100.0), n in direction()) {
+            let r = v.reflect(n);
+            prop_assert!(close(r.length(), v.length(), v.length()));
+            prop_assert!(close(r.dot(&n), -v.dot(&n), v.length()));
+            prop_assert!(close((r.reflect(n) - v).length(), 0.0, v.length()));
+        }
+
+        #[test]
+        fn refraction_obeys_snells_law(uv in direction(), n in direction(), eta in 0.3..3.0f64) {
+            // Rays arrive from the side the normal points to, and only those that aren't totally internally
+            // reflected are refracted.
+            let uv = if uv.dot(&n) > 0.0 {-uv} else {uv};
+            let eta = eta as Float;
+            let sin_in = uv.cross(&n).length();
+            prop_assume!(uv.dot(&n) < -0.01 && eta * sin_in < 0.99);
+
+            let out = Vec3::refract(uv, n, eta);
+            prop_assert!(close(out.length(), 1.0, 1.0));
+            prop_assert!(out.dot(&n) < 0.0);
+            prop_assert!(close(out.cross(&n).length(), eta * sin_in, 1.0));
+            // The refracted ray stays in the plane of incidence.
+            prop_assert!(close(out.dot(&uv.cross(&n)), 0.0, 1.0));
+        }
+
+        #[test]
+        fn ray_positions_are_linear_in_t(origin in vec3(100.0), direction in vec3(10.0), s in -100.0..100.0f64, t in -100.0..100.0f64) {
+            let ray = Ray::new(origin, direction);
+            let (s, t) = (s as Float, t as Float);
+            let scale = origin.length() + direction.length() * (s.abs() + t.abs());
+            prop_assert!(close((ray.at(0.0) - origin).length(), 0.0, scale));
+            prop_assert!(close((ray.at(s + t) - (ray.at(s) + direction * t)).length(), 0.0, scale));
+            prop_assert!(close((ray.at(s * 2.0) - origin - (ray.at(s) - origin) * 2.0).length(), 0.0, scale));
+        }
+    }
 }
@@ -0,0 +1,104 @@
+use std::fmt;
+
+// --------------------------------------------------
+// ImageMetrics
+// --------------------------------------------------
+// Error metrics between two images. All values are computed on display values in [0, 1].
+#[derive(Clone, Copy)]
+pub struct ImageMetrics {
+    pub mse: f32,
+    pub rmse: f32,
+    pub ssim: f32 // Mean structural similarity of the luminance. 1.0 means identical.
+}
+
+impl fmt::Display for ImageMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MSE: {:.6}, RMSE: {:.6}, SSIM: {:.6}", self.mse, self.rmse, self.ssim)
+    }
+}
+
+const SSIM_WINDOW: u32 = 8;
+const SSIM_C1: f32 = 0.01 * 0.01; // (k1 * L)^2 with L = 1.
+const SSIM_C2: f32 = 0.03 * 0.03; // (k2 * L)^2 with L = 1.
+
+// Compares two RGB images of the same size stored as tightly packed rows of [0, 1] values.
+pub fn compute(image: &[f32], reference: &[f32], width: u32, height: u32) -> ImageMetrics {
+    let channels = 3;
+    let count = (width * height * channels) as usize;
+
+    let mut sum_sq = 0.0f64;
+    for i in 0..count {
+        let diff = (image[i] - reference[i]) as f64;
+        sum_sq += diff * diff;
+    }
+    let mse = if count > 0 {(sum_sq / (count as f64)) as f32} else {0.0};
+
+    ImageMetrics {
+        mse: mse,
+        rmse: mse.sqrt(),
+        ssim: ssim(&luminance(image, width, height), &luminance(reference, width, height), width, height)
+    }
+}
+
+fn luminance(image: &[f32], width: u32, height: u32) -> Vec<f32> {
+    let mut out = Vec::with_capacity((width * height) as usize);
+    for i in 0..((width * height) as usize) {
+        out.push(0.2126 * image[i * 3 + 0] + 0.7152 * image[i * 3 + 1] + 0.0722 * image[i * 3 + 2]);
+    }
+    out
+}
+
+// Mean SSIM over non-overlapping windows. Partial windows at the edges are included.
+fn ssim(a: &[f32], b: &[f32], width: u32, height: u32) -> f32 {
+    let mut total = 0.0f64;
+    let mut windows = 0;
+
+    let mut wy = 0;
+    while wy < height {
+        let mut wx = 0;
+        while wx < width {
+            let max_x = (wx + SSIM_WINDOW).min(width);
+            let max_y = (wy + SSIM_WINDOW).min(height);
+            let n = ((max_x - wx) * (max_y - wy)) as f32;
+
+            let mut mean_a = 0.0;
+            let mut mean_b = 0.0;
+            for y in wy..max_y {
+                for x in wx..max_x {
+                    let i = (y * width + x) as usize;
+                    mean_a += a[i];
+                    mean_b += b[i];
+                }
+            }
+            mean_a /= n;
+            mean_b /= n;
+
+            let mut var_a = 0.0;
+            let mut var_b = 0.0;
+            let mut covar = 0.0;
+            for y in wy..max_y {
+                for x in wx..max_x {
+                    let i = (y * width + x) as usize;
+                    let da = a[i] - mean_a;
+                    let db = b[i] - mean_b;
+                    var_a += da * da;
+                    var_b += db * db;
+                    covar += da * db;
+                }
+            }
+            var_a /= n;
+            var_b /= n;
+            covar /= n;
+
+            let numerator = (2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * covar + SSIM_C2);
+            let denominator = (mean_a * mean_a + mean_b * mean_b + SSIM_C1) * (var_a + var_b + SSIM_C2);
+            total += (numerator / denominator) as f64;
+            windows += 1;
+
+            wx += SSIM_WINDOW;
+        }
+        wy += SSIM_WINDOW;
+    }
+
+    if windows > 0 {(total / (windows as f64)) as f32} else {1.0}
+}
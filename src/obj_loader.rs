@@ -0,0 +1,364 @@
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::fs::File;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use log::{debug, info, warn};
+
+use crate::error::{RaytracerError, Result};
+use crate::image::Texture;
+use crate::math::*;
+use crate::primitives::Mesh;
+use crate::raytracer::{Alpha, AlphaMode, Dielectric, Lambertian, Metal, PbrMaterial};
+use crate::scene::Scene;
+
+fn invalid(path: &str, line: usize, reason: &str) -> RaytracerError {
+    RaytracerError::InvalidScene {
+        path: path.to_string(),
+        reason: format!("line {}: {}", line, reason)
+    }
+}
+
+fn parse_floats(path: &str, line: usize, tokens: &[&str], count: usize) -> Result<Vec<Float>> {
+    if tokens.len() < count {
+        return Err(invalid(path, line, &format!("expected {} numbers", count)))
+    }
+    tokens[..count].iter().map(|t| t.parse().map_err(|_| invalid(path, line, &format!("malformed number '{}'", t)))).collect()
+}
+
+
+// --------------------------------------------------
+// MTL
+// --------------------------------------------------
+// The subset of an MTL material that maps onto the ray tracer's materials.
+struct MtlMaterial {
+    diffuse: Vec3, // Kd
+    specular: Vec3, // Ks
+    shininess: Float, // Ns, from 0 to 1000.
+    ior: Float, // Ni
+    opacity: Float, // d, or 1 - Tr.
+    diffuse_texture: Option<Arc<Texture>>, // map_Kd
+    alpha_texture: Option<Arc<Texture>> // map_d
+}
+
+impl MtlMaterial {
+    fn new() -> MtlMaterial {
+        MtlMaterial {
+            diffuse: Vec3::new(0.8, 0.8, 0.8),
+            specular: Vec3::zero(),
+            shininess: 0.0,
+            ior: 1.5,
+            opacity: 1.0,
+            diffuse_texture: None,
+            alpha_texture: None
+        }
+    }
+
+    // Transparent materials become dielectrics. Otherwise materials whose specular color outweighs
+    // their diffuse color become metals, with the fuzz derived from the shininess. The rest are Lambertian,
+    // or fully rough dielectric PBR materials if they have a diffuse texture, which is tinted by the diffuse color.
+    // An alpha texture cuts out the parts of those where it is below one half instead.
+    fn add_to(&self, scene: &mut Scene) -> u32 {
+        let max = |v: Vec3| v.x.max(v.y).max(v.z);
+        if self.opacity < 1.0 && self.alpha_texture.is_none() {
+            scene.add_dielectric_material(Dielectric::new(if self.ior > 1.0 {self.ior} else {1.5}))
+        } else if max(self.specular) > max(self.diffuse) {
+            let fuzz = (2.0 / (self.shininess.max(0.0) + 2.0)).sqrt().min(1.0);
+            scene.add_metal_material(Metal::new(self.specular, fuzz))
+        } else if let Some(texture) = &self.diffuse_texture {
+            let mut mat = PbrMaterial::new(self.diffuse, 0.0, 1.0);
+            mat.base_color_texture = Some(texture.clone());
+            mat.alpha = self.alpha();
+            scene.add_pbr_material(mat)
+        } else {
+            let mut mat = Lambertian::new(self.diffuse);
+            mat.alpha = self.alpha();
+            scene.add_lambertian_material(mat)
+        }
+    }
+
+    fn alpha(&self) -> Alpha {
+        match &self.alpha_texture {
+            Some(texture) => Alpha {
+                mode: AlphaMode::Mask(0.5),
+                value: self.opacity,
+                texture: Some(texture.clone())
+            },
+            None => Alpha::opaque()
+        }
+    }
+}
+
+// Adds every material in an MTL file and registers it under its name. Textures are loaded through the
+// scene's assets, relative to the MTL file.
+pub fn load_mtl(scene: &mut Scene, path: &str) -> Result<()> {
+    let reader = BufReader::new(File::open(path)?);
+    let directory = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    let mut materials: Vec<(String, MtlMaterial)> = Vec::new();
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let line_no = line_idx + 1;
+        if tokens.is_empty() || tokens[0].starts_with('#') {
+            continue;
+        }
+
+        if tokens[0] == "newmtl" {
+            materials.push((tokens[1..].join(" "), MtlMaterial::new()));
+            continue;
+        }
+        let current = match materials.last_mut() {
+            Some((_, mat)) => mat,
+            None => return Err(invalid(path, line_no, "material statement before newmtl"))
+        };
+        match tokens[0] {
+            "Kd" => {let v = parse_floats(path, line_no, &tokens[1..], 3)?; current.diffuse = Vec3::new(v[0], v[1], v[2]);}
+            "Ks" => {let v = parse_floats(path, line_no, &tokens[1..], 3)?; current.specular = Vec3::new(v[0], v[1], v[2]);}
+            "Ns" => current.shininess = parse_floats(path, line_no, &tokens[1..], 1)?[0],
+            "Ni" => current.ior = parse_floats(path, line_no, &tokens[1..], 1)?[0],
+            "d" => current.opacity = parse_floats(path, line_no, &tokens[1..], 1)?[0],
+            "Tr" => current.opacity = 1.0 - parse_floats(path, line_no, &tokens[1..], 1)?[0],
+            "map_Kd" | "map_d" => {
+                // Options such as -s come before the file name, which is assumed to be the last token.
+                let texture_path = match tokens.last() {
+                    Some(name) if tokens.len() > 1 => directory.join(name),
+                    _ => return Err(invalid(path, line_no, &format!("{} without a file name", tokens[0])))
+                };
+                let texture_path = texture_path.to_string_lossy();
+                let assets = scene.get_assets_mut();
+                // Only PGM/PPM images can be read, so other textures are skipped rather than failing the load.
+                match assets.load_texture(&texture_path) {
+                    Ok(handle) if tokens[0] == "map_d" => current.alpha_texture = assets.get_texture(handle),
+                    Ok(handle) => current.diffuse_texture = assets.get_texture(handle),
+                    Err(e) => warn!(target: "io", "Skipping texture of line {} in {}: {}", line_no, path, e)
+                }
+            }
+            _ => {} // Other textures, illumination models, and the like are not supported.
+        }
+    }
+
+    for (name, mat) in &materials {
+        let mat_id = mat.add_to(scene);
+        scene.set_material_name(mat_id, name)?;
+    }
+    debug!(target: "scene", "Loaded {} materials from {}.", materials.len(), path);
+    Ok(())
+}
+
+
+// --------------------------------------------------
+// OBJ
+// --------------------------------------------------
+// Faces sharing a material, with vertices deduplicated by their (position, uv, normal) indices.
+struct Group {
+    mat_id: u32,
+    vertices: HashMap<(usize, Option<usize>, Option<usize>), u32>,
+    corners: Vec<(usize, Option<usize>, Option<usize>)>,
+    triangles: Vec<[u32; 3]>
+}
+
+impl Group {
+    fn new(mat_id: u32) -> Group {
+        Group {
+            mat_id: mat_id,
+            vertices: HashMap::new(),
+            corners: Vec::new(),
+            triangles: Vec::new()
+        }
+    }
+
+    fn vertex(&mut self, corner: (usize, Option<usize>, Option<usize>)) -> u32 {
+        let corners = &mut self.corners;
+        *self.vertices.entry(corner).or_insert_with(|| {
+            corners.push(corner);
+            (corners.len() - 1) as u32
+        })
+    }
+
+    // Normals and UVs are only kept if every vertex of the group has them.
+    fn into_mesh(self, positions: &[Vec3], normals: &[Vec3], uvs: &[(Float, Float)]) -> Result<Mesh> {
+        let has_normals = self.corners.iter().all(|c| c.2.is_some());
+        let has_uvs = self.corners.iter().all(|c| c.1.is_some());
+        Mesh::new(
+            self.corners.iter().map(|c| positions[c.0]).collect(),
+            if has_normals {self.corners.iter().map(|c| normals[c.2.unwrap()]).collect()} else {Vec::new()},
+            if has_uvs {self.corners.iter().map(|c| uvs[c.1.unwrap()]).collect()} else {Vec::new()},
+            self.triangles,
+            self.mat_id
+        )
+    }
+}
+
+// Resolves a 1-based (or negative, relative to the end) OBJ index.
+fn resolve_index(path: &str, line: usize, token: &str, count: usize) -> Result<usize> {
+    let idx: i64 = token.parse().map_err(|_| invalid(path, line, &format!("malformed index '{}'", token)))?;
+    let resolved = if idx < 0 {count as i64 + idx} else {idx - 1};
+    if resolved < 0 || resolved >= count as i64 {
+        return Err(invalid(path, line, &format!("index {} is out of range", idx)))
+    }
+    Ok(resolved as usize)
+}
+
+// Adds the polygons of an OBJ file as one mesh per material. Materials come from the files named
+// by mtllib, relative to the OBJ file, and can afterwards be looked up by name.
+pub fn load(scene: &mut Scene, path: &str) -> Result<()> {
+    let meshes = read_meshes(path, Some(&mut *scene), 0)?;
+    let triangle_count = add_meshes(scene, path, meshes)?;
+    info!(target: "scene", "Loaded {} triangles from {}.", triangle_count, path);
+    Ok(())
+}
+
+// Adds the polygons of an OBJ file as one mesh with mat_id, ignoring its materials, e.g. to drop a model into another scene.
+pub fn load_model(scene: &mut Scene, path: &str, mat_id: u32) -> Result<()> {
+    let meshes = read_meshes(path, None, mat_id)?;
+    let triangle_count = add_meshes(scene, path, meshes)?;
+    info!(target: "scene", "Added {} triangles from {}.", triangle_count, path);
+    Ok(())
+}
+
+// Adds the meshes of a file, named after it, and returns their triangle count.
+fn add_meshes(scene: &mut Scene, path: &str, meshes: Vec<Mesh>) -> Result<usize> {
+    let name = Path::new(path).file_stem().map_or(path.to_string(), |stem| stem.to_string_lossy().into_owned());
+    let mut triangle_count = 0;
+    for mesh in meshes {
+        triangle_count += mesh.triangle_count();
+        scene.add_mesh(mesh)?;
+        if let Some(object_id) = scene.last_object_id() {
+            scene.set_object_name(object_id, &name)?;
+        }
+    }
+    Ok(triangle_count)
+}
+
+// Reads the polygons of an OBJ file as one mesh per material. Materials are only loaded if a scene is given.
+// Without one, all polygons form a single mesh with mat_id.
+pub fn read_meshes(path: &str, mut scene: Option<&mut Scene>, mat_id: u32) -> Result<Vec<Mesh>> {
+    info!(target: "io", "Reading OBJ file {}...", path);
+    let reader = BufReader::new(File::open(path)?);
+    let directory = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+
+    let mut positions: Vec<Vec3> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut uvs: Vec<(Float, Float)> = Vec::new();
+    let mut groups: Vec<Group> = vec![Group::new(mat_id)];
+    let mut current = 0;
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let line_no = line_idx + 1;
+        if tokens.is_empty() || tokens[0].starts_with('#') {
+            continue;
+        }
+
+        match tokens[0] {
+            "v" => {let v = parse_floats(path, line_no, &tokens[1..], 3)?; positions.push(Vec3::new(v[0], v[1], v[2]));}
+            "vn" => {let v = parse_floats(path, line_no, &tokens[1..], 3)?; normals.push(Vec3::new(v[0], v[1], v[2]).normalized());}
+            // OBJ texture coordinates start at the bottom left, whereas images start at the top left.
+            "vt" => {let v = parse_floats(path, line_no, &tokens[1..], 2)?; uvs.push((v[0], 1.0 - v[1]));}
+            "f" => {
+                if tokens.len() < 4 {
+                    return Err(invalid(path, line_no, "a face needs at least three vertices"))
+                }
+                let mut face = Vec::with_capacity(tokens.len() - 1);
+                for corner in &tokens[1..] {
+                    // v, v/vt, v//vn, or v/vt/vn
+                    let mut parts = corner.split('/');
+                    let v = resolve_index(path, line_no, parts.next().unwrap_or(""), positions.len())?;
+                    let vt = match parts.next() {
+                        Some(t) if !t.is_empty() => Some(resolve_index(path, line_no, t, uvs.len())?),
+                        _ => None
+                    };
+                    let vn = match parts.next() {
+                        Some(t) if !t.is_empty() => Some(resolve_index(path, line_no, t, normals.len())?),
+                        _ => None
+                    };
+                    face.push(groups[current].vertex((v, vt, vn)));
+                }
+                // Polygons are triangulated as a fan.
+                for i in 1..(face.len() - 1) {
+                    groups[current].triangles.push([face[0], face[i], face[i + 1]]);
+                }
+            }
+            "mtllib" => {
+                if let Some(scene) = scene.as_deref_mut() {
+                    for library in &tokens[1..] {
+                        let mtl_path = directory.join(library);
+                        load_mtl(scene, &mtl_path.to_string_lossy())?;
+                    }
+                }
+            }
+            "usemtl" => {
+                let scene = match scene.as_deref() {
+                    Some(scene) => scene,
+                    None => continue
+                };
+                let name = tokens[1..].join(" ");
+                let mat_id = scene.get_material_by_name(&name).unwrap_or_else(|| {
+                    warn!(target: "scene", "Unknown material '{}' in {}. Using the default material.", name, path);
+                    0
+                });
+                current = match groups.iter().position(|g| g.mat_id == mat_id) {
+                    Some(idx) => idx,
+                    None => {
+                        groups.push(Group::new(mat_id));
+                        groups.len() - 1
+                    }
+                };
+            }
+            _ => {} // Objects, groups, smoothing groups, and curves are ignored.
+        }
+    }
+
+    groups.into_iter().filter(|g| !g.triangles.is_empty()).map(|g| g.into_mesh(&positions, &normals, &uvs)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Fixture;
+
+    #[test]
+    fn obj_files_load_one_mesh_per_material() {
+        let obj = "mtllib box.mtl\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+            usemtl red\nf 1/1 2/2 3/3 4/4\nusemtl chrome\nf -4 -2 -1\nusemtl glass\nf 1 2 3\n";
+        let mtl = "newmtl red\nKd 1 0 0\nnewmtl chrome\nKd 0.1 0.1 0.1\nKs 0.9 0.9 0.9\nNs 200\nnewmtl glass\nd 0.5\nNi 1.33\n";
+        let files = Fixture::new("obj_files");
+        let path = files.write("box.obj", obj);
+        files.write("box.mtl", mtl);
+        let mut scene = Scene::new();
+        load(&mut scene, &path).unwrap();
+        assert_eq!(scene.object_count(), 3);
+        assert_eq!(scene.material_count(), 4);
+        assert!(scene.get_material_as::<Lambertian>(scene.get_material_by_name("red").unwrap()).is_ok());
+        assert!(scene.get_material_as::<Metal>(scene.get_material_by_name("chrome").unwrap()).is_ok());
+        assert!(scene.get_material_as::<Dielectric>(scene.get_material_by_name("glass").unwrap()).is_ok());
+        assert_eq!(scene.get_object_by_name("box"), Some(0));
+
+        // The quad is split into two triangles, and models ignore the materials.
+        let meshes = read_meshes(&path, None, 0).unwrap();
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(meshes[0].triangle_count(), 4);
+    }
+
+    #[test]
+    fn malformed_obj_files_name_the_line() {
+        let files = Fixture::new("malformed_obj");
+        let message = |name: &str, obj: &str| {
+            let mut scene = Scene::new();
+            let error = load(&mut scene, &files.write(&format!("{}.obj", name), obj)).expect_err("malformed file should fail");
+            assert_eq!(scene.object_count(), 0);
+            error.to_string()
+        };
+        assert!(message("number", "v 0 0 0\nv 1 zero 0\n").contains("line 2"));
+        assert!(message("index", "v 0 0 0\nv 1 0 0\nv 0 1 0\n\nf 1 2 4\n").contains("line 5"));
+        assert!(message("face", "v 0 0 0\nv 1 0 0\nf 1 2\n").contains("line 3"));
+        assert!(matches!(load(&mut Scene::new(), &files.write("library.obj", "mtllib missing.mtl\n")), Err(RaytracerError::Io(_))));
+
+        let path = files.write("statement.mtl", "Kd 1 1 1\n");
+        let error = load_mtl(&mut Scene::new(), &path).err().unwrap();
+        assert!(matches!(error, RaytracerError::InvalidScene{..}));
+        assert!(error.to_string().contains("line 1"));
+    }
+}
@@ -0,0 +1,435 @@
+use std::time::Duration;
+
+use crate::config::{self, Config};
+use crate::error::{RaytracerError, Result};
+use crate::math::Float;
+use crate::{accumulation, color, exposure, generator, image, post, poster, presets, raytracer, scene};
+
+// --------------------------------------------------
+// Options
+// --------------------------------------------------
+// Everything a run is set up with, layered from the built in defaults, then the config file, then the command
+// line, each overriding the one before.
+
+// Most blades --aperture-blades accepts. Apertures with more are as good as round.
+pub const MAX_APERTURE_BLADES: u32 = 32;
+
+// Scenes built in code rather than loaded from a file, in place of the default spheres.
+pub enum BuiltinScene {
+    Generated(generator::GeneratorSettings),
+    Preset(String)
+}
+
+impl BuiltinScene {
+    pub fn build(&self, scene: &mut scene::Scene) -> Result<()> {
+        match self {
+            BuiltinScene::Generated(settings) => generator::generate(scene, settings).map(|_| ()),
+            BuiltinScene::Preset(name) => presets::load(scene, name)
+        }
+    }
+
+    // For the stamp.
+    pub fn name(&self) -> String {
+        match self {
+            BuiltinScene::Generated(settings) => settings.describe(),
+            BuiltinScene::Preset(name) => format!("Preset {}", name)
+        }
+    }
+}
+
+pub struct Options {
+    pub reference_path: Option<String>,
+    pub scene_path: Option<String>,
+    pub builtin_scene: Option<BuiltinScene>,
+    pub batch_path: Option<String>,
+    pub furnace_test: bool,
+    pub poster_tiles: Option<u32>,
+    pub poster_order: poster::TileOrder,
+    pub script_path: Option<String>,
+    pub backplate_path: Option<String>,
+    pub watch: bool,
+    pub output_directory: String,
+    pub resolution: (u32, u32),
+    pub texture_budget: Option<usize>, // In bytes.
+    pub bracket_stops: Float,
+    pub snapshot_every: Option<Duration>,
+    pub snapshot_samples: Option<u32>,
+    pub aperture_blades: Option<u32>,
+    pub blade_rotation: Float, // In degrees.
+    pub bokeh_mask_path: Option<String>,
+    pub key_bindings: Vec<(String, String)>, // Key of an action, and the key that also triggers it, by SDL key name.
+    pub log_level: log::LevelFilter,
+    pub settings: raytracer::RenderSettings // All but the aperture, which render_settings adds.
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options::new()
+    }
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            reference_path: None,
+            scene_path: None,
+            builtin_scene: None,
+            batch_path: None,
+            furnace_test: false,
+            poster_tiles: None,
+            poster_order: poster::TileOrder::Rows,
+            script_path: None,
+            backplate_path: None,
+            watch: false,
+            output_directory: ".".to_string(),
+            resolution: (raytracer::WIDTH, raytracer::HEIGHT),
+            texture_budget: None,
+            bracket_stops: 2.0,
+            snapshot_every: None,
+            snapshot_samples: None,
+            aperture_blades: None,
+            blade_rotation: 0.0,
+            bokeh_mask_path: None,
+            key_bindings: Vec::new(),
+            log_level: log::LevelFilter::Info,
+            settings: raytracer::RenderSettings::new()
+        }
+    }
+
+    // The defaults, overridden by the config if there is one, and then by the command line arguments.
+    pub fn parse<I: Iterator<Item = String>>(config: Option<&Config>, args: I) -> Result<Options> {
+        let mut options = Options::new();
+        if let Some(config) = config {
+            options.merge(config);
+        }
+        options.read_args(args)?;
+        options.check()?;
+        Ok(options)
+    }
+
+    // Takes the settings the config has.
+    pub fn merge(&mut self, config: &Config) {
+        let settings = &mut self.settings;
+        settings.samples_per_pixel = config.samples_per_pixel.unwrap_or(settings.samples_per_pixel);
+        settings.max_depth = config.max_depth.unwrap_or(settings.max_depth);
+        settings.filter = config.filter.unwrap_or(settings.filter);
+        settings.color_space = config.color_space.unwrap_or(settings.color_space);
+        settings.white_balance.temperature = config.white_balance.unwrap_or(settings.white_balance.temperature);
+        settings.white_balance.tint = config.tint.unwrap_or(settings.white_balance.tint);
+        settings.low_priority = config.low_priority.unwrap_or(settings.low_priority);
+        settings.stamp = config.stamp.unwrap_or(settings.stamp);
+        if let Some(half_buffer) = config.half_buffer {
+            settings.accumulation = if half_buffer {accumulation::Precision::Half} else {accumulation::Precision::Full};
+        }
+        settings.max_time = config.max_time.or(settings.max_time);
+        settings.max_total_samples = config.max_total_samples.or(settings.max_total_samples);
+        self.output_directory = config.output_directory.clone().unwrap_or_else(|| self.output_directory.clone());
+        self.texture_budget = config.texture_budget.or(self.texture_budget);
+        self.resolution = config.resolution.unwrap_or(self.resolution);
+        self.key_bindings.extend(config.key_bindings.iter().cloned());
+    }
+
+    fn read_args<I: Iterator<Item = String>>(&mut self, mut args: I) -> Result<()> {
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--reference" => {
+                    self.reference_path = Some(args.next().ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--reference requires a file path.".to_string())
+                    })?);
+                }
+                "--scene" => {
+                    self.scene_path = Some(args.next().ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--scene requires a file path.".to_string())
+                    })?);
+                }
+                "--generate" => {
+                    let spec = args.next().ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--generate requires settings, e.g. count=1000,placement=poisson.".to_string())
+                    })?;
+                    self.builtin_scene = Some(BuiltinScene::Generated(generator::GeneratorSettings::parse(&spec)?));
+                }
+                "--preset" => {
+                    let name = args.next().ok_or_else(|| {
+                        RaytracerError::InvalidArgument(format!("--preset requires a name: {}.", presets::PRESETS.join(", ")))
+                    })?;
+                    self.builtin_scene = Some(BuiltinScene::Preset(name));
+                }
+                "--script" => {
+                    self.script_path = Some(args.next().ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--script requires a file path.".to_string())
+                    })?);
+                }
+                "--backplate" => {
+                    self.backplate_path = Some(args.next().ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--backplate requires a file path.".to_string())
+                    })?);
+                }
+                "--watch" => {
+                    self.watch = true;
+                }
+                "--filter" => {
+                    let name = args.next().ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--filter requires box, tent, gaussian, or mitchell.".to_string())
+                    })?;
+                    self.settings.filter = raytracer::PixelFilter::from_name(&name).ok_or_else(|| {
+                        RaytracerError::InvalidArgument(format!("Unknown filter '{}'. Use box, tent, gaussian, or mitchell.", name))
+                    })?;
+                }
+                "--color-space" => {
+                    let name = args.next().ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--color-space requires srgb, rec709, acescg, or linear.".to_string())
+                    })?;
+                    self.settings.color_space = color::ColorSpace::from_name(&name).ok_or_else(|| {
+                        RaytracerError::InvalidArgument(format!("Unknown color space '{}'. Use srgb, rec709, acescg, or linear.", name))
+                    })?;
+                }
+                "--white-balance" => {
+                    self.settings.white_balance.temperature = args.next().and_then(|value| value.parse().ok()).ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--white-balance requires a temperature in kelvin.".to_string())
+                    })?;
+                }
+                "--tint" => {
+                    self.settings.white_balance.tint = args.next().and_then(|value| value.parse().ok()).ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--tint requires a value from -1 (green) to 1 (magenta).".to_string())
+                    })?;
+                }
+                "--auto-white-balance" => {
+                    self.settings.auto_white_balance = true;
+                }
+                "--low-priority" => {
+                    self.settings.low_priority = true;
+                }
+                "--raycast" => {
+                    self.settings.mode = raytracer::RenderMode::Raycast;
+                }
+                "--bracket" => {
+                    self.bracket_stops = args.next().and_then(|value| value.parse().ok()).filter(|stops: &Float| *stops > 0.0).ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--bracket requires a positive number of stops.".to_string())
+                    })?;
+                }
+                "--f-stop" => {
+                    let f_stop = args.next().and_then(|value| value.parse().ok()).filter(|f_stop: &Float| *f_stop > 0.0).ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--f-stop requires a positive number, such as 2.8.".to_string())
+                    })?;
+                    self.settings.physical_exposure.get_or_insert_with(exposure::PhysicalExposure::new).f_stop = f_stop;
+                }
+                "--shutter" => {
+                    let shutter = args.next().and_then(|value| exposure::parse_shutter(&value)).ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--shutter requires a time in seconds, such as 1/125 or 2.".to_string())
+                    })?;
+                    self.settings.physical_exposure.get_or_insert_with(exposure::PhysicalExposure::new).shutter = shutter;
+                }
+                "--iso" => {
+                    let iso = args.next().and_then(|value| value.parse().ok()).filter(|iso: &Float| *iso > 0.0).ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--iso requires a positive number, such as 400.".to_string())
+                    })?;
+                    self.settings.physical_exposure.get_or_insert_with(exposure::PhysicalExposure::new).iso = iso;
+                }
+                "--aperture-blades" => {
+                    self.aperture_blades = Some(args.next().and_then(|value| value.parse().ok()).filter(|blades| (3..=MAX_APERTURE_BLADES).contains(blades)).ok_or_else(|| {
+                        RaytracerError::InvalidArgument(format!("--aperture-blades requires a number of blades, from 3 to {}.", MAX_APERTURE_BLADES))
+                    })?);
+                }
+                "--blade-rotation" => {
+                    self.blade_rotation = args.next().and_then(|value| value.parse().ok()).ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--blade-rotation requires an angle in degrees.".to_string())
+                    })?;
+                }
+                "--bokeh-mask" => {
+                    self.bokeh_mask_path = Some(args.next().ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--bokeh-mask requires a file path.".to_string())
+                    })?);
+                }
+                "--stamp" => {
+                    self.settings.stamp = true;
+                }
+                "--output-dir" => {
+                    self.output_directory = args.next().ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--output-dir requires a directory.".to_string())
+                    })?;
+                }
+                "--no-config" => {}
+                "--furnace-test" => {
+                    self.furnace_test = true;
+                }
+                "--batch" => {
+                    self.batch_path = Some(args.next().ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--batch requires a file path.".to_string())
+                    })?);
+                }
+                "--poster" => {
+                    self.poster_tiles = Some(args.next().and_then(|value| value.parse().ok()).filter(|tiles| (1..=poster::MAX_TILES).contains(tiles)).ok_or_else(|| {
+                        RaytracerError::InvalidArgument(format!("--poster requires a number of tiles along each side, from 1 to {}.", poster::MAX_TILES))
+                    })?);
+                }
+                "--tile-order" => {
+                    let name = args.next().ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--tile-order requires rows, spiral, center, or hilbert.".to_string())
+                    })?;
+                    self.poster_order = poster::TileOrder::from_name(&name).ok_or_else(|| {
+                        RaytracerError::InvalidArgument(format!("Unknown tile order '{}'. Use rows, spiral, center, or hilbert.", name))
+                    })?;
+                }
+                "--half-buffer" => {
+                    self.settings.accumulation = accumulation::Precision::Half;
+                }
+                "--max-time" => {
+                    self.settings.max_time = Some(args.next().and_then(|value| value.parse().ok()).and_then(|seconds| Duration::try_from_secs_f64(seconds).ok()).ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--max-time requires a number of seconds.".to_string())
+                    })?);
+                }
+                "--max-samples" => {
+                    self.settings.max_total_samples = Some(args.next().and_then(|value| value.parse().ok()).ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--max-samples requires a number of camera samples.".to_string())
+                    })?);
+                }
+                "--snapshot-minutes" => {
+                    let every = args.next().and_then(|value| value.parse().ok()).filter(|minutes: &f64| *minutes > 0.0).and_then(|minutes| Duration::try_from_secs_f64(minutes * 60.0).ok());
+                    self.snapshot_every = Some(every.ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--snapshot-minutes requires a number of minutes.".to_string())
+                    })?);
+                }
+                "--snapshot-samples" => {
+                    self.snapshot_samples = Some(args.next().and_then(|value| value.parse().ok()).filter(|samples: &u32| *samples > 0).ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--snapshot-samples requires a number of samples per pixel.".to_string())
+                    })?);
+                }
+                "--resolution" => {
+                    self.resolution = args.next().and_then(|value| config::parse_resolution(&value)).ok_or_else(|| {
+                        RaytracerError::InvalidArgument(format!("--resolution requires a size such as 3840x2160, of at most {} along each side.", raytracer::MAX_RESOLUTION))
+                    })?;
+                }
+                "--texture-budget" => {
+                    let bytes = args.next()
+                        .and_then(|value| value.parse::<usize>().ok())
+                        .and_then(|megabytes| megabytes.checked_mul(1024 * 1024))
+                        .ok_or_else(|| RaytracerError::InvalidArgument("--texture-budget requires a size in megabytes.".to_string()))?;
+                    self.texture_budget = Some(bytes);
+                }
+                "--transparent" => {
+                    self.settings.transparent_background = true;
+                }
+                "--depth-clips" => {
+                    let mut clip = || -> Result<Float> {
+                        args.next().and_then(|value| value.parse().ok()).ok_or_else(|| {
+                            RaytracerError::InvalidArgument("--depth-clips requires a near and a far distance.".to_string())
+                        })
+                    };
+                    let (near, far) = (clip()?, clip()?);
+                    if !(near >= 0.0 && far > near) {
+                        return Err(RaytracerError::InvalidArgument("--depth-clips requires 0 <= near < far.".to_string()))
+                    }
+                    self.settings.depth_clips = Some((near, far));
+                }
+                "--depth-encoding" => {
+                    let name = args.next().ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--depth-encoding requires linear or normalized.".to_string())
+                    })?;
+                    self.settings.depth_encoding = raytracer::DepthEncoding::from_name(&name).ok_or_else(|| {
+                        RaytracerError::InvalidArgument(format!("Unknown depth encoding '{}'. Use linear or normalized.", name))
+                    })?;
+                }
+                "--id-coverage" => {
+                    self.settings.id_coverage = true;
+                }
+                "--autofocus" => {
+                    self.settings.autofocus = true;
+                }
+                "--bloom" => {
+                    self.settings.bloom = Some(self.settings.bloom.unwrap_or_default());
+                }
+                "--glare" => {
+                    let streaks = args.next().and_then(|value| value.parse().ok()).ok_or_else(|| {
+                        RaytracerError::InvalidArgument("--glare requires a number of streaks.".to_string())
+                    })?;
+                    self.settings.bloom = Some(post::Bloom{streaks: streaks, ..self.settings.bloom.unwrap_or_default()});
+                }
+                "--lens-effects" => {
+                    self.settings.lens = Some(post::LensEffects::new());
+                }
+                "--max-diffuse-depth" | "--max-specular-depth" => {
+                    let depth = args.next().and_then(|value| value.parse().ok()).ok_or_else(|| {
+                        RaytracerError::InvalidArgument(format!("{} requires a number of bounces.", arg))
+                    })?;
+                    if arg == "--max-diffuse-depth" {
+                        self.settings.max_diffuse_depth = Some(depth);
+                    } else {
+                        self.settings.max_specular_depth = Some(depth);
+                    }
+                }
+                "--quiet" | "-q" => {
+                    self.log_level = log::LevelFilter::Warn;
+                }
+                "--verbose" | "-v" => {
+                    // Repeating the flag also shows per-line render progress.
+                    self.log_level = if self.log_level == log::LevelFilter::Debug {log::LevelFilter::Trace} else {log::LevelFilter::Debug};
+                }
+                _ => {
+                    return Err(RaytracerError::InvalidArgument(format!("Unknown argument '{}'.", arg)))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Rejects flags that can't be used together.
+    fn check(&self) -> Result<()> {
+        if self.watch && self.scene_path.is_none() {
+            return Err(RaytracerError::InvalidArgument("--watch requires --scene <file>.".to_string()))
+        }
+        if self.batch_path.is_some() && (self.scene_path.is_some() || self.watch) {
+            return Err(RaytracerError::InvalidArgument("--batch lists its own scenes, so it can't be used with --scene or --watch.".to_string()))
+        }
+        if self.poster_tiles.is_some() && (self.batch_path.is_some() || self.watch) {
+            return Err(RaytracerError::InvalidArgument("--poster renders a single scene, so it can't be used with --batch or --watch.".to_string()))
+        }
+        if self.builtin_scene.is_some() && (self.scene_path.is_some() || self.batch_path.is_some()) {
+            return Err(RaytracerError::InvalidArgument("--generate and --preset replace the scene, so they can't be used with --scene or --batch.".to_string()))
+        }
+        if self.bokeh_mask_path.is_some() && self.aperture_blades.is_some() {
+            return Err(RaytracerError::InvalidArgument("--bokeh-mask and --aperture-blades both shape the aperture, so only one can be used.".to_string()))
+        }
+        Ok(())
+    }
+
+    // The render settings with the aperture, reading its mask if it has one.
+    pub fn render_settings(&self) -> Result<raytracer::RenderSettings> {
+        let mut settings = self.settings.clone();
+        settings.aperture = match (&self.bokeh_mask_path, self.aperture_blades) {
+            (Some(path), _) => {
+                let mask = exposure::ApertureMask::new(&image::Image::read_ppm(path)?).ok_or_else(|| {
+                    RaytracerError::InvalidArgument(format!("The bokeh mask {} is black, so it lets no light through.", path))
+                })?;
+                exposure::Aperture::Mask(std::sync::Arc::new(mask))
+            }
+            (None, Some(count)) => exposure::Aperture::Blades{count: count, rotation: self.blade_rotation},
+            (None, None) => exposure::Aperture::Circle
+        };
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_overrides_the_config() {
+        let config = config::parse("rs-raytracer.toml", "samples = 64\nresolution = \"640x360\"\nhalf_buffer = true\n\n[keys]\nP = \"F12\"\n").unwrap();
+        let args = |list: &[&str]| list.iter().map(|arg| arg.to_string()).collect::<Vec<String>>().into_iter();
+        let options = Options::parse(Some(&config), args(&["--resolution", "1280x720", "--stamp"])).unwrap();
+        assert_eq!(options.settings.samples_per_pixel, 64);
+        assert_eq!(options.resolution, (1280, 720));
+        assert!(options.settings.accumulation == accumulation::Precision::Half);
+        assert!(options.settings.stamp);
+        assert_eq!(options.key_bindings, vec![("P".to_string(), "F12".to_string())]);
+
+        let defaults = Options::parse(None, args(&[])).unwrap();
+        assert_eq!(defaults.resolution, (raytracer::WIDTH, raytracer::HEIGHT));
+        assert!(!defaults.settings.stamp);
+        assert!(Options::parse(None, args(&["--watch"])).is_err());
+        assert!(Options::parse(None, args(&["--tint"])).is_err());
+        assert!(Options::parse(None, args(&["--shiny"])).is_err());
+        assert!(Options::parse(None, args(&["--bokeh-mask", "star.ppm", "--aperture-blades", "6"])).is_err());
+    }
+}
@@ -0,0 +1,606 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use log::{debug, info, warn};
+
+use crate::error::{RaytracerError, Result};
+use crate::light::Light;
+use crate::math::*;
+use crate::primitives::{Disk, Mesh};
+use crate::raytracer::{Camera, Dielectric, Lambertian, Metal, MixMaterial, PbrMaterial, Sphere, MAX_RESOLUTION, WIDTH, HEIGHT};
+use crate::scene::Scene;
+
+// --------------------------------------------------
+// Tokens
+// --------------------------------------------------
+#[derive(Clone, PartialEq)]
+enum Token {
+    Word(String), // Directive names and numbers.
+    Str(String), // Quoted strings.
+    Open,
+    Close
+}
+
+impl Token {
+    fn is_directive(&self) -> bool {
+        match self {
+            Token::Word(w) => w.starts_with(|c: char| c.is_ascii_alphabetic()),
+            _ => false
+        }
+    }
+}
+
+fn tokenize(path: &str, text: &str) -> Result<Vec<(Token, usize)>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            '#' => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                        break;
+                    }
+                }
+            }
+            '[' => tokens.push((Token::Open, line)),
+            ']' => tokens.push((Token::Close, line)),
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => s.push(c),
+                        None => return Err(invalid(path, line, "unterminated string"))
+                    }
+                }
+                tokens.push((Token::Str(s), line));
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut s = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || next == '[' || next == ']' || next == '"' || next == '#' {
+                        break;
+                    }
+                    s.push(next);
+                    chars.next();
+                }
+                tokens.push((Token::Word(s), line));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn invalid(path: &str, line: usize, reason: &str) -> RaytracerError {
+    RaytracerError::InvalidScene {
+        path: path.to_string(),
+        reason: format!("line {}: {}", line, reason)
+    }
+}
+
+
+// --------------------------------------------------
+// Directives
+// --------------------------------------------------
+// A typed parameter such as "float radius" [1].
+struct Param {
+    type_name: String,
+    name: String,
+    numbers: Vec<Float>,
+    strings: Vec<String>
+}
+
+struct Directive {
+    name: String,
+    line: usize,
+    numbers: Vec<Float>, // Positional numbers, e.g. for Translate.
+    strings: Vec<String>, // Positional strings, e.g. the shape type.
+    params: Vec<Param>
+}
+
+impl Directive {
+    fn param(&self, name: &str) -> Option<&Param> {
+        self.params.iter().find(|p| p.name == name)
+    }
+
+    fn float(&self, name: &str, default: Float) -> Float {
+        self.param(name).and_then(|p| p.numbers.first().copied()).unwrap_or(default)
+    }
+
+    fn floats(&self, name: &str) -> Vec<Float> {
+        self.param(name).map_or(Vec::new(), |p| p.numbers.clone())
+    }
+
+    fn string(&self, name: &str) -> Option<&str> {
+        self.param(name).and_then(|p| p.strings.first()).map(|s| s.as_str())
+    }
+
+    // RGB parameters. Textures and spectra are not supported and fall back to the default.
+    fn rgb(&self, name: &str, default: Vec3) -> Vec3 {
+        match self.param(name) {
+            Some(p) if (p.type_name == "rgb" || p.type_name == "color") && p.numbers.len() >= 3 => {
+                Vec3::new(p.numbers[0], p.numbers[1], p.numbers[2])
+            }
+            Some(p) if p.type_name == "float" && !p.numbers.is_empty() => Vec3::one() * p.numbers[0],
+            Some(p) => {
+                warn!(target: "scene", "Unsupported {} value for '{}' on line {}. Using the default.", p.type_name, name, self.line);
+                default
+            }
+            None => default
+        }
+    }
+
+    fn positional(&self, count: usize, path: &str) -> Result<&[Float]> {
+        if self.numbers.len() < count {
+            return Err(invalid(path, self.line, &format!("{} expects {} numbers", self.name, count)))
+        }
+        Ok(&self.numbers[..count])
+    }
+}
+
+fn parse_number(path: &str, line: usize, word: &str) -> Result<Float> {
+    word.parse().map_err(|_| invalid(path, line, &format!("malformed number '{}'", word)))
+}
+
+// Splits a token stream into directives. Positional arguments come first, then "type name" value pairs.
+fn parse_directives(path: &str, tokens: &[(Token, usize)]) -> Result<Vec<Directive>> {
+    let mut directives = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let (token, line) = &tokens[i];
+        let name = match token {
+            Token::Word(w) if token.is_directive() => w.clone(),
+            _ => return Err(invalid(path, *line, "expected a directive"))
+        };
+        let mut directive = Directive {
+            name: name,
+            line: *line,
+            numbers: Vec::new(),
+            strings: Vec::new(),
+            params: Vec::new()
+        };
+        i += 1;
+
+        let mut in_list = false;
+        while i < tokens.len() && !tokens[i].0.is_directive() {
+            let (token, line) = &tokens[i];
+            i += 1;
+            match token {
+                Token::Open if directive.params.is_empty() => in_list = true,
+                Token::Close if in_list => in_list = false,
+                Token::Word(w) if directive.params.is_empty() => directive.numbers.push(parse_number(path, *line, w)?),
+                // Parameter declarations are the only strings with a space in them.
+                Token::Str(s) if s.split_whitespace().count() == 2 => {
+                    let mut parts = s.split_whitespace();
+                    let type_name = parts.next().unwrap_or("").to_string();
+                    let param_name = parts.next().unwrap_or("").to_string();
+                    let mut param = Param {
+                        type_name: type_name,
+                        name: param_name,
+                        numbers: Vec::new(),
+                        strings: Vec::new()
+                    };
+
+                    // The value is either a single item or a bracketed list.
+                    let mut values = Vec::new();
+                    match tokens.get(i) {
+                        Some((Token::Open, _)) => {
+                            i += 1;
+                            while i < tokens.len() && tokens[i].0 != Token::Close {
+                                values.push(tokens[i].clone());
+                                i += 1;
+                            }
+                            if i == tokens.len() {
+                                return Err(invalid(path, *line, "unterminated list"))
+                            }
+                            i += 1;
+                        }
+                        Some(value) => {
+                            values.push(value.clone());
+                            i += 1;
+                        }
+                        None => return Err(invalid(path, *line, &format!("missing value for '{}'", s)))
+                    }
+                    for (value, line) in values {
+                        match value {
+                            Token::Word(w) => param.numbers.push(parse_number(path, line, &w)?),
+                            Token::Str(s) => param.strings.push(s),
+                            _ => return Err(invalid(path, line, "nested lists are not allowed"))
+                        }
+                    }
+                    directive.params.push(param);
+                }
+                Token::Str(s) if directive.params.is_empty() => directive.strings.push(s.clone()),
+                _ => return Err(invalid(path, *line, &format!("unexpected argument to {}", directive.name)))
+            }
+        }
+        directives.push(directive);
+    }
+    Ok(directives)
+}
+
+
+// --------------------------------------------------
+// Loader
+// --------------------------------------------------
+#[derive(Clone)]
+struct GraphicsState {
+    transform: Mat4, // Object to world, or world to camera before WorldBegin.
+    mat_id: u32
+}
+
+struct Loader<'a> {
+    scene: &'a mut Scene,
+    state: GraphicsState,
+    stack: Vec<GraphicsState>,
+    coordinate_systems: HashMap<String, Mat4>,
+    camera: Option<(Mat4, Float)>, // Camera to world and the field of view along the shorter image axis.
+    film_size: (u32, u32),
+    shape_count: usize,
+    ignored: Vec<String> // Directives that were skipped, reported once each.
+}
+
+impl<'a> Loader<'a> {
+    fn ignore(&mut self, what: String) {
+        if !self.ignored.contains(&what) {
+            warn!(target: "scene", "Ignoring unsupported {}.", what);
+            self.ignored.push(what);
+        }
+    }
+
+    fn load_file(&mut self, path: &Path) -> Result<()> {
+        let path_str = path.to_string_lossy().to_string();
+        let text = fs::read_to_string(path)?;
+        let directives = parse_directives(&path_str, &tokenize(&path_str, &text)?)?;
+        let directory = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+        for directive in &directives {
+            self.apply(&path_str, &directory, directive)?;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, path: &str, directory: &Path, d: &Directive) -> Result<()> {
+        let first = d.strings.first().map(|s| s.as_str()).unwrap_or("");
+        match d.name.as_str() {
+            // Transforms are post-multiplied, so the last one applies to objects first.
+            "Identity" => self.state.transform = Mat4::identity(),
+            "Translate" => {
+                let v = d.positional(3, path)?;
+                self.state.transform = self.state.transform * Mat4::translation(Vec3::new(v[0], v[1], v[2]));
+            }
+            "Scale" => {
+                let v = d.positional(3, path)?;
+                self.state.transform = self.state.transform * Mat4::scale(Vec3::new(v[0], v[1], v[2]));
+            }
+            "Rotate" => {
+                let v = d.positional(4, path)?;
+                self.state.transform = self.state.transform * Mat4::rotation(v[0].to_radians(), Vec3::new(v[1], v[2], v[3]));
+            }
+            "LookAt" => {
+                let v = d.positional(9, path)?;
+                let eye = Vec3::new(v[0], v[1], v[2]);
+                let dir = (Vec3::new(v[3], v[4], v[5]) - eye).normalized();
+                let right = Vec3::new(v[6], v[7], v[8]).normalized().cross(&dir).normalized();
+                let up = dir.cross(&right);
+                let camera_to_world = Mat4 {
+                    m: [
+                        [right.x, up.x, dir.x, eye.x],
+                        [right.y, up.y, dir.y, eye.y],
+                        [right.z, up.z, dir.z, eye.z],
+                        [0.0, 0.0, 0.0, 1.0]
+                    ]
+                };
+                let world_to_camera = camera_to_world.inverse().ok_or_else(|| invalid(path, d.line, "degenerate LookAt"))?;
+                self.state.transform = self.state.transform * world_to_camera;
+            }
+            "Transform" | "ConcatTransform" => {
+                let v = d.positional(16, path)?;
+                let mut cols = [[0.0; 4]; 4];
+                for (i, value) in v.iter().enumerate() {
+                    cols[i / 4][i % 4] = *value;
+                }
+                let m = Mat4::from_cols(&cols);
+                self.state.transform = if d.name == "Transform" {m} else {self.state.transform * m};
+            }
+            "CoordinateSystem" => {
+                self.coordinate_systems.insert(first.to_string(), self.state.transform);
+            }
+            "CoordSysTransform" => {
+                match self.coordinate_systems.get(first) {
+                    Some(m) => self.state.transform = *m,
+                    None => warn!(target: "scene", "Unknown coordinate system '{}' on line {}.", first, d.line)
+                }
+            }
+
+            "Camera" => {
+                if first != "perspective" {
+                    self.ignore(format!("{} camera", first));
+                    return Ok(())
+                }
+                let camera_to_world = self.state.transform.inverse().ok_or_else(|| invalid(path, d.line, "singular camera transform"))?;
+                self.camera = Some((camera_to_world, d.float("fov", 90.0)));
+                self.coordinate_systems.insert("camera".to_string(), camera_to_world);
+            }
+            "Film" => {
+                // Held to the same sizes as --resolution.
+                let size = |name: &str, default: Float| -> Result<u32> {
+                    let value = d.float(name, default);
+                    if value.fract() != 0.0 || value < 1.0 || value > MAX_RESOLUTION as Float {
+                        return Err(RaytracerError::InvalidArgument(format!("{} on line {} of {} must be a whole number from 1 to {}, not {}.",
+                            name, d.line, path, MAX_RESOLUTION, value)))
+                    }
+                    Ok(value as u32)
+                };
+                self.film_size = (size("xresolution", 1280.0)?, size("yresolution", 720.0)?);
+            }
+            "WorldBegin" => {
+                self.state.transform = Mat4::identity();
+                self.coordinate_systems.insert("world".to_string(), Mat4::identity());
+            }
+            "WorldEnd" => {}
+
+            "AttributeBegin" | "TransformBegin" => self.stack.push(self.state.clone()),
+            "AttributeEnd" | "TransformEnd" => {
+                let previous = self.stack.pop().ok_or_else(|| invalid(path, d.line, &format!("unmatched {}", d.name)))?;
+                // TransformEnd only restores the transform.
+                if d.name == "TransformEnd" {
+                    self.state.transform = previous.transform;
+                } else {
+                    self.state = previous;
+                }
+            }
+
+            "Material" => self.state.mat_id = self.make_material(first, d),
+            "MakeNamedMaterial" => {
+                let mat_type = d.string("type").unwrap_or("matte").to_string();
+                let mat_id = self.make_material(&mat_type, d);
+                self.scene.set_material_name(mat_id, first)?;
+            }
+            "NamedMaterial" => {
+                match self.scene.get_material_by_name(first) {
+                    Some(mat_id) => self.state.mat_id = mat_id,
+                    None => warn!(target: "scene", "Unknown material '{}' on line {}. Using the default material.", first, d.line)
+                }
+            }
+
+            "Shape" => self.add_shape(path, first, d)?,
+            "LightSource" => self.add_light(first, d),
+            "Include" => {
+                let include = directory.join(first);
+                debug!(target: "io", "Including {}...", include.to_string_lossy());
+                self.load_file(&include)?;
+            }
+
+            // Options that don't affect what this ray tracer renders.
+            "Sampler" | "Integrator" | "PixelFilter" | "Accelerator" | "ReverseOrientation" => {
+                debug!(target: "scene", "Skipping {} on line {}.", d.name, d.line);
+            }
+            other => self.ignore(format!("directive {}", other))
+        }
+        Ok(())
+    }
+
+    fn make_material(&mut self, mat_type: &str, d: &Directive) -> u32 {
+        match mat_type {
+            "matte" => self.scene.add_lambertian_material(Lambertian::new(d.rgb("Kd", Vec3::one() * 0.5))),
+            "plastic" => {
+                let roughness = d.float("roughness", 0.1);
+                self.scene.add_pbr_material(PbrMaterial::new(d.rgb("Kd", Vec3::one() * 0.25), 0.0, roughness.sqrt()))
+            }
+            "mirror" => self.scene.add_metal_material(Metal::new(d.rgb("Kr", Vec3::one() * 0.9), 0.0)),
+            "metal" => {
+                // Reflectance at normal incidence from the complex index of refraction. Defaults to copper.
+                let eta = d.rgb("eta", Vec3::new(0.2, 0.92, 1.1));
+                let k = d.rgb("k", Vec3::new(3.91, 2.45, 2.14));
+                let reflectance = |n: Float, k: Float| ((n - 1.0) * (n - 1.0) + k * k) / ((n + 1.0) * (n + 1.0) + k * k);
+                let albedo = Vec3::new(reflectance(eta.x, k.x), reflectance(eta.y, k.y), reflectance(eta.z, k.z));
+                let roughness = d.float("roughness", 0.01);
+                self.scene.add_metal_material(Metal::new(albedo, roughness.sqrt()))
+            }
+            "glass" => {
+                let ior = d.param("eta").or_else(|| d.param("index")).and_then(|p| p.numbers.first().copied()).unwrap_or(1.5);
+                self.scene.add_dielectric_material(Dielectric::new(ior))
+            }
+            "mix" => {
+                // amount is the weight of the second material. Textured amounts fall back to an even mix.
+                let names = d.param("materials").map_or(Vec::new(), |p| p.strings.clone());
+                let amount = d.rgb("amount", Vec3::one() * 0.5);
+                let shared = |name: Option<&String>| {
+                    let mat_id = name.and_then(|name| self.scene.get_material_by_name(name));
+                    if mat_id.is_none() {
+                        warn!(target: "scene", "Unknown mix material '{}' on line {}. Using the default material.", name.map_or("", |name| name.as_str()), d.line);
+                    }
+                    self.scene.get_shared_material(mat_id.unwrap_or(0))
+                };
+                match (shared(names.first()), shared(names.get(1))) {
+                    (Ok(a), Ok(b)) => self.scene.add_mix_material(MixMaterial::from_shared(a, b, (amount.x + amount.y + amount.z) / 3.0)),
+                    _ => 0
+                }
+            }
+            other => {
+                self.ignore(format!("{} material (using matte)", other));
+                self.scene.add_lambertian_material(Lambertian::new(d.rgb("Kd", Vec3::one() * 0.5)))
+            }
+        }
+    }
+
+    fn add_light(&mut self, light_type: &str, d: &Directive) {
+        let transform = self.state.transform;
+        let point = |name: &str, default: Vec3| match d.floats(name).as_slice() {
+            [x, y, z] => Vec3::new(*x, *y, *z),
+            _ => default
+        };
+        let from = point("from", Vec3::zero());
+        let to = point("to", Vec3::new(0.0, 0.0, 1.0));
+        let scale = d.rgb("scale", Vec3::one());
+        let light = match light_type {
+            "point" => Light::point(transform.transform_point(from), d.rgb("I", Vec3::one()) * scale),
+            "spot" => Light::spot(
+                transform.transform_point(from), transform.transform_dir(to - from), d.rgb("I", Vec3::one()) * scale,
+                d.float("coneangle", 30.0), d.float("conedeltaangle", 5.0)
+            ),
+            "distant" => Light::directional(transform.transform_dir(to - from), d.rgb("L", Vec3::one()) * scale),
+            other => {
+                self.ignore(format!("{} light", other));
+                return
+            }
+        };
+        self.scene.add_light(light);
+    }
+
+    fn add_shape(&mut self, path: &str, shape: &str, d: &Directive) -> Result<()> {
+        let transform = self.state.transform;
+        let mat_id = self.state.mat_id;
+        match shape {
+            "sphere" => {
+                // Non-uniform scales are approximated by their average.
+                let radius = d.float("radius", 1.0) * transform.determinant().abs().cbrt();
+                self.scene.add_sphere(Sphere::new(transform.transform_point(Vec3::zero()), radius, mat_id))?;
+            }
+            "disk" => {
+                let center = transform.transform_point(Vec3::new(0.0, 0.0, d.float("height", 0.0)));
+                let radius = d.float("radius", 1.0) * transform.determinant().abs().cbrt();
+                let normal = transform.transform_normal(Vec3::new(0.0, 0.0, 1.0));
+                self.scene.add_disk(Disk::new(center, normal, radius, mat_id))?;
+            }
+            "trianglemesh" => {
+                let points = d.floats("P");
+                let indices = d.floats("indices");
+                let normals = d.floats("N");
+                let uvs = if d.param("uv").is_some() {d.floats("uv")} else {d.floats("st")};
+
+                let positions: Vec<Vec3> = points.chunks_exact(3).map(|p| transform.transform_point(Vec3::new(p[0], p[1], p[2]))).collect();
+                let normals: Vec<Vec3> = normals.chunks_exact(3).map(|n| transform.transform_normal(Vec3::new(n[0], n[1], n[2]))).collect();
+                let uvs: Vec<(Float, Float)> = uvs.chunks_exact(2).map(|uv| (uv[0], 1.0 - uv[1])).collect();
+                let triangles: Vec<[u32; 3]> = if indices.is_empty() && positions.len() == 3 {
+                    vec![[0, 1, 2]]
+                } else {
+                    indices.chunks_exact(3).map(|tri| [tri[0] as u32, tri[1] as u32, tri[2] as u32]).collect()
+                };
+
+                let mesh = Mesh::new(positions, normals, uvs, triangles, mat_id).map_err(|e| invalid(path, d.line, &e.to_string()))?;
+                self.scene.add_mesh(mesh)?;
+            }
+            other => {
+                self.ignore(format!("{} shape", other));
+                return Ok(())
+            }
+        }
+        self.shape_count += 1;
+        Ok(())
+    }
+}
+
+// Adds the shapes, materials, point, spot, and distant lights, and perspective camera of a PBRT v3 scene.
+// Other lights, textures, and sampler/integrator settings are skipped, as this ray tracer has neither.
+pub fn load(scene: &mut Scene, path: &str) -> Result<()> {
+    info!(target: "io", "Reading PBRT file {}...", path);
+    let mut loader = Loader {
+        scene: scene,
+        state: GraphicsState {
+            transform: Mat4::identity(),
+            mat_id: 0
+        },
+        stack: Vec::new(),
+        coordinate_systems: HashMap::new(),
+        camera: None,
+        film_size: (1280, 720),
+        shape_count: 0,
+        ignored: Vec::new()
+    };
+    loader.load_file(Path::new(path))?;
+
+    if loader.film_size != (WIDTH, HEIGHT) {
+        warn!(target: "scene", "Film resolution {}x{} differs from the default resolution {}x{}. Keeping the field of view.",
+            loader.film_size.0, loader.film_size.1, WIDTH, HEIGHT);
+    }
+    if let Some((camera_to_world, fov)) = loader.camera {
+        // PBRT's fov spans the shorter image axis.
+        let aspect_ratio = (WIDTH as Float) / (HEIGHT as Float);
+        let film_aspect = (loader.film_size.0 as Float) / (loader.film_size.1 as Float);
+        let vertical_fov = if film_aspect >= 1.0 {
+            fov
+        } else {
+            2.0 * ((fov.to_radians() * 0.5).tan() / film_aspect).atan().to_degrees()
+        };
+
+        // PBRT cameras look down +Z with +X to the right of the image.
+        let origin = camera_to_world.transform_point(Vec3::zero());
+        let right = camera_to_world.transform_dir(Vec3::new(1.0, 0.0, 0.0)).normalized();
+        let up = camera_to_world.transform_dir(Vec3::new(0.0, 1.0, 0.0)).normalized();
+        let forward = camera_to_world.transform_dir(Vec3::new(0.0, 0.0, 1.0)).normalized();
+        loader.scene.set_camera(Camera::from_frame(origin, right, up, forward, vertical_fov, aspect_ratio));
+    }
+
+    info!(target: "scene", "Loaded {} shapes from {}.", loader.shape_count, path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Fixture;
+
+    const SCENE: &str = r#"# A sphere and a floor, with the floor in an included file.
+LookAt 0 1 -5  0 1 0  0 1 0
+Camera "perspective" "float fov" [45]
+Film "image" "integer xresolution" [640] "integer yresolution" [360]
+Sampler "halton"
+WorldBegin
+LightSource "point" "rgb I" [10 10 10] "point from" [0 4 0]
+MakeNamedMaterial "gold" "string type" "metal"
+AttributeBegin
+  Translate 0 1 0
+  NamedMaterial "gold"
+  Shape "sphere" "float radius" 1
+AttributeEnd
+Include "floor.pbrt"
+WorldEnd
+"#;
+
+    const FLOOR: &str = r#"Material "matte" "rgb Kd" [0.5 0.5 0.5]
+Shape "trianglemesh" "point P" [-5 0 -5  5 0 -5  5 0 5  -5 0 5] "integer indices" [0 1 2  0 2 3]
+"#;
+
+    #[test]
+    fn pbrt_scenes_load_shapes_materials_lights_and_camera() {
+        let files = Fixture::new("pbrt_scenes");
+        let path = files.write("scene.pbrt", SCENE);
+        files.write("floor.pbrt", FLOOR);
+        let mut scene = Scene::new();
+        load(&mut scene, &path).unwrap();
+        assert_eq!(scene.object_count(), 2);
+        assert_eq!(scene.material_count(), 3);
+        assert!(scene.get_material_as::<Metal>(scene.get_material_by_name("gold").unwrap()).is_ok());
+        assert_eq!(scene.get_lights().len(), 1);
+        assert!((scene.get_camera().get_position() - Vec3::new(0.0, 1.0, -5.0)).length() < 1e-4);
+
+        // The camera looks at the sphere, which the Translate lifted off the floor.
+        let ray = Ray::new(Vec3::new(0.0, 1.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        let hr = scene.hit(&ray, Interval::new(0.001, Float::MAX)).0.unwrap();
+        assert!((hr.t - 4.0).abs() < 1e-4);
+        assert_eq!(hr.mat_id, scene.get_material_by_name("gold").unwrap());
+    }
+
+    #[test]
+    fn malformed_pbrt_scenes_are_errors() {
+        let files = Fixture::new("malformed_pbrt");
+        let load_text = |name: &str, text: &str| load(&mut Scene::new(), &files.write(&format!("{}.pbrt", name), text));
+        let message = |name: &str, text: &str| load_text(name, text).err().map(|e| e.to_string()).unwrap_or_default();
+        assert!(message("unmatched", "WorldBegin\n\nAttributeEnd\n").contains("line 3"));
+        assert!(message("positional", "Translate 1 2\n").contains("line 1"));
+        assert!(load_text("string", "Shape \"sphere\" \"float radius [1]\n").is_err());
+        assert!(matches!(load_text("include", "Include \"missing.pbrt\"\n"), Err(RaytracerError::Io(_))));
+
+        // Film sizes that --resolution would refuse are refused here too.
+        for size in ["0", "-4", "12.5", "100000"].iter() {
+            let film = format!("Film \"image\" \"integer xresolution\" [{}] \"integer yresolution\" [360]\n", size);
+            assert!(matches!(load_text("film", &film), Err(RaytracerError::InvalidArgument(_))));
+        }
+    }
+}
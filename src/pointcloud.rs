@@ -0,0 +1,274 @@
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::fs::File;
+
+use crate::bvh::Bvh;
+use crate::error::{RaytracerError, Result};
+use crate::math::*;
+use crate::raytracer::{HitRecord, Hittable, ObjectMemory, Sphere};
+
+// --------------------------------------------------
+// PointCloud
+// --------------------------------------------------
+// A sphere splat per point, all sharing a radius and material.
+pub struct PointCloud {
+    points: Vec<Vec3>,
+    radius: Float,
+    bvh: Bvh,
+    pub mat_id: u32
+}
+
+impl PointCloud {
+    pub fn new(points: Vec<Vec3>, radius: Float, mat_id: u32) -> PointCloud {
+        let bounds: Vec<Aabb> = points.iter().map(|p| Aabb::around_sphere(*p, radius)).collect();
+        PointCloud {
+            points: points,
+            radius: radius,
+            bvh: Bvh::build(&bounds),
+            mat_id: mat_id
+        }
+    }
+
+    // Loads a .ply or .xyz file depending on its extension.
+    pub fn load(path: &str, radius: Float, mat_id: u32) -> Result<PointCloud> {
+        let points = if path.to_lowercase().ends_with(".ply") {read_ply(path)?} else {read_xyz(path)?};
+        Ok(PointCloud::new(points, radius, mat_id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+}
+
+impl Hittable for PointCloud {
+    fn materials(&self) -> Vec<u32> {
+        vec![self.mat_id]
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bvh.bounds()
+    }
+
+    fn memory(&self) -> ObjectMemory {
+        ObjectMemory::with_bvh(self.points.len(), self.points.len() * std::mem::size_of::<Vec3>(), &self.bvh)
+    }
+
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        self.bvh.hit(ray, ray_t, |idx, ray, ray_t| {
+            Sphere::new(self.points[idx as usize], self.radius, self.mat_id).hit(ray, ray_t)
+        }).0
+    }
+}
+
+
+// --------------------------------------------------
+// Loaders
+// --------------------------------------------------
+fn invalid(path: &str, reason: &str) -> RaytracerError {
+    RaytracerError::InvalidScene {
+        path: path.to_string(),
+        reason: reason.to_string()
+    }
+}
+
+// One point per line as whitespace separated "x y z", optionally followed by other values (e.g. color) that are ignored.
+pub fn read_xyz(path: &str) -> Result<Vec<Vec3>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut points = Vec::new();
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let values: Vec<Float> = line.split_whitespace().take(3).filter_map(|v| v.parse().ok()).collect();
+        if values.len() != 3 {
+            return Err(invalid(path, &format!("line {} does not start with three numbers", line_idx + 1)))
+        }
+        points.push(Vec3::new(values[0], values[1], values[2]));
+    }
+    Ok(points)
+}
+
+#[derive(PartialEq)]
+enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian
+}
+
+// Byte size of a PLY scalar type.
+fn ply_type_size(name: &str) -> Option<usize> {
+    match name {
+        "char" | "uchar" | "int8" | "uint8" => Some(1),
+        "short" | "ushort" | "int16" | "uint16" => Some(2),
+        "int" | "uint" | "int32" | "uint32" | "float" | "float32" => Some(4),
+        "double" | "float64" => Some(8),
+        _ => None
+    }
+}
+
+// Reads a binary scalar of the given PLY type as Float.
+fn ply_read_binary(bytes: &[u8], type_name: &str, little_endian: bool) -> Float {
+    macro_rules! read {
+        ($t:ty, $n:expr) => {{
+            let mut buf = [0u8; $n];
+            buf.copy_from_slice(&bytes[..$n]);
+            (if little_endian {<$t>::from_le_bytes(buf)} else {<$t>::from_be_bytes(buf)}) as Float
+        }};
+    }
+    match type_name {
+        "char" | "int8" => read!(i8, 1),
+        "uchar" | "uint8" => read!(u8, 1),
+        "short" | "int16" => read!(i16, 2),
+        "ushort" | "uint16" => read!(u16, 2),
+        "int" | "int32" => read!(i32, 4),
+        "uint" | "uint32" => read!(u32, 4),
+        "float" | "float32" => read!(f32, 4),
+        _ => read!(f64, 8)
+    }
+}
+
+// Reads the vertex positions of an ASCII or binary PLY file. The vertex element must come first.
+pub fn read_ply(path: &str) -> Result<Vec<Vec3>> {
+    let mut data = Vec::new();
+    BufReader::new(File::open(path)?).read_to_end(&mut data)?;
+
+    // The header is always ASCII and ends with an end_header line.
+    let marker = b"end_header";
+    let header_end = data.windows(marker.len()).position(|w| w == marker).ok_or_else(|| invalid(path, "missing end_header"))?;
+    let mut body_start = header_end + marker.len();
+    while body_start < data.len() && data[body_start] != b'\n' {
+        body_start += 1;
+    }
+    body_start += 1;
+    let header = String::from_utf8_lossy(&data[..header_end]).into_owned();
+
+    let mut format = None;
+    let mut vertex_count = 0;
+    let mut in_vertex = false;
+    let mut seen_element = false;
+    let mut properties: Vec<(String, String)> = Vec::new(); // (type, name)
+    for (line_idx, line) in header.lines().enumerate() {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if line_idx == 0 {
+            if tokens != ["ply"] {
+                return Err(invalid(path, "not a PLY file"))
+            }
+            continue;
+        }
+        match tokens.as_slice() {
+            ["format", "ascii", ..] => format = Some(PlyFormat::Ascii),
+            ["format", "binary_little_endian", ..] => format = Some(PlyFormat::BinaryLittleEndian),
+            ["format", "binary_big_endian", ..] => format = Some(PlyFormat::BinaryBigEndian),
+            ["element", name, count] => {
+                if *name == "vertex" {
+                    if seen_element {
+                        return Err(invalid(path, "the vertex element must be the first element"))
+                    }
+                    vertex_count = count.parse().map_err(|_| invalid(path, "malformed vertex count"))?;
+                }
+                in_vertex = *name == "vertex";
+                seen_element = true;
+            }
+            ["property", "list", ..] if in_vertex => {
+                return Err(invalid(path, "list properties on vertices are not supported"))
+            }
+            ["property", type_name, name] if in_vertex => {
+                properties.push((type_name.to_string(), name.to_string()));
+            }
+            _ => {}
+        }
+    }
+    let format = format.ok_or_else(|| invalid(path, "missing format"))?;
+
+    let find = |axis: &str| properties.iter().position(|(_, name)| name == axis).ok_or_else(|| invalid(path, "vertices have no x, y, and z properties"));
+    let axes = [find("x")?, find("y")?, find("z")?];
+
+    // The count comes from the file, so only trust it as far as the file is long.
+    let mut points = Vec::with_capacity(vertex_count.min(data.len()));
+    if format == PlyFormat::Ascii {
+        // Lines of the body are numbered on from the end_header line.
+        let first_line = header.lines().count() + 2;
+        let body = String::from_utf8_lossy(&data[body_start.min(data.len())..]).into_owned();
+        for (line_idx, line) in body.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()).take(vertex_count) {
+            let line_no = first_line + line_idx;
+            let values = line.split_whitespace().map(|v| v.parse::<Float>()).collect::<std::result::Result<Vec<Float>, _>>()
+                .map_err(|_| invalid(path, &format!("line {} has a malformed vertex value", line_no)))?;
+            if values.len() < properties.len() {
+                return Err(invalid(path, &format!("line {} has too few vertex values", line_no)))
+            }
+            points.push(Vec3::new(values[axes[0]], values[axes[1]], values[axes[2]]));
+        }
+    } else {
+        // Byte offset of every property within a vertex.
+        let mut offsets = Vec::with_capacity(properties.len());
+        let mut stride = 0;
+        for (type_name, _) in &properties {
+            offsets.push(stride);
+            stride += ply_type_size(type_name).ok_or_else(|| invalid(path, &format!("unknown property type {}", type_name)))?;
+        }
+        if stride.checked_mul(vertex_count).and_then(|size| size.checked_add(body_start)).filter(|end| *end <= data.len()).is_none() {
+            return Err(invalid(path, "truncated vertex data"))
+        }
+
+        let little_endian = format == PlyFormat::BinaryLittleEndian;
+        for i in 0..vertex_count {
+            let vertex = &data[(body_start + i * stride)..];
+            let value = |axis: usize| ply_read_binary(&vertex[offsets[axis]..], &properties[axis].0, little_endian);
+            points.push(Vec3::new(value(axes[0]), value(axes[1]), value(axes[2])));
+        }
+    }
+
+    if points.len() != vertex_count {
+        return Err(invalid(path, "truncated vertex data"))
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Fixture;
+
+    #[test]
+    fn point_clouds_load_from_ply_and_xyz() {
+        let files = Fixture::new("point_clouds");
+        let header = "ply\nformat ascii 1.0\nelement vertex 2\nproperty float x\nproperty float y\nproperty float z\nproperty uchar red\nend_header\n";
+        let ascii = files.write("cloud.ply", format!("{}0 1 2 255\n\n3 4 5 0\n", header).as_bytes());
+        let cloud = PointCloud::load(&ascii, 0.1, 3).unwrap();
+        assert_eq!(cloud.len(), 2);
+        assert_eq!(cloud.materials(), vec![3]);
+        let points = read_ply(&ascii).unwrap();
+        assert_eq!((points[1].x, points[1].y, points[1].z), (3.0, 4.0, 5.0));
+
+        let mut binary = b"ply\nformat binary_little_endian 1.0\nelement vertex 1\nproperty float x\nproperty float y\nproperty float z\nend_header\n".to_vec();
+        for v in [1.0f32, 2.0, 3.0].iter() {
+            binary.extend_from_slice(&v.to_le_bytes());
+        }
+        let points = read_ply(&files.write("binary.ply", &binary)).unwrap();
+        assert_eq!((points[0].x, points[0].y, points[0].z), (1.0, 2.0, 3.0));
+
+        let xyz = files.write("cloud.xyz", b"# comment\n0 0 0 1 1 1\n\n1 2 3\n");
+        assert_eq!(read_xyz(&xyz).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn malformed_point_clouds_name_the_line() {
+        let files = Fixture::new("malformed_point_clouds");
+        let header = "ply\nformat ascii 1.0\nelement vertex 2\nproperty float x\nproperty float y\nproperty float z\nend_header\n";
+        let message = |path: &str| read_ply(path).err().map(|e| e.to_string()).unwrap_or_default();
+        assert!(message(&files.write("bad.ply", format!("{}0 0 0\n1 x 2\n", header).as_bytes())).contains("line 9"));
+        assert!(message(&files.write("short.ply", format!("{}0 0 0\n1 2\n", header).as_bytes())).contains("line 9"));
+        assert!(read_ply(&files.write("truncated.ply", format!("{}0 0 0\n", header).as_bytes())).is_err());
+        assert!(read_ply(&files.write("huge.ply", b"ply\nformat binary_little_endian 1.0\nelement vertex 18446744073709551615\nproperty double x\nproperty double y\nproperty double z\nend_header\n")).is_err());
+
+        let xyz = read_xyz(&files.write("bad.xyz", b"0 0 0\n1 two 3\n"));
+        assert!(xyz.err().map(|e| e.to_string()).unwrap_or_default().contains("line 2"));
+    }
+}
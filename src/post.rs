@@ -0,0 +1,283 @@
+use crate::math::*;
+
+// --------------------------------------------------
+// Bloom
+// --------------------------------------------------
+// Glow around the bright parts of the image, like the light a real lens (or eye) scatters, with optional
+// starburst streaks. Applied to the linear image before it is converted for display, so that only what is
+// brighter than the display can show spills over.
+#[derive(Clone, Copy, Debug)]
+pub struct Bloom {
+    pub threshold: Float, // Brightness above which pixels glow. 1.0 is the display's white.
+    pub intensity: Float, // Share of the brightness above the threshold spread into the glow.
+    pub radius: Float, // Of the glow, in pixels.
+    pub streaks: u32, // Evenly spaced streaks of glare around bright pixels. None if zero.
+    pub streak_length: Float // In pixels, by which the streaks have faded to about 5%.
+}
+
+impl Default for Bloom {
+    fn default() -> Bloom {
+        Bloom::new()
+    }
+}
+
+impl Bloom {
+    pub fn new() -> Bloom {
+        Bloom {
+            threshold: 1.0,
+            intensity: 0.3,
+            radius: 12.0,
+            streaks: 0,
+            streak_length: 48.0
+        }
+    }
+
+    // Adds the glow to an image of linear colors stored in rows. The streaks carry as much light as the glow.
+    pub fn apply(&self, image: &mut [Vec3], width: u32, height: u32) {
+        // Only the part above the threshold glows, keeping its color.
+        let bright: Vec<Vec3> = image.iter().map(|color| {
+            let peak = color.x.max(color.y).max(color.z);
+            if peak <= self.threshold {Vec3::zero()} else {*color * ((peak - self.threshold) / peak)}
+        }).collect();
+        if bright.iter().all(|color| color.x <= 0.0 && color.y <= 0.0 && color.z <= 0.0) {
+            return
+        }
+
+        let glow = gaussian_blur(&bright, width, height, self.radius);
+        for (pixel, glow) in image.iter_mut().zip(glow.iter()) {
+            *pixel += *glow * self.intensity;
+        }
+        if self.streaks > 0 {
+            let streaks = starburst(&bright, width, height, self.streaks, self.streak_length);
+            for (pixel, streak) in image.iter_mut().zip(streaks.iter()) {
+                *pixel += *streak * self.intensity;
+            }
+        }
+    }
+}
+
+// Separable blur reaching radius pixels (three standard deviations). Light blurred past the edges is lost.
+fn gaussian_blur(image: &[Vec3], width: u32, height: u32, radius: Float) -> Vec<Vec3> {
+    let reach = radius.ceil().max(1.0) as i64;
+    let sigma = (radius / 3.0).max(0.5);
+    let weights: Vec<Float> = (-reach..=reach).map(|i| (-((i * i) as Float) / (2.0 * sigma * sigma)).exp()).collect();
+    let total: Float = weights.iter().sum();
+
+    let pass = |source: &[Vec3], dx: i64, dy: i64| {
+        let mut out = vec![Vec3::zero(); source.len()];
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let mut sum = Vec3::zero();
+                for (k, weight) in weights.iter().enumerate() {
+                    let offset = (k as i64) - reach;
+                    let (sx, sy) = (x + dx * offset, y + dy * offset);
+                    if sx >= 0 && sy >= 0 && sx < width as i64 && sy < height as i64 {
+                        sum += source[(sy * width as i64 + sx) as usize] * *weight;
+                    }
+                }
+                out[(y * width as i64 + x) as usize] = sum / total;
+            }
+        }
+        out
+    };
+    let rows = pass(image, 1, 0);
+    pass(&rows, 0, 1)
+}
+
+// Spreads each bright pixel along streaks fading out over length pixels. Only bright pixels are visited,
+// which are usually few.
+fn starburst(image: &[Vec3], width: u32, height: u32, streaks: u32, length: Float) -> Vec<Vec3> {
+    let steps = length.ceil().max(1.0) as u32;
+    let falloff: Vec<Float> = (1..=steps).map(|step| (-3.0 * (step as Float) / length.max(1.0)).exp()).collect();
+    let total = (streaks as Float) * falloff.iter().sum::<Float>();
+    let directions: Vec<(Float, Float)> = (0..streaks).map(|i| {
+        let angle = (i as Float) * 2.0 * consts::PI / (streaks as Float);
+        (angle.cos(), angle.sin())
+    }).collect();
+
+    let mut out = vec![Vec3::zero(); image.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let color = image[(y * width + x) as usize];
+            if color.x <= 0.0 && color.y <= 0.0 && color.z <= 0.0 {
+                continue;
+            }
+            for (dx, dy) in directions.iter() {
+                for (step, weight) in falloff.iter().enumerate() {
+                    let distance = (step + 1) as Float;
+                    let sx = ((x as Float) + dx * distance).round();
+                    let sy = ((y as Float) + dy * distance).round();
+                    if sx < 0.0 || sy < 0.0 || sx >= width as Float || sy >= height as Float {
+                        break;
+                    }
+                    out[(sy as u32 * width + sx as u32) as usize] += color * (*weight / total);
+                }
+            }
+        }
+    }
+    out
+}
+
+// --------------------------------------------------
+// Lens effects
+// --------------------------------------------------
+// Imperfections of a real camera, for a filmic look: darkened corners, color fringes towards the edges, and
+// film grain that changes from frame to frame. Applied to the display colors, after the tonemapping, so
+// that they look the same however bright the scene is.
+#[derive(Clone, Copy, Debug)]
+pub struct LensEffects {
+    pub vignette: Float, // Share of the brightness lost in the corners, falling off smoothly from the center.
+    pub chromatic_aberration: Float, // In pixels at the corners, by which red is pushed outwards and blue inwards.
+    pub grain: Float // Strength of the noise, in display values.
+}
+
+impl Default for LensEffects {
+    fn default() -> LensEffects {
+        LensEffects::new()
+    }
+}
+
+impl LensEffects {
+    pub fn new() -> LensEffects {
+        LensEffects {
+            vignette: 0.3,
+            chromatic_aberration: 1.5,
+            grain: 0.03
+        }
+    }
+
+    // Applies the effects to an image of display colors stored in rows. The frame seeds the grain.
+    pub fn apply(&self, image: &mut [Vec3], width: u32, height: u32, frame: u32) {
+        let center_x = (width as Float) * 0.5;
+        let center_y = (height as Float) * 0.5;
+        let corner = (center_x * center_x + center_y * center_y).sqrt().max(1.0);
+
+        // Red is sampled nearer the center, so it lands further out, and blue the other way.
+        let source = image.to_vec();
+        for y in 0..height {
+            for x in 0..width {
+                let dx = (x as Float) + 0.5 - center_x;
+                let dy = (y as Float) + 0.5 - center_y;
+                let distance = (dx * dx + dy * dy).sqrt() / corner;
+                let pixel = &mut image[(y * width + x) as usize];
+
+                if self.chromatic_aberration != 0.0 {
+                    let shift = self.chromatic_aberration * distance / (corner * distance).max(1.0);
+                    pixel.x = sample_bilinear(&source, width, height, (x as Float) - dx * shift, (y as Float) - dy * shift).x;
+                    pixel.z = sample_bilinear(&source, width, height, (x as Float) + dx * shift, (y as Float) + dy * shift).z;
+                }
+
+                let falloff = distance * distance;
+                *pixel = *pixel * (1.0 - self.vignette * falloff * falloff);
+
+                if self.grain > 0.0 {
+                    let noise = (hash(x, y, frame) as Float) / (u32::MAX as Float) - 0.5;
+                    *pixel += Vec3::one() * (noise * 2.0 * self.grain);
+                }
+            }
+        }
+    }
+}
+
+// Color at fractional pixel coordinates, clamped to the edges.
+fn sample_bilinear(image: &[Vec3], width: u32, height: u32, x: Float, y: Float) -> Vec3 {
+    let x = x.max(0.0).min((width - 1) as Float);
+    let y = y.max(0.0).min((height - 1) as Float);
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (tx, ty) = (x - x0 as Float, y - y0 as Float);
+    let at = |x: u32, y: u32| image[(y * width + x) as usize];
+    let top = at(x0, y0) * (1.0 - tx) + at(x1, y0) * tx;
+    let bottom = at(x0, y1) * (1.0 - tx) + at(x1, y1) * tx;
+    top * (1.0 - ty) + bottom * ty
+}
+
+// Integer hash of a pixel and frame, so the grain is the same when redrawn but changes between frames.
+fn hash(x: u32, y: u32, frame: u32) -> u32 {
+    let mut h = x.wrapping_mul(0x8da6_b343) ^ y.wrapping_mul(0xd816_3841) ^ frame.wrapping_mul(0xcb1a_b31f);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb_352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846c_a68b);
+    h ^ (h >> 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bloom_spreads_only_light_above_the_threshold() {
+        let (width, height) = (61, 61);
+        let at = |x: u32, y: u32| (y * width + x) as usize;
+        let mut image = vec![Vec3::one() * 0.5; (width * height) as usize];
+        image[at(30, 30)] = Vec3::new(10.0, 5.0, 1.0);
+        let original = image.clone();
+        let mut bloom = Bloom::new();
+        bloom.apply(&mut image, width, height);
+
+        // The glow keeps the color and carries the intensity of what was above the threshold.
+        let added = |image: &[Vec3]| image.iter().zip(original.iter()).map(|(a, b)| (*a - *b).x).sum::<Float>();
+        assert!((added(&image) - 0.3 * 9.0).abs() < 1e-2);
+        let near = image[at(33, 30)] - original[at(33, 30)];
+        assert!(near.x > near.y && near.y > near.z && near.z > 0.0);
+        let mut dim = original.clone();
+        dim[at(30, 30)] = Vec3::one() * 0.9;
+        let unchanged = dim.clone();
+        bloom.apply(&mut dim, width, height);
+        assert!(dim.iter().zip(unchanged.iter()).all(|(a, b)| a.x == b.x));
+
+        // Streaks reach past the glow along their directions only.
+        bloom.streaks = 4;
+        bloom.streak_length = 25.0;
+        let mut streaked = original.clone();
+        bloom.apply(&mut streaked, width, height);
+        assert!(streaked[at(50, 30)].x > original[at(50, 30)].x + 1e-3);
+        assert_eq!(streaked[at(44, 44)].x, original[at(44, 44)].x);
+        assert!((added(&streaked) - 2.0 * 0.3 * 9.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn lens_effects_darken_corners_fringe_edges_and_animate_grain() {
+        let (width, height) = (41, 41);
+        let at = |x: u32, y: u32| (y * width + x) as usize;
+        let gray = vec![Vec3::one() * 0.5; (width * height) as usize];
+        let mut lens = LensEffects::new();
+        lens.chromatic_aberration = 0.0;
+        lens.grain = 0.0;
+
+        // The vignette leaves the center alone and darkens the corners.
+        let mut image = gray.clone();
+        lens.apply(&mut image, width, height, 0);
+        assert!((image[at(20, 20)].x - 0.5).abs() < 1e-3);
+        assert!(image[at(0, 0)].x < 0.5 * 0.75);
+
+        // A white square on the right gets red further out than blue.
+        lens.vignette = 0.0;
+        lens.chromatic_aberration = 3.0;
+        let mut image = vec![Vec3::zero(); (width * height) as usize];
+        for y in 15..26 {
+            for x in 30..36 {
+                image[at(x, y)] = Vec3::one();
+            }
+        }
+        lens.apply(&mut image, width, height, 0);
+        let edge = image[at(36, 20)];
+        assert!(edge.x > 0.0 && edge.z == 0.0);
+        let inner = image[at(29, 20)];
+        assert!(inner.z > 0.0 && inner.x == 0.0);
+
+        // The grain is the same within a frame and changes between them.
+        lens.chromatic_aberration = 0.0;
+        lens.grain = 0.05;
+        let grain = |frame: u32| {
+            let mut image = gray.clone();
+            lens.apply(&mut image, width, height, frame);
+            image
+        };
+        let (first, again, next) = (grain(1), grain(1), grain(2));
+        assert!(first.iter().zip(again.iter()).all(|(a, b)| a.x == b.x));
+        assert!(first.iter().zip(next.iter()).any(|(a, b)| a.x != b.x));
+        assert!(first.iter().all(|color| (color.x - 0.5).abs() <= 0.05 + 1e-6));
+    }
+}
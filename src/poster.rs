@@ -0,0 +1,232 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use log::{info, warn};
+
+use crate::error::{RaytracerError, Result};
+use crate::image::TiledExrWriter;
+use crate::math::Float;
+use crate::raytracer::{RSRaytracer, Tile};
+use crate::scene::Scene;
+
+// --------------------------------------------------
+// Posters
+// --------------------------------------------------
+// Renders images many times the size of the buffer (e.g. 16k posters) by tracing them a buffer-sized tile
+// at a time and writing each tile to a tiled EXR as soon as it is done, so that only one tile is ever held
+// in memory. The tiles line up exactly, as each traces its part of the view as the larger image would.
+// Effects over the whole image (bloom and lens effects) are left out, as are the stamp and color space:
+// the EXR holds the linear colors.
+pub const MAX_TILES: u32 = 32; // Along each side, for up to 40960x23040 pixels at the default resolution.
+
+// The order tiles are rendered in, so that the important part of the frame, usually the middle, is done
+// first when a poster is checked on or stopped partway. The file is the same whichever order is used.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TileOrder {
+    Rows, // Left to right, top to bottom.
+    Spiral, // Around the middle tile, outward in a square spiral.
+    CenterOut, // By distance from the middle of the image, so the done tiles grow as a disk.
+    Hilbert // Along a Hilbert curve, so each tile is next to the one before it.
+}
+
+impl TileOrder {
+    pub fn from_name(name: &str) -> Option<TileOrder> {
+        match name {
+            "rows" => Some(TileOrder::Rows),
+            "spiral" => Some(TileOrder::Spiral),
+            "center" => Some(TileOrder::CenterOut),
+            "hilbert" => Some(TileOrder::Hilbert),
+            _ => None
+        }
+    }
+
+    // Every (column, row) of a tiles by tiles poster, once each, in this order.
+    pub fn tiles(&self, tiles: u32) -> Vec<(u32, u32)> {
+        let count = (tiles * tiles) as usize;
+        let mut order = Vec::with_capacity(count);
+        match self {
+            TileOrder::Rows => {
+                for row in 0..tiles {
+                    for column in 0..tiles {
+                        order.push((column, row));
+                    }
+                }
+            }
+            TileOrder::Spiral => {
+                // Legs of 1, 1, 2, 2, 3, 3... tiles turning right, down, left, and up, skipping those outside.
+                let (mut column, mut row) = (((tiles - 1) / 2) as i64, ((tiles - 1) / 2) as i64);
+                let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+                let mut leg = 0;
+                order.push((column as u32, row as u32));
+                while order.len() < count {
+                    let (step_column, step_row) = directions[leg % 4];
+                    for _ in 0..(leg / 2 + 1) {
+                        column += step_column;
+                        row += step_row;
+                        if column >= 0 && row >= 0 && column < tiles as i64 && row < tiles as i64 {
+                            order.push((column as u32, row as u32));
+                        }
+                    }
+                    leg += 1;
+                }
+            }
+            TileOrder::CenterOut => {
+                order = TileOrder::Rows.tiles(tiles);
+                let middle = tiles as Float / 2.0;
+                let distance = |&(column, row): &(u32, u32)| {
+                    let (x, y) = (column as Float + 0.5 - middle, row as Float + 0.5 - middle);
+                    x * x + y * y
+                };
+                order.sort_by(|a, b| distance(a).total_cmp(&distance(b)));
+            }
+            TileOrder::Hilbert => {
+                // Walks the curve over the next power of two up, skipping tiles past the edges.
+                let side = tiles.next_power_of_two();
+                for index in 0..side * side {
+                    let (column, row) = hilbert_point(side, index);
+                    if column < tiles && row < tiles {
+                        order.push((column, row));
+                    }
+                }
+            }
+        }
+        order
+    }
+}
+
+// The point at index along a Hilbert curve over a side by side grid, where side is a power of two.
+fn hilbert_point(side: u32, index: u32) -> (u32, u32) {
+    let (mut x, mut y, mut t) = (0, 0, index);
+    let mut size = 1;
+    while size < side {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = size - 1 - x;
+                y = size - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += size * rx;
+        y += size * ry;
+        t /= 4;
+        size *= 2;
+    }
+    (x, y)
+}
+
+// Renders the scene at tiles times the buffer's resolution along each side to an EXR file. The settings'
+// tile and region are restored afterwards, and the buffer keeps the last tile.
+pub fn render(ray_tracer: &mut RSRaytracer, scene: &Scene, tiles: u32, order: TileOrder, path: &str) -> Result<()> {
+    if tiles == 0 || tiles > MAX_TILES {
+        return Err(RaytracerError::InvalidArgument(format!("posters need between 1 and {} tiles along each side, not {}", MAX_TILES, tiles)))
+    }
+    let (tile_width, tile_height) = ray_tracer.resolution();
+    let (width, height) = (tile_width * tiles, tile_height * tiles);
+    info!(target: "render", "Rendering a {}x{} poster in {} tiles to {}...", width, height, tiles * tiles, path);
+    let start_time = std::time::Instant::now();
+
+    let mut exr = TiledExrWriter::new(BufWriter::new(File::create(path)?), width, height, tile_width, tile_height, &["R", "G", "B", "A"])?;
+    let (tile, region) = (ray_tracer.get_settings().tile, ray_tracer.get_settings().region);
+    ray_tracer.get_settings_mut().region = None;
+    let result = render_tiles(ray_tracer, scene, tiles, order, &mut exr);
+    ray_tracer.get_settings_mut().tile = tile;
+    ray_tracer.get_settings_mut().region = region;
+    result?;
+    exr.finish()?;
+    info!(target: "render", "Poster finished in {:?}.", start_time.elapsed());
+    Ok(())
+}
+
+fn render_tiles(ray_tracer: &mut RSRaytracer, scene: &Scene, tiles: u32, order: TileOrder, exr: &mut TiledExrWriter<BufWriter<File>>) -> Result<()> {
+    for (index, (column, row)) in order.tiles(tiles).into_iter().enumerate() {
+        ray_tracer.get_settings_mut().tile = Some(Tile::new(column, row, tiles));
+        ray_tracer.run(scene);
+        if ray_tracer.limit_reached() {
+            warn!(target: "render", "Tile {} of {} (column {}, row {}) stopped at its limit, so parts of it are missing.", index + 1, tiles * tiles, column, row);
+        }
+        let [r, g, b, a] = ray_tracer.linear_channels();
+        exr.write_tile(column, row, &[("R", &r), ("G", &g), ("B", &b), ("A", &a)])?;
+        info!(target: "render", "Tile {} of {} (column {}, row {}) done.", index + 1, tiles * tiles, column, row);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::*;
+    use crate::primitives::Plane;
+    use crate::raytracer::{Camera, Lambertian, Region, WIDTH, HEIGHT};
+
+    #[test]
+    fn poster_tiles_trace_their_part_of_the_view() {
+        let mut scene = Scene::new();
+        let mat = scene.add_lambertian_material(Lambertian::new(Vec3::one() * 0.5));
+        scene.add_plane(Plane::new(Vec3::new(0.0, -1.0, 0.0), Vec3::new(1.0, 0.2, 0.0), mat)).unwrap();
+        scene.set_camera(Camera::new(Vec3::new(0.0, 0.0, 3.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 60.0, (WIDTH as Float) / (HEIGHT as Float)));
+        let mut ray_tracer = RSRaytracer::new();
+        ray_tracer.get_settings_mut().samples_per_pixel = 1;
+        ray_tracer.get_settings_mut().max_depth = 1;
+
+        // The depth pass traces pixel centers, which are where they would be in the larger image.
+        let tiles = 3;
+        for (column, row, x, y) in [(0, 0, 0, 0), (2, 1, WIDTH - 1, HEIGHT / 2), (1, 2, 7, HEIGHT - 1)].iter() {
+            ray_tracer.get_settings_mut().tile = Some(Tile::new(*column, *row, tiles));
+            ray_tracer.get_settings_mut().region = Some(Region::new(*x, *y, 1, 1));
+            ray_tracer.run(&scene);
+            let u = ((column * WIDTH + x) as Float + 0.5) / ((WIDTH * tiles - 1) as Float);
+            let v = ((row * HEIGHT + y) as Float + 0.5) / ((HEIGHT * tiles - 1) as Float);
+            let ray = scene.get_camera().get_ray(u, 1.0 - v);
+            let expected = scene.hit(&ray, scene.get_camera().get_clip_range()).0.map(|hit_rec| hit_rec.t * ray.direction.length());
+            let distance = ray_tracer.pixel_info(*x, *y).unwrap().distance;
+            assert_eq!(distance.is_some(), expected.is_some());
+            if let (Some(distance), Some(expected)) = (distance, expected) {
+                assert!((distance - expected).abs() < 1e-3 * expected);
+            }
+        }
+
+        // A single tile is the whole view, and the file holds it with the tile table.
+        let path = std::env::temp_dir().join(format!("rs_raytracer_poster_{}.exr", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        assert!(render(&mut ray_tracer, &scene, 0, TileOrder::Rows, &path).is_err());
+        render(&mut ray_tracer, &scene, 1, TileOrder::Rows, &path).unwrap();
+        assert_eq!(ray_tracer.get_settings().tile, Some(Tile::new(1, 2, tiles)));
+        let size = std::fs::metadata(&path).unwrap().len();
+        std::fs::remove_file(&path).unwrap();
+        let chunk = 20 + (WIDTH * HEIGHT * 4 * 4) as u64;
+        assert!(size > chunk + 8 && size < chunk + 8 + 1024);
+    }
+
+    #[test]
+    fn tile_orders_cover_every_tile_once() {
+        let orders = [TileOrder::Rows, TileOrder::Spiral, TileOrder::CenterOut, TileOrder::Hilbert];
+        for tiles in 1..=7 {
+            for order in orders.iter() {
+                let mut visited = order.tiles(tiles);
+                assert_eq!(visited.len(), (tiles * tiles) as usize, "{:?} with {} tiles", order, tiles);
+                visited.sort();
+                visited.dedup();
+                assert_eq!(visited.len(), (tiles * tiles) as usize, "{:?} with {} tiles", order, tiles);
+            }
+        }
+        assert_eq!(TileOrder::Rows.tiles(2), vec![(0, 0), (1, 0), (0, 1), (1, 1)]);
+
+        // The middle comes first, then its neighbours.
+        assert_eq!(&TileOrder::Spiral.tiles(3)[..3], &[(1, 1), (2, 1), (2, 2)]);
+        let center = TileOrder::CenterOut.tiles(5);
+        assert_eq!(center[0], (2, 2));
+        assert!(center[1..5].iter().all(|&(column, row)| (column as i32 - 2).abs() + (row as i32 - 2).abs() == 1));
+        assert!(center[21..].iter().all(|&(column, row)| (column == 0 || column == 4) && (row == 0 || row == 4)));
+
+        // Each step along the Hilbert curve is to a neighbouring tile.
+        let hilbert = TileOrder::Hilbert.tiles(8);
+        assert!(hilbert.windows(2).all(|pair| {
+            let (a, b) = (pair[0], pair[1]);
+            (a.0 as i32 - b.0 as i32).abs() + (a.1 as i32 - b.1 as i32).abs() == 1
+        }));
+        assert_eq!(TileOrder::from_name("center"), Some(TileOrder::CenterOut));
+        assert_eq!(TileOrder::from_name("zigzag"), None);
+    }
+}
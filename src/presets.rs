@@ -0,0 +1,192 @@
+use crate::error::{RaytracerError, Result};
+use crate::light::Light;
+use crate::math::*;
+use crate::primitives::{Mesh, Plane};
+use crate::raytracer::{Camera, CoatedMaterial, Dielectric, Lambertian, Metal, PbrMaterial, Sphere, ThinFilm, WIDTH, HEIGHT};
+use crate::scene::{Background, Scene};
+
+// --------------------------------------------------
+// Presets
+// --------------------------------------------------
+// Standard test scenes built in code, so that renders and correctness checks (such as the furnace test)
+// have canonical setups that don't depend on scene files. Each adds to the scene and sets its camera and
+// background.
+pub const PRESETS: [&str; 4] = ["cornell-box", "furnace", "three-spheres", "material-showcase"];
+
+// Name of the furnace test's sphere material, to swap in another material (see furnace::run).
+pub const FURNACE_MATERIAL: &str = "furnace";
+
+// Builds a preset by its name in PRESETS.
+pub fn load(scene: &mut Scene, name: &str) -> Result<()> {
+    match name {
+        "cornell-box" => cornell_box(scene),
+        "furnace" => furnace_test(scene, 1.0),
+        "three-spheres" => three_spheres(scene),
+        "material-showcase" => material_showcase(scene),
+        _ => Err(RaytracerError::InvalidArgument(format!("unknown preset '{}', expected one of {}", name, PRESETS.join(", "))))
+    }
+}
+
+fn camera(look_from: Vec3, look_at: Vec3, vertical_fov: Float) -> Camera {
+    Camera::new(look_from, look_at, Vec3::new(0.0, 1.0, 0.0), vertical_fov, (WIDTH as Float) / (HEIGHT as Float))
+}
+
+// Parallelogram from a corner along two edges, as two triangles.
+fn quad(corner: Vec3, edge_u: Vec3, edge_v: Vec3, mat_id: u32) -> Result<Mesh> {
+    let positions = vec![corner, corner + edge_u, corner + edge_u + edge_v, corner + edge_v];
+    Mesh::new(positions, Vec::new(), Vec::new(), vec![[0, 1, 2], [0, 2, 3]], mat_id)
+}
+
+// Box standing on the floor, turned by angle degrees around its vertical axis.
+fn block(center: Vec3, size: Vec3, angle: Float, mat_id: u32) -> Result<Mesh> {
+    let turn = Mat4::rotation(angle.to_radians(), Vec3::new(0.0, 1.0, 0.0));
+    let half = size * 0.5;
+    let positions = (0..8).map(|corner| {
+        let x = if corner & 1 == 0 {-half.x} else {half.x};
+        let y = if corner & 2 == 0 {0.0} else {size.y};
+        let z = if corner & 4 == 0 {-half.z} else {half.z};
+        center + turn.transform_dir(Vec3::new(x, y, z))
+    }).collect();
+    let triangles = vec![
+        [0, 1, 3], [0, 3, 2], [4, 6, 7], [4, 7, 5], // Front and back.
+        [0, 2, 6], [0, 6, 4], [1, 5, 7], [1, 7, 3], // Sides.
+        [0, 4, 5], [0, 5, 1], [2, 3, 7], [2, 7, 6] // Bottom and top.
+    ];
+    Mesh::new(positions, Vec::new(), Vec::new(), triangles, mat_id)
+}
+
+// The Cornell box: a white room two units wide with a red left and a green right wall, open towards the
+// camera, with a tall and a short block. Lit by a point light under the ceiling, as there are no emissive
+// materials, against a black background.
+pub fn cornell_box(scene: &mut Scene) -> Result<()> {
+    let white = scene.add_lambertian_material(Lambertian::new(Vec3::new(0.73, 0.73, 0.73)));
+    let red = scene.add_lambertian_material(Lambertian::new(Vec3::new(0.65, 0.05, 0.05)));
+    let green = scene.add_lambertian_material(Lambertian::new(Vec3::new(0.12, 0.45, 0.15)));
+    scene.set_material_name(white, "white")?;
+    scene.set_material_name(red, "red")?;
+    scene.set_material_name(green, "green")?;
+
+    let (x, y, z) = (Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 2.0, 0.0), Vec3::new(0.0, 0.0, 2.0));
+    let corner = Vec3::new(-1.0, 0.0, -1.0);
+    let walls = [
+        ("floor", quad(corner, x, z, white)?),
+        ("ceiling", quad(corner + y, x, z, white)?),
+        ("back wall", quad(corner, x, y, white)?),
+        ("left wall", quad(corner, y, z, red)?),
+        ("right wall", quad(corner + x, y, z, green)?)
+    ];
+    for (name, wall) in walls {
+        scene.add_mesh(wall)?;
+        name_last(scene, name)?;
+    }
+    scene.add_mesh(block(Vec3::new(-0.33, 0.0, -0.3), Vec3::new(0.6, 1.2, 0.6), 15.0, white)?)?;
+    name_last(scene, "tall block")?;
+    scene.add_mesh(block(Vec3::new(0.35, 0.0, 0.3), Vec3::new(0.6, 0.6, 0.6), -18.0, white)?)?;
+    name_last(scene, "short block")?;
+
+    scene.add_light(Light::point(Vec3::new(0.0, 1.9, 0.0), Vec3::one() * 10.0));
+    scene.set_background(Background::Solid(Vec3::zero()));
+    scene.set_camera(camera(Vec3::new(0.0, 1.0, 3.9), Vec3::new(0.0, 1.0, 0.0), 40.0));
+    Ok(())
+}
+
+// A diffuse sphere inside a uniform white environment. A convex diffuse surface can't see itself, so
+// every bounce off it escapes and the sphere shows exactly its albedo against the white background, and
+// vanishes at an albedo of one. Anything else shows that light is lost or made up along the way.
+pub fn furnace_test(scene: &mut Scene, albedo: Float) -> Result<()> {
+    let mat = scene.add_lambertian_material(Lambertian::new(Vec3::one() * albedo));
+    scene.set_material_name(mat, FURNACE_MATERIAL)?;
+    scene.add_sphere(Sphere::new(Vec3::zero(), 1.0, mat))?;
+    name_last(scene, "sphere")?;
+    scene.set_background(Background::Solid(Vec3::one()));
+    scene.set_camera(camera(Vec3::new(0.0, 0.0, 5.0), Vec3::zero(), 40.0));
+    Ok(())
+}
+
+// Glass, diffuse, and metal spheres on a large sphere, as in Ray Tracing in One Weekend.
+pub fn three_spheres(scene: &mut Scene) -> Result<()> {
+    let ground = scene.add_lambertian_material(Lambertian::new(Vec3::new(0.8, 0.8, 0.0)));
+    let center = scene.add_lambertian_material(Lambertian::new(Vec3::new(0.1, 0.2, 0.5)));
+    let left = scene.add_dielectric_material(Dielectric::new(1.5));
+    let right = scene.add_metal_material(Metal::new(Vec3::new(0.8, 0.6, 0.2), 0.0));
+    let spheres = [
+        ("ground", Vec3::new(0.0, -100.5, -1.0), 100.0, ground),
+        ("center", Vec3::new(0.0, 0.0, -1.0), 0.5, center),
+        ("left", Vec3::new(-1.0, 0.0, -1.0), 0.5, left),
+        ("right", Vec3::new(1.0, 0.0, -1.0), 0.5, right)
+    ];
+    for (name, position, radius, mat) in spheres {
+        scene.add_sphere(Sphere::new(position, radius, mat))?;
+        name_last(scene, name)?;
+    }
+    scene.set_background(Background::sky());
+    scene.set_camera(camera(Vec3::new(-2.0, 2.0, 1.0), Vec3::new(0.0, 0.0, -1.0), 20.0));
+    Ok(())
+}
+
+// A row of spheres, one of each kind of material, on a gray floor under the sky. Each sphere and its
+// material are named after the kind.
+pub fn material_showcase(scene: &mut Scene) -> Result<()> {
+    let floor = scene.add_lambertian_material(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+    scene.add_plane(Plane::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), floor))?;
+    name_last(scene, "floor")?;
+
+    let mut soap_film = Metal::new(Vec3::one() * 0.05, 0.0);
+    soap_film.film = Some(ThinFilm::new(400.0, 1.33));
+    let materials = [
+        ("lambertian", scene.add_lambertian_material(Lambertian::new(Vec3::new(0.7, 0.3, 0.2)))),
+        ("mirror", scene.add_metal_material(Metal::new(Vec3::new(0.9, 0.9, 0.9), 0.0))),
+        ("brushed metal", scene.add_metal_material(Metal::new(Vec3::new(0.8, 0.6, 0.2), 0.3))),
+        ("glass", scene.add_dielectric_material(Dielectric::new(1.5))),
+        ("dispersive glass", scene.add_dielectric_material(Dielectric::cauchy(1.728, 0.0134))),
+        ("pbr gold", scene.add_pbr_material(PbrMaterial::new(Vec3::new(1.0, 0.77, 0.34), 1.0, 0.25))),
+        ("coated paint", scene.add_coated_material(CoatedMaterial::new(Lambertian::new(Vec3::new(0.6, 0.05, 0.05)), 1.5, 0.0))),
+        ("thin film", scene.add_metal_material(soap_film))
+    ];
+    let spacing = 2.2;
+    let left = -spacing * ((materials.len() - 1) as Float) * 0.5;
+    for (idx, (name, mat)) in materials.iter().enumerate() {
+        scene.set_material_name(*mat, name)?;
+        scene.add_sphere(Sphere::new(Vec3::new(left + spacing * (idx as Float), 1.0, 0.0), 1.0, *mat))?;
+        name_last(scene, name)?;
+    }
+    scene.set_background(Background::sky());
+    scene.set_camera(camera(Vec3::new(0.0, 4.0, 16.0), Vec3::new(0.0, 1.0, 0.0), 40.0));
+    Ok(())
+}
+
+fn name_last(scene: &mut Scene, name: &str) -> Result<()> {
+    let object_id = scene.last_object_id().ok_or_else(|| RaytracerError::InvalidArgument("no object to name".to_string()))?;
+    scene.set_object_name(object_id, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raytracer::{Region, RSRaytracer};
+
+    #[test]
+    fn presets_build_and_the_furnace_keeps_energy() {
+        for name in PRESETS.iter() {
+            let mut scene = Scene::new();
+            load(&mut scene, name).unwrap();
+            assert!(scene.object_count() > 0);
+        }
+        assert!(load(&mut Scene::new(), "sponza").is_err());
+        let mut scene = Scene::new();
+        cornell_box(&mut scene).unwrap();
+        assert_eq!(scene.get_object_by_name("short block"), Some(6));
+
+        // The sphere in the middle of the view shows its albedo, and at one it disappears.
+        for albedo in [0.5, 1.0].iter() {
+            let mut scene = Scene::new();
+            furnace_test(&mut scene, *albedo).unwrap();
+            let mut ray_tracer = RSRaytracer::new();
+            ray_tracer.get_settings_mut().samples_per_pixel = 16;
+            ray_tracer.get_settings_mut().region = Some(Region::new(WIDTH / 2, HEIGHT / 2, 1, 1));
+            ray_tracer.run(&scene);
+            let center = ray_tracer.pixel_info(WIDTH / 2, HEIGHT / 2).unwrap().linear;
+            assert!((center.x - albedo).abs() < 1e-4 && (center.z - albedo).abs() < 1e-4);
+        }
+    }
+}
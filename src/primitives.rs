@@ -0,0 +1,1370 @@
+use crate::bvh::Bvh;
+use crate::error::{RaytracerError, Result};
+use crate::image::Image;
+use crate::math::*;
+use crate::raytracer::{HitRecord, Hittable, ObjectMemory, Sphere};
+
+// --------------------------------------------------
+// Helpers
+// --------------------------------------------------
+// Orthonormal frame with its Y axis along a primitive's main axis. Shapes are intersected in this local space.
+struct AxisFrame {
+    origin: Vec3,
+    tangent: Vec3,
+    axis: Vec3,
+    bitangent: Vec3
+}
+
+impl AxisFrame {
+    fn new(origin: Vec3, axis: Vec3) -> AxisFrame {
+        let axis = axis.normalized();
+        let helper = if axis.x.abs() > 0.9 {Vec3::new(0.0, 1.0, 0.0)} else {Vec3::new(1.0, 0.0, 0.0)};
+        let tangent = axis.cross(&helper).normalized();
+        let bitangent = tangent.cross(&axis);
+
+        AxisFrame {
+            origin: origin,
+            tangent: tangent,
+            axis: axis,
+            bitangent: bitangent
+        }
+    }
+
+    fn point_to_local(&self, p: Vec3) -> Vec3 {
+        self.dir_to_local(p - self.origin)
+    }
+
+    fn dir_to_local(&self, d: Vec3) -> Vec3 {
+        Vec3::new(d.dot(&self.tangent), d.dot(&self.axis), d.dot(&self.bitangent))
+    }
+
+    fn dir_to_world(&self, d: Vec3) -> Vec3 {
+        self.tangent * d.x + self.axis * d.y + self.bitangent * d.z
+    }
+}
+
+// Keeps the nearest of several candidate hits within ray_t.
+struct ClosestHit {
+    ray_t: Interval,
+    best: Option<(Float, Vec3, Float, Float)> // (t, outward normal, u, v)
+}
+
+impl ClosestHit {
+    fn new(ray_t: Interval) -> ClosestHit {
+        ClosestHit {
+            ray_t: ray_t,
+            best: None
+        }
+    }
+
+    fn offer(&mut self, t: Float, normal: Vec3, u: Float, v: Float) {
+        if !self.ray_t.contains(t) {
+            return
+        }
+        if let Some((best_t, _, _, _)) = self.best {
+            if best_t <= t {
+                return
+            }
+        }
+        self.best = Some((t, normal, u, v));
+    }
+
+    // Builds the final record. The normal must be in world space.
+    fn into_record(self, ray: &Ray, mat_id: u32) -> Option<HitRecord> {
+        let (t, normal, u, v) = self.best?;
+        let mut hr = HitRecord::new(
+            ray.at(t), Vec3::zero(), t, false, mat_id
+        );
+        hr.set_face_normal(ray, &normal.normalized());
+        hr.u = u;
+        hr.v = v;
+        Some(hr)
+    }
+}
+
+// Roots of a*t^2 + b*t + c, smallest first. Falls back to the linear case when a is ~0.
+fn solve_quadratic(a: Float, b: Float, c: Float) -> Option<(Float, Float)> {
+    if a.abs() < 1e-12 {
+        if b.abs() < 1e-12 {
+            return None
+        }
+        let t = -c / b;
+        return Some((t, t))
+    }
+
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None
+    }
+    let sqrtd = discriminant.sqrt();
+    let t0 = (-b - sqrtd) / (2.0 * a);
+    let t1 = (-b + sqrtd) / (2.0 * a);
+    Some((t0.min(t1), t0.max(t1)))
+}
+
+// Maps the angle of (x, z) around the Y axis to [0, 1].
+fn angle_to_u(x: Float, z: Float) -> Float {
+    let pi = consts::PI;
+    (z.atan2(x) + pi) / (2.0 * pi)
+}
+
+// Möller-Trumbore ray/triangle test returning (t, b1, b2) where b1 and b2 are the barycentric weights of v1 and v2.
+pub fn intersect_triangle(ray: &Ray, v0: Vec3, v1: Vec3, v2: Vec3) -> Option<(Float, Float, Float)> {
+    let edge1 = v1 - v0;
+    let edge2 = v2 - v0;
+    let pvec = ray.direction.cross(&edge2);
+    let det = edge1.dot(&pvec);
+    if det.abs() < 1e-12 {
+        return None
+    }
+    let inv_det = 1.0 / det;
+
+    let tvec = ray.origin - v0;
+    let b1 = tvec.dot(&pvec) * inv_det;
+    if !(0.0..=1.0).contains(&b1) {
+        return None
+    }
+
+    let qvec = tvec.cross(&edge1);
+    let b2 = ray.direction.dot(&qvec) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None
+    }
+
+    Some((edge2.dot(&qvec) * inv_det, b1, b2))
+}
+
+// Distance along the ray to the plane through point with the given normal, if not parallel.
+fn intersect_plane(ray: &Ray, point: Vec3, normal: Vec3) -> Option<Float> {
+    let denom = normal.dot(&ray.direction);
+    if denom.abs() < 1e-8 {
+        return None
+    }
+    Some((point - ray.origin).dot(&normal) / denom)
+}
+
+
+// --------------------------------------------------
+// Plane / Disk
+// --------------------------------------------------
+// Infinite plane. Cheaper and more precise than a giant sphere for ground planes.
+pub struct Plane {
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub mat_id: u32
+}
+
+impl Plane {
+    pub fn new(point: Vec3, normal: Vec3, mat_id: u32) -> Plane {
+        Plane {
+            point: point,
+            normal: normal.normalized(),
+            mat_id: mat_id
+        }
+    }
+}
+
+impl Hittable for Plane {
+    fn materials(&self) -> Vec<u32> {
+        vec![self.mat_id]
+    }
+
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let t = intersect_plane(ray, self.point, self.normal)?;
+        if !ray_t.contains(t) {
+            return None
+        }
+
+        let mut hr = HitRecord::new(
+            ray.at(t), Vec3::zero(), t, false, self.mat_id
+        );
+        hr.set_face_normal(ray, &self.normal);
+
+        // Texture coordinates repeat every unit across the plane.
+        let local = AxisFrame::new(self.point, self.normal).point_to_local(hr.p);
+        hr.u = local.x - local.x.floor();
+        hr.v = local.z - local.z.floor();
+
+        return Some(hr)
+    }
+}
+
+// Flat circle of the given radius facing along normal.
+pub struct Disk {
+    pub center: Vec3,
+    pub normal: Vec3,
+    pub radius: Float,
+    pub mat_id: u32
+}
+
+impl Disk {
+    pub fn new(center: Vec3, normal: Vec3, radius: Float, mat_id: u32) -> Disk {
+        Disk {
+            center: center,
+            normal: normal.normalized(),
+            radius: radius,
+            mat_id: mat_id
+        }
+    }
+}
+
+impl Hittable for Disk {
+    fn materials(&self) -> Vec<u32> {
+        vec![self.mat_id]
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::around_sphere(self.center, self.radius))
+    }
+
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let t = intersect_plane(ray, self.center, self.normal)?;
+        if !ray_t.contains(t) {
+            return None
+        }
+
+        let p = ray.at(t);
+        if (p - self.center).sqr_length() > (self.radius * self.radius) {
+            return None
+        }
+
+        let mut hr = HitRecord::new(
+            p, Vec3::zero(), t, false, self.mat_id
+        );
+        hr.set_face_normal(ray, &self.normal);
+
+        let local = AxisFrame::new(self.center, self.normal).point_to_local(p);
+        hr.u = (local.x / self.radius + 1.0) * 0.5;
+        hr.v = (local.z / self.radius + 1.0) * 0.5;
+
+        return Some(hr)
+    }
+}
+
+
+// --------------------------------------------------
+// Cylinder
+// --------------------------------------------------
+// Capped cylinder standing on base and extending height along axis.
+pub struct Cylinder {
+    frame: AxisFrame,
+    radius: Float,
+    height: Float,
+    pub mat_id: u32
+}
+
+impl Cylinder {
+    pub fn new(base: Vec3, axis: Vec3, radius: Float, height: Float, mat_id: u32) -> Cylinder {
+        Cylinder {
+            frame: AxisFrame::new(base, axis),
+            radius: radius,
+            height: height,
+            mat_id: mat_id
+        }
+    }
+}
+
+impl Hittable for Cylinder {
+    fn materials(&self) -> Vec<u32> {
+        vec![self.mat_id]
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let top = self.frame.origin + self.frame.axis * self.height;
+        Some(Aabb::around_sphere(self.frame.origin, self.radius).union(&Aabb::around_sphere(top, self.radius)))
+    }
+
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let o = self.frame.point_to_local(ray.origin);
+        let d = self.frame.dir_to_local(ray.direction);
+        let r = self.radius;
+        let mut closest = ClosestHit::new(ray_t);
+
+        // Side. Rays parallel to the axis can only hit the caps.
+        let a = d.x * d.x + d.z * d.z;
+        if a > 1e-12 {
+            let b = 2.0 * (o.x * d.x + o.z * d.z);
+            let c = o.x * o.x + o.z * o.z - r * r;
+            if let Some((t0, t1)) = solve_quadratic(a, b, c) {
+                for t in [t0, t1].iter() {
+                    let p = o + d * *t;
+                    if p.y >= 0.0 && p.y <= self.height {
+                        closest.offer(*t, Vec3::new(p.x, 0.0, p.z), angle_to_u(p.x, p.z), p.y / self.height);
+                    }
+                }
+            }
+        }
+
+        // Caps.
+        if d.y.abs() > 1e-12 {
+            for (cap_y, normal_y) in [(0.0, -1.0), (self.height, 1.0)].iter() {
+                let t = (cap_y - o.y) / d.y;
+                let p = o + d * t;
+                if p.x * p.x + p.z * p.z <= r * r {
+                    closest.offer(t, Vec3::new(0.0, *normal_y, 0.0), (p.x / r + 1.0) * 0.5, (p.z / r + 1.0) * 0.5);
+                }
+            }
+        }
+
+        if let Some(best) = closest.best.as_mut() {
+            best.1 = self.frame.dir_to_world(best.1);
+        }
+        closest.into_record(ray, self.mat_id)
+    }
+}
+
+
+// --------------------------------------------------
+// Cone
+// --------------------------------------------------
+// Capped cone with its base circle at base and its apex height along axis.
+pub struct Cone {
+    frame: AxisFrame,
+    radius: Float,
+    height: Float,
+    pub mat_id: u32
+}
+
+impl Cone {
+    pub fn new(base: Vec3, axis: Vec3, radius: Float, height: Float, mat_id: u32) -> Cone {
+        Cone {
+            frame: AxisFrame::new(base, axis),
+            radius: radius,
+            height: height,
+            mat_id: mat_id
+        }
+    }
+}
+
+impl Hittable for Cone {
+    fn materials(&self) -> Vec<u32> {
+        vec![self.mat_id]
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let top = self.frame.origin + self.frame.axis * self.height;
+        Some(Aabb::around_sphere(self.frame.origin, self.radius).union(&Aabb::new(top, top)))
+    }
+
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let o = self.frame.point_to_local(ray.origin);
+        let d = self.frame.dir_to_local(ray.direction);
+        let r = self.radius;
+        let h = self.height;
+        let mut closest = ClosestHit::new(ray_t);
+
+        // Side: x^2 + z^2 = k^2 * (h - y)^2 where k is the slope of the radius.
+        let k2 = (r / h) * (r / h);
+        let a = d.x * d.x + d.z * d.z - k2 * d.y * d.y;
+        let b = 2.0 * (o.x * d.x + o.z * d.z) + 2.0 * k2 * d.y * (h - o.y);
+        let c = o.x * o.x + o.z * o.z - k2 * (h - o.y) * (h - o.y);
+        if let Some((t0, t1)) = solve_quadratic(a, b, c) {
+            for t in [t0, t1].iter() {
+                let p = o + d * *t;
+                if p.y >= 0.0 && p.y <= h {
+                    // Gradient of the implicit surface.
+                    let normal = Vec3::new(p.x, k2 * (h - p.y), p.z);
+                    closest.offer(*t, normal, angle_to_u(p.x, p.z), p.y / h);
+                }
+            }
+        }
+
+        // Base cap.
+        if d.y.abs() > 1e-12 {
+            let t = -o.y / d.y;
+            let p = o + d * t;
+            if p.x * p.x + p.z * p.z <= r * r {
+                closest.offer(t, Vec3::new(0.0, -1.0, 0.0), (p.x / r + 1.0) * 0.5, (p.z / r + 1.0) * 0.5);
+            }
+        }
+
+        if let Some(best) = closest.best.as_mut() {
+            best.1 = self.frame.dir_to_world(best.1);
+        }
+        closest.into_record(ray, self.mat_id)
+    }
+}
+
+
+// --------------------------------------------------
+// Capsule
+// --------------------------------------------------
+// Swept sphere between two end points.
+pub struct Capsule {
+    start: Vec3,
+    end: Vec3,
+    radius: Float,
+    frame: AxisFrame,
+    pub mat_id: u32
+}
+
+impl Capsule {
+    pub fn new(start: Vec3, end: Vec3, radius: Float, mat_id: u32) -> Capsule {
+        // Degenerate capsules are spheres, so any axis will do.
+        let axis = if (end - start).near_zero() {Vec3::new(0.0, 1.0, 0.0)} else {end - start};
+        Capsule {
+            start: start,
+            end: end,
+            radius: radius,
+            frame: AxisFrame::new(start, axis),
+            mat_id: mat_id
+        }
+    }
+}
+
+impl Hittable for Capsule {
+    fn materials(&self) -> Vec<u32> {
+        vec![self.mat_id]
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::around_sphere(self.start, self.radius).union(&Aabb::around_sphere(self.end, self.radius)))
+    }
+
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let ba = self.end - self.start;
+        let oa = ray.origin - self.start;
+        let baba = ba.dot(&ba);
+        let bard = ba.dot(&ray.direction);
+        let baoa = ba.dot(&oa);
+        let r = self.radius;
+        let mut hits: Vec<Float> = Vec::with_capacity(6);
+
+        // Body: infinite cylinder around the segment, limited to between the end points.
+        let a = baba * ray.direction.sqr_length() - bard * bard;
+        let b = 2.0 * (baba * ray.direction.dot(&oa) - baoa * bard);
+        let c = baba * oa.sqr_length() - baoa * baoa - r * r * baba;
+        if a.abs() > 1e-12 {
+            if let Some((t0, t1)) = solve_quadratic(a, b, c) {
+                for t in [t0, t1].iter() {
+                    let y = baoa + t * bard;
+                    if y > 0.0 && y < baba {
+                        hits.push(*t);
+                    }
+                }
+            }
+        }
+
+        // End caps: each sphere only counts on its own side of the segment.
+        for (center, is_start) in [(self.start, true), (self.end, false)].iter() {
+            let oc = ray.origin - *center;
+            let a = ray.direction.sqr_length();
+            let b = 2.0 * oc.dot(&ray.direction);
+            let c = oc.sqr_length() - r * r;
+            if let Some((t0, t1)) = solve_quadratic(a, b, c) {
+                for t in [t0, t1].iter() {
+                    let y = baoa + t * bard;
+                    if (*is_start && y <= 0.0) || (!*is_start && y >= baba) {
+                        hits.push(*t);
+                    }
+                }
+            }
+        }
+
+        let mut closest = ClosestHit::new(ray_t);
+        for t in hits {
+            // Normal points away from the closest point on the segment.
+            let p = ray.at(t);
+            let h = if baba > 0.0 {((p - self.start).dot(&ba) / baba).clamp(0.0, 1.0)} else {0.0};
+            let normal = p - (self.start + ba * h);
+
+            let local = self.frame.point_to_local(p);
+            let length = baba.sqrt();
+            closest.offer(t, normal, angle_to_u(local.x, local.z), (local.y + r) / (length + 2.0 * r));
+        }
+        closest.into_record(ray, self.mat_id)
+    }
+}
+
+
+// --------------------------------------------------
+// Torus
+// --------------------------------------------------
+// Ring around axis through center. The tube of minor_radius sweeps a circle of major_radius.
+pub struct Torus {
+    frame: AxisFrame,
+    major_radius: Float,
+    minor_radius: Float,
+    pub mat_id: u32
+}
+
+impl Torus {
+    pub fn new(center: Vec3, axis: Vec3, major_radius: Float, minor_radius: Float, mat_id: u32) -> Torus {
+        Torus {
+            frame: AxisFrame::new(center, axis),
+            major_radius: major_radius,
+            minor_radius: minor_radius,
+            mat_id: mat_id
+        }
+    }
+}
+
+impl Hittable for Torus {
+    fn materials(&self) -> Vec<u32> {
+        vec![self.mat_id]
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::around_sphere(self.frame.origin, self.major_radius + self.minor_radius))
+    }
+
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let o = self.frame.point_to_local(ray.origin);
+        let d = self.frame.dir_to_local(ray.direction);
+
+        // Substitute the ray into (|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + z^2).
+        let (ox, oy, oz) = (o.x as f64, o.y as f64, o.z as f64);
+        let (dx, dy, dz) = (d.x as f64, d.y as f64, d.z as f64);
+        let major_sq = (self.major_radius as f64) * (self.major_radius as f64);
+        let minor_sq = (self.minor_radius as f64) * (self.minor_radius as f64);
+
+        let dd = dx * dx + dy * dy + dz * dz;
+        let od = ox * dx + oy * dy + oz * dz;
+        let k = ox * ox + oy * oy + oz * oz + major_sq - minor_sq;
+
+        let roots = solve_quartic(
+            dd * dd,
+            4.0 * dd * od,
+            4.0 * od * od + 2.0 * dd * k - 4.0 * major_sq * (dx * dx + dz * dz),
+            4.0 * od * k - 8.0 * major_sq * (ox * dx + oz * dz),
+            k * k - 4.0 * major_sq * (ox * ox + oz * oz)
+        );
+
+        let t = roots.into_iter().map(|t| t as Float).find(|t| ray_t.contains(*t))?;
+
+        // Normal points away from the nearest point on the ring through the middle of the tube.
+        let p = o + d * t;
+        let radial = Vec3::new(p.x, 0.0, p.z);
+        let ring = if radial.near_zero() {Vec3::zero()} else {radial.normalized() * self.major_radius};
+        let normal = p - ring;
+
+        let mut hr = HitRecord::new(
+            ray.at(t), Vec3::zero(), t, false, self.mat_id
+        );
+        hr.set_face_normal(ray, &self.frame.dir_to_world(normal).normalized());
+
+        // Angle around the axis, then angle around the tube.
+        let pi = consts::PI;
+        hr.u = angle_to_u(p.x, p.z);
+        hr.v = (p.y.atan2(radial.length() - self.major_radius) + pi) / (2.0 * pi);
+
+        return Some(hr)
+    }
+}
+
+// --------------------------------------------------
+// Primitive
+// --------------------------------------------------
+// The simple shapes, stored by value so that scenes keep them in one contiguous Vec and hits dispatch with a match
+// instead of a virtual call through a pointer per object. Aggregates and user types stay behind dyn Hittable.
+pub enum Primitive {
+    Sphere(Sphere),
+    Plane(Plane),
+    Disk(Disk),
+    Cylinder(Cylinder),
+    Cone(Cone),
+    Capsule(Capsule),
+    Torus(Torus)
+}
+
+impl Primitive {
+    pub fn mat_id(&self) -> u32 {
+        match self {
+            Primitive::Sphere(sphere) => sphere.mat_id,
+            Primitive::Plane(plane) => plane.mat_id,
+            Primitive::Disk(disk) => disk.mat_id,
+            Primitive::Cylinder(cylinder) => cylinder.mat_id,
+            Primitive::Cone(cone) => cone.mat_id,
+            Primitive::Capsule(capsule) => capsule.mat_id,
+            Primitive::Torus(torus) => torus.mat_id
+        }
+    }
+}
+
+impl Hittable for Primitive {
+    fn materials(&self) -> Vec<u32> {
+        vec![self.mat_id()]
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        match self {
+            Primitive::Sphere(sphere) => sphere.bounding_box(),
+            Primitive::Plane(plane) => plane.bounding_box(),
+            Primitive::Disk(disk) => disk.bounding_box(),
+            Primitive::Cylinder(cylinder) => cylinder.bounding_box(),
+            Primitive::Cone(cone) => cone.bounding_box(),
+            Primitive::Capsule(capsule) => capsule.bounding_box(),
+            Primitive::Torus(torus) => torus.bounding_box()
+        }
+    }
+
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        match self {
+            Primitive::Sphere(sphere) => sphere.hit(ray, ray_t),
+            Primitive::Plane(plane) => plane.hit(ray, ray_t),
+            Primitive::Disk(disk) => disk.hit(ray, ray_t),
+            Primitive::Cylinder(cylinder) => cylinder.hit(ray, ray_t),
+            Primitive::Cone(cone) => cone.hit(ray, ray_t),
+            Primitive::Capsule(capsule) => capsule.hit(ray, ray_t),
+            Primitive::Torus(torus) => torus.hit(ray, ray_t)
+        }
+    }
+}
+
+// Spheres with each field in its own array (structure of arrays). Spheres are tested with only the
+// centers and radii in cache, which suits scenes made of many small spheres.
+pub struct SphereSoa {
+    center_x: Vec<Float>,
+    center_y: Vec<Float>,
+    center_z: Vec<Float>,
+    radius: Vec<Float>,
+    mat_id: Vec<u32>
+}
+
+impl Default for SphereSoa {
+    fn default() -> SphereSoa {
+        SphereSoa::new()
+    }
+}
+
+impl SphereSoa {
+    pub fn new() -> SphereSoa {
+        SphereSoa {
+            center_x: Vec::new(),
+            center_y: Vec::new(),
+            center_z: Vec::new(),
+            radius: Vec::new(),
+            mat_id: Vec::new()
+        }
+    }
+
+    // Approximate bytes used by the spheres.
+    pub fn memory_usage(&self) -> usize {
+        self.len() * (4 * std::mem::size_of::<Float>() + std::mem::size_of::<u32>())
+    }
+
+    pub fn len(&self) -> usize {
+        self.radius.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.radius.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        *self = SphereSoa::new();
+    }
+
+    pub fn push(&mut self, sphere: Sphere) {
+        self.center_x.push(sphere.center.x);
+        self.center_y.push(sphere.center.y);
+        self.center_z.push(sphere.center.z);
+        self.radius.push(sphere.radius);
+        self.mat_id.push(sphere.mat_id);
+    }
+
+    pub fn get(&self, idx: usize) -> Sphere {
+        Sphere::new(Vec3::new(self.center_x[idx], self.center_y[idx], self.center_z[idx]), self.radius[idx], self.mat_id[idx])
+    }
+
+    pub fn bounding_box(&self, idx: usize) -> Aabb {
+        Aabb::around_sphere(Vec3::new(self.center_x[idx], self.center_y[idx], self.center_z[idx]), self.radius[idx])
+    }
+
+    // Rejects misses from the arrays alone. Only hits build a Sphere to fill in the record.
+    pub fn hit(&self, idx: usize, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let oc = ray.origin - Vec3::new(self.center_x[idx], self.center_y[idx], self.center_z[idx]);
+        let radius = self.radius[idx];
+        let half_b = oc.dot(&ray.direction);
+        let discriminant = half_b * half_b - ray.direction.sqr_length() * (oc.sqr_length() - radius * radius);
+        if discriminant < 0.0 {
+            return None
+        }
+        self.get(idx).hit(ray, ray_t)
+    }
+}
+
+// --------------------------------------------------
+// Heightfield
+// --------------------------------------------------
+// Terrain of two triangles per grid cell, spanning size_x by size_z on the XZ plane from origin with heights up the Y axis.
+pub struct Heightfield {
+    origin: Vec3,
+    columns: u32, // Samples along X.
+    rows: u32, // Samples along Z.
+    cell_x: Float,
+    cell_z: Float,
+    heights: Vec<Float>,
+    normals: Vec<Vec3>, // Per sample, for smooth shading.
+    min_height: Float,
+    max_height: Float,
+    pub mat_id: u32
+}
+
+impl Heightfield {
+    // Heights are given per sample in rows along Z. At least 2x2 samples are required.
+    pub fn new(origin: Vec3, size_x: Float, size_z: Float, columns: u32, rows: u32, heights: Vec<Float>, mat_id: u32) -> Result<Heightfield> {
+        if columns < 2 || rows < 2 {
+            return Err(RaytracerError::InvalidArgument("a heightfield needs at least 2x2 samples".to_string()))
+        }
+        if (columns as usize).checked_mul(rows as usize) != Some(heights.len()) {
+            return Err(RaytracerError::InvalidArgument("heightfield sample count does not match its dimensions".to_string()))
+        }
+
+        let cell_x = size_x / ((columns - 1) as Float);
+        let cell_z = size_z / ((rows - 1) as Float);
+
+        // Normals from central differences, clamped at the edges.
+        let at = |x: i64, z: i64| -> Float {
+            let x = x.clamp(0, columns as i64 - 1) as usize;
+            let z = z.clamp(0, rows as i64 - 1) as usize;
+            heights[z * (columns as usize) + x]
+        };
+        let mut normals = Vec::with_capacity(heights.len());
+        for z in 0..(rows as i64) {
+            for x in 0..(columns as i64) {
+                let dx = (at(x + 1, z) - at(x - 1, z)) / (2.0 * cell_x);
+                let dz = (at(x, z + 1) - at(x, z - 1)) / (2.0 * cell_z);
+                normals.push(Vec3::new(-dx, 1.0, -dz).normalized());
+            }
+        }
+
+        let min_height = heights.iter().cloned().fold(Float::MAX, Float::min);
+        let max_height = heights.iter().cloned().fold(Float::MIN, Float::max);
+
+        Ok(Heightfield {
+            origin: origin,
+            columns: columns,
+            rows: rows,
+            cell_x: cell_x,
+            cell_z: cell_z,
+            heights: heights,
+            normals: normals,
+            min_height: min_height,
+            max_height: max_height,
+            mat_id: mat_id
+        })
+    }
+
+    // Builds a heightfield from the luminance of a PGM/PPM image, with white reaching height_scale.
+    pub fn from_image(path: &str, origin: Vec3, size_x: Float, size_z: Float, height_scale: Float, mat_id: u32) -> Result<Heightfield> {
+        let image = Image::read_ppm(path)?;
+        if image.width < 2 || image.height < 2 {
+            return Err(RaytracerError::InvalidImage {
+                path: path.to_string(),
+                reason: "a heightfield needs at least 2x2 pixels".to_string()
+            })
+        }
+
+        let mut heights = Vec::with_capacity((image.width as usize) * (image.height as usize));
+        for y in 0..image.height {
+            for x in 0..image.width {
+                heights.push((image.luminance(x, y) as Float) * height_scale);
+            }
+        }
+        Heightfield::new(origin, size_x, size_z, image.width, image.height, heights, mat_id)
+    }
+
+    // Of the sample at a column and row, in heights and normals.
+    fn index(&self, x: u32, z: u32) -> usize {
+        (z as usize) * (self.columns as usize) + (x as usize)
+    }
+
+    fn vertex(&self, x: u32, z: u32) -> Vec3 {
+        Vec3::new(
+            (x as Float) * self.cell_x,
+            self.heights[self.index(x, z)],
+            (z as Float) * self.cell_z
+        )
+    }
+
+    // Nearest hit against the two triangles of a cell as (t, local normal, u, v).
+    fn hit_cell(&self, ray: &Ray, x: u32, z: u32, ray_t: Interval) -> Option<(Float, Vec3, Float, Float)> {
+        let corners = [(x, z), (x + 1, z), (x + 1, z + 1), (x, z + 1)];
+        let mut best: Option<(Float, Vec3, Float, Float)> = None;
+        for tri in [[0, 1, 2], [0, 2, 3]].iter() {
+            let (a, b, c) = (corners[tri[0]], corners[tri[1]], corners[tri[2]]);
+            if let Some((t, b1, b2)) = intersect_triangle(ray, self.vertex(a.0, a.1), self.vertex(b.0, b.1), self.vertex(c.0, c.1)) {
+                if !ray_t.contains(t) || best.is_some_and(|best| best.0 <= t) {
+                    continue;
+                }
+                let normal_at = |corner: (u32, u32)| self.normals[self.index(corner.0, corner.1)];
+                let normal = normal_at(a) * (1.0 - b1 - b2) + normal_at(b) * b1 + normal_at(c) * b2;
+                let p = ray.at(t);
+                let u = p.x / (self.cell_x * ((self.columns - 1) as Float));
+                let v = p.z / (self.cell_z * ((self.rows - 1) as Float));
+                best = Some((t, normal, u, v));
+            }
+        }
+        best
+    }
+}
+
+impl Hittable for Heightfield {
+    fn materials(&self) -> Vec<u32> {
+        vec![self.mat_id]
+    }
+
+    fn memory(&self) -> ObjectMemory {
+        ObjectMemory {
+            elements: self.heights.len(),
+            geometry: std::mem::size_of::<Heightfield>() + self.heights.len() * std::mem::size_of::<Float>() + self.normals.len() * std::mem::size_of::<Vec3>(),
+            bvh_nodes: 0,
+            bvh: 0
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let size = Vec3::new(
+            self.cell_x * ((self.columns - 1) as Float),
+            self.max_height,
+            self.cell_z * ((self.rows - 1) as Float)
+        );
+        Some(Aabb::new(self.origin + Vec3::new(0.0, self.min_height, 0.0), self.origin + size))
+    }
+
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        // Traverse in local space where the grid starts at the origin.
+        let local_ray = Ray::new(ray.origin - self.origin, ray.direction);
+        let o = local_ray.origin;
+        let d = local_ray.direction;
+        let size_x = self.cell_x * ((self.columns - 1) as Float);
+        let size_z = self.cell_z * ((self.rows - 1) as Float);
+
+        // Clip against the bounding box.
+        let mut t_enter = ray_t.min;
+        let mut t_exit = ray_t.max;
+        let slabs = [(o.x, d.x, 0.0, size_x), (o.y, d.y, self.min_height, self.max_height), (o.z, d.z, 0.0, size_z)];
+        for (origin, dir, lo, hi) in slabs.iter() {
+            if dir.abs() < 1e-12 {
+                if origin < lo || origin > hi {
+                    return None
+                }
+                continue;
+            }
+            let t0 = (lo - origin) / dir;
+            let t1 = (hi - origin) / dir;
+            t_enter = t_enter.max(t0.min(t1));
+            t_exit = t_exit.min(t0.max(t1));
+        }
+        if t_enter > t_exit {
+            return None
+        }
+
+        // 2D DDA over the cells the ray passes through on the XZ plane.
+        let start = local_ray.at(t_enter);
+        let mut cell_x = ((start.x / self.cell_x).floor() as i64).clamp(0, self.columns as i64 - 2);
+        let mut cell_z = ((start.z / self.cell_z).floor() as i64).clamp(0, self.rows as i64 - 2);
+        let step_x: i64 = if d.x > 0.0 {1} else {-1};
+        let step_z: i64 = if d.z > 0.0 {1} else {-1};
+        let next_boundary = |cell: i64, step: i64, size: Float| ((cell + if step > 0 {1} else {0}) as Float) * size;
+        let mut t_next_x = if d.x.abs() > 1e-12 {(next_boundary(cell_x, step_x, self.cell_x) - o.x) / d.x} else {Float::MAX};
+        let mut t_next_z = if d.z.abs() > 1e-12 {(next_boundary(cell_z, step_z, self.cell_z) - o.z) / d.z} else {Float::MAX};
+        let t_delta_x = if d.x.abs() > 1e-12 {self.cell_x / d.x.abs()} else {Float::MAX};
+        let t_delta_z = if d.z.abs() > 1e-12 {self.cell_z / d.z.abs()} else {Float::MAX};
+
+        loop {
+            if let Some((t, normal, u, v)) = self.hit_cell(&local_ray, cell_x as u32, cell_z as u32, ray_t) {
+                let mut hr = HitRecord::new(
+                    ray.at(t), Vec3::zero(), t, false, self.mat_id
+                );
+                hr.set_face_normal(ray, &normal.normalized());
+                hr.u = u;
+                hr.v = v;
+                return Some(hr)
+            }
+
+            // Step into whichever neighbouring cell the ray reaches first.
+            if t_next_x < t_next_z {
+                if t_next_x > t_exit {
+                    return None
+                }
+                cell_x += step_x;
+                t_next_x += t_delta_x;
+            } else {
+                if t_next_z > t_exit {
+                    return None
+                }
+                cell_z += step_z;
+                t_next_z += t_delta_z;
+            }
+            if cell_x < 0 || cell_z < 0 || cell_x > self.columns as i64 - 2 || cell_z > self.rows as i64 - 2 {
+                return None
+            }
+        }
+    }
+}
+
+
+// --------------------------------------------------
+// Strands
+// --------------------------------------------------
+// Thick polyline for hair or grass. Each segment is rendered as a capsule.
+pub struct Strand {
+    points: Vec<Vec3>,
+    radii: Vec<Float> // Per point. Segments use the average of their end points.
+}
+
+impl Strand {
+    pub fn new(points: Vec<Vec3>, radius: Float) -> Strand {
+        let radii = vec![radius; points.len()];
+        Strand {
+            points: points,
+            radii: radii
+        }
+    }
+
+    // Samples a cubic Bezier into segments, tapering the radius from root to tip.
+    pub fn from_bezier(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3, root_radius: Float, tip_radius: Float, segments: u32) -> Strand {
+        let segments = segments.max(1);
+        let mut points = Vec::with_capacity((segments + 1) as usize);
+        let mut radii = Vec::with_capacity((segments + 1) as usize);
+        for i in 0..=segments {
+            let t = (i as Float) / (segments as Float);
+            let s = 1.0 - t;
+            points.push(s * s * s * p0 + 3.0 * s * s * t * p1 + 3.0 * s * t * t * p2 + t * t * t * p3);
+            radii.push(root_radius + (tip_radius - root_radius) * t);
+        }
+        Strand {
+            points: points,
+            radii: radii
+        }
+    }
+}
+
+// Many strands sharing one material and one BVH over all of their segments.
+pub struct StrandSet {
+    segments: Vec<Capsule>,
+    bvh: Bvh,
+    pub mat_id: u32
+}
+
+impl StrandSet {
+    pub fn new(strands: &[Strand], mat_id: u32) -> StrandSet {
+        let mut segments = Vec::new();
+        for strand in strands {
+            for i in 1..strand.points.len() {
+                let radius = 0.5 * (strand.radii[i - 1] + strand.radii[i]);
+                segments.push(Capsule::new(strand.points[i - 1], strand.points[i], radius, mat_id));
+            }
+        }
+
+        let bounds: Vec<Aabb> = segments.iter().map(|segment| segment.bounding_box().unwrap()).collect();
+        StrandSet {
+            segments: segments,
+            bvh: Bvh::build(&bounds),
+            mat_id: mat_id
+        }
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+}
+
+impl Hittable for StrandSet {
+    fn materials(&self) -> Vec<u32> {
+        vec![self.mat_id]
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bvh.bounds()
+    }
+
+    fn memory(&self) -> ObjectMemory {
+        ObjectMemory::with_bvh(self.segments.len(), self.segments.len() * std::mem::size_of::<Capsule>(), &self.bvh)
+    }
+
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        self.bvh.hit(ray, ray_t, |idx, ray, ray_t| {
+            self.segments[idx as usize].hit(ray, ray_t)
+        }).0
+    }
+}
+
+
+// --------------------------------------------------
+// Mesh
+// --------------------------------------------------
+// Indexed triangle mesh. Normals and UVs are optional and, when present, given per vertex.
+pub struct Mesh {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(Float, Float)>,
+    triangles: Vec<[u32; 3]>,
+    bvh: Bvh,
+    pub mat_id: u32
+}
+
+impl Mesh {
+    pub fn new(positions: Vec<Vec3>, normals: Vec<Vec3>, uvs: Vec<(Float, Float)>, triangles: Vec<[u32; 3]>, mat_id: u32) -> Result<Mesh> {
+        if !normals.is_empty() && normals.len() != positions.len() {
+            return Err(RaytracerError::InvalidArgument("mesh normal count does not match its vertex count".to_string()))
+        }
+        if !uvs.is_empty() && uvs.len() != positions.len() {
+            return Err(RaytracerError::InvalidArgument("mesh UV count does not match its vertex count".to_string()))
+        }
+        if triangles.iter().flatten().any(|idx| *idx as usize >= positions.len()) {
+            return Err(RaytracerError::InvalidArgument("mesh triangle refers to a missing vertex".to_string()))
+        }
+
+        let bounds: Vec<Aabb> = triangles.iter().map(|tri| {
+            Aabb::empty().grow(positions[tri[0] as usize]).grow(positions[tri[1] as usize]).grow(positions[tri[2] as usize])
+        }).collect();
+        Ok(Mesh {
+            positions: positions,
+            normals: normals,
+            uvs: uvs,
+            triangles: triangles,
+            bvh: Bvh::build(&bounds),
+            mat_id: mat_id
+        })
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    // Vertex and index data plus the BVH, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.positions.len() * std::mem::size_of::<Vec3>()
+            + self.normals.len() * std::mem::size_of::<Vec3>()
+            + self.uvs.len() * std::mem::size_of::<(Float, Float)>()
+            + self.triangles.len() * std::mem::size_of::<[u32; 3]>()
+            + self.bvh.memory_usage()
+    }
+
+    fn hit_triangle(&self, idx: u32, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let tri = self.triangles[idx as usize];
+        let (v0, v1, v2) = (self.positions[tri[0] as usize], self.positions[tri[1] as usize], self.positions[tri[2] as usize]);
+        let (t, b1, b2) = intersect_triangle(ray, v0, v1, v2)?;
+        if !ray_t.contains(t) {
+            return None
+        }
+        let b0 = 1.0 - b1 - b2;
+
+        let mut hr = HitRecord::new(
+            ray.at(t), Vec3::zero(), t, false, self.mat_id
+        );
+        // The winding decides which side is the front; smooth normals only bend the shading.
+        let geometric = (v1 - v0).cross(&(v2 - v0));
+        hr.set_face_normal(ray, &geometric.normalized());
+        if !self.normals.is_empty() {
+            let smooth = self.normals[tri[0] as usize] * b0 + self.normals[tri[1] as usize] * b1 + self.normals[tri[2] as usize] * b2;
+            if !smooth.near_zero() {
+                let smooth = smooth.normalized();
+                hr.n = if hr.front_face {smooth} else {-smooth};
+            }
+        }
+        if !self.uvs.is_empty() {
+            let (uv0, uv1, uv2) = (self.uvs[tri[0] as usize], self.uvs[tri[1] as usize], self.uvs[tri[2] as usize]);
+            hr.u = uv0.0 * b0 + uv1.0 * b1 + uv2.0 * b2;
+            hr.v = uv0.1 * b0 + uv1.1 * b1 + uv2.1 * b2;
+            // Square root of the ratio of the triangle's area in texture space to its area in world space.
+            let uv_area = ((uv1.0 - uv0.0) * (uv2.1 - uv0.1) - (uv2.0 - uv0.0) * (uv1.1 - uv0.1)).abs();
+            let world_area = geometric.length();
+            if world_area > 0.0 {
+                hr.uv_scale = (uv_area / world_area).sqrt();
+            }
+        }
+        Some(hr)
+    }
+}
+
+impl Hittable for Mesh {
+    fn materials(&self) -> Vec<u32> {
+        vec![self.mat_id]
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bvh.bounds()
+    }
+
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        self.bvh.hit(ray, ray_t, |idx, ray, ray_t| {
+            self.hit_triangle(idx, ray, ray_t)
+        }).0
+    }
+
+    fn memory(&self) -> ObjectMemory {
+        ObjectMemory::with_bvh(self.triangle_count(), self.memory_usage() - self.bvh.memory_usage(), &self.bvh)
+    }
+}
+
+
+// --------------------------------------------------
+// CSG
+// --------------------------------------------------
+#[derive(Clone, Copy, PartialEq)]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    Difference // Left minus right.
+}
+
+impl CsgOp {
+    fn is_inside(&self, in_left: bool, in_right: bool) -> bool {
+        match self {
+            CsgOp::Union => in_left || in_right,
+            CsgOp::Intersection => in_left && in_right,
+            CsgOp::Difference => in_left && !in_right
+        }
+    }
+}
+
+// Boolean combination of two closed objects. Each hit keeps the material of the operand it came from.
+pub struct Csg {
+    left: Box<dyn Hittable>,
+    right: Box<dyn Hittable>,
+    op: CsgOp
+}
+
+const CSG_MAX_CROSSINGS: usize = 32;
+const CSG_STEP: Float = 1e-4;
+
+impl Csg {
+    pub fn new(left: Box<dyn Hittable>, right: Box<dyn Hittable>, op: CsgOp) -> Csg {
+        Csg {
+            left: left,
+            right: right,
+            op: op
+        }
+    }
+
+    // Every surface crossing of obj along the ray from t_start, in order.
+    fn crossings(obj: &dyn Hittable, ray: &Ray, t_start: Float) -> Vec<HitRecord> {
+        let mut hits = Vec::new();
+        let mut t_start = t_start;
+        while hits.len() < CSG_MAX_CROSSINGS {
+            match obj.hit(ray, Interval::new(t_start, Float::MAX)) {
+                Some(hr) => {
+                    t_start = hr.t + CSG_STEP;
+                    hits.push(hr);
+                }
+                None => break
+            }
+        }
+        hits
+    }
+}
+
+impl Hittable for Csg {
+    fn materials(&self) -> Vec<u32> {
+        let mut materials = self.left.materials();
+        materials.extend(self.right.materials());
+        materials
+    }
+
+    fn memory(&self) -> ObjectMemory {
+        self.left.memory().add(&self.right.memory())
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // Intersections and differences never extend past the left operand.
+        match self.op {
+            CsgOp::Union => Some(self.left.bounding_box()?.union(&self.right.bounding_box()?)),
+            CsgOp::Intersection | CsgOp::Difference => self.left.bounding_box()
+        }
+    }
+
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        let left_hits = Csg::crossings(self.left.as_ref(), ray, ray_t.min);
+        let right_hits = Csg::crossings(self.right.as_ref(), ray, ray_t.min);
+
+        // If the first crossing leaves an object, the ray started inside of it.
+        let mut in_left = left_hits.first().is_some_and(|hr| !hr.front_face);
+        let mut in_right = right_hits.first().is_some_and(|hr| !hr.front_face);
+        let inside = self.op.is_inside(in_left, in_right);
+
+        // Walk both lists in order of distance until the combined inside state changes.
+        let (mut li, mut ri) = (0, 0);
+        while li < left_hits.len() || ri < right_hits.len() {
+            let take_left = ri >= right_hits.len() || (li < left_hits.len() && left_hits[li].t <= right_hits[ri].t);
+            let hr = if take_left {
+                in_left = left_hits[li].front_face;
+                li += 1;
+                &left_hits[li - 1]
+            } else {
+                in_right = right_hits[ri].front_face;
+                ri += 1;
+                &right_hits[ri - 1]
+            };
+            if hr.t > ray_t.max {
+                return None
+            }
+
+            // The first change of the combined state is the visible surface.
+            if self.op.is_inside(in_left, in_right) == inside {
+                continue;
+            }
+
+            // Surfaces carved out by the right operand face the other way.
+            let mut outward = if hr.front_face {hr.n} else {-hr.n};
+            if !take_left && self.op == CsgOp::Difference {
+                outward = -outward;
+            }
+            let mut result = hr.clone();
+            result.set_face_normal(ray, &outward);
+            return Some(result)
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_torus() -> Torus {
+        Torus::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 2.0, 0.5, 0)
+    }
+
+    #[test]
+    fn torus_hit_through_tube() {
+        let ray = Ray::new(Vec3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let hr = unit_torus().hit(&ray, Interval::new(0.001, Float::MAX)).expect("ray through the tube should hit");
+        assert!((hr.t - 2.5).abs() < 1e-4);
+        assert!((hr.n.x + 1.0).abs() < 1e-4);
+        assert!(hr.front_face);
+    }
+
+    #[test]
+    fn torus_miss_through_hole() {
+        let ray = Ray::new(Vec3::new(0.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(unit_torus().hit(&ray, Interval::new(0.001, Float::MAX)).is_none());
+    }
+
+    #[test]
+    fn torus_grazing_top() {
+        // Touches the top of the tube at x = -2 and x = 2.
+        let ray = Ray::new(Vec3::new(-5.0, 0.5, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let hr = unit_torus().hit(&ray, Interval::new(0.001, Float::MAX)).expect("grazing ray should hit");
+        assert!((hr.t - 3.0).abs() < 1e-2);
+        assert!(hr.n.y.abs() > 0.99);
+    }
+
+    #[test]
+    fn torus_grazing_miss() {
+        let ray = Ray::new(Vec3::new(-5.0, 0.501, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(unit_torus().hit(&ray, Interval::new(0.001, Float::MAX)).is_none());
+    }
+
+    #[test]
+    fn torus_hit_from_inside_tube() {
+        let ray = Ray::new(Vec3::new(2.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let hr = unit_torus().hit(&ray, Interval::new(0.001, Float::MAX)).expect("ray inside the tube should exit");
+        assert!((hr.t - 0.5).abs() < 1e-4);
+        assert!(!hr.front_face);
+    }
+
+    #[test]
+    fn csg_difference_hole() {
+        // Sphere with a cylindrical hole drilled along Y.
+        let sphere = Box::new(crate::raytracer::Sphere::new(Vec3::zero(), 1.0, 1));
+        let drill = Box::new(Cylinder::new(Vec3::new(0.0, -2.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.25, 4.0, 2));
+        let csg = Csg::new(sphere, drill, CsgOp::Difference);
+
+        // Down the hole misses entirely.
+        let ray = Ray::new(Vec3::new(0.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(csg.hit(&ray, Interval::new(0.001, Float::MAX)).is_none());
+
+        // Across the hole hits the sphere first, then the inside of the drill.
+        let ray = Ray::new(Vec3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let hr = csg.hit(&ray, Interval::new(0.001, Float::MAX)).unwrap();
+        assert!((hr.t - 4.0).abs() < 1e-4);
+        assert_eq!(hr.mat_id, 1);
+        let hr = csg.hit(&ray, Interval::new(hr.t + 0.001, Float::MAX)).unwrap();
+        assert!((hr.t - 4.75).abs() < 1e-4);
+        assert_eq!(hr.mat_id, 2);
+        assert!(!hr.front_face); // Leaving the solid into the hole.
+
+        // Scenes check the materials of every operand, however deeply nested.
+        let mut scene = crate::scene::Scene::new();
+        scene.add_lambertian_material(crate::raytracer::Lambertian::new(Vec3::one()));
+        let nested = Csg::new(Box::new(csg), Box::new(crate::raytracer::Sphere::new(Vec3::zero(), 0.5, 0)), CsgOp::Union);
+        assert!(matches!(scene.add_csg(nested), Err(RaytracerError::InvalidMaterial(2))));
+        scene.add_lambertian_material(crate::raytracer::Lambertian::new(Vec3::one()));
+        let left = Box::new(crate::raytracer::Sphere::new(Vec3::zero(), 1.0, 1));
+        let right = Box::new(Cylinder::new(Vec3::new(0.0, -2.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.25, 4.0, 2));
+        assert!(scene.add_csg(Csg::new(left, right, CsgOp::Difference)).is_ok());
+        assert_eq!(scene.object_count(), 1);
+    }
+
+    #[test]
+    fn plane_hits_from_both_sides() {
+        let plane = Plane::new(Vec3::zero(), Vec3::new(0.0, 2.0, 0.0), 0);
+        let down = Ray::new(Vec3::new(0.3, 2.0, 0.7), Vec3::new(0.0, -1.0, 0.0));
+        let hr = plane.hit(&down, Interval::new(0.001, Float::MAX)).expect("ray from above should hit");
+        assert!((hr.t - 2.0).abs() < 1e-5 && hr.front_face && (hr.n.y - 1.0).abs() < 1e-5);
+
+        // From below the normal is flipped to face the ray.
+        let up = Ray::new(Vec3::new(0.3, -2.0, 0.7), Vec3::new(0.0, 1.0, 0.0));
+        let hr = plane.hit(&up, Interval::new(0.001, Float::MAX)).expect("ray from below should hit");
+        assert!(!hr.front_face && (hr.n.y + 1.0).abs() < 1e-5);
+
+        // Parallel rays, rays pointing away, and hits beyond t_max miss.
+        let along = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(plane.hit(&along, Interval::new(0.001, Float::MAX)).is_none());
+        let away = Ray::new(Vec3::new(0.3, 2.0, 0.7), Vec3::new(0.0, 1.0, 0.0));
+        assert!(plane.hit(&away, Interval::new(0.001, Float::MAX)).is_none());
+        assert!(plane.hit(&down, Interval::new(0.001, 1.999)).is_none());
+    }
+
+    #[test]
+    fn disk_edge() {
+        let disk = Disk::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 1.0, 0);
+        let ray = |x: Float| Ray::new(Vec3::new(x, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(disk.hit(&ray(0.999), Interval::new(0.001, Float::MAX)).is_some());
+        assert!(disk.hit(&ray(1.001), Interval::new(0.001, Float::MAX)).is_none());
+    }
+
+    #[test]
+    fn csg_intersection_lens() {
+        let left = Box::new(crate::raytracer::Sphere::new(Vec3::new(-0.5, 0.0, 0.0), 1.0, 0));
+        let right = Box::new(crate::raytracer::Sphere::new(Vec3::new(0.5, 0.0, 0.0), 1.0, 0));
+        let csg = Csg::new(left, right, CsgOp::Intersection);
+
+        let ray = Ray::new(Vec3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let hr = csg.hit(&ray, Interval::new(0.001, Float::MAX)).unwrap();
+        assert!((hr.t - 4.5).abs() < 1e-4);
+        assert!(hr.front_face);
+    }
+
+    #[test]
+    fn heightfield_checks_its_samples() {
+        let flat = Heightfield::new(Vec3::zero(), 2.0, 2.0, 2, 2, vec![0.5; 4], 0).unwrap();
+        let ray = Ray::new(Vec3::new(1.0, 2.0, 1.0), Vec3::new(0.0, -1.0, 0.0));
+        let hr = flat.hit(&ray, Interval::new(0.001, Float::MAX)).unwrap();
+        assert!((hr.t - 1.5).abs() < 1e-4);
+
+        assert!(matches!(Heightfield::new(Vec3::zero(), 1.0, 1.0, 1, 4, vec![0.0; 4], 0), Err(RaytracerError::InvalidArgument(_))));
+        assert!(matches!(Heightfield::new(Vec3::zero(), 1.0, 1.0, 2, 2, vec![0.0; 3], 0), Err(RaytracerError::InvalidArgument(_))));
+        // Would wrap around to 0 samples if multiplied in u32.
+        assert!(Heightfield::new(Vec3::zero(), 1.0, 1.0, 65536, 65536, Vec::new(), 0).is_err());
+    }
+
+    #[test]
+    fn strands_are_hit_along_their_segments() {
+        // A straight hair up Y and a curl tapering to half its radius.
+        let hair = Strand::new(vec![Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 2.0, 0.0)], 0.1);
+        let curl = Strand::from_bezier(Vec3::new(5.0, 0.0, 0.0), Vec3::new(6.0, 1.0, 0.0), Vec3::new(6.0, 2.0, 0.0), Vec3::new(5.0, 3.0, 0.0), 0.2, 0.1, 8);
+        let set = StrandSet::new(&[hair, curl], 0);
+        assert_eq!(set.segment_count(), 2 + 8);
+
+        let across = |y: Float| Ray::new(Vec3::new(0.0, y, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        let hr = set.hit(&across(1.5), Interval::new(0.001, Float::MAX)).unwrap();
+        assert!((hr.t - 4.9).abs() < 1e-4);
+        assert!(set.hit(&across(2.5), Interval::new(0.001, Float::MAX)).is_none());
+        // The first segment of the curl averages the radius at its two ends.
+        let root = Ray::new(Vec3::new(5.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        let radius = 0.5 * (0.2 + (0.2 - 0.1 / 8.0));
+        assert!((set.hit(&root, Interval::new(0.001, Float::MAX)).unwrap().t - (5.0 - radius)).abs() < 1e-4);
+
+        let mut scene = crate::scene::Scene::new();
+        scene.add_strands(set).unwrap();
+        assert_eq!(scene.object_count(), 1);
+        let stray = StrandSet::new(&[Strand::new(vec![Vec3::zero(), Vec3::new(0.0, 1.0, 0.0)], 0.1)], 4);
+        assert!(matches!(scene.add_strands(stray), Err(RaytracerError::InvalidMaterial(4))));
+    }
+}
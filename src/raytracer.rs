@@ -1,494 +1,3273 @@
-use std::io;
+use std::any::Any;
 use std::io::prelude::*;
 use std::io::BufWriter;
 use std::fs::File;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
+use log::{debug, info, trace, warn};
+
+use crate::accumulation::{AccumulationBuffer, Precision};
+use crate::bvh::Bvh;
+use crate::color::{self, ColorSpace, WhiteBalance};
+use crate::error::{RaytracerError, Result};
+use crate::exposure::{Aperture, PhysicalExposure};
+use crate::image::{self, Image, Texture};
 use crate::math::*;
+use crate::metrics;
+use crate::metrics::ImageMetrics;
+use crate::post::{Bloom, LensEffects};
+use crate::saver::{SaveFormat, SaveJob};
+use crate::scene::{BackFace, Scene, SceneStats};
+use crate::stamp;
 
 // --------------------------------------------------
 // RSRaytracer
 // --------------------------------------------------
+// Default resolution of the buffer, and the largest the viewer's window shows it at.
 pub const WIDTH: u32 = 1280;
 pub const HEIGHT: u32 = 720;
-pub const CHANNELS: u32 = 3;
+pub const MAX_RESOLUTION: u32 = 16384; // Along either side of the buffer.
+pub const CHANNELS: u32 = 4; // RGBA, where alpha is the share of camera rays that hit something.
 
 const SAMPLES_PER_PIXEL: u32 = 20; // 100
 const MAX_DEPTH: u32 = 20; // 50
+const HISTOGRAM_BINS: u32 = 112; // Of the exposure overlay, 8 per stop.
+const AUTOFOCUS_SPREAD: Float = 0.02; // Share of the image between the middle and the other rays autofocus casts.
+const LOW_PRIORITY_NICENESS: i32 = 10; // Added to the niceness of the thread that traces low priority renders.
 
 
-pub const PPM_OUT: &str = "./out.ppm";
-
-pub struct RSRaytracer {
-    pixels: Box<[f32]>,
-    objects: Vec<Box<dyn Hittable>>,
-    materials: Vec<Box<dyn Material>>,
-    cam: Camera,
-    rand_gen: RandGen // Shared random number generator.
-}
-
-impl RSRaytracer {
-    pub fn new() -> RSRaytracer {
-        let mut pixels = vec![1.0; (WIDTH * HEIGHT * CHANNELS) as usize];
-
-        // Start with a simple gradient.
-        for y in 0..HEIGHT {
-            for x in 0..WIDTH {
-                let pitch = WIDTH * CHANNELS;
-                let offset = (y * pitch + x * CHANNELS) as usize;
-
-                // Must be multiplied here as there's a conversion using this value when outputting the underlying data.
-                let scale: f32 = SAMPLES_PER_PIXEL as f32;
-
-                pixels[offset + 0] = ((x as f32) / (WIDTH as f32)) * scale;
-                pixels[offset + 1] = ((y as f32) / (HEIGHT as f32)) * scale;
-                pixels[offset + 2] = 0.0;
-            }
-        }
+// Output files, in the ray tracer's output directory.
+pub const PPM_OUT: &str = "out.ppm";
+pub const PNG_OUT: &str = "out.png";
+pub const DEPTH_PNG_OUT: &str = "depth.png";
+pub const DEPTH_EXR_OUT: &str = "depth.exr";
+pub const OBJECT_IDS_OUT: &str = "object_ids.exr";
+pub const MATERIAL_IDS_OUT: &str = "material_ids.exr";
+pub const LADDER_OUT: &str = "ladder"; // Quality ladder stages are saved as ladder_<samples>spp.png.
+pub const POSTER_OUT: &str = "poster.exr";
+pub const BRACKET_OUT: &str = "bracket"; // Exposure brackets are saved as bracket_<stops>ev.png, e.g. bracket_-2ev.png.
+pub const SNAPSHOT_OUT: &str = "snapshot"; // Progressive render snapshots are saved as snapshot_0001.png, snapshot_0002.png, ...
 
-        // Add a single default material so that default 0 indexes don't fail.
-        let mut mats: Vec<Box<dyn Material>> = Vec::new();
-        mats.push(Box::new(Lambertian::new(Vec3::one())));
+// Ids kept per pixel by the ID passes, the most covering first.
+pub const ID_RANKS: usize = 4;
 
-        // Default camera settings.
-        let look_from = Vec3::new(13.0, 2.0, 3.0); //Vec3::new(-2.0, 2.0, 1.0);
-        let look_at = Vec3::new(0.0, 0.0, 0.0); //Vec3::new(0.0, 0.0, -1.0);
-        let up = Vec3::new(0.0, 1.0, 0.0);
-        let aspect_ratio = (WIDTH as f32) / (HEIGHT as f32);
-        let vertical_fov = 20.0;
+// A sub-rectangle of the image in pixels, with the origin in the top left.
+#[derive(Clone, Copy)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32
+}
 
-        RSRaytracer {
-            pixels: pixels.into_boxed_slice(),
-            objects: Vec::<Box<dyn Hittable>>::new(),
-            materials: mats,
-            cam: Camera::new(look_from, look_at, up, vertical_fov, aspect_ratio),
-            rand_gen: RandGen::new()
+impl Region {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Region {
+        Region {
+            x: x,
+            y: y,
+            width: width,
+            height: height
         }
     }
 
-    pub fn add_lambertian_material(&mut self, mat: Lambertian) -> u32 {
-        let boxed_mat = Box::new(mat);
-        self.materials.push(boxed_mat);
-        return (self.materials.len() - 1) as u32
+    // Builds a region from two arbitrary corners (e.g. the start and end of a mouse drag).
+    pub fn from_corners(x0: i32, y0: i32, x1: i32, y1: i32) -> Region {
+        let min_x = x0.min(x1).max(0) as u32;
+        let min_y = y0.min(y1).max(0) as u32;
+        let max_x = x0.max(x1).max(0) as u32;
+        let max_y = y0.max(y1).max(0) as u32;
+        Region::new(min_x, min_y, max_x - min_x, max_y - min_y)
     }
 
-    pub fn add_metal_material(&mut self, mat: Metal) -> u32 {
-        let boxed_mat = Box::new(mat);
-        self.materials.push(boxed_mat);
-        return (self.materials.len() - 1) as u32
+    // The whole of an image of the given size.
+    pub fn full(width: u32, height: u32) -> Region {
+        Region::new(0, 0, width, height)
     }
 
-    pub fn add_dielectric_material(&mut self, mat: Dielectric) -> u32 {
-        let boxed_mat = Box::new(mat);
-        self.materials.push(boxed_mat);
-        return (self.materials.len() - 1) as u32
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
     }
 
-    pub fn get_material(&self, idx: u32) -> &Box<dyn Material> {
-        &self.materials[idx as usize]
+    // Returns this region limited to the bounds of an image of the given size.
+    pub fn clamped(&self, image_width: u32, image_height: u32) -> Region {
+        let x = self.x.min(image_width);
+        let y = self.y.min(image_height);
+        let width = self.width.min(image_width - x);
+        let height = self.height.min(image_height - y);
+        Region::new(x, y, width, height)
     }
+}
 
-    pub fn get_rng(&mut self) -> &mut RandGen {
-        &mut self.rand_gen
-    }
+// One part of an image tiles times as wide and high as the buffer, e.g. for posters (see poster.rs). Rendering
+// a tile traces just its part of the camera's view into the buffer, as it would be in the larger image.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Tile {
+    pub column: u32,
+    pub row: u32, // From the top.
+    pub tiles: u32 // Along each side.
+}
 
-    pub fn add_sphere(&mut self, sphere: Sphere) {
-        let boxed_obj = Box::new(sphere);
-        self.objects.push(boxed_obj)
+impl Tile {
+    pub fn new(column: u32, row: u32, tiles: u32) -> Tile {
+        Tile {
+            column: column,
+            row: row,
+            tiles: tiles
+        }
     }
+}
 
-    fn hit_objects(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let mut best_rec: HitRecord = HitRecord::empty();
-        let mut hit_anything = false;
-        let mut closest_so_far = t_max;
-
-        for obj in self.objects.as_slice() {
-            let tmp_rec = obj.hit(ray, t_min, closest_so_far);
-            if !tmp_rec.is_none() {
-                let tmp_rec = tmp_rec.unwrap();
-                hit_anything = true;
-                closest_so_far = tmp_rec.t;
-                best_rec = tmp_rec;
-            }
-        }
+// Reconstruction filter that weights each sample's contribution to the pixels around it, by the
+// distance in pixels from the sample to the pixel centers. Wider filters give smoother edges at the cost of some sharpness.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PixelFilter {
+    Box, // Each sample only counts towards its own pixel, equally.
+    Tent(Float), // Linear falloff to zero at the given radius.
+    Gaussian(Float), // Gaussian of the given standard deviation, cut off at three of them.
+    Mitchell(Float, Float) // Mitchell-Netravali cubic with parameters (B, C) and a radius of 2. Sharper, but can ring.
+}
 
-        return if hit_anything {Some(best_rec)} else {None}
+impl PixelFilter {
+    // Mitchell and Netravali's recommended parameters.
+    pub fn mitchell() -> PixelFilter {
+        PixelFilter::Mitchell(1.0 / 3.0, 1.0 / 3.0)
     }
 
-    pub fn copy_to(&self, texture: &mut sdl2::render::Texture) {
-        // Safety check before copying.
-        let query = texture.query();
-        if (query.width != WIDTH) || (query.height != HEIGHT) {
-            println!("Texture dimensions do not match internal dimensions. Ignoring copy request.");
-            return
+    // Parses a filter by name with its default parameters, e.g. from the command line.
+    pub fn from_name(name: &str) -> Option<PixelFilter> {
+        match name {
+            "box" => Some(PixelFilter::Box),
+            "tent" => Some(PixelFilter::Tent(1.0)),
+            "gaussian" => Some(PixelFilter::Gaussian(0.5)),
+            "mitchell" => Some(PixelFilter::mitchell()),
+            _ => None
         }
+    }
 
-        // Manual copy per pixel.
-        texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
-            for y in 0..HEIGHT {
-                for x in 0..WIDTH {
-                    let offset = (y * (pitch as u32) + x * CHANNELS) as usize;
-
-                    let pixel_color = Vec3::new(
-                        self.pixels[offset + 0],
-                        self.pixels[offset + 1],
-                        self.pixels[offset + 2]
-                    );
-                    let (r_value, g_value, b_value) = self.get_final_rgb(&pixel_color);
+    // Distance in pixels beyond which the weight is zero.
+    pub fn radius(&self) -> Float {
+        match *self {
+            PixelFilter::Box => 0.5,
+            PixelFilter::Tent(radius) => radius,
+            PixelFilter::Gaussian(sigma) => 3.0 * sigma,
+            PixelFilter::Mitchell(_, _) => 2.0
+        }
+    }
 
-                    buffer[offset + 0] = r_value;
-                    buffer[offset + 1] = g_value;
-                    buffer[offset + 2] = b_value;
+    // Weight along one axis. Filters are separable, so the weight of an offset (dx, dy) is weight(dx) * weight(dy).
+    pub fn weight(&self, d: Float) -> Float {
+        let x = d.abs();
+        if x >= self.radius() {
+            return 0.0
+        }
+        match *self {
+            PixelFilter::Box => 1.0,
+            PixelFilter::Tent(radius) => 1.0 - x / radius,
+            PixelFilter::Gaussian(sigma) => {
+                // Shifted down so that the weight reaches zero at the radius instead of jumping there.
+                let gaussian = |x: Float| (-x * x / (2.0 * sigma * sigma)).exp();
+                gaussian(x) - gaussian(3.0 * sigma)
+            }
+            PixelFilter::Mitchell(b, c) => {
+                let (x2, x3) = (x * x, x * x * x);
+                if x < 1.0 {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + (6.0 - 2.0 * b)) / 6.0
+                } else {
+                    ((-b - 6.0 * c) * x3 + (6.0 * b + 30.0 * c) * x2 + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
                 }
             }
-        }).unwrap();
-
-        // Direct memory copy from internal pixels array. Requires pixels to be [u8] format.
-        // texture.update(
-        //     sdl2::rect::Rect::new(0, 0, IMAGE_WIDTH, IMAGE_HEIGHT),
-        //     &self.pixels,
-        //     (IMAGE_WIDTH * CHANNELS) as usize
-        // ).unwrap();
+        }
     }
+}
 
-    pub fn run(&mut self) {
-        println!("Starting ray tracer...");
-        let start_time = std::time::Instant::now();
-
-        let pitch = WIDTH * CHANNELS;
-        for y in 0..HEIGHT {
-            print!("Rendering line {}/{}...", y+1, HEIGHT);
-            for x in 0..WIDTH {
-                let offset = (y * pitch + x * CHANNELS) as usize;
+// How the depth pass stores the distance to the surface seen through each pixel, between the near and far clips.
+// Pixels that see nothing are at the far clip.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DepthEncoding {
+    Linear, // Distance in scene units, clamped to the clips. PNG can only hold [0, 1], so it is normalized there.
+    Normalized // 0 at the near clip to 1 at the far clip.
+}
 
-                let mut pixel_color = Vec3::zero();
-                for _i in 0..SAMPLES_PER_PIXEL {
-                    let r0: f32 = self.rand_gen.next01();
-                    let u = ((x as f32) + r0) / ((WIDTH-1) as f32);
+impl DepthEncoding {
+    pub fn from_name(name: &str) -> Option<DepthEncoding> {
+        match name {
+            "linear" => Some(DepthEncoding::Linear),
+            "normalized" => Some(DepthEncoding::Normalized),
+            _ => None
+        }
+    }
+}
 
-                    let r1: f32 = self.rand_gen.next01();
-                    let v = ((y as f32) + r1) / ((HEIGHT-1) as f32);
+// What the ID passes tell apart.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IdPass {
+    Object,
+    Material
+}
 
-                    let r = self.cam.get_ray(u, 1.0 - v);
-                    pixel_color += self.ray_color(&r, MAX_DEPTH);
-                }
+// Ids seen through a pixel, with the share of its camera samples that saw them, the most covering first.
+// Unused ranks have no coverage. The coverage adds up to less than one where the background shows.
+#[derive(Clone, Copy)]
+pub struct IdCoverage {
+    pub ids: [u32; ID_RANKS],
+    pub coverage: [Float; ID_RANKS]
+}
 
-                self.pixels[offset + 0] = pixel_color.x;
-                self.pixels[offset + 1] = pixel_color.y;
-                self.pixels[offset + 2] = pixel_color.z;
-            }
-            println!("done!");
+impl IdCoverage {
+    pub fn none() -> IdCoverage {
+        IdCoverage {
+            ids: [0; ID_RANKS],
+            coverage: [0.0; ID_RANKS]
         }
-
-        let end_time = std::time::Instant::now();
-        let delta_time = end_time.duration_since(start_time);
-        println!("Ray trace complete in {:?}.", delta_time);
     }
 
-    fn ray_color(&mut self, ray: &Ray, depth: u32) -> Vec3 {
-        // Exceeded bounce limit, so no more light is gathered.
-        if depth <= 0 {
-            return Vec3::zero();
+    // Keeps the most seen of the ids counted over a number of samples.
+    fn from_counts(counts: &mut [(u32, Float)], samples: Float) -> IdCoverage {
+        counts.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)));
+        let mut ranked = IdCoverage::none();
+        for (rank, (id, count)) in counts.iter().take(ID_RANKS).enumerate() {
+            ranked.ids[rank] = *id;
+            ranked.coverage[rank] = *count / samples;
         }
+        ranked
+    }
 
-        let hit_rec = self.hit_objects(ray, 0.001, f32::MAX);
-        if !hit_rec.is_none() {
-            let mut scattered: Ray = Ray::new(Vec3::zero(), Vec3::zero());
-            let mut attenuation: Vec3 = Vec3::zero();
-            let hit_rec = hit_rec.unwrap();
-            let mut rgen = &mut self.rand_gen;
-            let mat = &mut self.materials[hit_rec.mat_id as usize];
-            if mat.scatter(ray, &hit_rec, &mut attenuation, &mut scattered, &mut rgen) {
-                return attenuation * self.ray_color(&scattered, depth - 1)
+    // Adds the ids back to counts as seen over a number of samples, so that passes can be merged.
+    fn add_to_counts(&self, counts: &mut Vec<(u32, Float)>, samples: Float) {
+        for rank in (0..ID_RANKS).filter(|rank| self.coverage[*rank] > 0.0) {
+            let count = self.coverage[rank] * samples;
+            match counts.iter_mut().find(|(counted, _)| *counted == self.ids[rank]) {
+                Some((_, counted)) => *counted += count,
+                None => counts.push((self.ids[rank], count))
             }
-
-            return Vec3::zero()
         }
+    }
 
-        let direction = ray.direction.normalized();
-        let t = 0.5 * (direction.y + 1.0);
-        return (1.0-t) * Vec3::new(1.0, 1.0, 1.0) + t * Vec3::new(0.5, 0.7, 1.0)
+    // The id covering most of the pixel, if any.
+    pub fn first(&self) -> Option<u32> {
+        if self.coverage[0] > 0.0 {Some(self.ids[0])} else {None}
     }
 
-    fn get_final_rgb(&self, pixel_color: &Vec3) -> (u8, u8, u8) {
-        let mut out_color = pixel_color.clone();
+    // Share of the pixel covered by an id. Zero for ids beyond the kept ranks.
+    pub fn of(&self, id: u32) -> Float {
+        (0..ID_RANKS).filter(|rank| self.ids[*rank] == id).map(|rank| self.coverage[rank]).sum()
+    }
+}
 
-        // Divide the color by the number of samples and gamma correct for gamma=2.0.
-        let scale = 1.0 / (SAMPLES_PER_PIXEL as f32);
-        out_color.x = (out_color.x * scale).sqrt();
-        out_color.y = (out_color.y * scale).sqrt();
-        out_color.z = (out_color.z * scale).sqrt();
+#[derive(Clone)]
+pub struct RenderSettings {
+    pub region: Option<Region>, // Only this part of the image is traced if set. The rest of the buffer is left untouched.
+    pub samples_per_pixel: u32,
+    pub max_depth: u32, // Maximum number of bounces per path.
+    pub max_diffuse_depth: Option<u32>, // Maximum number of diffuse bounces per path, within max_depth.
+    pub max_specular_depth: Option<u32>, // Maximum number of specular bounces per path, within max_depth.
+    pub filter: PixelFilter,
+    pub transparent_background: bool, // Camera rays that hit nothing add no color, so the image can be composited over others.
+    pub depth_clips: Option<(Float, Float)>, // Near and far distances of the depth pass. Fit to the nearest and farthest surfaces if unset.
+    pub depth_encoding: DepthEncoding,
+    pub id_coverage: bool, // The ID passes count what every camera sample sees, not just the pixel center, at the cost of an extra ray each.
+    pub bloom: Option<Bloom>, // Glow around bright parts, added when the image is shown or saved.
+    pub lens: Option<LensEffects>, // Camera imperfections, added to the displayed image when it is shown or saved.
+    pub color_space: ColorSpace, // Of the shown and saved images.
+    pub white_balance: WhiteBalance, // Applied to the linear colors when the image is shown or saved.
+    pub auto_white_balance: bool, // Also makes the average of the background gray, on top of the white balance.
+    pub exposure: Float, // In stops, applied to the linear colors when the image is shown or saved. Each doubles them.
+    pub physical_exposure: Option<PhysicalExposure>, // Exposes as a camera's settings would on top of the stops, and blurs by the f-stop's lens.
+    pub aperture: Aperture, // Shape of the lens's opening, and so of out of focus highlights, with the physical exposure.
+    pub mode: RenderMode, // How the light reaching the camera is worked out.
+    pub low_priority: bool, // Lowers the thread's priority where it can and pauses after each row for as long as it took, at half the speed.
+    pub autofocus: bool, // Focuses on the nearest surface in the middle of the image before each render or pass.
+    pub accumulation: Precision, // Of the accumulation buffer. The buffer is converted at the start of the next render.
+    pub max_time: Option<Duration>, // Wall-clock time after which a render, including all of its progressive passes, stops.
+    pub max_total_samples: Option<u64>, // Camera samples over the whole image after which a render, including all of its passes, stops.
+    pub stamp: bool, // Burns the scene name, samples per pixel, render time, and resolution into a corner of saved images.
+    pub tile: Option<Tile> // Renders this part of a larger image into the buffer if set, rather than the whole view.
+}
 
-        // Translate RGB to [0, 255] and return.
-        (
-            (256.0 * out_color.x.clamp(0.0, 0.999)) as u8,
-            (256.0 * out_color.y.clamp(0.0, 0.999)) as u8,
-            (256.0 * out_color.z.clamp(0.0, 0.999)) as u8
-        )
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings::new()
     }
+}
 
-    pub fn save_as_ppm(&self) -> io::Result<()> {
-        print!("Writing PPM file...");
-        let f = File::create(PPM_OUT)?;
-        {
-            let mut writer = BufWriter::new(f);
+impl RenderSettings {
+    pub fn new() -> RenderSettings {
+        RenderSettings {
+            region: None,
+            samples_per_pixel: SAMPLES_PER_PIXEL,
+            max_depth: MAX_DEPTH,
+            max_diffuse_depth: None,
+            max_specular_depth: None,
+            filter: PixelFilter::Box,
+            transparent_background: false,
+            depth_clips: None,
+            depth_encoding: DepthEncoding::Normalized,
+            id_coverage: false,
+            bloom: None,
+            lens: None,
+            color_space: ColorSpace::Srgb,
+            white_balance: WhiteBalance::new(),
+            auto_white_balance: false,
+            exposure: 0.0,
+            physical_exposure: None,
+            aperture: Aperture::Circle,
+            mode: RenderMode::PathTrace,
+            low_priority: false,
+            autofocus: false,
+            accumulation: Precision::Full,
+            max_time: None,
+            max_total_samples: None,
+            stamp: false,
+            tile: None
+        }
+    }
 
-            // P3
-            // WIDTH HEIGHT
-            // MAX_VALUE
-            write!(writer, "P3\n{} {}\n255\n", WIDTH, HEIGHT)?;
+    // Fast and noisy, for quick previews.
+    pub fn draft() -> RenderSettings {
+        RenderSettings {
+            region: None,
+            samples_per_pixel: 4,
+            max_depth: 4,
+            max_diffuse_depth: None,
+            max_specular_depth: None,
+            filter: PixelFilter::Box,
+            transparent_background: false,
+            depth_clips: None,
+            depth_encoding: DepthEncoding::Normalized,
+            id_coverage: false,
+            bloom: None,
+            lens: None,
+            color_space: ColorSpace::Srgb,
+            white_balance: WhiteBalance::new(),
+            auto_white_balance: false,
+            exposure: 0.0,
+            physical_exposure: None,
+            aperture: Aperture::Circle,
+            mode: RenderMode::PathTrace,
+            low_priority: false,
+            autofocus: false,
+            accumulation: Precision::Full,
+            max_time: None,
+            max_total_samples: None,
+            stamp: false,
+            tile: None
+        }
+    }
+}
 
-            // Pixels (in rows, left to right, top to bottom).
-            let pitch = WIDTH * CHANNELS;
-            for y in 0..HEIGHT {
-                for x in 0..WIDTH {
-                    let offset = (y * pitch + x * CHANNELS) as usize;
+// How camera samples are shaded.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RenderMode {
+    PathTrace, // Light bouncing around the scene, up to the depth limits.
+    Raycast // Only what camera rays hit, lit straight by the scene's lights with hard shadows. See raycast_color.
+}
 
-                    let pixel_color = Vec3::new(
-                        self.pixels[offset + 0],
-                        self.pixels[offset + 1],
-                        self.pixels[offset + 2]
-                    );
-                    let (r_value, g_value, b_value) = self.get_final_rgb(&pixel_color);
+// An image to measure the ray tracer output against.
+pub enum Reference<'a> {
+    File(&'a str), // PPM file of the same dimensions.
+    Buffer(&'a [Float]) // Linear (already averaged) RGB values of the same dimensions.
+}
 
-                    write!(writer, "{} {} {}\n", r_value, g_value, b_value)?;
-                }
-            }
+// What copy_to shows.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DisplayMode {
+    Color, // The rendered image.
+    CostHeatmap, // Object tests per pixel of the last render as false color.
+    BvhVisits, // Scene BVH nodes visited per pixel of the last render as false color.
+    BvhLeafDepth, // Depth of the scene BVH leaf holding the object seen in each pixel as false color. Black if none.
+    FalseColor // Luminance bands in calibrated colors, as on a camera's false color monitor. See false_color.
+}
 
-        } // Buffer is flushed when it goes out of scope.
-        println!("Done!");
+// Debug lines that copy_to draws over the image, to see what the scene is made of.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DebugOverlay {
+    None,
+    Edges, // Outlines and creases of the objects seen by the camera.
+    BvhBoxes(u32) // Bounds of the scene BVH's nodes down to the given depth, colored by depth.
+}
 
-        Ok(())
-    }
+// Luminance of the render region's pixels, in stops (powers of two) relative to the display's white, after the
+// white balance and before the post effects.
+pub struct Histogram {
+    pub bins: Vec<u32>, // Pixels per equal range of stops from MIN_STOPS to MAX_STOPS. The end bins also count what is beyond.
+    pub under: u32, // Pixels shown as black.
+    pub over: u32 // Pixels with a channel clipped at the display's white.
 }
 
+impl Histogram {
+    pub const MIN_STOPS: Float = -10.0;
+    pub const MAX_STOPS: Float = 4.0;
 
-// --------------------------------------------------
-// Camera
-// --------------------------------------------------
-pub struct Camera {
-    origin: Vec3,
-    lower_left_corner: Vec3,
-    horizontal: Vec3,
-    vertical: Vec3
+    // Bin of a luminance.
+    pub fn bin_of(&self, luminance: Float) -> usize {
+        let stops = luminance.max(1e-12).log2();
+        let t = (stops - Histogram::MIN_STOPS) / (Histogram::MAX_STOPS - Histogram::MIN_STOPS);
+        ((t * (self.bins.len() as Float)).floor().max(0.0) as usize).min(self.bins.len() - 1)
+    }
 }
 
-impl Camera {
-    pub fn new(
-        look_from: Vec3, look_at: Vec3, up: Vec3,
-        vertical_fov: f32, aspect_ratio: f32
-    ) -> Camera {
-        let theta = vertical_fov * 0.01745329; // Convert to radians.
-        let h = (theta * 0.5).tan();
-        let viewport_height = 2.0 * h;
-        let viewport_width = aspect_ratio * viewport_height;
+// Whether a pixel is shown as black, clipped, or neither.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Exposure {
+    Under,
+    Normal,
+    Over
+}
 
-        let w = (look_from - look_at).normalized();
-        let u = up.cross(&w).normalized();
-        let v = w.cross(&u);
+// Everything known about a single pixel of the accumulation buffer.
+pub struct PixelInfo {
+    pub linear: Vec3, // Averaged linear HDR value.
+    pub alpha: Float, // Share of the camera rays that hit something.
+    pub display: (u8, u8, u8), // Value after the sample average and color space encoding, as written to the output.
+    pub samples: u32, // Number of samples accumulated into the pixel.
+    pub cost: u32, // Object tests spent on the pixel in the last render.
+    pub bvh_visits: u32, // Scene BVH nodes visited for the pixel in the last render.
+    pub leaf_depth: Option<u32>, // Depth of the scene BVH leaf holding the object seen in the pixel.
+    pub distance: Option<Float>, // Distance from the camera to the surface seen through the pixel center.
+    pub object_id: Option<u32>, // Object covering most of the pixel in the ID pass.
+    pub material_id: Option<u32> // Material covering most of the pixel in the ID pass.
+}
 
-        let origin = look_from;
-        let horizontal = viewport_width * u;
-        let vertical = viewport_height * v;
-        let lower_left_corner = origin - (horizontal * 0.5) - (vertical * 0.5) - w;
+// Counters gathered over a single call to run().
+#[derive(Clone, Copy)]
+pub struct RenderStats {
+    pub primary_rays: u64, // Rays generated by the camera.
+    pub bounce_rays: u64, // Rays scattered by materials.
+    pub shadow_rays: u64, // Rays towards lights.
+    pub object_tests: u64, // Ray vs. object tests. A mesh is one test, as the triangles its own BVH tests aren't counted.
+    pub bvh_node_visits: u64,
+    pub escaped_rays: u64, // Rays that hit nothing and gathered the background.
+    pub depth_limit_hits: u64, // Paths terminated by the maximum depth or a maximum depth per ray kind.
+    pub render_time: Duration
+}
 
-        Camera {
-            origin: origin,
-            lower_left_corner: lower_left_corner,
-            horizontal: horizontal,
-            vertical: vertical
+impl Default for RenderStats {
+    fn default() -> RenderStats {
+        RenderStats::new()
+    }
+}
+
+impl RenderStats {
+    pub fn new() -> RenderStats {
+        RenderStats {
+            primary_rays: 0,
+            bounce_rays: 0,
+            shadow_rays: 0,
+            object_tests: 0,
+            bvh_node_visits: 0,
+            escaped_rays: 0,
+            depth_limit_hits: 0,
+            render_time: Duration::from_secs(0)
         }
     }
 
-    pub fn get_ray(&mut self, u: f32, v: f32) -> Ray {
-        Ray::new(self.origin, self.lower_left_corner + u*self.horizontal + v*self.vertical - self.origin)
+    pub fn total_rays(&self) -> u64 {
+        self.primary_rays + self.bounce_rays + self.shadow_rays
+    }
+
+    pub fn rays_per_second(&self) -> f64 {
+        let seconds = self.render_time.as_secs_f64();
+        if seconds > 0.0 {(self.total_rays() as f64) / seconds} else {0.0}
     }
 }
 
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Primary rays:       {}", self.primary_rays)?;
+        writeln!(f, "Bounce rays:        {}", self.bounce_rays)?;
+        writeln!(f, "Shadow rays:        {}", self.shadow_rays)?;
+        writeln!(f, "Object tests:       {}", self.object_tests)?;
+        writeln!(f, "BVH node visits:    {}", self.bvh_node_visits)?;
+        writeln!(f, "Escaped rays:       {}", self.escaped_rays)?;
+        writeln!(f, "Depth limit hits:   {}", self.depth_limit_hits)?;
+        write!(f, "Render time:        {:?} ({:.0} rays/s)", self.render_time, self.rays_per_second())
+    }
+}
 
-// --------------------------------------------------
-// Material(s)
-// --------------------------------------------------
-// https://stackoverflow.com/questions/30353462/how-to-clone-a-struct-storing-a-boxed-trait-object
-// pub trait Material: MaterialClone {
-//     fn scatter(&self, ray: &Ray, hit_rec: &HitRecord, out_attenuation: &mut Vec3, out_scattered: &mut Ray) -> bool;
-// }
-// pub trait MaterialClone {
-//     fn clone_mat(&self) -> Box<dyn Material>;
-// }
-// impl<T> MaterialClone for T where T: 'static + Material + Clone, {
-//     fn clone_mat(&self) -> Box<dyn Material> {
-//         Box::new(self.clone())
-//     }
-// }
-// impl Clone for Box<dyn Material> {
-//     fn clone(&self) -> Box<dyn Material> {
-//         self.clone_mat()
-//     }
-// }
-// NOTE: The above is no longer needed as materials are now referred to by an index. I'm keeping this around for posterity, though.
-pub trait Material {
-    fn scatter(&self, ray: &Ray, hit_rec: &HitRecord, out_attenuation: &mut Vec3, out_scattered: &mut Ray, rng: &mut RandGen) -> bool;
+// Bounces a path has left, in all and of each kind.
+#[derive(Clone, Copy)]
+struct PathDepth {
+    total: u32,
+    diffuse: u32,
+    specular: u32
 }
 
-pub struct Lambertian {
-    albedo: Vec3
+// A copy of the accumulation buffer kept around to compare against.
+struct CompareStash {
+    pixels: AccumulationBuffer,
+    sample_counts: Box<[u32]>
 }
-impl Lambertian {
-    pub fn new(albedo: Vec3) -> Lambertian {
-        Lambertian {
-            albedo: albedo
-        }
-    }
+
+pub struct RSRaytracer {
+    width: u32, // Of the buffer, which the image is rendered and saved at.
+    height: u32,
+    pixels: AccumulationBuffer,
+    sample_counts: Box<[u32]>, // Number of samples accumulated per pixel.
+    pixel_costs: Box<[u32]>, // Object tests per pixel in the last render.
+    pixel_visits: Box<[u32]>, // Scene BVH nodes visited per pixel in the last render.
+    pixel_leaf_depths: Box<[Option<u32>]>, // Scene BVH leaf depth of the object seen through each pixel center.
+    pixel_distances: Box<[Option<Float>]>, // Distance to the surface seen through each pixel center, for the depth pass.
+    pixel_object_ids: Box<[IdCoverage]>, // Objects seen through each pixel, for the ID passes.
+    pixel_material_ids: Box<[IdCoverage]>, // Materials seen through each pixel, for the ID passes.
+    max_leaf_depth: u32, // Of the scene BVH in the last render.
+    display_mode: DisplayMode,
+    debug_overlay: DebugOverlay,
+    overlay_pixels: Box<[Option<(u8, u8, u8)>]>, // Drawn over the image where set.
+    exposure_overlay: bool, // Whether copy_to draws the histogram and zebra stripes over the image.
+    compare_stash: Option<CompareStash>,
+    compare_split: Option<u32>, // Columns left of this show the stash. No comparison is shown if unset.
+    progressive: bool, // Whether the buffer holds progressive passes that more samples can be added to.
+    frame: u32, // Renders and passes so far, which animates the film grain.
+    autofocus: Option<Float>, // Focus distance that autofocus found for the current render or pass.
+    normal_niceness: Option<i32>, // Of the tracing thread before low priority rendering lowered it, while it is lowered.
+    render_start: std::time::Instant, // Of the current render, for the time limit. Passes that accumulate continue it.
+    render_samples: u64, // Camera samples traced by the current render, for the sample limit.
+    render_time: Duration, // Spent tracing the current render, over all of its passes.
+    scene_name: String, // Of the scene last given to the ray tracer, for the stamp.
+    limit_reached: bool, // Whether the current render stopped at its time or sample limit.
+    output_directory: String, // That the save functions write their files to.
+    environment_average: Vec3, // Of the background of the last rendered scene, for auto white balance.
+    settings: RenderSettings,
+    stats: RenderStats,
+    rand_gen: RandGen // Shared random number generator.
 }
-impl Material for Lambertian {
-    fn scatter(&self, _ray: &Ray, hit_rec: &HitRecord, out_attenuation: &mut Vec3, out_scattered: &mut Ray, _rng: &mut RandGen) -> bool {
-        let mut scatter_dir = hit_rec.n + Vec3::random_on_sphere();
 
-        // Catch degenerate scatter direction.
-        if scatter_dir.near_zero() {
-            scatter_dir = hit_rec.n;
+impl RSRaytracer {
+    pub fn new() -> RSRaytracer {
+        RSRaytracer::with_resolution(WIDTH, HEIGHT)
+    }
+
+    // A ray tracer whose buffer is the given size, which need not be the size it is shown at (see copy_to).
+    pub fn with_resolution(width: u32, height: u32) -> RSRaytracer {
+        let (width, height) = (width.max(1), height.max(1));
+        let pixel_count = (width * height) as usize;
+        let mut pixels = AccumulationBuffer::new(pixel_count, Precision::Full);
+
+        // Start with a simple gradient.
+        for y in 0..height {
+            for x in 0..width {
+                // Must be multiplied here as there's a conversion using this value when outputting the underlying data.
+                let scale: Float = SAMPLES_PER_PIXEL as Float;
+
+                let gradient = Vec3::new((x as Float) / (width as Float), (y as Float) / (height as Float), 0.0);
+                pixels.set((y * width + x) as usize, gradient * scale, scale, SAMPLES_PER_PIXEL);
+            }
         }
 
-        out_scattered.origin = hit_rec.p;
-        out_scattered.direction = scatter_dir;
+        // The gradient is pre-scaled above, so it counts as being fully sampled.
+        let sample_counts = vec![SAMPLES_PER_PIXEL; pixel_count];
 
-        *out_attenuation = self.albedo;
+        RSRaytracer {
+            width: width,
+            height: height,
+            pixels: pixels,
+            sample_counts: sample_counts.into_boxed_slice(),
+            pixel_costs: vec![0; pixel_count].into_boxed_slice(),
+            pixel_visits: vec![0; pixel_count].into_boxed_slice(),
+            pixel_leaf_depths: vec![None; pixel_count].into_boxed_slice(),
+            pixel_distances: vec![None; pixel_count].into_boxed_slice(),
+            pixel_object_ids: vec![IdCoverage::none(); pixel_count].into_boxed_slice(),
+            pixel_material_ids: vec![IdCoverage::none(); pixel_count].into_boxed_slice(),
+            max_leaf_depth: 0,
+            display_mode: DisplayMode::Color,
+            debug_overlay: DebugOverlay::None,
+            overlay_pixels: vec![None; pixel_count].into_boxed_slice(),
+            exposure_overlay: false,
+            compare_stash: None,
+            compare_split: None,
+            progressive: false,
+            frame: 0,
+            autofocus: None,
+            normal_niceness: None,
+            render_start: std::time::Instant::now(),
+            render_samples: 0,
+            render_time: Duration::from_secs(0),
+            scene_name: "untitled".to_string(),
+            limit_reached: false,
+            output_directory: ".".to_string(),
+            environment_average: Vec3::one(),
+            settings: RenderSettings::new(),
+            stats: RenderStats::new(),
+            rand_gen: RandGen::new()
+        }
+    }
 
-        return true
+    // Width and height of the buffer in pixels.
+    pub fn resolution(&self) -> (u32, u32) {
+        (self.width, self.height)
     }
-}
 
-pub struct Metal {
-    albedo: Vec3,
-    fuzz: f32
-}
-impl Metal {
-    pub fn new(albedo: Vec3, fuzz: f32) -> Metal {
-        Metal {
-            albedo: albedo,
-            fuzz: fuzz
+    // The region of the settings limited to the buffer, or all of it without one.
+    fn render_region(&self) -> Region {
+        match self.settings.region {
+            Some(region) => region.clamped(self.width, self.height),
+            None => Region::full(self.width, self.height)
         }
     }
-}
-impl Material for Metal {
-    fn scatter(&self, ray: &Ray, hit_rec: &HitRecord, out_attenuation: &mut Vec3, out_scattered: &mut Ray, _rng: &mut RandGen) -> bool {
-        let reflected = ray.direction.normalized().reflect(hit_rec.n);
 
-        out_scattered.origin = hit_rec.p;
-        out_scattered.direction = reflected + self.fuzz * Vec3::random_on_sphere();
+    pub fn get_rng(&mut self) -> &mut RandGen {
+        &mut self.rand_gen
+    }
 
-        *out_attenuation = self.albedo;
+    pub fn get_settings(&self) -> &RenderSettings {
+        &self.settings
+    }
 
-        return out_scattered.direction.dot(&hit_rec.n) > 0.0
+    pub fn get_settings_mut(&mut self) -> &mut RenderSettings {
+        &mut self.settings
     }
-}
 
-pub struct Dielectric {
-    ior: f32 // Index of refraction.
-}
-impl Dielectric {
-    pub fn new(ior: f32) -> Dielectric {
-        Dielectric {
-            ior: ior
-        }
+    pub fn get_output_directory(&self) -> &str {
+        &self.output_directory
     }
 
-    pub fn reflectance(&self, cosine: f32, ref_idx: f32) -> f32 {
-        // Schlick's approximation.
-        let mut r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
-        r0 = r0 * r0;
-        return r0 + (1.0 - r0) * ((1.0 - cosine).powf(5.0));
+    // The directory must exist by the time a file is saved.
+    pub fn set_output_directory(&mut self, directory: &str) {
+        self.output_directory = directory.to_string();
     }
-}
-impl Material for Dielectric {
-    fn scatter(&self, ray: &Ray, hit_rec: &HitRecord, out_attenuation: &mut Vec3, out_scattered: &mut Ray, rng: &mut RandGen) -> bool {
-        let refract_ratio = if hit_rec.front_face {1.0 / self.ior} else {self.ior};
-        let unit_direction = ray.direction.normalized();
 
-        let cos_theta = (-unit_direction).dot(&hit_rec.n).min(1.0);
-        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+    pub fn get_scene_name(&self) -> &str {
+        &self.scene_name
+    }
 
-        let cannot_refract = (refract_ratio * sin_theta) > 1.0;
-        
-        let direction = if cannot_refract || self.reflectance(cos_theta, refract_ratio) > rng.next01() {
-            unit_direction.reflect(hit_rec.n)
-        } else {
-            Vec3::refract(unit_direction, hit_rec.n, refract_ratio)
-        };
+    // Shown by the stamp, e.g. the scene's file name.
+    pub fn set_scene_name(&mut self, name: &str) {
+        self.scene_name = name.to_string();
+    }
 
-        *out_scattered = Ray::new(hit_rec.p, direction);
-        *out_attenuation = Vec3::one();
+    // Path of an output file in the output directory. Absolute paths are left as they are.
+    pub fn output_path(&self, file: &str) -> String {
+        std::path::Path::new(&self.output_directory).join(file).to_string_lossy().into_owned()
+    }
 
-        true
+    // Counters from the most recent call to run().
+    pub fn stats(&self) -> &RenderStats {
+        &self.stats
     }
-}
 
+    // Keeps a copy of the current buffer for A/B comparisons, replacing any previous one.
+    pub fn stash_for_compare(&mut self) {
+        self.compare_stash = Some(CompareStash {
+            pixels: self.pixels.clone(),
+            sample_counts: self.sample_counts.clone()
+        });
+    }
 
-// --------------------------------------------------
-// Hittable / HitRecord
-// --------------------------------------------------
-#[derive(Clone)]
-pub struct HitRecord {
-    pub p: Vec3,
-    pub n: Vec3,
-    pub t: f32,
-    pub front_face: bool,
-    pub mat_id: u32
-}
+    pub fn has_compare_stash(&self) -> bool {
+        self.compare_stash.is_some()
+    }
 
-impl HitRecord {
-    pub fn empty() -> HitRecord {
-        HitRecord{
-            p: Vec3::new(0.0, 0.0, 0.0),
-            n: Vec3::new(0.0, 0.0, 0.0),
-            t: 0.0,
-            front_face: false,
-            mat_id: 0
-        }
+    pub fn clear_compare_stash(&mut self) {
+        self.compare_stash = None;
+        self.compare_split = None;
     }
 
-    pub fn new(p: Vec3, n: Vec3, t: f32, front_face: bool, mat_id: u32) -> HitRecord {
-        HitRecord {
-            p: p,
-            n: n,
-            t: t,
-            front_face: front_face,
-            mat_id: mat_id
-        }
+    pub fn get_compare_split(&self) -> Option<u32> {
+        self.compare_split
     }
 
-    pub fn set_face_normal(&mut self, ray: &Ray, outward_normal: &Vec3) {
-        self.front_face = ray.direction.dot(outward_normal) < 0.0;
-        self.n = if self.front_face {*outward_normal} else {-*outward_normal};
+    // Sets the column that splits the stashed buffer (left) from the current buffer (right) in copy_to.
+    pub fn set_compare_split(&mut self, split: Option<u32>) {
+        self.compare_split = split.map(|x| x.min(self.width));
     }
-}
 
-pub trait Hittable {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
-}
+    pub fn get_display_mode(&self) -> DisplayMode {
+        self.display_mode
+    }
 
+    pub fn set_display_mode(&mut self, mode: DisplayMode) {
+        self.display_mode = mode;
+    }
 
-// --------------------------------------------------
+    pub fn get_debug_overlay(&self) -> DebugOverlay {
+        self.debug_overlay
+    }
+
+    pub fn get_exposure_overlay(&self) -> bool {
+        self.exposure_overlay
+    }
+
+    // Draws the luminance histogram of the render region in the lower left corner in copy_to, and stripes over pixels
+    // shown as black (blue) or clipped (red).
+    pub fn set_exposure_overlay(&mut self, enabled: bool) {
+        self.exposure_overlay = enabled;
+    }
+
+    // Draws the overlay for the scene. It is redrawn by every run() until set back to DebugOverlay::None.
+    pub fn set_debug_overlay(&mut self, overlay: DebugOverlay, scene: &Scene) {
+        self.debug_overlay = overlay;
+        self.draw_debug_overlay(scene);
+    }
+
+    fn draw_debug_overlay(&mut self, scene: &Scene) {
+        for pixel in self.overlay_pixels.iter_mut() {
+            *pixel = None;
+        }
+        match self.debug_overlay {
+            DebugOverlay::None => {}
+            DebugOverlay::Edges => self.draw_edges(scene),
+            DebugOverlay::BvhBoxes(max_depth) => {
+                for (aabb, depth) in scene.bvh_node_bounds(max_depth) {
+                    let color = heatmap_color((depth as Float) / (max_depth.max(1) as Float));
+                    self.draw_box(scene.get_camera(), &aabb, color);
+                }
+            }
+        }
+    }
+
+    // Traces a ray through each pixel center and marks pixels whose right or lower neighbor sees a
+    // different object (outlines) or a surface facing a noticeably different way (creases).
+    fn draw_edges(&mut self, scene: &Scene) {
+        let mut hits: Vec<Option<(u32, Vec3)>> = Vec::with_capacity((self.width * self.height) as usize);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let ray = self.camera_ray(scene, (x as Float) + 0.5, (y as Float) + 0.5, None);
+                let (hit, _, _) = scene.hit_with_id(&ray, scene.get_camera().get_clip_range());
+                hits.push(hit.map(|(id, hit_rec)| (id, hit_rec.n)));
+            }
+        }
+
+        let is_edge = |a: &Option<(u32, Vec3)>, b: &Option<(u32, Vec3)>| match (a, b) {
+            (Some((id_a, n_a)), Some((id_b, n_b))) => id_a != id_b || n_a.dot(n_b) < 0.9,
+            (None, None) => false,
+            _ => true
+        };
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let idx = (y * self.width + x) as usize;
+                let right = x + 1 < self.width && is_edge(&hits[idx], &hits[idx + 1]);
+                let below = y + 1 < self.height && is_edge(&hits[idx], &hits[idx + self.width as usize]);
+                if right || below {
+                    self.overlay_pixels[idx] = Some((255, 255, 255));
+                }
+            }
+        }
+    }
+
+    fn draw_box(&mut self, camera: &Camera, aabb: &Aabb, color: (u8, u8, u8)) {
+        let corner = |i: usize| Vec3::new(
+            if i & 1 == 0 {aabb.min.x} else {aabb.max.x},
+            if i & 2 == 0 {aabb.min.y} else {aabb.max.y},
+            if i & 4 == 0 {aabb.min.z} else {aabb.max.z}
+        );
+        // Corners differing in a single bit share an edge.
+        for i in 0..8 {
+            for bit in [1, 2, 4].iter() {
+                if i & bit == 0 {
+                    self.draw_line(camera, corner(i), corner(i | bit), color);
+                }
+            }
+        }
+    }
+
+    fn draw_line(&mut self, camera: &Camera, a: Vec3, b: Vec3, color: (u8, u8, u8)) {
+        // Cut off the part behind the camera.
+        const NEAR: Float = 1e-3;
+        let (depth_a, depth_b) = (camera.depth(a), camera.depth(b));
+        if depth_a < NEAR && depth_b < NEAR {
+            return
+        }
+        let cut = |from: Vec3, to: Vec3, depth_from: Float, depth_to: Float| {
+            from + (to - from) * ((NEAR - depth_from) / (depth_to - depth_from))
+        };
+        let a_clipped = if depth_a < NEAR {cut(a, b, depth_a, depth_b)} else {a};
+        let b_clipped = if depth_b < NEAR {cut(b, a, depth_b, depth_a)} else {b};
+        let (start, end) = match (camera.project(a_clipped), camera.project(b_clipped)) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return
+        };
+
+        // To pixels, as run() maps them, then clipped to the image so that the step count stays bounded.
+        let to_pixel = |(u, v): (Float, Float)| (u * ((self.width-1) as Float), (1.0 - v) * ((self.height-1) as Float));
+        let (x0, y0) = to_pixel(start);
+        let (x1, y1) = to_pixel(end);
+        let (dx, dy) = (x1 - x0, y1 - y0);
+        let mut t_range = Interval::new(0.0, 1.0);
+        for (p, d, max) in [(x0, dx, (self.width-1) as Float), (y0, dy, (self.height-1) as Float)].iter() {
+            if d.abs() < 1e-9 {
+                if *p < 0.0 || *p > *max {
+                    return
+                }
+                continue;
+            }
+            let (t0, t1) = ((0.0 - p) / d, (max - p) / d);
+            t_range.min = t_range.min.max(t0.min(t1));
+            t_range.max = t_range.max.min(t0.max(t1));
+        }
+        if t_range.is_empty() {
+            return
+        }
+
+        let steps = ((dx.abs().max(dy.abs()) * t_range.size()).ceil() as u32).max(1);
+        for step in 0..=steps {
+            let t = t_range.min + t_range.size() * (step as Float) / (steps as Float);
+            let x = (x0 + dx * t).round() as u32;
+            let y = (y0 + dy * t).round() as u32;
+            if x < self.width && y < self.height {
+                self.overlay_pixels[(y * self.width + x) as usize] = Some(color);
+            }
+        }
+    }
+
+    // Copies the image to a texture, which is usually the size of the buffer. A smaller texture shows a preview:
+    // each of its pixels is the average of the buffer pixels it covers, weighted by how much of each it covers.
+    // Debug overlays stay drawn over the preview, as averaging would fade their thin lines.
+    pub fn copy_to(&self, texture: &mut sdl2::render::Texture) -> Result<()> {
+        let query = texture.query();
+        let (texture_width, texture_height) = (query.width, query.height);
+
+        // Columns left of the split come from the stash when comparing.
+        let (stash, split) = match (&self.compare_stash, self.compare_split) {
+            (Some(stash), Some(split)) => (Some(stash), split),
+            _ => (None, 0)
+        };
+        let image = self.final_image(&self.pixels, &self.sample_counts, true);
+        let stash_image = stash.and_then(|stash| self.final_image(&stash.pixels, &stash.sample_counts, true));
+
+        // Costs and visits are normalized against the most expensive pixel, and depths against the deepest leaf.
+        let max_cost = self.pixel_costs.iter().copied().max().unwrap_or(0).max(1);
+        let max_visits = self.pixel_visits.iter().copied().max().unwrap_or(0).max(1);
+        let max_depth = self.max_leaf_depth.max(1);
+        let histogram = if self.exposure_overlay {Some(self.histogram(HISTOGRAM_BINS))} else {None};
+        // Zebra stripes are widened by the downscale, so that they stay visible in a preview.
+        let stripe_width = 4 * self.width.div_ceil(texture_width).max(1);
+
+        // RGBA of a buffer pixel as shown, without the debug overlay.
+        let shown_pixel = |x: u32, y: u32| -> (u8, u8, u8, u8) {
+            let pixel_idx = (y * self.width + x) as usize;
+            let false_color = match self.display_mode {
+                DisplayMode::Color => None,
+                DisplayMode::FalseColor => {
+                    let (luminance, exposure) = self.exposure_of(pixel_idx);
+                    Some(false_color(luminance, exposure, self.settings.color_space))
+                }
+                DisplayMode::CostHeatmap => Some(heatmap_color((self.pixel_costs[pixel_idx] as Float) / (max_cost as Float))),
+                DisplayMode::BvhVisits => Some(heatmap_color((self.pixel_visits[pixel_idx] as Float) / (max_visits as Float))),
+                DisplayMode::BvhLeafDepth => Some(match self.pixel_leaf_depths[pixel_idx] {
+                    Some(depth) => heatmap_color((depth as Float) / (max_depth as Float)),
+                    None => (0, 0, 0)
+                })
+            };
+            if let Some((r_value, g_value, b_value)) = false_color {
+                return (r_value, g_value, b_value, 255)
+            }
+
+            let (pixels, sample_counts, image) = match stash {
+                Some(stash) if x < split => (&stash.pixels, &stash.sample_counts, &stash_image),
+                _ => (&self.pixels, &self.sample_counts, &image)
+            };
+
+            let rgba = match image {
+                Some(image) => image[pixel_idx],
+                None => {
+                    let samples = sample_counts[pixel_idx];
+                    let (pixel_color, alpha) = pixels.get(pixel_idx, samples);
+                    self.get_final_rgba(&pixel_color, alpha, samples)
+                }
+            };
+
+            // Diagonal zebra stripes over the current buffer's black and clipped pixels.
+            if self.exposure_overlay && (stash.is_none() || x >= split) && ((x + y) / stripe_width).is_multiple_of(2) {
+                match self.exposure_of(pixel_idx).1 {
+                    Exposure::Under => return (0, 96, 255, 255),
+                    Exposure::Normal => {}
+                    Exposure::Over => return (255, 0, 0, 255)
+                }
+            }
+            rgba
+        };
+
+        // The texture is RGBA, with the same byte order as the buffer.
+        texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+            if (texture_width, texture_height) == (self.width, self.height) {
+                for y in 0..self.height {
+                    for x in 0..self.width {
+                        let offset = (y * (pitch as u32) + x * 4) as usize;
+                        let (r_value, g_value, b_value, a_value) = shown_pixel(x, y);
+                        buffer[offset + 0] = r_value;
+                        buffer[offset + 1] = g_value;
+                        buffer[offset + 2] = b_value;
+                        buffer[offset + 3] = a_value;
+                    }
+                }
+            } else {
+                let mut shown = Vec::with_capacity((self.width * self.height) as usize);
+                for y in 0..self.height {
+                    for x in 0..self.width {
+                        shown.push(shown_pixel(x, y));
+                    }
+                }
+                box_downsample(&shown, self.width, self.height, buffer, pitch, texture_width, texture_height);
+            }
+
+            // Overlay pixels cover the texture pixel they fall in.
+            for y in 0..self.height {
+                for x in 0..self.width {
+                    if let Some((r_value, g_value, b_value)) = self.overlay_pixels[(y * self.width + x) as usize] {
+                        let texture_x = ((x as u64) * (texture_width as u64) / (self.width as u64)) as u32;
+                        let texture_y = ((y as u64) * (texture_height as u64) / (self.height as u64)) as u32;
+                        let offset = (texture_y * (pitch as u32) + texture_x * 4) as usize;
+                        buffer[offset + 0] = r_value;
+                        buffer[offset + 1] = g_value;
+                        buffer[offset + 2] = b_value;
+                        buffer[offset + 3] = 255;
+                    }
+                }
+            }
+
+            if let Some(histogram) = &histogram {
+                draw_histogram(buffer, pitch, texture_height, histogram);
+            }
+        }).map_err(RaytracerError::Sdl)?;
+
+        // Direct memory copy from internal pixels array. Requires pixels to be [u8] format.
+        // texture.update(
+        //     sdl2::rect::Rect::new(0, 0, IMAGE_WIDTH, IMAGE_HEIGHT),
+        //     &self.pixels,
+        //     (IMAGE_WIDTH * CHANNELS) as usize
+        // ).unwrap();
+
+        Ok(())
+    }
+
+    // Renders a scene into the ray tracer's buffer using the current settings.
+    pub fn run(&mut self, scene: &Scene) {
+        info!(target: "render", "Starting ray tracer...");
+        self.progressive = false;
+        if self.trace(scene, self.settings.samples_per_pixel, false) {
+            let status = if self.limit_reached {"stopped at its limit"} else {"complete"};
+            info!(target: "render", "Ray trace {} in {:?}.", status, self.stats.render_time);
+            info!(target: "render", "Render statistics:\n{}", self.stats);
+        }
+    }
+
+    // Adds a pass of samples per pixel to the buffer, so that the image refines the longer passes are run.
+    // The first pass after any other render starts over.
+    pub fn run_pass(&mut self, scene: &Scene, samples_per_pixel: u32) {
+        let accumulate = self.progressive;
+        if self.trace(scene, samples_per_pixel, accumulate) {
+            self.progressive = true;
+            debug!(target: "render", "Progressive pass complete in {:?}, at {:.1} samples per pixel.", self.stats.render_time, self.average_samples());
+        }
+    }
+
+    pub fn is_progressive(&self) -> bool {
+        self.progressive
+    }
+
+    // Whether the last render (or progressive pass) stopped at the time or sample limit of the settings. Further
+    // passes trace nothing until a new render starts.
+    pub fn limit_reached(&self) -> bool {
+        self.limit_reached
+    }
+
+    // Whether tracing another row of samples would go past a limit. Checked between rows, so a render can run
+    // over its time limit by a row.
+    fn over_limit(&self, row_samples: u64) -> bool {
+        let over_time = self.settings.max_time.is_some_and(|max_time| self.render_start.elapsed() >= max_time);
+        let over_samples = self.settings.max_total_samples.is_some_and(|max_samples| self.render_samples + row_samples > max_samples);
+        over_time || over_samples
+    }
+
+    // Mean samples per pixel in the render region.
+    pub fn average_samples(&self) -> Float {
+        let region = self.render_region();
+        if region.is_empty() {
+            return 0.0
+        }
+        let mut sum = 0.0;
+        for y in region.y..(region.y + region.height) {
+            for x in region.x..(region.x + region.width) {
+                sum += self.sample_counts[(y * self.width + x) as usize] as Float;
+            }
+        }
+        sum / ((region.width * region.height) as Float)
+    }
+
+    // Lowers the priority of the thread that traces when low priority rendering is turned on, and raises it back
+    // when it is turned off. Raising it needs privileges on most systems, so without them it stays lowered.
+    fn update_thread_priority(&mut self) {
+        match (self.settings.low_priority, self.normal_niceness) {
+            (true, None) => {
+                self.normal_niceness = lower_thread_priority();
+                if let Some(niceness) = self.normal_niceness {
+                    debug!(target: "render", "Lowered the render thread's niceness from {}.", niceness);
+                }
+            },
+            (false, Some(niceness)) => {
+                if !set_thread_niceness(niceness) {
+                    warn!(target: "render", "Can't raise the render thread's priority back without privileges. It stays lowered until the program exits.");
+                }
+                self.normal_niceness = None;
+            },
+            _ => {}
+        }
+    }
+
+    // Traces the render region with a number of samples per pixel, either replacing the buffer or adding
+    // to what it holds. Returns false if there was nothing to trace.
+    fn trace(&mut self, scene: &Scene, samples_per_pixel: u32, accumulate: bool) -> bool {
+        debug!(target: "scene", "Scene has {} objects and {} materials.", scene.object_count(), scene.material_count());
+        let start_time = std::time::Instant::now();
+        self.stats = RenderStats::new();
+        self.frame = self.frame.wrapping_add(1);
+        if !accumulate {
+            self.render_start = start_time;
+            self.render_samples = 0;
+            self.render_time = Duration::from_secs(0);
+            self.limit_reached = false;
+        }
+        self.environment_average = scene.get_background().average();
+        self.update_thread_priority();
+        self.autofocus = if self.settings.autofocus {RSRaytracer::autofocus_distance(scene)} else {None};
+        if let Some(distance) = self.autofocus {
+            debug!(target: "render", "Autofocus distance: {:.3}.", distance);
+        }
+
+        let region = self.render_region();
+        if region.is_empty() {
+            warn!(target: "render", "Render region is empty. Nothing to trace.");
+            return false
+        }
+
+        self.max_leaf_depth = scene.bvh_depth();
+        if self.pixels.precision() != self.settings.accumulation {
+            debug!(target: "render", "Converting the accumulation buffer to {} precision.", self.settings.accumulation.name());
+            self.pixels = self.pixels.with_precision(self.settings.accumulation, &self.sample_counts);
+        }
+
+        // Samples are splatted into every pixel of the region within the filter's radius. The weighted
+        // sums are normalized once all samples are in.
+        let filter = self.settings.filter;
+        let region_idx = |x: u32, y: u32| ((y - region.y) * region.width + (x - region.x)) as usize;
+        let mut sums = vec![Vec3::zero(); (region.width * region.height) as usize];
+        let mut alpha_sums: Vec<Float> = vec![0.0; (region.width * region.height) as usize];
+        let mut weights: Vec<Float> = vec![0.0; (region.width * region.height) as usize];
+
+        let max_depth = self.settings.max_depth;
+        let depth = PathDepth {
+            total: max_depth,
+            diffuse: self.settings.max_diffuse_depth.unwrap_or(max_depth),
+            specular: self.settings.max_specular_depth.unwrap_or(max_depth)
+        };
+        let clip_range = scene.get_camera().get_clip_range();
+
+        let row_samples = (region.width as u64) * (samples_per_pixel as u64);
+        let mut traced_rows = region.height;
+        for y in region.y..(region.y + region.height) {
+            if self.over_limit(row_samples) {
+                if !self.limit_reached {
+                    info!(target: "render", "Render limit reached after {:?} and {} samples. Stopping.", self.render_start.elapsed(), self.render_samples);
+                }
+                self.limit_reached = true;
+                traced_rows = y - region.y;
+                break;
+            }
+            self.render_samples += row_samples;
+            trace!(target: "render", "Rendering line {}/{}...", y - region.y + 1, region.height);
+            let row_start = std::time::Instant::now();
+            for x in region.x..(region.x + region.width) {
+                let tests_before = self.stats.object_tests;
+                let visits_before = self.stats.bvh_node_visits;
+                let mut object_counts: Vec<(u32, Float)> = Vec::new();
+                let mut material_counts: Vec<(u32, Float)> = Vec::new();
+                for _i in 0..samples_per_pixel {
+                    let r0: Float = self.rand_gen.next01();
+                    let r1: Float = self.rand_gen.next01();
+                    let lens = if self.settings.physical_exposure.is_some() {Some(self.settings.aperture.sample(&mut self.rand_gen))} else {None};
+                    let r = self.camera_ray(scene, (x as Float) + r0, (y as Float) + r1, lens);
+                    self.stats.primary_rays += 1;
+                    // Traced again for the ID passes, which the statistics leave out like the center ray below.
+                    if self.settings.id_coverage {
+                        if let Some((object_id, mat_id)) = scene.hit_ids(&r, clip_range) {
+                            count_id(&mut object_counts, object_id);
+                            count_id(&mut material_counts, mat_id);
+                        }
+                    }
+                    let (mut sample_color, sample_alpha) = match self.settings.mode {
+                        RenderMode::PathTrace => self.ray_color(scene, &r, clip_range, depth),
+                        RenderMode::Raycast => self.raycast_color(scene, &r, clip_range)
+                    };
+                    if sample_alpha == 0.0 {
+                        if self.settings.transparent_background {
+                            sample_color = Vec3::zero();
+                        } else if let Some(backplate) = scene.get_backplate() {
+                            // Images hold display values, so they are squared back to linear to show as they are.
+                            let (plate_x, plate_y, tiles) = self.image_position((x as Float) + r0, (y as Float) + r1);
+                            let plate = backplate.sample(plate_x / ((self.width * tiles) as Float), plate_y / ((self.height * tiles) as Float));
+                            sample_color = plate * plate;
+                        }
+                    }
+
+                    // Pixel centers are at half pixels, so those within the radius are the ones whose
+                    // index is within radius - 0.5 of the sample position.
+                    let (sample_x, sample_y) = ((x as Float) + r0, (y as Float) + r1);
+                    let radius = filter.radius();
+                    let min_x = ((sample_x - radius - 0.5).ceil().max(region.x as Float)) as u32;
+                    let max_x = ((sample_x + radius - 0.5).floor().min((region.x + region.width - 1) as Float)) as u32;
+                    let min_y = ((sample_y - radius - 0.5).ceil().max(region.y as Float)) as u32;
+                    let max_y = ((sample_y + radius - 0.5).floor().min((region.y + region.height - 1) as Float)) as u32;
+                    for splat_y in min_y..=max_y {
+                        let weight_y = filter.weight(sample_y - ((splat_y as Float) + 0.5));
+                        for splat_x in min_x..=max_x {
+                            let weight = weight_y * filter.weight(sample_x - ((splat_x as Float) + 0.5));
+                            if weight != 0.0 {
+                                sums[region_idx(splat_x, splat_y)] += sample_color * weight;
+                                alpha_sums[region_idx(splat_x, splat_y)] += sample_alpha * weight;
+                                weights[region_idx(splat_x, splat_y)] += weight;
+                            }
+                        }
+                    }
+                }
+
+                let previous_samples = if accumulate {self.sample_counts[(y * self.width + x) as usize]} else {0};
+                if accumulate {
+                    self.sample_counts[(y * self.width + x) as usize] += samples_per_pixel;
+                } else {
+                    self.sample_counts[(y * self.width + x) as usize] = samples_per_pixel;
+                }
+                self.pixel_costs[(y * self.width + x) as usize] = (self.stats.object_tests - tests_before) as u32;
+                self.pixel_visits[(y * self.width + x) as usize] = (self.stats.bvh_node_visits - visits_before) as u32;
+
+                // Not counted in the statistics, as it is only traced for display.
+                let center_ray = self.camera_ray(scene, (x as Float) + 0.5, (y as Float) + 0.5, None);
+                self.pixel_leaf_depths[(y * self.width + x) as usize] = scene.hit_leaf_depth(&center_ray, clip_range);
+                let (center_hit, _, _) = scene.hit(&center_ray, clip_range);
+                self.pixel_distances[(y * self.width + x) as usize] = center_hit.map(|hit_rec| hit_rec.t * center_ray.direction.length());
+                // Without coverage, the ID passes only see the pixel center.
+                let mut samples = samples_per_pixel as Float;
+                if !self.settings.id_coverage {
+                    if let Some((object_id, mat_id)) = scene.hit_ids(&center_ray, clip_range) {
+                        count_id(&mut object_counts, object_id);
+                        count_id(&mut material_counts, mat_id);
+                    }
+                    samples = 1.0;
+                } else if previous_samples > 0 {
+                    // Earlier passes of a progressive render count with the samples they took, like their colors.
+                    let previous = previous_samples as Float;
+                    self.pixel_object_ids[(y * self.width + x) as usize].add_to_counts(&mut object_counts, previous);
+                    self.pixel_material_ids[(y * self.width + x) as usize].add_to_counts(&mut material_counts, previous);
+                    samples += previous;
+                }
+                self.pixel_object_ids[(y * self.width + x) as usize] = IdCoverage::from_counts(&mut object_counts, samples);
+                self.pixel_material_ids[(y * self.width + x) as usize] = IdCoverage::from_counts(&mut material_counts, samples);
+            }
+
+            // Sleeping rather than yielding, as a yield returns at once when nothing else is waiting to run.
+            if self.settings.low_priority {
+                std::thread::sleep(row_start.elapsed());
+            }
+        }
+
+        // The filtered averages are merged into the buffer, which weights each progressive pass by its samples
+        // (the sample counts already include this pass). Negative lobes can ring below zero, which is clamped away.
+        // Rows a limit stopped the render before are left as they were.
+        if traced_rows == 0 {
+            return false
+        }
+        for y in region.y..(region.y + traced_rows) {
+            for x in region.x..(region.x + region.width) {
+                let weight = weights[region_idx(x, y)];
+                let average = if weight > 0.0 {sums[region_idx(x, y)] / weight} else {Vec3::zero()};
+                let average_alpha = if weight > 0.0 {alpha_sums[region_idx(x, y)] / weight} else {0.0};
+                let color = Vec3::new(average.x.max(0.0), average.y.max(0.0), average.z.max(0.0));
+                let idx = (y * self.width + x) as usize;
+                self.pixels.add(idx, color, average_alpha.clamp(0.0, 1.0), samples_per_pixel, self.sample_counts[idx]);
+            }
+        }
+
+        // The overlay follows changes to the scene.
+        if self.debug_overlay != DebugOverlay::None {
+            self.draw_debug_overlay(scene);
+        }
+
+        let end_time = std::time::Instant::now();
+        self.stats.render_time = end_time.duration_since(start_time);
+        self.render_time += self.stats.render_time;
+        true
+    }
+
+    // A position in the buffer in pixels from the top left, in the image of the tile being rendered (which is
+    // the buffer without one), with the number of tiles along each side of that image.
+    fn image_position(&self, x: Float, y: Float) -> (Float, Float, u32) {
+        match self.settings.tile {
+            Some(tile) => (x + (tile.column * self.width) as Float, y + (tile.row * self.height) as Float, tile.tiles.max(1)),
+            None => (x, y, 1)
+        }
+    }
+
+    // Camera ray through a position in the buffer in pixels from the top left. Rays through a point of the lens,
+    // on a lens of radius 1, are blurred by the depth of field of the physical exposure's f-stop.
+    fn camera_ray(&self, scene: &Scene, x: Float, y: Float, lens: Option<(Float, Float)>) -> Ray {
+        let (x, y, tiles) = self.image_position(x, y);
+        let u = x / ((self.width * tiles - 1) as Float);
+        let v = y / ((self.height * tiles - 1) as Float);
+        let camera = scene.get_camera();
+        let mut ray = match (lens, self.settings.physical_exposure) {
+            (Some(lens), Some(physical)) => {
+                let mut focused = *camera;
+                if let Some(distance) = self.autofocus {
+                    focused.set_focus_distance(distance);
+                }
+                focused.get_lens_ray(u, 1.0 - v, physical.lens_radius(camera.get_vertical_fov()), lens)
+            }
+            _ => camera.get_ray(u, 1.0 - v)
+        };
+        // The camera's cone covers a pixel at the default resolution. Pixels of larger images are smaller.
+        ray.cone_spread *= (HEIGHT as Float) / ((self.height * tiles) as Float);
+        ray
+    }
+
+    // Fast stand-in for ray_color, for previews and for checking what camera rays see: the color the surface's
+    // material gives a bounce (its albedo), lit straight by the scene's lights with hard shadows, or by a light
+    // from above when it has none. Nothing bounces, so mirrors and glass show only their tint. Cut outs let the
+    // ray through as they do when path tracing, so both see the same surfaces, and the alpha is the same.
+    fn raycast_color(&mut self, scene: &Scene, ray: &Ray, ray_t: Interval) -> (Vec3, Float) {
+        let mut ray = *ray;
+        let mut ray_t = ray_t;
+        for _ in 0..self.settings.max_depth.max(1) {
+            let (hit_rec, tests, visits) = scene.hit(&ray, ray_t);
+            self.stats.object_tests += tests;
+            self.stats.bvh_node_visits += visits;
+            let hit_rec = match hit_rec {
+                Some(hit_rec) => hit_rec,
+                None => {
+                    self.stats.escaped_rays += 1;
+                    return (scene.get_background().color(&ray), 0.0)
+                }
+            };
+            let mat = scene.material_for_hit(hit_rec.mat_id);
+            if !hit_rec.front_face && scene.get_back_face(hit_rec.mat_id) == BackFace::Black {
+                return (Vec3::zero(), 1.0)
+            }
+            let opacity = mat.opacity(&ray, &hit_rec);
+            if opacity < 1.0 && self.rand_gen.next01() >= opacity {
+                let mut through = Ray::new(hit_rec.p, ray.direction);
+                through.cone_spread = ray.cone_spread;
+                through.cone_width = ray.cone_width_at(hit_rec.t);
+                through.kind = ray.kind;
+                ray_t = Interval::new(0.001, ray_t.max - hit_rec.t);
+                ray = through;
+                continue
+            }
+
+            let mut albedo = Vec3::zero();
+            let mut scattered = Ray::new(Vec3::zero(), Vec3::zero());
+            if !mat.scatter(&ray, &hit_rec, &mut albedo, &mut scattered, &mut self.rand_gen) {
+                return (Vec3::zero(), 1.0)
+            }
+            let mut light = Vec3::zero();
+            if scene.get_lights().is_empty() {
+                let direction = Vec3::new(0.4, 1.0, 0.6).normalized();
+                let facing = hit_rec.n.dot(&direction);
+                if facing > 0.0 && !self.is_shadowed(scene, hit_rec.p, direction, Float::MAX) {
+                    light += Vec3::one() * facing;
+                }
+            } else {
+                for scene_light in scene.get_lights() {
+                    let sample = match scene_light.sample(hit_rec.p) {
+                        Some(sample) => sample,
+                        None => continue
+                    };
+                    let facing = hit_rec.n.dot(&sample.direction);
+                    if facing > 0.0 && !self.is_shadowed(scene, hit_rec.p, sample.direction, sample.distance) {
+                        light += sample.irradiance * (facing / consts::PI);
+                    }
+                }
+            }
+            return (albedo * light, 1.0)
+        }
+        self.stats.depth_limit_hits += 1;
+        (Vec3::zero(), 1.0)
+    }
+
+    // Returns the color gathered along the ray within a range of t and its alpha, which is zero if the ray
+    // (or the ray it passed through to) hit nothing. Only the camera ray's alpha is used.
+    fn ray_color(&mut self, scene: &Scene, ray: &Ray, ray_t: Interval, depth: PathDepth) -> (Vec3, Float) {
+        // Exceeded bounce limit, so no more light is gathered.
+        if depth.total <= 0 {
+            self.stats.depth_limit_hits += 1;
+            return (Vec3::zero(), 1.0);
+        }
+        if depth.total < self.settings.max_depth {
+            self.stats.bounce_rays += 1;
+        }
+
+        let (hit_rec, tests, visits) = scene.hit(ray, ray_t);
+        self.stats.object_tests += tests;
+        self.stats.bvh_node_visits += visits;
+        if !hit_rec.is_none() {
+            let mut scattered: Ray = Ray::new(Vec3::zero(), Vec3::zero());
+            let mut attenuation: Vec3 = Vec3::zero();
+            let hit_rec = hit_rec.unwrap();
+            let mat = scene.material_for_hit(hit_rec.mat_id);
+            if !hit_rec.front_face && scene.get_back_face(hit_rec.mat_id) == BackFace::Black {
+                return (Vec3::zero(), 1.0)
+            }
+
+            // Cut out surfaces let the ray through, as do partially transparent ones some of the time,
+            // which blends them over the samples. Passing through counts as a bounce so that stacks of
+            // cutouts such as foliage stay bounded.
+            let opacity = mat.opacity(ray, &hit_rec);
+            if opacity < 1.0 && self.rand_gen.next01() >= opacity {
+                let mut through = Ray::new(hit_rec.p, ray.direction);
+                through.cone_width = ray.cone_width_at(hit_rec.t);
+                through.cone_spread = ray.cone_spread;
+                through.wavelength = ray.wavelength;
+                through.scatter_pdf = ray.scatter_pdf;
+                through.kind = ray.kind;
+                // The rest of the range continues from the hit, so camera rays keep to the far clip.
+                let through_t = Interval::new(0.001, ray_t.max - hit_rec.t);
+                return self.ray_color(scene, &through, through_t, PathDepth{total: depth.total - 1, ..depth})
+            }
+
+            let direct = self.direct_light(scene, ray, &hit_rec, mat);
+            if mat.scatter(ray, &hit_rec, &mut attenuation, &mut scattered, &mut self.rand_gen) {
+                // The cone continues from its width at the hit. Widening by rough surfaces is ignored.
+                scattered.cone_width = ray.cone_width_at(hit_rec.t);
+                scattered.cone_spread = ray.cone_spread;
+                // Once picked, the wavelength stays with the path.
+                if scattered.wavelength == 0.0 {
+                    scattered.wavelength = ray.wavelength;
+                }
+                // Only the diffuse parts of materials give the direction a density.
+                scattered.kind = if scattered.scatter_pdf > 0.0 {RayKind::Diffuse} else {RayKind::Specular};
+                let mut next = PathDepth{total: depth.total - 1, ..depth};
+                let left = if scattered.kind == RayKind::Diffuse {&mut next.diffuse} else {&mut next.specular};
+                if *left == 0 {
+                    self.stats.depth_limit_hits += 1;
+                    return (direct, 1.0)
+                }
+                *left -= 1;
+                let (color, _) = self.ray_color(scene, &scattered, Interval::new(0.001, Float::MAX), next);
+                return (direct + attenuation * color, 1.0)
+            }
+
+            return (direct, 1.0)
+        }
+
+        self.stats.escaped_rays += 1;
+        let background = scene.get_background().color(ray);
+        // Diffuse bounces out through a portal share what they find with the portal samples in direct_light.
+        if ray.scatter_pdf > 0.0 {
+            let portal_pdf = scene.portal_pdf(ray.origin, ray.direction.normalized());
+            if portal_pdf > 0.0 {
+                return (background * power_heuristic(ray.scatter_pdf, portal_pdf), 0.0)
+            }
+        }
+        return (background, 0.0)
+    }
+
+    // Light reaching the hit straight from the scene's lights. Every light is sampled, as scenes rarely have many.
+    fn direct_light(&mut self, scene: &Scene, ray: &Ray, hit_rec: &HitRecord, mat: &dyn Material) -> Vec3 {
+        let mut sum = Vec3::zero();
+        for light in scene.get_lights() {
+            let sample = match light.sample(hit_rec.p) {
+                Some(sample) => sample,
+                None => continue
+            };
+            let response = mat.eval(ray, hit_rec, sample.direction);
+            if response.x <= 0.0 && response.y <= 0.0 && response.z <= 0.0 {
+                continue;
+            }
+            if self.is_shadowed(scene, hit_rec.p, sample.direction, sample.distance) {
+                continue;
+            }
+            sum += response * sample.irradiance;
+        }
+        if !scene.get_portals().is_empty() {
+            sum += self.portal_light(scene, ray, hit_rec, mat);
+        }
+        sum
+    }
+
+    // Background seen through one of the scene's portals, picked at random.
+    fn portal_light(&mut self, scene: &Scene, ray: &Ray, hit_rec: &HitRecord, mat: &dyn Material) -> Vec3 {
+        let portals = scene.get_portals();
+        let index = ((self.rand_gen.next01() * portals.len() as Float) as usize).min(portals.len() - 1);
+        let direction = match portals[index].sample(hit_rec.p, &mut self.rand_gen) {
+            Some(direction) => direction,
+            None => return Vec3::zero()
+        };
+        let response = mat.eval(ray, hit_rec, direction);
+        if response.x <= 0.0 && response.y <= 0.0 && response.z <= 0.0 {
+            return Vec3::zero()
+        }
+        // Portals can overlap as seen from the hit, so the density is that of all of them together.
+        let portal_pdf = scene.portal_pdf(hit_rec.p, direction);
+        if portal_pdf <= 0.0 || self.is_shadowed(scene, hit_rec.p, direction, Float::INFINITY) {
+            return Vec3::zero()
+        }
+        let weight = power_heuristic(portal_pdf, mat.pdf(ray, hit_rec, direction));
+        let background = scene.get_background().color(&Ray::new(hit_rec.p, direction));
+        response * background * (weight / portal_pdf)
+    }
+
+    // Whether anything blocks the way towards a light. Cut out and partially transparent surfaces let
+    // the shadow ray through as they would let other rays through.
+    fn is_shadowed(&mut self, scene: &Scene, origin: Vec3, direction: Vec3, distance: Float) -> bool {
+        let mut shadow_ray = Ray::new(origin, direction);
+        shadow_ray.kind = RayKind::Shadow;
+        let mut remaining = distance;
+        for _ in 0..self.settings.max_depth {
+            self.stats.shadow_rays += 1;
+            let (hit_rec, tests, visits) = scene.hit(&shadow_ray, Interval::new(0.001, remaining - 0.001));
+            self.stats.object_tests += tests;
+            self.stats.bvh_node_visits += visits;
+            let hit_rec = match hit_rec {
+                Some(hit_rec) => hit_rec,
+                None => return false
+            };
+            let opacity = scene.material_for_hit(hit_rec.mat_id).opacity(&shadow_ray, &hit_rec);
+            if opacity >= 1.0 || self.rand_gen.next01() < opacity {
+                return true
+            }
+            shadow_ray.origin = hit_rec.p;
+            remaining -= hit_rec.t;
+        }
+        true
+    }
+
+    // Distance along the view direction to the nearest surface in the middle of the image, seen by a ray through
+    // it and four around it, so that a small gap in the middle doesn't focus on the background.
+    pub fn autofocus_distance(scene: &Scene) -> Option<Float> {
+        let camera = scene.get_camera();
+        let spread = AUTOFOCUS_SPREAD;
+        [(0.0, 0.0), (-spread, 0.0), (spread, 0.0), (0.0, -spread), (0.0, spread)].iter()
+            .filter_map(|(du, dv)| scene.hit(&camera.get_ray(0.5 + du, 0.5 + dv), camera.get_clip_range()).0)
+            .map(|hit_rec| camera.depth_of(hit_rec.p))
+            .min_by(|a, b| a.total_cmp(b))
+    }
+
+    // Distance along the view direction to the surface seen through the center of a pixel, to focus on it.
+    pub fn focus_distance_at(&self, scene: &Scene, x: u32, y: u32) -> Option<Float> {
+        if x >= self.width || y >= self.height {
+            return None
+        }
+        let camera = scene.get_camera();
+        let ray = self.camera_ray(scene, (x as Float) + 0.5, (y as Float) + 0.5, None);
+        scene.hit(&ray, camera.get_clip_range()).0.map(|hit_rec| camera.depth_of(hit_rec.p))
+    }
+
+    pub fn pixel_info(&self, x: u32, y: u32) -> Option<PixelInfo> {
+        if x >= self.width || y >= self.height {
+            return None
+        }
+
+        let samples = self.sample_counts[(y * self.width + x) as usize];
+        let (pixel_color, alpha) = self.pixels.get((y * self.width + x) as usize, samples);
+
+        Some(PixelInfo {
+            linear: pixel_color / (samples.max(1) as Float),
+            alpha: alpha / (samples.max(1) as Float),
+            display: self.get_final_rgb(&pixel_color, samples),
+            samples: samples,
+            cost: self.pixel_costs[(y * self.width + x) as usize],
+            bvh_visits: self.pixel_visits[(y * self.width + x) as usize],
+            leaf_depth: self.pixel_leaf_depths[(y * self.width + x) as usize],
+            distance: self.pixel_distances[(y * self.width + x) as usize],
+            object_id: self.pixel_object_ids[(y * self.width + x) as usize].first(),
+            material_id: self.pixel_material_ids[(y * self.width + x) as usize].first()
+        })
+    }
+
+    // Counts and estimated memory of a scene, with the buffers of this ray tracer.
+    pub fn scene_stats(&self, scene: &Scene) -> SceneStats {
+        SceneStats {
+            framebuffer_memory: self.framebuffer_memory(),
+            ..scene.stats()
+        }
+    }
+
+    // Approximate bytes used by the buffers, including a stashed one.
+    fn framebuffer_memory(&self) -> usize {
+        let stash = self.compare_stash.as_ref().map_or(0, |stash| {
+            stash.pixels.memory_usage() + stash.sample_counts.len() * std::mem::size_of::<u32>()
+        });
+        self.pixels.memory_usage()
+            + self.sample_counts.len() * std::mem::size_of::<u32>()
+            + self.pixel_costs.len() * std::mem::size_of::<u32>()
+            + self.pixel_visits.len() * std::mem::size_of::<u32>()
+            + self.pixel_leaf_depths.len() * std::mem::size_of::<Option<u32>>()
+            + self.pixel_distances.len() * std::mem::size_of::<Option<Float>>()
+            + (self.pixel_object_ids.len() + self.pixel_material_ids.len()) * std::mem::size_of::<IdCoverage>()
+            + self.overlay_pixels.len() * std::mem::size_of::<Option<(u8, u8, u8)>>()
+            + stash
+    }
+
+    // Luminance histogram of the render region, with the given number of bins.
+    pub fn histogram(&self, bins: u32) -> Histogram {
+        let mut histogram = Histogram {
+            bins: vec![0; bins.max(1) as usize],
+            under: 0,
+            over: 0
+        };
+        let region = self.render_region();
+        for y in region.y..(region.y + region.height) {
+            for x in region.x..(region.x + region.width) {
+                let (luminance, exposure) = self.exposure_of((y * self.width + x) as usize);
+                let bin = histogram.bin_of(luminance);
+                histogram.bins[bin] += 1;
+                match exposure {
+                    Exposure::Under => histogram.under += 1,
+                    Exposure::Normal => {}
+                    Exposure::Over => histogram.over += 1
+                }
+            }
+        }
+        histogram
+    }
+
+    // Luminance of a pixel after the white balance, and whether it is shown as black or clipped.
+    fn exposure_of(&self, idx: usize) -> (Float, Exposure) {
+        let samples = self.sample_counts[idx];
+        let (pixel_color, _) = self.pixels.get(idx, samples);
+        let luminance = color::luminance(&(pixel_color / (samples.max(1) as Float) * self.white_gains() * self.exposure_gain()));
+        let display = self.get_display_color(&pixel_color, samples);
+        let peak = display.x.max(display.y).max(display.z);
+        let exposure = if peak >= 1.0 {
+            Exposure::Over
+        } else if peak < 0.5 / 255.0 {
+            Exposure::Under
+        } else {
+            Exposure::Normal
+        };
+        (luminance, exposure)
+    }
+
+    fn get_final_rgb(&self, pixel_color: &Vec3, samples: u32) -> (u8, u8, u8) {
+        to_bytes(self.get_display_color(pixel_color, samples))
+    }
+
+    // Divide the color by the number of samples, white balance and expose it, and encode it in the output color space.
+    fn get_display_color(&self, pixel_color: &Vec3, samples: u32) -> Vec3 {
+        let color = *pixel_color / (samples.max(1) as Float);
+        let balanced = if self.settings.white_balance.is_neutral() && !self.settings.auto_white_balance {
+            color
+        } else {
+            color * self.white_gains()
+        };
+        self.settings.color_space.encode(&(balanced * self.exposure_gain()))
+    }
+
+    fn exposure_gain(&self) -> Float {
+        let physical = self.settings.physical_exposure.map_or(1.0, |physical| physical.gain());
+        (2.0 as Float).powf(self.settings.exposure) * physical
+    }
+
+    // Per channel factors of the white balance settings.
+    pub fn white_gains(&self) -> Vec3 {
+        let gains = self.settings.white_balance.gains();
+        if self.settings.auto_white_balance {
+            gains * color::gray_world_gains(&self.environment_average)
+        } else {
+            gains
+        }
+    }
+
+    // As get_final_rgb, plus alpha.
+    fn get_final_rgba(&self, pixel_color: &Vec3, pixel_alpha: Float, samples: u32) -> (u8, u8, u8, u8) {
+        let (color, alpha) = self.get_display_rgba(pixel_color, pixel_alpha, samples);
+        let (r_value, g_value, b_value) = to_bytes(color);
+        (r_value, g_value, b_value, (255.0 * alpha).round() as u8)
+    }
+
+    // As get_display_color, plus alpha. With a transparent background, the colors are divided by the alpha
+    // (they were only gathered where it is non-zero), so that they can be blended by it. Otherwise the
+    // background is part of the image, and it is opaque.
+    fn get_display_rgba(&self, pixel_color: &Vec3, pixel_alpha: Float, samples: u32) -> (Vec3, Float) {
+        if !self.settings.transparent_background {
+            return (self.get_display_color(pixel_color, samples), 1.0)
+        }
+
+        let alpha = Interval::new(0.0, 1.0).clamp(pixel_alpha / (samples.max(1) as Float));
+        if alpha <= 0.0 {
+            return (Vec3::zero(), 0.0)
+        }
+        (self.get_display_color(&(*pixel_color / alpha), samples), alpha)
+    }
+
+    // The bytes of a buffer with the post effects applied, or None without any, in which case each pixel
+    // converts on its own. Bloom adds to the linear colors and the lens effects to the display colors.
+    // Without alpha, the image is opaque as with get_final_rgb. Pixel inspection and metrics look at the
+    // buffer without the effects.
+    fn final_image(&self, pixels: &AccumulationBuffer, sample_counts: &[u32], with_alpha: bool) -> Option<Vec<(u8, u8, u8, u8)>> {
+        if self.settings.bloom.is_none() && self.settings.lens.is_none() {
+            return None
+        }
+
+        let mut colors: Vec<Vec3> = (0..(self.width * self.height) as usize).map(|idx| {
+            pixels.get(idx, sample_counts[idx]).0 / (sample_counts[idx].max(1) as Float)
+        }).collect();
+        if let Some(bloom) = self.settings.bloom {
+            bloom.apply(&mut colors, self.width, self.height);
+        }
+
+        let mut alphas = vec![1.0; colors.len()];
+        for (idx, color) in colors.iter_mut().enumerate() {
+            if with_alpha {
+                let alpha = pixels.get(idx, sample_counts[idx]).1 / (sample_counts[idx].max(1) as Float);
+                let (display, alpha) = self.get_display_rgba(color, alpha, 1);
+                *color = display;
+                alphas[idx] = alpha;
+            } else {
+                *color = self.get_display_color(color, 1);
+            }
+        }
+        if let Some(lens) = self.settings.lens {
+            lens.apply(&mut colors, self.width, self.height, self.frame);
+        }
+
+        Some(colors.iter().zip(alphas.iter()).map(|(color, alpha)| {
+            let (r_value, g_value, b_value) = to_bytes(*color);
+            (r_value, g_value, b_value, (255.0 * alpha).round() as u8)
+        }).collect())
+    }
+
+    // Computes error metrics of the current buffer against a reference. Both are compared as displayed.
+    pub fn compare_to(&self, reference: Reference) -> Result<ImageMetrics> {
+        let reference_pixels = match reference {
+            Reference::File(path) => {
+                let image = Image::read_ppm(path)?;
+                if (image.width != self.width) || (image.height != self.height) {
+                    return Err(RaytracerError::SizeMismatch {
+                        expected: (self.width, self.height),
+                        actual: (image.width, image.height)
+                    })
+                }
+                image.pixels
+            }
+
+            Reference::Buffer(buffer) => {
+                if buffer.len() != (self.width * self.height * 3) as usize {
+                    // Report the size in pixels. Partial rows are rounded down.
+                    let buffer_pixels = (buffer.len() as u32) / 3;
+                    return Err(RaytracerError::SizeMismatch {
+                        expected: (self.width, self.height),
+                        actual: (self.width, buffer_pixels / self.width)
+                    })
+                }
+                let mut pixels = Vec::with_capacity(buffer.len());
+                for rgb in buffer.chunks(3) {
+                    let (r_value, g_value, b_value) = self.get_final_rgb(&Vec3::new(rgb[0], rgb[1], rgb[2]), 1);
+                    pixels.push((r_value as f32) / 255.0);
+                    pixels.push((g_value as f32) / 255.0);
+                    pixels.push((b_value as f32) / 255.0);
+                }
+                pixels
+            }
+        };
+
+        Ok(metrics::compute(&self.display_values(), &reference_pixels, self.width, self.height))
+    }
+
+    // Displayed RGB of every pixel in [0, 1], as the metrics expect.
+    fn display_values(&self) -> Vec<f32> {
+        let mut values = Vec::with_capacity((self.width * self.height * 3) as usize);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let info = self.pixel_info(x, y).unwrap();
+                values.push((info.display.0 as f32) / 255.0);
+                values.push((info.display.1 as f32) / 255.0);
+                values.push((info.display.2 as f32) / 255.0);
+            }
+        }
+        values
+    }
+
+    // Renders the frame at 1, 4, 16, ... samples per pixel up to the most, which is always the last stage,
+    // calling back with each one. Every stage adds to the one before, so the ladder costs no more than its
+    // last stage, and how much each stage changed the image is logged to show where more samples stop paying.
+    pub fn run_ladder<F>(&mut self, scene: &Scene, max_samples: u32, mut on_stage: F) -> Result<()>
+        where F: FnMut(&RSRaytracer, u32) -> Result<()>
+    {
+        info!(target: "render", "Starting quality ladder up to {} samples per pixel...", max_samples);
+        let start_time = std::time::Instant::now();
+        self.progressive = false;
+        let mut previous: Option<Vec<f32>> = None;
+        let mut samples = 0;
+        for stage in ladder_stages(max_samples) {
+            self.run_pass(scene, stage - samples);
+            if self.limit_reached {
+                // The stage is incomplete, so it isn't passed on.
+                info!(target: "render", "Quality ladder stopped at its limit during the {} samples per pixel stage.", stage);
+                break;
+            }
+            samples = stage;
+            let values = self.display_values();
+            match &previous {
+                Some(previous) => info!(
+                    target: "metrics", "{} samples per pixel after {:?}. Change from the last stage: {}",
+                    stage, start_time.elapsed(), metrics::compute(&values, previous, self.width, self.height)
+                ),
+                None => info!(target: "metrics", "{} samples per pixel after {:?}.", stage, start_time.elapsed())
+            }
+            previous = Some(values);
+            on_stage(self, stage)?;
+        }
+        Ok(())
+    }
+
+    pub fn save_as_ppm(&self) -> Result<()> {
+        let job = self.ppm_job();
+        info!(target: "io", "Writing PPM file {}...", job.path);
+        job.write()
+    }
+
+    // The final image to write to the PPM file, for writing later or elsewhere (see saver.rs).
+    pub fn ppm_job(&self) -> SaveJob {
+        let image = self.final_image(&self.pixels, &self.sample_counts, false);
+        let mut data = Vec::with_capacity((self.width * self.height * 3) as usize);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (r_value, g_value, b_value) = match &image {
+                    Some(image) => {
+                        let (r_value, g_value, b_value, _) = image[(y * self.width + x) as usize];
+                        (r_value, g_value, b_value)
+                    }
+                    None => {
+                        let samples = self.sample_counts[(y * self.width + x) as usize];
+                        let (pixel_color, _) = self.pixels.get((y * self.width + x) as usize, samples);
+                        self.get_final_rgb(&pixel_color, samples)
+                    }
+                };
+                data.extend_from_slice(&[r_value, g_value, b_value]);
+            }
+        }
+        if self.settings.stamp {
+            stamp::stamp(&mut data, self.width, self.height, 3, &self.stamp_lines());
+        }
+        SaveJob {
+            path: self.output_path(PPM_OUT),
+            format: SaveFormat::Ppm,
+            width: self.width,
+            height: self.height,
+            data: data
+        }
+    }
+
+    // The final image as 8-bit RGBA in rows from the top, as saved to PNG or copied to the clipboard, with the
+    // stamp if the settings ask for it.
+    pub fn rgba_image(&self) -> Vec<u8> {
+        let image = self.final_image(&self.pixels, &self.sample_counts, true);
+        let mut data = Vec::with_capacity((self.width * self.height * 4) as usize);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (r_value, g_value, b_value, a_value) = match &image {
+                    Some(image) => image[(y * self.width + x) as usize],
+                    None => {
+                        let samples = self.sample_counts[(y * self.width + x) as usize];
+                        let (pixel_color, alpha) = self.pixels.get((y * self.width + x) as usize, samples);
+                        self.get_final_rgba(&pixel_color, alpha, samples)
+                    }
+                };
+                data.extend_from_slice(&[r_value, g_value, b_value, a_value]);
+            }
+        }
+        if self.settings.stamp {
+            stamp::stamp(&mut data, self.width, self.height, 4, &self.stamp_lines());
+        }
+        data
+    }
+
+    // The scene, the samples per pixel and time of the current render, and the resolution.
+    fn stamp_lines(&self) -> Vec<String> {
+        vec![
+            self.scene_name.clone(),
+            format!("{:.1} spp in {:.1}s", self.average_samples(), self.render_time.as_secs_f64()),
+            format!("{}x{}", self.width, self.height)
+        ]
+    }
+
+    // Writes an 8-bit RGBA PNG. The alpha channel is only transparent with a transparent background.
+    pub fn save_as_png(&self) -> Result<()> {
+        self.save_as_png_to(&self.output_path(PNG_OUT))
+    }
+
+    pub fn save_as_png_to(&self, path: &str) -> Result<()> {
+        info!(target: "io", "Writing PNG file {}...", path);
+        self.png_job(path).write()
+    }
+
+    // The final image to write to a PNG file, for writing later or elsewhere (see saver.rs).
+    pub fn png_job(&self, path: &str) -> SaveJob {
+        SaveJob {
+            path: path.to_string(),
+            format: SaveFormat::Png,
+            width: self.width,
+            height: self.height,
+            data: self.rgba_image()
+        }
+    }
+
+    // Saves the image once for each number of stops away from the current exposure, so that the best one can
+    // be picked without rendering again. Returns the paths written.
+    pub fn save_exposure_bracket(&mut self, stops: &[Float]) -> Result<Vec<String>> {
+        let mut paths = Vec::with_capacity(stops.len());
+        for job in self.exposure_bracket_jobs(stops) {
+            job.write()?;
+            paths.push(job.path);
+        }
+        Ok(paths)
+    }
+
+    // The PNG files of an exposure bracket, for writing later or elsewhere.
+    pub fn exposure_bracket_jobs(&mut self, stops: &[Float]) -> Vec<SaveJob> {
+        let exposure = self.settings.exposure;
+        let jobs = stops.iter().map(|stop| {
+            let path = self.output_path(&format!("{}_{:+}ev.png", BRACKET_OUT, stop));
+            self.settings.exposure = exposure + stop;
+            self.png_job(&path)
+        }).collect();
+        self.settings.exposure = exposure;
+        jobs
+    }
+
+    // Linear red, green, blue, and alpha of every pixel in rows from the top left, white balanced and exposed
+    // as when saved but not encoded, for float images such as EXR. The colors are premultiplied by the alpha.
+    pub fn linear_channels(&self) -> [Vec<f32>; 4] {
+        let gains = self.white_gains() * self.exposure_gain();
+        let mut channels = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
+        for channel in channels.iter_mut() {
+            channel.reserve((self.width * self.height) as usize);
+        }
+        for (idx, samples) in self.sample_counts.iter().enumerate() {
+            let (pixel_color, alpha) = self.pixels.get(idx, *samples);
+            let color = pixel_color / ((*samples).max(1) as Float) * gains;
+            let alpha = if self.settings.transparent_background {alpha / ((*samples).max(1) as Float)} else {1.0};
+            channels[0].push(color.x as f32);
+            channels[1].push(color.y as f32);
+            channels[2].push(color.z as f32);
+            channels[3].push(alpha as f32);
+        }
+        channels
+    }
+
+    // Near and far clips of the depth pass, from the settings or else the range of distances in the buffer.
+    pub fn depth_clips(&self) -> (Float, Float) {
+        if let Some(clips) = self.settings.depth_clips {
+            return clips
+        }
+        let mut clips: (Float, Float) = (Float::MAX, 0.0);
+        for distance in self.pixel_distances.iter().flatten() {
+            clips = (clips.0.min(*distance), clips.1.max(*distance));
+        }
+        if clips.0 > clips.1 {(0.0, 1.0)} else {clips}
+    }
+
+    // Depth pass values in rows from the top left, encoded between the clips.
+    pub fn depth_values(&self, encoding: DepthEncoding) -> Vec<Float> {
+        let (near, far) = self.depth_clips();
+        self.pixel_distances.iter().map(|distance| {
+            let distance = distance.unwrap_or(far).clamp(near, far);
+            match encoding {
+                DepthEncoding::Linear => distance,
+                DepthEncoding::Normalized => if far > near {(distance - near) / (far - near)} else {0.0}
+            }
+        }).collect()
+    }
+
+    // Writes the depth pass as a normalized 16-bit grayscale PNG.
+    pub fn save_depth_as_png(&self) -> Result<()> {
+        let path = self.output_path(DEPTH_PNG_OUT);
+        info!(target: "io", "Writing depth PNG file {}...", path);
+        let mut data = Vec::with_capacity((self.width * self.height * 2) as usize);
+        for value in self.depth_values(DepthEncoding::Normalized) {
+            data.extend_from_slice(&((value * 65535.0).round() as u16).to_be_bytes());
+        }
+
+        let f = File::create(&path)?;
+        let mut encoder = png::Encoder::new(BufWriter::new(f), self.width, self.height);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Sixteen);
+        let mut writer = encoder.write_header().map_err(std::io::Error::from)?;
+        writer.write_image_data(&data).map_err(std::io::Error::from)?;
+        debug!(target: "io", "Finished writing {}.", path);
+
+        Ok(())
+    }
+
+    // Writes the depth pass as a single channel (Z) float EXR, in the settings' encoding.
+    pub fn save_depth_as_exr(&self) -> Result<()> {
+        let path = self.output_path(DEPTH_EXR_OUT);
+        info!(target: "io", "Writing depth EXR file {}...", path);
+        let values: Vec<f32> = self.depth_values(self.settings.depth_encoding).iter().map(|value| *value as f32).collect();
+        let f = File::create(&path)?;
+        let mut writer = BufWriter::new(f);
+        image::write_exr(&mut writer, self.width, self.height, &[("Z", &values)])?;
+        writer.flush()?;
+        debug!(target: "io", "Finished writing {}.", path);
+
+        Ok(())
+    }
+
+    // Ids seen through each pixel in rows from the top left.
+    pub fn id_coverage(&self, pass: IdPass) -> &[IdCoverage] {
+        match pass {
+            IdPass::Object => &self.pixel_object_ids,
+            IdPass::Material => &self.pixel_material_ids
+        }
+    }
+
+    // Share of each pixel covered by an object or material, e.g. to grade it separately in compositing.
+    pub fn id_mask(&self, pass: IdPass, id: u32) -> Vec<Float> {
+        self.id_coverage(pass).iter().map(|ranked| ranked.of(id)).collect()
+    }
+
+    // Writes an ID pass as an EXR with the id (id0 to id3) and coverage (coverage0 to coverage3) of the
+    // objects or materials covering most of each pixel, in the spirit of Cryptomatte. Ids are stored as
+    // floats, which hold them exactly up to 2^24.
+    pub fn save_id_pass_as_exr(&self, pass: IdPass) -> Result<()> {
+        let path = self.output_path(match pass {
+            IdPass::Object => OBJECT_IDS_OUT,
+            IdPass::Material => MATERIAL_IDS_OUT
+        });
+        info!(target: "io", "Writing ID pass file {}...", path);
+        let ranked = self.id_coverage(pass);
+        let mut names = Vec::with_capacity(ID_RANKS * 2);
+        let mut values: Vec<Vec<f32>> = Vec::with_capacity(ID_RANKS * 2);
+        for rank in 0..ID_RANKS {
+            names.push(format!("id{}", rank));
+            values.push(ranked.iter().map(|pixel| pixel.ids[rank] as f32).collect());
+            names.push(format!("coverage{}", rank));
+            values.push(ranked.iter().map(|pixel| pixel.coverage[rank] as f32).collect());
+        }
+        let channels: Vec<(&str, &[f32])> = names.iter().zip(values.iter()).map(|(name, values)| (name.as_str(), values.as_slice())).collect();
+
+        let f = File::create(&path)?;
+        let mut writer = BufWriter::new(f);
+        image::write_exr(&mut writer, self.width, self.height, &channels)?;
+        writer.flush()?;
+        debug!(target: "io", "Finished writing {}.", path);
+
+        Ok(())
+    }
+}
+
+
+// Translate display RGB to [0, 255].
+fn to_bytes(color: Vec3) -> (u8, u8, u8) {
+    let intensity = Interval::new(0.0, 0.999);
+    (
+        (256.0 * intensity.clamp(color.x)) as u8,
+        (256.0 * intensity.clamp(color.y)) as u8,
+        (256.0 * intensity.clamp(color.z)) as u8
+    )
+}
+
+// Adds a sample of an id to the counts of a pixel.
+fn count_id(counts: &mut Vec<(u32, Float)>, id: u32) {
+    match counts.iter_mut().find(|(counted, _)| *counted == id) {
+        Some((_, count)) => *count += 1.0,
+        None => counts.push((id, 1.0))
+    }
+}
+
+// Samples per pixel at each stage of a quality ladder: powers of 4 below the most, then the most.
+fn ladder_stages(max_samples: u32) -> Vec<u32> {
+    let mut stages = Vec::new();
+    let mut samples = 1;
+    while samples < max_samples {
+        stages.push(samples);
+        samples *= 4;
+    }
+    stages.push(max_samples.max(1));
+    stages
+}
+
+// Lowers the operating system priority of the calling thread for low priority rendering, and returns its
+// niceness from before. On Linux this is the calling thread's, elsewhere the whole process's. Other systems
+// keep their priority, and return None as nothing was changed.
+#[cfg(unix)]
+fn lower_thread_priority() -> Option<i32> {
+    // Asking for the calling thread can't fail, so a -1 is a niceness rather than an error.
+    let niceness = unsafe { libc::getpriority(libc::PRIO_PROCESS as _, 0) };
+    if set_thread_niceness((niceness + LOW_PRIORITY_NICENESS).min(19)) {Some(niceness)} else {None}
+}
+
+#[cfg(not(unix))]
+fn lower_thread_priority() -> Option<i32> {
+    None
+}
+
+// Sets the niceness of the calling thread, where 19 is the lowest priority. Returns whether it was set.
+#[cfg(unix)]
+fn set_thread_niceness(niceness: i32) -> bool {
+    unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, niceness) == 0 }
+}
+
+#[cfg(not(unix))]
+fn set_thread_niceness(_niceness: i32) -> bool {
+    true
+}
+
+// Weight for light found with density a by one strategy that another finds with density b (Veach's power
+// heuristic). The two weights add up to one, so light found both ways is counted once.
+fn power_heuristic(a: Float, b: Float) -> Float {
+    let (a2, b2) = (a * a, b * b);
+    if a2 + b2 <= 0.0 {
+        return 0.0
+    }
+    a2 / (a2 + b2)
+}
+
+// Scales an image of RGBA pixels down into an RGBA buffer of the target size with a box filter: each target pixel
+// averages the source pixels under it, weighted by the area of each it covers. Works for any ratio of sizes.
+fn box_downsample(source: &[(u8, u8, u8, u8)], width: u32, height: u32, target: &mut [u8], pitch: usize, target_width: u32, target_height: u32) {
+    // Source pixels a target pixel spans along one axis, with how much of each it covers (in source pixels).
+    let spans = |size: u32, target_size: u32| -> Vec<Vec<(u32, Float)>> {
+        let scale = (size as Float) / (target_size as Float);
+        (0..target_size).map(|target_pos| {
+            let (start, end) = ((target_pos as Float) * scale, ((target_pos + 1) as Float) * scale);
+            let first = start.floor() as u32;
+            let last = (end.ceil() as u32).min(size);
+            (first..last).map(|pos| {
+                let cover = end.min((pos + 1) as Float) - start.max(pos as Float);
+                (pos, cover)
+            }).filter(|(_, cover)| *cover > 0.0).collect()
+        }).collect()
+    };
+    let columns = spans(width, target_width);
+    let rows = spans(height, target_height);
+
+    for (target_y, row) in rows.iter().enumerate() {
+        for (target_x, column) in columns.iter().enumerate() {
+            let mut sum = [0.0; 4];
+            let mut area = 0.0;
+            for (y, cover_y) in row.iter() {
+                for (x, cover_x) in column.iter() {
+                    let (r_value, g_value, b_value, a_value) = source[(y * width + x) as usize];
+                    let weight = cover_x * cover_y;
+                    for (channel, value) in [r_value, g_value, b_value, a_value].iter().enumerate() {
+                        sum[channel] += (*value as Float) * weight;
+                    }
+                    area += weight;
+                }
+            }
+            let offset = target_y * pitch + target_x * 4;
+            for channel in 0..4 {
+                target[offset + channel] = (sum[channel] / area.max(1e-9)).round().min(255.0) as u8;
+            }
+        }
+    }
+}
+
+// Draws a histogram as bars over a darkened panel in the lower left corner of an RGBA buffer, with the
+// display's white marked in yellow, and the shares of black and clipped pixels as blue and red bars beside it.
+fn draw_histogram(buffer: &mut [u8], pitch: usize, buffer_height: u32, histogram: &Histogram) {
+    const BAR_WIDTH: u32 = 2;
+    const PANEL_HEIGHT: u32 = 96;
+    const MARGIN: u32 = 8;
+    let bins = histogram.bins.len() as u32;
+    let panel_width = bins * BAR_WIDTH + 4 * BAR_WIDTH;
+    if buffer_height < MARGIN + PANEL_HEIGHT || (pitch as u32) < (MARGIN + panel_width) * 4 {
+        return
+    }
+    let (left, top) = (MARGIN, buffer_height - MARGIN - PANEL_HEIGHT);
+    let tallest = histogram.bins.iter().copied().max().unwrap_or(0).max(1);
+    let total = histogram.bins.iter().sum::<u32>().max(1) as Float;
+    let white_bin = histogram.bin_of(1.0) as u32;
+
+    for y in 0..PANEL_HEIGHT {
+        for x in 0..panel_width {
+            let offset = ((top + y) * (pitch as u32) + (left + x) * 4) as usize;
+            let height = (PANEL_HEIGHT - y) as Float / (PANEL_HEIGHT as Float);
+            let bin = x / BAR_WIDTH;
+            let color = if bin < bins {
+                let share = (histogram.bins[bin as usize] as Float) / (tallest as Float);
+                if share >= height && share > 0.0 {
+                    Some((230, 230, 230))
+                } else if bin == white_bin && x % BAR_WIDTH == 0 {
+                    Some((255, 220, 0))
+                } else {
+                    None
+                }
+            } else if bin == bins + 1 && (histogram.under as Float) / total >= height && histogram.under > 0 {
+                Some((0, 96, 255))
+            } else if bin == bins + 2 && (histogram.over as Float) / total >= height && histogram.over > 0 {
+                Some((255, 0, 0))
+            } else {
+                None
+            };
+            match color {
+                Some((r_value, g_value, b_value)) => {
+                    buffer[offset + 0] = r_value;
+                    buffer[offset + 1] = g_value;
+                    buffer[offset + 2] = b_value;
+                }
+                None => {
+                    for channel in 0..3 {
+                        buffer[offset + channel] /= 3;
+                    }
+                }
+            }
+            buffer[offset + 3] = 255;
+        }
+    }
+}
+
+// Luminance in stops from middle gray (18%) to the bands of a false color monitor: purple for black,
+// blue for the darkest shadows near black, green around middle gray, pink about a stop above it (skin),
+// yellow for highlights near white, and red for clipped. Everything else is gray at its display brightness.
+fn false_color(luminance: Float, exposure: Exposure, color_space: ColorSpace) -> (u8, u8, u8) {
+    let stops = luminance.max(1e-12).log2() - (0.18 as Float).log2();
+    match exposure {
+        Exposure::Under => return (128, 0, 160),
+        Exposure::Over => return (255, 0, 0),
+        Exposure::Normal => {}
+    }
+    if stops < -5.0 {
+        (0, 64, 255)
+    } else if stops.abs() <= 0.3 {
+        (0, 200, 64)
+    } else if (0.7..=1.3).contains(&stops) {
+        (255, 140, 190)
+    } else if stops >= 2.0 {
+        (255, 230, 0)
+    } else {
+        let gray = color_space.encode(&(Vec3::one() * luminance)).y;
+        let (value, _, _) = to_bytes(Vec3::one() * gray);
+        (value, value, value)
+    }
+}
+
+// Maps [0, 1] to a blue -> cyan -> green -> yellow -> red ramp.
+fn heatmap_color(t: Float) -> (u8, u8, u8) {
+    let t = t.clamp(0.0, 1.0) * 4.0;
+    let (r, g, b) = if t < 1.0 {
+        (0.0, t, 1.0)
+    } else if t < 2.0 {
+        (0.0, 1.0, 2.0 - t)
+    } else if t < 3.0 {
+        (t - 2.0, 1.0, 0.0)
+    } else {
+        (1.0, 4.0 - t, 0.0)
+    };
+    ((r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8)
+}
+
+
+// --------------------------------------------------
+// Camera
+// --------------------------------------------------
+#[derive(Clone, Copy)]
+pub struct Camera {
+    origin: Vec3,
+    lower_left_corner: Vec3,
+    horizontal: Vec3,
+    vertical: Vec3,
+    near: Float, // Camera rays only see surfaces between the clip distances along the view direction.
+    far: Float,
+    focus_distance: Float, // Along the view direction, of the plane that is sharp through a lens.
+    distortion: (Float, Float), // Radial lens distortion coefficients k1 and k2, as in OpenCV's and Brown's model.
+    shift: (Float, Float), // Of the image across the lens, in image widths and heights, right and up.
+    tilt: (Float, Float) // Of the plane of focus about the right and up directions, in degrees.
+}
+
+impl Camera {
+    pub fn new(
+        look_from: Vec3, look_at: Vec3, up: Vec3,
+        vertical_fov: Float, aspect_ratio: Float
+    ) -> Camera {
+        let theta = vertical_fov * 0.01745329; // Convert to radians.
+        let h = (theta * 0.5).tan();
+        let viewport_height = 2.0 * h;
+        let viewport_width = aspect_ratio * viewport_height;
+
+        let w = (look_from - look_at).normalized();
+        let u = up.cross(&w).normalized();
+        let v = w.cross(&u);
+
+        let origin = look_from;
+        let horizontal = viewport_width * u;
+        let vertical = viewport_height * v;
+        let lower_left_corner = origin - (horizontal * 0.5) - (vertical * 0.5) - w;
+
+        Camera {
+            origin: origin,
+            lower_left_corner: lower_left_corner,
+            horizontal: horizontal,
+            vertical: vertical,
+            near: 0.001,
+            far: Float::MAX,
+            focus_distance: (look_from - look_at).length(),
+            distortion: (0.0, 0.0),
+            shift: (0.0, 0.0),
+            tilt: (0.0, 0.0)
+        }
+    }
+
+    // From an orthonormal frame, for cameras that are not defined by a look-at (e.g. mirrored ones). Focused at
+    // infinity.
+    pub fn from_frame(
+        origin: Vec3, right: Vec3, up: Vec3, forward: Vec3,
+        vertical_fov: Float, aspect_ratio: Float
+    ) -> Camera {
+        let theta = vertical_fov * 0.01745329; // Convert to radians.
+        let viewport_height = 2.0 * (theta * 0.5).tan();
+        let viewport_width = aspect_ratio * viewport_height;
+
+        let horizontal = viewport_width * right;
+        let vertical = viewport_height * up;
+        let lower_left_corner = origin - (horizontal * 0.5) - (vertical * 0.5) + forward;
+
+        Camera {
+            origin: origin,
+            lower_left_corner: lower_left_corner,
+            horizontal: horizontal,
+            vertical: vertical,
+            near: 0.001,
+            far: Float::MAX,
+            focus_distance: Float::INFINITY,
+            distortion: (0.0, 0.0),
+            shift: (0.0, 0.0),
+            tilt: (0.0, 0.0)
+        }
+    }
+
+    // Distance of a point along the view direction, where the image plane is at 1. Negative behind the camera.
+    pub fn depth(&self, p: Vec3) -> Float {
+        let normal = self.horizontal.cross(&self.vertical);
+        (p - self.origin).dot(&normal) / (self.lower_left_corner - self.origin).dot(&normal)
+    }
+
+    // The (u, v) for which get_ray passes through a point in front of the camera. Points off screen
+    // are outside of [0, 1].
+    pub fn project(&self, p: Vec3) -> Option<(Float, Float)> {
+        let depth = self.depth(p);
+        if depth <= 0.0 {
+            return None
+        }
+        let on_plane = (p - self.origin) / depth - (self.lower_left_corner - self.origin);
+        let (u, v) = (
+            on_plane.dot(&self.horizontal) / self.horizontal.dot(&self.horizontal),
+            on_plane.dot(&self.vertical) / self.vertical.dot(&self.vertical)
+        );
+        let (u, v) = if self.distortion == (0.0, 0.0) {(u, v)} else {
+            let (x, y) = self.plane_offset(u, v);
+            let scale = self.distortion_scale(x * x + y * y);
+            (0.5 + (u - 0.5) * scale, 0.5 + (v - 0.5) * scale)
+        };
+        Some((u - self.shift.0, v - self.shift.1))
+    }
+
+    pub fn get_distortion(&self) -> (Float, Float) {
+        self.distortion
+    }
+
+    // Bends straight lines as a real lens would, to match footage for compositing. Given as the coefficients k1
+    // and k2 of the radial model used by OpenCV and most camera calibration tools, in which a point at distance
+    // r from the center of the undistorted image plane (at distance 1) ends up at r * (1 + k1 r² + k2 r⁴).
+    // Negative values give barrel distortion, and positive ones pincushion distortion.
+    pub fn set_distortion(&mut self, k1: Float, k2: Float) {
+        self.distortion = (k1, k2);
+    }
+
+    pub fn get_shift(&self) -> (Float, Float) {
+        self.shift
+    }
+
+    // Moves the image across the lens, in image widths to the right and heights up, as a shift lens does. The
+    // view direction stays the same, so a level camera shifted up frames a tall building without its verticals
+    // converging. Lens distortion stays centered on the view direction.
+    pub fn set_shift(&mut self, right: Float, up: Float) {
+        self.shift = (right, up);
+    }
+
+    pub fn get_tilt(&self) -> (Float, Float) {
+        self.tilt
+    }
+
+    // Turns the plane of focus about its middle, at the focus distance along the view direction, as tilting a lens
+    // does. Positive angles bring its top (about the right direction) and right side (about the up direction) nearer.
+    // Lining the plane up with a surface keeps all of it sharp, and turning it across one keeps only a band sharp,
+    // which makes scenes look like miniatures.
+    pub fn set_tilt(&mut self, right: Float, up: Float) {
+        self.tilt = (right, up);
+    }
+
+    // Unit normal of the plane of focus, which faces along the view direction while it is not tilted.
+    fn focus_normal(&self) -> Vec3 {
+        let (right, up) = (self.horizontal.normalized(), self.vertical.normalized());
+        let (tilt_sin, tilt_cos) = self.tilt.0.to_radians().sin_cos();
+        let (swing_sin, swing_cos) = self.tilt.1.to_radians().sin_cos();
+        (self.get_forward() * swing_cos + right * swing_sin) * tilt_cos + up * tilt_sin
+    }
+
+    // Position on the image plane, at distance 1, relative to its center.
+    fn plane_offset(&self, u: Float, v: Float) -> (Float, Float) {
+        ((u - 0.5) * self.horizontal.length(), (v - 0.5) * self.vertical.length())
+    }
+
+    // Factor that distortion moves an undistorted point on the image plane away from its center by.
+    fn distortion_scale(&self, r2: Float) -> Float {
+        let (k1, k2) = self.distortion;
+        1.0 + k1 * r2 + k2 * r2 * r2
+    }
+
+    // The undistorted (u, v) that distortion moves to a position in the image. Solved by Newton's method, which
+    // stops where the distortion folds back on itself, far beyond the corners for realistic lenses.
+    fn undistorted(&self, u: Float, v: Float) -> (Float, Float) {
+        let (x, y) = self.plane_offset(u, v);
+        let distorted = (x * x + y * y).sqrt();
+        if distorted == 0.0 {
+            return (u, v)
+        }
+        let (k1, k2) = self.distortion;
+        let mut r = distorted;
+        for _ in 0..8 {
+            let r2 = r * r;
+            let slope = 1.0 + 3.0 * k1 * r2 + 5.0 * k2 * r2 * r2;
+            if slope <= 0.0 {
+                break
+            }
+            r -= (r * self.distortion_scale(r2) - distorted) / slope;
+        }
+        let scale = r / distorted;
+        (0.5 + (u - 0.5) * scale, 0.5 + (v - 0.5) * scale)
+    }
+
+    pub fn get_position(&self) -> Vec3 {
+        self.origin
+    }
+
+    // Unit vector along the middle of the view.
+    pub fn get_forward(&self) -> Vec3 {
+        (self.lower_left_corner + self.horizontal * 0.5 + self.vertical * 0.5 - self.origin).normalized()
+    }
+
+    // In degrees. The image plane is at distance 1, so its height gives the angle.
+    pub fn get_vertical_fov(&self) -> Float {
+        (2.0 * (self.vertical.length() * 0.5).atan()).to_degrees()
+    }
+
+    // The same camera turned to look at a target, keeping its position, field of view, and (as far as
+    // possible) its up direction. It is focused on the target.
+    pub fn looking_at(&self, target: Vec3) -> Camera {
+        let aspect_ratio = self.horizontal.length() / self.vertical.length();
+        Camera::new(self.origin, target, self.vertical.normalized(), self.get_vertical_fov(), aspect_ratio).with_lens_of(self)
+    }
+
+    pub fn get_focus_distance(&self) -> Float {
+        self.focus_distance
+    }
+
+    // Of a point along the view direction, as the focus distance is measured.
+    pub fn depth_of(&self, point: Vec3) -> Float {
+        (point - self.origin).dot(&self.get_forward())
+    }
+
+    // Of the plane that is sharp when rendering through a lens, along the view direction.
+    pub fn set_focus_distance(&mut self, distance: Float) {
+        self.focus_distance = distance.max(0.001);
+    }
+
+    // Moved along its own axes as with moved, but still looking at the target, e.g. to circle an object.
+    pub fn tracking(&self, right: Float, up: Float, forward: Float, target: Vec3) -> Camera {
+        self.moved(right, up, forward).looking_at(target)
+    }
+
+    // Moved forward by a distance while the field of view changes so that the plane through the target
+    // keeps its size in the image, and everything in front of and behind it is stretched or squashed (a
+    // dolly zoom). None if the target would end up behind the camera or the field of view would open too far.
+    pub fn dolly_zoom(&self, distance: Float, target: Vec3) -> Option<Camera> {
+        let forward = self.get_forward();
+        let depth = (target - self.origin).dot(&forward);
+        let new_depth = depth - distance;
+        if depth <= 0.0 || new_depth <= 0.001 {
+            return None
+        }
+        let half_height = (self.get_vertical_fov().to_radians() * 0.5).tan() * depth / new_depth;
+        let vertical_fov = (2.0 * half_height.atan()).to_degrees();
+        if vertical_fov > 170.0 {
+            return None
+        }
+        let aspect_ratio = self.horizontal.length() / self.vertical.length();
+        let mut zoomed = Camera::from_frame(
+            self.origin + forward * distance, self.horizontal.normalized(), self.vertical.normalized(), forward,
+            vertical_fov, aspect_ratio
+        ).with_lens_of(self);
+        zoomed.focus_distance = new_depth;
+        Some(zoomed)
+    }
+
+    // Turned about its view direction by an angle in degrees, keeping its position and field of view, e.g. for
+    // dutch angles. Positive angles tip its right side up, so the scene turns clockwise in the image.
+    pub fn rolled(&self, degrees: Float) -> Camera {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let (right, up) = (self.horizontal.normalized(), self.vertical.normalized());
+        let center = self.lower_left_corner + self.horizontal * 0.5 + self.vertical * 0.5;
+        let horizontal = (right * cos + up * sin) * self.horizontal.length();
+        let vertical = (up * cos - right * sin) * self.vertical.length();
+        Camera {
+            lower_left_corner: center - (horizontal * 0.5) - (vertical * 0.5),
+            horizontal: horizontal,
+            vertical: vertical,
+            ..*self
+        }
+    }
+
+    // In degrees, of the camera's right side above the horizon, as in rolled. 0 when the horizon is level, or
+    // when looking straight up or down.
+    pub fn get_roll(&self) -> Float {
+        let (right, up) = (self.horizontal.normalized(), self.vertical.normalized());
+        if right.y == 0.0 && up.y == 0.0 {0.0} else {right.y.atan2(up.y).to_degrees()}
+    }
+
+    // Rolled back so that the horizon is level, with +Y up in the image.
+    pub fn leveled(&self) -> Camera {
+        self.rolled(-self.get_roll())
+    }
+
+    // The same camera moved along its own right, up, and forward directions.
+    pub fn moved(&self, right: Float, up: Float, forward: Float) -> Camera {
+        let to_center = self.lower_left_corner + self.horizontal * 0.5 + self.vertical * 0.5 - self.origin;
+        let offset = self.horizontal.normalized() * right + self.vertical.normalized() * up + to_center.normalized() * forward;
+        Camera {
+            origin: self.origin + offset,
+            lower_left_corner: self.lower_left_corner + offset,
+            ..*self
+        }
+    }
+
+    // Range of t along camera rays between the clip distances. The image plane is at distance 1 along the view,
+    // so t is the distance along it as well.
+    pub fn get_clip_range(&self) -> Interval {
+        Interval::new(self.near, self.far)
+    }
+
+    // Surfaces nearer than near are cut away, e.g. to look inside objects, and those beyond far are left out.
+    pub fn set_clips(&mut self, near: Float, far: Float) {
+        self.near = near.max(0.001);
+        self.far = far.max(self.near);
+    }
+
+    // Takes the clips, distortion, shift, and tilt of another camera, which stay with it wherever it looks.
+    fn with_lens_of(mut self, other: &Camera) -> Camera {
+        self.near = other.near;
+        self.far = other.far;
+        self.distortion = other.distortion;
+        self.shift = other.shift;
+        self.tilt = other.tilt;
+        self
+    }
+
+    // The ray's cone covers one pixel at the default resolution.
+    pub fn get_ray(&self, u: Float, v: Float) -> Ray {
+        let (u, v) = (u + self.shift.0, v + self.shift.1);
+        let (u, v) = if self.distortion == (0.0, 0.0) {(u, v)} else {self.undistorted(u, v)};
+        let mut ray = Ray::new(self.origin, self.lower_left_corner + u*self.horizontal + v*self.vertical - self.origin);
+        ray.cone_spread = self.vertical.length() / (HEIGHT as Float);
+        ray.kind = RayKind::Camera;
+        ray
+    }
+
+    // As get_ray, but from a point of a thin lens of the radius, given along its right and up directions as on a
+    // lens of radius 1 (see Aperture::sample), so that only surfaces at the focus distance are sharp. The
+    // direction still reaches the image plane's depth at t = 1.
+    pub fn get_lens_ray(&self, u: Float, v: Float, lens_radius: Float, lens: (Float, Float)) -> Ray {
+        let mut ray = self.get_ray(u, v);
+        if lens_radius <= 0.0 {
+            return ray
+        }
+        // Rays from all over the lens meet where the ray through its center crosses the plane of focus. Parts of
+        // a tilted plane that the ray can't reach are as good as infinitely far.
+        let focus_t = if self.tilt == (0.0, 0.0) {self.focus_distance} else {
+            let normal = self.focus_normal();
+            let facing = ray.direction.dot(&normal);
+            if facing > 1e-6 {self.focus_distance * self.get_forward().dot(&normal) / facing} else {Float::INFINITY}
+        };
+        let offset = self.horizontal.normalized() * (lens.0 * lens_radius) + self.vertical.normalized() * (lens.1 * lens_radius);
+        ray.origin = self.origin + offset;
+        ray.direction = ray.direction - offset / focus_t;
+        ray
+    }
+}
+
+
+// --------------------------------------------------
+// Material(s)
+// --------------------------------------------------
+// https://stackoverflow.com/questions/30353462/how-to-clone-a-struct-storing-a-boxed-trait-object
+// pub trait Material: MaterialClone {
+//     fn scatter(&self, ray: &Ray, hit_rec: &HitRecord, out_attenuation: &mut Vec3, out_scattered: &mut Ray) -> bool;
+// }
+// pub trait MaterialClone {
+//     fn clone_mat(&self) -> Box<dyn Material>;
+// }
+// impl<T> MaterialClone for T where T: 'static + Material + Clone, {
+//     fn clone_mat(&self) -> Box<dyn Material> {
+//         Box::new(self.clone())
+//     }
+// }
+// impl Clone for Box<dyn Material> {
+//     fn clone(&self) -> Box<dyn Material> {
+//         self.clone_mat()
+//     }
+// }
+// NOTE: The above is no longer needed as materials are now referred to by an index. I'm keeping this around for posterity, though.
+// Materials are shared between the threads rendering a scene, so any per-sample state comes in through rng.
+pub trait Material: AsAny + Send + Sync {
+    fn scatter(&self, ray: &Ray, hit_rec: &HitRecord, out_attenuation: &mut Vec3, out_scattered: &mut Ray, rng: &mut RandGen) -> bool;
+
+    // Chance that a ray stops at the surface rather than passing straight through it.
+    fn opacity(&self, _ray: &Ray, _hit_rec: &HitRecord) -> Float {
+        1.0
+    }
+
+    // Share of the light arriving from the unit direction that is scattered back along the ray, including
+    // the cosine at the surface. Used to light surfaces by analytic lights. Only diffuse parts are included,
+    // as mirror-like and fuzzy reflections are left to scatter, which cannot find such lights.
+    fn eval(&self, _ray: &Ray, _hit_rec: &HitRecord, _direction: Vec3) -> Vec3 {
+        Vec3::zero()
+    }
+
+    // Density with which scatter picks the unit direction through the diffuse parts that eval covers,
+    // including the chance of picking them at all. scatter leaves it in the scattered ray's scatter_pdf, so
+    // light found both by sampling portals and by scattering through them can be weighted between the two.
+    fn pdf(&self, _ray: &Ray, _hit_rec: &HitRecord, _direction: Vec3) -> Float {
+        0.0
+    }
+}
+
+// Lets a material be downcast to its type, e.g. to edit its parameters in place. Implemented for all types.
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// How a material's alpha is applied, as in glTF.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AlphaMode {
+    Opaque, // Alpha is ignored.
+    Mask(Float), // Alpha below the cutoff is cut out and the rest is opaque, e.g. for leaves or fences.
+    Blend // Alpha is the opacity.
+}
+
+// Alpha of materials that support transparency. The value multiplies the texture's alpha.
+#[derive(Clone)]
+pub struct Alpha {
+    pub mode: AlphaMode,
+    pub value: Float,
+    pub texture: Option<Arc<Texture>> // Alpha channel, or luminance for textures without one.
+}
+impl Alpha {
+    pub fn opaque() -> Alpha {
+        Alpha {
+            mode: AlphaMode::Opaque,
+            value: 1.0,
+            texture: None
+        }
+    }
+
+    pub fn opacity(&self, ray: &Ray, hit_rec: &HitRecord) -> Float {
+        if self.mode == AlphaMode::Opaque {
+            return 1.0
+        }
+        let mut alpha = self.value;
+        if let Some(texture) = &self.texture {
+            alpha *= texture.sample_mask(hit_rec.u, hit_rec.v, hit_rec.uv_footprint(ray));
+        }
+        match self.mode {
+            AlphaMode::Opaque => 1.0,
+            AlphaMode::Mask(cutoff) => if alpha < cutoff {0.0} else {1.0},
+            AlphaMode::Blend => alpha.clamp(0.0, 1.0)
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Lambertian {
+    albedo: Vec3,
+    pub alpha: Alpha
+}
+impl Lambertian {
+    pub fn new(albedo: Vec3) -> Lambertian {
+        Lambertian {
+            albedo: albedo,
+            alpha: Alpha::opaque()
+        }
+    }
+
+    pub fn get_albedo(&self) -> Vec3 {
+        self.albedo
+    }
+
+    pub fn set_albedo(&mut self, albedo: Vec3) {
+        self.albedo = albedo;
+    }
+}
+impl Material for Lambertian {
+    fn opacity(&self, ray: &Ray, hit_rec: &HitRecord) -> Float {
+        self.alpha.opacity(ray, hit_rec)
+    }
+
+    fn scatter(&self, _ray: &Ray, hit_rec: &HitRecord, out_attenuation: &mut Vec3, out_scattered: &mut Ray, rng: &mut RandGen) -> bool {
+        // Cosine-weighted directions around the normal. This follows the Lambertian falloff exactly,
+        // so no weighting by a PDF is needed.
+        out_scattered.origin = hit_rec.p;
+        out_scattered.direction = Onb::from_normal(hit_rec.n).local(rng.cosine_direction());
+        out_scattered.scatter_pdf = cosine_pdf(hit_rec.n.dot(&out_scattered.direction.normalized()));
+
+        *out_attenuation = self.albedo;
+
+        return true
+    }
+
+    fn eval(&self, _ray: &Ray, hit_rec: &HitRecord, direction: Vec3) -> Vec3 {
+        self.albedo * cosine_pdf(hit_rec.n.dot(&direction))
+    }
+
+    fn pdf(&self, _ray: &Ray, hit_rec: &HitRecord, direction: Vec3) -> Float {
+        cosine_pdf(hit_rec.n.dot(&direction))
+    }
+}
+
+// Wavelengths in nanometers that stand in for the red, green, and blue channels.
+const RGB_WAVELENGTHS: [Float; 3] = [650.0, 532.0, 450.0];
+
+// A thin transparent coating, as on soap bubbles or oil slicks. Light reflected off its top and bottom
+// interferes, so the reflected color depends on the thickness and the viewing angle.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ThinFilm {
+    pub thickness: Float, // In nanometers. Colors are strongest from about 100 to 1000.
+    pub ior: Float
+}
+impl ThinFilm {
+    pub fn new(thickness: Float, ior: Float) -> ThinFilm {
+        ThinFilm {
+            thickness: thickness,
+            ior: ior
+        }
+    }
+
+    // Reflectance of the film on top of a material of substrate_ior, for light from the air at a wavelength
+    // in nanometers. Airy's formula, averaged over both polarizations.
+    pub fn reflectance(&self, cos_theta: Float, substrate_ior: Float, wavelength: Float) -> Float {
+        let sin2_theta = 1.0 - cos_theta * cos_theta;
+        let cos_film = (1.0 - sin2_theta / (self.ior * self.ior)).max(0.0).sqrt();
+        let cos2_substrate = 1.0 - sin2_theta / (substrate_ior * substrate_ior);
+        if cos2_substrate <= 0.0 {
+            return 1.0
+        }
+        let cos_substrate = cos2_substrate.sqrt();
+
+        // Phase difference between the two reflections.
+        let phase = 4.0 * std::f64::consts::PI as Float * self.ior * self.thickness * cos_film / wavelength;
+        let airy = |r12: Float, r23: Float| {
+            let cross = 2.0 * r12 * r23 * phase.cos();
+            (r12 * r12 + r23 * r23 + cross) / (1.0 + r12 * r12 * r23 * r23 + cross)
+        };
+        let (n1, n2, n3) = (1.0, self.ior, substrate_ior);
+        let s = airy(
+            (n1 * cos_theta - n2 * cos_film) / (n1 * cos_theta + n2 * cos_film),
+            (n2 * cos_film - n3 * cos_substrate) / (n2 * cos_film + n3 * cos_substrate)
+        );
+        let p = airy(
+            (n2 * cos_theta - n1 * cos_film) / (n2 * cos_theta + n1 * cos_film),
+            (n3 * cos_film - n2 * cos_substrate) / (n3 * cos_film + n2 * cos_substrate)
+        );
+        (0.5 * (s + p)).clamp(0.0, 1.0)
+    }
+
+    // Reflectance per channel, each at its own wavelength, or all at the path's wavelength if it has one.
+    pub fn reflectance_rgb(&self, cos_theta: Float, substrate_iors: Vec3, wavelength: Float) -> Vec3 {
+        let at = |channel: usize, ior: Float| {
+            self.reflectance(cos_theta, ior, if wavelength > 0.0 {wavelength} else {RGB_WAVELENGTHS[channel]})
+        };
+        Vec3::new(at(0, substrate_iors.x), at(1, substrate_iors.y), at(2, substrate_iors.z))
+    }
+}
+
+#[derive(Clone)]
+pub struct Metal {
+    albedo: Vec3,
+    fuzz: Float,
+    pub film: Option<ThinFilm> // Replaces the albedo by the coating's reflectance on top of it.
+}
+impl Metal {
+    pub fn new(albedo: Vec3, fuzz: Float) -> Metal {
+        Metal {
+            albedo: albedo,
+            fuzz: fuzz,
+            film: None
+        }
+    }
+
+    pub fn get_albedo(&self) -> Vec3 {
+        self.albedo
+    }
+
+    pub fn set_albedo(&mut self, albedo: Vec3) {
+        self.albedo = albedo;
+    }
+
+    pub fn get_fuzz(&self) -> Float {
+        self.fuzz
+    }
+
+    pub fn set_fuzz(&mut self, fuzz: Float) {
+        self.fuzz = fuzz;
+    }
+}
+impl Material for Metal {
+    fn scatter(&self, ray: &Ray, hit_rec: &HitRecord, out_attenuation: &mut Vec3, out_scattered: &mut Ray, _rng: &mut RandGen) -> bool {
+        let unit_direction = ray.direction.normalized();
+        let reflected = unit_direction.reflect(hit_rec.n);
+
+        out_scattered.origin = hit_rec.p;
+        out_scattered.direction = reflected + self.fuzz * Vec3::random_on_sphere();
+
+        *out_attenuation = self.albedo;
+        if let Some(film) = &self.film {
+            // The metal is treated as a dielectric whose reflectance head-on matches the albedo.
+            let ior = |albedo: Float| {
+                let r = albedo.clamp(0.0, 0.99).sqrt();
+                (1.0 + r) / (1.0 - r)
+            };
+            let cos_theta = (-unit_direction).dot(&hit_rec.n).clamp(0.0, 1.0);
+            *out_attenuation = film.reflectance_rgb(cos_theta, Vec3::new(ior(self.albedo.x), ior(self.albedo.y), ior(self.albedo.z)), ray.wavelength);
+        }
+
+        return out_scattered.direction.dot(&hit_rec.n) > 0.0
+    }
+}
+
+// Visible wavelengths in nanometers that dispersive materials sample from.
+pub const WAVELENGTH_MIN: Float = 380.0;
+pub const WAVELENGTH_MAX: Float = 780.0;
+
+// RGB response to a single wavelength, from a Gaussian per channel. Scaled so that the average over
+// uniformly sampled wavelengths is white, so paths of a random wavelength tinted by this average out
+// to the color they would have without dispersion.
+pub fn wavelength_to_rgb(wavelength: Float) -> Vec3 {
+    static SCALE: OnceLock<Vec3> = OnceLock::new();
+    let response = |wavelength: Float| {
+        let gaussian = |center: Float, width: Float| (-0.5 * ((wavelength - center) / width).powi(2)).exp();
+        Vec3::new(gaussian(600.0, 40.0), gaussian(550.0, 40.0), gaussian(455.0, 30.0))
+    };
+    let scale = SCALE.get_or_init(|| {
+        const STEPS: u32 = 1000;
+        let mut sum = Vec3::zero();
+        for i in 0..STEPS {
+            sum += response(WAVELENGTH_MIN + (WAVELENGTH_MAX - WAVELENGTH_MIN) * ((i as Float) + 0.5) / (STEPS as Float));
+        }
+        let mean = sum / (STEPS as Float);
+        Vec3::new(1.0 / mean.x, 1.0 / mean.y, 1.0 / mean.z)
+    });
+    response(wavelength) * *scale
+}
+
+#[derive(Clone)]
+pub struct Dielectric {
+    ior: Float, // Index of refraction. Cauchy's A for dispersive materials.
+    cauchy_b: Float, // Cauchy's B in square micrometers. Zero for materials without dispersion.
+    pub film: Option<ThinFilm> // Coating on the outside, e.g. for soap bubbles.
+}
+impl Dielectric {
+    pub fn new(ior: Float) -> Dielectric {
+        Dielectric {
+            ior: ior,
+            cauchy_b: 0.0,
+            film: None
+        }
+    }
+
+    // Splits light by wavelength, with an index of refraction of a + b / wavelength^2 for wavelengths in
+    // micrometers (Cauchy's equation). E.g. BK7 glass is about (1.5046, 0.0042) and dense flint glass
+    // about (1.728, 0.0134). Paths through it are traced for a single wavelength, so they take longer to converge.
+    pub fn cauchy(a: Float, b: Float) -> Dielectric {
+        Dielectric {
+            ior: a,
+            cauchy_b: b,
+            film: None
+        }
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        self.cauchy_b != 0.0
+    }
+
+    // Cauchy's A for dispersive materials.
+    pub fn get_ior(&self) -> Float {
+        self.ior
+    }
+
+    pub fn set_ior(&mut self, ior: Float) {
+        self.ior = ior;
+    }
+
+    // Index of refraction at a wavelength in nanometers.
+    pub fn ior_at(&self, wavelength: Float) -> Float {
+        let micrometers = wavelength / 1000.0;
+        self.ior + self.cauchy_b / (micrometers * micrometers)
+    }
+
+    pub fn reflectance(&self, cosine: Float, ref_idx: Float) -> Float {
+        // Schlick's approximation.
+        let mut r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
+        r0 = r0 * r0;
+        return r0 + (1.0 - r0) * ((1.0 - cosine).powf(5.0));
+    }
+}
+impl Material for Dielectric {
+    fn scatter(&self, ray: &Ray, hit_rec: &HitRecord, out_attenuation: &mut Vec3, out_scattered: &mut Ray, rng: &mut RandGen) -> bool {
+        // Dispersive materials pick a wavelength for the path if it has none yet, and tint it by its color.
+        let mut wavelength = ray.wavelength;
+        let mut tint = Vec3::one();
+        if self.is_dispersive() && wavelength == 0.0 {
+            wavelength = rng.next_range(WAVELENGTH_MIN..WAVELENGTH_MAX);
+            tint = wavelength_to_rgb(wavelength);
+        }
+        let ior = if self.is_dispersive() {self.ior_at(wavelength)} else {self.ior};
+
+        let refract_ratio = if hit_rec.front_face {1.0 / ior} else {ior};
+        let unit_direction = ray.direction.normalized();
+
+        let cos_theta = (-unit_direction).dot(&hit_rec.n).min(1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+
+        let cannot_refract = (refract_ratio * sin_theta) > 1.0;
+
+        let mut attenuation = tint;
+        let reflect = match (&self.film, hit_rec.front_face) {
+            _ if cannot_refract => true,
+            (Some(film), true) => {
+                // Reflection is picked by the average over the channels, and each channel weighted by its share.
+                let reflectance = film.reflectance_rgb(cos_theta, Vec3::one() * ior, wavelength);
+                let chance = (reflectance.x + reflectance.y + reflectance.z) / 3.0;
+                if rng.next01() < chance {
+                    attenuation = attenuation * reflectance / chance;
+                    true
+                } else {
+                    attenuation = attenuation * (Vec3::one() - reflectance) / (1.0 - chance);
+                    false
+                }
+            }
+            _ => self.reflectance(cos_theta, refract_ratio) > rng.next01()
+        };
+        let direction = if reflect {
+            unit_direction.reflect(hit_rec.n)
+        } else {
+            Vec3::refract(unit_direction, hit_rec.n, refract_ratio)
+        };
+
+        *out_scattered = Ray::new(hit_rec.p, direction);
+        out_scattered.wavelength = wavelength;
+        *out_attenuation = attenuation;
+
+        true
+    }
+}
+
+// Clear dielectric coat over another material, such as lacquer over paint or wood. The coat reflects by its
+// Fresnel reflectance and lets the rest through to the base, so e.g. a coated Lambertian looks like car paint.
+// Refraction by the coat is ignored, as it is assumed to be thin.
+#[derive(Clone)]
+pub struct CoatedMaterial {
+    base: Arc<dyn Material>,
+    pub ior: Float,
+    pub roughness: Float, // Fuzz of the coat's reflection, as for Metal.
+    pub tint: Vec3 // Absorbed by the coat on the way to the base and back.
+}
+impl CoatedMaterial {
+    pub fn new<M: Material + 'static>(base: M, ior: Float, roughness: Float) -> CoatedMaterial {
+        CoatedMaterial::from_shared(Arc::new(base), ior, roughness)
+    }
+
+    // Coats a material that is also used on its own, e.g. one already in a scene.
+    pub fn from_shared(base: Arc<dyn Material>, ior: Float, roughness: Float) -> CoatedMaterial {
+        CoatedMaterial {
+            base: base,
+            ior: ior,
+            roughness: roughness,
+            tint: Vec3::one()
+        }
+    }
+}
+impl Material for CoatedMaterial {
+    fn opacity(&self, ray: &Ray, hit_rec: &HitRecord) -> Float {
+        self.base.opacity(ray, hit_rec)
+    }
+
+    fn scatter(&self, ray: &Ray, hit_rec: &HitRecord, out_attenuation: &mut Vec3, out_scattered: &mut Ray, rng: &mut RandGen) -> bool {
+        // Rays from inside (e.g. of a coated glass) only see the base.
+        if !hit_rec.front_face {
+            return self.base.scatter(ray, hit_rec, out_attenuation, out_scattered, rng)
+        }
+
+        // The coat is picked with the chance of its reflectance, so neither layer needs further weighting.
+        let unit_direction = ray.direction.normalized();
+        let cos_theta = (-unit_direction).dot(&hit_rec.n).clamp(0.0, 1.0);
+        let mut r0 = (1.0 - self.ior) / (1.0 + self.ior);
+        r0 = r0 * r0;
+        let reflectance = r0 + (1.0 - r0) * (1.0 - cos_theta).powf(5.0);
+        if rng.next01() < reflectance {
+            out_scattered.origin = hit_rec.p;
+            out_scattered.direction = unit_direction.reflect(hit_rec.n) + self.roughness * Vec3::random_on_sphere();
+            *out_attenuation = Vec3::one();
+            return out_scattered.direction.dot(&hit_rec.n) > 0.0
+        }
+
+        if !self.base.scatter(ray, hit_rec, out_attenuation, out_scattered, rng) {
+            return false
+        }
+        *out_attenuation = *out_attenuation * self.tint * self.tint;
+        if out_scattered.scatter_pdf > 0.0 {
+            out_scattered.scatter_pdf = self.pdf(ray, hit_rec, out_scattered.direction.normalized());
+        }
+        true
+    }
+
+    fn eval(&self, ray: &Ray, hit_rec: &HitRecord, direction: Vec3) -> Vec3 {
+        if !hit_rec.front_face {
+            return self.base.eval(ray, hit_rec, direction)
+        }
+
+        // What the coat lets through on the way in and out. Its own reflection is left to scatter.
+        let fresnel = |cos_theta: Float| {
+            let mut r0 = (1.0 - self.ior) / (1.0 + self.ior);
+            r0 = r0 * r0;
+            r0 + (1.0 - r0) * (1.0 - cos_theta.clamp(0.0, 1.0)).powf(5.0)
+        };
+        let cos_in = (-ray.direction.normalized()).dot(&hit_rec.n);
+        let cos_out = direction.dot(&hit_rec.n);
+        self.base.eval(ray, hit_rec, direction) * self.tint * self.tint * ((1.0 - fresnel(cos_in)) * (1.0 - fresnel(cos_out)))
+    }
+
+    fn pdf(&self, ray: &Ray, hit_rec: &HitRecord, direction: Vec3) -> Float {
+        if !hit_rec.front_face {
+            return self.base.pdf(ray, hit_rec, direction)
+        }
+
+        // The base is only reached when the coat does not reflect.
+        let cos_theta = (-ray.direction.normalized()).dot(&hit_rec.n).clamp(0.0, 1.0);
+        let mut r0 = (1.0 - self.ior) / (1.0 + self.ior);
+        r0 = r0 * r0;
+        let reflectance = r0 + (1.0 - r0) * (1.0 - cos_theta).powf(5.0);
+        self.base.pdf(ray, hit_rec, direction) * (1.0 - reflectance)
+    }
+}
+
+// Picks one of two materials per hit, b with the chance of amount times the mask texture's value there
+// (alpha, or luminance without one). E.g. rust patches on a metal, or a sphere that is glass on one side only.
+#[derive(Clone)]
+pub struct MixMaterial {
+    a: Arc<dyn Material>,
+    b: Arc<dyn Material>,
+    pub amount: Float,
+    pub mask: Option<Arc<Texture>>
+}
+impl MixMaterial {
+    pub fn new<A: Material + 'static, B: Material + 'static>(a: A, b: B, amount: Float) -> MixMaterial {
+        MixMaterial::from_shared(Arc::new(a), Arc::new(b), amount)
+    }
+
+    // Mixes materials that are also used on their own, e.g. ones already in a scene.
+    pub fn from_shared(a: Arc<dyn Material>, b: Arc<dyn Material>, amount: Float) -> MixMaterial {
+        MixMaterial {
+            a: a,
+            b: b,
+            amount: amount,
+            mask: None
+        }
+    }
+
+    // Chance of picking b at a hit.
+    pub fn weight(&self, ray: &Ray, hit_rec: &HitRecord) -> Float {
+        let mut weight = self.amount;
+        if let Some(mask) = &self.mask {
+            weight *= mask.sample_mask(hit_rec.u, hit_rec.v, hit_rec.uv_footprint(ray));
+        }
+        weight.clamp(0.0, 1.0)
+    }
+}
+impl Material for MixMaterial {
+    fn opacity(&self, ray: &Ray, hit_rec: &HitRecord) -> Float {
+        let weight = self.weight(ray, hit_rec);
+        self.a.opacity(ray, hit_rec) * (1.0 - weight) + self.b.opacity(ray, hit_rec) * weight
+    }
+
+    fn scatter(&self, ray: &Ray, hit_rec: &HitRecord, out_attenuation: &mut Vec3, out_scattered: &mut Ray, rng: &mut RandGen) -> bool {
+        let weight = self.weight(ray, hit_rec);
+        let mat = if rng.next01() < weight {&self.b} else {&self.a};
+        if !mat.scatter(ray, hit_rec, out_attenuation, out_scattered, rng) {
+            return false
+        }
+        // Either material could have picked a diffuse direction.
+        if out_scattered.scatter_pdf > 0.0 {
+            out_scattered.scatter_pdf = self.pdf(ray, hit_rec, out_scattered.direction.normalized());
+        }
+        true
+    }
+
+    fn eval(&self, ray: &Ray, hit_rec: &HitRecord, direction: Vec3) -> Vec3 {
+        let weight = self.weight(ray, hit_rec);
+        self.a.eval(ray, hit_rec, direction) * (1.0 - weight) + self.b.eval(ray, hit_rec, direction) * weight
+    }
+
+    fn pdf(&self, ray: &Ray, hit_rec: &HitRecord, direction: Vec3) -> Float {
+        let weight = self.weight(ray, hit_rec);
+        self.a.pdf(ray, hit_rec, direction) * (1.0 - weight) + self.b.pdf(ray, hit_rec, direction) * weight
+    }
+}
+
+// glTF-style metallic-roughness material. Texture values multiply the matching factors.
+#[derive(Clone)]
+pub struct PbrMaterial {
+    pub base_color: Vec3,
+    pub metallic: Float,
+    pub roughness: Float,
+    pub base_color_texture: Option<Arc<Texture>>, // Linear RGB.
+    pub metallic_roughness_texture: Option<Arc<Texture>>, // Roughness in green, metallic in blue.
+    pub alpha: Alpha
+}
+impl PbrMaterial {
+    pub fn new(base_color: Vec3, metallic: Float, roughness: Float) -> PbrMaterial {
+        PbrMaterial {
+            base_color: base_color,
+            metallic: metallic,
+            roughness: roughness,
+            base_color_texture: None,
+            metallic_roughness_texture: None,
+            alpha: Alpha::opaque()
+        }
+    }
+
+    // Base color, metallic, and roughness at a hit, with the textures applied.
+    fn textured(&self, ray: &Ray, hit_rec: &HitRecord) -> (Vec3, Float, Float) {
+        let footprint = hit_rec.uv_footprint(ray);
+        let mut base_color = self.base_color;
+        if let Some(texture) = &self.base_color_texture {
+            base_color = base_color * texture.sample(hit_rec.u, hit_rec.v, footprint);
+        }
+        let mut metallic = self.metallic;
+        let mut roughness = self.roughness;
+        if let Some(texture) = &self.metallic_roughness_texture {
+            let texel = texture.sample(hit_rec.u, hit_rec.v, footprint);
+            roughness *= texel.y;
+            metallic *= texel.z;
+        }
+        (base_color, metallic, roughness)
+    }
+}
+impl Material for PbrMaterial {
+    fn opacity(&self, ray: &Ray, hit_rec: &HitRecord) -> Float {
+        self.alpha.opacity(ray, hit_rec)
+    }
+
+    fn scatter(&self, ray: &Ray, hit_rec: &HitRecord, out_attenuation: &mut Vec3, out_scattered: &mut Ray, rng: &mut RandGen) -> bool {
+        let (base_color, metallic, roughness) = self.textured(ray, hit_rec);
+
+        // Metals reflect tinted by the base color. Dielectrics reflect untinted with a 4% Schlick
+        // Fresnel, and otherwise scatter diffusely.
+        let unit_direction = ray.direction.normalized();
+        let cos_theta = (-unit_direction).dot(&hit_rec.n).clamp(0.0, 1.0);
+        let specular_chance = 0.04 + 0.96 * (1.0 - cos_theta).powf(5.0);
+        let is_metal = rng.next01() < metallic;
+        if is_metal || rng.next01() < specular_chance {
+            let reflected = unit_direction.reflect(hit_rec.n);
+            out_scattered.origin = hit_rec.p;
+            out_scattered.direction = reflected + roughness * Vec3::random_on_sphere();
+            *out_attenuation = if is_metal {base_color} else {Vec3::one()};
+            return out_scattered.direction.dot(&hit_rec.n) > 0.0
+        }
+
+        out_scattered.origin = hit_rec.p;
+        out_scattered.direction = Onb::from_normal(hit_rec.n).local(rng.cosine_direction());
+        out_scattered.scatter_pdf = (1.0 - metallic.clamp(0.0, 1.0)) * (1.0 - specular_chance) * cosine_pdf(hit_rec.n.dot(&out_scattered.direction.normalized()));
+        *out_attenuation = base_color;
+
+        true
+    }
+
+    fn eval(&self, ray: &Ray, hit_rec: &HitRecord, direction: Vec3) -> Vec3 {
+        // The diffuse part, which is what is left after the metal and specular chances in scatter.
+        let (base_color, metallic, _) = self.textured(ray, hit_rec);
+        let cos_theta = (-ray.direction.normalized()).dot(&hit_rec.n).clamp(0.0, 1.0);
+        let specular_chance = 0.04 + 0.96 * (1.0 - cos_theta).powf(5.0);
+        base_color * ((1.0 - metallic.clamp(0.0, 1.0)) * (1.0 - specular_chance) * cosine_pdf(hit_rec.n.dot(&direction)))
+    }
+
+    fn pdf(&self, ray: &Ray, hit_rec: &HitRecord, direction: Vec3) -> Float {
+        let (_, metallic, _) = self.textured(ray, hit_rec);
+        let cos_theta = (-ray.direction.normalized()).dot(&hit_rec.n).clamp(0.0, 1.0);
+        let specular_chance = 0.04 + 0.96 * (1.0 - cos_theta).powf(5.0);
+        (1.0 - metallic.clamp(0.0, 1.0)) * (1.0 - specular_chance) * cosine_pdf(hit_rec.n.dot(&direction))
+    }
+}
+
+
+// --------------------------------------------------
+// Hittable / HitRecord
+// --------------------------------------------------
+#[derive(Clone)]
+pub struct HitRecord {
+    pub p: Vec3,
+    pub n: Vec3,
+    pub t: Float,
+    pub front_face: bool,
+    pub mat_id: u32,
+    pub u: Float, // Surface texture coordinates in [0, 1].
+    pub v: Float,
+    pub uv_scale: Float // Change in texture coordinates per unit of distance on the surface. Zero if unknown.
+}
+
+impl HitRecord {
+    pub fn empty() -> HitRecord {
+        HitRecord{
+            p: Vec3::new(0.0, 0.0, 0.0),
+            n: Vec3::new(0.0, 0.0, 0.0),
+            t: 0.0,
+            front_face: false,
+            mat_id: 0,
+            u: 0.0,
+            v: 0.0,
+            uv_scale: 0.0
+        }
+    }
+
+    pub fn new(p: Vec3, n: Vec3, t: Float, front_face: bool, mat_id: u32) -> HitRecord {
+        HitRecord {
+            p: p,
+            n: n,
+            t: t,
+            front_face: front_face,
+            mat_id: mat_id,
+            u: 0.0,
+            v: 0.0,
+            uv_scale: 0.0
+        }
+    }
+
+    // Width of the ray's cone at the hit in texture coordinates, for use as a texture footprint.
+    // Grazing angles stretch it. Zero if the ray has no cone or the surface has no uv_scale.
+    pub fn uv_footprint(&self, ray: &Ray) -> Float {
+        if self.uv_scale <= 0.0 {
+            return 0.0
+        }
+        let cos_theta = ray.direction.normalized().dot(&self.n).abs().max(0.05);
+        ray.cone_width_at(self.t) * self.uv_scale / cos_theta
+    }
+
+    pub fn set_face_normal(&mut self, ray: &Ray, outward_normal: &Vec3) {
+        self.front_face = ray.direction.dot(outward_normal) < 0.0;
+        self.n = if self.front_face {*outward_normal} else {-*outward_normal};
+    }
+}
+
+pub trait Hittable: Send + Sync {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<HitRecord>;
+
+    // Unbounded objects (e.g. infinite planes) are tested against every ray instead of going in the BVH.
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+
+    // Elements and estimated memory, for the scene statistics. By default a single shape of the type's size.
+    fn memory(&self) -> ObjectMemory {
+        ObjectMemory::single(std::mem::size_of_val(self))
+    }
+
+    // Material ids the object's hits can have, so that the scene can check them when the object is added. Types
+    // that don't list theirs aren't checked, and must only use materials the scene has.
+    fn materials(&self) -> Vec<u32> {
+        Vec::new()
+    }
+}
+
+// Elements and estimated memory of an object, for the scene statistics.
+#[derive(Clone, Copy, Debug)]
+pub struct ObjectMemory {
+    pub elements: usize, // Triangles, points, segments, or samples. 1 for single shapes.
+    pub geometry: usize, // Bytes of the elements.
+    pub bvh_nodes: usize, // Of the object's own BVH, if any.
+    pub bvh: usize // Bytes of that BVH.
+}
+
+impl ObjectMemory {
+    pub fn single(bytes: usize) -> ObjectMemory {
+        ObjectMemory {
+            elements: 1,
+            geometry: bytes,
+            bvh_nodes: 0,
+            bvh: 0
+        }
+    }
+
+    // Elements in a BVH of their own.
+    pub fn with_bvh(elements: usize, geometry: usize, bvh: &Bvh) -> ObjectMemory {
+        ObjectMemory {
+            elements: elements,
+            geometry: geometry,
+            bvh_nodes: bvh.node_count(),
+            bvh: bvh.memory_usage()
+        }
+    }
+
+    pub fn add(&self, other: &ObjectMemory) -> ObjectMemory {
+        ObjectMemory {
+            elements: self.elements + other.elements,
+            geometry: self.geometry + other.geometry,
+            bvh_nodes: self.bvh_nodes + other.bvh_nodes,
+            bvh: self.bvh + other.bvh
+        }
+    }
+}
+
+
+// --------------------------------------------------
 // Sphere
 // --------------------------------------------------
+// A negative radius turns the sphere inside out: the same surface with its normals pointing inwards, so
+// that rays from outside see its back face. Inside a glass sphere, one makes the glass hollow (see shell).
 pub struct Sphere {
     pub center: Vec3,
-    pub radius: f32,
+    pub radius: Float,
     pub mat_id: u32
 }
 
 impl Sphere {
-    pub fn new(center: Vec3, radius: f32, mat_id: u32) -> Sphere {
+    pub fn new(center: Vec3, radius: Float, mat_id: u32) -> Sphere {
         Sphere {
             center: center,
             radius: radius,
             mat_id: mat_id
         }
     }
+
+    // Hollow sphere with walls of the given thickness, e.g. a glass bubble: the outer surface, and an
+    // inside-out one for the inner surface, through which rays leave the material into the hollow.
+    pub fn shell(center: Vec3, radius: Float, thickness: Float, mat_id: u32) -> Result<[Sphere; 2]> {
+        if !(thickness > 0.0 && thickness < radius) {
+            return Err(RaytracerError::InvalidArgument(format!("shell thickness {} must be between 0 and its radius {}", thickness, radius)))
+        }
+        Ok([Sphere::new(center, radius, mat_id), Sphere::new(center, -(radius - thickness), mat_id)])
+    }
 }
 
 impl Hittable for Sphere {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn materials(&self) -> Vec<u32> {
+        vec![self.mat_id]
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::around_sphere(self.center, self.radius))
+    }
+
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
         let oc = ray.origin - self.center;
         let a = ray.direction.sqr_length();
         let half_b = oc.dot(&ray.direction);
@@ -502,9 +3281,9 @@ impl Hittable for Sphere {
 
         // Find the nearest root that lies in the acceptable range.
         let mut root = (-half_b - sqrtd) / a;
-        if root < t_min || t_max < root {
+        if !ray_t.contains(root) {
             root = (-half_b + sqrtd) / a;
-            if root < t_min || t_max < root {
+            if !ray_t.contains(root) {
                 return None
             }
         }
@@ -515,6 +3294,880 @@ impl Hittable for Sphere {
         let outward_normal = (hr.p - self.center) / self.radius;
         hr.set_face_normal(ray, &outward_normal);
 
+        // Longitude around +Y and latitude from -Y, of the point and not the normal, so that inside-out
+        // spheres are mapped the same.
+        let pi = consts::PI;
+        let direction = outward_normal * self.radius.signum();
+        hr.u = ((-direction.z).atan2(direction.x) + pi) / (2.0 * pi);
+        hr.v = (-direction.y).clamp(-1.0, 1.0).acos() / pi;
+        // u runs around the equator (2 pi r) and v from pole to pole (pi r). Their geometric mean.
+        hr.uv_scale = 1.0 / (pi * self.radius.abs() * consts::SQRT_2);
+
         return Some(hr)
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::{Light, Portal};
+    use crate::primitives::{Disk, Plane};
+    use crate::scene::Background;
+
+    // Sum of the weights of the pixel centers around a sample at offset from one of them.
+    fn weight_sum(filter: PixelFilter, offset: Float) -> Float {
+        (-3..=3).map(|i| filter.weight(offset - (i as Float))).sum()
+    }
+
+    #[test]
+    fn pixel_filter_weights() {
+        for filter in [PixelFilter::Box, PixelFilter::Tent(1.0), PixelFilter::Gaussian(0.5), PixelFilter::mitchell()].iter() {
+            assert!(filter.weight(0.0) > 0.0);
+            assert_eq!(filter.weight(filter.radius()), 0.0);
+            assert_eq!(filter.weight(0.3), filter.weight(-0.3));
+        }
+        assert_eq!(PixelFilter::Box.weight(0.49), 1.0);
+        assert_eq!(PixelFilter::Tent(2.0).weight(1.0), 0.5);
+        assert!(PixelFilter::mitchell().weight(1.5) < 0.0);
+
+        // A tent of radius one and Mitchell filters with B + 2C = 1 spread every sample over a total weight of one.
+        for offset in [0.0, 0.25, 0.5, 0.9].iter() {
+            assert!((weight_sum(PixelFilter::Tent(1.0), *offset) - 1.0).abs() < 1e-5);
+            assert!((weight_sum(PixelFilter::mitchell(), *offset) - 1.0).abs() < 1e-5);
+        }
+        assert_eq!(PixelFilter::from_name("mitchell"), Some(PixelFilter::mitchell()));
+        assert_eq!(PixelFilter::from_name("lanczos"), None);
+    }
+
+    #[test]
+    fn dispersion_averages_to_white() {
+        let mut rng = RandGen::new();
+        let mut sum = Vec3::zero();
+        for _ in 0..20000 {
+            sum += wavelength_to_rgb(rng.next_range(WAVELENGTH_MIN..WAVELENGTH_MAX));
+        }
+        let mean = sum / 20000.0;
+        for channel in [mean.x, mean.y, mean.z].iter() {
+            assert!((channel - 1.0).abs() < 0.05);
+        }
+        assert!(wavelength_to_rgb(650.0).x > wavelength_to_rgb(650.0).z);
+        assert!(wavelength_to_rgb(450.0).z > wavelength_to_rgb(450.0).x);
+
+        // Blue bends more than red.
+        let glass = Dielectric::cauchy(1.5046, 0.0042);
+        assert!(glass.ior_at(450.0) > glass.ior_at(650.0));
+        assert!(!Dielectric::new(1.5).is_dispersive());
+    }
+
+    #[test]
+    fn thin_film_interference() {
+        // Without thickness the film leaves plain Fresnel reflectance, 4% head-on for glass.
+        let bare = ThinFilm::new(0.0, 1.33);
+        assert!((bare.reflectance(1.0, 1.5, 550.0) - 0.04).abs() < 1e-4);
+        assert!(bare.reflectance(0.05, 1.5, 550.0) > 0.5);
+
+        // A quarter wave coating of ior sqrt(1.5) cancels the reflection at its wavelength only.
+        let coating = ThinFilm::new(550.0 / (4.0 * (1.5 as Float).sqrt()), (1.5 as Float).sqrt());
+        assert!(coating.reflectance(1.0, 1.5, 550.0) < 1e-4);
+        assert!(coating.reflectance(1.0, 1.5, 400.0) > 0.001);
+
+        // Thicker films reflect the channels differently.
+        let rgb = ThinFilm::new(250.0, 1.33).reflectance_rgb(1.0, Vec3::one() * 1.5, 0.0);
+        assert!((rgb.x - rgb.z).abs() > 0.01);
+    }
+
+    #[test]
+    fn coat_reflects_by_fresnel() {
+        // Over a black base, only the coat's reflection is left: 4% head-on for ior 1.5, most of it at grazing angles.
+        let coated = CoatedMaterial::new(Lambertian::new(Vec3::zero()), 1.5, 0.0);
+        let mut hit_rec = HitRecord::empty();
+        hit_rec.n = Vec3::new(0.0, 1.0, 0.0);
+        hit_rec.front_face = true;
+        let mut rng = RandGen::new();
+        let mut mean_reflected = |direction: Vec3| {
+            let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), direction);
+            let mut sum = 0.0;
+            for _ in 0..20000 {
+                let mut attenuation = Vec3::zero();
+                let mut scattered = Ray::new(Vec3::zero(), Vec3::zero());
+                if coated.scatter(&ray, &hit_rec, &mut attenuation, &mut scattered, &mut rng) {
+                    sum += attenuation.x;
+                }
+            }
+            sum / 20000.0
+        };
+        assert!((mean_reflected(Vec3::new(0.0, -1.0, 0.0)) - 0.04).abs() < 0.01);
+        assert!(mean_reflected(Vec3::new(1.0, -0.05, 0.0)) > 0.5);
+    }
+
+    #[test]
+    fn mix_picks_by_amount() {
+        let mix = MixMaterial::new(Lambertian::new(Vec3::zero()), Lambertian::new(Vec3::one()), 0.25);
+        let mut hit_rec = HitRecord::empty();
+        hit_rec.n = Vec3::new(0.0, 1.0, 0.0);
+        let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let mut rng = RandGen::new();
+        let mut sum = 0.0;
+        for _ in 0..20000 {
+            let mut attenuation = Vec3::zero();
+            let mut scattered = Ray::new(Vec3::zero(), Vec3::zero());
+            assert!(mix.scatter(&ray, &hit_rec, &mut attenuation, &mut scattered, &mut rng));
+            sum += attenuation.x;
+        }
+        assert!((sum / 20000.0 - 0.25).abs() < 0.02);
+        assert_eq!(mix.opacity(&ray, &hit_rec), 1.0);
+    }
+
+    #[test]
+    fn backplate_is_only_seen_by_camera_rays() {
+        let red = Image {
+            width: 1,
+            height: 1,
+            pixels: vec![1.0, 0.0, 0.0],
+            alpha: Vec::new()
+        };
+        let mut scene = Scene::new();
+        let mirror = scene.add_metal_material(Metal::new(Vec3::one(), 0.0));
+        scene.add_sphere(Sphere::new(Vec3::zero(), 1.0, mirror)).unwrap();
+        scene.set_backplate(Some(red));
+        let mut ray_tracer = RSRaytracer::new();
+        ray_tracer.get_settings_mut().samples_per_pixel = 2;
+
+        ray_tracer.get_settings_mut().region = Some(Region::new(0, 0, 1, 1));
+        ray_tracer.run(&scene);
+        let corner = ray_tracer.pixel_info(0, 0).unwrap();
+        assert!((corner.linear.x - 1.0).abs() < 1e-5 && corner.linear.y == 0.0);
+        assert_eq!(corner.alpha, 0.0);
+
+        // The mirror reflects the sky instead.
+        ray_tracer.get_settings_mut().region = Some(Region::new(WIDTH / 2, HEIGHT / 2, 1, 1));
+        ray_tracer.run(&scene);
+        assert!(ray_tracer.pixel_info(WIDTH / 2, HEIGHT / 2).unwrap().linear.y > 0.5);
+    }
+
+    #[test]
+    fn lights_cast_shadows() {
+        // Only the sun lights the ground, with an irradiance of pi so that white would show as one.
+        let mut scene = Scene::new();
+        scene.set_background(Background::Solid(Vec3::zero()));
+        let ground = scene.add_lambertian_material(Lambertian::new(Vec3::one() * 0.5));
+        scene.add_plane(Plane::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), ground)).unwrap();
+        let sun = scene.add_light(Light::directional(Vec3::new(0.0, -1.0, 0.0), Vec3::one() * consts::PI));
+        let mut ray_tracer = RSRaytracer::new();
+        ray_tracer.get_settings_mut().samples_per_pixel = 2;
+        ray_tracer.get_settings_mut().max_depth = 1; // Direct light only.
+        ray_tracer.get_settings_mut().region = Some(Region::new(WIDTH / 2, HEIGHT / 2, 1, 1));
+        let center = |ray_tracer: &mut RSRaytracer, scene: &Scene| {
+            ray_tracer.run(scene);
+            ray_tracer.pixel_info(WIDTH / 2, HEIGHT / 2).unwrap().linear
+        };
+        assert_eq!(sun, 0);
+        assert!((center(&mut ray_tracer, &scene).x - 0.5).abs() < 1e-4);
+
+        // A sphere above the middle of the view shades it.
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 5.0, 0.0), 1.0, ground)).unwrap();
+        assert_eq!(center(&mut ray_tracer, &scene).x, 0.0);
+    }
+
+    #[test]
+    fn raycasting_matches_the_direct_light_of_path_tracing() {
+        let mut scene = Scene::new();
+        scene.set_background(Background::Solid(Vec3::zero()));
+        let ground = scene.add_lambertian_material(Lambertian::new(Vec3::one() * 0.5));
+        scene.add_plane(Plane::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), ground)).unwrap();
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 5.0, 0.0), 1.0, ground)).unwrap();
+        scene.add_light(Light::directional(Vec3::new(0.0, -1.0, 0.0), Vec3::one() * consts::PI));
+        let mut ray_tracer = RSRaytracer::new();
+        ray_tracer.get_settings_mut().samples_per_pixel = 4;
+        ray_tracer.get_settings_mut().max_depth = 1; // Direct light only, as raycasting sees.
+        let mut render = |mode: RenderMode| {
+            ray_tracer.get_settings_mut().mode = mode;
+            let mut pixels = Vec::new();
+            for (x, y) in [(WIDTH / 2, HEIGHT / 2), (WIDTH / 3, HEIGHT - 1), (0, 0), (WIDTH - 1, HEIGHT / 4)].iter() {
+                ray_tracer.get_settings_mut().region = Some(Region::new(*x, *y, 1, 1));
+                ray_tracer.run(&scene);
+                let info = ray_tracer.pixel_info(*x, *y).unwrap();
+                pixels.push((info.linear, info.alpha, info.distance));
+            }
+            pixels
+        };
+
+        // Both see the same surfaces, in shadow or not, and light them alike.
+        let (traced, cast) = (render(RenderMode::PathTrace), render(RenderMode::Raycast));
+        for ((traced_color, traced_alpha, traced_distance), (color, alpha, distance)) in traced.iter().zip(cast.iter()) {
+            assert!((*traced_color - *color).length() < 1e-2);
+            assert_eq!((traced_alpha, traced_distance), (alpha, distance));
+        }
+        assert_eq!(cast[0].0.x, 0.0);
+        assert!((cast[1].0.x - 0.5).abs() < 1e-4);
+
+        // A cut out back face shaded black is black in both, rather than let through to the lit ground
+        // behind it.
+        let mut cutout = Lambertian::new(Vec3::one());
+        cutout.alpha = Alpha{mode: AlphaMode::Mask(0.5), value: 0.0, texture: None};
+        let cutout = scene.add_lambertian_material(cutout);
+        scene.set_back_face(cutout, BackFace::Black).unwrap();
+        scene.set_camera(Camera::new(Vec3::new(3.0, 5.0, 5.0), Vec3::new(3.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 40.0, (WIDTH as Float) / (HEIGHT as Float)));
+        scene.add_disk(Disk::new(Vec3::new(3.0, 2.5, 2.5), Vec3::new(0.0, -1.0, -1.0).normalized(), 1.0, cutout)).unwrap();
+        ray_tracer.get_settings_mut().region = Some(Region::new(WIDTH / 2, HEIGHT / 2, 1, 1));
+        ray_tracer.get_settings_mut().max_depth = 2; // Passing through counts as a bounce.
+        for mode in [RenderMode::PathTrace, RenderMode::Raycast].iter() {
+            ray_tracer.get_settings_mut().mode = *mode;
+            ray_tracer.run(&scene);
+            let info = ray_tracer.pixel_info(WIDTH / 2, HEIGHT / 2).unwrap();
+            assert!(info.linear.length() == 0.0 && info.alpha == 1.0, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn portals_only_reduce_noise() {
+        // Under a white sky, every bounce off the ground escapes, so it always shows as its albedo.
+        let mut scene = Scene::new();
+        scene.set_background(Background::Solid(Vec3::one()));
+        let ground = scene.add_lambertian_material(Lambertian::new(Vec3::one() * 0.5));
+        scene.add_plane(Plane::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), ground)).unwrap();
+        let mut ray_tracer = RSRaytracer::new();
+        ray_tracer.get_settings_mut().samples_per_pixel = 2000;
+        ray_tracer.get_settings_mut().max_depth = 2;
+        ray_tracer.get_settings_mut().region = Some(Region::new(WIDTH / 2, HEIGHT / 2, 1, 1));
+        let center = |ray_tracer: &mut RSRaytracer, scene: &Scene| {
+            ray_tracer.run(scene);
+            ray_tracer.pixel_info(WIDTH / 2, HEIGHT / 2).unwrap().linear
+        };
+        assert!((center(&mut ray_tracer, &scene).x - 0.5).abs() < 1e-4);
+
+        // Light through a portal is found both by sampling it and by bouncing through it, but counted once.
+        scene.add_portal(Portal::new(Vec3::new(-20.0, 2.0, -20.0), Vec3::new(40.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 40.0)));
+        assert!((center(&mut ray_tracer, &scene).x - 0.5).abs() < 0.02);
+    }
+
+    #[test]
+    fn depth_pass_encodes_distance_between_clips() {
+        let mut scene = Scene::new();
+        scene.add_sphere(Sphere::new(Vec3::zero(), 1.0, 0)).unwrap();
+        scene.set_camera(Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 20.0, (WIDTH as Float) / (HEIGHT as Float)));
+        let mut ray_tracer = RSRaytracer::new();
+        ray_tracer.get_settings_mut().samples_per_pixel = 1;
+        ray_tracer.get_settings_mut().max_depth = 1;
+        for (x, y) in [(WIDTH / 2, HEIGHT / 2), (0, 0)].iter() {
+            ray_tracer.get_settings_mut().region = Some(Region::new(*x, *y, 1, 1));
+            ray_tracer.run(&scene);
+        }
+        let center = ((HEIGHT / 2) * WIDTH + WIDTH / 2) as usize;
+        assert!((ray_tracer.pixel_info(WIDTH / 2, HEIGHT / 2).unwrap().distance.unwrap() - 4.0).abs() < 1e-2);
+        assert!(ray_tracer.pixel_info(0, 0).unwrap().distance.is_none());
+
+        // Fit to the only surface seen, and then between set clips, with misses at the far one.
+        assert!((ray_tracer.depth_clips().0 - 4.0).abs() < 1e-2);
+        ray_tracer.get_settings_mut().depth_clips = Some((2.0, 6.0));
+        let normalized = ray_tracer.depth_values(DepthEncoding::Normalized);
+        assert!((normalized[center] - 0.5).abs() < 1e-2);
+        assert_eq!(normalized[0], 1.0);
+        let linear = ray_tracer.depth_values(DepthEncoding::Linear);
+        assert!((linear[center] - 4.0).abs() < 1e-2);
+        assert_eq!(linear[0], 6.0);
+    }
+
+    #[test]
+    fn progressive_passes_accumulate_until_another_render() {
+        let mut scene = Scene::new();
+        scene.add_sphere(Sphere::new(Vec3::zero(), 1.0, 0)).unwrap();
+        let mut ray_tracer = RSRaytracer::new();
+        ray_tracer.get_settings_mut().samples_per_pixel = 3;
+        ray_tracer.get_settings_mut().max_depth = 1;
+        ray_tracer.get_settings_mut().region = Some(Region::new(0, 0, 4, 2));
+        for _pass in 0..3 {
+            ray_tracer.run_pass(&scene, 2);
+        }
+        assert_eq!(ray_tracer.average_samples(), 6.0);
+        let info = ray_tracer.pixel_info(1, 1).unwrap();
+        assert_eq!(info.samples, 6);
+        assert!(info.alpha >= 0.0 && info.alpha <= 1.0);
+
+        // A full render replaces the passes, and the next pass starts over.
+        ray_tracer.run(&scene);
+        assert_eq!(ray_tracer.average_samples(), 3.0);
+        ray_tracer.run_pass(&scene, 2);
+        assert_eq!(ray_tracer.average_samples(), 2.0);
+    }
+
+    #[test]
+    fn renders_stop_at_their_limits() {
+        let mut scene = Scene::new();
+        scene.add_sphere(Sphere::new(Vec3::zero(), 1.0, 0)).unwrap();
+        let mut ray_tracer = RSRaytracer::new();
+        ray_tracer.get_settings_mut().max_depth = 1;
+        ray_tracer.get_settings_mut().region = Some(Region::new(0, 0, 4, 4));
+
+        // Rows of 4 pixels at 2 samples are 8 samples each, so 20 samples covers two rows of the first pass.
+        ray_tracer.get_settings_mut().max_total_samples = Some(20);
+        ray_tracer.run_pass(&scene, 2);
+        assert!(ray_tracer.limit_reached());
+        assert_eq!(ray_tracer.pixel_info(3, 1).unwrap().samples, 2);
+        assert_eq!(ray_tracer.pixel_info(0, 2).unwrap().samples, SAMPLES_PER_PIXEL);
+
+        // Further passes trace nothing, and a new render starts a new budget.
+        ray_tracer.run_pass(&scene, 2);
+        assert_eq!(ray_tracer.pixel_info(0, 0).unwrap().samples, 2);
+        ray_tracer.get_settings_mut().max_total_samples = Some(32);
+        ray_tracer.get_settings_mut().samples_per_pixel = 2;
+        ray_tracer.run(&scene);
+        assert!(!ray_tracer.limit_reached());
+        assert_eq!(ray_tracer.average_samples(), 2.0);
+
+        // A time limit that has already passed stops the next pass before its first row.
+        ray_tracer.get_settings_mut().max_time = Some(Duration::from_secs(0));
+        ray_tracer.run_pass(&scene, 2);
+        assert!(ray_tracer.limit_reached());
+        assert_eq!(ray_tracer.average_samples(), 2.0);
+    }
+
+    // Each test runs on its own thread, so on Linux only this test's thread is lowered.
+    #[cfg(unix)]
+    #[test]
+    fn low_priority_renders_lower_the_thread_priority() {
+        let niceness = || unsafe { libc::getpriority(libc::PRIO_PROCESS as _, 0) };
+        let before = niceness();
+        let mut scene = Scene::new();
+        scene.add_sphere(Sphere::new(Vec3::zero(), 1.0, 0)).unwrap();
+        let mut ray_tracer = RSRaytracer::new();
+        ray_tracer.get_settings_mut().region = Some(Region::new(0, 0, 2, 2));
+        ray_tracer.get_settings_mut().samples_per_pixel = 1;
+        ray_tracer.get_settings_mut().low_priority = true;
+        ray_tracer.run(&scene);
+        assert_eq!(niceness(), (before + LOW_PRIORITY_NICENESS).min(19));
+        assert_eq!(ray_tracer.normal_niceness, Some(before));
+
+        // Raising it back may not be allowed, but the ray tracer stops holding it either way.
+        ray_tracer.get_settings_mut().low_priority = false;
+        ray_tracer.run(&scene);
+        assert_eq!(ray_tracer.normal_niceness, None);
+    }
+
+    #[test]
+    fn white_balance_is_applied_when_shown() {
+        let mut scene = Scene::new();
+        scene.set_background(Background::Solid(Vec3::new(0.2, 0.3, 0.6)));
+        let mut ray_tracer = RSRaytracer::new();
+        ray_tracer.get_settings_mut().samples_per_pixel = 1;
+        ray_tracer.get_settings_mut().region = Some(Region::new(0, 0, 2, 2));
+        ray_tracer.run(&scene);
+        let display = |rt: &RSRaytracer| rt.pixel_info(0, 0).unwrap().display;
+        let (r, _, b) = display(&ray_tracer);
+        assert!(b > r + 50);
+
+        // Auto white balance grays the background, without rendering again.
+        ray_tracer.get_settings_mut().auto_white_balance = true;
+        let (r, g, b) = display(&ray_tracer);
+        assert!((r as i32 - b as i32).abs() <= 1 && (g as i32 - b as i32).abs() <= 1);
+
+        // Balancing for warmer light than the scene's makes it bluer.
+        ray_tracer.get_settings_mut().white_balance.temperature = 4000.0;
+        let (r, _, b) = display(&ray_tracer);
+        assert!(b > r + 10);
+    }
+
+    #[test]
+    fn exposure_brackets_are_saved_from_one_render() {
+        let mut scene = Scene::new();
+        scene.set_background(Background::Solid(Vec3::one() * 0.18));
+        let mut ray_tracer = RSRaytracer::new();
+        ray_tracer.get_settings_mut().samples_per_pixel = 1;
+        ray_tracer.get_settings_mut().color_space = ColorSpace::Linear;
+        ray_tracer.get_settings_mut().region = Some(Region::new(0, 0, 2, 2));
+        ray_tracer.run(&scene);
+
+        // Each stop doubles the shown colors.
+        let gray = |rt: &mut RSRaytracer, exposure: Float| {
+            rt.get_settings_mut().exposure = exposure;
+            rt.pixel_info(0, 0).unwrap().display.0
+        };
+        assert_eq!((gray(&mut ray_tracer, -1.0), gray(&mut ray_tracer, 0.0), gray(&mut ray_tracer, 1.0)), (23, 46, 92));
+        assert_eq!(gray(&mut ray_tracer, 3.0), 255);
+
+        let directory = std::env::temp_dir().join(format!("rs_raytracer_bracket_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        ray_tracer.set_output_directory(&directory.to_string_lossy());
+        ray_tracer.get_settings_mut().exposure = 0.5;
+        let paths = ray_tracer.save_exposure_bracket(&[-2.0, 0.0, 2.0]).unwrap();
+        let names: Vec<String> = paths.iter().map(|path| std::path::Path::new(path).file_name().unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(names, ["bracket_-2ev.png", "bracket_+0ev.png", "bracket_+2ev.png"]);
+        assert!(paths.iter().all(|path| std::path::Path::new(path).is_file()));
+        assert_eq!(ray_tracer.get_settings().exposure, 0.5);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn larger_resolutions_trace_the_same_view() {
+        let mut scene = Scene::new();
+        scene.add_sphere(Sphere::new(Vec3::zero(), 1.0, 0)).unwrap();
+        scene.set_camera(Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 20.0, (WIDTH as Float) / (HEIGHT as Float)));
+        let mut ray_tracer = RSRaytracer::with_resolution(WIDTH * 3, HEIGHT * 3);
+        assert_eq!(ray_tracer.resolution(), (WIDTH * 3, HEIGHT * 3));
+        ray_tracer.get_settings_mut().samples_per_pixel = 1;
+        ray_tracer.get_settings_mut().region = Some(Region::new(WIDTH * 3 / 2, HEIGHT * 3 / 2, 1, 1));
+        ray_tracer.run(&scene);
+        assert!((ray_tracer.pixel_info(WIDTH * 3 / 2, HEIGHT * 3 / 2).unwrap().distance.unwrap() - 4.0).abs() < 1e-2);
+        assert!(ray_tracer.pixel_info(WIDTH * 3, 0).is_none());
+    }
+
+    #[test]
+    fn box_downsample_weights_by_covered_area() {
+        // Halving averages each 2x2 block.
+        let source = [(0, 0, 0, 255), (200, 0, 0, 255), (0, 100, 0, 255), (200, 100, 40, 255)];
+        let mut target = [0; 4];
+        box_downsample(&source, 2, 2, &mut target, 4, 1, 1);
+        assert_eq!(target, [100, 50, 10, 255]);
+
+        // Three pixels into two: the middle one is shared equally between both.
+        let source = [(0, 0, 0, 0), (90, 90, 90, 90), (180, 180, 180, 180)];
+        let mut target = [0; 8];
+        box_downsample(&source, 3, 1, &mut target, 8, 2, 1);
+        assert_eq!((target[0], target[4]), (30, 150));
+    }
+
+    #[test]
+    fn histogram_counts_stops_and_clipping() {
+        let mut scene = Scene::new();
+        let mut ray_tracer = RSRaytracer::new();
+        ray_tracer.get_settings_mut().samples_per_pixel = 4;
+        ray_tracer.get_settings_mut().region = Some(Region::new(0, 0, 16, 8));
+        let count = 16 * 8;
+        let mut render = |rt: &mut RSRaytracer, level: Float| {
+            scene.set_background(Background::Solid(Vec3::one() * level));
+            rt.run(&scene);
+            rt.histogram(14)
+        };
+
+        // A bin per stop, with the display's white starting the fourth from the end.
+        let histogram = render(&mut ray_tracer, 0.3);
+        assert_eq!(histogram.bins[8], count);
+        assert_eq!((histogram.under, histogram.over), (0, 0));
+        assert_eq!(histogram.bin_of(1.0), 10);
+        let histogram = render(&mut ray_tracer, 5.0);
+        assert_eq!((histogram.bins[12], histogram.over), (count, count));
+        let histogram = render(&mut ray_tracer, 0.0);
+        assert_eq!((histogram.bins[0], histogram.under), (count, count));
+
+        // The white balance is taken into account.
+        ray_tracer.get_settings_mut().white_balance.temperature = 3000.0;
+        let histogram = render(&mut ray_tracer, 0.9);
+        assert_eq!(histogram.over, count);
+    }
+
+    #[test]
+    fn false_color_bands_follow_the_stops_from_middle_gray() {
+        let band = |luminance: Float| false_color(luminance, Exposure::Normal, ColorSpace::Srgb);
+        assert_eq!(band(0.18), (0, 200, 64));
+        assert_eq!(band(0.36), (255, 140, 190));
+        assert_eq!(band(0.9), (255, 230, 0));
+        assert_eq!(band(0.18 / 64.0), (0, 64, 255));
+        assert_eq!(false_color(0.0, Exposure::Under, ColorSpace::Srgb), (128, 0, 160));
+        assert_eq!(false_color(2.0, Exposure::Over, ColorSpace::Srgb), (255, 0, 0));
+
+        // Between the bands, brighter is lighter gray.
+        let (dark, _, _) = band(0.06);
+        let (light, _, _) = band(0.5);
+        assert_eq!(band(0.06), (dark, dark, dark));
+        assert!(light > dark);
+    }
+
+    #[test]
+    fn quality_ladder_builds_on_each_stage() {
+        assert_eq!(ladder_stages(64), vec![1, 4, 16, 64]);
+        assert_eq!(ladder_stages(100), vec![1, 4, 16, 64, 100]);
+        assert_eq!(ladder_stages(0), vec![1]);
+
+        let mut scene = Scene::new();
+        scene.add_sphere(Sphere::new(Vec3::zero(), 1.0, 0)).unwrap();
+        let mut ray_tracer = RSRaytracer::new();
+        ray_tracer.get_settings_mut().max_depth = 1;
+        ray_tracer.get_settings_mut().region = Some(Region::new(0, 0, 2, 2));
+        let mut seen = Vec::new();
+        ray_tracer.run_ladder(&scene, 20, |ray_tracer, stage| {
+            seen.push((stage, ray_tracer.average_samples()));
+            Ok(())
+        }).unwrap();
+        assert_eq!(seen, vec![(1, 1.0), (4, 4.0), (16, 16.0), (20, 20.0)]);
+    }
+
+    #[test]
+    fn depth_limits_per_ray_kind_stop_their_bounces() {
+        let center_with = |mat_id: u32, diffuse: Option<u32>, specular: Option<u32>| {
+            let mut scene = Scene::new();
+            scene.add_lambertian_material(Lambertian::new(Vec3::one() * 0.5));
+            scene.add_metal_material(Metal::new(Vec3::one() * 0.9, 0.0));
+            scene.add_sphere(Sphere::new(Vec3::zero(), 1.0, mat_id)).unwrap();
+            scene.set_camera(Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 20.0, (WIDTH as Float) / (HEIGHT as Float)));
+            let mut ray_tracer = RSRaytracer::new();
+            let settings = ray_tracer.get_settings_mut();
+            settings.samples_per_pixel = 4;
+            settings.max_diffuse_depth = diffuse;
+            settings.max_specular_depth = specular;
+            settings.region = Some(Region::new(WIDTH / 2, HEIGHT / 2, 1, 1));
+            ray_tracer.run(&scene);
+            ray_tracer.pixel_info(WIDTH / 2, HEIGHT / 2).unwrap().linear
+        };
+
+        // Without lights, the spheres only show the sky they bounce rays to.
+        let (diffuse, metal) = (1, 2);
+        assert!(center_with(diffuse, None, Some(0)).y > 0.1);
+        assert_eq!(center_with(diffuse, Some(0), None).y, 0.0);
+        assert!(center_with(metal, Some(0), None).y > 0.1);
+        assert_eq!(center_with(metal, None, Some(0)).y, 0.0);
+    }
+
+    #[test]
+    fn camera_clips_cut_away_surfaces_for_camera_rays() {
+        let mut scene = Scene::new();
+        scene.add_sphere(Sphere::new(Vec3::zero(), 1.0, 0)).unwrap();
+        let mut camera = Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 20.0, (WIDTH as Float) / (HEIGHT as Float));
+        let mut ray_tracer = RSRaytracer::new();
+        ray_tracer.get_settings_mut().samples_per_pixel = 1;
+        ray_tracer.get_settings_mut().region = Some(Region::new(WIDTH / 2, HEIGHT / 2, 1, 1));
+        let mut center_distance = |camera: Camera| {
+            scene.set_camera(camera);
+            ray_tracer.run(&scene);
+            ray_tracer.pixel_info(WIDTH / 2, HEIGHT / 2).unwrap().distance
+        };
+
+        // Cutting into the sphere shows its inside, and moving the camera keeps the clips.
+        camera.set_clips(4.5, 100.0);
+        assert!((center_distance(camera).unwrap() - 6.0).abs() < 1e-2);
+        assert!((center_distance(camera.moved(0.0, 0.0, 1.0)).unwrap() - 5.0).abs() < 1e-2);
+        camera.set_clips(0.0, 3.0);
+        assert!(center_distance(camera.looking_at(Vec3::new(0.0, 0.1, 0.0))).is_none());
+    }
+
+    #[test]
+    fn rolling_turns_the_image_about_its_center() {
+        let camera = Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 40.0, 2.0);
+        let rolled = camera.rolled(90.0);
+        assert!((rolled.get_roll() - 90.0).abs() < 1e-3);
+        assert!((rolled.get_forward() - camera.get_forward()).length() < 1e-5);
+        assert!((rolled.get_vertical_fov() - 40.0).abs() < 1e-3);
+
+        // A point above the center shows to the right of it once the camera's right side is tipped up.
+        let (u, v) = rolled.project(Vec3::new(0.0, 1.0, 0.0)).unwrap();
+        assert!(u > 0.5 && (v - 0.5).abs() < 1e-4);
+        assert_eq!(rolled.project(Vec3::zero()).map(|(u, v)| ((u * 1e4).round(), (v * 1e4).round())), Some((5e3, 5e3)));
+
+        // Leveling undoes any roll, and looking at a point keeps it.
+        assert!((rolled.rolled(-30.0).looking_at(Vec3::new(0.0, 0.0, -1.0)).get_roll() - 60.0).abs() < 1e-3);
+        assert!(rolled.leveled().get_roll().abs() < 1e-3);
+        assert!((rolled.leveled().project(Vec3::new(0.0, 1.0, 0.0)).unwrap().0 - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn lens_rays_meet_at_the_focus_distance() {
+        let camera = Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 40.0, 2.0);
+        assert_eq!(camera.get_focus_distance(), 5.0);
+        let pinhole = camera.get_ray(0.3, 0.7);
+        let focused = pinhole.at(5.0);
+        for lens in [(0.0, 0.0), (0.0, 1.0), (-0.5, 0.4)].iter() {
+            let ray = camera.get_lens_ray(0.3, 0.7, 0.1, *lens);
+            assert!((ray.at(5.0) - focused).length() < 1e-4);
+            assert!((ray.origin - camera.get_position()).length() <= 0.1 + 1e-5);
+        }
+        // Nearer surfaces are seen from around the lens, so they blur.
+        let (a, b) = (camera.get_lens_ray(0.3, 0.7, 0.1, (1.0, 0.0)), camera.get_lens_ray(0.3, 0.7, 0.1, (-1.0, 0.0)));
+        assert!((a.at(2.0) - b.at(2.0)).length() > 0.05);
+    }
+
+    #[test]
+    fn focus_is_picked_from_the_surface_under_a_pixel() {
+        // A disk 5 in front of the camera, facing it, which ends short of the right edge of the image.
+        let mut scene = Scene::new();
+        scene.set_camera(Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 40.0, 2.0));
+        scene.add_disk(Disk::new(Vec3::new(-6.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0), 8.0, 0)).unwrap();
+        let rt = RSRaytracer::with_resolution(64, 32);
+
+        // The distance is along the view direction, so the whole wall is at the same focus distance.
+        assert!((rt.focus_distance_at(&scene, 32, 16).unwrap() - 5.0).abs() < 1e-4);
+        assert!((rt.focus_distance_at(&scene, 2, 30).unwrap() - 5.0).abs() < 1e-4);
+        assert!(rt.focus_distance_at(&scene, 63, 16).is_none());
+        assert!(rt.focus_distance_at(&scene, 64, 16).is_none());
+
+        // On a floor, which gets further away up the image, the depth is that seen through the pixel's center.
+        let mut scene = Scene::new();
+        scene.set_camera(Camera::new(Vec3::new(0.0, 1.0, 5.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 40.0, 2.0));
+        scene.add_plane(Plane::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 0)).unwrap();
+        for (x, y) in [(5, 31), (40, 20), (63, 17)].iter() {
+            let ray = rt.camera_ray(&scene, (*x as Float) + 0.5, (*y as Float) + 0.5, None);
+            let hit_rec = scene.hit(&ray, scene.get_camera().get_clip_range()).0.unwrap();
+            let expected = scene.get_camera().depth_of(hit_rec.p);
+            assert!((rt.focus_distance_at(&scene, *x, *y).unwrap() - expected).abs() < 1e-4 * expected);
+        }
+    }
+
+    #[test]
+    fn autofocus_focuses_lens_rays_on_the_middle_of_the_image() {
+        // The camera is focused on its look-at point, 10 away, behind a wall 4 in front of it.
+        let mut scene = Scene::new();
+        scene.set_camera(Camera::new(Vec3::new(0.0, 0.0, 10.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 40.0, 2.0));
+        scene.add_disk(Disk::new(Vec3::new(0.0, 0.0, 6.0), Vec3::new(0.0, 0.0, 1.0), 0.5, 0)).unwrap();
+        assert!((RSRaytracer::autofocus_distance(&scene).unwrap() - 4.0).abs() < 1e-4);
+
+        let mut rt = RSRaytracer::with_resolution(16, 8);
+        rt.get_settings_mut().physical_exposure = Some(PhysicalExposure{f_stop: 1.4, ..PhysicalExposure::new()});
+        // Rays from opposite sides of the lens cross again at the focus distance.
+        let crossing = |rt: &RSRaytracer| {
+            let (a, b) = (rt.camera_ray(&scene, 7.5, 3.5, Some((1.0, 0.0))), rt.camera_ray(&scene, 7.5, 3.5, Some((-1.0, 0.0))));
+            let (da, db) = (a.direction.normalized(), b.direction.normalized());
+            // Where the rays' X offsets cancel, along the view direction (-Z).
+            let t = (b.origin.x - a.origin.x) / (da.x - db.x);
+            scene.get_camera().depth_of(a.origin + da * t)
+        };
+        rt.run_pass(&scene, 1);
+        assert!((crossing(&rt) - 10.0).abs() < 1e-3);
+
+        rt.get_settings_mut().autofocus = true;
+        rt.run_pass(&scene, 1);
+        assert!((crossing(&rt) - 4.0).abs() < 1e-3);
+        assert_eq!(scene.get_camera().get_focus_distance(), 10.0);
+
+        // With nothing in the middle, the camera's own focus is kept.
+        let empty = Scene::new();
+        assert!(RSRaytracer::autofocus_distance(&empty).is_none());
+    }
+
+    #[test]
+    fn distortion_bends_rays_and_projections_alike() {
+        let mut camera = Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 60.0, 1.5);
+        let straight = camera;
+        camera.set_distortion(-0.2, 0.05);
+        assert_eq!(camera.looking_at(Vec3::new(0.0, 1.0, 0.0)).get_distortion(), (-0.2, 0.05));
+
+        // Projecting a point seen through any position gives that position back.
+        for (u, v) in [(0.1, 0.2), (0.5, 0.5), (0.95, 0.6), (0.0, 1.0)].iter() {
+            let (pu, pv) = camera.project(camera.get_ray(*u, *v).at(3.0)).unwrap();
+            assert!((pu - u).abs() < 1e-4 && (pv - v).abs() < 1e-4);
+        }
+
+        // Barrel distortion draws the edges in, so rays through the corners reach further out, while the center
+        // stays put.
+        let (corner, center) = (straight.get_ray(0.0, 0.0).direction.normalized(), camera.get_ray(0.5, 0.5).direction.normalized());
+        assert!(camera.get_ray(0.0, 0.0).direction.normalized().dot(&center) < corner.dot(&center));
+        assert!((center - straight.get_ray(0.5, 0.5).direction.normalized()).length() < 1e-6);
+        let (u, v) = camera.project(straight.get_ray(0.1, 0.5).at(3.0)).unwrap();
+        assert!(u > 0.1 && (v - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn shift_and_tilt_move_the_image_and_the_plane_of_focus() {
+        let mut camera = Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 40.0, 2.0);
+        let straight = camera;
+        camera.set_shift(0.0, 0.25);
+        camera.set_distortion(-0.1, 0.0);
+
+        // Shifting keeps the view direction, and moves what is seen up the image by the shift.
+        assert!((camera.get_forward() - straight.get_forward()).length() < 1e-6);
+        let (u, v) = camera.project(Vec3::zero()).unwrap();
+        assert!((u - 0.5).abs() < 1e-5 && (v - 0.25).abs() < 1e-5);
+        for (u, v) in [(0.1, 0.2), (0.5, 0.5), (0.9, 0.95)].iter() {
+            let (pu, pv) = camera.project(camera.get_ray(*u, *v).at(3.0)).unwrap();
+            assert!((pu - u).abs() < 1e-4 && (pv - v).abs() < 1e-4);
+        }
+        assert_eq!(camera.looking_at(Vec3::zero()).get_shift(), (0.0, 0.25));
+
+        // Tilted, rays from across the lens meet nearer above the middle and further below it.
+        let mut camera = straight;
+        camera.set_tilt(30.0, 0.0);
+        let focus_depth = |u: Float, v: Float| {
+            let (a, b) = (camera.get_lens_ray(u, v, 0.1, (1.0, 0.0)), camera.get_lens_ray(u, v, 0.1, (-1.0, 0.0)));
+            // The rays have the same direction along the view, so they cross where their sideways gap closes.
+            let gap = |t: Float| (a.at(t) - b.at(t)).x;
+            let t = gap(0.0) / (gap(0.0) - gap(1.0));
+            camera.depth(a.at(t))
+        };
+        assert!((focus_depth(0.5, 0.5) - 5.0).abs() < 1e-3);
+        assert!(focus_depth(0.5, 0.8) < 4.9);
+        assert!(focus_depth(0.5, 0.2) > 5.1);
+        let top = focus_depth(0.5, 0.8);
+        let point = camera.get_ray(0.5, 0.8).at(top);
+        assert!(point.dot(&camera.focus_normal()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn id_passes_mask_objects_and_materials() {
+        let mut scene = Scene::new();
+        let ground = scene.add_lambertian_material(Lambertian::new(Vec3::one() * 0.5));
+        scene.add_sphere(Sphere::new(Vec3::zero(), 1.0, 0)).unwrap();
+        scene.add_plane(Plane::new(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), ground)).unwrap();
+        scene.set_camera(Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 40.0, (WIDTH as Float) / (HEIGHT as Float)));
+        let mut ray_tracer = RSRaytracer::new();
+        ray_tracer.get_settings_mut().samples_per_pixel = 64;
+        ray_tracer.get_settings_mut().max_depth = 1;
+
+        // The sphere fills the middle, the ground shows in the bottom corner, and the sky at the top.
+        for (x, y) in [(WIDTH / 2, HEIGHT / 2), (0, HEIGHT - 1), (0, 0)].iter() {
+            ray_tracer.get_settings_mut().region = Some(Region::new(*x, *y, 1, 1));
+            ray_tracer.run(&scene);
+        }
+        let ids = |x: u32, y: u32| {
+            let info = ray_tracer.pixel_info(x, y).unwrap();
+            (info.object_id, info.material_id)
+        };
+        assert_eq!(ids(WIDTH / 2, HEIGHT / 2), (Some(0), Some(0)));
+        assert_eq!(ids(0, HEIGHT - 1), (Some(1), Some(ground)));
+        assert_eq!(ids(0, 0), (None, None));
+
+        // With coverage, the pixels on the sphere's top edge are partly covered by it.
+        ray_tracer.get_settings_mut().id_coverage = true;
+        ray_tracer.get_settings_mut().region = Some(Region::new(WIDTH / 2, 0, 1, HEIGHT / 2 + 1));
+        ray_tracer.run(&scene);
+        let mask = ray_tracer.id_mask(IdPass::Object, 0);
+        let column: Vec<Float> = (0..=(HEIGHT / 2)).map(|y| mask[(y * WIDTH + WIDTH / 2) as usize]).collect();
+        assert_eq!(column[0], 0.0);
+        assert_eq!(column[(HEIGHT / 2) as usize], 1.0);
+        assert!(column.iter().any(|coverage| *coverage > 0.0 && *coverage < 1.0));
+
+        // Progressive passes of a sample each add up to the same partial coverage, rather than each replacing
+        // the last with all or nothing.
+        for _ in 0..64 {
+            ray_tracer.run_pass(&scene, 1);
+        }
+        let mask = ray_tracer.id_mask(IdPass::Object, 0);
+        let column: Vec<Float> = (0..=(HEIGHT / 2)).map(|y| mask[(y * WIDTH + WIDTH / 2) as usize]).collect();
+        assert_eq!(column[(HEIGHT / 2) as usize], 1.0);
+        assert!(column.iter().any(|coverage| *coverage > 0.0 && *coverage < 1.0));
+    }
+
+    #[test]
+    fn background_is_transparent() {
+        let mut scene = Scene::new();
+        scene.add_sphere(Sphere::new(Vec3::zero(), 1.0, 0)).unwrap();
+        let mut ray_tracer = RSRaytracer::new();
+        let settings = ray_tracer.get_settings_mut();
+        settings.samples_per_pixel = 2;
+        settings.max_depth = 2;
+        settings.transparent_background = true;
+
+        // The sphere is in the middle of the default view, with the sky in the corners.
+        for (x, y, alpha) in [(WIDTH / 2, HEIGHT / 2, 1.0), (0, 0, 0.0)].iter() {
+            ray_tracer.get_settings_mut().region = Some(Region::new(*x, *y, 1, 1));
+            ray_tracer.run(&scene);
+            let info = ray_tracer.pixel_info(*x, *y).unwrap();
+            assert_eq!(info.alpha, *alpha);
+            assert_eq!(info.linear.length() == 0.0, *alpha == 0.0);
+        }
+    }
+
+    fn unit_sphere() -> Sphere {
+        Sphere::new(Vec3::zero(), 1.0, 0)
+    }
+
+    // Surface normals face against the ray, and front_face says whether that is the outward side.
+    fn assert_faces_ray(hr: &HitRecord, ray: &Ray) {
+        assert!(hr.n.dot(&ray.direction) <= 0.0);
+        assert!((hr.n.length() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn sphere_hit_from_outside() {
+        let ray = Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        let hr = unit_sphere().hit(&ray, Interval::new(0.001, Float::MAX)).expect("ray at the center should hit");
+        assert!((hr.t - 4.0).abs() < 1e-5);
+        assert!((hr.n.z + 1.0).abs() < 1e-5);
+        assert!(hr.front_face);
+        assert_faces_ray(&hr, &ray);
+
+        // t is in units of the direction, which needn't be normalized.
+        let ray = Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 2.0));
+        let hr = unit_sphere().hit(&ray, Interval::new(0.001, Float::MAX)).unwrap();
+        assert!((hr.t - 2.0).abs() < 1e-5);
+        assert!((hr.p.z + 1.0).abs() < 1e-5);
+
+        // Pointing away misses.
+        let ray = Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(unit_sphere().hit(&ray, Interval::new(0.001, Float::MAX)).is_none());
+    }
+
+    #[test]
+    fn sphere_tangent_rays() {
+        // Touches the top of the sphere. The ray runs across the normal there, so which side it sees is
+        // arbitrary, but the normal is still vertical.
+        let ray = Ray::new(Vec3::new(-5.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let hr = unit_sphere().hit(&ray, Interval::new(0.001, Float::MAX)).expect("tangent ray should hit");
+        assert!((hr.t - 5.0).abs() < 1e-3);
+        assert!(hr.n.y.abs() > 0.999);
+
+        let ray = Ray::new(Vec3::new(-5.0, 1.001, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(unit_sphere().hit(&ray, Interval::new(0.001, Float::MAX)).is_none());
+    }
+
+    #[test]
+    fn sphere_hit_from_inside() {
+        // Leaves through the far side, seeing the back face with the normal turned inwards.
+        let ray = Ray::new(Vec3::new(0.0, 0.5, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let hr = unit_sphere().hit(&ray, Interval::new(0.001, Float::MAX)).expect("ray inside should exit");
+        assert!((hr.t - (0.75 as Float).sqrt()).abs() < 1e-5);
+        assert!(!hr.front_face);
+        assert!(hr.n.x < 0.0);
+        assert_faces_ray(&hr, &ray);
+    }
+
+    #[test]
+    fn sphere_negative_radius_is_hollow() {
+        // The same surface with its normals flipped, e.g. for the inside of a glass bubble. Rays from outside
+        // hit it where they would a solid sphere, but on its back face.
+        let bubble = Sphere::new(Vec3::zero(), -1.0, 0);
+        let ray = Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        let hr = bubble.hit(&ray, Interval::new(0.001, Float::MAX)).expect("ray should hit the bubble");
+        assert!((hr.t - 4.0).abs() < 1e-5);
+        assert!(!hr.front_face);
+        assert_faces_ray(&hr, &ray);
+        assert!(hr.uv_scale > 0.0);
+
+        let ray = Ray::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0));
+        assert!(bubble.hit(&ray, Interval::new(0.001, Float::MAX)).unwrap().front_face);
+
+        // Points are mapped the same as on a solid sphere.
+        for direction in [Vec3::new(1.0, 0.2, 0.3), Vec3::new(-0.4, -0.9, 0.1)].iter() {
+            let ray = Ray::new(*direction * 5.0, -*direction);
+            let solid = unit_sphere().hit(&ray, Interval::new(0.001, Float::MAX)).unwrap();
+            let hollow = bubble.hit(&ray, Interval::new(0.001, Float::MAX)).unwrap();
+            assert!((solid.u - hollow.u).abs() < 1e-5 && (solid.v - hollow.v).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn shells_are_entered_and_left_twice() {
+        // Through the middle: into the glass, out into the hollow, back into the glass, and out again.
+        let shell = Sphere::shell(Vec3::zero(), 1.0, 0.1, 0).unwrap();
+        let ray = Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        let mut t = 0.001;
+        let mut hits = Vec::new();
+        while let Some(hr) = shell.iter().filter_map(|sphere| sphere.hit(&ray, Interval::new(t, Float::MAX))).min_by(|a, b| a.t.partial_cmp(&b.t).unwrap()) {
+            assert_faces_ray(&hr, &ray);
+            hits.push((hr.t, hr.front_face));
+            t = hr.t + 0.001;
+        }
+        let expected = [(4.0, true), (4.1, false), (5.9, true), (6.0, false)];
+        assert_eq!(hits.len(), expected.len());
+        for ((t, front_face), (expected_t, expected_front_face)) in hits.iter().zip(expected.iter()) {
+            assert!((t - expected_t).abs() < 1e-4 && front_face == expected_front_face);
+        }
+
+        assert!(Sphere::shell(Vec3::zero(), 1.0, 1.0, 0).is_err());
+        assert!(Sphere::shell(Vec3::zero(), 1.0, 0.0, 0).is_err());
+    }
+
+    #[test]
+    fn sphere_hits_respect_the_interval() {
+        // The ray enters at t = 4 and leaves at t = 6. Both ends of the interval are included.
+        let ray = Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        let hit = |min: Float, max: Float| unit_sphere().hit(&ray, Interval::new(min, max)).map(|hr| (hr.t, hr.front_face));
+        assert_eq!(hit(0.001, 4.0), Some((4.0, true)));
+        assert_eq!(hit(4.0, 10.0), Some((4.0, true)));
+        // Starting past the entry finds the exit.
+        assert_eq!(hit(4.001, 10.0), Some((6.0, false)));
+        assert_eq!(hit(4.001, 6.0), Some((6.0, false)));
+        // Neither root in the interval misses.
+        assert_eq!(hit(0.001, 3.999), None);
+        assert_eq!(hit(4.001, 5.999), None);
+        assert_eq!(hit(6.001, 10.0), None);
+        assert_eq!(hit(4.0, 3.0), None);
+    }
+}
@@ -0,0 +1,158 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::thread::JoinHandle;
+
+use log::debug;
+
+use crate::error::{RaytracerError, Result};
+
+// --------------------------------------------------
+// Saver
+// --------------------------------------------------
+// Writing large images to disk takes long enough to stall the viewer, so the ray tracer hands out the encoded
+// pixels as save jobs that can be written on another thread. Only the file writing moves off the viewer's
+// thread: the pixels are taken from the buffer at once, so renders can carry on while the file is written.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SaveFormat {
+    Ppm, // Plain text RGB.
+    Png // 8-bit RGBA.
+}
+
+// An image taken from the ray tracer, ready to be written to a file.
+pub struct SaveJob {
+    pub path: String,
+    pub format: SaveFormat,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8> // RGB for PPM and RGBA for PNG, in rows from the top left.
+}
+
+impl SaveJob {
+    pub fn write(&self) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        match self.format {
+            SaveFormat::Ppm => {
+                // P3
+                // WIDTH HEIGHT
+                // MAX_VALUE
+                write!(writer, "P3\n{} {}\n255\n", self.width, self.height)?;
+                // Pixels (in rows, left to right, top to bottom).
+                for rgb in self.data.chunks_exact(3) {
+                    writeln!(writer, "{} {} {}", rgb[0], rgb[1], rgb[2])?;
+                }
+                writer.flush()?;
+            }
+            SaveFormat::Png => {
+                let mut encoder = png::Encoder::new(writer, self.width, self.height);
+                encoder.set_color(png::ColorType::RGBA);
+                encoder.set_depth(png::BitDepth::Eight);
+                let mut writer = encoder.write_header().map_err(std::io::Error::from)?;
+                writer.write_image_data(&self.data).map_err(std::io::Error::from)?;
+            }
+        }
+        debug!(target: "io", "Finished writing {}.", self.path);
+        Ok(())
+    }
+}
+
+// Writes save jobs on a background thread, one batch at a time. The viewer polls it for the outcome.
+pub struct BackgroundSaver {
+    in_flight: Option<(Vec<String>, JoinHandle<Result<()>>)> // Paths being written, and the thread writing them.
+}
+
+impl Default for BackgroundSaver {
+    fn default() -> BackgroundSaver {
+        BackgroundSaver::new()
+    }
+}
+
+impl BackgroundSaver {
+    pub fn new() -> BackgroundSaver {
+        BackgroundSaver {
+            in_flight: None
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.in_flight.is_some()
+    }
+
+    // Paths of the files being written, if any.
+    pub fn writing(&self) -> &[String] {
+        self.in_flight.as_ref().map_or(&[], |(paths, _)| &paths[..])
+    }
+
+    // Starts writing the jobs in order, stopping at the first that fails. Refused while another batch is
+    // being written, so that two saves never write the same file at once.
+    pub fn start(&mut self, jobs: Vec<SaveJob>) -> Result<()> {
+        if let Some((paths, _)) = &self.in_flight {
+            return Err(RaytracerError::SaveInProgress(paths.join(", ")))
+        }
+        let paths = jobs.iter().map(|job| job.path.clone()).collect();
+        let handle = std::thread::spawn(move || jobs.iter().try_for_each(|job| job.write()));
+        self.in_flight = Some((paths, handle));
+        Ok(())
+    }
+
+    // The paths and outcome of the batch being written once it is done, or None while it is still being
+    // written (or if there is none).
+    pub fn poll(&mut self) -> Option<(Vec<String>, Result<()>)> {
+        match &self.in_flight {
+            Some((_, handle)) if handle.is_finished() => self.wait(),
+            _ => None
+        }
+    }
+
+    // Waits for the batch being written to finish, e.g. before quitting.
+    pub fn wait(&mut self) -> Option<(Vec<String>, Result<()>)> {
+        let (paths, handle) = self.in_flight.take()?;
+        let result = handle.join().unwrap_or_else(|_| Err(RaytracerError::Io(std::io::Error::other("the saving thread panicked"))));
+        Some((paths, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_save_at_a_time() {
+        let directory = std::env::temp_dir().join(format!("rs_raytracer_saver_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let job = |name: &str, format: SaveFormat, channels: usize| SaveJob {
+            path: directory.join(name).to_string_lossy().into_owned(),
+            format: format,
+            width: 2,
+            height: 1,
+            data: vec![255; 2 * channels]
+        };
+
+        let mut saver = BackgroundSaver::new();
+        assert!(saver.poll().is_none());
+        saver.start(vec![job("a.ppm", SaveFormat::Ppm, 3), job("a.png", SaveFormat::Png, 4)]).unwrap();
+        assert!(saver.is_busy());
+        assert_eq!(saver.writing().len(), 2);
+        match saver.start(vec![job("b.png", SaveFormat::Png, 4)]) {
+            Err(RaytracerError::SaveInProgress(paths)) => assert!(paths.ends_with("a.png")),
+            _ => panic!("a second save should wait for the first")
+        }
+        let (paths, result) = saver.wait().unwrap();
+        assert!(result.is_ok());
+        assert_eq!(paths.len(), 2);
+        assert!(!saver.is_busy());
+        assert_eq!(std::fs::read_to_string(&paths[0]).unwrap(), "P3\n2 1\n255\n255 255 255\n255 255 255\n");
+        assert!(std::path::Path::new(&paths[1]).is_file());
+
+        // Failures are reported once the thread is done, and free the saver for the next save.
+        saver.start(vec![job("missing/c.png", SaveFormat::Png, 4)]).unwrap();
+        while saver.is_busy() {
+            match saver.poll() {
+                Some((_, result)) => assert!(result.is_err()),
+                None => std::thread::sleep(std::time::Duration::from_millis(1))
+            }
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,1607 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+use log::debug;
+
+use crate::assets::{AssetManager, MeshHandle};
+use crate::bvh::Bvh;
+use crate::error::{RaytracerError, Result};
+use crate::hierarchy::TransformGraph;
+use crate::image::Image;
+use crate::light::{Light, Portal};
+use crate::math::*;
+use crate::pointcloud::PointCloud;
+use crate::primitives::*;
+use crate::raytracer::{Camera, CoatedMaterial, Dielectric, Hittable, HitRecord, Lambertian, Material, Metal, MixMaterial, ObjectMemory, PbrMaterial, Sphere, WIDTH, HEIGHT};
+use crate::sdf::SdfObject;
+use crate::sky::Sky;
+
+// --------------------------------------------------
+// Background
+// --------------------------------------------------
+// Color seen by rays that escape the scene. As there are no other lights, this also lights the scene.
+#[derive(Clone, Copy)]
+pub enum Background {
+    Gradient { bottom: Vec3, top: Vec3 }, // Blended by the ray's vertical direction.
+    Solid(Vec3),
+    Sky(Sky) // Daylight for a sun direction and haziness. Add its sun_light to the scene to light it by the sun as well.
+}
+
+impl Background {
+    // White to light blue, as in Ray Tracing in One Weekend.
+    pub fn sky() -> Background {
+        Background::Gradient {
+            bottom: Vec3::new(1.0, 1.0, 1.0),
+            top: Vec3::new(0.5, 0.7, 1.0)
+        }
+    }
+
+    pub fn color(&self, ray: &Ray) -> Vec3 {
+        match self {
+            Background::Gradient{bottom, top} => {
+                let direction = ray.direction.normalized();
+                let t = 0.5 * (direction.y + 1.0);
+                (1.0 - t) * *bottom + t * *top
+            }
+            Background::Solid(color) => *color,
+            Background::Sky(sky) => sky.radiance(&ray.direction)
+        }
+    }
+
+    // Average color over all directions, from evenly spread ones on a Fibonacci sphere.
+    pub fn average(&self) -> Vec3 {
+        const DIRECTIONS: u32 = 256;
+        let golden_angle = consts::PI * (3.0 - (5.0 as Float).sqrt());
+        let mut sum = Vec3::zero();
+        for i in 0..DIRECTIONS {
+            let y = 1.0 - 2.0 * ((i as Float) + 0.5) / (DIRECTIONS as Float);
+            let radius = (1.0 - y * y).sqrt();
+            let angle = golden_angle * (i as Float);
+            sum += self.color(&Ray::new(Vec3::zero(), Vec3::new(radius * angle.cos(), y, radius * angle.sin())));
+        }
+        sum / (DIRECTIONS as Float)
+    }
+}
+
+
+// --------------------------------------------------
+// Scene
+// --------------------------------------------------
+// How spheres are stored. Both render the same. Soa is usually faster for scenes made of many spheres.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SphereLayout {
+    Aos, // Alongside the other primitives.
+    Soa // In a SphereSoa.
+}
+
+// What a material does where rays hit the back of a surface.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BackFace {
+    Shade, // Like the front. Needed by dielectrics, which are hit from inside.
+    Black, // Absorbs all light, which shows where open meshes are seen from behind.
+    Cull // Invisible, so rays pass on to whatever is behind, e.g. for single-sided lights or walls seen from outside a room.
+}
+
+// Which kinds of rays see an object. Rays that don't see it pass through as if it weren't there.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Visibility {
+    pub camera: bool, // Seen directly by the camera.
+    pub shadows: bool, // Casts shadows.
+    pub reflections: bool // Seen in reflections and refractions, and bounces light onto other objects.
+}
+
+impl Visibility {
+    pub fn all() -> Visibility {
+        Visibility {
+            camera: true,
+            shadows: true,
+            reflections: true
+        }
+    }
+
+    fn sees(&self, kind: RayKind) -> bool {
+        match kind {
+            RayKind::Camera => self.camera,
+            RayKind::Shadow => self.shadows,
+            RayKind::Diffuse | RayKind::Specular => self.reflections
+        }
+    }
+}
+
+// What the scene keeps about each object besides its geometry.
+#[derive(Clone)]
+struct ObjectInfo {
+    visibility: Visibility,
+    name: Option<String>, // From the scene file or the builder, for looking the object up and in logs.
+    node: Option<u32> // Transform node the object moves with, if any.
+}
+
+impl ObjectInfo {
+    fn new() -> ObjectInfo {
+        ObjectInfo {
+            visibility: Visibility::all(),
+            name: None,
+            node: None
+        }
+    }
+}
+
+// Culled back faces of one object skipped by a single ray before it is counted as a miss.
+const MAX_CULLED_HITS: u32 = 16;
+// How far past a culled back face the next search along the ray starts, as ray intervals include their ends.
+const CULL_STEP: Float = 1e-4;
+
+// Refers to an object in one of the scene's object lists.
+#[derive(Clone, Copy)]
+enum ObjectRef {
+    Primitive(u32),
+    Sphere(u32),
+    Object(u32)
+}
+
+// Acceleration structure over the scene's objects.
+struct SceneBvh {
+    bvh: Bvh,
+    bounded: Vec<ObjectRef>, // Object for each BVH item.
+    leaf_depths: Vec<u32>, // Depth of the leaf holding each BVH item.
+    unbounded: Vec<ObjectRef> // Objects without bounds, tested against every ray.
+}
+
+// Counts and estimated memory of a scene, to anticipate memory pressure before rendering large ones.
+// Objects shared between several places in the scene (e.g. a mesh asset added twice) are counted once.
+#[derive(Clone, Copy, Debug)]
+pub struct SceneStats {
+    pub objects: usize,
+    pub elements: usize, // Triangles, points, segments, and single shapes.
+    pub geometry_memory: usize,
+    pub bvh_nodes: usize, // Of the scene BVH and the objects' own BVHs.
+    pub bvh_memory: usize,
+    pub materials: u32,
+    pub textures: usize, // Loaded as assets. Textures made directly by loaders aren't known to the scene.
+    pub texture_memory: usize, // Of the texture assets and the backplate.
+    pub framebuffer_memory: usize // Of the ray tracer's buffers. Zero for the scene alone.
+}
+
+impl SceneStats {
+    pub fn total_memory(&self) -> usize {
+        self.geometry_memory + self.bvh_memory + self.texture_memory + self.framebuffer_memory
+    }
+}
+
+impl fmt::Display for SceneStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mb = |bytes: usize| (bytes as f64) / (1024.0 * 1024.0);
+        writeln!(f, "Objects:      {} ({} elements, {:.1} MB)", self.objects, self.elements, mb(self.geometry_memory))?;
+        writeln!(f, "BVH nodes:    {} ({:.1} MB)", self.bvh_nodes, mb(self.bvh_memory))?;
+        writeln!(f, "Materials:    {}", self.materials)?;
+        writeln!(f, "Textures:     {} ({:.1} MB)", self.textures, mb(self.texture_memory))?;
+        writeln!(f, "Framebuffers: {:.1} MB", mb(self.framebuffer_memory))?;
+        write!(f, "Total memory: {:.1} MB", mb(self.total_memory()))
+    }
+}
+
+// The objects, materials, camera, and background to render. Independent of the ray tracer, so the same
+// scene can be rendered by several ray tracers and a ray tracer can render several scenes.
+// Scenes are Send + Sync, so they can be put in an Arc and read from many threads at once.
+pub struct Scene {
+    primitives: Vec<Primitive>,
+    spheres: SphereSoa, // Only used with SphereLayout::Soa.
+    sphere_layout: SphereLayout,
+    objects: Vec<Arc<dyn Hittable>>, // Aggregates (meshes, CSG, ...) and custom types.
+    primitive_info: Vec<ObjectInfo>, // For each primitive,
+    sphere_info: Vec<ObjectInfo>, // sphere,
+    object_info: Vec<ObjectInfo>, // and object.
+    last_object: Option<ObjectRef>, // Most recently added, for setting its visibility.
+    materials: Vec<Arc<dyn Material>>,
+    material_names: HashMap<String, u32>,
+    back_faces: Vec<BackFace>, // For each material.
+    cam: Camera, // The active camera, which renders.
+    cameras: Vec<(String, Camera)>, // Named viewpoints that can be made the active camera.
+    background: Background,
+    backplate: Option<Image>, // Seen by camera rays that miss, in place of the background.
+    lights: Vec<Light>,
+    portals: Vec<Portal>, // Openings the background is sampled through.
+    assets: AssetManager, // Files shared by the objects and materials.
+    transforms: TransformGraph, // Nodes objects are attached to, to move them in groups.
+    scene_bvh: OnceLock<SceneBvh> // Built on demand when rendering. Cleared whenever objects change.
+}
+
+impl Default for Scene {
+    fn default() -> Scene {
+        Scene::new()
+    }
+}
+
+impl Scene {
+    // An empty scene with the default material, camera, and background.
+    pub fn new() -> Scene {
+        // Add a single default material so that default 0 indexes don't fail.
+        let mut mats: Vec<Arc<dyn Material>> = Vec::new();
+        mats.push(Arc::new(Lambertian::new(Vec3::one())));
+
+        Scene {
+            primitives: Vec::new(),
+            spheres: SphereSoa::new(),
+            sphere_layout: SphereLayout::Aos,
+            objects: Vec::new(),
+            primitive_info: Vec::new(),
+            sphere_info: Vec::new(),
+            object_info: Vec::new(),
+            last_object: None,
+            materials: mats,
+            material_names: HashMap::new(),
+            back_faces: vec![BackFace::Shade],
+            cam: Scene::default_camera(),
+            cameras: Vec::new(),
+            background: Background::sky(),
+            backplate: None,
+            lights: Vec::new(),
+            portals: Vec::new(),
+            assets: AssetManager::new(),
+            transforms: TransformGraph::new(),
+            scene_bvh: OnceLock::new()
+        }
+    }
+
+    pub fn builder() -> SceneBuilder {
+        SceneBuilder {
+            scene: Scene::new(),
+            error: None
+        }
+    }
+
+    fn default_camera() -> Camera {
+        let look_from = Vec3::new(13.0, 2.0, 3.0); //Vec3::new(-2.0, 2.0, 1.0);
+        let look_at = Vec3::new(0.0, 0.0, 0.0); //Vec3::new(0.0, 0.0, -1.0);
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let aspect_ratio = (WIDTH as Float) / (HEIGHT as Float);
+        let vertical_fov = 20.0;
+        Camera::new(look_from, look_at, up, vertical_fov, aspect_ratio)
+    }
+
+    // Removes all objects, materials (except the default one), and assets and resets the camera and background, e.g. before reloading.
+    pub fn clear(&mut self) {
+        self.primitives.clear();
+        self.spheres.clear();
+        self.objects.clear();
+        self.primitive_info.clear();
+        self.sphere_info.clear();
+        self.object_info.clear();
+        self.last_object = None;
+        self.materials.truncate(1);
+        self.material_names.clear();
+        self.back_faces.truncate(1);
+        self.back_faces[0] = BackFace::Shade;
+        self.assets.clear();
+        self.transforms.clear();
+        self.scene_bvh = OnceLock::new();
+        self.cam = Scene::default_camera();
+        self.cameras.clear();
+        self.background = Background::sky();
+        self.backplate = None;
+        self.lights.clear();
+        self.portals.clear();
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.primitives.len() + self.spheres.len() + self.objects.len()
+    }
+
+    pub fn get_sphere_layout(&self) -> SphereLayout {
+        self.sphere_layout
+    }
+
+    // Moves existing spheres over to the new layout. Later spheres are added in it as well.
+    pub fn set_sphere_layout(&mut self, layout: SphereLayout) {
+        if layout == self.sphere_layout {
+            return
+        }
+        self.sphere_layout = layout;
+        match layout {
+            SphereLayout::Soa => {
+                let prims: Vec<(Primitive, ObjectInfo)> = self.primitives.drain(..).zip(self.primitive_info.drain(..)).collect();
+                for (prim, info) in prims {
+                    match prim {
+                        Primitive::Sphere(sphere) => {
+                            self.spheres.push(sphere);
+                            self.sphere_info.push(info);
+                        }
+                        prim => {
+                            self.primitives.push(prim);
+                            self.primitive_info.push(info);
+                        }
+                    }
+                }
+            }
+            SphereLayout::Aos => {
+                for idx in 0..self.spheres.len() {
+                    self.primitives.push(Primitive::Sphere(self.spheres.get(idx)));
+                }
+                self.spheres.clear();
+                self.primitive_info.append(&mut self.sphere_info);
+            }
+        }
+        // Spheres moved, so the last object can't be found anymore.
+        self.last_object = None;
+        self.scene_bvh = OnceLock::new();
+    }
+
+    pub fn material_count(&self) -> u32 {
+        self.materials.len() as u32
+    }
+
+    pub fn get_material(&self, idx: u32) -> Result<&dyn Material> {
+        self.materials.get(idx as usize).map(|mat| mat.as_ref()).ok_or(RaytracerError::InvalidMaterial(idx))
+    }
+
+    // The material itself rather than a reference, for building materials on top of it (e.g. CoatedMaterial::from_shared).
+    pub fn get_shared_material(&self, idx: u32) -> Result<Arc<dyn Material>> {
+        self.materials.get(idx as usize).cloned().ok_or(RaytracerError::InvalidMaterial(idx))
+    }
+
+    pub fn get_material_by_name(&self, name: &str) -> Option<u32> {
+        self.material_names.get(name).copied()
+    }
+
+    // A material can be given several names, in which case the alphabetically first is returned.
+    pub fn get_material_name(&self, idx: u32) -> Option<&str> {
+        self.material_names.iter().filter(|(_, mat_id)| **mat_id == idx).map(|(name, _)| name.as_str()).min()
+    }
+
+    // The material's name and index for logs, e.g. "'gold' (material 2)", or just its index if it has no name.
+    pub fn describe_material(&self, idx: u32) -> String {
+        match self.get_material_name(idx) {
+            Some(name) => format!("'{}' (material {})", name, idx),
+            None => format!("material {}", idx)
+        }
+    }
+
+    // Replaces a material in place, keeping its index, names, and back face setting, so that objects using it
+    // render with the new one. Materials built on top of the old one (e.g. coats and mixes) keep it.
+    pub fn set_material<M: Material + 'static>(&mut self, idx: u32, mat: M) -> Result<()> {
+        let slot = self.materials.get_mut(idx as usize).ok_or(RaytracerError::InvalidMaterial(idx))?;
+        *slot = Arc::new(mat);
+        Ok(())
+    }
+
+    // The material as its type, e.g. to read a Metal's fuzz. Fails if it is of another type.
+    pub fn get_material_as<T: Material + 'static>(&self, idx: u32) -> Result<&T> {
+        let slot = self.materials.get(idx as usize).ok_or(RaytracerError::InvalidMaterial(idx))?;
+        (**slot).as_any().downcast_ref::<T>().ok_or(RaytracerError::MaterialType {
+            idx: idx,
+            expected: std::any::type_name::<T>().rsplit("::").next().unwrap_or("")
+        })
+    }
+
+    // The material as its type, to edit its parameters between renders without rebuilding the scene. A
+    // material that is shared (e.g. by a coat or mix built on top of it) is copied first, and only the copy
+    // under this index is edited.
+    pub fn get_material_mut<T: Material + Clone + 'static>(&mut self, idx: u32) -> Result<&mut T> {
+        self.get_material_as::<T>(idx)?;
+        let slot = &mut self.materials[idx as usize];
+        if Arc::get_mut(slot).is_none() {
+            let copy = (**slot).as_any().downcast_ref::<T>().expect("material type was checked").clone();
+            *slot = Arc::new(copy);
+        }
+        let mat = Arc::get_mut(slot).expect("material is not shared after copying it");
+        Ok(mat.as_any_mut().downcast_mut::<T>().expect("material type was checked"))
+    }
+
+    pub fn get_camera(&self) -> &Camera {
+        &self.cam
+    }
+
+    pub fn set_camera(&mut self, cam: Camera) {
+        self.cam = cam;
+    }
+
+    // Adds a named viewpoint, e.g. one of a set of standard views of the scene, without making it active.
+    // A camera of the same name is replaced. Returns the index of the camera.
+    pub fn add_named_camera(&mut self, name: &str, cam: Camera) -> u32 {
+        if let Some(idx) = self.cameras.iter().position(|(existing, _)| existing == name) {
+            self.cameras[idx].1 = cam;
+            return idx as u32
+        }
+        self.cameras.push((name.to_string(), cam));
+        (self.cameras.len() - 1) as u32
+    }
+
+    pub fn named_camera_count(&self) -> usize {
+        self.cameras.len()
+    }
+
+    pub fn get_named_camera(&self, idx: u32) -> Result<(&str, &Camera)> {
+        match self.cameras.get(idx as usize) {
+            Some((name, cam)) => Ok((name.as_str(), cam)),
+            None => Err(RaytracerError::InvalidCamera(idx))
+        }
+    }
+
+    // Renders from a named camera from now on. Later changes to the active camera leave the named one as it was.
+    pub fn set_active_camera(&mut self, idx: u32) -> Result<()> {
+        self.cam = *self.get_named_camera(idx)?.1;
+        Ok(())
+    }
+
+    pub fn get_background(&self) -> &Background {
+        &self.background
+    }
+
+    pub fn set_background(&mut self, background: Background) {
+        self.background = background;
+    }
+
+    pub fn get_backplate(&self) -> Option<&Image> {
+        self.backplate.as_ref()
+    }
+
+    // Sets an image that camera rays which miss everything see instead of the background, stretched
+    // over the whole frame. The background still lights the scene and shows in reflections and refractions.
+    pub fn set_backplate(&mut self, backplate: Option<Image>) {
+        self.backplate = backplate;
+    }
+
+    // Lights in addition to the background. Returns the index of the light.
+    pub fn add_light(&mut self, light: Light) -> u32 {
+        self.lights.push(light);
+        (self.lights.len() - 1) as u32
+    }
+
+    pub fn get_lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    // Openings such as windows in an otherwise closed room, through which surfaces look for the background
+    // directly. They only guide sampling, so they should cover every opening the background shines through
+    // or those left out are found just by chance, as before. Returns the index of the portal.
+    pub fn add_portal(&mut self, portal: Portal) -> u32 {
+        self.portals.push(portal);
+        (self.portals.len() - 1) as u32
+    }
+
+    pub fn get_portals(&self) -> &[Portal] {
+        &self.portals
+    }
+
+    // Density with which a portal, picked at random, is sampled in the unit direction from p.
+    pub fn portal_pdf(&self, p: Vec3, direction: Vec3) -> Float {
+        if self.portals.is_empty() {
+            return 0.0
+        }
+        let sum: Float = self.portals.iter().map(|portal| portal.pdf(p, direction)).sum();
+        sum / self.portals.len() as Float
+    }
+
+    pub fn get_assets(&self) -> &AssetManager {
+        &self.assets
+    }
+
+    pub fn get_assets_mut(&mut self) -> &mut AssetManager {
+        &mut self.assets
+    }
+
+    fn add_material(&mut self, mat: Arc<dyn Material>) -> u32 {
+        self.materials.push(mat);
+        self.back_faces.push(BackFace::Shade);
+        return (self.materials.len() - 1) as u32
+    }
+
+    pub fn add_lambertian_material(&mut self, mat: Lambertian) -> u32 {
+        self.add_material(Arc::new(mat))
+    }
+
+    pub fn add_metal_material(&mut self, mat: Metal) -> u32 {
+        self.add_material(Arc::new(mat))
+    }
+
+    pub fn add_dielectric_material(&mut self, mat: Dielectric) -> u32 {
+        self.add_material(Arc::new(mat))
+    }
+
+    pub fn add_pbr_material(&mut self, mat: PbrMaterial) -> u32 {
+        self.add_material(Arc::new(mat))
+    }
+
+    pub fn add_coated_material(&mut self, mat: CoatedMaterial) -> u32 {
+        self.add_material(Arc::new(mat))
+    }
+
+    pub fn add_mix_material(&mut self, mat: MixMaterial) -> u32 {
+        self.add_material(Arc::new(mat))
+    }
+
+    // Removes the materials from an index on, with their names, e.g. those added for a model that then failed
+    // to load. No object may use them anymore. The default material is always kept.
+    pub fn truncate_materials(&mut self, count: u32) {
+        let count = count.max(1);
+        self.materials.truncate(count as usize);
+        self.back_faces.truncate(count as usize);
+        self.material_names.retain(|_, idx| *idx < count);
+    }
+
+
+    // Unknown materials render with the default material, and so with its back faces.
+    pub fn get_back_face(&self, idx: u32) -> BackFace {
+        self.back_faces.get(idx as usize).copied().unwrap_or(self.back_faces[0])
+    }
+
+    pub fn set_back_face(&mut self, idx: u32, back_face: BackFace) -> Result<()> {
+        match self.back_faces.get_mut(idx as usize) {
+            Some(slot) => {
+                *slot = back_face;
+                Ok(())
+            }
+            None => Err(RaytracerError::InvalidMaterial(idx))
+        }
+    }
+
+    // Registers a name for an existing material. Naming another material the same moves the name over.
+    pub fn set_material_name(&mut self, idx: u32, name: &str) -> Result<()> {
+        self.get_material(idx)?;
+        if let Some(previous) = self.material_names.insert(name.to_string(), idx) {
+            if previous != idx {
+                debug!(target: "scene", "Material name '{}' moved from material {} to {}.", name, previous, idx);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_sphere(&mut self, sphere: Sphere) -> Result<()> {
+        self.add_primitive(Primitive::Sphere(sphere))
+    }
+
+    // Adds the two spheres of Sphere::shell. The inner one is the object added last.
+    pub fn add_shell(&mut self, center: Vec3, radius: Float, thickness: Float, mat_id: u32) -> Result<()> {
+        let [outer, inner] = Sphere::shell(center, radius, thickness, mat_id)?;
+        self.add_sphere(outer)?;
+        self.add_sphere(inner)
+    }
+
+    pub fn add_plane(&mut self, plane: Plane) -> Result<()> {
+        self.add_primitive(Primitive::Plane(plane))
+    }
+
+    pub fn add_disk(&mut self, disk: Disk) -> Result<()> {
+        self.add_primitive(Primitive::Disk(disk))
+    }
+
+    pub fn add_cylinder(&mut self, cylinder: Cylinder) -> Result<()> {
+        self.add_primitive(Primitive::Cylinder(cylinder))
+    }
+
+    pub fn add_cone(&mut self, cone: Cone) -> Result<()> {
+        self.add_primitive(Primitive::Cone(cone))
+    }
+
+    pub fn add_capsule(&mut self, capsule: Capsule) -> Result<()> {
+        self.add_primitive(Primitive::Capsule(capsule))
+    }
+
+    pub fn add_torus(&mut self, torus: Torus) -> Result<()> {
+        self.add_primitive(Primitive::Torus(torus))
+    }
+
+    pub fn add_heightfield(&mut self, heightfield: Heightfield) -> Result<()> {
+        self.add_object(Arc::new(heightfield))
+    }
+
+    pub fn add_sdf(&mut self, sdf: SdfObject) -> Result<()> {
+        self.add_object(Arc::new(sdf))
+    }
+
+    // Fails if any operand, however deeply nested, uses a material the scene doesn't have.
+    pub fn add_csg(&mut self, csg: Csg) -> Result<()> {
+        self.add_object(Arc::new(csg))
+    }
+
+    pub fn add_strands(&mut self, strands: StrandSet) -> Result<()> {
+        self.add_object(Arc::new(strands))
+    }
+
+    pub fn add_point_cloud(&mut self, cloud: PointCloud) -> Result<()> {
+        self.add_object(Arc::new(cloud))
+    }
+
+    pub fn add_mesh(&mut self, mesh: Mesh) -> Result<()> {
+        self.add_object(Arc::new(mesh))
+    }
+
+    // Adds a mesh loaded with the scene's AssetManager. Adding it several times shares one copy.
+    pub fn add_mesh_asset(&mut self, handle: MeshHandle) -> Result<()> {
+        let mesh = self.assets.get_mesh(handle).ok_or_else(|| RaytracerError::InvalidArgument(format!("mesh asset {:?} was released", handle)))?;
+        self.add_object(mesh)
+    }
+
+    // Objects of any other type. Only the materials they list in Hittable::materials are validated.
+    pub fn add_custom<H: Hittable + 'static>(&mut self, obj: H) -> Result<()> {
+        self.add_object(Arc::new(obj))
+    }
+
+    fn add_primitive(&mut self, prim: Primitive) -> Result<()> {
+        self.get_material(prim.mat_id())?;
+        self.add_primitive_unchecked(prim);
+        Ok(())
+    }
+
+    fn add_primitive_unchecked(&mut self, prim: Primitive) {
+        match (self.sphere_layout, prim) {
+            (SphereLayout::Soa, Primitive::Sphere(sphere)) => {
+                self.spheres.push(sphere);
+                self.sphere_info.push(ObjectInfo::new());
+                self.last_object = Some(ObjectRef::Sphere((self.spheres.len() - 1) as u32));
+            }
+            (_, prim) => {
+                self.primitives.push(prim);
+                self.primitive_info.push(ObjectInfo::new());
+                self.last_object = Some(ObjectRef::Primitive((self.primitives.len() - 1) as u32));
+            }
+        }
+        self.scene_bvh = OnceLock::new();
+    }
+
+
+    fn add_object(&mut self, obj: Arc<dyn Hittable>) -> Result<()> {
+        // Validate up front so that rendering never has to.
+        for mat_id in obj.materials() {
+            self.get_material(mat_id)?;
+        }
+        self.add_object_unchecked(obj);
+        Ok(())
+    }
+
+    // For objects whose materials are already known to be valid.
+    fn add_object_unchecked(&mut self, obj: Arc<dyn Hittable>) {
+        self.objects.push(obj);
+        self.object_info.push(ObjectInfo::new());
+        self.last_object = Some(ObjectRef::Object((self.objects.len() - 1) as u32));
+        self.scene_bvh = OnceLock::new();
+    }
+
+    // Id of the most recently added object, as numbered by the ID passes. None once spheres change layout.
+    pub fn last_object_id(&self) -> Option<u32> {
+        self.last_object.map(|obj| self.object_id(obj))
+    }
+
+    pub fn get_visibility(&self, object_id: u32) -> Result<Visibility> {
+        let obj = self.object_ref(object_id).ok_or(RaytracerError::InvalidObject(object_id))?;
+        Ok(self.visibility_of(obj))
+    }
+
+    // Hides an object from some kinds of rays, e.g. a fill card that lights the scene but isn't seen, or a
+    // character that shouldn't cast shadows. Objects are numbered as in the ID passes.
+    pub fn set_visibility(&mut self, object_id: u32, visibility: Visibility) -> Result<()> {
+        let obj = self.object_ref(object_id).ok_or(RaytracerError::InvalidObject(object_id))?;
+        self.info_mut(obj).visibility = visibility;
+        Ok(())
+    }
+
+    fn visibility_of(&self, obj: ObjectRef) -> Visibility {
+        self.info(obj).visibility
+    }
+
+    pub fn get_object_name(&self, object_id: u32) -> Option<&str> {
+        self.object_ref(object_id).and_then(|obj| self.info(obj).name.as_deref())
+    }
+
+    // Names an object, e.g. after the node of the scene file it came from. Unlike materials, objects can share
+    // a name, such as the parts of one model.
+    pub fn set_object_name(&mut self, object_id: u32, name: &str) -> Result<()> {
+        let obj = self.object_ref(object_id).ok_or(RaytracerError::InvalidObject(object_id))?;
+        self.info_mut(obj).name = Some(name.to_string());
+        Ok(())
+    }
+
+    // Id of the first object with the name, as numbered by the ID passes.
+    pub fn get_object_by_name(&self, name: &str) -> Option<u32> {
+        (0..(self.object_count() as u32)).find(|object_id| self.get_object_name(*object_id) == Some(name))
+    }
+
+    // The object's name and id for logs, e.g. "'teapot' (object 3)", or just its id if it has no name.
+    pub fn describe_object(&self, object_id: u32) -> String {
+        match self.get_object_name(object_id) {
+            Some(name) => format!("'{}' (object {})", name, object_id),
+            None => format!("object {}", object_id)
+        }
+    }
+
+    // --------------------------------------------------
+    // Transform hierarchy
+    // --------------------------------------------------
+    // Objects keep the geometry they were added with, which their node's world transform then moves. Nodes
+    // are parented to other nodes, including those of objects, so that moving a node moves everything below it.
+    // Changing the hierarchy rebuilds the BVH on the next render, so nodes can be animated between renders.
+
+    // An empty node, which only groups the nodes and objects parented to it, e.g. to move a whole model.
+    pub fn add_empty(&mut self, name: &str, parent: Option<u32>, local: Mat4) -> Result<u32> {
+        let node = self.transforms.add_node(Some(name), parent, local)?;
+        self.scene_bvh = OnceLock::new();
+        Ok(node)
+    }
+
+    pub fn get_node_by_name(&self, name: &str) -> Option<u32> {
+        self.transforms.get_by_name(name)
+    }
+
+    pub fn node_count(&self) -> u32 {
+        self.transforms.node_count()
+    }
+
+    // The node the object moves with, attaching a new one (at the identity) if it has none, so that other
+    // objects can be parented to this one.
+    pub fn object_node(&mut self, object_id: u32) -> Result<u32> {
+        let obj = self.object_ref(object_id).ok_or(RaytracerError::InvalidObject(object_id))?;
+        if let Some(node) = self.info(obj).node {
+            return Ok(node)
+        }
+        let node = self.transforms.add_node(None, None, Mat4::identity())?;
+        self.info_mut(obj).node = Some(node);
+        Ok(node)
+    }
+
+    // Parents an object to a node, e.g. an empty or another object's node (see object_node). None unparents it.
+    pub fn set_object_parent(&mut self, object_id: u32, parent: Option<u32>) -> Result<()> {
+        let node = self.object_node(object_id)?;
+        self.set_node_parent(node, parent)
+    }
+
+    pub fn get_node_parent(&self, node: u32) -> Result<Option<u32>> {
+        self.transforms.get_parent(node)
+    }
+
+    // Fails if the parent is below the node, which would make a cycle.
+    pub fn set_node_parent(&mut self, node: u32, parent: Option<u32>) -> Result<()> {
+        self.transforms.set_parent(node, parent)?;
+        self.scene_bvh = OnceLock::new();
+        Ok(())
+    }
+
+    // Relative to the node's parent.
+    pub fn get_node_transform(&self, node: u32) -> Result<Mat4> {
+        self.transforms.get_local(node)
+    }
+
+    pub fn set_node_transform(&mut self, node: u32, local: Mat4) -> Result<()> {
+        self.transforms.set_local(node, local)?;
+        self.scene_bvh = OnceLock::new();
+        Ok(())
+    }
+
+    // Computed from the node's ancestors on demand.
+    pub fn get_world_transform(&self, node: u32) -> Result<Mat4> {
+        Ok(self.transforms.world(node)?.to_world)
+    }
+
+    fn info(&self, obj: ObjectRef) -> &ObjectInfo {
+        match obj {
+            ObjectRef::Primitive(idx) => &self.primitive_info[idx as usize],
+            ObjectRef::Sphere(idx) => &self.sphere_info[idx as usize],
+            ObjectRef::Object(idx) => &self.object_info[idx as usize]
+        }
+    }
+
+    fn info_mut(&mut self, obj: ObjectRef) -> &mut ObjectInfo {
+        match obj {
+            ObjectRef::Primitive(idx) => &mut self.primitive_info[idx as usize],
+            ObjectRef::Sphere(idx) => &mut self.sphere_info[idx as usize],
+            ObjectRef::Object(idx) => &mut self.object_info[idx as usize]
+        }
+    }
+
+    // Objects are numbered in the order of the scene's primitives, spheres, and other objects.
+    fn object_id(&self, obj: ObjectRef) -> u32 {
+        match obj {
+            ObjectRef::Primitive(idx) => idx,
+            ObjectRef::Sphere(idx) => (self.primitives.len() as u32) + idx,
+            ObjectRef::Object(idx) => (self.primitives.len() + self.spheres.len()) as u32 + idx
+        }
+    }
+
+    fn object_ref(&self, object_id: u32) -> Option<ObjectRef> {
+        let (prims, spheres) = (self.primitives.len() as u32, self.spheres.len() as u32);
+        if object_id < prims {
+            Some(ObjectRef::Primitive(object_id))
+        } else if object_id < prims + spheres {
+            Some(ObjectRef::Sphere(object_id - prims))
+        } else if ((object_id - prims - spheres) as usize) < self.objects.len() {
+            Some(ObjectRef::Object(object_id - prims - spheres))
+        } else {
+            None
+        }
+    }
+
+    // Objects' materials are validated when they are added, so hits always have one.
+    pub(crate) fn material_for_hit(&self, mat_id: u32) -> &dyn Material {
+        self.materials[mat_id as usize].as_ref()
+    }
+
+    fn build_bvh(&self) -> SceneBvh {
+        let mut bounded = Vec::new();
+        let mut bounds = Vec::new();
+        let mut unbounded = Vec::new();
+        let mut add = |obj: ObjectRef, aabb: Option<Aabb>| match aabb {
+            Some(aabb) => {
+                bounded.push(obj);
+                bounds.push(aabb);
+            }
+            None => unbounded.push(obj)
+        };
+        // Bounds of objects attached to nodes are moved with them.
+        let mut add = |obj: ObjectRef, aabb: Option<Aabb>| {
+            let aabb = match self.info(obj).node {
+                Some(node) => aabb.map(|aabb| self.transforms.world(node).expect("object nodes exist").transform_box(&aabb)),
+                None => aabb
+            };
+            add(obj, aabb)
+        };
+        for (idx, prim) in self.primitives.iter().enumerate() {
+            add(ObjectRef::Primitive(idx as u32), prim.bounding_box());
+        }
+        for idx in 0..self.spheres.len() {
+            add(ObjectRef::Sphere(idx as u32), Some(self.spheres.bounding_box(idx)));
+        }
+        for (idx, obj) in self.objects.iter().enumerate() {
+            add(ObjectRef::Object(idx as u32), obj.bounding_box());
+        }
+
+        let bvh = Bvh::build(&bounds);
+        debug!(target: "scene", "Built BVH with {} nodes over {} objects ({} unbounded).", bvh.node_count(), bounded.len(), unbounded.len());
+        SceneBvh {
+            leaf_depths: bvh.leaf_depths(),
+            bvh: bvh,
+            bounded: bounded,
+            unbounded: unbounded
+        }
+    }
+
+    // Counts and estimated memory of the scene, building its BVH if rendering hasn't yet.
+    pub fn stats(&self) -> SceneStats {
+        let mut memory = ObjectMemory {
+            elements: self.primitives.len() + self.spheres.len(),
+            geometry: self.primitives.len() * std::mem::size_of::<Primitive>() + self.spheres.memory_usage(),
+            bvh_nodes: 0,
+            bvh: 0
+        };
+        let mut seen = HashSet::new();
+        for obj in self.objects.iter() {
+            if seen.insert(Arc::as_ptr(obj) as *const u8) {
+                memory = memory.add(&obj.memory());
+            }
+        }
+
+        let scene_bvh = self.scene_bvh.get_or_init(|| self.build_bvh());
+        let refs = scene_bvh.bounded.len() + scene_bvh.unbounded.len();
+        let backplate = self.backplate.as_ref().map_or(0, |image| image.memory_usage());
+        SceneStats {
+            objects: self.object_count(),
+            elements: memory.elements,
+            geometry_memory: memory.geometry,
+            bvh_nodes: memory.bvh_nodes + scene_bvh.bvh.node_count(),
+            bvh_memory: memory.bvh + scene_bvh.bvh.memory_usage() + refs * std::mem::size_of::<ObjectRef>()
+                + scene_bvh.leaf_depths.len() * std::mem::size_of::<u32>(),
+            materials: self.material_count(),
+            textures: self.assets.texture_count(),
+            texture_memory: self.assets.texture_memory_usage() + backplate,
+            framebuffer_memory: 0
+        }
+    }
+
+    fn hit_object(&self, obj: ObjectRef, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        if !self.visibility_of(obj).sees(ray.kind) {
+            return None
+        }
+        let transform = match self.info(obj).node {
+            Some(node) => Some(self.transforms.world(node).expect("object nodes exist")).filter(|transform| !transform.is_identity),
+            None => None
+        };
+        let transform = match transform {
+            Some(transform) => transform,
+            None => return self.hit_object_geometry(obj, ray, ray_t)
+        };
+
+        // The object is hit in the space it was added in, by a ray with a unit direction there, so distances
+        // along it are scaled by the transform's stretch in the ray's direction.
+        let to_local = transform.to_local?;
+        let direction = to_local.transform_dir(ray.direction);
+        let stretch = direction.length();
+        if stretch == 0.0 {
+            return None
+        }
+        let mut local_ray = *ray;
+        local_ray.origin = to_local.transform_point(ray.origin);
+        local_ray.direction = direction / stretch;
+        local_ray.cone_width *= stretch;
+        let local_t = Interval::new(ray_t.min * stretch, ray_t.max * stretch);
+
+        let mut hit_rec = self.hit_object_geometry(obj, &local_ray, local_t)?;
+        hit_rec.t /= stretch;
+        hit_rec.p = transform.to_world.transform_point(hit_rec.p);
+        hit_rec.n = transform.to_world.transform_normal(hit_rec.n).normalized();
+        hit_rec.uv_scale *= stretch;
+        Some(hit_rec)
+    }
+
+    fn hit_object_geometry(&self, obj: ObjectRef, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        // Culled back faces are skipped by looking further along the ray for another surface of the object.
+        let mut ray_t = ray_t;
+        for _ in 0..MAX_CULLED_HITS {
+            let hit_rec = match obj {
+                ObjectRef::Primitive(idx) => self.primitives[idx as usize].hit(ray, ray_t),
+                ObjectRef::Sphere(idx) => self.spheres.hit(idx as usize, ray, ray_t),
+                ObjectRef::Object(idx) => self.objects[idx as usize].hit(ray, ray_t)
+            }?;
+            if hit_rec.front_face || self.get_back_face(hit_rec.mat_id) != BackFace::Cull {
+                return Some(hit_rec)
+            }
+            ray_t.min = hit_rec.t + CULL_STEP;
+        }
+        None
+    }
+
+    // Returns the closest hit along with the number of objects tested and BVH nodes visited. The triangles of a
+    // mesh are tested by the mesh, so it counts as one.
+    pub(crate) fn hit(&self, ray: &Ray, ray_t: Interval) -> (Option<HitRecord>, u64, u64) {
+        let (hit, tests, visits) = self.hit_with_id(ray, ray_t);
+        (hit.map(|(_, hit_rec)| hit_rec), tests, visits)
+    }
+
+    // Like hit, but also returns an id of the object that was hit. Ids stay the same until the objects change.
+    pub(crate) fn hit_with_id(&self, ray: &Ray, ray_t: Interval) -> (Option<(u32, HitRecord)>, u64, u64) {
+        let mut best_rec: HitRecord = HitRecord::empty();
+        let mut best_id = 0;
+        let mut hit_anything = false;
+        let mut closest_so_far = ray_t;
+        let mut tests = 0;
+        let mut visits = 0;
+
+        // The BVH is built by the first ray after the objects changed.
+        let scene_bvh = self.scene_bvh.get_or_init(|| self.build_bvh());
+        for (idx, obj) in scene_bvh.unbounded.iter().enumerate() {
+            tests += 1;
+            if let Some(tmp_rec) = self.hit_object(*obj, ray, closest_so_far) {
+                hit_anything = true;
+                closest_so_far.max = tmp_rec.t;
+                best_rec = tmp_rec;
+                best_id = (scene_bvh.bounded.len() + idx) as u32;
+            }
+        }
+
+        // Every hit the BVH reports is closer than the previous ones, so the last item hit is the closest.
+        let mut bvh_id = 0;
+        let (bvh_rec, bvh_visits) = scene_bvh.bvh.hit(ray, closest_so_far, |item, ray, ray_t| {
+            tests += 1;
+            let hit_rec = self.hit_object(scene_bvh.bounded[item as usize], ray, ray_t);
+            if hit_rec.is_some() {
+                bvh_id = item;
+            }
+            hit_rec
+        });
+        visits += bvh_visits as u64;
+        if let Some(tmp_rec) = bvh_rec {
+            hit_anything = true;
+            best_rec = tmp_rec;
+            best_id = bvh_id;
+        }
+
+        return if hit_anything {(Some((best_id, best_rec)), tests, visits)} else {(None, tests, visits)}
+    }
+
+    // Depth of the BVH leaf holding the closest object hit, or None if the ray hits nothing or an unbounded object.
+    pub(crate) fn hit_leaf_depth(&self, ray: &Ray, ray_t: Interval) -> Option<u32> {
+        let (hit, _, _) = self.hit_with_id(ray, ray_t);
+        let scene_bvh = self.scene_bvh.get_or_init(|| self.build_bvh());
+        hit.and_then(|(id, _)| scene_bvh.leaf_depths.get(id as usize).copied())
+    }
+
+    // Object and material ids of the closest object hit, for the ID passes. Objects are numbered in the order of
+    // the scene's primitives, spheres, and other objects, so ids stay the same until the objects change.
+    pub(crate) fn hit_ids(&self, ray: &Ray, ray_t: Interval) -> Option<(u32, u32)> {
+        let (hit, _, _) = self.hit_with_id(ray, ray_t);
+        let (id, hit_rec) = hit?;
+        let scene_bvh = self.scene_bvh.get_or_init(|| self.build_bvh());
+        let obj = if (id as usize) < scene_bvh.bounded.len() {
+            scene_bvh.bounded[id as usize]
+        } else {
+            scene_bvh.unbounded[id as usize - scene_bvh.bounded.len()]
+        };
+        Some((self.object_id(obj), hit_rec.mat_id))
+    }
+
+    // Depth of the deepest leaf of the scene BVH.
+    pub fn bvh_depth(&self) -> u32 {
+        self.scene_bvh.get_or_init(|| self.build_bvh()).leaf_depths.iter().copied().max().unwrap_or(0)
+    }
+
+    // Bounds of the scene BVH's nodes down to max_depth, with their depth.
+    pub fn bvh_node_bounds(&self, max_depth: u32) -> Vec<(Aabb, u32)> {
+        self.scene_bvh.get_or_init(|| self.build_bvh()).bvh.node_bounds(max_depth)
+    }
+}
+
+
+// --------------------------------------------------
+// SceneBuilder
+// --------------------------------------------------
+// Fluent construction of a Scene. Materials are given a name when added and objects refer to them by it.
+// The first error (e.g. an unknown material name) is reported by build().
+//
+// let scene = Scene::builder()
+//     .material("ground", Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))
+//     .material("gold", Metal::new(Vec3::new(0.8, 0.6, 0.2), 0.1))
+//     .plane(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), "ground")
+//     .sphere(Vec3::new(0.0, 1.0, 0.0), 1.0, "gold")
+//     .build()?;
+// ray_tracer.run(&scene);
+pub struct SceneBuilder {
+    scene: Scene,
+    error: Option<RaytracerError>
+}
+
+impl SceneBuilder {
+    fn fail(&mut self, error: RaytracerError) {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+    }
+
+    fn material_id(&mut self, name: &str) -> Option<u32> {
+        let mat_id = self.scene.get_material_by_name(name);
+        if mat_id.is_none() {
+            self.fail(RaytracerError::UnknownMaterial(name.to_string()));
+        }
+        mat_id
+    }
+
+    fn primitive<F: FnOnce(u32) -> Primitive>(mut self, material: &str, make: F) -> SceneBuilder {
+        if let Some(mat_id) = self.material_id(material) {
+            self.scene.add_primitive_unchecked(make(mat_id));
+        }
+        self
+    }
+
+    pub fn camera(mut self, cam: Camera) -> SceneBuilder {
+        self.scene.set_camera(cam);
+        self
+    }
+
+    pub fn named_camera(mut self, name: &str, cam: Camera) -> SceneBuilder {
+        self.scene.add_named_camera(name, cam);
+        self
+    }
+
+    pub fn background(mut self, background: Background) -> SceneBuilder {
+        self.scene.set_background(background);
+        self
+    }
+
+    pub fn backplate(mut self, backplate: Image) -> SceneBuilder {
+        self.scene.set_backplate(Some(backplate));
+        self
+    }
+
+    pub fn light(mut self, light: Light) -> SceneBuilder {
+        self.scene.add_light(light);
+        self
+    }
+
+    pub fn portal(mut self, portal: Portal) -> SceneBuilder {
+        self.scene.add_portal(portal);
+        self
+    }
+
+    // Adding a material under an existing name replaces it for the objects added afterwards.
+    pub fn material<M: Material + 'static>(mut self, name: &str, mat: M) -> SceneBuilder {
+        let mat_id = self.scene.add_material(Arc::new(mat));
+        self.scene.material_names.insert(name.to_string(), mat_id);
+        self
+    }
+
+    pub fn sphere(self, center: Vec3, radius: Float, material: &str) -> SceneBuilder {
+        self.primitive(material, |mat_id| Primitive::Sphere(Sphere::new(center, radius, mat_id)))
+    }
+
+    pub fn shell(mut self, center: Vec3, radius: Float, thickness: Float, material: &str) -> SceneBuilder {
+        if let Some(mat_id) = self.material_id(material) {
+            match Sphere::shell(center, radius, thickness, mat_id) {
+                Ok([outer, inner]) => {
+                    self.scene.add_primitive_unchecked(Primitive::Sphere(outer));
+                    self.scene.add_primitive_unchecked(Primitive::Sphere(inner));
+                }
+                Err(e) => self.fail(e)
+            }
+        }
+        self
+    }
+
+    pub fn plane(self, point: Vec3, normal: Vec3, material: &str) -> SceneBuilder {
+        self.primitive(material, |mat_id| Primitive::Plane(Plane::new(point, normal, mat_id)))
+    }
+
+    pub fn disk(self, center: Vec3, normal: Vec3, radius: Float, material: &str) -> SceneBuilder {
+        self.primitive(material, |mat_id| Primitive::Disk(Disk::new(center, normal, radius, mat_id)))
+    }
+
+    pub fn cylinder(self, base: Vec3, axis: Vec3, radius: Float, height: Float, material: &str) -> SceneBuilder {
+        self.primitive(material, |mat_id| Primitive::Cylinder(Cylinder::new(base, axis, radius, height, mat_id)))
+    }
+
+    pub fn cone(self, base: Vec3, axis: Vec3, radius: Float, height: Float, material: &str) -> SceneBuilder {
+        self.primitive(material, |mat_id| Primitive::Cone(Cone::new(base, axis, radius, height, mat_id)))
+    }
+
+    pub fn capsule(self, start: Vec3, end: Vec3, radius: Float, material: &str) -> SceneBuilder {
+        self.primitive(material, |mat_id| Primitive::Capsule(Capsule::new(start, end, radius, mat_id)))
+    }
+
+    pub fn torus(self, center: Vec3, axis: Vec3, major_radius: Float, minor_radius: Float, material: &str) -> SceneBuilder {
+        self.primitive(material, |mat_id| Primitive::Torus(Torus::new(center, axis, major_radius, minor_radius, mat_id)))
+    }
+
+    pub fn mesh(mut self, positions: Vec<Vec3>, normals: Vec<Vec3>, uvs: Vec<(Float, Float)>, triangles: Vec<[u32; 3]>, material: &str) -> SceneBuilder {
+        if let Some(mat_id) = self.material_id(material) {
+            match Mesh::new(positions, normals, uvs, triangles, mat_id) {
+                Ok(mesh) => self.scene.add_object_unchecked(Arc::new(mesh)),
+                Err(e) => self.fail(e)
+            }
+        }
+        self
+    }
+
+    // Names the object added last.
+    pub fn name(mut self, name: &str) -> SceneBuilder {
+        let result = match self.scene.last_object_id() {
+            Some(object_id) => self.scene.set_object_name(object_id, name),
+            None => Err(RaytracerError::InvalidArgument("name set before any object".to_string()))
+        };
+        if let Err(e) = result {
+            self.fail(e);
+        }
+        self
+    }
+
+    // Adds an empty at the root of the hierarchy, which objects can then be parented to by its name.
+    pub fn empty(mut self, name: &str, local: Mat4) -> SceneBuilder {
+        if let Err(e) = self.scene.add_empty(name, None, local) {
+            self.fail(e);
+        }
+        self
+    }
+
+    // Parents the object added last to the named empty.
+    pub fn parent(mut self, name: &str) -> SceneBuilder {
+        let result = match (self.scene.last_object_id(), self.scene.get_node_by_name(name)) {
+            (Some(object_id), Some(node)) => self.scene.set_object_parent(object_id, Some(node)),
+            (None, _) => Err(RaytracerError::InvalidArgument("parent set before any object".to_string())),
+            (_, None) => Err(RaytracerError::InvalidArgument(format!("no empty named '{}'", name)))
+        };
+        if let Err(e) = result {
+            self.fail(e);
+        }
+        self
+    }
+
+    // Sets which kinds of rays see the object added last.
+    pub fn visibility(mut self, visibility: Visibility) -> SceneBuilder {
+        let result = match self.scene.last_object_id() {
+            Some(object_id) => self.scene.set_visibility(object_id, visibility),
+            None => Err(RaytracerError::InvalidArgument("visibility set before any object".to_string()))
+        };
+        if let Err(e) = result {
+            self.fail(e);
+        }
+        self
+    }
+
+    pub fn build(self) -> Result<Scene> {
+        if let Some(error) = self.error {
+            return Err(error)
+        }
+        Ok(self.scene)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raytracer::Metal;
+
+    #[test]
+    fn stats_count_shared_objects_once() {
+        let mut scene = Scene::new();
+        scene.add_sphere(Sphere::new(Vec3::zero(), 1.0, 0)).unwrap();
+        scene.add_sphere(Sphere::new(Vec3::new(3.0, 0.0, 0.0), 1.0, 0)).unwrap();
+        let positions = vec![Vec3::zero(), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 1.0, 0.0)];
+        let mesh: Arc<dyn Hittable> = Arc::new(Mesh::new(positions, Vec::new(), Vec::new(), vec![[0, 1, 2], [1, 3, 2]], 0).unwrap());
+        let mesh_memory = mesh.memory();
+        scene.add_object(mesh.clone()).unwrap();
+        scene.add_object(mesh).unwrap();
+
+        let stats = scene.stats();
+        assert_eq!(stats.objects, 4);
+        assert_eq!(stats.elements, 2 + 2);
+        assert_eq!(mesh_memory.elements, 2);
+        assert!(stats.geometry_memory >= mesh_memory.geometry + 2 * std::mem::size_of::<Sphere>());
+        assert!(stats.bvh_nodes > mesh_memory.bvh_nodes && stats.bvh_memory > mesh_memory.bvh);
+        assert_eq!(stats.framebuffer_memory, 0);
+        assert_eq!(stats.total_memory(), stats.geometry_memory + stats.bvh_memory + stats.texture_memory);
+
+        // The ray tracer adds its buffers, at least the accumulation buffer.
+        let stats = crate::raytracer::RSRaytracer::new().scene_stats(&scene);
+        assert!(stats.framebuffer_memory >= (WIDTH * HEIGHT * 4) as usize * std::mem::size_of::<Float>());
+    }
+
+    #[test]
+    fn builder_resolves_material_names() {
+        let scene = Scene::builder()
+            .material("ground", Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))
+            .material("gold", Metal::new(Vec3::new(0.8, 0.6, 0.2), 0.1))
+            .plane(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), "ground")
+            .sphere(Vec3::new(0.0, 1.0, 0.0), 1.0, "gold")
+            .build()
+            .unwrap();
+        assert_eq!(scene.object_count(), 2);
+        assert_eq!(scene.get_material_by_name("gold"), Some(2));
+
+        let ray = Ray::new(Vec3::new(0.0, 1.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let (hit_rec, _, _) = scene.hit(&ray, Interval::new(0.001, Float::MAX));
+        assert_eq!(hit_rec.map(|rec| rec.mat_id), Some(2));
+    }
+
+    #[test]
+    fn scene_is_shared_between_threads() {
+        let scene = Arc::new(Scene::builder()
+            .material("red", Lambertian::new(Vec3::new(0.8, 0.1, 0.1)))
+            .sphere(Vec3::zero(), 1.0, "red")
+            .build()
+            .unwrap());
+        let threads: Vec<_> = (0..4).map(|_| {
+            let scene = Arc::clone(&scene);
+            std::thread::spawn(move || {
+                let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+                scene.hit(&ray, Interval::new(0.001, Float::MAX)).0.is_some()
+            })
+        }).collect();
+        for thread in threads {
+            assert!(thread.join().unwrap());
+        }
+    }
+
+    #[test]
+    fn builder_reports_unknown_material() {
+        let result = Scene::builder()
+            .sphere(Vec3::zero(), 1.0, "missing")
+            .build();
+        assert!(matches!(result, Err(RaytracerError::UnknownMaterial(name)) if name == "missing"));
+    }
+
+    #[test]
+    fn hits_report_object_ids_and_bvh_bounds() {
+        let scene = Scene::builder()
+            .material("red", Lambertian::new(Vec3::new(0.8, 0.1, 0.1)))
+            .plane(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), "red")
+            .sphere(Vec3::new(-2.0, 0.0, 0.0), 1.0, "red")
+            .sphere(Vec3::new(2.0, 0.0, 0.0), 1.0, "red")
+            .build()
+            .unwrap();
+        let id = |x: Float| {
+            let ray = Ray::new(Vec3::new(x, 0.0, 5.0), Vec3::new(0.0, -0.1, -1.0));
+            scene.hit_with_id(&ray, Interval::new(0.001, Float::MAX)).0.map(|(id, _)| id)
+        };
+        assert!(id(-2.0).is_some() && id(2.0).is_some() && id(0.0).is_some());
+        assert_ne!(id(-2.0), id(2.0));
+        assert_ne!(id(0.0), id(2.0));
+        assert_eq!(id(2.0), id(2.1));
+
+        // Only the spheres are bounded. The root covers both.
+        let nodes = scene.bvh_node_bounds(4);
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].1, 0);
+        assert_eq!((nodes[0].0.min.x, nodes[0].0.max.x), (-3.0, 3.0));
+        assert_eq!(scene.bvh_depth(), 0);
+        let ray = Ray::new(Vec3::new(2.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        assert_eq!(scene.hit_leaf_depth(&ray, Interval::new(0.001, Float::MAX)), Some(0));
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, -0.1, -1.0));
+        assert_eq!(scene.hit_leaf_depth(&ray, Interval::new(0.001, Float::MAX)), None);
+    }
+
+    #[test]
+    fn camera_projects_onto_its_rays() {
+        let camera = Camera::new(Vec3::new(1.0, 2.0, 8.0), Vec3::new(0.0, 0.5, 0.0), Vec3::new(0.0, 1.0, 0.0), 40.0, 16.0 / 9.0);
+        for (u, v) in [(0.5, 0.5), (0.1, 0.8), (1.2, -0.3)].iter() {
+            let ray = camera.get_ray(*u, *v);
+            let (pu, pv) = camera.project(ray.at(3.0)).unwrap();
+            assert!((pu - u).abs() < 1e-4 && (pv - v).abs() < 1e-4);
+        }
+        assert!(camera.project(Vec3::new(1.0, 2.0, 20.0)).is_none());
+    }
+
+    #[test]
+    fn cameras_track_targets_and_dolly_zoom() {
+        let camera = Camera::new(Vec3::new(0.0, 0.0, 8.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 40.0, 16.0 / 9.0);
+        assert!((camera.get_vertical_fov() - 40.0).abs() < 1e-3);
+        let centered = |camera: &Camera, p: Vec3| {
+            let (u, v) = camera.project(p).unwrap();
+            (u - 0.5).abs() < 1e-4 && (v - 0.5).abs() < 1e-4
+        };
+        assert!(centered(&camera.looking_at(Vec3::new(1.0, 0.5, 0.0)), Vec3::new(1.0, 0.5, 0.0)));
+        let tracked = camera.tracking(2.0, 0.0, 0.0, Vec3::zero());
+        assert!(centered(&tracked, Vec3::zero()));
+        assert!((tracked.get_position().x - 2.0).abs() < 1e-4);
+
+        // Halfway to the target, the view is wider, but the target's plane looks the same.
+        let zoomed = camera.dolly_zoom(4.0, Vec3::zero()).unwrap();
+        assert!((zoomed.get_position().z - 4.0).abs() < 1e-4);
+        assert!(zoomed.get_vertical_fov() > 40.0);
+        let edge = Vec3::new(1.0, 1.0, 0.0);
+        let (before, after) = (camera.project(edge).unwrap(), zoomed.project(edge).unwrap());
+        assert!((before.0 - after.0).abs() < 1e-4 && (before.1 - after.1).abs() < 1e-4);
+        assert!(camera.dolly_zoom(9.0, Vec3::zero()).is_none());
+    }
+
+    #[test]
+    fn hidden_objects_are_skipped_by_their_ray_kinds() {
+        let mut scene = Scene::builder()
+            .material("white", Lambertian::new(Vec3::one()))
+            .sphere(Vec3::zero(), 1.0, "white")
+            .visibility(Visibility{camera: false, shadows: true, reflections: false})
+            .plane(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), "white")
+            .build()
+            .unwrap();
+        let camera = Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 20.0, 1.0);
+        let mut ray = camera.get_ray(0.5, 0.5);
+        assert_eq!(ray.kind, RayKind::Camera);
+        let hit = |scene: &Scene, ray: &Ray| scene.hit(ray, Interval::new(0.001, Float::MAX)).0.is_some();
+        assert!(!hit(&scene, &ray));
+        ray.kind = RayKind::Shadow;
+        assert!(hit(&scene, &ray));
+        ray.kind = RayKind::Diffuse;
+        assert!(!hit(&scene, &ray));
+
+        // Visibility stays with the sphere when it moves to the other layout, which renumbers it.
+        scene.set_sphere_layout(SphereLayout::Soa);
+        assert_eq!(scene.get_visibility(0).unwrap(), Visibility::all());
+        assert!(!scene.get_visibility(1).unwrap().camera);
+        scene.set_visibility(1, Visibility::all()).unwrap();
+        assert!(hit(&scene, &ray));
+        assert!(scene.set_visibility(2, Visibility::all()).is_err());
+        assert!(Scene::builder().visibility(Visibility::all()).build().is_err());
+    }
+
+    #[test]
+    fn objects_and_materials_are_found_by_name() {
+        let mut scene = Scene::builder()
+            .material("white", Lambertian::new(Vec3::one()))
+            .sphere(Vec3::zero(), 1.0, "white")
+            .name("ball")
+            .plane(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), "white")
+            .sphere(Vec3::new(2.0, 0.0, 0.0), 1.0, "white")
+            .name("ball")
+            .build()
+            .unwrap();
+        assert_eq!(scene.get_object_by_name("ball"), Some(0));
+        assert_eq!(scene.get_object_name(1), None);
+        assert_eq!(scene.get_object_by_name("floor"), None);
+        assert_eq!(scene.describe_object(2), "'ball' (object 2)");
+        assert_eq!(scene.describe_object(1), "object 1");
+        assert_eq!(scene.get_material_name(1), Some("white"));
+        assert_eq!(scene.describe_material(0), "material 0");
+
+        // Names stay with the spheres when they move to the other layout, which renumbers them.
+        scene.set_object_name(1, "floor").unwrap();
+        scene.set_sphere_layout(SphereLayout::Soa);
+        assert_eq!(scene.get_object_by_name("floor"), Some(0));
+        assert_eq!(scene.get_object_by_name("ball"), Some(1));
+        assert!(scene.set_object_name(3, "missing").is_err());
+        assert!(Scene::builder().name("nothing").build().is_err());
+    }
+
+    #[test]
+    fn parented_objects_move_with_their_group() {
+        let mut scene = Scene::builder()
+            .material("white", Lambertian::new(Vec3::one()))
+            .empty("group", Mat4::identity())
+            .sphere(Vec3::zero(), 1.0, "white")
+            .parent("group")
+            .sphere(Vec3::new(0.0, 3.0, 0.0), 0.5, "white")
+            .build()
+            .unwrap();
+        let down = |x: Float| Ray::new(Vec3::new(x, 10.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let hit = |scene: &Scene, ray: &Ray| scene.hit(ray, Interval::new(0.001, Float::MAX)).0;
+
+        // Moving and scaling the group moves the sphere in it, and distances stay those of the world.
+        let group = scene.get_node_by_name("group").unwrap();
+        scene.set_node_transform(group, Mat4::translation(Vec3::new(5.0, 0.0, 0.0)) * Mat4::scale(Vec3::one() * 2.0)).unwrap();
+        assert!(hit(&scene, &down(0.0)).unwrap().p.y > 2.0);
+        let moved = hit(&scene, &down(5.0)).unwrap();
+        assert!((moved.t - 8.0).abs() < 1e-4 && (moved.p.y - 2.0).abs() < 1e-4);
+        assert!((moved.n.y - 1.0).abs() < 1e-4);
+
+        // The small sphere parented to the big one follows it, on top of its own position.
+        let big = scene.object_node(0).unwrap();
+        scene.set_object_parent(1, Some(big)).unwrap();
+        assert!(hit(&scene, &down(0.0)).is_none());
+        assert!((hit(&scene, &down(5.0)).unwrap().p.y - 7.0).abs() < 1e-4);
+        let small = scene.object_node(1).unwrap();
+        assert!(scene.set_node_parent(group, Some(small)).is_err());
+        assert!(scene.set_object_parent(2, Some(group)).is_err());
+    }
+
+    #[test]
+    fn materials_are_edited_in_place() {
+        let mut scene = Scene::new();
+        let steel = scene.add_metal_material(Metal::new(Vec3::one() * 0.8, 0.1));
+        let base = scene.get_shared_material(steel).unwrap();
+        scene.add_coated_material(CoatedMaterial::from_shared(base.clone(), 1.5, 0.0));
+
+        // The coat keeps the steel it was built on, and only the steel under its own index changes.
+        scene.get_material_mut::<Metal>(steel).unwrap().set_fuzz(0.5);
+        assert_eq!(scene.get_material_as::<Metal>(steel).unwrap().get_fuzz(), 0.5);
+        assert_eq!((*base).as_any().downcast_ref::<Metal>().unwrap().get_fuzz(), 0.1);
+        scene.get_material_mut::<Metal>(steel).unwrap().set_albedo(Vec3::zero());
+        assert_eq!(scene.get_material_as::<Metal>(steel).unwrap().get_fuzz(), 0.5);
+
+        match scene.get_material_mut::<Dielectric>(steel) {
+            Err(RaytracerError::MaterialType{idx, expected}) => assert_eq!((idx, expected), (steel, "Dielectric")),
+            _ => panic!("steel is not glass")
+        }
+        scene.set_material(steel, Dielectric::new(1.5)).unwrap();
+        scene.get_material_mut::<Dielectric>(steel).unwrap().set_ior(1.33);
+        assert_eq!(scene.get_material_as::<Dielectric>(steel).unwrap().get_ior(), 1.33);
+        assert!(scene.set_material(9, Lambertian::new(Vec3::one())).is_err());
+
+        // Materials added for a model that fails to load are dropped with their names, down to the default.
+        scene.set_material_name(steel + 1, "coat").unwrap();
+        scene.truncate_materials(steel + 1);
+        assert_eq!(scene.material_count(), steel + 1);
+        assert_eq!(scene.get_material_by_name("coat"), None);
+        scene.truncate_materials(0);
+        assert_eq!(scene.material_count(), 1);
+    }
+
+    #[test]
+    fn named_cameras_switch_the_active_one() {
+        let front = Camera::new(Vec3::new(0.0, 0.0, 8.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 40.0, 16.0 / 9.0);
+        let side = Camera::new(Vec3::new(8.0, 0.0, 0.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 40.0, 16.0 / 9.0);
+        let mut scene = Scene::builder()
+            .named_camera("front", front)
+            .named_camera("side", front)
+            .build()
+            .unwrap();
+        let origin_x = |scene: &Scene| scene.get_camera().get_ray(0.5, 0.5).origin.x;
+        assert_eq!(origin_x(&scene), 13.0);
+
+        // Names are unique, so adding one again replaces it.
+        assert_eq!(scene.add_named_camera("side", side), 1);
+        assert_eq!(scene.named_camera_count(), 2);
+        scene.set_active_camera(1).unwrap();
+        assert_eq!(origin_x(&scene), 8.0);
+        assert_eq!(scene.get_named_camera(1).unwrap().0, "side");
+
+        // Moving the active camera leaves the named one in place.
+        scene.set_camera(scene.get_camera().moved(1.0, 0.0, 0.0));
+        scene.set_active_camera(1).unwrap();
+        assert_eq!(origin_x(&scene), 8.0);
+        assert!(matches!(scene.set_active_camera(2), Err(RaytracerError::InvalidCamera(2))));
+        scene.clear();
+        assert_eq!(scene.named_camera_count(), 0);
+    }
+
+    // Random spheres in a 20 unit cube as (center, radius) and rays from random points towards its middle.
+    fn random_spheres(count: usize) -> (Vec<(Vec3, Float)>, Vec<Ray>) {
+        let mut rng = RandGen::new();
+        let spheres = (0..count).map(|_| (Vec3::random_range(-10.0..10.0), rng.next_range(0.05..0.3))).collect();
+        let rays = (0..count).map(|_| {
+            let origin = Vec3::random_range(-20.0..20.0);
+            Ray::new(origin, Vec3::random_range(-5.0..5.0) - origin)
+        }).collect();
+        (spheres, rays)
+    }
+
+    // Spheres are added as trait objects if no layout is given.
+    fn sphere_scene(spheres: &[(Vec3, Float)], layout: Option<SphereLayout>) -> Scene {
+        let mut scene = Scene::new();
+        if let Some(layout) = layout {
+            scene.set_sphere_layout(layout);
+        }
+        for (center, radius) in spheres {
+            let sphere = Sphere::new(*center, *radius, 0);
+            match layout {
+                Some(_) => scene.add_sphere(sphere).unwrap(),
+                None => scene.add_custom(sphere).unwrap()
+            }
+        }
+        scene
+    }
+
+    #[test]
+    fn sphere_layouts_hit_the_same() {
+        let (spheres, rays) = random_spheres(500);
+        let mut scene = sphere_scene(&spheres, Some(SphereLayout::Aos));
+        scene.add_plane(Plane::new(Vec3::new(0.0, -10.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0)).unwrap();
+        let hits = |scene: &Scene| -> Vec<Option<Float>> {
+            rays.iter().map(|ray| scene.hit(ray, Interval::new(0.001, Float::MAX)).0.map(|rec| rec.t)).collect()
+        };
+        let aos_hits = hits(&scene);
+        scene.set_sphere_layout(SphereLayout::Soa);
+        assert_eq!(scene.object_count(), 501);
+        assert_eq!(hits(&scene), aos_hits);
+        scene.set_sphere_layout(SphereLayout::Aos);
+        assert_eq!(scene.object_count(), 501);
+        assert_eq!(hits(&scene), aos_hits);
+    }
+
+    #[test]
+    fn median_split_bvh_is_balanced() {
+        // 500 items split in half until at most 4 remain take 7 levels.
+        let (spheres, rays) = random_spheres(500);
+        let scene = sphere_scene(&spheres, Some(SphereLayout::Aos));
+        assert_eq!(scene.bvh_depth(), 7);
+        for ray in &rays {
+            let depth = scene.hit_leaf_depth(ray, Interval::new(0.001, Float::MAX));
+            assert!(depth.is_none_or(|depth| (6..=7).contains(&depth)));
+        }
+    }
+
+    // Compares the trait object, enum, and SoA storage of spheres.
+    // cargo test --release sphere_storage_benchmark -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn sphere_storage_benchmark() {
+        let (spheres, rays) = random_spheres(20_000);
+        for (name, layout) in [("dyn Hittable", None), ("Primitive (AoS)", Some(SphereLayout::Aos)), ("SphereSoa", Some(SphereLayout::Soa))].iter() {
+            let scene = sphere_scene(&spheres, *layout);
+            scene.hit(&rays[0], Interval::new(0.001, Float::MAX)); // Builds the BVH.
+            let start = std::time::Instant::now();
+            let mut hit_count = 0;
+            for _ in 0..5 {
+                for ray in &rays {
+                    hit_count += scene.hit(ray, Interval::new(0.001, Float::MAX)).0.is_some() as usize;
+                }
+            }
+            let elapsed = start.elapsed();
+            println!("{}: {:.1} ns/ray ({} hits)", name, elapsed.as_nanos() as f64 / (rays.len() * 5) as f64, hit_count);
+        }
+    }
+
+    #[test]
+    fn back_faces_can_be_culled() {
+        // A disk facing +z with a sphere in front of it, seen from behind the disk.
+        let mut scene = Scene::builder()
+            .material("wall", Lambertian::new(Vec3::one()))
+            .material("ball", Lambertian::new(Vec3::one()))
+            .disk(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0), 2.0, "wall")
+            .sphere(Vec3::new(0.0, 0.0, 5.0), 1.0, "ball")
+            .build()
+            .unwrap();
+        let wall = scene.get_material_by_name("wall").unwrap();
+        let ball = scene.get_material_by_name("ball").unwrap();
+        let from_behind = Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        let from_front = Ray::new(Vec3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit_mat = |scene: &Scene, ray: &Ray| scene.hit(ray, Interval::new(0.001, Float::MAX)).0.map(|rec| rec.mat_id);
+
+        assert_eq!(scene.get_back_face(wall), BackFace::Shade);
+        assert_eq!(hit_mat(&scene, &from_behind), Some(wall));
+        scene.set_back_face(wall, BackFace::Cull).unwrap();
+        assert_eq!(hit_mat(&scene, &from_behind), Some(ball));
+        assert_eq!(hit_mat(&scene, &from_front), Some(wall));
+
+        // The inside of a culled sphere is invisible too.
+        scene.set_back_face(ball, BackFace::Cull).unwrap();
+        let inside = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(hit_mat(&scene, &inside), None);
+        assert!(scene.set_back_face(99, BackFace::Black).is_err());
+
+        // A front face of the same object behind a culled one is still seen: from outside an inside-out
+        // sphere, the near side faces away and the far side faces the ray.
+        let mut shell = Scene::builder()
+            .material("shell", Lambertian::new(Vec3::one()))
+            .sphere(Vec3::zero(), -1.0, "shell")
+            .build()
+            .unwrap();
+        shell.set_back_face(shell.get_material_by_name("shell").unwrap(), BackFace::Cull).unwrap();
+        let hit_rec = shell.hit(&from_behind, Interval::new(0.001, Float::MAX)).0.unwrap();
+        assert!(hit_rec.front_face);
+        assert!((hit_rec.t - 6.0).abs() < 1e-4);
+    }
+}
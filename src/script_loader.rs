@@ -0,0 +1,516 @@
+use std::cell::RefCell;
+use std::fs;
+use std::rc::Rc;
+
+use log::{debug, info};
+use rhai::{Engine, EvalAltResult, FLOAT, INT};
+
+use crate::error::{RaytracerError, Result};
+use crate::light::{Light, Portal};
+use crate::math::*;
+use crate::primitives::*;
+use crate::raytracer::{Camera, CoatedMaterial, Dielectric, Lambertian, Metal, MixMaterial, PbrMaterial, Sphere, WIDTH, HEIGHT};
+use crate::scene::{BackFace, Background, Scene, Visibility};
+use crate::sky::Sky;
+
+// Scene changes are recorded while the script runs and only applied once it has finished,
+// so a failing script leaves the scene untouched.
+type SceneOp = Box<dyn FnOnce(&mut Scene) -> Result<()>>;
+
+struct ScriptState {
+    ops: Vec<SceneOp>,
+    next_material: u32, // Index the next material added by the script will get.
+    object_count: usize,
+    rand_gen: RandGen
+}
+
+type SharedState = Rc<RefCell<ScriptState>>;
+type ScriptResult<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+fn add_material(state: &SharedState, add: Box<dyn FnOnce(&mut Scene) -> u32>) -> INT {
+    let mut state = state.borrow_mut();
+    let mat_id = state.next_material;
+    state.next_material += 1;
+    state.ops.push(Box::new(move |scene| {
+        add(scene);
+        Ok(())
+    }));
+    mat_id as INT
+}
+
+fn check_material(state: &ScriptState, mat: INT) -> ScriptResult<()> {
+    if mat < 0 || mat >= state.next_material as INT {
+        return Err(format!("material {} does not exist", mat).into())
+    }
+    Ok(())
+}
+
+fn add_object(state: &SharedState, mat: INT, add: SceneOp) -> ScriptResult<()> {
+    let mut state = state.borrow_mut();
+    check_material(&state, mat)?;
+    state.ops.push(add);
+    state.object_count += 1;
+    Ok(())
+}
+
+fn find_node(scene: &Scene, name: &str) -> Result<u32> {
+    scene.get_node_by_name(name).ok_or_else(|| RaytracerError::InvalidArgument(format!("no empty named '{}'", name)))
+}
+
+// Applies a transform after the node's own, in its parent's space.
+fn transform_node(state: &SharedState, name: &str, transform: Mat4) {
+    let name = name.to_string();
+    state.borrow_mut().ops.push(Box::new(move |scene| {
+        let node = find_node(scene, &name)?;
+        let local = scene.get_node_transform(node)?;
+        scene.set_node_transform(node, transform * local)
+    }));
+}
+
+fn add_light(state: &SharedState, light: Light) {
+    state.borrow_mut().ops.push(Box::new(move |scene| {
+        scene.add_light(light);
+        Ok(())
+    }));
+}
+
+// Only scene building functions are exposed. Scripts have no file or network access, and
+// runaway scripts are stopped by the operation and nesting limits.
+fn create_engine(state: &SharedState) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(100_000_000);
+    engine.set_max_call_levels(64);
+    engine.set_max_expr_depths(64, 64);
+    engine.set_max_array_size(1_000_000);
+    engine.set_max_string_size(100_000);
+    engine.on_print(|s| info!(target: "scene", "{}", s));
+    engine.on_debug(|s, _, _| debug!(target: "scene", "{}", s));
+
+    // Vectors.
+    engine.register_type_with_name::<Vec3>("Vec3");
+    engine.register_fn("vec3", |x: FLOAT, y: FLOAT, z: FLOAT| Vec3::new(x as Float, y as Float, z as Float));
+    engine.register_get("x", |v: &mut Vec3| v.x as FLOAT);
+    engine.register_get("y", |v: &mut Vec3| v.y as FLOAT);
+    engine.register_get("z", |v: &mut Vec3| v.z as FLOAT);
+    engine.register_fn("+", |a: Vec3, b: Vec3| a + b);
+    engine.register_fn("-", |a: Vec3, b: Vec3| a - b);
+    engine.register_fn("-", |a: Vec3| -a);
+    engine.register_fn("*", |a: Vec3, b: Vec3| a * b);
+    engine.register_fn("*", |a: Vec3, b: FLOAT| a * (b as Float));
+    engine.register_fn("*", |a: FLOAT, b: Vec3| (a as Float) * b);
+    engine.register_fn("/", |a: Vec3, b: FLOAT| a / (b as Float));
+    engine.register_fn("length", |v: &mut Vec3| v.length() as FLOAT);
+    engine.register_fn("normalized", |v: &mut Vec3| v.normalized());
+    engine.register_fn("dot", |a: &mut Vec3, b: Vec3| a.dot(&b) as FLOAT);
+    engine.register_fn("cross", |a: &mut Vec3, b: Vec3| a.cross(&b));
+    engine.register_fn("to_string", |v: &mut Vec3| format!("({}, {}, {})", v.x, v.y, v.z));
+
+    // Random numbers.
+    let s = state.clone();
+    engine.register_fn("rand", move || s.borrow_mut().rand_gen.next01() as FLOAT);
+    let s = state.clone();
+    engine.register_fn("rand_range", move |lo: FLOAT, hi: FLOAT| {
+        if hi > lo {s.borrow_mut().rand_gen.next_range((lo as Float)..(hi as Float)) as FLOAT} else {lo}
+    });
+
+    // Materials return their index for use with objects.
+    let s = state.clone();
+    engine.register_fn("lambertian", move |albedo: Vec3| {
+        add_material(&s, Box::new(move |scene| scene.add_lambertian_material(Lambertian::new(albedo))))
+    });
+    let s = state.clone();
+    engine.register_fn("metal", move |albedo: Vec3, fuzz: FLOAT| {
+        add_material(&s, Box::new(move |scene| scene.add_metal_material(Metal::new(albedo, fuzz as Float))))
+    });
+    let s = state.clone();
+    engine.register_fn("dielectric", move |ior: FLOAT| {
+        add_material(&s, Box::new(move |scene| scene.add_dielectric_material(Dielectric::new(ior as Float))))
+    });
+    let s = state.clone();
+    engine.register_fn("dielectric", move |a: FLOAT, b: FLOAT| {
+        add_material(&s, Box::new(move |scene| scene.add_dielectric_material(Dielectric::cauchy(a as Float, b as Float))))
+    });
+    let s = state.clone();
+    engine.register_fn("pbr", move |base_color: Vec3, metallic: FLOAT, roughness: FLOAT| {
+        add_material(&s, Box::new(move |scene| scene.add_pbr_material(PbrMaterial::new(base_color, metallic as Float, roughness as Float))))
+    });
+    let s = state.clone();
+    engine.register_fn("coat", move |base: INT, ior: FLOAT, roughness: FLOAT| -> ScriptResult<INT> {
+        check_material(&s.borrow(), base)?;
+        let mut state = s.borrow_mut();
+        let mat_id = state.next_material;
+        state.next_material += 1;
+        state.ops.push(Box::new(move |scene| {
+            let base = scene.get_shared_material(base as u32)?;
+            scene.add_coated_material(CoatedMaterial::from_shared(base, ior as Float, roughness as Float));
+            Ok(())
+        }));
+        Ok(mat_id as INT)
+    });
+    let s = state.clone();
+    engine.register_fn("mix", move |a: INT, b: INT, amount: FLOAT| -> ScriptResult<INT> {
+        check_material(&s.borrow(), a)?;
+        check_material(&s.borrow(), b)?;
+        let mut state = s.borrow_mut();
+        let mat_id = state.next_material;
+        state.next_material += 1;
+        state.ops.push(Box::new(move |scene| {
+            let mix = MixMaterial::from_shared(scene.get_shared_material(a as u32)?, scene.get_shared_material(b as u32)?, amount as Float);
+            scene.add_mix_material(mix);
+            Ok(())
+        }));
+        Ok(mat_id as INT)
+    });
+    let s = state.clone();
+    engine.register_fn("name_material", move |mat: INT, name: &str| -> ScriptResult<()> {
+        let mut state = s.borrow_mut();
+        check_material(&state, mat)?;
+        let name = name.to_string();
+        state.ops.push(Box::new(move |scene| scene.set_material_name(mat as u32, &name)));
+        Ok(())
+    });
+
+    // Tweak materials already added, e.g. to try variations without rewriting their constructors.
+    let s = state.clone();
+    engine.register_fn("set_albedo", move |mat: INT, albedo: Vec3| -> ScriptResult<()> {
+        let mut state = s.borrow_mut();
+        check_material(&state, mat)?;
+        state.ops.push(Box::new(move |scene| {
+            if let Ok(lambertian) = scene.get_material_mut::<Lambertian>(mat as u32) {
+                lambertian.set_albedo(albedo);
+                return Ok(())
+            }
+            scene.get_material_mut::<Metal>(mat as u32)?.set_albedo(albedo);
+            Ok(())
+        }));
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("set_fuzz", move |mat: INT, fuzz: FLOAT| -> ScriptResult<()> {
+        let mut state = s.borrow_mut();
+        check_material(&state, mat)?;
+        state.ops.push(Box::new(move |scene| {
+            scene.get_material_mut::<Metal>(mat as u32)?.set_fuzz(fuzz as Float);
+            Ok(())
+        }));
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("set_ior", move |mat: INT, ior: FLOAT| -> ScriptResult<()> {
+        let mut state = s.borrow_mut();
+        check_material(&state, mat)?;
+        state.ops.push(Box::new(move |scene| {
+            scene.get_material_mut::<Dielectric>(mat as u32)?.set_ior(ior as Float);
+            Ok(())
+        }));
+        Ok(())
+    });
+
+    let s = state.clone();
+    engine.register_fn("back_face", move |mat: INT, mode: &str| -> ScriptResult<()> {
+        let mut state = s.borrow_mut();
+        check_material(&state, mat)?;
+        let back_face = match mode {
+            "shade" => BackFace::Shade,
+            "black" => BackFace::Black,
+            "cull" => BackFace::Cull,
+            _ => return Err(format!("unknown back face mode '{}', expected shade, black, or cull", mode).into())
+        };
+        state.ops.push(Box::new(move |scene| scene.set_back_face(mat as u32, back_face)));
+        Ok(())
+    });
+
+    // Objects.
+    let s = state.clone();
+    engine.register_fn("sphere", move |center: Vec3, radius: FLOAT, mat: INT| {
+        add_object(&s, mat, Box::new(move |scene| scene.add_sphere(Sphere::new(center, radius as Float, mat as u32))))
+    });
+    let s = state.clone();
+    engine.register_fn("shell", move |center: Vec3, radius: FLOAT, thickness: FLOAT, mat: INT| -> ScriptResult<()> {
+        let (radius, thickness) = (radius as Float, thickness as Float);
+        Sphere::shell(center, radius, thickness, mat as u32).map_err(|e| e.to_string())?;
+        add_object(&s, mat, Box::new(move |scene| scene.add_shell(center, radius, thickness, mat as u32)))?;
+        s.borrow_mut().object_count += 1;
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("plane", move |point: Vec3, normal: Vec3, mat: INT| {
+        add_object(&s, mat, Box::new(move |scene| scene.add_plane(Plane::new(point, normal, mat as u32))))
+    });
+    let s = state.clone();
+    engine.register_fn("disk", move |center: Vec3, normal: Vec3, radius: FLOAT, mat: INT| {
+        add_object(&s, mat, Box::new(move |scene| scene.add_disk(Disk::new(center, normal, radius as Float, mat as u32))))
+    });
+    let s = state.clone();
+    engine.register_fn("cylinder", move |base: Vec3, axis: Vec3, radius: FLOAT, height: FLOAT, mat: INT| {
+        add_object(&s, mat, Box::new(move |scene| scene.add_cylinder(Cylinder::new(base, axis, radius as Float, height as Float, mat as u32))))
+    });
+    let s = state.clone();
+    engine.register_fn("cone", move |base: Vec3, axis: Vec3, radius: FLOAT, height: FLOAT, mat: INT| {
+        add_object(&s, mat, Box::new(move |scene| scene.add_cone(Cone::new(base, axis, radius as Float, height as Float, mat as u32))))
+    });
+    let s = state.clone();
+    engine.register_fn("capsule", move |start: Vec3, end: Vec3, radius: FLOAT, mat: INT| {
+        add_object(&s, mat, Box::new(move |scene| scene.add_capsule(Capsule::new(start, end, radius as Float, mat as u32))))
+    });
+    let s = state.clone();
+    engine.register_fn("torus", move |center: Vec3, axis: Vec3, major_radius: FLOAT, minor_radius: FLOAT, mat: INT| {
+        add_object(&s, mat, Box::new(move |scene| scene.add_torus(Torus::new(center, axis, major_radius as Float, minor_radius as Float, mat as u32))))
+    });
+
+    let s = state.clone();
+    engine.register_fn("visibility", move |camera: bool, shadows: bool, reflections: bool| -> ScriptResult<()> {
+        let mut state = s.borrow_mut();
+        if state.object_count == 0 {
+            return Err("visibility() needs an object added before it".into())
+        }
+        let visibility = Visibility{camera: camera, shadows: shadows, reflections: reflections};
+        state.ops.push(Box::new(move |scene| {
+            let object_id = scene.last_object_id().ok_or_else(|| RaytracerError::InvalidArgument("no object to set the visibility of".to_string()))?;
+            scene.set_visibility(object_id, visibility)
+        }));
+        Ok(())
+    });
+
+    let s = state.clone();
+    engine.register_fn("name_object", move |name: &str| -> ScriptResult<()> {
+        let mut state = s.borrow_mut();
+        if state.object_count == 0 {
+            return Err("name_object() needs an object added before it".into())
+        }
+        let name = name.to_string();
+        state.ops.push(Box::new(move |scene| {
+            let object_id = scene.last_object_id().ok_or_else(|| RaytracerError::InvalidArgument("no object to name".to_string()))?;
+            scene.set_object_name(object_id, &name)
+        }));
+        Ok(())
+    });
+
+    // Transform hierarchy. Empties are referred to by name, and transforms apply on top of their current one.
+    let s = state.clone();
+    engine.register_fn("empty", move |name: &str| {
+        let name = name.to_string();
+        s.borrow_mut().ops.push(Box::new(move |scene| scene.add_empty(&name, None, Mat4::identity()).map(|_| ())));
+    });
+    let s = state.clone();
+    engine.register_fn("empty", move |name: &str, parent: &str| {
+        let (name, parent) = (name.to_string(), parent.to_string());
+        s.borrow_mut().ops.push(Box::new(move |scene| {
+            let parent = find_node(scene, &parent)?;
+            scene.add_empty(&name, Some(parent), Mat4::identity()).map(|_| ())
+        }));
+    });
+    let s = state.clone();
+    engine.register_fn("parent", move |name: &str| -> ScriptResult<()> {
+        let mut state = s.borrow_mut();
+        if state.object_count == 0 {
+            return Err("parent() needs an object added before it".into())
+        }
+        let name = name.to_string();
+        state.ops.push(Box::new(move |scene| {
+            let object_id = scene.last_object_id().ok_or_else(|| RaytracerError::InvalidArgument("no object to parent".to_string()))?;
+            let node = find_node(scene, &name)?;
+            scene.set_object_parent(object_id, Some(node))
+        }));
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("translate", move |name: &str, offset: Vec3| {
+        transform_node(&s, name, Mat4::translation(offset));
+    });
+    let s = state.clone();
+    engine.register_fn("rotate", move |name: &str, axis: Vec3, degrees: FLOAT| {
+        transform_node(&s, name, Mat4::rotation((degrees as Float).to_radians(), axis));
+    });
+    let s = state.clone();
+    engine.register_fn("scale", move |name: &str, factor: FLOAT| {
+        transform_node(&s, name, Mat4::scale(Vec3::one() * (factor as Float)));
+    });
+
+    // Lights.
+    let s = state.clone();
+    engine.register_fn("point_light", move |position: Vec3, intensity: Vec3| {
+        add_light(&s, Light::point(position, intensity));
+    });
+    let s = state.clone();
+    engine.register_fn("spot_light", move |position: Vec3, target: Vec3, intensity: Vec3, cone_angle: FLOAT, falloff_angle: FLOAT| {
+        add_light(&s, Light::spot(position, target - position, intensity, cone_angle as Float, falloff_angle as Float));
+    });
+    let s = state.clone();
+    engine.register_fn("sun_light", move |direction: Vec3, irradiance: Vec3| {
+        add_light(&s, Light::directional(direction, irradiance));
+    });
+
+    let s = state.clone();
+    engine.register_fn("portal", move |corner: Vec3, edge_u: Vec3, edge_v: Vec3| {
+        s.borrow_mut().ops.push(Box::new(move |scene| {
+            scene.add_portal(Portal::new(corner, edge_u, edge_v));
+            Ok(())
+        }));
+    });
+
+    let s = state.clone();
+    engine.register_fn("sky", move |sun_direction: Vec3, turbidity: FLOAT| {
+        let sky = Sky::new(sun_direction, turbidity as Float);
+        s.borrow_mut().ops.push(Box::new(move |scene| {
+            scene.set_background(Background::Sky(sky));
+            scene.add_light(sky.sun_light());
+            Ok(())
+        }));
+    });
+
+    // Camera.
+    let s = state.clone();
+    engine.register_fn("camera", move |look_from: Vec3, look_at: Vec3, up: Vec3, vertical_fov: FLOAT| {
+        s.borrow_mut().ops.push(Box::new(move |scene| {
+            scene.set_camera(Camera::new(look_from, look_at, up, vertical_fov as Float, (WIDTH as Float) / (HEIGHT as Float)));
+            Ok(())
+        }));
+    });
+    let s = state.clone();
+    engine.register_fn("camera_clips", move |near: FLOAT, far: FLOAT| {
+        s.borrow_mut().ops.push(Box::new(move |scene| {
+            let mut cam = *scene.get_camera();
+            cam.set_clips(near as Float, far as Float);
+            scene.set_camera(cam);
+            Ok(())
+        }));
+    });
+    let s = state.clone();
+    engine.register_fn("camera_focus", move |distance: FLOAT| {
+        s.borrow_mut().ops.push(Box::new(move |scene| {
+            let mut cam = *scene.get_camera();
+            cam.set_focus_distance(distance as Float);
+            scene.set_camera(cam);
+            Ok(())
+        }));
+    });
+    let s = state.clone();
+    engine.register_fn("camera_distortion", move |k1: FLOAT, k2: FLOAT| {
+        s.borrow_mut().ops.push(Box::new(move |scene| {
+            let mut cam = *scene.get_camera();
+            cam.set_distortion(k1 as Float, k2 as Float);
+            scene.set_camera(cam);
+            Ok(())
+        }));
+    });
+    let s = state.clone();
+    engine.register_fn("camera_shift", move |right: FLOAT, up: FLOAT| {
+        s.borrow_mut().ops.push(Box::new(move |scene| {
+            let mut cam = *scene.get_camera();
+            cam.set_shift(right as Float, up as Float);
+            scene.set_camera(cam);
+            Ok(())
+        }));
+    });
+    let s = state.clone();
+    engine.register_fn("camera_tilt", move |right: FLOAT, up: FLOAT| {
+        s.borrow_mut().ops.push(Box::new(move |scene| {
+            let mut cam = *scene.get_camera();
+            cam.set_tilt(right as Float, up as Float);
+            scene.set_camera(cam);
+            Ok(())
+        }));
+    });
+    let s = state.clone();
+    engine.register_fn("camera_roll", move |degrees: FLOAT| {
+        s.borrow_mut().ops.push(Box::new(move |scene| {
+            let cam = scene.get_camera().rolled(degrees as Float);
+            scene.set_camera(cam);
+            Ok(())
+        }));
+    });
+    let s = state.clone();
+    engine.register_fn("named_camera", move |name: &str, look_from: Vec3, look_at: Vec3, up: Vec3, vertical_fov: FLOAT| {
+        let name = name.to_string();
+        s.borrow_mut().ops.push(Box::new(move |scene| {
+            scene.add_named_camera(&name, Camera::new(look_from, look_at, up, vertical_fov as Float, (WIDTH as Float) / (HEIGHT as Float)));
+            Ok(())
+        }));
+    });
+
+    engine
+}
+
+// Runs a Rhai script that adds to the current scene. name is used in error messages.
+pub fn run(scene: &mut Scene, source: &str, name: &str) -> Result<()> {
+    let state = Rc::new(RefCell::new(ScriptState {
+        ops: Vec::new(),
+        next_material: scene.material_count(),
+        object_count: 0,
+        rand_gen: RandGen::new()
+    }));
+    let engine = create_engine(&state);
+    engine.run(source).map_err(|e| RaytracerError::InvalidScene {
+        path: name.to_string(),
+        reason: e.to_string()
+    })?;
+    // The engine's functions hold the other references to the state.
+    drop(engine);
+
+    let state = Rc::try_unwrap(state).ok().expect("script state is still shared").into_inner();
+    let material_count = state.next_material - scene.material_count();
+    for op in state.ops {
+        op(scene)?;
+    }
+    info!(target: "scene", "Script {} added {} objects and {} materials.", name, state.object_count, material_count);
+    Ok(())
+}
+
+pub fn load(scene: &mut Scene, path: &str) -> Result<()> {
+    info!(target: "io", "Running script {}...", path);
+    let source = fs::read_to_string(path)?;
+    run(scene, &source, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"
+        let ground = lambertian(vec3(0.5, 0.5, 0.5));
+        let chrome = metal(vec3(0.9, 0.9, 0.9), 0.1);
+        name_material(chrome, "chrome");
+        plane(vec3(0.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0), ground);
+        for i in 0..3 {
+            sphere(vec3(i * 3.0, 1.0, 10.0), 1.0, chrome);
+        }
+        empty("rig");
+        sphere(vec3(0.0, 0.0, 0.0), 0.5, chrome);
+        name_object("lifted");
+        parent("rig");
+        translate("rig", vec3(0.0, 5.0, 0.0));
+        point_light(vec3(0.0, 10.0, 0.0), vec3(100.0, 100.0, 100.0));
+        camera(vec3(0.0, 1.0, 0.0), vec3(0.0, 1.0, 1.0), vec3(0.0, 1.0, 0.0), 40.0);
+    "#;
+
+    #[test]
+    fn scripts_add_materials_objects_lights_and_cameras() {
+        let mut scene = Scene::new();
+        run(&mut scene, SCRIPT, "test.rhai").unwrap();
+        assert_eq!(scene.object_count(), 5);
+        assert_eq!(scene.material_count(), 3);
+        assert_eq!(scene.get_material_by_name("chrome"), Some(2));
+        assert_eq!(scene.get_lights().len(), 1);
+        assert!((scene.get_camera().get_position() - Vec3::new(0.0, 1.0, 0.0)).length() < 1e-5);
+
+        // The sphere parented to the rig moves with it.
+        let lifted = scene.get_object_by_name("lifted").unwrap();
+        let down = Ray::new(Vec3::new(0.0, 20.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let (hit, _, _) = scene.hit(&down, Interval::new(0.001, Float::MAX));
+        assert!((hit.unwrap().t - 14.5).abs() < 1e-4);
+        assert_eq!(scene.get_object_name(lifted), Some("lifted"));
+    }
+
+    #[test]
+    fn failing_scripts_leave_the_scene_untouched() {
+        let mut scene = Scene::new();
+        let is_invalid = |result: Result<()>| matches!(result, Err(RaytracerError::InvalidScene{..}));
+        assert!(is_invalid(run(&mut scene, "let m = lambertian(vec3(1.0, 1.0, 1.0));\nsphere(vec3(0.0, 0.0, 0.0), 1.0, m", "syntax.rhai")));
+        assert!(is_invalid(run(&mut scene, "let m = lambertian(vec3(1.0, 1.0, 1.0));\nsphere(vec3(0.0, 0.0, 0.0), 1.0, m + 1);", "material.rhai")));
+        assert!(is_invalid(run(&mut scene, "name_object(\"nothing\");", "name.rhai")));
+        assert!(is_invalid(run(&mut scene, "sphere(vec3(0.0, 0.0, 0.0), 1.0, 0);\nopen_file(\"/etc/passwd\");", "sandbox.rhai")));
+        assert_eq!((scene.object_count(), scene.material_count()), (0, 1));
+        assert!(matches!(load(&mut scene, "no_such_script.rhai"), Err(RaytracerError::Io(_))));
+    }
+}
@@ -0,0 +1,278 @@
+use crate::math::*;
+use crate::raytracer::{HitRecord, Hittable};
+
+// --------------------------------------------------
+// Sdf
+// --------------------------------------------------
+// Signed distance to a surface. Negative inside. Must never overestimate the true distance.
+pub trait Sdf: Send + Sync {
+    fn distance(&self, p: Vec3) -> Float;
+}
+
+// Any thread safe closure can be used as a distance function.
+impl<F> Sdf for F where F: Fn(Vec3) -> Float + Send + Sync {
+    fn distance(&self, p: Vec3) -> Float {
+        self(p)
+    }
+}
+
+pub struct SdfSphere {
+    pub center: Vec3,
+    pub radius: Float
+}
+
+impl SdfSphere {
+    pub fn new(center: Vec3, radius: Float) -> SdfSphere {
+        SdfSphere {
+            center: center,
+            radius: radius
+        }
+    }
+}
+
+impl Sdf for SdfSphere {
+    fn distance(&self, p: Vec3) -> Float {
+        (p - self.center).length() - self.radius
+    }
+}
+
+// Axis-aligned box whose edges are rounded off by radius (included in half_extents).
+pub struct RoundedBox {
+    pub center: Vec3,
+    pub half_extents: Vec3,
+    pub radius: Float
+}
+
+impl RoundedBox {
+    pub fn new(center: Vec3, half_extents: Vec3, radius: Float) -> RoundedBox {
+        RoundedBox {
+            center: center,
+            half_extents: half_extents,
+            radius: radius
+        }
+    }
+}
+
+impl Sdf for RoundedBox {
+    fn distance(&self, p: Vec3) -> Float {
+        let p = p - self.center;
+        let q = Vec3::new(
+            p.x.abs() - self.half_extents.x + self.radius,
+            p.y.abs() - self.half_extents.y + self.radius,
+            p.z.abs() - self.half_extents.z + self.radius
+        );
+        let outside = Vec3::new(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)).length();
+        let inside = q.x.max(q.y.max(q.z)).min(0.0);
+        outside + inside - self.radius
+    }
+}
+
+// Power 8 is the classic bulb. The fractal fits in a sphere of about 1.2 * scale.
+pub struct Mandelbulb {
+    pub center: Vec3,
+    pub scale: Float,
+    pub power: Float,
+    pub iterations: u32
+}
+
+impl Mandelbulb {
+    pub fn new(center: Vec3, scale: Float) -> Mandelbulb {
+        Mandelbulb {
+            center: center,
+            scale: scale,
+            power: 8.0,
+            iterations: 12
+        }
+    }
+}
+
+impl Sdf for Mandelbulb {
+    fn distance(&self, p: Vec3) -> Float {
+        let c = (p - self.center) / self.scale;
+        let mut z = c;
+        let mut dr = 1.0;
+        let mut r = z.length();
+
+        for _i in 0..self.iterations {
+            r = z.length();
+            if !(1e-8..=2.0).contains(&r) {
+                break;
+            }
+
+            // Raise to the power in spherical coordinates.
+            let theta = (z.z / r).clamp(-1.0, 1.0).acos() * self.power;
+            let phi = z.y.atan2(z.x) * self.power;
+            dr = r.powf(self.power - 1.0) * self.power * dr + 1.0;
+            let zr = r.powf(self.power);
+            z = zr * Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos()) + c;
+        }
+
+        if r < 1e-8 {
+            return 0.0
+        }
+        0.5 * r.ln() * r / dr * self.scale
+    }
+}
+
+// Blends two distance fields with a fillet of roughly size k.
+pub struct SmoothUnion {
+    pub a: Box<dyn Sdf>,
+    pub b: Box<dyn Sdf>,
+    pub k: Float
+}
+
+impl SmoothUnion {
+    pub fn new(a: Box<dyn Sdf>, b: Box<dyn Sdf>, k: Float) -> SmoothUnion {
+        SmoothUnion {
+            a: a,
+            b: b,
+            k: k
+        }
+    }
+}
+
+impl Sdf for SmoothUnion {
+    fn distance(&self, p: Vec3) -> Float {
+        let d1 = self.a.distance(p);
+        let d2 = self.b.distance(p);
+        if self.k <= 0.0 {
+            return d1.min(d2)
+        }
+        let h = (0.5 + 0.5 * (d2 - d1) / self.k).clamp(0.0, 1.0);
+        d2 + (d1 - d2) * h - self.k * h * (1.0 - h)
+    }
+}
+
+
+// --------------------------------------------------
+// SdfObject
+// --------------------------------------------------
+// Renders any Sdf by sphere tracing.
+pub struct SdfObject {
+    sdf: Box<dyn Sdf>,
+    pub max_steps: u32,
+    pub max_distance: Float, // Marching gives up beyond this distance from the ray origin.
+    pub epsilon: Float, // Distance at which the surface counts as hit.
+    pub mat_id: u32
+}
+
+impl SdfObject {
+    pub fn new(sdf: Box<dyn Sdf>, mat_id: u32) -> SdfObject {
+        SdfObject {
+            sdf: sdf,
+            max_steps: 256,
+            max_distance: 100.0,
+            epsilon: 1e-4,
+            mat_id: mat_id
+        }
+    }
+
+    // Gradient by central differences using the tetrahedron trick (four samples instead of six).
+    fn normal(&self, p: Vec3) -> Vec3 {
+        let h = self.epsilon;
+        let k0 = Vec3::new(1.0, -1.0, -1.0);
+        let k1 = Vec3::new(-1.0, -1.0, 1.0);
+        let k2 = Vec3::new(-1.0, 1.0, -1.0);
+        let k3 = Vec3::new(1.0, 1.0, 1.0);
+        let gradient = k0 * self.sdf.distance(p + k0 * h)
+            + k1 * self.sdf.distance(p + k1 * h)
+            + k2 * self.sdf.distance(p + k2 * h)
+            + k3 * self.sdf.distance(p + k3 * h);
+        if gradient.near_zero() {Vec3::new(0.0, 1.0, 0.0)} else {gradient.normalized()}
+    }
+}
+
+impl Hittable for SdfObject {
+    fn materials(&self) -> Vec<u32> {
+        vec![self.mat_id]
+    }
+
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<HitRecord> {
+        // March in world units along a unit direction and convert back to ray parameters.
+        let dir_length = ray.direction.length();
+        if dir_length <= 0.0 {
+            return None
+        }
+        let dir = ray.direction / dir_length;
+        let origin = ray.at(ray_t.min);
+        let max_s = (self.max_distance).min(ray_t.size() * dir_length);
+
+        // Rays starting inside march outwards on the negated field.
+        let sign = if self.sdf.distance(origin) < 0.0 {-1.0} else {1.0};
+
+        let mut s = 0.0;
+        for _i in 0..self.max_steps {
+            let d = sign * self.sdf.distance(origin + dir * s);
+            if d < self.epsilon {
+                // Skip hits right at the start so that rays leaving the surface can escape.
+                if s > 2.0 * self.epsilon {
+                    let t = ray_t.min + s / dir_length;
+                    let mut hr = HitRecord::new(
+                        ray.at(t), Vec3::zero(), t, false, self.mat_id
+                    );
+                    let normal = self.normal(hr.p);
+                    hr.set_face_normal(ray, &normal);
+                    return Some(hr)
+                }
+                s += 2.0 * self.epsilon;
+                continue;
+            }
+
+            s += d;
+            if s > max_s {
+                return None
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RaytracerError;
+    use crate::scene::Scene;
+
+    fn hit_from_left(object: &SdfObject) -> Option<HitRecord> {
+        let ray = Ray::new(Vec3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        object.hit(&ray, Interval::new(0.001, Float::MAX))
+    }
+
+    #[test]
+    fn sdf_shapes_are_hit_at_their_surface() {
+        let sphere = SdfObject::new(Box::new(SdfSphere::new(Vec3::zero(), 1.0)), 0);
+        let hr = hit_from_left(&sphere).unwrap();
+        assert!((hr.t - 4.0).abs() < 1e-3);
+        assert!((hr.n.x + 1.0).abs() < 1e-2);
+        assert!(hr.front_face);
+
+        let cube = SdfObject::new(Box::new(RoundedBox::new(Vec3::zero(), Vec3::new(2.0, 1.0, 1.0), 0.1)), 0);
+        assert!((hit_from_left(&cube).unwrap().t - 3.0).abs() < 1e-3);
+
+        // Blending two spheres that don't touch still fills in between them.
+        let blend = SmoothUnion::new(Box::new(SdfSphere::new(Vec3::new(0.0, 1.1, 0.0), 1.0)), Box::new(SdfSphere::new(Vec3::new(0.0, -1.1, 0.0), 1.0)), 0.5);
+        assert!(blend.distance(Vec3::zero()) < 0.0);
+        let hard = SmoothUnion::new(Box::new(SdfSphere::new(Vec3::zero(), 1.0)), Box::new(SdfSphere::new(Vec3::new(3.0, 0.0, 0.0), 1.0)), 0.0);
+        assert!((hard.distance(Vec3::new(1.5, 0.0, 0.0)) - 0.5).abs() < 1e-5);
+
+        let bulb = SdfObject::new(Box::new(Mandelbulb::new(Vec3::zero(), 1.0)), 0);
+        assert!(hit_from_left(&bulb).is_some_and(|hr| hr.t > 3.5 && hr.t < 5.0));
+
+        // Rays starting inside leave through the far side, and rays that point away miss.
+        let inside = Ray::new(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0));
+        assert!((sphere.hit(&inside, Interval::new(0.0, Float::MAX)).unwrap().t - 1.0).abs() < 1e-3);
+        let away = Ray::new(Vec3::new(-5.0, 0.0, 0.0), Vec3::new(-1.0, 0.0, 0.0));
+        assert!(sphere.hit(&away, Interval::new(0.001, Float::MAX)).is_none());
+    }
+
+    #[test]
+    fn sdfs_need_a_known_material() {
+        let mut scene = Scene::new();
+        let sphere = || SdfSphere::new(Vec3::zero(), 1.0);
+        scene.add_sdf(SdfObject::new(Box::new(sphere()), 0)).unwrap();
+        assert_eq!(scene.object_count(), 1);
+        assert!(matches!(scene.add_sdf(SdfObject::new(Box::new(sphere()), 1)), Err(RaytracerError::InvalidMaterial(1))));
+        assert_eq!(scene.object_count(), 1);
+    }
+}
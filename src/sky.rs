@@ -0,0 +1,158 @@
+use crate::light::Light;
+use crate::math::*;
+
+// --------------------------------------------------
+// Sky
+// --------------------------------------------------
+// Clear daylight sky from the analytic model of Preetham, Shirley, and Smits ("A Practical Analytic Model
+// for Daylight", 1999). The sky is fit per CIE xyY channel by a Perez distribution of the angles to the
+// zenith and to the sun, scaled by its value at the zenith.
+#[derive(Clone, Copy)]
+pub struct Sky {
+    sun_direction: Vec3, // Unit vector towards the sun.
+    turbidity: Float,
+    pub scale: Float, // Multiplies the sky's luminance in kcd/m^2, which is about 5 to 10 at the zenith.
+    perez: [[Float; 5]; 3], // Coefficients A to E for x, y, and Y.
+    zenith: [Float; 3], // x, y, and Y at the zenith.
+    sun_theta: Float // Angle between the sun and the zenith.
+}
+
+// Solar illuminance above the atmosphere in klux, in the same units as the sky's luminance.
+const SUN_ILLUMINANCE: Float = 128.0;
+
+impl Sky {
+    // Turbidity is the haziness of the air, from 2 for a very clear sky to 10 for a hazy one.
+    pub fn new(sun_direction: Vec3, turbidity: Float) -> Sky {
+        let t = turbidity.clamp(2.0, 10.0);
+        let sun_direction = sun_direction.normalized();
+        // The fit breaks down once the sun sets, so it stays just above the horizon for the sky.
+        let sun_theta = sun_direction.y.clamp(-1.0, 1.0).acos().min(consts::FRAC_PI_2 - 0.01);
+
+        let perez = [
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703]
+        ];
+
+        // Zenith chromaticity from the paper's fits in turbidity and sun angle, and luminance in kcd/m^2.
+        let chromaticity = |m: [[Float; 4]; 3]| {
+            let thetas = [sun_theta.powi(3), sun_theta.powi(2), sun_theta, 1.0];
+            let ts = [t * t, t, 1.0];
+            let mut sum = 0.0;
+            for row in 0..3 {
+                for col in 0..4 {
+                    sum += ts[row] * m[row][col] * thetas[col];
+                }
+            }
+            sum
+        };
+        let x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886]
+        ]);
+        let y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688]
+        ]);
+        let chi = (4.0 / 9.0 - t / 120.0) * (consts::PI - 2.0 * sun_theta);
+        let luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+
+        Sky {
+            sun_direction: sun_direction,
+            turbidity: t,
+            scale: 0.1,
+            perez: perez,
+            zenith: [x, y, luminance],
+            sun_theta: sun_theta
+        }
+    }
+
+    pub fn get_sun_direction(&self) -> Vec3 {
+        self.sun_direction
+    }
+
+    pub fn get_turbidity(&self) -> Float {
+        self.turbidity
+    }
+
+    // Radiance of the sky in a direction. The sun itself is left out, as sun_light adds it. Directions
+    // below the horizon see the horizon.
+    pub fn radiance(&self, direction: &Vec3) -> Vec3 {
+        let direction = direction.normalized();
+        let cos_theta = direction.y.max(0.01);
+        let cos_gamma = direction.dot(&self.sun_direction).clamp(-1.0, 1.0);
+        let gamma = cos_gamma.acos();
+
+        let perez = |c: &[Float; 5], cos_theta: Float, gamma: Float, cos_gamma: Float| {
+            (1.0 + c[0] * (c[1] / cos_theta).exp()) * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * cos_gamma * cos_gamma)
+        };
+        let mut xyy = [0.0; 3];
+        for (i, value) in xyy.iter_mut().enumerate() {
+            let at_zenith = perez(&self.perez[i], 1.0, self.sun_theta, self.sun_theta.cos());
+            *value = self.zenith[i] * perez(&self.perez[i], cos_theta, gamma, cos_gamma) / at_zenith;
+        }
+        xyy_to_rgb(xyy[0], xyy[1], xyy[2] * self.scale)
+    }
+
+    // Directional light for the sun, dimmed and reddened by the air it passes through, in the sky's units.
+    pub fn sun_light(&self) -> Light {
+        if self.sun_direction.y <= 0.0 {
+            return Light::directional(-self.sun_direction, Vec3::zero())
+        }
+
+        // Relative air mass (Kasten and Young) with Rayleigh and aerosol optical depths at the wavelengths of
+        // the color channels, in micrometers.
+        let elevation = 90.0 - self.sun_theta.to_degrees();
+        let air_mass = 1.0 / (self.sun_direction.y + 0.50572 * (elevation + 6.07995).powf(-1.6364));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let transmittance = |wavelength: Float| {
+            let rayleigh = 0.008735 * wavelength.powf(-4.08);
+            let aerosol = beta * wavelength.powf(-1.3);
+            (-air_mass * (rayleigh + aerosol)).exp()
+        };
+        let color = Vec3::new(transmittance(0.65), transmittance(0.55), transmittance(0.45));
+        Light::directional(-self.sun_direction, color * (SUN_ILLUMINANCE * self.scale))
+    }
+}
+
+fn xyy_to_rgb(x: Float, y: Float, luminance: Float) -> Vec3 {
+    if y <= 0.0 {
+        return Vec3::zero()
+    }
+    let big_x = x / y * luminance;
+    let big_z = (1.0 - x - y) / y * luminance;
+    // XYZ to linear sRGB (D65).
+    Vec3::new(
+        (3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z).max(0.0),
+        (-0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z).max(0.0),
+        (0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z).max(0.0)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sky_is_blue_and_bright_around_the_sun() {
+        let sky = Sky::new(Vec3::new(0.0, 1.0, 1.0), 3.0);
+        let zenith = sky.radiance(&Vec3::new(0.0, 1.0, 0.0));
+        assert!(zenith.z > zenith.x);
+        assert!((zenith.y - 0.1 * sky.zenith[2]).abs() < 0.1);
+        let near_sun = sky.radiance(&Vec3::new(0.0, 1.0, 1.2));
+        let away = sky.radiance(&Vec3::new(0.0, 1.0, -1.2));
+        assert!(near_sun.y > away.y);
+
+        // The setting sun is dimmer and redder.
+        let sun_at = |height: Float| match Sky::new(Vec3::new(0.0, height, 1.0), 3.0).sun_light() {
+            Light::Directional{irradiance, ..} => irradiance,
+            _ => unreachable!()
+        };
+        let (high, low) = (sun_at(2.0), sun_at(0.05));
+        assert!(high.y > low.y);
+        assert!(low.x / low.z > high.x / high.z);
+        assert_eq!(sun_at(-1.0).x, 0.0);
+    }
+}
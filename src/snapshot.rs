@@ -0,0 +1,88 @@
+use std::time::{Duration, Instant};
+
+use crate::math::Float;
+
+// --------------------------------------------------
+// Snapshots
+// --------------------------------------------------
+// Long progressive renders can save what they have accumulated every so often, so that a crash or power cut
+// only loses the passes since the last snapshot. Snapshots are numbered, and the numbers carry on across
+// renders, so none overwrites another from the same session.
+
+pub struct SnapshotSchedule {
+    every: Option<Duration>, // Wall-clock time between snapshots.
+    every_samples: Option<Float>, // Samples per pixel between snapshots.
+    count: u32, // Snapshots taken so far.
+    last_time: Instant, // Of the last snapshot, or of the start of the render.
+    last_samples: Float // Samples per pixel at the last snapshot, or at the start of the render.
+}
+
+impl SnapshotSchedule {
+    pub fn new(every: Option<Duration>, every_samples: Option<u32>) -> SnapshotSchedule {
+        SnapshotSchedule {
+            every: every,
+            every_samples: every_samples.map(|samples| samples as Float),
+            count: 0,
+            last_time: Instant::now(),
+            last_samples: 0.0
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.every.is_some() || self.every_samples.is_some()
+    }
+
+    // Counts intervals from now, e.g. when a progressive render starts or resumes with the samples per pixel it
+    // already has.
+    pub fn restart(&mut self, samples: Float) {
+        self.last_time = Instant::now();
+        self.last_samples = samples;
+    }
+
+    // Whether a snapshot is due at the render's samples per pixel. A render with fewer samples than the last
+    // snapshot has started over, and its intervals count from now.
+    pub fn is_due(&mut self, samples: Float) -> bool {
+        if samples < self.last_samples {
+            self.restart(samples);
+        }
+        let time_due = self.every.is_some_and(|every| self.last_time.elapsed() >= every);
+        let samples_due = self.every_samples.is_some_and(|every| samples - self.last_samples >= every);
+        time_due || samples_due
+    }
+
+    // Records a snapshot taken at the render's samples per pixel, and returns its number, from 1.
+    pub fn taken(&mut self, samples: Float) -> u32 {
+        self.count += 1;
+        self.restart(samples);
+        self.count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_are_due_every_interval() {
+        let mut schedule = SnapshotSchedule::new(None, Some(8));
+        assert!(schedule.is_enabled());
+        assert!(!schedule.is_due(7.5));
+        assert!(schedule.is_due(8.0));
+        assert_eq!(schedule.taken(9.0), 1);
+        assert!(!schedule.is_due(16.0));
+        assert!(schedule.is_due(17.0));
+        assert_eq!(schedule.taken(17.0), 2);
+
+        // Resuming counts from the samples the render has, and keeps numbering.
+        schedule.restart(40.0);
+        assert!(!schedule.is_due(44.0));
+        assert_eq!(schedule.taken(48.0), 3);
+        assert!(!schedule.is_due(2.0));
+        assert!(schedule.is_due(10.0));
+
+        let mut schedule = SnapshotSchedule::new(Some(Duration::from_secs(0)), None);
+        assert!(schedule.is_due(0.0));
+        assert_eq!(schedule.taken(0.0), 1);
+        assert!(!SnapshotSchedule::new(None, None).is_enabled());
+    }
+}
@@ -0,0 +1,158 @@
+// --------------------------------------------------
+// Stamp
+// --------------------------------------------------
+// Lines of text burnt into a corner of a saved image, such as the settings it was rendered with, so that
+// comparison renders can be told apart once shared. Drawn with a built-in 5x7 pixel font, which covers digits,
+// letters (shown in capitals), and common punctuation. Other characters are drawn as '?'.
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+const ADVANCE: u32 = GLYPH_WIDTH + 1;
+const LINE_HEIGHT: u32 = GLYPH_HEIGHT + 3;
+const PADDING: u32 = 4; // Around the text, inside the box.
+
+// Rows of a glyph from the top, with the leftmost pixel in bit 4.
+fn glyph(c: char) -> [u8; 7] {
+    match c.to_ascii_uppercase() {
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '0' => [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+        '1' => [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        '2' => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+        '3' => [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+        '4' => [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+        '5' => [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+        '6' => [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+        '7' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+        '9' => [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+        'A' => [0x0e, 0x11, 0x11, 0x11, 0x1f, 0x11, 0x11],
+        'B' => [0x1e, 0x11, 0x11, 0x1e, 0x11, 0x11, 0x1e],
+        'C' => [0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e],
+        'D' => [0x1c, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1c],
+        'E' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f],
+        'F' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x10],
+        'G' => [0x0e, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0f],
+        'H' => [0x11, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11],
+        'I' => [0x0e, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0c],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1f],
+        'M' => [0x11, 0x1b, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+        'P' => [0x1e, 0x11, 0x11, 0x1e, 0x10, 0x10, 0x10],
+        'Q' => [0x0e, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0d],
+        'R' => [0x1e, 0x11, 0x11, 0x1e, 0x14, 0x12, 0x11],
+        'S' => [0x0f, 0x10, 0x10, 0x0e, 0x01, 0x01, 0x1e],
+        'T' => [0x1f, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0a, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0a],
+        'X' => [0x11, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0a, 0x04, 0x04, 0x04],
+        'Z' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1f],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0c, 0x04, 0x08],
+        ':' => [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00],
+        '+' => [0x00, 0x04, 0x04, 0x1f, 0x04, 0x04, 0x00],
+        '=' => [0x00, 0x00, 0x1f, 0x00, 0x1f, 0x00, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1f],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '%' => [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+        _ => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04]
+    }
+}
+
+// Draws lines of text into the bottom left corner of an 8-bit image with 3 (RGB) or 4 (RGBA) channels, in
+// white over a darkened box so that it reads over any render. The box is opaque in RGBA images, so the text
+// shows over transparent backgrounds too. The font is scaled up for images taller than 360 pixels, and text
+// that doesn't fit is cut off.
+pub fn stamp(data: &mut [u8], width: u32, height: u32, channels: u32, lines: &[String]) {
+    if lines.is_empty() {
+        return
+    }
+    let scale = (height / 360).max(1);
+    let columns = lines.iter().map(|line| line.chars().count() as u32).max().unwrap_or(0);
+    let box_width = ((columns * ADVANCE).saturating_sub(1) + PADDING * 2) * scale;
+    let box_height = ((lines.len() as u32) * LINE_HEIGHT - (LINE_HEIGHT - GLYPH_HEIGHT) + PADDING * 2) * scale;
+    let left = PADDING * scale;
+    let top = height.saturating_sub(box_height + PADDING * scale);
+
+    let mut set = |x: u32, y: u32, value: Option<u8>| {
+        if x >= width || y >= height {
+            return
+        }
+        let offset = ((y * width + x) * channels) as usize;
+        for channel in 0..3 {
+            data[offset + channel] = match value {
+                Some(value) => value,
+                None => data[offset + channel] / 3
+            };
+        }
+        if channels == 4 {
+            data[offset + 3] = 255;
+        }
+    };
+
+    for y in top..(top + box_height) {
+        for x in left..(left + box_width) {
+            set(x, y, None);
+        }
+    }
+    for (row, line) in lines.iter().enumerate() {
+        let line_top = top + (PADDING + (row as u32) * LINE_HEIGHT) * scale;
+        for (column, c) in line.chars().enumerate() {
+            let glyph_left = left + (PADDING + (column as u32) * ADVANCE) * scale;
+            for (glyph_y, bits) in glyph(c).iter().enumerate() {
+                for glyph_x in 0..GLYPH_WIDTH {
+                    if bits & (0x10 >> glyph_x) == 0 {
+                        continue;
+                    }
+                    for dy in 0..scale {
+                        for dx in 0..scale {
+                            set(glyph_left + glyph_x * scale + dx, line_top + (glyph_y as u32) * scale + dy, Some(255));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stamps_text_into_the_bottom_left_corner() {
+        let (width, height) = (64, 40);
+        let mut data = vec![90; (width * height * 4) as usize];
+        stamp(&mut data, width, height, 4, &["1.0".to_string()]);
+        let pixel = |x: u32, y: u32| {
+            let offset = ((y * width + x) * 4) as usize;
+            (data[offset], data[offset + 3])
+        };
+
+        // The box is 3 glyphs and padding wide, and sits above the bottom margin.
+        let box_top = height - (GLYPH_HEIGHT + PADDING * 3);
+        assert_eq!(pixel(PADDING, box_top), (30, 255));
+        assert_eq!(pixel(PADDING, box_top - 1), (90, 90));
+        assert_eq!(pixel(PADDING * 3 + ADVANCE * 3, box_top), (90, 90));
+
+        // The top of the '1' is lit, and the top of the '.' isn't.
+        let text_left = PADDING * 2;
+        let text_top = box_top + PADDING;
+        assert_eq!(pixel(text_left + 2, text_top), (255, 255));
+        assert_eq!(pixel(text_left + ADVANCE + 2, text_top), (30, 255));
+
+        // Text past the edges is cut off, and other characters are drawn.
+        let mut rgb = vec![0; (16 * 16 * 3) as usize];
+        stamp(&mut rgb, 16, 16, 3, &["a long line ~".to_string(), "and another".to_string()]);
+        assert!(rgb.contains(&255));
+        assert_eq!(glyph('~'), glyph('?'));
+        assert_eq!(glyph('x'), glyph('X'));
+    }
+}
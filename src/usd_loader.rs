@@ -0,0 +1,682 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+
+use log::{info, warn};
+
+use crate::error::{RaytracerError, Result};
+use crate::math::*;
+use crate::primitives::Mesh;
+use crate::raytracer::{Dielectric, PbrMaterial, Sphere};
+use crate::scene::Scene;
+
+fn invalid(path: &str, reason: &str) -> RaytracerError {
+    RaytracerError::InvalidScene {
+        path: path.to_string(),
+        reason: reason.to_string()
+    }
+}
+
+// --------------------------------------------------
+// USDA parsing
+// --------------------------------------------------
+#[derive(Clone, PartialEq)]
+enum Token {
+    Word(String), // Identifiers, type names, and numbers.
+    Str(String),
+    Path(String), // <...>
+    Asset(String), // @...@
+    Punct(char)
+}
+
+fn tokenize(path: &str, text: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '"' | '\'' => {
+                // Triple quoted strings may span lines.
+                let triple = chars.peek() == Some(&c) && {
+                    let mut ahead = chars.clone();
+                    ahead.next();
+                    ahead.next() == Some(c)
+                };
+                if triple {
+                    chars.next();
+                    chars.next();
+                }
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c && !triple => break,
+                        Some(q) if q == c && chars.peek() == Some(&c) => {
+                            let mut ahead = chars.clone();
+                            ahead.next();
+                            if ahead.next() == Some(c) {
+                                chars.next();
+                                chars.next();
+                                break;
+                            }
+                            s.push(q);
+                        }
+                        Some('\\') => {
+                            if let Some(escaped) = chars.next() {
+                                s.push(escaped);
+                            }
+                        }
+                        Some(q) => s.push(q),
+                        None => return Err(invalid(path, "unterminated string"))
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            '<' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('>') => break,
+                        Some(q) => s.push(q),
+                        None => return Err(invalid(path, "unterminated path"))
+                    }
+                }
+                tokens.push(Token::Path(s));
+            }
+            '@' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('@') => break,
+                        Some(q) => s.push(q),
+                        None => return Err(invalid(path, "unterminated asset path"))
+                    }
+                }
+                tokens.push(Token::Asset(s));
+            }
+            '(' | ')' | '[' | ']' | '{' | '}' | '=' | ',' | ';' => tokens.push(Token::Punct(c)),
+            c if c.is_whitespace() => {}
+            c => {
+                let mut s = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || "()[]{}=,;\"'<@#".contains(next) {
+                        break;
+                    }
+                    s.push(next);
+                    chars.next();
+                }
+                tokens.push(Token::Word(s));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Clone)]
+enum Value {
+    Number(Float),
+    Text(String), // Strings, tokens, and asset paths.
+    Path(String),
+    List(Vec<Value>) // Tuples and arrays alike.
+}
+
+impl Value {
+    fn number(&self) -> Option<Float> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None
+        }
+    }
+
+    fn text(&self) -> Option<&str> {
+        match self {
+            Value::Text(s) => Some(s),
+            _ => None
+        }
+    }
+
+    fn list(&self) -> &[Value] {
+        match self {
+            Value::List(items) => items,
+            _ => &[]
+        }
+    }
+
+    fn vec3(&self) -> Option<Vec3> {
+        let items = self.list();
+        if items.len() != 3 {
+            return None
+        }
+        Some(Vec3::new(items[0].number()?, items[1].number()?, items[2].number()?))
+    }
+
+    fn numbers(&self) -> Vec<Float> {
+        self.list().iter().filter_map(|v| v.number()).collect()
+    }
+}
+
+struct Prim {
+    type_name: String,
+    name: String,
+    path: String,
+    attributes: HashMap<String, Value>,
+    relationships: HashMap<String, Vec<String>>,
+    children: Vec<Prim>
+}
+
+impl Prim {
+    fn number(&self, name: &str, default: Float) -> Float {
+        self.attributes.get(name).and_then(|v| v.number()).unwrap_or(default)
+    }
+}
+
+struct Parser<'a> {
+    path: &'a str,
+    tokens: Vec<Token>,
+    pos: usize
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let token = self.tokens.get(self.pos).cloned().ok_or_else(|| invalid(self.path, "unexpected end of file"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        match self.next()? {
+            Token::Punct(p) if p == c => Ok(()),
+            _ => Err(invalid(self.path, &format!("expected '{}'", c)))
+        }
+    }
+
+    // Skips a balanced (...) block such as layer or prim metadata.
+    fn skip_parens(&mut self) -> Result<()> {
+        self.expect('(')?;
+        let mut depth = 1;
+        while depth > 0 {
+            match self.next()? {
+                Token::Punct('(') => depth += 1,
+                Token::Punct(')') => depth -= 1,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.next()? {
+            Token::Word(w) => Ok(w.parse().map(Value::Number).unwrap_or(Value::Text(w))),
+            Token::Str(s) | Token::Asset(s) => Ok(Value::Text(s)),
+            Token::Path(p) => Ok(Value::Path(p)),
+            Token::Punct(open) if open == '(' || open == '[' => {
+                let close = if open == '(' {')'} else {']'};
+                let mut items = Vec::new();
+                loop {
+                    match self.peek() {
+                        Some(Token::Punct(c)) if *c == close => {
+                            self.pos += 1;
+                            break;
+                        }
+                        Some(Token::Punct(',')) => self.pos += 1,
+                        _ => items.push(self.value()?)
+                    }
+                }
+                Ok(Value::List(items))
+            }
+            Token::Punct('{') => {
+                // Dictionaries and time samples are skipped.
+                let mut depth = 1;
+                while depth > 0 {
+                    match self.next()? {
+                        Token::Punct('{') => depth += 1,
+                        Token::Punct('}') => depth -= 1,
+                        _ => {}
+                    }
+                }
+                Ok(Value::List(Vec::new()))
+            }
+            _ => Err(invalid(self.path, "malformed value"))
+        }
+    }
+
+    // Returns the root prims and whether the stage is Z up.
+    fn stage(&mut self) -> Result<(Vec<Prim>, bool)> {
+        let mut z_up = false;
+        if let Some(Token::Punct('(')) = self.peek() {
+            let start = self.pos;
+            self.skip_parens()?;
+            z_up = self.tokens[start..self.pos].windows(3).any(|w| {
+                w[0] == Token::Word("upAxis".to_string()) && w[1] == Token::Punct('=') && w[2] == Token::Str("Z".to_string())
+            });
+        }
+        let mut prims = Vec::new();
+        while self.peek().is_some() {
+            if let Some(prim) = self.prim("")? {
+                prims.push(prim);
+            }
+        }
+        Ok((prims, z_up))
+    }
+
+    // def/over/class [Type] "name" [(metadata)] { ... }. Abstract classes are parsed but dropped.
+    fn prim(&mut self, parent_path: &str) -> Result<Option<Prim>> {
+        let specifier = match self.next()? {
+            Token::Word(w) if w == "def" || w == "over" || w == "class" => w,
+            _ => return Err(invalid(self.path, "expected a prim definition"))
+        };
+        let type_name = match self.peek() {
+            Some(Token::Word(w)) => {let w = w.clone(); self.pos += 1; w}
+            _ => String::new()
+        };
+        let name = match self.next()? {
+            Token::Str(s) => s,
+            _ => return Err(invalid(self.path, "expected a prim name"))
+        };
+        if let Some(Token::Punct('(')) = self.peek() {
+            self.skip_parens()?;
+        }
+        self.expect('{')?;
+
+        let mut prim = Prim {
+            type_name: type_name,
+            path: format!("{}/{}", parent_path, name),
+            name: name,
+            attributes: HashMap::new(),
+            relationships: HashMap::new(),
+            children: Vec::new()
+        };
+        loop {
+            match self.peek() {
+                Some(Token::Punct('}')) => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(Token::Word(w)) if w == "def" || w == "over" || w == "class" => {
+                    if let Some(child) = self.prim(&prim.path)? {
+                        prim.children.push(child);
+                    }
+                }
+                Some(Token::Word(w)) if w == "variantSet" => return Err(invalid(self.path, "variant sets are not supported")),
+                Some(_) => self.property(&mut prim)?,
+                None => return Err(invalid(self.path, "unterminated prim"))
+            }
+        }
+        Ok(if specifier == "class" {None} else {Some(prim)})
+    }
+
+    // [custom] [uniform] [rel | type] name [= value] [(metadata)]
+    fn property(&mut self, prim: &mut Prim) -> Result<()> {
+        let mut words = Vec::new();
+        while let Some(Token::Word(w)) = self.peek() {
+            words.push(w.clone());
+            self.pos += 1;
+        }
+        // Array types are written as type[] and tokenize as type [ ].
+        if let (Some(Token::Punct('[')), Some(Token::Punct(']'))) = (self.tokens.get(self.pos), self.tokens.get(self.pos + 1)) {
+            self.pos += 2;
+            if let Some(Token::Word(w)) = self.peek() {
+                words.push(w.clone());
+                self.pos += 1;
+            }
+        }
+        let name = words.pop().ok_or_else(|| invalid(self.path, &format!("malformed property in {}", prim.path)))?;
+        let is_rel = words.iter().any(|w| w == "rel");
+
+        let mut value = None;
+        if let Some(Token::Punct('=')) = self.peek() {
+            self.pos += 1;
+            value = Some(self.value()?);
+        }
+        if let Some(Token::Punct('(')) = self.peek() {
+            self.skip_parens()?;
+        }
+
+        if let Some(value) = value {
+            if is_rel {
+                let targets = match value {
+                    Value::Path(p) => vec![p],
+                    Value::List(items) => items.into_iter().filter_map(|v| if let Value::Path(p) = v {Some(p)} else {None}).collect(),
+                    _ => Vec::new()
+                };
+                prim.relationships.insert(name, targets);
+            } else {
+                prim.attributes.insert(name, value);
+            }
+        }
+        Ok(())
+    }
+}
+
+
+// --------------------------------------------------
+// Stage to scene
+// --------------------------------------------------
+struct Loader<'a> {
+    path: &'a str,
+    materials: HashMap<String, u32>, // Material prim path to ray tracer material index.
+    shape_count: usize,
+    ignored: Vec<String>
+}
+
+impl<'a> Loader<'a> {
+    fn ignore(&mut self, what: String) {
+        if !self.ignored.contains(&what) {
+            warn!(target: "scene", "Ignoring unsupported {} in {}.", what, self.path);
+            self.ignored.push(what);
+        }
+    }
+
+    // Material prims are read up front so that bindings can refer to materials defined later.
+    fn load_materials(&mut self, scene: &mut Scene, prims: &[Prim]) -> Result<()> {
+        for prim in prims {
+            if prim.type_name == "Material" {
+                let surface = prim.children.iter().find(|child| {
+                    child.type_name == "Shader" && child.attributes.get("info:id").and_then(|v| v.text()) == Some("UsdPreviewSurface")
+                });
+                if let Some(shader) = surface {
+                    let mat_id = self.add_preview_surface(scene, shader);
+                    scene.set_material_name(mat_id, &prim.name)?;
+                    self.materials.insert(prim.path.clone(), mat_id);
+                } else {
+                    self.ignore(format!("material {} without a UsdPreviewSurface", prim.path));
+                }
+            }
+            self.load_materials(scene, &prim.children)?;
+        }
+        Ok(())
+    }
+
+    // Textured inputs are connections rather than values, so they fall back to the defaults.
+    fn add_preview_surface(&mut self, scene: &mut Scene, shader: &Prim) -> u32 {
+        let diffuse = shader.attributes.get("inputs:diffuseColor").and_then(|v| v.vec3()).unwrap_or(Vec3::one() * 0.18);
+        if shader.number("inputs:opacity", 1.0) < 1.0 {
+            return scene.add_dielectric_material(Dielectric::new(shader.number("inputs:ior", 1.5)))
+        }
+        scene.add_pbr_material(PbrMaterial::new(diffuse, shader.number("inputs:metallic", 0.0), shader.number("inputs:roughness", 0.5)))
+    }
+
+    // Composes the ops listed in xformOpOrder, with the first op outermost.
+    fn local_transform(&mut self, prim: &Prim) -> (Mat4, bool) {
+        let mut transform = Mat4::identity();
+        let mut reset = false;
+        let order: Vec<String> = prim.attributes.get("xformOpOrder")
+            .map_or(Vec::new(), |v| v.list().iter().filter_map(|op| op.text().map(|s| s.to_string())).collect());
+        for op in &order {
+            if op == "!resetXformStack!" {
+                reset = true;
+                transform = Mat4::identity();
+                continue;
+            }
+            let (name, invert) = match op.strip_prefix("!invert!") {
+                Some(name) => (name, true),
+                None => (op.as_str(), false)
+            };
+            let value = match prim.attributes.get(name) {
+                Some(value) => value,
+                None => continue
+            };
+            let vec3 = value.vec3();
+            let scalar = value.number();
+            let kind = name.split(':').nth(1).unwrap_or("");
+            let rotate = |axis: Vec3, degrees: Float| Mat4::rotation(degrees.to_radians(), axis);
+            let (x, y, z) = (Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.0, 1.0));
+            let m = match (kind, vec3, scalar) {
+                ("translate", Some(t), _) => Mat4::translation(t),
+                ("scale", Some(s), _) => Mat4::scale(s),
+                ("scale", None, Some(s)) => Mat4::scale(Vec3::one() * s),
+                ("rotateX", _, Some(a)) => rotate(x, a),
+                ("rotateY", _, Some(a)) => rotate(y, a),
+                ("rotateZ", _, Some(a)) => rotate(z, a),
+                // The first named axis is applied first.
+                ("rotateXYZ", Some(r), _) => rotate(z, r.z) * rotate(y, r.y) * rotate(x, r.x),
+                ("rotateXZY", Some(r), _) => rotate(y, r.y) * rotate(z, r.z) * rotate(x, r.x),
+                ("rotateYXZ", Some(r), _) => rotate(z, r.z) * rotate(x, r.x) * rotate(y, r.y),
+                ("rotateYZX", Some(r), _) => rotate(x, r.x) * rotate(z, r.z) * rotate(y, r.y),
+                ("rotateZXY", Some(r), _) => rotate(y, r.y) * rotate(x, r.x) * rotate(z, r.z),
+                ("rotateZYX", Some(r), _) => rotate(x, r.x) * rotate(y, r.y) * rotate(z, r.z),
+                ("transform", _, _) => {
+                    // USD matrices are row-major for row vectors, which is column-major for column vectors.
+                    let rows = value.list();
+                    let mut cols = [[0.0; 4]; 4];
+                    for (i, row) in rows.iter().take(4).enumerate() {
+                        for (j, v) in row.numbers().iter().take(4).enumerate() {
+                            cols[i][j] = *v;
+                        }
+                    }
+                    Mat4::from_cols(&cols)
+                }
+                _ => {
+                    self.ignore(format!("transform op {}", name));
+                    continue;
+                }
+            };
+            let m = if invert {m.inverse().unwrap_or_else(Mat4::identity)} else {m};
+            transform = transform * m;
+        }
+        (transform, reset)
+    }
+
+    fn load_prim(&mut self, scene: &mut Scene, prim: &Prim, parent: &Mat4, parent_mat: u32) -> Result<()> {
+        let (local, reset) = self.local_transform(prim);
+        let transform = if reset {local} else {*parent * local};
+        // Bindings are inherited by descendants.
+        let mat_id = prim.relationships.get("material:binding")
+            .and_then(|targets| targets.first())
+            .and_then(|target| self.materials.get(target).copied())
+            .unwrap_or(parent_mat);
+
+        match prim.type_name.as_str() {
+            "Sphere" => {
+                let radius = prim.number("radius", 1.0) * transform.determinant().abs().cbrt();
+                scene.add_sphere(Sphere::new(transform.transform_point(Vec3::zero()), radius, mat_id))?;
+                self.shape_count += 1;
+            }
+            "Mesh" => {
+                self.add_mesh(scene, prim, &transform, mat_id)?;
+                self.shape_count += 1;
+            }
+            "" | "Xform" | "Scope" | "Material" | "Shader" => {}
+            other => self.ignore(format!("{} prims", other))
+        }
+        // Shapes are named by their prim's path, which is unique in the stage.
+        if let ("Sphere", Some(object_id)) | ("Mesh", Some(object_id)) = (prim.type_name.as_str(), scene.last_object_id()) {
+            scene.set_object_name(object_id, &prim.path)?;
+        }
+
+        // Materials are leaves as far as geometry is concerned.
+        if prim.type_name != "Material" {
+            for child in &prim.children {
+                self.load_prim(scene, child, &transform, mat_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_mesh(&mut self, scene: &mut Scene, prim: &Prim, transform: &Mat4, mat_id: u32) -> Result<()> {
+        let list = |name: &str| prim.attributes.get(name).map_or(Vec::new(), |v| v.list().to_vec());
+        let positions: Vec<Vec3> = list("points").iter().filter_map(|p| p.vec3()).map(|p| transform.transform_point(p)).collect();
+        let counts: Vec<usize> = list("faceVertexCounts").iter().filter_map(|v| v.number()).map(|v| v as usize).collect();
+        let indices: Vec<u32> = list("faceVertexIndices").iter().filter_map(|v| v.number()).map(|v| v as u32).collect();
+
+        // Only per-point normals map onto the mesh. Face-varying ones are dropped for flat shading.
+        let mut normals: Vec<Vec3> = list("normals").iter().filter_map(|n| n.vec3()).map(|n| transform.transform_normal(n)).collect();
+        if normals.len() != positions.len() {
+            normals.clear();
+        }
+
+        // A left handed orientation or a mirroring transform flips the winding.
+        let left_handed = prim.attributes.get("orientation").and_then(|v| v.text()) == Some("leftHanded");
+        let flip = left_handed != (transform.determinant() < 0.0);
+
+        let mut triangles = Vec::new();
+        let mut start = 0;
+        for count in counts {
+            if start + count > indices.len() {
+                return Err(invalid(self.path, &format!("{} has more face vertices than indices", prim.path)))
+            }
+            let face = &indices[start..(start + count)];
+            for i in 1..count.saturating_sub(1) {
+                triangles.push(if flip {[face[0], face[i + 1], face[i]]} else {[face[0], face[i], face[i + 1]]});
+            }
+            start += count;
+        }
+
+        let mesh = Mesh::new(positions, normals, Vec::new(), triangles, mat_id)
+            .map_err(|e| invalid(self.path, &format!("{}: {}", prim.path, e)))?;
+        scene.add_mesh(mesh)
+    }
+}
+
+// Reads the root layer text of a .usda file, or of the first .usda layer inside a .usdz archive.
+fn read_layer(path: &str) -> Result<String> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    if data.starts_with(b"PK") {
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data)).map_err(|e| invalid(path, &e.to_string()))?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i).map_err(|e| invalid(path, &e.to_string()))?;
+            if entry.name().ends_with(".usda") {
+                let mut text = String::new();
+                entry.read_to_string(&mut text)?;
+                return Ok(text)
+            }
+        }
+        return Err(invalid(path, "the archive has no .usda layer (binary .usdc layers are not supported)"))
+    }
+    if data.starts_with(b"PXR-USDC") {
+        return Err(invalid(path, "binary .usdc layers are not supported, export as .usda instead"))
+    }
+    String::from_utf8(data).map_err(|_| invalid(path, "not a text layer"))
+}
+
+// Adds the spheres and meshes of a USD stage with their UsdPreviewSurface materials.
+// Only the root layer is read, so references, payloads, and sublayers are not composed.
+pub fn load(scene: &mut Scene, path: &str) -> Result<()> {
+    info!(target: "io", "Reading USD file {}...", path);
+    let text = read_layer(path)?;
+    if !text.starts_with("#usda") {
+        return Err(invalid(path, "missing #usda header"))
+    }
+    let mut parser = Parser {
+        path: path,
+        tokens: tokenize(path, &text)?,
+        pos: 0
+    };
+    let (prims, z_up) = parser.stage()?;
+    // The ray tracer is Y up.
+    let root = if z_up {Mat4::rotation(-consts::FRAC_PI_2, Vec3::new(1.0, 0.0, 0.0))} else {Mat4::identity()};
+
+    let mut loader = Loader {
+        path: path,
+        materials: HashMap::new(),
+        shape_count: 0,
+        ignored: Vec::new()
+    };
+    loader.load_materials(scene, &prims)?;
+    for prim in &prims {
+        loader.load_prim(scene, prim, &root, 0)?;
+    }
+
+    info!(target: "scene", "Loaded {} shapes and {} materials from {}.", loader.shape_count, loader.materials.len(), path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Fixture;
+
+    // A Z up stage with a glass sphere lifted 2 up, and a floor quad that uses the default material.
+    const STAGE: &str = r#"#usda 1.0
+(
+    upAxis = "Z"
+    metersPerUnit = 1
+)
+
+def Xform "World"
+{
+    def Material "Glass"
+    {
+        def Shader "Surface"
+        {
+            uniform token info:id = "UsdPreviewSurface"
+            float inputs:opacity = 0.1
+            float inputs:ior = 1.33
+        }
+    }
+
+    def Xform "Ball" (
+        prepend apiSchemas = ["MaterialBindingAPI"]
+    )
+    {
+        rel material:binding = </World/Glass>
+        double3 xformOp:translate = (0, 0, 2)
+        uniform token[] xformOpOrder = ["xformOp:translate"]
+
+        def Sphere "Shape"
+        {
+            double radius = 0.5
+        }
+    }
+
+    def Mesh "Floor"
+    {
+        int[] faceVertexCounts = [4]
+        int[] faceVertexIndices = [0, 1, 2, 3]
+        point3f[] points = [(-5, -5, 0), (5, -5, 0), (5, 5, 0), (-5, 5, 0)]
+    }
+
+    class "Template"
+    {
+        def Sphere "Ignored" {}
+    }
+}
+"#;
+
+    #[test]
+    fn usd_stages_load_shapes_materials_and_transforms() {
+        let files = Fixture::new("usd_stages");
+        let mut scene = Scene::new();
+        load(&mut scene, &files.write("stage.usda", STAGE)).unwrap();
+        assert_eq!(scene.object_count(), 2);
+        assert_eq!(scene.material_count(), 2);
+        let glass = scene.get_material_by_name("Glass").unwrap();
+        assert!(scene.get_material_as::<Dielectric>(glass).is_ok());
+        assert!(scene.get_object_by_name("/World/Ball/Shape").is_some());
+        assert!(scene.get_object_by_name("/World/Floor").is_some());
+
+        // Z up becomes Y up: the ball sits 2 above the floor, and keeps the binding of its parent.
+        let down = Ray::new(Vec3::new(0.0, 10.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let hr = scene.hit(&down, Interval::new(0.001, Float::MAX)).0.unwrap();
+        assert!((hr.t - 7.5).abs() < 1e-4);
+        assert_eq!(hr.mat_id, glass);
+        let beside = Ray::new(Vec3::new(3.0, 10.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let hr = scene.hit(&beside, Interval::new(0.001, Float::MAX)).0.unwrap();
+        assert!((hr.t - 10.0).abs() < 1e-4);
+        assert_eq!(hr.mat_id, 0);
+    }
+
+    #[test]
+    fn malformed_usd_stages_are_scene_errors() {
+        let files = Fixture::new("malformed_usd");
+        let load_text = |name: &str, text: &str| load(&mut Scene::new(), &files.write(name, text));
+        let is_invalid = |result: Result<()>| matches!(result, Err(RaytracerError::InvalidScene{..}));
+        assert!(is_invalid(load_text("header.usda", "def Sphere \"Ball\" {}\n")));
+        assert!(is_invalid(load_text("binary.usdc", "PXR-USDC")));
+        assert!(is_invalid(load_text("unterminated.usda", "#usda 1.0\ndef Xform \"World\" {\n")));
+        assert!(is_invalid(load_text("variants.usda", "#usda 1.0\ndef Xform \"World\" {\n    variantSet \"look\" = {}\n}\n")));
+        assert!(is_invalid(load_text("faces.usda", &STAGE.replace("[0, 1, 2, 3]", "[0, 1, 2]"))));
+        assert!(is_invalid(load_text("points.usda", &STAGE.replace("[0, 1, 2, 3]", "[0, 1, 2, 7]"))));
+    }
+}
@@ -0,0 +1,181 @@
+use sdl2::rect::Rect;
+
+use crate::raytracer::{WIDTH, HEIGHT};
+
+// --------------------------------------------------
+// Viewport
+// --------------------------------------------------
+// The part of the image the viewer's window shows, for zooming in on and panning around renders. The canvas
+// has the image's logical size, so canvas positions (drawing and mouse events) are in image pixels while the
+// whole image is shown. Zoomed in, they are mapped to the part of the image under them.
+
+// Window pixels per image pixel when zoomed in all the way.
+const MAX_SCALE: f64 = 32.0;
+
+pub struct Viewport {
+    image_width: u32,
+    image_height: u32,
+    fit_scale: f64, // Window pixels per image pixel with the whole image shown, at most 1.
+    scale: f64, // Window pixels per image pixel, from fit_scale to MAX_SCALE.
+    left: f64, // Image position at the top left of the window.
+    top: f64
+}
+
+impl Viewport {
+    // Shows the whole image, scaled down to fit the default resolution where it is larger.
+    pub fn new(image_width: u32, image_height: u32) -> Viewport {
+        let fit_scale = ((WIDTH as f64) / (image_width as f64)).min((HEIGHT as f64) / (image_height as f64)).min(1.0);
+        Viewport {
+            image_width: image_width,
+            image_height: image_height,
+            fit_scale: fit_scale,
+            scale: fit_scale,
+            left: 0.0,
+            top: 0.0
+        }
+    }
+
+    pub fn image_size(&self) -> (u32, u32) {
+        (self.image_width, self.image_height)
+    }
+
+    // Of the window, which shows the whole image at the fitted scale.
+    pub fn window_size(&self) -> (u32, u32) {
+        let width = ((self.image_width as f64) * self.fit_scale).round() as u32;
+        let height = ((self.image_height as f64) * self.fit_scale).round() as u32;
+        (width.max(1), height.max(1))
+    }
+
+    // Window pixels per image pixel.
+    pub fn get_scale(&self) -> f64 {
+        self.scale
+    }
+
+    pub fn is_zoomed(&self) -> bool {
+        self.scale > self.fit_scale
+    }
+
+    // Size of the texture the image is copied to: the window's, or the whole image while zoomed in on one larger
+    // than the window, so that its pixels can be seen.
+    pub fn texture_size(&self) -> (u32, u32) {
+        if self.is_zoomed() && self.fit_scale < 1.0 {self.image_size()} else {self.window_size()}
+    }
+
+    // Canvas pixels per image pixel.
+    fn zoom(&self) -> f64 {
+        self.scale / self.fit_scale
+    }
+
+    // Image position under a canvas position.
+    fn image_position(&self, x: f64, y: f64) -> (f64, f64) {
+        (self.left + x / self.zoom(), self.top + y / self.zoom())
+    }
+
+    // Image pixel under a canvas position, e.g. of a mouse event. May lie outside the image.
+    pub fn to_image(&self, x: i32, y: i32) -> (i32, i32) {
+        let (image_x, image_y) = self.image_position(x as f64, y as f64);
+        (image_x.floor() as i32, image_y.floor() as i32)
+    }
+
+    // Canvas position of an image position, e.g. of a region corner.
+    pub fn to_canvas(&self, x: f64, y: f64) -> (i32, i32) {
+        (((x - self.left) * self.zoom()).round() as i32, ((y - self.top) * self.zoom()).round() as i32)
+    }
+
+    // Zooms in (positive steps) or out by a factor of two per step, keeping the image position under a canvas
+    // position where it is. Scales snap to powers of two, so that image pixels cover whole window pixels.
+    pub fn zoom_at(&mut self, steps: i32, x: i32, y: i32) {
+        let (image_x, image_y) = self.image_position(x as f64, y as f64);
+        let mut exponent = self.scale.log2();
+        exponent = if steps > 0 {(exponent + 1e-6).floor() + (steps as f64)} else {(exponent - 1e-6).ceil() + (steps as f64)};
+        self.scale = exponent.exp2().clamp(self.fit_scale, MAX_SCALE);
+        self.left = image_x - (x as f64) / self.zoom();
+        self.top = image_y - (y as f64) / self.zoom();
+        self.keep_in_image();
+    }
+
+    // Moves the image with a drag across the canvas.
+    pub fn pan(&mut self, dx: i32, dy: i32) {
+        self.left -= (dx as f64) / self.zoom();
+        self.top -= (dy as f64) / self.zoom();
+        self.keep_in_image();
+    }
+
+    // Shows the whole image again.
+    pub fn reset(&mut self) {
+        self.scale = self.fit_scale;
+        self.left = 0.0;
+        self.top = 0.0;
+    }
+
+    // Keeps the window on the image, with its top left corner on a whole image pixel so that zoomed pixels line
+    // up with the texture's.
+    fn keep_in_image(&mut self) {
+        let zoom = self.zoom();
+        let (width, height) = ((self.image_width as f64) / zoom, (self.image_height as f64) / zoom);
+        self.left = self.left.clamp(0.0, (self.image_width as f64) - width).round();
+        self.top = self.top.clamp(0.0, (self.image_height as f64) - height).round();
+    }
+
+    // Rectangles of a texture of the given size and of the canvas for copying the visible part of the image.
+    pub fn copy_rects(&self, texture_width: u32, texture_height: u32) -> (Rect, Rect) {
+        let zoom = self.zoom();
+        let width = ((self.image_width as f64) / zoom).ceil().min((self.image_width as f64) - self.left);
+        let height = ((self.image_height as f64) / zoom).ceil().min((self.image_height as f64) - self.top);
+        let (to_texture_x, to_texture_y) = ((texture_width as f64) / (self.image_width as f64), (texture_height as f64) / (self.image_height as f64));
+        let source = Rect::new(
+            (self.left * to_texture_x).round() as i32, (self.top * to_texture_y).round() as i32,
+            ((width * to_texture_x).round() as u32).max(1), ((height * to_texture_y).round() as u32).max(1)
+        );
+        let destination = Rect::new(0, 0, ((width * zoom).round() as u32).max(1), ((height * zoom).round() as u32).max(1));
+        (source, destination)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zooming_keeps_the_pixel_under_the_mouse() {
+        // A 4k image is shown at a third of its size, 1:1 two steps in, and at 2:1 three steps in.
+        let mut viewport = Viewport::new(WIDTH * 3, HEIGHT * 3);
+        assert_eq!(viewport.window_size(), (WIDTH, HEIGHT));
+        assert_eq!(viewport.texture_size(), (WIDTH, HEIGHT));
+        assert_eq!(viewport.to_image(100, 50), (100, 50));
+
+        viewport.zoom_at(2, 1000, 600);
+        assert_eq!(viewport.get_scale(), 1.0);
+        assert_eq!(viewport.texture_size(), (WIDTH * 3, HEIGHT * 3));
+        assert_eq!(viewport.to_image(1000, 600), (1000, 600));
+        let (source, destination) = viewport.copy_rects(WIDTH * 3, HEIGHT * 3);
+        assert_eq!((source.width(), source.height()), (WIDTH, HEIGHT));
+        assert_eq!((destination.width(), destination.height()), (WIDTH * 3, HEIGHT * 3));
+
+        viewport.zoom_at(1, 1000, 600);
+        assert_eq!(viewport.get_scale(), 2.0);
+        let (x, y) = viewport.to_image(1000, 600);
+        assert!((x - 1000).abs() <= 1 && (y - 600).abs() <= 1);
+        let (canvas_x, canvas_y) = viewport.to_canvas(x as f64, y as f64);
+        assert!((canvas_x - 1000).abs() <= 6 && (canvas_y - 600).abs() <= 6);
+
+        // Zooming out far enough shows the whole image.
+        viewport.zoom_at(-10, 0, 0);
+        assert!(!viewport.is_zoomed());
+        assert_eq!(viewport.copy_rects(WIDTH, HEIGHT).0, Rect::new(0, 0, WIDTH, HEIGHT));
+    }
+
+    #[test]
+    fn panning_stays_on_the_image() {
+        let mut viewport = Viewport::new(WIDTH, HEIGHT);
+        viewport.zoom_at(1, 0, 0);
+        assert_eq!(viewport.to_image(0, 0), (0, 0));
+        viewport.pan(-400, -200);
+        assert_eq!(viewport.to_image(0, 0), (200, 100));
+        assert_eq!(viewport.to_canvas(200.0, 100.0), (0, 0));
+        viewport.pan(-100000, -100000);
+        assert_eq!(viewport.to_image(0, 0), ((WIDTH / 2) as i32, (HEIGHT / 2) as i32));
+        viewport.reset();
+        assert_eq!(viewport.to_image(0, 0), (0, 0));
+    }
+}